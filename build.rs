@@ -0,0 +1,57 @@
+//! Records what `nix-installer` was built from, see `src/build_info.rs`
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("Cargo sets CARGO_MANIFEST_DIR");
+    let git_dir = Path::new(&manifest_dir).join(".git");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!(
+        "cargo:rustc-env=NIX_INSTALLER_BUILD_TARGET={}",
+        std::env::var("TARGET").expect("Cargo sets TARGET")
+    );
+
+    // Builds from a crate tarball (or a vendored copy) have no git metadata to record
+    if !git_dir.is_dir() {
+        return;
+    }
+    for watched in ["HEAD", "index"] {
+        println!("cargo:rerun-if-changed={}", git_dir.join(watched).display());
+    }
+    if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!(
+                "cargo:rerun-if-changed={}",
+                git_dir.join(reference).display()
+            );
+        }
+    }
+
+    if let Some(sha) = git(&manifest_dir, &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=NIX_INSTALLER_BUILD_GIT_SHA={sha}");
+        if let Some(status) = git(
+            &manifest_dir,
+            &["status", "--porcelain", "--untracked-files=no"],
+        ) {
+            println!(
+                "cargo:rustc-env=NIX_INSTALLER_BUILD_GIT_DIRTY={}",
+                !status.is_empty()
+            );
+        }
+    }
+}
+
+/// The trimmed output of `git args` in `dir`, if it succeeded
+fn git(dir: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
@@ -7,6 +7,7 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -39,10 +40,10 @@ impl AddUserToGroup {
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("addgroup").is_ok() || which::which("gpasswd").is_ok()) {
+                if !(tools::find("addgroup").is_some() || tools::find("gpasswd").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingAddUserToGroupCommand));
                 }
-                if !(which::which("delgroup").is_ok() || which::which("gpasswd").is_ok()) {
+                if !(tools::find("delgroup").is_some() || tools::find("gpasswd").is_some()) {
                     return Err(Self::error(
                         ActionErrorKind::MissingRemoveUserFromGroupCommand,
                     ));
@@ -87,8 +88,7 @@ impl AddUserToGroup {
                     command.stdout(Stdio::piped());
                     command.stderr(Stdio::piped());
                     tracing::trace!("Executing `{:?}`", command.as_std());
-                    let output = command
-                        .output()
+                    let output = crate::command_runner::output(&mut command)
                         .await
                         .map_err(|e| ActionErrorKind::command(&command, e))
                         .map_err(Self::error)?;
@@ -122,7 +122,7 @@ impl AddUserToGroup {
                 },
                 _ => {
                     let output = execute_command(
-                        Command::new("groups")
+                        tools::command("groups")
                             .process_group(0)
                             .arg(&this.name)
                             .stdin(std::process::Stdio::null()),
@@ -227,9 +227,9 @@ impl Action for AddUserToGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("gpasswd").is_ok() {
+                if tools::find("gpasswd").is_some() {
                     execute_command(
-                        Command::new("gpasswd")
+                        tools::command("gpasswd")
                             .process_group(0)
                             .args(["-a"])
                             .args([name, groupname])
@@ -237,9 +237,9 @@ impl Action for AddUserToGroup {
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("addgroup").is_ok() {
+                } else if tools::find("addgroup").is_some() {
                     execute_command(
-                        Command::new("addgroup")
+                        tools::command("addgroup")
                             .process_group(0)
                             .args([name, groupname])
                             .stdin(std::process::Stdio::null()),
@@ -297,9 +297,9 @@ impl Action for AddUserToGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("gpasswd").is_ok() {
+                if tools::find("gpasswd").is_some() {
                     execute_command(
-                        Command::new("gpasswd")
+                        tools::command("gpasswd")
                             .process_group(0)
                             .args(["-d"])
                             .args([&name.to_string(), &groupname.to_string()])
@@ -307,9 +307,9 @@ impl Action for AddUserToGroup {
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("delgroup").is_ok() {
+                } else if tools::find("delgroup").is_some() {
                     execute_command(
-                        Command::new("delgroup")
+                        tools::command("delgroup")
                             .process_group(0)
                             .args([name, groupname])
                             .stdin(std::process::Stdio::null()),
@@ -0,0 +1,142 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::{check_pem_bundle, temp_artifacts};
+
+/// The bundle of public and corporate CAs written with `--append-corp-ca`
+pub const NIX_CA_BUNDLE: &str = "/etc/nix/ca-bundle.crt";
+/// The name of [`NIX_CA_BUNDLE`] in the Nix configuration directory, which `--nix-conf-dir` may move
+pub const NIX_CA_BUNDLE_NAME: &str = "ca-bundle.crt";
+/// The public CAs, from the `nss-cacert` package in the default profile
+pub(crate) const NSS_CA_BUNDLE: &str = "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt";
+
+/**
+Concatenate a corporate CA bundle onto the public CAs shipped with Nix, so both public and
+internal hosts can be fetched from
+
+The public bundle only exists once the default profile is set up.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CreateCaBundle {
+    corp_ca: PathBuf,
+    path: PathBuf,
+}
+
+impl CreateCaBundle {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        corp_ca: impl AsRef<Path>,
+        nix_conf_dir: impl AsRef<Path>,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let corp_ca = corp_ca
+            .as_ref()
+            .canonicalize()
+            .map_err(|e| Self::error(ActionErrorKind::Canonicalize(corp_ca.as_ref().into(), e)))?;
+        check_pem_bundle(&corp_ca).await.map_err(Self::error)?;
+
+        let path = nix_conf_dir.as_ref().join(NIX_CA_BUNDLE_NAME);
+        crate::path_policy::check(&path).map_err(Self::error)?;
+        if path.exists() && !force {
+            return Err(Self::error(ActionErrorKind::FileExists(path)));
+        }
+
+        Ok(Self { corp_ca, path }.into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_ca_bundle")]
+impl Action for CreateCaBundle {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_ca_bundle")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Create `{}` from the Nix CA bundle and `{}`",
+            self.path.display(),
+            self.corp_ca.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_ca_bundle",
+            corp_ca = tracing::field::display(self.corp_ca.display()),
+            path = tracing::field::display(self.path.display()),
+        )
+    }
+
+    fn depends_on(&self) -> Vec<&'static str> {
+        // The public CAs are in the default profile
+        vec!["setup_default_profile"]
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![format!(
+                "Nix is configured to use it in place of `{NSS_CA_BUNDLE}`"
+            )],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let Self { corp_ca, path } = self;
+        crate::path_policy::check(path).map_err(Self::error)?;
+
+        let mut bundle = tokio::fs::read(NSS_CA_BUNDLE)
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Read(NSS_CA_BUNDLE.into(), e)))?;
+        let corp_ca_buf = tokio::fs::read(&corp_ca)
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Read(corp_ca.clone(), e)))?;
+        if !bundle.ends_with(b"\n") {
+            bundle.push(b'\n');
+        }
+        bundle.extend_from_slice(&corp_ca_buf);
+
+        // Write aside and rename, the daemon may read it at any time
+        let temp_path = path.with_extension("crt.tmp");
+        temp_artifacts::register(&temp_path);
+        tokio::fs::write(&temp_path, &bundle)
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Write(temp_path.clone(), e)))?;
+        tokio::fs::set_permissions(&temp_path, PermissionsExt::from_mode(0o644))
+            .await
+            .map_err(|e| {
+                Self::error(ActionErrorKind::SetPermissions(0o644, temp_path.clone(), e))
+            })?;
+        tokio::fs::rename(&temp_path, &path).await.map_err(|e| {
+            Self::error(ActionErrorKind::Rename(temp_path.clone(), path.clone(), e))
+        })?;
+        temp_artifacts::unregister(&temp_path);
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Remove `{}`", self.path.display()),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if self.path.exists() {
+            tokio::fs::remove_file(&self.path)
+                .await
+                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.clone(), e)))?;
+        }
+
+        Ok(())
+    }
+}
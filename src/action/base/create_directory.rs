@@ -3,13 +3,14 @@ use std::path::{Path, PathBuf};
 
 use nix::unistd::{chown, Group, User};
 
-use tokio::fs::{create_dir, remove_dir_all, remove_file};
-use tokio::process::Command;
+use tokio::fs::create_dir;
 use tracing::{span, Span};
 
 use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
 use crate::action::{ActionError, StatefulAction};
 use crate::execute_command;
+use crate::os::mounts;
+use crate::os::tools;
 
 /** Create a directory at the given location, optionally with an owning user, group, and mode.
 
@@ -18,6 +19,7 @@ If `force_prune_on_revert` is set, the folder will always be deleted on
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateDirectory {
+    #[serde(with = "crate::lossless_path")]
     path: PathBuf,
     user: Option<String>,
     group: Option<String>,
@@ -36,6 +38,7 @@ impl CreateDirectory {
         force_prune_on_revert: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        crate::path_policy::check(&path).map_err(Self::error)?;
         let user = user.into();
         let group = group.into();
         let mode = mode.into();
@@ -110,6 +113,9 @@ impl CreateDirectory {
                 force_prune_on_revert,
             },
             state: action_state,
+            id: None,
+            timing: None,
+            reverted_at: None,
         })
     }
 }
@@ -121,7 +127,10 @@ impl Action for CreateDirectory {
         crate::action::ActionTag("create_directory")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Create directory `{}`", self.path.display())
+        format!(
+            "Create directory `{}`",
+            crate::lossless_path::Display(&self.path)
+        )
     }
 
     fn tracing_span(&self) -> Span {
@@ -151,6 +160,7 @@ impl Action for CreateDirectory {
             is_mountpoint, // If `is_mountpoint = true` the `ActionState` should be completed.
             force_prune_on_revert: _,
         } = self;
+        crate::path_policy::check(path).map_err(Self::error)?;
 
         if *is_mountpoint {
             // A `/nix` mount exists, we don't need to do anything.
@@ -251,35 +261,29 @@ impl Action for CreateDirectory {
         match (is_mountpoint, is_empty, force_prune_on_revert) {
             (true, _, true) => {
                 tracing::debug!("Cleaning mountpoint `{}`", path.display());
-                for child_path in contents {
-                    let child_path = child_path
-                        .map_err(|e| ActionErrorKind::ReadDir(path.clone(), e))
-                        .map_err(Self::error)?;
-                    let child_path_path = child_path.path();
-                    let child_path_type = child_path
-                        .file_type()
-                        .map_err(|e| ActionErrorKind::GettingMetadata(child_path_path.clone(), e))
-                        .map_err(Self::error)?;
-                    if child_path_type.is_dir() {
-                        remove_dir_all(child_path_path.clone())
-                            .await
-                            .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
-                            .map_err(Self::error)?
-                    } else {
-                        remove_file(child_path_path)
-                            .await
-                            .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
-                            .map_err(Self::error)?
-                    }
-                }
+                mounts::ensure_no_mounts_under(path)
+                    .await
+                    .map_err(Self::error)?;
+                let dev = tokio::fs::metadata(&path)
+                    .await
+                    .map_err(|e| ActionErrorKind::GettingMetadata(path.clone(), e))
+                    .map_err(Self::error)?
+                    .dev();
+                mounts::remove_dir_contents_on(path, dev)
+                    .await
+                    .map_err(Self::error)?;
             },
             (true, _, false) => {
                 tracing::debug!("Not cleaning mountpoint `{}`", path.display());
             },
-            (false, true, _) | (false, false, true) => remove_dir_all(path.clone())
-                .await
-                .map_err(|e| ActionErrorKind::Remove(path.clone(), e))
-                .map_err(Self::error)?,
+            (false, true, _) | (false, false, true) => {
+                mounts::ensure_no_mounts_under(path)
+                    .await
+                    .map_err(Self::error)?;
+                mounts::remove_dir_all_same_filesystem(path)
+                    .await
+                    .map_err(Self::error)?;
+            },
             (false, false, false) => {
                 tracing::debug!("Not removing `{}`, the folder is not empty", path.display());
             },
@@ -296,7 +300,7 @@ async fn path_is_mountpoint(path: &Path) -> Result<bool, ActionErrorKind> {
         None => return Err(ActionErrorKind::PathNoneString(path.to_path_buf())),
     };
 
-    let mut mount_command = Command::new("mount");
+    let mut mount_command = tools::command("mount");
     mount_command.process_group(0);
 
     #[cfg(target_os = "macos")]
@@ -1,15 +1,17 @@
-use nix::unistd::{chown, Group, User};
+use nix::unistd::{Group, User};
 use tracing::{span, Span};
 
 use std::{
+    fs::Metadata,
     os::{unix::fs::MetadataExt, unix::fs::PermissionsExt},
     path::{Path, PathBuf},
 };
 use tokio::{
-    fs::{remove_file, File, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
+    fs::{remove_file, File},
+    io::AsyncReadExt,
 };
 
+use super::replaced_file::{self, Original, Written};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
@@ -17,17 +19,25 @@ use crate::action::{
 /** Create a file at the given location with the provided `buf`,
 optionally with an owning user, group, and mode.
 
-If `force` is set, the file will always be overwritten (and deleted)
-regardless of its presence prior to install.
+If `force` is set, a file already there with different content, mode or owner is overwritten
+instead of refused. What it had is kept in the action, and put back exactly on revert rather than
+the file being deleted.
+
+A file edited since, or replaced by a symlink or directory, is left in place on revert and reported
+as a [leftover](crate::leftovers) instead.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateFile {
+    #[serde(with = "crate::lossless_path")]
     pub(crate) path: PathBuf,
     user: Option<String>,
     group: Option<String>,
     mode: Option<u32>,
     buf: String,
     force: bool,
+    /// What the file overwritten had, see [`replaced_file`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<Original>,
 }
 
 impl CreateFile {
@@ -41,6 +51,7 @@ impl CreateFile {
         force: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        crate::path_policy::check(&path).map_err(Self::error)?;
         let mode = mode.into();
         let user = user.into();
         let group = group.into();
@@ -51,6 +62,7 @@ impl CreateFile {
             mode,
             buf,
             force,
+            original: None,
         };
 
         if this.path.exists() {
@@ -70,75 +82,93 @@ impl CreateFile {
                 return Err(Self::error(ActionErrorKind::PathWasNotFile(this.path)));
             }
 
-            if let Some(mode) = mode {
-                // Does the file have the right permissions?
-                let discovered_mode = metadata.permissions().mode();
-                // We only care about user-group-other permissions
-                let discovered_mode = discovered_mode & 0o777;
-
-                if discovered_mode != mode {
-                    return Err(Self::error(ActionErrorKind::PathModeMismatch(
-                        this.path.clone(),
-                        discovered_mode,
-                        mode,
-                    )));
-                }
+            match this
+                .mismatch(&mut file, &metadata)
+                .await
+                .map_err(Self::error)?
+            {
+                Some(mismatch) if this.force => {
+                    tracing::debug!(
+                        "Overwriting file `{}` as forced: {mismatch}",
+                        this.path.display()
+                    );
+                    return Ok(StatefulAction::uncompleted(this));
+                },
+                Some(mismatch) => return Err(Self::error(mismatch)),
+                None => (),
             }
 
-            // Does it have the right user/group?
-            if let Some(user) = &this.user {
-                // If the file exists, the user must also exist to be correct.
-                let expected_uid = User::from_name(user.as_str())
-                    .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))
-                    .map_err(Self::error)?
-                    .uid;
-                let found_uid = metadata.uid();
-                if found_uid != expected_uid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathUserMismatch(
-                        this.path.clone(),
-                        found_uid,
-                        expected_uid.as_raw(),
-                    )));
-                }
-            }
-            if let Some(group) = &this.group {
-                // If the file exists, the group must also exist to be correct.
-                let expected_gid = Group::from_name(group.as_str())
-                    .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))
-                    .map_err(Self::error)?
-                    .ok_or_else(|| ActionErrorKind::NoUser(group.clone()))
-                    .map_err(Self::error)?
-                    .gid;
-                let found_gid = metadata.gid();
-                if found_gid != expected_gid.as_raw() {
-                    return Err(Self::error(ActionErrorKind::PathGroupMismatch(
-                        this.path.clone(),
-                        found_gid,
-                        expected_gid.as_raw(),
-                    )));
-                }
-            }
+            tracing::debug!("Creating file `{}` already complete", this.path.display());
+            return Ok(StatefulAction::completed(this));
+        }
 
-            // Does it have the right content?
-            let mut discovered_buf = String::new();
-            file.read_to_string(&mut discovered_buf)
-                .await
-                .map_err(|e| ActionErrorKind::Read(this.path.clone(), e))
-                .map_err(Self::error)?;
+        Ok(StatefulAction::uncompleted(this))
+    }
 
-            if discovered_buf != this.buf {
-                return Err(Self::error(ActionErrorKind::DifferentContent(
-                    this.path.clone(),
+    /// How the existing `file` differs from the one planned, if it does
+    async fn mismatch(
+        &self,
+        file: &mut File,
+        metadata: &Metadata,
+    ) -> Result<Option<ActionErrorKind>, ActionErrorKind> {
+        if let Some(mode) = self.mode {
+            // Does the file have the right permissions?
+            let discovered_mode = metadata.permissions().mode();
+            // We only care about user-group-other permissions
+            let discovered_mode = discovered_mode & 0o777;
+
+            if discovered_mode != mode {
+                return Ok(Some(ActionErrorKind::PathModeMismatch(
+                    self.path.clone(),
+                    discovered_mode,
+                    mode,
                 )));
             }
+        }
 
-            tracing::debug!("Creating file `{}` already complete", this.path.display());
-            return Ok(StatefulAction::completed(this));
+        // Does it have the right user/group?
+        if let Some(user) = &self.user {
+            // If the file exists, the user must also exist to be correct.
+            let expected_uid = User::from_name(user.as_str())
+                .map_err(|e| ActionErrorKind::GettingUserId(user.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoUser(user.clone()))?
+                .uid;
+            let found_uid = metadata.uid();
+            if found_uid != expected_uid.as_raw() {
+                return Ok(Some(ActionErrorKind::PathUserMismatch(
+                    self.path.clone(),
+                    found_uid,
+                    expected_uid.as_raw(),
+                )));
+            }
+        }
+        if let Some(group) = &self.group {
+            // If the file exists, the group must also exist to be correct.
+            let expected_gid = Group::from_name(group.as_str())
+                .map_err(|e| ActionErrorKind::GettingGroupId(group.clone(), e))?
+                .ok_or_else(|| ActionErrorKind::NoUser(group.clone()))?
+                .gid;
+            let found_gid = metadata.gid();
+            if found_gid != expected_gid.as_raw() {
+                return Ok(Some(ActionErrorKind::PathGroupMismatch(
+                    self.path.clone(),
+                    found_gid,
+                    expected_gid.as_raw(),
+                )));
+            }
         }
 
-        Ok(StatefulAction::uncompleted(this))
+        // Does it have the right content?
+        let mut discovered_buf = Vec::new();
+        file.read_to_end(&mut discovered_buf)
+            .await
+            .map_err(|e| ActionErrorKind::Read(self.path.clone(), e))?;
+
+        if discovered_buf != self.buf.as_bytes() {
+            return Ok(Some(ActionErrorKind::DifferentContent(self.path.clone())));
+        }
+
+        Ok(None)
     }
 }
 
@@ -149,7 +179,10 @@ impl Action for CreateFile {
         ActionTag("create_file")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Create or overwrite file `{}`", self.path.display())
+        format!(
+            "Create or overwrite file `{}`",
+            crate::lossless_path::Display(&self.path)
+        )
     }
 
     fn tracing_span(&self) -> Span {
@@ -184,31 +217,15 @@ impl Action for CreateFile {
             mode,
             buf,
             force: _,
+            original,
         } = self;
+        crate::path_policy::check(path).map_err(Self::error)?;
 
         if tracing::enabled!(tracing::Level::TRACE) {
             let span = tracing::Span::current();
             span.record("buf", &buf);
         }
 
-        let mut options = OpenOptions::new();
-        options.create_new(true).write(true).read(true);
-
-        if let Some(mode) = mode {
-            options.mode(*mode);
-        }
-
-        let mut file = options
-            .open(&path)
-            .await
-            .map_err(|e| ActionErrorKind::Open(path.to_owned(), e))
-            .map_err(Self::error)?;
-
-        file.write_all(buf.as_bytes())
-            .await
-            .map_err(|e| ActionErrorKind::Write(path.to_owned(), e))
-            .map_err(Self::error)?;
-
         let gid = if let Some(group) = group {
             Some(
                 Group::from_name(group.as_str())
@@ -233,8 +250,13 @@ impl Action for CreateFile {
         } else {
             None
         };
-        chown(path, uid, gid)
-            .map_err(|e| ActionErrorKind::Chown(path.clone(), e))
+
+        // Kept from an earlier attempt, the file there now may already be the one written
+        if original.is_none() {
+            *original = Original::read(path).await.map_err(Self::error)?;
+        }
+        replaced_file::replace(path, buf.as_bytes(), *mode, uid, gid)
+            .await
             .map_err(Self::error)?;
 
         Ok(())
@@ -248,11 +270,16 @@ impl Action for CreateFile {
             mode: _,
             buf: _,
             force: _,
+            original,
         } = &self;
 
+        let description = match original {
+            Some(_) => format!("Restore the original file `{}`", path.display()),
+            None => format!("Delete file `{}`", path.display()),
+        };
         vec![ActionDescription::new(
-            format!("Delete file `{}`", path.display()),
-            vec![format!("Delete file `{}`", path.display())],
+            description.clone(),
+            vec![description],
         )]
     }
 
@@ -263,11 +290,42 @@ impl Action for CreateFile {
             user: _,
             group: _,
             mode: _,
-            buf: _,
+            buf,
             force: _,
+            original,
         } = self;
-        // The user already deleted it
-        if !path.exists() {
+        // Whatever took its place since is someone else's, and must not be removed through
+        let not_restored = match original {
+            Some(_) => ", the file it replaced was not restored",
+            None => "",
+        };
+        match Written::inspect(path).await.map_err(Self::error)? {
+            Written::Replaced(by) => {
+                crate::leftovers::report(
+                    path.clone(),
+                    format!("it was replaced by {by} since the install{not_restored}"),
+                );
+                return Ok(());
+            },
+            Written::File => {
+                let contents = tokio::fs::read(&path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
+                    .map_err(Self::error)?;
+                if contents != buf.as_bytes() {
+                    crate::leftovers::report(
+                        path.clone(),
+                        format!("it was edited since the install{not_restored}"),
+                    );
+                    return Ok(());
+                }
+            },
+            // The user already deleted it
+            Written::Missing if original.is_none() => return Ok(()),
+            Written::Missing => (),
+        }
+        if let Some(original) = original.take() {
+            original.restore(path).await.map_err(Self::error)?;
             return Ok(());
         }
 
@@ -284,7 +342,7 @@ impl Action for CreateFile {
 mod test {
     use super::*;
     use color_eyre::eyre::eyre;
-    use tokio::fs::write;
+    use tokio::fs::{read_to_string, write};
 
     #[tokio::test]
     async fn creates_and_deletes_file() -> eyre::Result<()> {
@@ -303,11 +361,9 @@ mod test {
     }
 
     #[tokio::test]
-    async fn creates_and_deletes_file_even_if_edited() -> eyre::Result<()> {
+    async fn leaves_file_edited_since() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
-        let test_file = temp_dir
-            .path()
-            .join("creates_and_deletes_file_even_if_edited");
+        let test_file = temp_dir.path().join("leaves_file_edited_since");
         let mut action =
             CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
 
@@ -315,9 +371,66 @@ mod test {
 
         write(test_file.as_path(), "More content").await?;
 
-        action.try_revert().await?;
+        let (reverted, leftovers) = crate::leftovers::collect(action.try_revert()).await;
+        reverted?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        assert_eq!(read_to_string(&test_file).await?, "More content");
+        assert_eq!(leftovers.len(), 1);
+        assert_eq!(leftovers[0].path, test_file);
+        assert!(leftovers[0].reason.contains("edited"), "{}", leftovers[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_file_replaced_by_a_symlink_or_directory() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("leaves_file_replaced_by_a_symlink_or_directory");
+        // The copy managed elsewhere has what the installer wrote, and must survive anyway
+        let managed = temp_dir.path().join("managed");
+        write(&managed, "Test").await?;
+
+        for replace_with_symlink in [true, false] {
+            let mut action =
+                CreateFile::plan(test_file.clone(), None, None, None, "Test".into(), false).await?;
+            action.try_execute().await?;
+
+            remove_file(&test_file).await?;
+            if replace_with_symlink {
+                tokio::fs::symlink(&managed, &test_file).await?;
+            } else {
+                tokio::fs::create_dir(&test_file).await?;
+                write(test_file.join("inside"), "Test").await?;
+            }
+
+            let (reverted, leftovers) = crate::leftovers::collect(action.try_revert()).await;
+            reverted?;
+
+            assert_eq!(leftovers.len(), 1);
+            if replace_with_symlink {
+                assert!(tokio::fs::symlink_metadata(&test_file)
+                    .await?
+                    .file_type()
+                    .is_symlink());
+                assert!(
+                    leftovers[0].reason.contains("a symlink"),
+                    "{}",
+                    leftovers[0]
+                );
+                remove_file(&test_file).await?;
+            } else {
+                assert_eq!(read_to_string(test_file.join("inside")).await?, "Test");
+                assert!(
+                    leftovers[0].reason.contains("a directory"),
+                    "{}",
+                    leftovers[0]
+                );
+                tokio::fs::remove_dir_all(&test_file).await?;
+            }
+            assert_eq!(read_to_string(&managed).await?, "Test");
+        }
 
         Ok(())
     }
@@ -464,6 +577,90 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn forced_overwrite_restores_original_exactly() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("forced_overwrite_restores_original_exactly");
+        let original_bytes = b"Original\n\xff\x00 not UTF-8";
+        write(test_file.as_path(), original_bytes).await?;
+        tokio::fs::set_permissions(test_file.as_path(), PermissionsExt::from_mode(0o640)).await?;
+        // Ownership is only restored to someone else as root
+        let original_owner = if nix::unistd::geteuid().is_root() {
+            nix::unistd::chown(
+                test_file.as_path(),
+                Some(nix::unistd::Uid::from_raw(1)),
+                Some(nix::unistd::Gid::from_raw(1)),
+            )?;
+            (1, 1)
+        } else {
+            let metadata = tokio::fs::metadata(test_file.as_path()).await?;
+            (metadata.uid(), metadata.gid())
+        };
+
+        let mut action = CreateFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            Some(0o644),
+            "Forced content".into(),
+            true,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(
+            tokio::fs::read_to_string(test_file.as_path()).await?,
+            "Forced content"
+        );
+        let metadata = tokio::fs::metadata(test_file.as_path()).await?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+
+        // The original is kept in the receipt, across the removal of `/nix`
+        let mut action: StatefulAction<CreateFile> =
+            serde_json::from_str(&serde_json::to_string(&action)?)?;
+        action.try_revert().await?;
+
+        assert_eq!(tokio::fs::read(test_file.as_path()).await?, original_bytes);
+        let metadata = tokio::fs::metadata(test_file.as_path()).await?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!((metadata.uid(), metadata.gid()), original_owner);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_on_dir_even_if_forced() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+
+        match CreateFile::plan(
+            temp_dir.path(),
+            None,
+            None,
+            None,
+            "Some different content".into(),
+            true,
+        )
+        .await
+        {
+            Err(err) => match err.kind() {
+                ActionErrorKind::PathWasNotFile(path) => assert_eq!(path, temp_dir.path()),
+                _ => {
+                    return Err(eyre!(
+                        "Should have returned an ActionErrorKind::PathWasNotFile error"
+                    ))
+                },
+            },
+            _ => {
+                return Err(eyre!(
+                    "Should have returned an ActionErrorKind::PathWasNotFile error"
+                ))
+            },
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn errors_on_dir() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
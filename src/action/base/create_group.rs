@@ -1,10 +1,11 @@
-use nix::unistd::Group;
 use target_lexicon::OperatingSystem;
 use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::nss::{NssUserBackend, UserBackend};
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -13,47 +14,91 @@ Create an operating system level user group
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateGroup {
-    name: String,
-    gid: u32,
+    pub(crate) name: String,
+    pub(crate) gid: u32,
+    /// The group existed before the install, with the planned GID
+    #[serde(default)]
+    adopted: bool,
+}
+
+/// What an existing group means for the group to create, see [`GroupResolution::resolve`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GroupResolution {
+    /// The group exists with the planned GID
+    Adopt,
+    /// The group exists with another GID
+    NameHasOtherGid(u32),
+    /// The planned GID belongs to a group of another name
+    GidTaken(String),
+    /// Neither the group nor the GID exist
+    Create,
+}
+
+impl GroupResolution {
+    pub(crate) fn resolve(backend: &impl UserBackend, name: &str, gid: u32) -> Self {
+        match backend.group_gid(name) {
+            Some(existing) if existing == gid => Self::Adopt,
+            Some(existing) => Self::NameHasOtherGid(existing),
+            None => match backend.group_name(gid) {
+                Some(other) => Self::GidTaken(other),
+                None => Self::Create,
+            },
+        }
+    }
 }
 
 impl CreateGroup {
     #[tracing::instrument(level = "debug", skip_all)]
     pub fn plan(name: String, gid: u32) -> Result<StatefulAction<Self>, ActionError> {
-        let this = Self {
-            name: name.clone(),
-            gid,
-        };
-
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("groupadd").is_ok() || which::which("addgroup").is_ok()) {
+                if !(tools::find("groupadd").is_some() || tools::find("addgroup").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingGroupCreationCommand));
                 }
-                if !(which::which("groupdel").is_ok() || which::which("delgroup").is_ok()) {
+                if !(tools::find("groupdel").is_some() || tools::find("delgroup").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingGroupDeletionCommand));
                 }
             },
         }
 
-        // Ensure group does not exists
-        if let Some(group) = Group::from_name(name.as_str())
-            .map_err(|e| ActionErrorKind::GettingGroupId(name.clone(), e))
-            .map_err(Self::error)?
-        {
-            if group.gid.as_raw() != gid {
-                return Err(Self::error(ActionErrorKind::GroupGidMismatch(
-                    name.clone(),
-                    group.gid.as_raw(),
-                    gid,
-                )));
-            }
+        Self::plan_with(&NssUserBackend, name, gid)
+    }
+
+    /// The group `name` as identity management provisioned it, which is neither created nor removed
+    pub(crate) fn provisioned(name: String, gid: u32) -> StatefulAction<Self> {
+        StatefulAction::skipped(Self {
+            name,
+            gid,
+            adopted: true,
+        })
+    }
 
-            tracing::debug!("Creating group `{}` already complete", this.name);
-            return Ok(StatefulAction::completed(this));
+    /// Plan the group `name` with `gid`, looking existing groups up with `backend`
+    pub(crate) fn plan_with(
+        backend: &impl UserBackend,
+        name: String,
+        gid: u32,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let mut this = Self {
+            name: name.clone(),
+            gid,
+            adopted: false,
+        };
+        match GroupResolution::resolve(backend, &name, gid) {
+            GroupResolution::Adopt => {
+                tracing::debug!("Creating group `{}` already complete", this.name);
+                this.adopted = true;
+                Ok(StatefulAction::completed(this))
+            },
+            GroupResolution::NameHasOtherGid(existing) => Err(Self::error(
+                ActionErrorKind::GroupGidMismatch(name, existing, gid),
+            )),
+            GroupResolution::GidTaken(other) => Err(Self::error(ActionErrorKind::GroupGidTaken(
+                gid, other, name,
+            ))),
+            GroupResolution::Create => Ok(StatefulAction::uncompleted(this)),
         }
-        Ok(StatefulAction::uncompleted(this))
     }
 }
 
@@ -67,7 +112,11 @@ impl Action for CreateGroup {
         format!("Create group `{}` (GID {})", self.name, self.gid)
     }
     fn execute_description(&self) -> Vec<ActionDescription> {
-        let Self { name: _, gid: _ } = &self;
+        let Self {
+            name: _,
+            gid: _,
+            adopted: _,
+        } = &self;
         vec![ActionDescription::new(
             self.tracing_synopsis(),
             vec![format!(
@@ -87,7 +136,11 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid } = self;
+        let Self {
+            name,
+            gid,
+            adopted: _,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -115,18 +168,18 @@ impl Action for CreateGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("groupadd").is_ok() {
+                if tools::find("groupadd").is_some() {
                     execute_command(
-                        Command::new("groupadd")
+                        tools::command("groupadd")
                             .process_group(0)
                             .args(["-g", &gid.to_string(), "--system", name])
                             .stdin(std::process::Stdio::null()),
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("addgroup").is_ok() {
+                } else if tools::find("addgroup").is_some() {
                     execute_command(
-                        Command::new("addgroup")
+                        tools::command("addgroup")
                             .process_group(0)
                             .args(["-g", &gid.to_string(), "--system", name])
                             .stdin(std::process::Stdio::null()),
@@ -143,7 +196,11 @@ impl Action for CreateGroup {
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
-        let Self { name, gid } = &self;
+        let Self {
+            name,
+            gid,
+            adopted: _,
+        } = &self;
         vec![ActionDescription::new(
             format!("Delete group `{name}` (GID {gid})"),
             vec![format!(
@@ -154,7 +211,11 @@ impl Action for CreateGroup {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        let Self { name, gid: _ } = self;
+        let Self {
+            name,
+            gid: _,
+            adopted: _,
+        } = self;
 
         use OperatingSystem;
         match OperatingSystem::host() {
@@ -173,18 +234,18 @@ impl Action for CreateGroup {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("groupdel").is_ok() {
+                if tools::find("groupdel").is_some() {
                     execute_command(
-                        Command::new("groupdel")
+                        tools::command("groupdel")
                             .process_group(0)
                             .arg(name)
                             .stdin(std::process::Stdio::null()),
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("delgroup").is_ok() {
+                } else if tools::find("delgroup").is_some() {
                     execute_command(
-                        Command::new("delgroup")
+                        tools::command("delgroup")
                             .process_group(0)
                             .arg(name)
                             .stdin(std::process::Stdio::null()),
@@ -200,3 +261,90 @@ impl Action for CreateGroup {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A single existing group
+    struct FakeBackend(&'static str, u32);
+
+    impl UserBackend for FakeBackend {
+        fn user_exists(&self, _name: &str) -> bool {
+            false
+        }
+
+        fn group_exists(&self, name: &str) -> bool {
+            name == self.0
+        }
+
+        fn group_gid(&self, name: &str) -> Option<u32> {
+            (name == self.0).then_some(self.1)
+        }
+
+        fn group_name(&self, gid: u32) -> Option<String> {
+            (gid == self.1).then(|| self.0.to_string())
+        }
+
+        fn user_ids(&self, _name: &str) -> Option<(u32, u32)> {
+            None
+        }
+
+        fn user_name(&self, _uid: u32) -> Option<String> {
+            None
+        }
+
+        fn group_members(&self, _name: &str) -> Vec<String> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn resolves_existing_groups() {
+        let resolve =
+            |name, gid| GroupResolution::resolve(&FakeBackend("nixbld", 30000), name, gid);
+        assert_eq!(resolve("nixbld", 30000), GroupResolution::Adopt);
+        assert_eq!(
+            resolve("nixbld", 3000),
+            GroupResolution::NameHasOtherGid(30000)
+        );
+        assert_eq!(
+            resolve("nixbuild", 30000),
+            GroupResolution::GidTaken("nixbld".into())
+        );
+        assert_eq!(resolve("nixbuild", 3000), GroupResolution::Create);
+    }
+
+    #[test]
+    fn adopts_or_refuses_existing_groups() -> eyre::Result<()> {
+        let backend = FakeBackend("nixbld", 30000);
+
+        let adopted = CreateGroup::plan_with(&backend, "nixbld".into(), 30000)?;
+        assert_eq!(adopted.state, crate::action::ActionState::Completed);
+        assert!(adopted.inner().adopted);
+        assert_eq!(
+            serde_json::to_value(adopted.inner())?["adopted"],
+            serde_json::json!(true)
+        );
+
+        let created = CreateGroup::plan_with(&backend, "nixbuild".into(), 3000)?;
+        assert_eq!(created.state, crate::action::ActionState::Uncompleted);
+        assert!(!created.inner().adopted);
+
+        let mismatch = CreateGroup::plan_with(&backend, "nixbld".into(), 3000).unwrap_err();
+        assert!(matches!(
+            mismatch.kind(),
+            ActionErrorKind::GroupGidMismatch(name, 30000, 3000) if name == "nixbld"
+        ));
+        assert!(mismatch
+            .kind()
+            .to_string()
+            .contains("--nix-build-group-id 30000"));
+        let taken = CreateGroup::plan_with(&backend, "nixbuild".into(), 30000).unwrap_err();
+        assert!(matches!(
+            taken.kind(),
+            ActionErrorKind::GroupGidTaken(30000, other, name) if other == "nixbld" && name == "nixbuild"
+        ));
+        Ok(())
+    }
+}
@@ -1,9 +1,9 @@
-use nix::unistd::{chown, Group, User};
+use nix::unistd::{chown, Gid, Group, Uid, User};
 
+use super::replaced_file::{temp_path_beside, Written};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
-use rand::Rng;
 use std::{
     io::SeekFrom,
     os::{unix::fs::MetadataExt, unix::prelude::PermissionsExt},
@@ -11,10 +11,42 @@ use std::{
 };
 use tokio::{
     fs::{remove_file, File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
 };
 use tracing::{span, Span};
 
+/// How much of a file is held in memory at once while searching or rewriting it
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// The lines the blocks the installer inserts (eg. into shell profiles) are fenced with
+const FENCE_START: &str = "# Nix";
+const FENCE_END: &str = "# End Nix";
+/// Appended to the name of an existing file for the copy of it taken before inserting into it
+const BACKUP_SUFFIX: &str = ".backup-before-nix";
+/// Where a symlink inserted into (eg. an MDM managed `/etc/zshrc`) may lead, besides the directory it is in
+const SYMLINK_TARGET_ROOTS: &[&str] = &["/etc", "/private/etc"];
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateOrInsertIntoFileError {
+    #[error(
+        "`{}` is a symlink to `{}`, which {}, refusing to insert into it",
+        .path.display(),
+        .target.display(),
+        if *.dangling { "does not exist" } else { "is outside of `/etc`, `/private/etc` and the directory of the symlink" }
+    )]
+    UnsafeSymlink {
+        path: PathBuf,
+        target: PathBuf,
+        dangling: bool,
+    },
+}
+
+impl From<CreateOrInsertIntoFileError> for ActionErrorKind {
+    fn from(val: CreateOrInsertIntoFileError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
 pub enum Position {
     Beginning,
@@ -25,19 +57,86 @@ pub enum Position {
 contents, optionally with an owning user, group, and mode.
 
 If the file exists, the provided `buf` will be inserted at its
-beginning or end, depending on the position field.
+beginning or end, depending on the position field. Nothing is inserted
+if the file already holds `buf`, or a different block between the same
+`# Nix` and `# End Nix` fences, and only an inserted `buf` is removed on
+revert.
+
+If `path` is a symlink, the file it leads to is inserted into and the symlink is left in place. Both
+are recorded, so revert edits the same file. Symlinks which dangle, or lead outside of `/etc`,
+`/private/etc` and the directory they are in, are refused.
+
+An existing file is copied beside it (eg. to `/etc/zshrc.backup-before-nix`)
+before inserting into it. If `buf` can't be found to remove on revert, as
+other tooling rewrote the file since, the copy is restored instead. Without a copy, or if the file
+was replaced by a symlink or directory since, it is left in place and reported as a
+[leftover](crate::leftovers).
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateOrInsertIntoFile {
+    #[serde(with = "crate::lossless_path")]
     path: PathBuf,
     user: Option<String>,
     group: Option<String>,
     mode: Option<u32>,
     buf: String,
     position: Position,
+    /// If executing inserted `buf`, receipts from before this was recorded assume it did
+    #[serde(default = "inserted_default")]
+    inserted: bool,
+    /// If executing created the file, which revert then removes if nothing else was added to it
+    /// since (receipts from before this was recorded leave it in place)
+    #[serde(default)]
+    created: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup: Option<Backup>,
+    /// The file the symlink at `path` led to when planned, which is the one inserted into
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::lossless_path::option"
+    )]
+    resolved: Option<PathBuf>,
+}
+
+/// The copy of a file taken before inserting into it
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+struct Backup {
+    #[serde(with = "crate::lossless_path")]
+    path: PathBuf,
+    /// Of the copy, which is only restored as long as it is unchanged
+    sha256: String,
+    /// If this install took it, rather than a previous one which it is kept from
+    created: bool,
+}
+
+fn inserted_default() -> bool {
+    true
+}
+
+/// What a file already holds of a fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Existing {
+    Missing,
+    Exact,
+    /// A different block between the same fences, which someone else configured
+    Fenced,
 }
 
 impl CreateOrInsertIntoFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn buf(&self) -> &str {
+        &self.buf
+    }
+
+    /// The file inserted into, which `path` may be a symlink to
+    fn target(&self) -> &Path {
+        self.resolved.as_deref().unwrap_or(&self.path)
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         path: impl AsRef<Path>,
@@ -48,9 +147,11 @@ impl CreateOrInsertIntoFile {
         position: Position,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        crate::path_policy::check(&path).map_err(Self::error)?;
         let mode = mode.into();
         let user = user.into();
         let group = group.into();
+        let resolved = resolve_symlink(&path).await.map_err(Self::error)?;
         let this = Self {
             path,
             user,
@@ -58,22 +159,27 @@ impl CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            inserted: false,
+            created: false,
+            backup: None,
+            resolved,
         };
-        if this.path.exists() {
+        let target = this.target().to_owned();
+        if target.exists() {
             // If the path exists, perhaps we can just skip this
-            let mut file = File::open(&this.path)
+            let mut file = File::open(&target)
                 .await
-                .map_err(|e| ActionErrorKind::Open(this.path.clone(), e))
+                .map_err(|e| ActionErrorKind::Open(target.clone(), e))
                 .map_err(Self::error)?;
 
             let metadata = file
                 .metadata()
                 .await
-                .map_err(|e| ActionErrorKind::GettingMetadata(this.path.clone(), e))
+                .map_err(|e| ActionErrorKind::GettingMetadata(target.clone(), e))
                 .map_err(Self::error)?;
 
             if !metadata.is_file() {
-                return Err(Self::error(ActionErrorKind::PathWasNotFile(this.path)));
+                return Err(Self::error(ActionErrorKind::PathWasNotFile(target)));
             }
 
             if let Some(mode) = mode {
@@ -85,7 +191,7 @@ impl CreateOrInsertIntoFile {
                 if discovered_mode != mode {
                     tracing::debug!(
                         "`{}` has mode `{}`, a mode of `{}` was expected",
-                        this.path.display(),
+                        target.display(),
                         discovered_mode,
                         mode,
                     );
@@ -104,7 +210,7 @@ impl CreateOrInsertIntoFile {
                 let found_uid = metadata.uid();
                 if found_uid != expected_uid.as_raw() {
                     return Err(Self::error(ActionErrorKind::PathUserMismatch(
-                        this.path.clone(),
+                        target.clone(),
                         found_uid,
                         expected_uid.as_raw(),
                     )));
@@ -121,7 +227,7 @@ impl CreateOrInsertIntoFile {
                 let found_gid = metadata.gid();
                 if found_gid != expected_gid.as_raw() {
                     return Err(Self::error(ActionErrorKind::PathGroupMismatch(
-                        this.path.clone(),
+                        target.clone(),
                         found_gid,
                         expected_gid.as_raw(),
                     )));
@@ -129,14 +235,14 @@ impl CreateOrInsertIntoFile {
             }
 
             // Does it have the right content?
-            let mut discovered_buf = String::new();
-            file.read_to_string(&mut discovered_buf)
+            let existing = this
+                .existing(&mut file)
                 .await
-                .map_err(|e| ActionErrorKind::Read(this.path.clone(), e))
+                .map_err(|e| ActionErrorKind::Read(target.clone(), e))
                 .map_err(Self::error)?;
 
-            if discovered_buf.contains(&this.buf) {
-                tracing::debug!("Inserting into `{}` already complete", this.path.display(),);
+            if this.buf.is_empty() || existing != Existing::Missing {
+                this.log_existing(existing);
                 return Ok(StatefulAction::completed(this));
             }
 
@@ -145,6 +251,118 @@ impl CreateOrInsertIntoFile {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// What `file` already holds of `buf`, profiles may be huge or not valid UTF-8 so it is searched in chunks
+    async fn existing(&self, file: &mut File) -> Result<Existing, std::io::Error> {
+        if rfind_in(file, self.buf.as_bytes()).await?.is_some() {
+            return Ok(Existing::Exact);
+        }
+        let fenced = self.buf.lines().any(|line| line == FENCE_START)
+            && self.buf.lines().any(|line| line == FENCE_END);
+        if fenced {
+            file.seek(SeekFrom::Start(0)).await?;
+            if has_fenced_block(&mut BufReader::new(file)).await? {
+                return Ok(Existing::Fenced);
+            }
+        }
+        Ok(Existing::Missing)
+    }
+
+    /// Copy the file to [`BACKUP_SUFFIX`] beside it with the same owner and mode, unless a previous run did
+    async fn back_up(&self) -> Result<Backup, ActionErrorKind> {
+        let target = self.target();
+        let mut file_name = target.file_name().unwrap_or_default().to_os_string();
+        file_name.push(BACKUP_SUFFIX);
+        let path = target.with_file_name(file_name);
+
+        let created = match tokio::fs::symlink_metadata(&path).await {
+            Ok(_) => {
+                tracing::debug!(
+                    "Keeping `{}` from a previous install as the backup of `{}`",
+                    path.display(),
+                    target.display()
+                );
+                false
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tokio::fs::copy(target, &path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Copy(target.to_owned(), path.clone(), e))?;
+                let metadata = tokio::fs::metadata(target)
+                    .await
+                    .map_err(|e| ActionErrorKind::GettingMetadata(target.to_owned(), e))?;
+                chown(
+                    &path,
+                    Some(Uid::from_raw(metadata.uid())),
+                    Some(Gid::from_raw(metadata.gid())),
+                )
+                .map_err(|e| ActionErrorKind::Chown(path.clone(), e))?;
+                true
+            },
+            Err(e) => return Err(ActionErrorKind::GettingMetadata(path, e)),
+        };
+        let sha256 = sha256_of(&path)
+            .await
+            .map_err(|e| ActionErrorKind::Read(path.clone(), e))?;
+        Ok(Backup {
+            path,
+            sha256,
+            created,
+        })
+    }
+
+    /// Put the backup back in place of the file, if it is still what was copied
+    async fn restore_backup(&self) -> Result<(), ActionErrorKind> {
+        let Some(backup) = &self.backup else {
+            return Ok(());
+        };
+        match sha256_of(&backup.path).await {
+            Ok(sha256) if sha256 == backup.sha256 => (),
+            Ok(_) => {
+                tracing::warn!(
+                    "Not restoring `{}` from `{}`, which changed since it was taken",
+                    self.path.display(),
+                    backup.path.display()
+                );
+                return Ok(());
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(ActionErrorKind::Read(backup.path.clone(), e)),
+        }
+        tracing::warn!(
+            "Could not find what was inserted into `{}`, which was changed since, restoring it from `{}`",
+            self.path.display(),
+            backup.path.display()
+        );
+        tokio::fs::copy(&backup.path, self.target())
+            .await
+            .map_err(|e| ActionErrorKind::Copy(backup.path.clone(), self.target().to_owned(), e))?;
+        Ok(())
+    }
+
+    /// Remove the backup this install took, once it is no longer needed
+    async fn remove_backup(&self) -> Result<(), ActionErrorKind> {
+        match &self.backup {
+            Some(backup) if backup.created => match remove_file(&backup.path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(ActionErrorKind::Remove(backup.path.clone(), e)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn log_existing(&self, existing: Existing) {
+        match existing {
+            Existing::Fenced => tracing::warn!(
+                "Not inserting into `{}`, it already has a `{FENCE_START}` block which differs from the installer's, leaving it as is",
+                self.path.display(),
+            ),
+            Existing::Exact | Existing::Missing => {
+                tracing::debug!("Inserting into `{}` already complete", self.path.display())
+            },
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -154,7 +372,10 @@ impl Action for CreateOrInsertIntoFile {
         ActionTag("create_or_insert_into_file")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Create or insert file `{}`", self.path.display())
+        format!(
+            "Create or insert file `{}`",
+            crate::lossless_path::Display(&self.path)
+        )
     }
 
     fn tracing_span(&self) -> Span {
@@ -182,6 +403,39 @@ impl Action for CreateOrInsertIntoFile {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        self.inserted = false;
+        self.created = false;
+        crate::path_policy::check(self.target()).map_err(Self::error)?;
+
+        let mut orig_file = match OpenOptions::new().read(true).open(self.target()).await {
+            Ok(f) => Some(f),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(Self::error(ActionErrorKind::Open(
+                    self.target().to_owned(),
+                    e,
+                )))
+            },
+        };
+
+        // Written since planning, say by a previous run of the installer
+        if let Some(ref mut orig_file) = orig_file {
+            let existing = self
+                .existing(orig_file)
+                .await
+                .map_err(|e| ActionErrorKind::Read(self.target().to_owned(), e))
+                .map_err(Self::error)?;
+            if existing != Existing::Missing {
+                self.log_existing(existing);
+                return Ok(());
+            }
+            orig_file
+                .seek(SeekFrom::Start(0))
+                .await
+                .map_err(|e| ActionErrorKind::Read(self.target().to_owned(), e))
+                .map_err(Self::error)?;
+            self.backup = Some(self.back_up().await.map_err(Self::error)?);
+        }
         let Self {
             path,
             user,
@@ -189,23 +443,17 @@ impl Action for CreateOrInsertIntoFile {
             mode,
             buf,
             position,
+            inserted,
+            created,
+            backup: _,
+            resolved,
         } = self;
-
-        let mut orig_file = match OpenOptions::new().read(true).open(&path).await {
-            Ok(f) => Some(f),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
-            Err(e) => return Err(Self::error(ActionErrorKind::Open(path.to_owned(), e))),
-        };
+        let path = resolved.as_ref().unwrap_or(path);
 
         // Create a temporary file in the same directory as the one
         // that the final file goes in, so that we can rename it
         // atomically
-        let parent_dir = path.parent().expect("File must be in a directory");
-        let mut temp_file_path = parent_dir.to_owned();
-        {
-            let mut rng = rand::thread_rng();
-            temp_file_path.push(format!("nix-installer-tmp.{}", rng.gen::<u32>()));
-        }
+        let temp_file_path = temp_path_beside(path);
         let mut temp_file = OpenOptions::new()
             .create(true)
             .write(true)
@@ -248,6 +496,11 @@ impl Action for CreateOrInsertIntoFile {
                     .map_err(Self::error)?;
             }
         }
+        temp_file
+            .flush()
+            .await
+            .map_err(|e| ActionErrorKind::Flush(temp_file_path.clone(), e))
+            .map_err(Self::error)?;
 
         let gid = if let Some(group) = group {
             Some(
@@ -286,7 +539,7 @@ impl Action for CreateOrInsertIntoFile {
                 .await
                 .map_err(|e| ActionErrorKind::SetPermissions(*mode, path.to_owned(), e))
                 .map_err(Self::error)?;
-        } else if let Some(original_file) = orig_file {
+        } else if let Some(original_file) = &orig_file {
             let original_file_mode = original_file
                 .metadata()
                 .await
@@ -307,6 +560,8 @@ impl Action for CreateOrInsertIntoFile {
             .await
             .map_err(|e| ActionErrorKind::Rename(path.to_owned(), temp_file_path.to_owned(), e))
             .map_err(Self::error)?;
+        *inserted = true;
+        *created = orig_file.is_none();
 
         Ok(())
     }
@@ -319,6 +574,10 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            inserted: _,
+            created: _,
+            backup: _,
+            resolved: _,
         } = &self;
         vec![ActionDescription::new(
             format!("Delete Nix related fragment from file `{}`", path.display()),
@@ -338,56 +597,252 @@ impl Action for CreateOrInsertIntoFile {
             mode: _,
             buf,
             position: _,
+            inserted,
+            created,
+            backup: _,
+            resolved,
         } = self;
-        // The user already deleted it
-        if !path.exists() {
+        let path = resolved.as_ref().unwrap_or(path).clone();
+        if !*inserted {
+            tracing::debug!(
+                "Leaving `{}` as is, the installer inserted nothing into it",
+                path.display()
+            );
             return Ok(());
         }
+        match Written::inspect(&path).await.map_err(Self::error)? {
+            // The user already deleted it
+            Written::Missing => return Ok(()),
+            // Whatever took its place since is someone else's, and must not be edited through
+            Written::Replaced(by) => {
+                crate::leftovers::report(
+                    path,
+                    format!("it was replaced by {by} since the install, what was inserted into it was not removed"),
+                );
+                return Ok(());
+            },
+            Written::File => (),
+        }
+        let buf = buf.clone();
 
-        let mut file = OpenOptions::new()
-            .create(false)
-            .write(true)
-            .read(true)
-            .open(&path)
+        let mut file = File::open(&path)
             .await
             .map_err(|e| ActionErrorKind::Open(path.to_owned(), e))
             .map_err(Self::error)?;
-
-        let mut file_contents = String::default();
-        file.read_to_string(&mut file_contents)
+        let len = file
+            .metadata()
+            .await
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.to_owned(), e))
+            .map_err(Self::error)?
+            .len();
+        // Wherever it is, something else may have appended after it since
+        let found = rfind_in(&mut file, buf.as_bytes())
             .await
             .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))
             .map_err(Self::error)?;
+        drop(file);
 
-        if let Some(start) = file_contents.rfind(buf.as_str()) {
-            let end = start + buf.len();
-            file_contents.replace_range(start..end, "")
+        match found {
+            None if self.backup.is_some() => {
+                self.restore_backup().await.map_err(Self::error)?;
+            },
+            None => {
+                crate::leftovers::report(
+                    path,
+                    "it was edited since the install, what was inserted into it could not be found to remove",
+                );
+                return Ok(());
+            },
+            // Nothing else was added to the file created for it since
+            Some(_) if *created && len == buf.len() as u64 => {
+                remove_file(&path)
+                    .await
+                    .map_err(|e| ActionErrorKind::Remove(path.to_owned(), e))
+                    .map_err(Self::error)?;
+            },
+            Some(start) => {
+                // Everything but our fragment is left untouched, byte for byte
+                splice_out(&path, start, start + buf.len() as u64)
+                    .await
+                    .map_err(Self::error)?;
+            },
         }
+        self.remove_backup().await.map_err(Self::error)?;
+        Ok(())
+    }
+}
 
-        if file_contents.is_empty() {
-            remove_file(&path)
-                .await
-                .map_err(|e| ActionErrorKind::Remove(path.to_owned(), e))
-                .map_err(Self::error)?;
-        } else {
-            file.seek(SeekFrom::Start(0))
-                .await
-                .map_err(|e| ActionErrorKind::Seek(path.to_owned(), e))
-                .map_err(Self::error)?;
-            file.set_len(0)
-                .await
-                .map_err(|e| ActionErrorKind::Truncate(path.to_owned(), e))
-                .map_err(Self::error)?;
-            file.write_all(file_contents.as_bytes())
-                .await
-                .map_err(|e| ActionErrorKind::Write(path.to_owned(), e))
-                .map_err(Self::error)?;
-            file.flush()
+/// The file the symlink at `path` leads to, `None` if `path` is not a symlink
+async fn resolve_symlink(path: &Path) -> Result<Option<PathBuf>, ActionErrorKind> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_symlink() => (),
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_owned(), e)),
+    }
+    let target = match tokio::fs::canonicalize(path).await {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let target = tokio::fs::read_link(path)
                 .await
-                .map_err(|e| ActionErrorKind::Flush(path.to_owned(), e))
-                .map_err(Self::error)?;
+                .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))?;
+            return Err(CreateOrInsertIntoFileError::UnsafeSymlink {
+                path: path.to_owned(),
+                target,
+                dangling: true,
+            }
+            .into());
+        },
+        Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_owned(), e)),
+    };
+
+    // Compared canonicalized, as on macOS `/etc` is itself a symlink to `/private/etc`
+    let roots = SYMLINK_TARGET_ROOTS
+        .iter()
+        .map(Path::new)
+        .chain(path.parent())
+        .filter_map(|root| std::fs::canonicalize(root).ok());
+    for root in roots {
+        if target.starts_with(root) {
+            return Ok(Some(target));
+        }
+    }
+    Err(CreateOrInsertIntoFileError::UnsafeSymlink {
+        path: path.to_owned(),
+        target,
+        dangling: false,
+    }
+    .into())
+}
+
+/// Replace the file at `path` with one without the bytes between `start` and `end`, atomically
+///
+/// The rest is copied a chunk at a time to a temporary file with the same owner and mode, which is
+/// renamed over it.
+async fn splice_out(path: &Path, start: u64, end: u64) -> Result<(), ActionErrorKind> {
+    let mut file = File::open(path)
+        .await
+        .map_err(|e| ActionErrorKind::Open(path.to_owned(), e))?;
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_owned(), e))?;
+
+    let temp_file_path = temp_path_beside(path);
+    let mut temp_file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(&temp_file_path)
+        .await
+        .map_err(|e| ActionErrorKind::Open(temp_file_path.clone(), e))?;
+    let copied = async {
+        tokio::io::copy(&mut (&mut file).take(start), &mut temp_file).await?;
+        file.seek(SeekFrom::Start(end)).await?;
+        tokio::io::copy(&mut file, &mut temp_file).await?;
+        temp_file.flush().await
+    }
+    .await;
+    if let Err(e) = copied {
+        let _ = remove_file(&temp_file_path).await;
+        return Err(ActionErrorKind::Copy(
+            path.to_owned(),
+            temp_file_path.clone(),
+            e,
+        ));
+    }
+
+    chown(
+        &temp_file_path,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+    )
+    .map_err(|e| ActionErrorKind::Chown(temp_file_path.clone(), e))?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    tokio::fs::set_permissions(&temp_file_path, PermissionsExt::from_mode(mode))
+        .await
+        .map_err(|e| ActionErrorKind::SetPermissions(mode, temp_file_path.clone(), e))?;
+    tokio::fs::rename(&temp_file_path, path)
+        .await
+        .map_err(|e| ActionErrorKind::Rename(temp_file_path.clone(), path.to_owned(), e))
+}
+
+/// The SHA-256 of the file at `path`, in lowercase hex
+async fn sha256_of(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = File::open(path).await?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        context.update(&chunk[..read]);
+    }
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Find the offset of the last occurrence of `needle`, holding at most about [`CHUNK_SIZE`] bytes in memory
+async fn rfind_in(
+    reader: &mut (impl AsyncRead + Unpin),
+    needle: &[u8],
+) -> Result<Option<u64>, std::io::Error> {
+    if needle.is_empty() {
+        return Ok(None);
+    }
+    // Keep the tail of the previous chunk around so matches spanning two chunks are found
+    let overlap = needle.len() - 1;
+    let mut window = Vec::with_capacity(CHUNK_SIZE + overlap);
+    let mut window_offset = 0_u64;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut found = None;
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        window.extend_from_slice(&chunk[..read]);
+        if let Some(position) = window
+            .windows(needle.len())
+            .rposition(|candidate| candidate == needle)
+        {
+            found = Some(window_offset + position as u64);
+        }
+        if window.len() > overlap {
+            let drained = window.len() - overlap;
+            window.drain(..drained);
+            window_offset += drained as u64;
+        }
+    }
+    Ok(found)
+}
+
+/// If `reader` has a line [`FENCE_START`] followed by a line [`FENCE_END`], reading it a line at a time
+async fn has_fenced_block(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+) -> Result<bool, std::io::Error> {
+    let mut line = vec![];
+    let mut started = false;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(false);
+        }
+        let end = line
+            .iter()
+            .rposition(|byte| !byte.is_ascii_whitespace())
+            .map_or(0, |last| last + 1);
+        let trimmed = &line[..end];
+        if trimmed == FENCE_START.as_bytes() {
+            started = true;
+        } else if started && trimmed == FENCE_END.as_bytes() {
+            return Ok(true);
         }
-        Ok(())
     }
 }
 
@@ -420,6 +875,65 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn leaves_file_changed_since() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("leaves_file_changed_since");
+        // The copy managed elsewhere has what the installer inserted, and must survive anyway
+        let managed = temp_dir.path().join("managed");
+        write(&managed, "Before\nTest").await?;
+
+        for replacement in ["edited", "a symlink", "a directory"] {
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                "Test".into(),
+                Position::End,
+            )
+            .await?;
+            action.try_execute().await?;
+
+            match replacement {
+                "edited" => write(&test_file, "Rewritten by configuration management").await?,
+                "a symlink" => {
+                    remove_file(&test_file).await?;
+                    tokio::fs::symlink(&managed, &test_file).await?;
+                },
+                _ => {
+                    remove_file(&test_file).await?;
+                    tokio::fs::create_dir(&test_file).await?;
+                },
+            }
+
+            let (reverted, leftovers) = crate::leftovers::collect(action.try_revert()).await;
+            reverted?;
+
+            assert_eq!(leftovers.len(), 1, "{replacement}");
+            assert_eq!(leftovers[0].path, test_file);
+            assert!(
+                leftovers[0].reason.contains(replacement),
+                "{}",
+                leftovers[0]
+            );
+            match replacement {
+                "edited" => {
+                    assert_eq!(
+                        read_to_string(&test_file).await?,
+                        "Rewritten by configuration management"
+                    );
+                    remove_file(&test_file).await?;
+                },
+                "a symlink" => remove_file(&test_file).await?,
+                _ => tokio::fs::remove_dir(&test_file).await?,
+            }
+            assert_eq!(read_to_string(&managed).await?, "Before\nTest");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn edits_and_reverts_file() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -455,6 +969,37 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn only_removes_files_it_created() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let empty_file = temp_dir.path().join("empty");
+        write(&empty_file, "").await?;
+        let created_file = temp_dir.path().join("created");
+
+        for test_file in [&empty_file, &created_file] {
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file,
+                None,
+                None,
+                None,
+                "Test".into(),
+                Position::End,
+            )
+            .await?;
+            action.try_execute().await?;
+            assert_eq!(read_to_string(test_file).await?, "Test");
+            action.try_revert().await?;
+        }
+
+        assert_eq!(
+            read_to_string(&empty_file).await?,
+            "",
+            "The file was empty before, not missing"
+        );
+        assert!(!created_file.exists(), "The installer created the file");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn recognizes_existing_containing_exact_contents_and_reverts_it() -> eyre::Result<()> {
         let temp_dir = tempfile::tempdir()?;
@@ -549,7 +1094,405 @@ mod test {
 
         action.try_revert().await?;
 
-        assert!(!test_file.exists(), "File should have been deleted");
+        // It already held the content, which it still does
+        assert!(test_file.exists(), "File should have not been deleted");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_fragment_written_since_planning() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir
+            .path()
+            .join("skips_fragment_written_since_planning");
+        let fragment =
+            "\n# Nix\n. '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n# End Nix\n";
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            fragment.into(),
+            Position::Beginning,
+        )
+        .await?;
+        let existing = format!("{fragment}alias ll='ls -l'\n");
+        write(&test_file, &existing).await?;
+
+        action.try_execute().await?;
+        assert_eq!(read_to_string(&test_file).await?, existing);
+        action.try_revert().await?;
+        assert_eq!(read_to_string(&test_file).await?, existing);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_differing_fenced_block() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("leaves_differing_fenced_block");
+        let existing = "# Nix\n. /opt/nix/etc/profile.d/nix.sh\n# End Nix\n";
+        write(&test_file, existing).await?;
+        let fragment =
+            "\n# Nix\n. '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n# End Nix\n";
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            fragment.into(),
+            Position::Beginning,
+        )
+        .await?;
+        assert_eq!(action.state, crate::action::ActionState::Completed);
+        action.try_revert().await?;
+        assert_eq!(read_to_string(&test_file).await?, existing);
+
+        // Unfenced fragments are inserted regardless
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "/nix/var/nix/profiles/default/bin\n".into(),
+            Position::End,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(
+            read_to_string(&test_file).await?,
+            format!("{existing}/nix/var/nix/profiles/default/bin\n")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backs_up_and_restores_rewritten_file() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("zshrc");
+        let backup = temp_dir.path().join("zshrc.backup-before-nix");
+        let original = "setopt autocd\n";
+        write(&test_file, original).await?;
+        tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o640)).await?;
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "\n# Nix\n# End Nix\n".into(),
+            Position::End,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(read_to_string(&backup).await?, original);
+        assert_eq!(
+            tokio::fs::metadata(&backup).await?.permissions().mode() & 0o777,
+            0o640
+        );
+
+        // Other tooling rewrote the block
+        write(&test_file, format!("{original}\n# Nix\n\n# End Nix\n")).await?;
+        action.try_revert().await?;
+        assert_eq!(read_to_string(&test_file).await?, original);
+        assert!(!backup.exists(), "The backup should have been removed");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_existing_backup() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("bashrc");
+        let backup = temp_dir.path().join("bashrc.backup-before-nix");
+        write(&test_file, "shopt -s histappend\n").await?;
+        // From a previous install
+        write(&backup, "shopt -s checkwinsize\n").await?;
+
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            "\n# Nix\n# End Nix\n".into(),
+            Position::End,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(read_to_string(&backup).await?, "shopt -s checkwinsize\n");
+
+        action.try_revert().await?;
+        assert_eq!(read_to_string(&test_file).await?, "shopt -s histappend\n");
+        assert_eq!(read_to_string(&backup).await?, "shopt -s checkwinsize\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reverts_block_wherever_it_is() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("profile");
+        let fragment = "\n# Nix\n# End Nix\n";
+        for appended_since in ["", "# Added by another installer\nexport EDITOR=vi\n"] {
+            write(&test_file, "umask 022\n").await?;
+            tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o640)).await?;
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                fragment.into(),
+                Position::End,
+            )
+            .await?;
+            action.try_execute().await?;
+            let inserted = read_to_string(&test_file).await?;
+            write(&test_file, format!("{inserted}{appended_since}")).await?;
+
+            action.try_revert().await?;
+            assert_eq!(
+                read_to_string(&test_file).await?,
+                format!("umask 022\n{appended_since}")
+            );
+            assert_eq!(
+                tokio::fs::metadata(&test_file).await?.permissions().mode() & 0o777,
+                0o640
+            );
+        }
+        // Only what was reverted is left, no backups or temporary files
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_file_without_block() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        // Rewritten without it (even to nothing) and deleted entirely, each is left as is
+        for contents in [Some("export PATH=/opt/bin:$PATH\n"), Some(""), None] {
+            let test_file = temp_dir.path().join("leaves_file_without_block");
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                "\n# Nix\n# End Nix\n".into(),
+                Position::End,
+            )
+            .await?;
+            action.try_execute().await?;
+
+            match contents {
+                Some(contents) => {
+                    write(&test_file, contents).await?;
+                    action.try_revert().await?;
+                    assert_eq!(read_to_string(&test_file).await?, contents);
+                    remove_file(&test_file).await?;
+                },
+                None => {
+                    remove_file(&test_file).await?;
+                    action.try_revert().await?;
+                    assert!(!test_file.exists());
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn preserves_invalid_utf8_byte_for_byte() -> eyre::Result<()> {
+        const INVALID_UTF8: &[u8] =
+            include_bytes!("../../../tests/fixtures/shell-profile/invalid-utf8.bashrc");
+
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("preserves_invalid_utf8_byte_for_byte");
+        let fragment =
+            "\n# Nix\n. '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n# End Nix\n";
+
+        for position in [Position::Beginning, Position::End] {
+            write(&test_file, INVALID_UTF8).await?;
+
+            let mut action = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                fragment.into(),
+                position.clone(),
+            )
+            .await?;
+            action.try_execute().await?;
+
+            let inserted = tokio::fs::read(&test_file).await?;
+            let expected = match position {
+                Position::Beginning => [fragment.as_bytes(), INVALID_UTF8].concat(),
+                Position::End => [INVALID_UTF8, fragment.as_bytes()].concat(),
+            };
+            assert_eq!(inserted, expected);
+
+            // The fragment is found despite the rest of the file not being UTF-8
+            let replanned = CreateOrInsertIntoFile::plan(
+                test_file.clone(),
+                None,
+                None,
+                None,
+                fragment.into(),
+                position,
+            )
+            .await?;
+            assert_eq!(replanned.state, crate::action::ActionState::Completed);
+
+            action.try_revert().await?;
+            assert_eq!(tokio::fs::read(&test_file).await?, INVALID_UTF8);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handles_large_file() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("handles_large_file");
+        // Tens of megabytes of generated shell, spanning many chunks
+        let original = (0..1_000_000)
+            .map(|line| format!("export GENERATED_{line}=\"value\"\n"))
+            .collect::<String>()
+            .into_bytes();
+        assert!(original.len() > 30 * 1024 * 1024);
+        write(&test_file, &original).await?;
+
+        let fragment = "\n# Nix\n# End Nix\n";
+        let mut action = CreateOrInsertIntoFile::plan(
+            test_file.clone(),
+            None,
+            None,
+            None,
+            fragment.into(),
+            Position::End,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(
+            tokio::fs::metadata(&test_file).await?.len(),
+            (original.len() + fragment.len()) as u64
+        );
+
+        action.try_revert().await?;
+        assert!(tokio::fs::read(&test_file).await? == original);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finds_fragment_spanning_chunks() -> eyre::Result<()> {
+        let needle = b"# Nix fragment";
+        let mut haystack = vec![b'a'; CHUNK_SIZE - 3];
+        haystack.extend_from_slice(needle);
+        haystack.extend_from_slice(b"trailing");
+
+        let found = rfind_in(&mut haystack.as_slice(), needle).await?;
+        assert_eq!(found, Some((CHUNK_SIZE - 3) as u64));
+        assert_eq!(rfind_in(&mut haystack.as_slice(), b"missing").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inserts_into_symlink_targets_and_reverts_them() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        tokio::fs::create_dir(temp_dir.path().join("managed")).await?;
+        let target = temp_dir.path().join("managed/zshrc");
+        let absolute_target = std::fs::canonicalize(temp_dir.path())?.join("managed/zshrc");
+        for (link_name, link_target) in [
+            ("relative", PathBuf::from("managed/zshrc")),
+            ("absolute", absolute_target.clone()),
+        ] {
+            let test_content = "Some managed content\n";
+            write(&target, test_content).await?;
+            let link = temp_dir.path().join(link_name);
+            tokio::fs::symlink(&link_target, &link).await?;
+
+            let action = CreateOrInsertIntoFile::plan(
+                link.clone(),
+                None,
+                None,
+                None,
+                "Test\n".into(),
+                Position::End,
+            )
+            .await?;
+            assert_eq!(action.action.resolved.as_ref(), Some(&absolute_target));
+            // The receipt records both, so revert edits the same file
+            let mut action: StatefulAction<CreateOrInsertIntoFile> =
+                serde_json::from_str(&serde_json::to_string(&action)?)?;
+
+            action.try_execute().await?;
+            assert!(tokio::fs::symlink_metadata(&link)
+                .await?
+                .file_type()
+                .is_symlink());
+            assert_eq!(
+                read_to_string(&target).await?,
+                format!("{test_content}Test\n")
+            );
+
+            action.try_revert().await?;
+            assert!(tokio::fs::symlink_metadata(&link)
+                .await?
+                .file_type()
+                .is_symlink());
+            assert_eq!(read_to_string(&target).await?, test_content);
+            tokio::fs::remove_file(&link).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_unsafe_symlinks() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        tokio::fs::create_dir(temp_dir.path().join("profiles")).await?;
+        let outside = temp_dir.path().join("outside");
+        write(&outside, "Some content").await?;
+        let outside_link = temp_dir.path().join("profiles/outside");
+        tokio::fs::symlink(&outside, &outside_link).await?;
+        let dangling_link = temp_dir.path().join("profiles/dangling");
+        tokio::fs::symlink("missing", &dangling_link).await?;
+
+        for (link, expected_dangling) in [(&outside_link, false), (&dangling_link, true)] {
+            let err = match CreateOrInsertIntoFile::plan(
+                link,
+                None,
+                None,
+                None,
+                "Test".into(),
+                Position::End,
+            )
+            .await
+            {
+                Err(err) => err,
+                Ok(_) => return Err(eyre!("Should have refused `{}`", link.display())),
+            };
+            match err.kind() {
+                ActionErrorKind::Custom(custom) => match custom
+                    .downcast_ref::<CreateOrInsertIntoFileError>()
+                {
+                    Some(CreateOrInsertIntoFileError::UnsafeSymlink { path, dangling, .. }) => {
+                        assert_eq!(path, link);
+                        assert_eq!(*dangling, expected_dangling);
+                    },
+                    _ => return Err(eyre!("Should have returned an UnsafeSymlink error")),
+                },
+                _ => return Err(eyre!("Should have returned an UnsafeSymlink error")),
+            }
+        }
+        assert_eq!(read_to_string(&outside).await?, "Some content");
+        assert!(!temp_dir.path().join("profiles/missing").exists());
 
         Ok(())
     }
@@ -14,6 +14,7 @@ use tracing::{span, Span};
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
+use crate::temp_artifacts;
 
 /// The `nix.conf` configuration names that are safe to merge.
 // FIXME(@cole-h): make configurable by downstream users?
@@ -44,6 +45,7 @@ impl From<CreateOrMergeNixConfigError> for ActionErrorKind {
 /// Create or merge an existing `nix.conf` at the specified path.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateOrMergeNixConfig {
+    #[serde(with = "crate::lossless_path")]
     pub(crate) path: PathBuf,
     pending_nix_config: NixConfig,
 }
@@ -55,6 +57,7 @@ impl CreateOrMergeNixConfig {
         pending_nix_config: NixConfig,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let path = path.as_ref().to_path_buf();
+        crate::path_policy::check(&path).map_err(Self::error)?;
 
         let this = Self {
             path,
@@ -183,7 +186,7 @@ impl Action for CreateOrMergeNixConfig {
     fn tracing_synopsis(&self) -> String {
         format!(
             "Merge or create nix.conf file `{path}`",
-            path = self.path.display(),
+            path = crate::lossless_path::Display(&self.path),
         )
     }
 
@@ -233,6 +236,7 @@ impl Action for CreateOrMergeNixConfig {
             path,
             pending_nix_config,
         } = self;
+        crate::path_policy::check(path).map_err(Self::error)?;
 
         if tracing::enabled!(tracing::Level::TRACE) {
             let span = tracing::Span::current();
@@ -256,6 +260,7 @@ impl Action for CreateOrMergeNixConfig {
             let mut rng = rand::thread_rng();
             temp_file_path.push(format!("nix-installer-tmp.{}", rng.gen::<u32>()));
         }
+        temp_artifacts::register(&temp_file_path);
         let mut temp_file = OpenOptions::new()
             .create(true)
             .write(true)
@@ -425,6 +430,10 @@ impl Action for CreateOrMergeNixConfig {
             .write_all(new_config.as_bytes())
             .await
             .map_err(|e| Self::error(ActionErrorKind::Write(temp_file_path.clone(), e)))?;
+        temp_file
+            .flush()
+            .await
+            .map_err(|e| Self::error(ActionErrorKind::Flush(temp_file_path.clone(), e)))?;
         tokio::fs::set_permissions(&temp_file_path, PermissionsExt::from_mode(NIX_CONF_MODE))
             .await
             .map_err(|e| {
@@ -447,6 +456,7 @@ impl Action for CreateOrMergeNixConfig {
                     e,
                 ))
             })?;
+        temp_artifacts::unregister(&temp_file_path);
 
         Ok(())
     }
@@ -5,6 +5,7 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -13,7 +14,7 @@ Create an operating system level user in the given group
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateUser {
-    name: String,
+    pub(crate) name: String,
     uid: u32,
     groupname: String,
     gid: u32,
@@ -40,10 +41,10 @@ impl CreateUser {
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("useradd").is_ok() || which::which("adduser").is_ok()) {
+                if !(tools::find("useradd").is_some() || tools::find("adduser").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingUserCreationCommand));
                 }
-                if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
+                if !(tools::find("userdel").is_some() || tools::find("deluser").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
                 }
             },
@@ -76,6 +77,23 @@ impl CreateUser {
 
         Ok(StatefulAction::uncompleted(this))
     }
+
+    /// The user `name` as identity management provisioned it, which is neither created nor removed
+    pub(crate) fn provisioned(
+        name: String,
+        uid: u32,
+        groupname: String,
+        gid: u32,
+        comment: String,
+    ) -> StatefulAction<Self> {
+        StatefulAction::skipped(Self {
+            name,
+            uid,
+            groupname,
+            gid,
+            comment,
+        })
+    }
 }
 
 #[async_trait::async_trait]
@@ -203,9 +221,9 @@ impl Action for CreateUser {
                 .map_err(Self::error)?;
             },
             _ => {
-                if which::which("useradd").is_ok() {
+                if tools::find("useradd").is_some() {
                     execute_command(
-                        Command::new("useradd")
+                        tools::command("useradd")
                             .process_group(0)
                             .args([
                                 "--home-dir",
@@ -230,9 +248,9 @@ impl Action for CreateUser {
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("adduser").is_ok() {
+                } else if tools::find("adduser").is_some() {
                     execute_command(
-                        Command::new("adduser")
+                        tools::command("adduser")
                             .process_group(0)
                             .args([
                                 "--home",
@@ -293,8 +311,7 @@ impl Action for CreateUser {
                 command.process_group(0);
                 command.stdin(std::process::Stdio::null());
 
-                let output = command
-                    .output()
+                let output = crate::command_runner::output(&mut command)
                     .await
                     .map_err(|e| ActionErrorKind::command(&command, e))
                     .map_err(Self::error)?;
@@ -315,18 +332,18 @@ impl Action for CreateUser {
                 }
             },
             _ => {
-                if which::which("userdel").is_ok() {
+                if tools::find("userdel").is_some() {
                     execute_command(
-                        Command::new("userdel")
+                        tools::command("userdel")
                             .process_group(0)
                             .arg(&self.name)
                             .stdin(std::process::Stdio::null()),
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("deluser").is_ok() {
+                } else if tools::find("deluser").is_some() {
                     execute_command(
-                        Command::new("deluser")
+                        tools::command("deluser")
                             .process_group(0)
                             .arg(&self.name)
                             .stdin(std::process::Stdio::null()),
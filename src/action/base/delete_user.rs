@@ -5,6 +5,7 @@ use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -24,7 +25,7 @@ impl DeleteUser {
         match OperatingSystem::host() {
             OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin => (),
             _ => {
-                if !(which::which("userdel").is_ok() || which::which("deluser").is_ok()) {
+                if !(tools::find("userdel").is_some() || tools::find("deluser").is_some()) {
                     return Err(Self::error(ActionErrorKind::MissingUserDeletionCommand));
                 }
             },
@@ -89,8 +90,7 @@ impl Action for DeleteUser {
                 command.process_group(0);
                 command.stdin(std::process::Stdio::null());
 
-                let output = command
-                    .output()
+                let output = crate::command_runner::output(&mut command)
                     .await
                     .map_err(|e| ActionErrorKind::command(&command, e))
                     .map_err(Self::error)?;
@@ -111,18 +111,18 @@ impl Action for DeleteUser {
                 }
             },
             _ => {
-                if which::which("userdel").is_ok() {
+                if tools::find("userdel").is_some() {
                     execute_command(
-                        Command::new("userdel")
+                        tools::command("userdel")
                             .process_group(0)
                             .arg(&self.name)
                             .stdin(std::process::Stdio::null()),
                     )
                     .await
                     .map_err(Self::error)?;
-                } else if which::which("deluser").is_ok() {
+                } else if tools::find("deluser").is_some() {
                     execute_command(
-                        Command::new("deluser")
+                        tools::command("deluser")
                             .process_group(0)
                             .arg(&self.name)
                             .stdin(std::process::Stdio::null()),
@@ -1,15 +1,82 @@
-use std::path::PathBuf;
+use std::{
+    io::{Read, SeekFrom},
+    net::{IpAddr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use bytes::{Buf, Bytes};
-use reqwest::Url;
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_RANGE, DATE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    StatusCode, Url,
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+    task::JoinSet,
+};
 use tracing::{span, Span};
 
 use crate::{
     action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
-    parse_ssl_cert,
-    settings::UrlOrPath,
+    http, parse_ssl_cert,
+    planner::{format_bytes, SCRATCH_SPACE_NEEDED},
+    progress::{ProgressUnit, Tracker},
+    settings::{
+        IpFamily, UrlOrPath, DEFAULT_DOWNLOAD_ATTEMPTS, DEFAULT_DOWNLOAD_CONNECTIONS,
+        DEFAULT_MAX_BUFFER_SIZE, NIX_AARCH64_DARWIN_URL, NIX_AARCH64_LINUX_URL, NIX_I686_LINUX_URL,
+        NIX_X64_64_DARWIN_URL, NIX_X64_64_LINUX_URL,
+    },
+    temp_artifacts,
 };
 
+/// Decoding the release tarballs (`xz -6`) needs ~9 MiB, this leaves room for up to `xz -9e` (~65 MiB)
+const XZ_DECODER_MEMORY_LIMIT: u64 = 96 * 1024 * 1024;
+/// What every xz stream starts with
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Where downloaded tarballs are streamed to inside `dest`, hidden from the `nix-*` glob of [`MoveUnpackedNix`](crate::action::base::MoveUnpackedNix)
+const DOWNLOAD_FILE_NAME: &str = ".nix-download.tar.xz";
+/// Where the ranges of a multi-connection download completed so far are recorded, so a retry only fetches the rest
+const DOWNLOAD_PROGRESS_FILE_NAME: &str = ".nix-download.ranges.json";
+/// What downloads are reported as, see [`crate::progress`]
+const DOWNLOAD_TASK: &str = "Downloading Nix";
+/// How long to wait before the second attempt at a download, doubled for each one after
+const DOWNLOAD_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// Where the store paths of the tarball end up, the only place its absolute symlinks may point
+const STORE_DIR: &str = "/nix/store/";
+/// How long to wait on the size of a tarball while planning, it is only an estimate
+const SIZE_ESTIMATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How far the system clock may be off before certificates rejected by their dates are blamed on it
+const CLOCK_SKEW_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+/// How long to wait on the `Date` of a server when checking the system clock against it
+const CLOCK_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the system clock is checked while waiting for it to be synchronized
+const CLOCK_SYNC_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Files the system clock cannot be earlier than the modification time of, as `systemd-timesyncd`
+/// assumes without a network
+const CLOCK_FLOOR_PATHS: &[&str] = &["/var/lib/systemd/timesync/clock", "/usr/lib/clock-epoch"];
+
+const MIB: u64 = 1024 * 1024;
+/// Approximate sizes of the default tarballs (rounded up), to download and once unpacked into `/nix/store`
+const KNOWN_SIZES: &[(&str, u64, u64)] = &[
+    (NIX_X64_64_LINUX_URL, 24 * MIB, 150 * MIB),
+    (NIX_I686_LINUX_URL, 23 * MIB, 145 * MIB),
+    (NIX_AARCH64_LINUX_URL, 22 * MIB, 145 * MIB),
+    (NIX_X64_64_DARWIN_URL, 21 * MIB, 120 * MIB),
+    (NIX_AARCH64_DARWIN_URL, 20 * MIB, 115 * MIB),
+];
+
+fn default_max_buffer_size() -> usize {
+    DEFAULT_MAX_BUFFER_SIZE
+}
+
+fn default_download_connections() -> u8 {
+    DEFAULT_DOWNLOAD_CONNECTIONS
+}
+
+fn default_download_attempts() -> u8 {
+    DEFAULT_DOWNLOAD_ATTEMPTS
+}
+
 /**
 Fetch a URL to the given path
 */
@@ -19,6 +86,70 @@ pub struct FetchAndUnpackNix {
     dest: PathBuf,
     proxy: Option<Url>,
     ssl_cert_file: Option<PathBuf>,
+    #[serde(default)]
+    preferred_ip_family: Option<IpFamily>,
+    #[serde(default = "default_max_buffer_size")]
+    max_buffer_size: usize,
+    #[serde(default = "default_download_connections")]
+    download_connections: u8,
+    /// How many times to try downloading, retrying only failures which may be transient
+    #[serde(default = "default_download_attempts")]
+    download_attempts: u8,
+    #[serde(default)]
+    size_estimate: SizeEstimate,
+    /// The SHA-256 of the tarball, in lowercase hex
+    #[serde(default)]
+    sha256: Option<String>,
+    /// The SHA-256 the tarball was found to have, in lowercase hex, `None` until it is read
+    #[serde(default)]
+    observed_sha256: Option<String>,
+    /// Seconds to wait for the system clock to be synchronized when it is too far off to fetch over TLS
+    #[serde(default)]
+    wait_for_clock_sync: Option<u64>,
+}
+
+/// Roughly how much Nix takes to download and once unpacked, `None` where that is unknown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct SizeEstimate {
+    pub download: Option<u64>,
+    pub unpacked: Option<u64>,
+}
+
+impl SizeEstimate {
+    /// The sizes of one of the default tarballs, without asking the network
+    pub(crate) fn known(url_or_path: &UrlOrPath) -> Self {
+        let UrlOrPath::Url(url) = url_or_path else {
+            return Self::default();
+        };
+        KNOWN_SIZES
+            .iter()
+            .find(|(known, _, _)| url.as_str() == *known)
+            .map(|(_, download, unpacked)| Self {
+                download: Some(*download),
+                unpacked: Some(*unpacked),
+            })
+            .unwrap_or_default()
+    }
+
+    /// The free space needed to unpack Nix, what the preflight check enforces
+    ///
+    /// This is more than [`SCRATCH_SPACE_NEEDED`] only when the tarball and its contents are known to be.
+    pub(crate) fn scratch_needed(&self) -> u64 {
+        match (self.download, self.unpacked) {
+            (Some(download), Some(unpacked)) => SCRATCH_SPACE_NEEDED.max(download + unpacked),
+            _ => SCRATCH_SPACE_NEEDED,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let show = |bytes: Option<u64>| bytes.map(format_bytes).unwrap_or("unknown".into());
+        format!(
+            "download: {}, unpacked: {}, scratch space: {}",
+            show(self.download),
+            show(self.unpacked),
+            format_bytes(self.scratch_needed()),
+        )
+    }
 }
 
 impl FetchAndUnpackNix {
@@ -28,6 +159,9 @@ impl FetchAndUnpackNix {
         dest: PathBuf,
         proxy: Option<Url>,
         ssl_cert_file: Option<PathBuf>,
+        preferred_ip_family: Option<IpFamily>,
+        max_buffer_size: usize,
+        download_connections: u8,
     ) -> Result<StatefulAction<Self>, ActionError> {
         // TODO(@hoverbear): Check URL exists?
         // TODO(@hoverbear): Check tempdir exists
@@ -38,6 +172,9 @@ impl FetchAndUnpackNix {
                 _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
             }
         }
+        if let Some(path) = local_path(&url_or_path) {
+            check_xz(&path).map_err(Self::error)?;
+        }
 
         if let Some(proxy) = &proxy {
             match proxy.scheme() {
@@ -55,9 +192,101 @@ impl FetchAndUnpackNix {
             dest,
             proxy,
             ssl_cert_file,
+            preferred_ip_family,
+            max_buffer_size,
+            download_connections: download_connections.max(1),
+            download_attempts: DEFAULT_DOWNLOAD_ATTEMPTS,
+            size_estimate: SizeEstimate::default(),
+            sha256: None,
+            observed_sha256: None,
+            wait_for_clock_sync: None,
         }
         .into())
     }
+
+    /// Wait up to `seconds` for the system clock to be synchronized (such as by cloud-init) and
+    /// retry once, when fetching fails as it is too far off
+    pub fn set_wait_for_clock_sync(&mut self, seconds: Option<u64>) {
+        self.wait_for_clock_sync = seconds;
+    }
+
+    /// Try downloading up to `attempts` times, backing off exponentially between them
+    pub fn set_download_attempts(&mut self, attempts: u8) {
+        self.download_attempts = attempts.max(1);
+    }
+
+    /// Verify the tarball has the SHA-256 `sha256` (in hex), reading it from stdin requires one
+    pub fn set_sha256(&mut self, sha256: Option<String>) -> Result<(), ActionError> {
+        self.sha256 = match sha256 {
+            Some(sha256) => Some(parse_sha256(&sha256).map_err(Self::error)?),
+            None => None,
+        };
+        if self.url_or_path == UrlOrPath::Stdin && self.sha256.is_none() {
+            return Err(Self::error(FetchUrlError::StdinWithoutSha256));
+        }
+        Ok(())
+    }
+
+    /// Estimate how much Nix takes to download and once unpacked, from the built in table or the
+    /// size of the tarball, leaving what cannot be determined (such as while offline) unknown
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub(crate) async fn estimate_size(&mut self) {
+        let known = SizeEstimate::known(&self.url_or_path);
+        self.size_estimate = if known.download.is_some() {
+            known
+        } else {
+            SizeEstimate {
+                download: self.tarball_size().await,
+                unpacked: None,
+            }
+        };
+        tracing::debug!(
+            "Estimated the size of Nix ({})",
+            self.size_estimate.describe()
+        );
+    }
+
+    pub fn size_estimate(&self) -> SizeEstimate {
+        self.size_estimate
+    }
+
+    async fn tarball_size(&self) -> Option<u64> {
+        let url = match &self.url_or_path {
+            UrlOrPath::Path(path) => return path.metadata().ok().map(|metadata| metadata.len()),
+            UrlOrPath::Url(url) if url.scheme() == "file" => {
+                return url
+                    .to_file_path()
+                    .ok()?
+                    .metadata()
+                    .ok()
+                    .map(|metadata| metadata.len())
+            },
+            UrlOrPath::Url(url) => url,
+            UrlOrPath::Stdin => return None,
+        };
+        let client = self.client(url).await.ok()?;
+        let response = client
+            .head(url.clone())
+            .timeout(SIZE_ESTIMATE_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        response
+            .headers()
+            .get(CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Where the tarball is unpacked to
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
 }
 
 #[async_trait::async_trait]
@@ -67,7 +296,10 @@ impl Action for FetchAndUnpackNix {
         ActionTag("fetch_and_unpack_nix")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Fetch `{}` to `{}`", self.url_or_path, self.dest.display())
+        match self.url_or_path {
+            UrlOrPath::Stdin => format!("Read Nix from stdin to `{}`", self.dest.display()),
+            _ => format!("Fetch `{}` to `{}`", self.url_or_path, self.dest.display()),
+        }
     }
 
     fn tracing_span(&self) -> Span {
@@ -77,7 +309,10 @@ impl Action for FetchAndUnpackNix {
             url_or_path = tracing::field::display(&self.url_or_path),
             proxy = tracing::field::Empty,
             ssl_cert_file = tracing::field::Empty,
+            preferred_ip_family = tracing::field::Empty,
             dest = tracing::field::display(self.dest.display()),
+            max_buffer_size = self.max_buffer_size,
+            download_connections = self.download_connections,
         );
         if let Some(proxy) = &self.proxy {
             span.record("proxy", tracing::field::display(&proxy));
@@ -88,86 +323,71 @@ impl Action for FetchAndUnpackNix {
                 tracing::field::display(&ssl_cert_file.display()),
             );
         }
+        if let Some(preferred_ip_family) = &self.preferred_ip_family {
+            span.record(
+                "preferred_ip_family",
+                tracing::field::display(preferred_ip_family),
+            );
+        }
         span
     }
 
     fn execute_description(&self) -> Vec<ActionDescription> {
-        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+        let mut explanation = vec![];
+        if local_path(&self.url_or_path).is_some() {
+            explanation.push("The tarball is read locally, without network access".to_string());
+        }
+        if let Some(sha256) = &self.sha256 {
+            explanation.push(format!("Verify it has the SHA-256 `{sha256}`"));
+        }
+        vec![ActionDescription::new(
+            format!(
+                "{} ({})",
+                self.tracing_synopsis(),
+                self.size_estimate.describe()
+            ),
+            explanation,
+        )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        let bytes = match &self.url_or_path {
-            UrlOrPath::Url(url) => {
-                let bytes = match url.scheme() {
-                    "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &self.proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let req = client
-                            .get(url.clone())
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        let res = client
-                            .execute(req)
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
-                        res.bytes()
-                            .await
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?
-                    },
-                    "file" => {
-                        let buf = tokio::fs::read(url.path())
-                            .await
-                            .map_err(|e| ActionErrorKind::Read(PathBuf::from(url.path()), e))
-                            .map_err(Self::error)?;
-                        Bytes::from(buf)
-                    },
-                    _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
-                };
-                bytes
+        // Removed by a later step once its contents are moved into place
+        temp_artifacts::register(&self.dest);
+        tokio::fs::create_dir_all(&self.dest)
+            .await
+            .map_err(|e| ActionErrorKind::CreateDirectory(self.dest.clone(), e))
+            .map_err(Self::error)?;
+
+        // Only ever hold `max_buffer_size` of the tarball in memory, downloads are streamed to disk first
+        let (archive_path, downloaded) = match &self.url_or_path {
+            UrlOrPath::Url(url) => match url.scheme() {
+                "https" | "http" => {
+                    let download_path = self.dest.join(DOWNLOAD_FILE_NAME);
+                    self.download(url, &download_path).await?;
+                    (Some(download_path), true)
+                },
+                "file" => (local_path(&self.url_or_path), false),
+                _ => return Err(Self::error(ActionErrorKind::UnknownUrlScheme)),
             },
-            UrlOrPath::Path(path) => {
-                let buf = tokio::fs::read(path)
-                    .await
-                    .map_err(|e| ActionErrorKind::Read(PathBuf::from(path), e))
-                    .map_err(Self::error)?;
-                Bytes::from(buf)
+            UrlOrPath::Path(path) => (Some(path.clone()), false),
+            UrlOrPath::Stdin if self.sha256.is_none() => {
+                return Err(Self::error(FetchUrlError::StdinWithoutSha256))
             },
+            UrlOrPath::Stdin => (None, false),
         };
 
-        // TODO(@Hoverbear): Pick directory
         tracing::trace!("Unpacking tar.xz");
-        let dest_clone = self.dest.clone();
-
-        let decoder = xz2::read::XzDecoder::new(bytes.reader());
-        let mut archive = tar::Archive::new(decoder);
-        archive.set_preserve_permissions(true);
-        archive.set_preserve_mtime(true);
-        archive.set_unpack_xattrs(true);
-        archive
-            .unpack(&dest_clone)
-            .map_err(FetchUrlError::Unarchive)
-            .map_err(Self::error)?;
+        let unpacked = self.unpack_verified(archive_path.as_deref());
 
-        Ok(())
+        if let (Some(archive_path), true) = (&archive_path, downloaded) {
+            tokio::fs::remove_file(archive_path)
+                .await
+                .map_err(|e| ActionErrorKind::Remove(archive_path.clone(), e))
+                .map_err(Self::error)?;
+        }
+
+        unpacked.map_err(Self::error)
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
@@ -180,13 +400,1145 @@ impl Action for FetchAndUnpackNix {
     }
 }
 
+impl FetchAndUnpackNix {
+    /// Unpack the tarball at `archive_path` (or stdin) into `dest`, verifying it against
+    /// [`sha256`](Self::sha256) and recording what it has in [`observed_sha256`](Self::observed_sha256)
+    ///
+    /// Files are verified before anything is unpacked from them, stdin can only be read once so it
+    /// is verified as it is unpacked, see [`unpack_from`].
+    fn unpack_verified(&mut self, archive_path: Option<&Path>) -> Result<(), FetchUrlError> {
+        match archive_path {
+            Some(archive_path) => {
+                self.verify_sha256(sha256_of(archive_path)?)?;
+                unpack(archive_path, &self.dest, self.max_buffer_size)?;
+            },
+            None => {
+                let observed =
+                    unpack_from(std::io::stdin().lock(), &self.dest, self.max_buffer_size)?;
+                self.verify_sha256(observed)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn verify_sha256(&mut self, observed: String) -> Result<(), FetchUrlError> {
+        self.observed_sha256 = Some(observed.clone());
+        match &self.sha256 {
+            Some(expected) if *expected != observed => Err(FetchUrlError::Sha256Mismatch {
+                expected: expected.clone(),
+                actual: observed,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn download(&self, url: &Url, download_path: &Path) -> Result<(), ActionError> {
+        let client = self.client(url).await?;
+        let progress_path = download_path.with_file_name(DOWNLOAD_PROGRESS_FILE_NAME);
+
+        if self.download_connections > 1 {
+            match download_ranges(
+                &client,
+                url,
+                download_path,
+                &progress_path,
+                self.download_connections,
+                self.max_buffer_size,
+            )
+            .await
+            {
+                Ok(true) => {
+                    remove_progress(&progress_path).await;
+                    return Ok(());
+                },
+                Ok(false) => tracing::debug!(
+                    "`{url}` does not support range requests, downloading over a single connection"
+                ),
+                Err(err) => tracing::warn!(
+                    "Downloading `{url}` over {} connections failed, falling back to a single connection: {err}",
+                    self.download_connections
+                ),
+            }
+            // The single stream rewrites the whole file, so no ranges of it can be reused
+            remove_progress(&progress_path).await;
+        }
+
+        let attempts = self.download_attempts.max(1);
+        // What the server identifies the partially downloaded file by, it is only resumed if that still matches
+        let mut validator = None;
+        let mut attempt = 1;
+        loop {
+            match self
+                .download_stream(&client, url, download_path, &mut validator)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < attempts && err.is_transient() => {
+                    let backoff = retry_backoff(attempt);
+                    tracing::warn!(
+                        "Downloading `{url}` failed (attempt {attempt} of {attempts}), retrying in {}s: {err}",
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                },
+                Err(err) => return Err(Self::error(err)),
+            }
+        }
+    }
+
+    /// Download `url` to `download_path` over a single connection, resuming what a previous attempt
+    /// downloaded when `validator` is of it and the server supports ranges
+    async fn download_stream(
+        &self,
+        client: &reqwest::Client,
+        url: &Url,
+        download_path: &Path,
+        validator: &mut Option<String>,
+    ) -> Result<(), FetchUrlError> {
+        let downloaded = match validator {
+            Some(_) => tokio::fs::metadata(download_path)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            None => 0,
+        };
+        let resume_from = validator
+            .as_deref()
+            .filter(|_| downloaded > 0)
+            .map(|validator| (downloaded, validator));
+
+        let res = match self.get(client, url, resume_from).await {
+            Ok(res) => res,
+            Err(FetchUrlError::Download { failure, .. })
+                if failure.clock_skew().is_some() && self.wait_for_clock_sync.is_some() =>
+            {
+                let wait = Duration::from_secs(self.wait_for_clock_sync.unwrap_or_default());
+                tracing::warn!(
+                    "Fetching `{url}` failed, {failure}; waiting up to {}s for the clock to be synchronized",
+                    wait.as_secs()
+                );
+                self.wait_for_clock_sync(url, wait).await;
+                self.get(client, url, resume_from).await?
+            },
+            Err(err) => return Err(err),
+        };
+        *validator = [ETAG, LAST_MODIFIED]
+            .iter()
+            .find_map(|header| res.headers().get(header))
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string);
+
+        // A server which doesn't support ranges, or whose file changed, sends all of it
+        let resumed = res.status() == StatusCode::PARTIAL_CONTENT
+            && res
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_content_range)
+                .map(|(start, _)| start)
+                == Some(downloaded);
+        let resumed_from = if resumed { downloaded } else { 0 };
+        let tracker = Tracker::new(
+            DOWNLOAD_TASK,
+            res.content_length().map(|length| resumed_from + length),
+            ProgressUnit::Bytes,
+        );
+        tracker.advance(resumed_from);
+        let file = if resumed {
+            tracing::info!("Resuming the download of `{url}` from byte {downloaded}");
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(download_path)
+                .await
+        } else {
+            tokio::fs::File::create(download_path).await
+        }
+        .map_err(|e| FetchUrlError::Write(download_path.to_path_buf(), e))?;
+        let mut writer = BufWriter::with_capacity(self.max_buffer_size, file);
+        let mut res = res;
+        while let Some(chunk) = res
+            .chunk()
+            .await
+            .map_err(|source| FetchUrlError::Interrupted {
+                url: url.clone(),
+                source,
+            })?
+        {
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(|e| FetchUrlError::Write(download_path.to_path_buf(), e))?;
+            tracker.advance(chunk.len() as u64);
+        }
+        writer
+            .flush()
+            .await
+            .map_err(|e| FetchUrlError::Write(download_path.to_path_buf(), e))?;
+
+        Ok(())
+    }
+
+    /// Request `url`, from the byte and with the validator of `resume_from` if given
+    async fn get(
+        &self,
+        client: &reqwest::Client,
+        url: &Url,
+        resume_from: Option<(u64, &str)>,
+    ) -> Result<reqwest::Response, FetchUrlError> {
+        let mut request = client.get(url.clone());
+        if let Some((start, validator)) = resume_from {
+            request = request
+                .header(RANGE, format!("bytes={start}-"))
+                .header(IF_RANGE, validator);
+        }
+        let source = match request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+        {
+            Ok(res) => return Ok(res),
+            Err(source) => source,
+        };
+        let mut failure = DownloadFailure::classify(url, &source).await;
+        if let DownloadFailure::CertificateValidity { skew, .. } = &mut failure {
+            *skew = self.clock_skew(url).await;
+        }
+        Err(FetchUrlError::Download {
+            url: url.clone(),
+            failure: Box::new(failure),
+            source,
+        })
+    }
+
+    /// How far the system clock is off, if by more than [`CLOCK_SKEW_THRESHOLD`]
+    async fn clock_skew(&self, url: &Url) -> Option<ClockSkew> {
+        ClockSkew::detect(
+            SystemTime::now(),
+            self.server_time(url).await,
+            clock_floor(),
+            CLOCK_SKEW_THRESHOLD,
+        )
+    }
+
+    /// The time according to the server of `url`, from the `Date` of a response which is otherwise ignored
+    ///
+    /// Its certificate is not verified, a clock too far off to verify it is what this checks for.
+    async fn server_time(&self, url: &Url) -> Option<SystemTime> {
+        let client = self
+            .client_builder(url)
+            .await
+            .ok()?
+            .danger_accept_invalid_certs(true)
+            .build()
+            .ok()?;
+        let response = client
+            .head(url.clone())
+            .timeout(CLOCK_CHECK_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        parse_http_date(response.headers().get(DATE)?.to_str().ok()?)
+    }
+
+    async fn wait_for_clock_sync(&self, url: &Url, wait: Duration) {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                tracing::warn!("The system clock was not synchronized, retrying anyway");
+                return;
+            }
+            tokio::time::sleep(CLOCK_SYNC_POLL_INTERVAL.min(deadline - now)).await;
+            if self.clock_skew(url).await.is_none() {
+                tracing::info!("The system clock was synchronized, retrying");
+                return;
+            }
+        }
+    }
+
+    async fn client(&self, url: &Url) -> Result<reqwest::Client, ActionError> {
+        self.client_builder(url)
+            .await?
+            .build()
+            .map_err(ActionErrorKind::Reqwest)
+            .map_err(Self::error)
+    }
+
+    async fn client_builder(&self, url: &Url) -> Result<reqwest::ClientBuilder, ActionError> {
+        let mut buildable_client =
+            http::client_builder(self.proxy.as_ref(), self.ssl_cert_file.as_deref())
+                .await
+                .map_err(Self::error)?;
+        // Behind a proxy, it does the resolving
+        if let (Some(preferred_ip_family), None, Some(host)) =
+            (self.preferred_ip_family, &self.proxy, url.host_str())
+        {
+            let addrs = resolve_preferring(host, preferred_ip_family).await;
+            if !addrs.is_empty() {
+                buildable_client = buildable_client.resolve_to_addrs(host, &addrs);
+            }
+        }
+        Ok(buildable_client)
+    }
+}
+
+/// The ranges of a multi-connection download which are already written to the download file
+#[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+struct DownloadProgress {
+    content_length: u64,
+    /// The `ETag` or `Last-Modified` of the tarball, so ranges of a different tarball are never reused
+    validator: String,
+    /// Half open `[start, end)` byte ranges
+    completed: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum RangeError {
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Writing `{0}`")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Expected `206 Partial Content` starting at byte {0}, got `{1}`")]
+    NotPartial(u64, StatusCode),
+    #[error("Expected {0} bytes for the range starting at byte {1}, got {2}")]
+    Length(u64, u64, u64),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/**
+Download `url` to `download_path` over `connections` concurrent range requests into a preallocated
+file, returning `false` if the server does not support ranges
+
+Completed ranges are recorded in `progress_path`, so after an interruption only the missing ranges
+are fetched again.
+*/
+async fn download_ranges(
+    client: &reqwest::Client,
+    url: &Url,
+    download_path: &Path,
+    progress_path: &Path,
+    connections: u8,
+    max_buffer_size: usize,
+) -> Result<bool, RangeError> {
+    // Asking for the first byte checks ranges are supported and finds the length
+    let probe = client
+        .get(url.clone())
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .await?
+        .error_for_status()?;
+    let content_length = match probe
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_content_range)
+    {
+        Some((_, content_length)) if probe.status() == StatusCode::PARTIAL_CONTENT => {
+            content_length
+        },
+        _ => return Ok(false),
+    };
+    let validator = [ETAG, LAST_MODIFIED]
+        .iter()
+        .find_map(|header| probe.headers().get(header))
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string);
+    // Mirrors redirect to signed URLs, those are fetched directly rather than redirected to again
+    let url = probe.url().clone();
+    drop(probe);
+
+    let mut progress = match (read_progress(progress_path).await, &validator) {
+        (Some(progress), Some(validator))
+            if progress.content_length == content_length
+                && progress.validator == *validator
+                && tokio::fs::metadata(download_path)
+                    .await
+                    .map(|metadata| metadata.len() == content_length)
+                    .unwrap_or(false) =>
+        {
+            tracing::debug!(
+                "Resuming download of `{url}`, {} ranges are already complete",
+                progress.completed.len()
+            );
+            progress
+        },
+        _ => {
+            let file = tokio::fs::File::create(download_path)
+                .await
+                .map_err(|e| RangeError::Io(download_path.to_path_buf(), e))?;
+            file.set_len(content_length)
+                .await
+                .map_err(|e| RangeError::Io(download_path.to_path_buf(), e))?;
+            DownloadProgress {
+                content_length,
+                validator: validator.unwrap_or_default(),
+                completed: vec![],
+            }
+        },
+    };
+
+    let buffer_size = (max_buffer_size / usize::from(connections)).max(1);
+    let tracker = Arc::new(Tracker::new(
+        DOWNLOAD_TASK,
+        Some(content_length),
+        ProgressUnit::Bytes,
+    ));
+    let mut set = JoinSet::new();
+    for (start, end) in split_ranges(content_length, connections) {
+        if progress.completed.contains(&(start, end)) {
+            tracker.advance(end - start);
+            continue;
+        }
+        let (client, url, download_path, tracker) = (
+            client.clone(),
+            url.clone(),
+            download_path.to_owned(),
+            tracker.clone(),
+        );
+        set.spawn(async move {
+            download_range(
+                &client,
+                &url,
+                &download_path,
+                start,
+                end,
+                buffer_size,
+                &tracker,
+            )
+            .await
+            .map(|()| (start, end))
+        });
+    }
+    // Any failure drops the set, aborting the other ranges
+    while let Some(result) = set.join_next().await {
+        progress.completed.push(result??);
+        if !progress.validator.is_empty() {
+            write_progress(progress_path, &progress).await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Fetch the half open range `[start, end)` of `url` into the same bytes of `download_path`
+async fn download_range(
+    client: &reqwest::Client,
+    url: &Url,
+    download_path: &Path,
+    start: u64,
+    end: u64,
+    buffer_size: usize,
+    tracker: &Tracker,
+) -> Result<(), RangeError> {
+    let mut res = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={start}-{}", end - 1))
+        .send()
+        .await?
+        .error_for_status()?;
+    let range_start = res
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_content_range)
+        .map(|(range_start, _)| range_start);
+    if res.status() != StatusCode::PARTIAL_CONTENT || range_start != Some(start) {
+        return Err(RangeError::NotPartial(start, res.status()));
+    }
+
+    let io_error = |e| RangeError::Io(download_path.to_path_buf(), e);
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(download_path)
+        .await
+        .map_err(io_error)?;
+    file.seek(SeekFrom::Start(start)).await.map_err(io_error)?;
+    let mut writer = BufWriter::with_capacity(buffer_size, file);
+    let expected = end - start;
+    let mut written = 0;
+    while let Some(chunk) = res.chunk().await? {
+        written += chunk.len() as u64;
+        // More than was asked for would overwrite the next range
+        if written > expected {
+            return Err(RangeError::Length(expected, start, written));
+        }
+        writer.write_all(&chunk).await.map_err(io_error)?;
+        tracker.advance(chunk.len() as u64);
+    }
+    if written != expected {
+        return Err(RangeError::Length(expected, start, written));
+    }
+    writer.flush().await.map_err(io_error)?;
+
+    Ok(())
+}
+
+/// Split `content_length` bytes into at most `connections` contiguous half open ranges
+fn split_ranges(content_length: u64, connections: u8) -> Vec<(u64, u64)> {
+    let connections = u64::from(connections.max(1));
+    #[allow(clippy::manual_div_ceil)] // `div_ceil` is newer than the flake's toolchain
+    let size = ((content_length + connections - 1) / connections).max(1);
+    (0..connections)
+        .map(|index| (index * size, ((index + 1) * size).min(content_length)))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+/// The start and complete length from a `Content-Range` like `bytes 0-0/1234`
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let (range, content_length) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _end) = range.split_once('-')?;
+    Some((start.parse().ok()?, content_length.parse().ok()?))
+}
+
+async fn read_progress(progress_path: &Path) -> Option<DownloadProgress> {
+    let progress = tokio::fs::read(progress_path).await.ok()?;
+    serde_json::from_slice(&progress).ok()
+}
+
+async fn write_progress(
+    progress_path: &Path,
+    progress: &DownloadProgress,
+) -> Result<(), RangeError> {
+    let buf = serde_json::to_vec(progress)
+        .map_err(|e| RangeError::Io(progress_path.to_path_buf(), e.into()))?;
+    tokio::fs::write(progress_path, buf)
+        .await
+        .map_err(|e| RangeError::Io(progress_path.to_path_buf(), e))
+}
+
+async fn remove_progress(progress_path: &Path) {
+    match tokio::fs::remove_file(progress_path).await {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => tracing::warn!("Could not remove `{}`: {e}", progress_path.display()),
+    }
+}
+
+/// The addresses of `host`, those of the `preferred` family first so they are connected to first (the others are still tried after)
+async fn resolve_preferring(host: &str, preferred: IpFamily) -> Vec<SocketAddr> {
+    let mut addrs = match tokio::net::lookup_host((host, 0)).await {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        // Left for the client to fail on, so it is classified like any other failure
+        Err(_) => return vec![],
+    };
+    addrs.sort_by_key(|addr| ip_family(&addr.ip()) != preferred);
+    addrs
+}
+
+fn ip_family(addr: &IpAddr) -> IpFamily {
+    match addr {
+        IpAddr::V4(_) => IpFamily::Ipv4,
+        IpAddr::V6(_) => IpFamily::Ipv6,
+    }
+}
+
+/// The address families this machine has a route for, connecting a UDP socket only consults the routing table
+async fn routable_ip_families() -> Vec<IpFamily> {
+    let mut families = vec![];
+    for (family, local, remote) in [
+        (IpFamily::Ipv4, "0.0.0.0:0", "192.0.2.1:443"),
+        (IpFamily::Ipv6, "[::]:0", "[2001:db8::1]:443"),
+    ] {
+        if let Ok(socket) = tokio::net::UdpSocket::bind(local).await {
+            if socket.connect(remote).await.is_ok() {
+                families.push(family);
+            }
+        }
+    }
+    families
+}
+
+/// The stage of connecting which failed
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectStage {
+    Dns,
+    Tcp { timed_out: bool },
+    Tls,
+    Unknown,
+}
+
+impl ConnectStage {
+    /// `reqwest` does not say which stage failed, so look through the errors it wraps
+    fn of(err: &(dyn std::error::Error + 'static), https: bool) -> Self {
+        let mut source = Some(err);
+        while let Some(err) = source {
+            if let Some(io) = err.downcast_ref::<std::io::Error>() {
+                let unreachable = [
+                    nix::errno::Errno::ENETUNREACH,
+                    nix::errno::Errno::EHOSTUNREACH,
+                    nix::errno::Errno::EADDRNOTAVAIL,
+                ]
+                .into_iter()
+                .any(|errno| io.raw_os_error() == Some(errno as i32));
+                match io.kind() {
+                    std::io::ErrorKind::TimedOut => return Self::Tcp { timed_out: true },
+                    std::io::ErrorKind::ConnectionRefused => return Self::Tcp { timed_out: false },
+                    _ if unreachable => return Self::Tcp { timed_out: false },
+                    _ => (),
+                }
+            }
+            // Labels from `hyper`'s connector
+            let message = err.to_string();
+            if message.starts_with("dns error") {
+                return Self::Dns;
+            } else if message.starts_with("tcp connect error") {
+                return Self::Tcp { timed_out: false };
+            }
+            source = err.source();
+        }
+        // A connection was made, so the handshake is what failed
+        if https {
+            Self::Tls
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Why fetching Nix failed, with how to get past it
+#[derive(Debug)]
+pub enum DownloadFailure {
+    /// The host name could not be resolved
+    Dns { host: String },
+    /// Connecting to every address the host resolved to failed
+    Connect {
+        host: String,
+        addresses: Vec<IpAddr>,
+        routable: Vec<IpFamily>,
+        timed_out: bool,
+    },
+    /// The TLS handshake failed
+    Tls { host: String },
+    /// The certificate of the host was rejected for its validity period, `skew` is how far the system
+    /// clock is off when that is to blame
+    CertificateValidity {
+        host: String,
+        validity: CertificateValidity,
+        skew: Option<ClockSkew>,
+    },
+    /// The server responded with an error
+    Http(reqwest::StatusCode),
+    /// Anything else
+    Other,
+}
+
+impl DownloadFailure {
+    async fn classify(url: &Url, err: &reqwest::Error) -> Self {
+        if let Some(status) = err.status() {
+            return Self::Http(status);
+        }
+        let host = url.host_str().unwrap_or_default().to_string();
+        if !err.is_connect() {
+            return Self::Other;
+        }
+        match ConnectStage::of(err, url.scheme() == "https") {
+            ConnectStage::Dns => Self::Dns { host },
+            ConnectStage::Tcp { timed_out } => {
+                let port = url.port_or_known_default().unwrap_or(443);
+                let mut addresses = tokio::net::lookup_host((host.as_str(), port))
+                    .await
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                addresses.dedup();
+                Self::Connect {
+                    host,
+                    addresses,
+                    routable: routable_ip_families().await,
+                    timed_out,
+                }
+            },
+            ConnectStage::Tls => match CertificateValidity::of(err) {
+                Some(validity) => Self::CertificateValidity {
+                    host,
+                    validity,
+                    skew: None,
+                },
+                None => Self::Tls { host },
+            },
+            ConnectStage::Unknown => Self::Other,
+        }
+    }
+
+    /// How far the system clock is off, when fetching failed because of it
+    pub fn clock_skew(&self) -> Option<ClockSkew> {
+        match self {
+            Self::CertificateValidity { skew, .. } => *skew,
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadFailure::Dns { host } => write!(
+                f,
+                "could not resolve `{host}`, check the DNS configuration (eg. `/etc/resolv.conf`) or pass `--nix-package-url` pointing at a reachable mirror or local tarball"
+            ),
+            DownloadFailure::Connect {
+                host,
+                addresses,
+                routable,
+                timed_out,
+            } => {
+                let mut families = addresses.iter().map(ip_family).collect::<Vec<_>>();
+                families.sort_by_key(|family| *family == IpFamily::Ipv6);
+                families.dedup();
+                let attempted = addresses
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let failed = if *timed_out { "timed out" } else { "failed" };
+                write!(f, "connecting to `{host}` ({attempted}) {failed}")?;
+
+                match (families.as_slice(), routable.as_slice()) {
+                    (_, []) => write!(f, ", this machine has no network route"),
+                    ([family], [other, ..]) if !routable.contains(family) => write!(
+                        f,
+                        " -- `{host}` resolves only to {family} and this machine has no {family} route, pass `--nix-package-url` pointing at an {other}-reachable mirror"
+                    ),
+                    ([_, _], [only]) => write!(
+                        f,
+                        ", this machine only has an {only} route, try `--prefer-{}`",
+                        only.to_string().to_lowercase()
+                    ),
+                    _ => write!(f, ", check firewalls or pass `--proxy`"),
+                }
+            },
+            DownloadFailure::Tls { host } => write!(
+                f,
+                "the TLS handshake with `{host}` failed, check the system clock, or pass `--ssl-cert-file` if a proxy intercepts TLS"
+            ),
+            DownloadFailure::CertificateValidity {
+                host,
+                validity,
+                skew: Some(skew),
+            } => write!(
+                f,
+                "the certificate of `{host}` {validity} by the system clock, which is {skew} -- synchronize it (eg. `timedatectl set-ntp true`) or, if something like cloud-init is about to, pass `--wait-for-clock-sync <secs>`"
+            ),
+            DownloadFailure::CertificateValidity {
+                host,
+                validity,
+                skew: None,
+            } => write!(
+                f,
+                "the certificate of `{host}` {validity}, check the system clock (`date -u`), or pass `--ssl-cert-file` if a proxy intercepts TLS"
+            ),
+            DownloadFailure::Http(status) => write!(f, "the server responded `{status}`"),
+            DownloadFailure::Other => write!(f, "the request failed"),
+        }
+    }
+}
+
+/// Which end of its validity period a certificate was rejected at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateValidity {
+    NotYetValid,
+    Expired,
+}
+
+impl CertificateValidity {
+    /// Look through the errors `reqwest` wraps for `rustls` rejecting a certificate by its dates
+    fn of(err: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        let mut source = Some(err);
+        while let Some(err) = source {
+            let message = err.to_string();
+            if message.contains("invalid peer certificate: NotValidYet") {
+                return Some(Self::NotYetValid);
+            } else if message.contains("invalid peer certificate: Expired") {
+                return Some(Self::Expired);
+            }
+            source = err.source();
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for CertificateValidity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateValidity::NotYetValid => write!(f, "is not yet valid"),
+            CertificateValidity::Expired => write!(f, "has expired"),
+        }
+    }
+}
+
+/// How far the system clock is off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// Positive when the system clock is ahead
+    pub seconds: i64,
+    /// Only a lower bound on how far behind it is, without a server to compare against
+    pub at_least: bool,
+}
+
+impl ClockSkew {
+    /// Compare the `system` clock against the `server`'s, or failing that against a `floor` it
+    /// cannot be earlier than, returning how far it is off if by more than `threshold`
+    fn detect(
+        system: SystemTime,
+        server: Option<SystemTime>,
+        floor: Option<SystemTime>,
+        threshold: Duration,
+    ) -> Option<Self> {
+        let skew = match (server, floor) {
+            (Some(server), _) => Self {
+                seconds: seconds_between(server, system),
+                at_least: false,
+            },
+            (None, Some(floor)) if system < floor => Self {
+                seconds: seconds_between(floor, system),
+                at_least: true,
+            },
+            (None, _) => return None,
+        };
+        (skew.seconds.unsigned_abs() > threshold.as_secs()).then_some(skew)
+    }
+}
+
+impl std::fmt::Display for ClockSkew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.seconds.unsigned_abs();
+        let units = [
+            (seconds / 86_400, "d"),
+            (seconds / 3_600 % 24, "h"),
+            (seconds / 60 % 60, "m"),
+            (seconds % 60, "s"),
+        ];
+        let magnitude = units
+            .iter()
+            .skip_while(|(value, _)| *value == 0)
+            .take(2)
+            .filter(|(value, _)| *value != 0)
+            .map(|(value, unit)| format!("{value}{unit}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let direction = if self.seconds < 0 { "behind" } else { "ahead" };
+        if self.at_least {
+            write!(f, "at least {magnitude} {direction}")
+        } else {
+            write!(f, "{magnitude} {direction}")
+        }
+    }
+}
+
+/// `to - from` in whole seconds
+fn seconds_between(from: SystemTime, to: SystemTime) -> i64 {
+    match to.duration_since(from) {
+        Ok(ahead) => ahead.as_secs().try_into().unwrap_or(i64::MAX),
+        Err(behind) => -behind.duration().as_secs().try_into().unwrap_or(i64::MAX),
+    }
+}
+
+/// The latest time the system clock is known to have reached, see [`CLOCK_FLOOR_PATHS`]
+fn clock_floor() -> Option<SystemTime> {
+    CLOCK_FLOOR_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .chain(std::env::current_exe().ok())
+        .filter_map(|path| path.metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// Parse an HTTP date in the `Sun, 06 Nov 1994 08:49:37 GMT` format servers send
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_weekday, rest) = value.split_once(", ")?;
+    let [day, month, year, time, "GMT"] = rest.split(' ').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let day = day
+        .parse::<i64>()
+        .ok()
+        .filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year = year.parse::<i64>().ok().filter(|year| *year >= 1970)?;
+    let [hour, minute, second] = time
+        .split(':')
+        .map(|part| part.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?[..]
+    else {
+        return None;
+    };
+    crate::timestamp::from_civil(year, month, day, hour, minute, second)
+}
+
+/// Stream the `tar.xz` at `archive_path` into `dest`, see [`unpack_from`]
+fn unpack(
+    archive_path: &Path,
+    dest: &Path,
+    max_buffer_size: usize,
+) -> Result<String, FetchUrlError> {
+    let file = std::fs::File::open(archive_path)
+        .map_err(|e| FetchUrlError::Open(archive_path.to_path_buf(), e))?;
+    unpack_from(file, dest, max_buffer_size)
+}
+
+/// The tarball file `url_or_path` is read from, `None` when it is fetched or read from stdin
+fn local_path(url_or_path: &UrlOrPath) -> Option<PathBuf> {
+    match url_or_path {
+        UrlOrPath::Path(path) => Some(path.clone()),
+        UrlOrPath::Url(url) if url.scheme() == "file" => Some(PathBuf::from(url.path())),
+        _ => None,
+    }
+}
+
+/// Refuse a local tarball which can't be read or is not xz compressed, before planning on it
+fn check_xz(path: &Path) -> Result<(), FetchUrlError> {
+    let mut magic = [0; XZ_MAGIC.len()];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => FetchUrlError::NotXz(path.to_path_buf()),
+            _ => FetchUrlError::Open(path.to_path_buf(), e),
+        })?;
+    if magic != XZ_MAGIC {
+        return Err(FetchUrlError::NotXz(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// The SHA-256 of the file at `path`, in lowercase hex
+fn sha256_of(path: &Path) -> Result<String, FetchUrlError> {
+    let file = std::fs::File::open(path).map_err(|e| FetchUrlError::Open(path.to_path_buf(), e))?;
+    let mut source = Sha256Reader::new(file);
+    std::io::copy(&mut source, &mut std::io::sink())
+        .map_err(|e| FetchUrlError::Open(path.to_path_buf(), e))?;
+    Ok(source.finish())
+}
+
+/**
+Stream a `tar.xz` into `dest`, buffering at most `max_buffer_size` of the compressed input
+
+We run as root, so every entry is checked to land strictly under `dest` before anything is written,
+see [`entry_relative_path`] and [`link_target_is_contained`].
+
+Returns the SHA-256 of the input, hashed as it is read, so a mismatch is only found once it is all
+unpacked, the caller must throw `dest` away then (it is a [temporary artifact](crate::temp_artifacts)).
+*/
+fn unpack_from(
+    source: impl Read,
+    dest: &Path,
+    max_buffer_size: usize,
+) -> Result<String, FetchUrlError> {
+    let mut source = Sha256Reader::new(source);
+    let reader = std::io::BufReader::with_capacity(max_buffer_size.max(1), &mut source);
+    let stream = xz2::stream::Stream::new_stream_decoder(XZ_DECODER_MEMORY_LIMIT, 0)
+        .map_err(FetchUrlError::XzDecoder)?;
+    let decoder = xz2::bufread::XzDecoder::new_stream(reader, stream);
+
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_unpack_xattrs(true);
+
+    // Like `Archive::unpack`, directories are unpacked last so read-only ones can still be filled
+    let mut directories = Vec::new();
+    let tracker = Tracker::new("Unpacking Nix", None, ProgressUnit::Entries);
+    for entry in archive.entries().map_err(FetchUrlError::Unarchive)? {
+        let mut entry = entry.map_err(FetchUrlError::Unarchive)?;
+        let path = entry.path().map_err(FetchUrlError::Unarchive)?.into_owned();
+        let relative = entry_relative_path(&path)?;
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry
+                .link_name()
+                .map_err(FetchUrlError::Unarchive)?
+                .ok_or_else(|| FetchUrlError::UnsafeLinkTarget(path.clone(), PathBuf::new()))?
+                .into_owned();
+            // Hard link targets are other entries of the archive
+            let contained = if entry_type.is_hard_link() {
+                entry_relative_path(&target).is_ok()
+            } else {
+                link_target_is_contained(&relative, &target)
+            };
+            if !contained {
+                return Err(FetchUrlError::UnsafeLinkTarget(path, target));
+            }
+        }
+
+        if entry_type.is_dir() {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(dest).map_err(FetchUrlError::Unarchive)?;
+            tracker.advance(1);
+        }
+    }
+    // Deepest first, so a parent's permissions are set after its children are created
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        directory
+            .unpack_in(dest)
+            .map_err(FetchUrlError::Unarchive)?;
+        tracker.advance(1);
+    }
+
+    // Whatever follows the end of the archive (such as padding) is part of the tarball too
+    let mut rest = archive.into_inner().into_inner();
+    std::io::copy(&mut rest, &mut std::io::sink()).map_err(FetchUrlError::Unarchive)?;
+    drop(rest);
+
+    Ok(source.finish())
+}
+
+/// Hashes everything read through it
+struct Sha256Reader<R> {
+    inner: R,
+    context: ring::digest::Context,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+        }
+    }
+
+    /// The SHA-256 of what was read, in lowercase hex
+    fn finish(self) -> String {
+        self.context
+            .finish()
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.context.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// A SHA-256 given in hex, lowercased
+fn parse_sha256(sha256: &str) -> Result<String, FetchUrlError> {
+    if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(sha256.to_ascii_lowercase())
+    } else {
+        Err(FetchUrlError::InvalidSha256(sha256.to_string()))
+    }
+}
+
+/// The path of an archive entry, which must be relative and never climb out with `..`
+fn entry_relative_path(path: &Path) -> Result<PathBuf, FetchUrlError> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => (),
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir => {
+                return Err(FetchUrlError::UnsafeEntryPath(path.to_path_buf()))
+            },
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(FetchUrlError::UnsafeEntryPath(path.to_path_buf()));
+    }
+    Ok(relative)
+}
+
+/**
+Whether a symlink at `entry` (relative to the unpacked tree) cannot escape once dereferenced, during or
+after [`MoveUnpackedNix`](crate::action::base::MoveUnpackedNix)
+
+Absolute targets must be in `/nix/store`, where the store paths are moved to. Relative targets must
+stay within the top two components of `entry`, the `store` directory of `nix-*` for store paths.
+*/
+fn link_target_is_contained(entry: &Path, target: &Path) -> bool {
+    if target.is_absolute() {
+        return target.starts_with(STORE_DIR)
+            && target
+                .components()
+                .all(|component| !matches!(component, Component::ParentDir));
+    }
+
+    let mut depth = entry.components().count().saturating_sub(1);
+    let floor = depth.min(2);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir if depth > floor => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum FetchUrlError {
     #[error("Unarchiving error")]
     Unarchive(#[source] std::io::Error),
+    #[error("Opening `{0}` for unarchiving")]
+    Open(PathBuf, #[source] std::io::Error),
+    #[error("Creating xz decoder")]
+    XzDecoder(#[source] xz2::stream::Error),
     #[error("Unknown proxy scheme, `https://`, `socks5://`, and `http://` supported")]
     UnknownProxyScheme,
+    #[error("Fetching `{url}`: {failure}")]
+    Download {
+        url: Url,
+        failure: Box<DownloadFailure>,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Refusing to unpack `{0}`, archive entries must be relative paths without `..`")]
+    UnsafeEntryPath(PathBuf),
+    #[error("Refusing to unpack `{0}`, its link target `{1}` points outside of the unpacked tarball or `/nix/store`")]
+    UnsafeLinkTarget(PathBuf, PathBuf),
+    #[error("`{0}` is not a SHA-256, expected 64 hexadecimal digits")]
+    InvalidSha256(String),
+    #[error("Reading the Nix package from stdin requires `--nix-package-sha256` to verify it")]
+    StdinWithoutSha256,
+    #[error("The Nix package has the SHA-256 `{actual}`, expected `{expected}`")]
+    Sha256Mismatch { expected: String, actual: String },
+    #[error("`{0}` is not an xz compressed tarball, like the `nix-<version>-<system>.tar.xz` of a Nix release")]
+    NotXz(PathBuf),
+    #[error("Downloading `{url}` was interrupted")]
+    Interrupted {
+        url: Url,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Writing the download to `{0}`")]
+    Write(PathBuf, #[source] std::io::Error),
+}
+
+impl FetchUrlError {
+    /// If retrying may get past it: connections which failed, timed out or dropped, and server
+    /// errors, never client errors (such as `404 Not Found`) or a mismatched checksum
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Download {
+                failure, source, ..
+            } => match **failure {
+                DownloadFailure::Connect { .. } => true,
+                DownloadFailure::Http(status) => status.is_server_error(),
+                DownloadFailure::Other => source.is_timeout() || source.is_request(),
+                _ => false,
+            },
+            Self::Interrupted { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// How long to wait after failed attempt `attempt` (from 1) at a download
+fn retry_backoff(attempt: u8) -> Duration {
+    DOWNLOAD_RETRY_BACKOFF * 2u32.saturating_pow(u32::from(attempt.saturating_sub(1)))
 }
 
 impl From<FetchUrlError> for ActionErrorKind {
@@ -194,3 +1546,1015 @@ impl From<FetchUrlError> for ActionErrorKind {
         ActionErrorKind::Custom(Box::new(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UNPACKED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Peak resident set size (`VmHWM`) in KiB, `None` outside of Linux
+    fn peak_rss_kib() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|rest| rest.trim().trim_end_matches("kB").trim().parse().ok())
+    }
+
+    fn write_fixture_tarball(path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let encoder = xz2::write::XzEncoder::new(file, 1);
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(UNPACKED_FILE_SIZE);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(
+            &mut header,
+            "nix-fixture/store/large-file",
+            std::io::repeat(0x5a).take(UNPACKED_FILE_SIZE),
+        )?;
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn unpacks_with_bounded_memory() -> eyre::Result<()> {
+        if peak_rss_kib().is_none() {
+            // `/proc/self/status` is Linux only
+            return Ok(());
+        }
+        // The peak memory is of the whole process, which other tests must not add to
+        crate::test_support::run_alone(module_path!(), "unpacks_with_bounded_memory", || async {
+            let temp_dir = tempfile::tempdir()?;
+            let tarball = temp_dir.path().join("fixture.tar.xz");
+            write_fixture_tarball(&tarball)?;
+            let dest = temp_dir.path().join("unpacked");
+
+            // Reset the high water mark so the tarball creation above is not counted, not all kernels support this
+            let _ = std::fs::write("/proc/self/clear_refs", "5");
+            let before = peak_rss_kib().expect("Could not read peak RSS");
+
+            let mut action = FetchAndUnpackNix::plan(
+                UrlOrPath::Path(tarball),
+                dest.clone(),
+                None,
+                None,
+                None,
+                4096,
+                1,
+            )
+            .await?;
+            action.try_execute().await?;
+
+            let after = peak_rss_kib().expect("Could not read peak RSS");
+            let unpacked = dest.join("nix-fixture/store/large-file");
+            assert_eq!(std::fs::metadata(unpacked)?.len(), UNPACKED_FILE_SIZE);
+            // A quarter of the unpacked size leaves room for the runtime and the decoder (around 6 MiB),
+            // holding the file in memory would blow through this
+            let growth_kib = after.saturating_sub(before);
+            assert!(
+                growth_kib < UNPACKED_FILE_SIZE / 1024 / 4,
+                "Peak RSS grew by {growth_kib} KiB while unpacking"
+            );
+
+            Ok(())
+        })
+    }
+
+    fn small_tarball() -> std::io::Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(Vec::new(), 1));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "nix-fixture/store/hello", &b"hello"[..])?;
+        builder.into_inner()?.finish()
+    }
+
+    fn sha256_hex(bytes: &[u8]) -> eyre::Result<String> {
+        let sha256 = ring::digest::digest(&ring::digest::SHA256, bytes)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>();
+        Ok(parse_sha256(&sha256)?)
+    }
+
+    #[test]
+    fn unpacks_a_stream_hashing_it() -> eyre::Result<()> {
+        let tarball = small_tarball()?;
+        let temp_dir = tempfile::tempdir()?;
+        let observed = unpack_from(&tarball[..], temp_dir.path(), 4096)?;
+        assert_eq!(observed, sha256_hex(&tarball)?);
+        assert_eq!(
+            std::fs::read(temp_dir.path().join("nix-fixture/store/hello"))?,
+            b"hello"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unpacks_without_archivers_on_path() -> eyre::Result<()> {
+        crate::test_support::run_without_path(
+            module_path!(),
+            "unpacks_without_archivers_on_path",
+            || async {
+                let temp_dir = tempfile::tempdir()?;
+                let archive_path = temp_dir.path().join("nix.tar.xz");
+                std::fs::write(&archive_path, small_tarball()?)?;
+                let dest = temp_dir.path().join("unpacked");
+                let mut action = FetchAndUnpackNix::plan(
+                    UrlOrPath::Path(archive_path),
+                    dest.clone(),
+                    None,
+                    None,
+                    None,
+                    4096,
+                    1,
+                )
+                .await?;
+                let (executed, commands) =
+                    crate::command_runner::recording(vec![], action.try_execute()).await;
+                executed?;
+
+                assert_eq!(
+                    std::fs::read(dest.join("nix-fixture/store/hello"))?,
+                    b"hello"
+                );
+                assert_eq!(commands, vec![]);
+                Ok(())
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn verifies_a_file_before_unpacking_it() -> eyre::Result<()> {
+        let tarball = small_tarball()?;
+        let sha256 = sha256_hex(&tarball)?;
+        let temp_dir = tempfile::tempdir()?;
+        let archive_path = temp_dir.path().join("nix.tar.xz");
+        std::fs::write(&archive_path, &tarball)?;
+        let dest = temp_dir.path().join("unpacked");
+        std::fs::create_dir(&dest)?;
+        let mut action = FetchAndUnpackNix::plan(
+            UrlOrPath::Path(archive_path.clone()),
+            dest.clone(),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+
+        let wrong = "0".repeat(64);
+        action.action.set_sha256(Some(wrong.clone()))?;
+        match action.action.unpack_verified(Some(&archive_path)) {
+            Err(FetchUrlError::Sha256Mismatch { expected, actual }) => {
+                assert_eq!(expected, wrong);
+                assert_eq!(actual, sha256);
+            },
+            other => panic!("Expected a SHA-256 mismatch, got {other:?}"),
+        }
+        assert!(
+            std::fs::read_dir(&dest)?.next().is_none(),
+            "Nothing is unpacked from a mismatched tarball"
+        );
+
+        action.action.set_sha256(Some(sha256.clone()))?;
+        action.action.unpack_verified(Some(&archive_path))?;
+        assert_eq!(
+            std::fs::read(dest.join("nix-fixture/store/hello"))?,
+            b"hello"
+        );
+
+        // The receipt records what was installed
+        let recorded = serde_json::to_value(&action.action)?;
+        assert_eq!(recorded["sha256"], sha256.as_str());
+        assert_eq!(recorded["observed_sha256"], sha256.as_str());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_local_files_which_are_not_xz() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plan = |path: &Path| {
+            FetchAndUnpackNix::plan(
+                UrlOrPath::Path(path.to_path_buf()),
+                temp_dir.path().join("unpacked"),
+                None,
+                None,
+                None,
+                4096,
+                1,
+            )
+        };
+
+        let missing = temp_dir.path().join("missing.tar.xz");
+        let err = plan(&missing).await.expect_err("The tarball is missing");
+        assert!(format!("{err:?}").contains("Open("), "{err:?}");
+
+        let not_xz = temp_dir.path().join("nix.tar.gz");
+        std::fs::write(&not_xz, b"\x1f\x8b not xz")?;
+        let err = plan(&not_xz).await.expect_err("The tarball is not xz");
+        assert!(format!("{err:?}").contains("NotXz("), "{err:?}");
+        let empty = temp_dir.path().join("empty.tar.xz");
+        std::fs::write(&empty, b"")?;
+        assert!(plan(&empty).await.is_err());
+
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        std::fs::write(&tarball, small_tarball()?)?;
+        let action = plan(&tarball).await?;
+        assert!(action
+            .describe_execute()
+            .iter()
+            .flat_map(|description| &description.explanation)
+            .any(|line| line.contains("without network access")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stdin_requires_a_sha256() -> eyre::Result<()> {
+        let mut action = FetchAndUnpackNix::plan(
+            UrlOrPath::Stdin,
+            PathBuf::from("unused"),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+        assert!(action.action.set_sha256(None).is_err());
+        assert!(action.action.set_sha256(Some("abc".into())).is_err());
+        action.action.set_sha256(Some("A".repeat(64)))?;
+        assert_eq!(action.action.sha256, Some("a".repeat(64)));
+        Ok(())
+    }
+
+    /// Append an entry with a raw name and link name, bypassing the checks `tar` makes when building archives
+    fn append_raw(
+        builder: &mut tar::Builder<impl std::io::Write>,
+        entry_type: tar::EntryType,
+        name: &str,
+        link_name: &str,
+    ) -> std::io::Result<()> {
+        let data: &[u8] = if entry_type.is_file() {
+            b"escaped"
+        } else {
+            b""
+        };
+        let mut header = tar::Header::new_gnu();
+        let gnu = header.as_gnu_mut().expect("GNU header");
+        gnu.name[..name.len()].copy_from_slice(name.as_bytes());
+        gnu.linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, data)
+    }
+
+    fn write_malicious_tarball(
+        path: &Path,
+        entry_type: tar::EntryType,
+        name: &str,
+        link_name: &str,
+    ) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(file, 1));
+        append_raw(&mut builder, entry_type, name, link_name)?;
+        // Written through the symlink if it were unpacked
+        append_raw(
+            &mut builder,
+            tar::EntryType::Regular,
+            &format!("{name}/escaped"),
+            "",
+        )?;
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    /// Unpack a crafted tarball into a sandbox, returning the error and anything written beside the sandbox
+    fn unpack_malicious(
+        entry_type: tar::EntryType,
+        name: impl Fn(&Path) -> String,
+        link_name: &str,
+    ) -> eyre::Result<(FetchUrlError, Vec<PathBuf>)> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("malicious.tar.xz");
+        let name = name(temp_dir.path());
+        write_malicious_tarball(&tarball, entry_type, &name, link_name)?;
+        let dest = temp_dir.path().join("sandbox/dest");
+        std::fs::create_dir_all(&dest)?;
+
+        let err = unpack(&tarball, &dest, 4096).expect_err("Malicious tarball was unpacked");
+        let mut written = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        written.retain(|path| *path != tarball && *path != temp_dir.path().join("sandbox"));
+        written.extend(
+            walkdir::WalkDir::new(&dest)
+                .min_depth(1)
+                .into_iter()
+                .map(|entry| entry.map(|entry| entry.into_path()))
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        Ok((err, written))
+    }
+
+    #[test]
+    fn rejects_absolute_entry() -> eyre::Result<()> {
+        let (err, written) = unpack_malicious(
+            tar::EntryType::Regular,
+            |temp_dir| temp_dir.join("escaped").display().to_string(),
+            "",
+        )?;
+        assert!(matches!(err, FetchUrlError::UnsafeEntryPath(_)), "{err:?}");
+        assert_eq!(written, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_parent_dir_entry() -> eyre::Result<()> {
+        let (err, written) = unpack_malicious(
+            tar::EntryType::Regular,
+            |_| "nix-fixture/../../../escaped".into(),
+            "",
+        )?;
+        assert!(matches!(err, FetchUrlError::UnsafeEntryPath(_)), "{err:?}");
+        assert_eq!(written, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_escaping_symlink() -> eyre::Result<()> {
+        let (err, written) = unpack_malicious(
+            tar::EntryType::Symlink,
+            |_| "nix-fixture/store/escape".into(),
+            "../../../..",
+        )?;
+        assert!(
+            matches!(err, FetchUrlError::UnsafeLinkTarget(_, _)),
+            "{err:?}"
+        );
+        assert_eq!(written, Vec::<PathBuf>::new());
+
+        let (err, written) = unpack_malicious(
+            tar::EntryType::Symlink,
+            |_| "nix-fixture/store/escape".into(),
+            "/etc",
+        )?;
+        assert!(
+            matches!(err, FetchUrlError::UnsafeLinkTarget(_, _)),
+            "{err:?}"
+        );
+        assert_eq!(written, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn allows_store_symlinks() {
+        let entry = Path::new("nix-fixture/store/abc-nix/bin/nix-build");
+        assert!(link_target_is_contained(entry, Path::new("nix")));
+        assert!(link_target_is_contained(
+            entry,
+            Path::new("../../def-nss-cacert/etc")
+        ));
+        assert!(link_target_is_contained(
+            entry,
+            Path::new("/nix/store/def-nss-cacert/etc")
+        ));
+        assert!(!link_target_is_contained(
+            entry,
+            Path::new("../../../install")
+        ));
+        assert!(!link_target_is_contained(
+            entry,
+            Path::new("/nix/store/../../etc")
+        ));
+    }
+
+    /// Accept one connection on localhost and answer it with `response`
+    async fn serve_once(response: &'static [u8]) -> std::io::Result<u16> {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            if let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response).await;
+            }
+        });
+        Ok(port)
+    }
+
+    async fn fetch(url: &str) -> eyre::Result<ActionError> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut action = FetchAndUnpackNix::plan(
+            UrlOrPath::Url(url.parse()?),
+            temp_dir.path().join("dest"),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+        Ok(action
+            .try_execute()
+            .await
+            .expect_err("Fetching should have failed"))
+    }
+
+    fn download_failure(err: &ActionError) -> &DownloadFailure {
+        match err.kind() {
+            ActionErrorKind::Custom(custom) => match custom.downcast_ref::<FetchUrlError>() {
+                Some(FetchUrlError::Download { failure, .. }) => failure,
+                _ => panic!("Expected a download error, got {err:?}"),
+            },
+            _ => panic!("Expected a download error, got {err:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn classifies_http_errors() -> eyre::Result<()> {
+        let port = serve_once(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n").await?;
+        let err = fetch(&format!("http://127.0.0.1:{port}/nix.tar.xz")).await?;
+        assert!(matches!(
+            download_failure(&err),
+            DownloadFailure::Http(reqwest::StatusCode::NOT_FOUND)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn classifies_refused_connections() -> eyre::Result<()> {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            listener.local_addr()?.port()
+        };
+        let err = fetch(&format!("http://127.0.0.1:{port}/nix.tar.xz")).await?;
+        match download_failure(&err) {
+            DownloadFailure::Connect {
+                addresses,
+                timed_out,
+                ..
+            } => {
+                assert_eq!(addresses, &[IpAddr::from([127, 0, 0, 1])]);
+                assert!(!timed_out);
+            },
+            failure => panic!("Expected a connect failure, got {failure:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn classifies_tls_errors() -> eyre::Result<()> {
+        let port = serve_once(b"HTTP/1.1 200 OK\r\n\r\nnot tls").await?;
+        let err = fetch(&format!("https://127.0.0.1:{port}/nix.tar.xz")).await?;
+        assert!(
+            matches!(download_failure(&err), DownloadFailure::Tls { .. }),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn classifies_unresolvable_hosts() -> eyre::Result<()> {
+        let err = fetch("http://nix-installer-test.invalid/nix.tar.xz").await?;
+        assert!(
+            matches!(download_failure(&err), DownloadFailure::Dns { host } if host == "nix-installer-test.invalid"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hints_at_unroutable_families() {
+        let failure = DownloadFailure::Connect {
+            host: "releases.nixos.org".into(),
+            addresses: vec![IpAddr::from([192, 0, 2, 1])],
+            routable: vec![IpFamily::Ipv6],
+            timed_out: false,
+        };
+        assert!(failure.to_string().ends_with(
+            "`releases.nixos.org` resolves only to IPv4 and this machine has no IPv4 route, pass `--nix-package-url` pointing at an IPv6-reachable mirror"
+        ));
+
+        let failure = DownloadFailure::Connect {
+            host: "releases.nixos.org".into(),
+            addresses: vec![IpAddr::from([192, 0, 2, 1]), "2001:db8::1".parse().unwrap()],
+            routable: vec![IpFamily::Ipv4],
+            timed_out: true,
+        };
+        assert!(failure.to_string().ends_with("try `--prefer-ipv4`"));
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("error trying to connect")]
+    struct Connect(#[source] std::io::Error);
+
+    #[test]
+    fn classifies_certificate_validity_errors() {
+        let not_yet_valid = Connect(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid peer certificate: NotValidYet",
+        ));
+        assert_eq!(
+            CertificateValidity::of(&not_yet_valid),
+            Some(CertificateValidity::NotYetValid)
+        );
+        let expired = Connect(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid peer certificate: Expired",
+        ));
+        assert_eq!(
+            CertificateValidity::of(&expired),
+            Some(CertificateValidity::Expired)
+        );
+        let unknown_issuer = Connect(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid peer certificate: UnknownIssuer",
+        ));
+        assert_eq!(CertificateValidity::of(&unknown_issuer), None);
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_164_800))
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn detects_clock_skew() {
+        let server = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let day = Duration::from_secs(86_400);
+
+        // A freshly imaged machine, booted a few days in the past
+        let skew = ClockSkew::detect(
+            server - 3 * day - Duration::from_secs(7_200),
+            Some(server),
+            None,
+            CLOCK_SKEW_THRESHOLD,
+        )
+        .expect("Expected the clock to be off");
+        assert_eq!(skew.to_string(), "3d 2h behind");
+
+        let skew = ClockSkew::detect(
+            server + Duration::from_secs(20 * 60 + 5),
+            Some(server),
+            None,
+            CLOCK_SKEW_THRESHOLD,
+        )
+        .expect("Expected the clock to be off");
+        assert_eq!(skew.to_string(), "20m 5s ahead");
+
+        // Within the threshold, the certificate is to blame
+        assert_eq!(
+            ClockSkew::detect(
+                server + Duration::from_secs(30),
+                Some(server),
+                None,
+                CLOCK_SKEW_THRESHOLD
+            ),
+            None
+        );
+        // The server wins over the floor
+        assert_eq!(
+            ClockSkew::detect(
+                server,
+                Some(server),
+                Some(server + day),
+                CLOCK_SKEW_THRESHOLD
+            ),
+            None
+        );
+
+        // Without a server, only a clock behind the floor is detected
+        let skew = ClockSkew::detect(server - 400 * day, None, Some(server), CLOCK_SKEW_THRESHOLD)
+            .expect("Expected the clock to be off");
+        assert_eq!(skew.to_string(), "at least 400d behind");
+        assert_eq!(
+            ClockSkew::detect(server, None, Some(server - day), CLOCK_SKEW_THRESHOLD),
+            None
+        );
+        assert_eq!(
+            ClockSkew::detect(server, None, None, CLOCK_SKEW_THRESHOLD),
+            None
+        );
+    }
+
+    #[test]
+    fn suggests_clock_sync() {
+        let failure = DownloadFailure::CertificateValidity {
+            host: "releases.nixos.org".into(),
+            validity: CertificateValidity::NotYetValid,
+            skew: Some(ClockSkew {
+                seconds: -3 * 86_400,
+                at_least: false,
+            }),
+        };
+        assert!(failure.clock_skew().is_some());
+        assert!(failure.to_string().starts_with(
+            "the certificate of `releases.nixos.org` is not yet valid by the system clock, which is 3d behind"
+        ));
+        assert!(failure.to_string().contains("--wait-for-clock-sync"));
+    }
+
+    #[tokio::test]
+    async fn resolves_preferred_family_first() {
+        let addrs = resolve_preferring("127.0.0.1", IpFamily::Ipv6).await;
+        assert_eq!(addrs, vec![SocketAddr::from(([127, 0, 0, 1], 0))]);
+        assert!(
+            resolve_preferring("nix-installer-test.invalid", IpFamily::Ipv4)
+                .await
+                .is_empty()
+        );
+    }
+
+    /// Requests seen by [`serve_ranges`], the requested `Range` or `None` for the whole body
+    type SeenRanges = std::sync::Arc<std::sync::Mutex<Vec<Option<String>>>>;
+
+    /// Serve `body` on localhost, with an `ETag` and optionally range support, answering ranges starting at `fail_start` with a `500`
+    async fn serve_ranges(
+        body: Vec<u8>,
+        ranges: bool,
+        fail_start: Option<u64>,
+    ) -> std::io::Result<(u16, SeenRanges)> {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let body = std::sync::Arc::new(body);
+        let seen = SeenRanges::default();
+        let server_seen = seen.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (body, seen) = (body.clone(), server_seen.clone());
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => request.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_lowercase();
+                    let range = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .map(ToString::to_string);
+                    seen.lock().unwrap().push(range.clone());
+
+                    let parsed = range.as_deref().and_then(|range| {
+                        let (start, end) = range.split_once('-')?;
+                        Some((start.parse::<u64>().ok()?, end.parse::<u64>().ok()?))
+                    });
+                    let (head, content) = match parsed {
+                        Some((start, _)) if ranges && Some(start) == fail_start => {
+                            ("HTTP/1.1 500 Internal Server Error".to_string(), &body[..0])
+                        },
+                        Some((start, end)) if ranges => (
+                            format!(
+                                "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes {start}-{end}/{}",
+                                body.len()
+                            ),
+                            &body[start as usize..=end as usize],
+                        ),
+                        _ => ("HTTP/1.1 200 OK".to_string(), &body[..]),
+                    };
+                    let head = format!(
+                        "{head}\r\netag: \"fixture\"\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        content.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(content).await;
+                });
+            }
+        });
+        Ok((port, seen))
+    }
+
+    /// Download from `port` over four connections, returning what was downloaded
+    async fn download_from(port: u16, dest: &Path) -> eyre::Result<Vec<u8>> {
+        let url: Url = format!("http://127.0.0.1:{port}/nix.tar.xz").parse()?;
+        let action = FetchAndUnpackNix::plan(
+            UrlOrPath::Url(url.clone()),
+            dest.to_path_buf(),
+            None,
+            None,
+            None,
+            4096,
+            4,
+        )
+        .await?;
+        let download_path = dest.join(DOWNLOAD_FILE_NAME);
+        action.action.download(&url, &download_path).await?;
+        assert!(!dest.join(DOWNLOAD_PROGRESS_FILE_NAME).exists());
+        Ok(std::fs::read(download_path)?)
+    }
+
+    fn range_fixture() -> Vec<u8> {
+        (0..100_003u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn downloads_over_ranges() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let body = range_fixture();
+        let (port, seen) = serve_ranges(body.clone(), true, None).await?;
+
+        assert_eq!(download_from(port, temp_dir.path()).await?, body);
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                "0-0",
+                "0-25000",
+                "25001-50001",
+                "50002-75002",
+                "75003-100002"
+            ]
+            .map(|range| Some(range.to_string()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_a_range_fails() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let body = range_fixture();
+        let (port, seen) = serve_ranges(body.clone(), true, Some(50002)).await?;
+
+        assert_eq!(download_from(port, temp_dir.path()).await?, body);
+        assert!(seen.lock().unwrap().contains(&None));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_range_support() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let body = range_fixture();
+        let (port, seen) = serve_ranges(body.clone(), false, None).await?;
+
+        assert_eq!(download_from(port, temp_dir.path()).await?, body);
+        assert_eq!(*seen.lock().unwrap(), [Some("0-0".to_string()), None]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_completed_ranges() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let body = range_fixture();
+        let (port, seen) = serve_ranges(body.clone(), true, None).await?;
+
+        // Interrupted with the first two ranges written
+        let mut partial = body[..50002].to_vec();
+        partial.resize(body.len(), 0);
+        std::fs::write(temp_dir.path().join(DOWNLOAD_FILE_NAME), partial)?;
+        let progress = DownloadProgress {
+            content_length: body.len() as u64,
+            validator: "\"fixture\"".into(),
+            completed: vec![(0, 25001), (25001, 50002)],
+        };
+        std::fs::write(
+            temp_dir.path().join(DOWNLOAD_PROGRESS_FILE_NAME),
+            serde_json::to_vec(&progress)?,
+        )?;
+
+        assert_eq!(download_from(port, temp_dir.path()).await?, body);
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(
+            seen,
+            ["0-0", "50002-75002", "75003-100002"].map(|range| Some(range.to_string()))
+        );
+        Ok(())
+    }
+
+    /// How [`serve_flaky`] answers a request
+    #[derive(Clone, Copy)]
+    enum Reply {
+        Status(u16),
+        /// All of the body is announced, but the connection is closed after this many bytes
+        DropAfter(usize),
+        /// The body, from the start of an open ended `Range` if there is one
+        Body,
+    }
+
+    /// Serve `body` on localhost with an `ETag`, answering the nth request as `replies[n]`
+    async fn serve_flaky(body: Vec<u8>, replies: Vec<Reply>) -> std::io::Result<(u16, SeenRanges)> {
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let seen = SeenRanges::default();
+        let server_seen = seen.clone();
+        tokio::spawn(async move {
+            for reply in replies {
+                let Ok((mut stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let range = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .map(ToString::to_string);
+                server_seen.lock().unwrap().push(range.clone());
+
+                let start = range
+                    .as_deref()
+                    .and_then(|range| range.strip_suffix('-')?.parse::<usize>().ok());
+                let (head, content) = match (reply, start) {
+                    (Reply::Status(status), _) => {
+                        (format!("HTTP/1.1 {status} Failing"), &body[..0])
+                    },
+                    (Reply::Body, Some(start)) => (
+                        format!(
+                            "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes {start}-{}/{}",
+                            body.len() - 1,
+                            body.len()
+                        ),
+                        &body[start..],
+                    ),
+                    _ => ("HTTP/1.1 200 OK".to_string(), &body[..]),
+                };
+                let head = format!(
+                    "{head}\r\netag: \"fixture\"\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    content.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let content = match reply {
+                    Reply::DropAfter(sent) => &content[..sent],
+                    _ => content,
+                };
+                let _ = stream.write_all(content).await;
+            }
+        });
+        Ok((port, seen))
+    }
+
+    /// Download from `port` over a single connection, trying up to `attempts` times
+    async fn download_attempts_from(port: u16, dest: &Path, attempts: u8) -> eyre::Result<Vec<u8>> {
+        let url: Url = format!("http://127.0.0.1:{port}/nix.tar.xz").parse()?;
+        let mut action = FetchAndUnpackNix::plan(
+            UrlOrPath::Url(url.clone()),
+            dest.to_path_buf(),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+        action.action.set_download_attempts(attempts);
+        let download_path = dest.join(DOWNLOAD_FILE_NAME);
+        action.action.download(&url, &download_path).await?;
+        Ok(std::fs::read(download_path)?)
+    }
+
+    #[tokio::test]
+    async fn retries_resuming_an_interrupted_download() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let body = range_fixture();
+        let (port, seen) = serve_flaky(
+            body.clone(),
+            vec![Reply::Status(503), Reply::DropAfter(40_000), Reply::Body],
+        )
+        .await?;
+
+        let started = std::time::Instant::now();
+        assert_eq!(
+            download_attempts_from(port, temp_dir.path(), 3).await?,
+            body
+        );
+        assert!(started.elapsed() >= retry_backoff(1) + retry_backoff(2));
+        let seen = seen.lock().unwrap().clone();
+        assert_eq!(seen[..2], [None, None]);
+        // Resumed from wherever the dropped connection stopped writing
+        let resumed_from = seen[2]
+            .as_deref()
+            .and_then(|range| range.strip_suffix('-')?.parse::<usize>().ok())
+            .expect("The last request resumes");
+        assert!(resumed_from > 0 && resumed_from <= 40_000, "{resumed_from}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors_or_past_the_last_attempt() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let (port, seen) =
+            serve_flaky(range_fixture(), vec![Reply::Status(404), Reply::Body]).await?;
+        let err = download_attempts_from(port, temp_dir.path(), 3)
+            .await
+            .expect_err("A 404 is not retried");
+        assert!(format!("{err:?}").contains("404"), "{err:?}");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        let (port, seen) =
+            serve_flaky(range_fixture(), vec![Reply::Status(503), Reply::Body]).await?;
+        let err = download_attempts_from(port, temp_dir.path(), 1)
+            .await
+            .expect_err("A single attempt is not retried");
+        assert!(format!("{err:?}").contains("503"), "{err:?}");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(retry_backoff(1), DOWNLOAD_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(2), DOWNLOAD_RETRY_BACKOFF * 2);
+        assert_eq!(retry_backoff(4), DOWNLOAD_RETRY_BACKOFF * 8);
+    }
+
+    #[test]
+    fn splits_ranges() {
+        assert_eq!(split_ranges(10, 4), vec![(0, 3), (3, 6), (6, 9), (9, 10)]);
+        assert_eq!(split_ranges(2, 4), vec![(0, 1), (1, 2)]);
+        assert_eq!(parse_content_range("bytes 0-0/1234"), Some((0, 1234)));
+        assert_eq!(parse_content_range("bytes */1234"), None);
+    }
+
+    #[test]
+    fn formats_size_estimates() {
+        let known = SizeEstimate::known(&NIX_X64_64_LINUX_URL.parse().unwrap());
+        assert_eq!(
+            known.describe(),
+            "download: 24.0 MiB, unpacked: 150.0 MiB, scratch space: 512.0 MiB"
+        );
+        let custom = SizeEstimate::known(&"https://example.com/nix.tar.xz".parse().unwrap());
+        assert_eq!(
+            custom.describe(),
+            "download: unknown, unpacked: unknown, scratch space: 512.0 MiB"
+        );
+    }
+
+    #[test]
+    fn size_estimates_agree_with_preflight() {
+        for (url, download, unpacked) in KNOWN_SIZES {
+            let estimate = SizeEstimate::known(&url.parse().unwrap());
+            assert_eq!(estimate.download, Some(*download));
+            // The scratch space shown for a default tarball is exactly what is enforced
+            assert!(
+                download + unpacked <= SCRATCH_SPACE_NEEDED,
+                "{url} outgrew the preflight check"
+            );
+            assert_eq!(estimate.scratch_needed(), SCRATCH_SPACE_NEEDED);
+        }
+        let larger = SizeEstimate {
+            download: Some(SCRATCH_SPACE_NEEDED),
+            unpacked: Some(MIB),
+        };
+        assert_eq!(larger.scratch_needed(), SCRATCH_SPACE_NEEDED + MIB);
+    }
+
+    #[tokio::test]
+    async fn estimates_local_tarball_size() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tarball = temp_dir.path().join("nix.tar.xz");
+        write_fixture_tarball(&tarball)?;
+        let mut action = FetchAndUnpackNix::plan(
+            UrlOrPath::Path(tarball.clone()),
+            temp_dir.path().join("dest"),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+        action.action.estimate_size().await;
+        assert_eq!(
+            action.action.size_estimate(),
+            SizeEstimate {
+                download: Some(tarball.metadata()?.len()),
+                unpacked: None,
+            }
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 //! Base [`Action`](crate::action::Action)s that themselves have no other actions as dependencies
 
 pub(crate) mod add_user_to_group;
+pub(crate) mod create_ca_bundle;
 pub(crate) mod create_directory;
 pub(crate) mod create_file;
 pub(crate) mod create_group;
@@ -11,17 +12,25 @@ pub(crate) mod delete_user;
 pub(crate) mod fetch_and_unpack_nix;
 pub(crate) mod move_unpacked_nix;
 pub(crate) mod remove_directory;
+pub(crate) mod reown_nix_store;
+pub(crate) mod replaced_file;
 pub(crate) mod setup_default_profile;
+pub(crate) mod store_group;
+pub(crate) mod verify_nix_store;
 
 pub use add_user_to_group::AddUserToGroup;
+pub use create_ca_bundle::{CreateCaBundle, NIX_CA_BUNDLE, NIX_CA_BUNDLE_NAME};
 pub use create_directory::CreateDirectory;
 pub use create_file::CreateFile;
 pub use create_group::CreateGroup;
-pub use create_or_insert_into_file::CreateOrInsertIntoFile;
+pub use create_or_insert_into_file::{CreateOrInsertIntoFile, CreateOrInsertIntoFileError};
 pub use create_or_merge_nix_config::CreateOrMergeNixConfig;
 pub use create_user::CreateUser;
 pub use delete_user::DeleteUser;
-pub use fetch_and_unpack_nix::{FetchAndUnpackNix, FetchUrlError};
+pub use fetch_and_unpack_nix::{DownloadFailure, FetchAndUnpackNix, FetchUrlError, SizeEstimate};
 pub use move_unpacked_nix::{MoveUnpackedNix, MoveUnpackedNixError};
 pub use remove_directory::RemoveDirectory;
+pub use reown_nix_store::{Reown, ReownCheckpoint, ReownNixStore};
 pub use setup_default_profile::{SetupDefaultProfile, SetupDefaultProfileError};
+pub use store_group::{StoreGroupDecision, StoreGroupSample};
+pub use verify_nix_store::{StoreVerifyReport, VerifyNixStore, VerifyNixStoreError};
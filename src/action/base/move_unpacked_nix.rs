@@ -7,8 +7,9 @@ use std::{
 use tracing::{span, Span};
 use walkdir::WalkDir;
 
-use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+use crate::{
+    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    progress::{ProgressUnit, Tracker},
 };
 
 pub(crate) const DEST: &str = "/nix/";
@@ -90,6 +91,7 @@ impl Action for MoveUnpackedNix {
                 .map_err(Self::error)?;
         }
 
+        let tracker = Tracker::new("Moving Nix into place", None, ProgressUnit::Entries);
         while let Some(entry) = src_store_listing
             .next_entry()
             .await
@@ -134,6 +136,7 @@ impl Action for MoveUnpackedNix {
                 .await
                 .map_err(|e| ActionErrorKind::Symlink(entry_dest.to_owned(), entry.path(), e))
                 .map_err(Self::error)?;
+            tracker.advance(1);
         }
 
         Ok(())
@@ -1,15 +1,17 @@
 use std::path::{Path, PathBuf};
 
-use tokio::fs::remove_dir_all;
 use tracing::{span, Span};
 
 use crate::action::{Action, ActionDescription, ActionErrorKind, ActionState};
 use crate::action::{ActionError, StatefulAction};
+use crate::os::mounts;
+use crate::temp_artifacts;
 
 /** Remove a directory, does nothing on revert.
 */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct RemoveDirectory {
+    #[serde(with = "crate::lossless_path")]
     path: PathBuf,
 }
 
@@ -21,6 +23,9 @@ impl RemoveDirectory {
         Ok(StatefulAction {
             action: Self { path },
             state: ActionState::Uncompleted,
+            id: None,
+            timing: None,
+            reverted_at: None,
         })
     }
 }
@@ -55,12 +60,16 @@ impl Action for RemoveDirectory {
                     self.path.clone(),
                 )));
             }
-            remove_dir_all(&self.path)
+            mounts::ensure_no_mounts_under(&self.path)
                 .await
-                .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.clone(), e)))?;
+                .map_err(Self::error)?;
+            mounts::remove_dir_all_same_filesystem(&self.path)
+                .await
+                .map_err(Self::error)?;
         } else {
             tracing::debug!("Directory `{}` not present, skipping", self.path.display(),);
         };
+        temp_artifacts::unregister(&self.path);
 
         Ok(())
     }
@@ -0,0 +1,496 @@
+use std::{
+    collections::VecDeque,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use tracing::{span, Span};
+
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+/// How many entries to change the owner of at a time, cancellation is checked between batches
+const BATCH_SIZE: usize = 4096;
+/// How many batches between writing the checkpoint file
+const CHECKPOINT_INTERVAL: usize = 8;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Next to the store, where the checkpoint survives the installer being killed
+const CHECKPOINT_FILE_NAME: &str = ".reown-nix-store.json";
+
+/// Entries to change, with the owner and group to give them
+type Batch = Vec<(PathBuf, (Option<Uid>, Option<Gid>))>;
+
+/// Changes of owner, as `(from, to)` pairs, anything not owned by a `from` is left alone
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Reown {
+    pub uid: Option<(u32, u32)>,
+    pub gid: Option<(u32, u32)>,
+}
+
+impl Reown {
+    fn inverted(&self) -> Self {
+        Self {
+            uid: self.uid.map(|(from, to)| (to, from)),
+            gid: self.gid.map(|(from, to)| (to, from)),
+        }
+    }
+
+    /// The owner and group to give an entry, `None` if it needs no change
+    fn apply(&self, metadata: &std::fs::Metadata) -> Option<(Option<Uid>, Option<Gid>)> {
+        let uid = self
+            .uid
+            .filter(|(from, _)| metadata.uid() == *from)
+            .map(|(_, to)| Uid::from_raw(to));
+        let gid = self
+            .gid
+            .filter(|(from, _)| metadata.gid() == *from)
+            .map(|(_, to)| Gid::from_raw(to));
+        (uid.is_some() || gid.is_some()).then_some((uid, gid))
+    }
+}
+
+impl std::fmt::Display for Reown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let changes = [("UID", self.uid), ("GID", self.gid)]
+            .into_iter()
+            .filter_map(|(name, change)| {
+                let (from, to) = change?;
+                Some(format!("{name} {from} to {to}"))
+            })
+            .collect::<Vec<_>>();
+        f.write_str(&changes.join(" and "))
+    }
+}
+
+/// Where an interrupted run of [`ReownNixStore`] stopped
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ReownCheckpoint {
+    /// If the run was reverting, rather than executing
+    reverting: bool,
+    /// The last store path (in sorted order) whose entries were all changed
+    after: String,
+    /// How many entries were visited up to and including [`after`](ReownCheckpoint::after)
+    visited: u64,
+}
+
+/// What a run of [`ReownNixStore`] did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReownStats {
+    /// Entries looked at by this run, not counting those skipped by resuming
+    pub(crate) visited: u64,
+    pub(crate) changed: u64,
+    pub(crate) cancelled: bool,
+}
+
+/**
+Change the owner and group of the entries of a large store in bulk
+
+Store paths are walked in sorted order, each breadth-first, and changed in batches. Symlinks are
+changed themselves, never followed, and other filesystems mounted inside the store are not entered.
+
+Between batches it reports progress, stops if the plan was cancelled, and records how far it got in
+its receipt entry (and periodically in a checkpoint file next to the store, in case the installer is
+killed), so running it again continues where it left off.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ReownNixStore {
+    path: PathBuf,
+    reown: Reown,
+    #[serde(default)]
+    checkpoint: Option<ReownCheckpoint>,
+}
+
+impl ReownNixStore {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn plan(path: impl AsRef<Path>, reown: Reown) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            reown,
+            checkpoint: None,
+        }
+        .into())
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.path.with_file_name(CHECKPOINT_FILE_NAME)
+    }
+
+    /// The checkpoint to resume from, the further of the one in the receipt and the one on disk
+    fn resume_from(&self, reverting: bool) -> Option<ReownCheckpoint> {
+        let on_disk = std::fs::read(self.checkpoint_path())
+            .ok()
+            .and_then(|buf| serde_json::from_slice::<ReownCheckpoint>(&buf).ok());
+        [self.checkpoint.clone(), on_disk]
+            .into_iter()
+            .flatten()
+            .filter(|checkpoint| checkpoint.reverting == reverting)
+            .max_by_key(|checkpoint| checkpoint.visited)
+    }
+
+    fn write_checkpoint(&self) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        let path = self.checkpoint_path();
+        let written = serde_json::to_vec(checkpoint)
+            .map_err(std::io::Error::from)
+            .and_then(|buf| std::fs::write(&path, buf));
+        if let Err(e) = written {
+            tracing::debug!("Could not write checkpoint `{}`: {e}", path.display());
+        }
+    }
+
+    /// Change every entry of the store, resuming from the last checkpoint of a run in the same direction
+    pub(crate) async fn run(&mut self, reverting: bool) -> Result<ReownStats, ActionErrorKind> {
+        let reown = if reverting {
+            self.reown.inverted()
+        } else {
+            self.reown
+        };
+        let mut stats = ReownStats::default();
+        let resume_from = self.resume_from(reverting);
+        if let Some(checkpoint) = &resume_from {
+            tracing::info!(
+                "Resuming changing the owner of `{}` after `{}`",
+                self.path.display(),
+                checkpoint.after
+            );
+        }
+
+        let root = self
+            .path
+            .symlink_metadata()
+            .map_err(|e| ActionErrorKind::GettingMetadata(self.path.clone(), e))?;
+        let mut batch = Vec::new();
+        if resume_from.is_none() {
+            stats.visited += 1;
+            if let Some(owner) = reown.apply(&root) {
+                batch.push((self.path.clone(), owner));
+            }
+        }
+
+        let mut store_paths = self
+            .path
+            .read_dir()
+            .map_err(|e| ActionErrorKind::ReadDir(self.path.clone(), e))?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        store_paths.sort();
+        let total_store_paths = store_paths.len();
+        let skipped = match &resume_from {
+            Some(checkpoint) => store_paths
+                .iter()
+                .take_while(|name| **name <= checkpoint.after)
+                .count(),
+            None => 0,
+        };
+        let previously_visited = resume_from
+            .as_ref()
+            .map(|checkpoint| checkpoint.visited)
+            .unwrap_or_default();
+
+        let mut batches = 0;
+        let mut last_progress = Instant::now();
+        for (index, name) in store_paths.iter().enumerate().skip(skipped) {
+            stats.visited += walk(&self.path.join(name), root.dev(), &reown, &mut batch);
+
+            let is_last = index + 1 == total_store_paths;
+            if batch.len() < BATCH_SIZE && !is_last {
+                continue;
+            }
+            stats.changed += change_owners(&mut batch)?;
+            batches += 1;
+            self.checkpoint = Some(ReownCheckpoint {
+                reverting,
+                after: name.clone(),
+                visited: previously_visited + stats.visited,
+            });
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                // Extrapolated from the store paths done so far
+                let done = index + 1;
+                let visited = previously_visited + stats.visited;
+                tracing::info!(
+                    "Changing the owner of `{}`: {visited} of ~{} entries",
+                    self.path.display(),
+                    visited * total_store_paths as u64 / done as u64
+                );
+            }
+            if crate::cancellation::requested() {
+                self.write_checkpoint();
+                stats.cancelled = true;
+                return Ok(stats);
+            }
+            if batches % CHECKPOINT_INTERVAL == 0 {
+                self.write_checkpoint();
+            }
+            tokio::task::yield_now().await;
+        }
+        stats.changed += change_owners(&mut batch)?;
+
+        self.checkpoint = None;
+        if let Err(e) = std::fs::remove_file(self.checkpoint_path()) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::debug!(
+                    "Could not remove checkpoint `{}`: {e}",
+                    self.checkpoint_path().display()
+                );
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Walk the store path at `path` breadth-first, adding the entries `reown` changes to `batch` and returning how many were visited
+fn walk(path: &Path, dev: u64, reown: &Reown, batch: &mut Batch) -> u64 {
+    let mut visited = 0;
+    let mut queue = VecDeque::from([path.to_path_buf()]);
+    let mut is_store_path = true;
+    while let Some(path) = queue.pop_front() {
+        let entries = if is_store_path {
+            is_store_path = false;
+            vec![path]
+        } else {
+            let Ok(entries) = path.read_dir() else {
+                continue;
+            };
+            entries.flatten().map(|entry| entry.path()).collect()
+        };
+        for entry in entries {
+            let Ok(metadata) = entry.symlink_metadata() else {
+                continue;
+            };
+            if metadata.dev() != dev {
+                tracing::debug!(
+                    "Not entering `{}`, it is on another filesystem",
+                    entry.display()
+                );
+                continue;
+            }
+            visited += 1;
+            if let Some(owner) = reown.apply(&metadata) {
+                batch.push((entry.clone(), owner));
+            }
+            if metadata.is_dir() {
+                queue.push_back(entry);
+            }
+        }
+    }
+    visited
+}
+
+fn change_owners(batch: &mut Batch) -> Result<u64, ActionErrorKind> {
+    let changed = batch.len() as u64;
+    for (path, (uid, gid)) in batch.drain(..) {
+        fchownat(None, &path, uid, gid, FchownatFlags::NoFollowSymlink)
+            .map_err(|e| ActionErrorKind::Chown(path, e))?;
+    }
+    Ok(changed)
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "reown_nix_store")]
+impl Action for ReownNixStore {
+    fn action_tag() -> ActionTag {
+        ActionTag("reown_nix_store")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Change the owner of `{}` from {}",
+            self.path.display(),
+            self.reown
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "reown_nix_store",
+            path = tracing::field::display(self.path.display()),
+            reown = tracing::field::display(self.reown),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                "This can take several minutes on large stores, if interrupted it continues where it left off".to_string(),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let stats = self.run(false).await.map_err(Self::error)?;
+        if stats.cancelled {
+            return Err(Self::error(ActionErrorKind::Cancelled));
+        }
+        tracing::debug!(
+            "Changed the owner of {} of {} entries of `{}`",
+            stats.changed,
+            stats.visited,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Change the owner of `{}` back from {}",
+                self.path.display(),
+                self.reown.inverted()
+            ),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let stats = self.run(true).await.map_err(Self::error)?;
+        if stats.cancelled {
+            return Err(Self::error(ActionErrorKind::Cancelled));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{lchown, symlink};
+
+    use super::*;
+
+    const OLD_GID: u32 = 30000;
+    const NEW_GID: u32 = 3000;
+    const STORE_PATHS: usize = 200;
+    const FILES_PER_STORE_PATH: usize = 100;
+
+    /// A store of tens of thousands of small files, all group owned by [`OLD_GID`]
+    fn store() -> eyre::Result<(tempfile::TempDir, PathBuf)> {
+        let temp_dir = tempfile::tempdir()?;
+        let store = temp_dir.path().join("store");
+        for index in 0..STORE_PATHS {
+            let store_path = store.join(format!("{index:032}-package-{index}"));
+            let share = store_path.join("share");
+            std::fs::create_dir_all(&share)?;
+            for file in 0..FILES_PER_STORE_PATH {
+                std::fs::write(share.join(file.to_string()), "")?;
+            }
+            symlink("/nix/store/elsewhere", store_path.join("link"))?;
+        }
+        for entry in walkdir::WalkDir::new(&store) {
+            lchown(entry?.path(), Some(0), Some(OLD_GID))?;
+        }
+        Ok((temp_dir, store))
+    }
+
+    /// Every entry in `store` and its group
+    fn gids(store: &Path) -> Vec<u32> {
+        walkdir::WalkDir::new(store)
+            .into_iter()
+            .map(|entry| entry.unwrap().path().symlink_metadata().unwrap().gid())
+            .collect()
+    }
+
+    fn regroup() -> Reown {
+        Reown {
+            uid: None,
+            gid: Some((OLD_GID, NEW_GID)),
+        }
+    }
+
+    #[tokio::test]
+    async fn reowns_a_large_store() -> eyre::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, changing ownership needs root");
+            return Ok(());
+        }
+        let (_temp_dir, store) = store()?;
+        let entries = 1 + STORE_PATHS * (3 + FILES_PER_STORE_PATH);
+
+        let mut action = ReownNixStore::plan(&store, regroup())?;
+        let started = Instant::now();
+        let stats = action.action.run(false).await?;
+        // Generous, a naive walk of this tree takes well under a second
+        assert!(started.elapsed() < Duration::from_secs(30));
+        assert_eq!(stats.visited, entries as u64);
+        assert_eq!(stats.changed, entries as u64);
+        assert!(gids(&store).iter().all(|gid| *gid == NEW_GID));
+        // Finished, so nothing to resume from
+        assert_eq!(action.action.checkpoint, None);
+        assert!(!action.action.checkpoint_path().exists());
+
+        action.action.revert().await?;
+        assert!(gids(&store).iter().all(|gid| *gid == OLD_GID));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_after_cancellation() -> eyre::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, changing ownership needs root");
+            return Ok(());
+        }
+        let (_temp_dir, store) = store()?;
+        let entries = (1 + STORE_PATHS * (3 + FILES_PER_STORE_PATH)) as u64;
+
+        // Cancelled as soon as it starts, so it stops after the first batch
+        let (sender, receiver) = tokio::sync::broadcast::channel(1);
+        let mut action = ReownNixStore::plan(&store, regroup())?;
+        let result = crate::cancellation::scope(Some(&receiver), async {
+            sender.send(()).expect("Expected a receiver");
+            action.try_execute().await
+        })
+        .await;
+        assert!(matches!(
+            result
+                .map_err(|e| e.kind().to_string())
+                .as_ref()
+                .map_err(String::as_str),
+            Err("Cancelled by user")
+        ));
+        let checkpoint = action
+            .action
+            .checkpoint
+            .clone()
+            .expect("Expected a checkpoint");
+        assert!(checkpoint.visited >= BATCH_SIZE as u64 && checkpoint.visited < entries);
+        assert!(action.action.checkpoint_path().exists());
+        let changed = gids(&store).iter().filter(|gid| **gid == NEW_GID).count() as u64;
+        assert_eq!(changed, checkpoint.visited);
+
+        // Through the receipt, as a resumed install would
+        let mut resumed: StatefulAction<ReownNixStore> =
+            serde_json::from_value(serde_json::to_value(&action)?)?;
+        std::fs::remove_file(resumed.action.checkpoint_path())?;
+        let stats = resumed.action.run(false).await?;
+        assert_eq!(stats.visited, entries - checkpoint.visited);
+        assert_eq!(stats.changed, entries - checkpoint.visited);
+        assert!(gids(&store).iter().all(|gid| *gid == NEW_GID));
+        Ok(())
+    }
+
+    #[test]
+    fn reown_applies_and_inverts() {
+        let reown = Reown {
+            uid: Some((1000, 0)),
+            gid: Some((100, 30000)),
+        };
+        assert_eq!(reown.to_string(), "UID 1000 to 0 and GID 100 to 30000");
+        assert_eq!(reown.inverted().inverted(), reown);
+        assert_eq!(
+            reown.inverted().to_string(),
+            "UID 0 to 1000 and GID 30000 to 100"
+        );
+    }
+}
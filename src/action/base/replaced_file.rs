@@ -0,0 +1,230 @@
+/*! Writing files atomically, keeping what they replaced (eg. with `--force`) to put it back on revert
+
+The original bytes, mode and ownership are kept in the action replacing the file, so in the receipt,
+rather than in a stash under `/nix`: uninstalling removes `/nix`, and putting back what the install
+replaced must not depend on in which order that happens.
+*/
+
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{chown, Gid, Uid};
+use rand::Rng;
+use tokio::{
+    fs::{remove_file, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::action::ActionErrorKind;
+
+/// What is at a path the installer wrote a regular file to, without following symlinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Written {
+    Missing,
+    File,
+    /// Replaced since, by what (such as `a symlink`), which reverting must leave alone
+    Replaced(&'static str),
+}
+
+impl Written {
+    pub(crate) async fn inspect(path: &Path) -> Result<Self, ActionErrorKind> {
+        let file_type = match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) => metadata.file_type(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::Missing),
+            Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_owned(), e)),
+        };
+        Ok(if file_type.is_file() {
+            Self::File
+        } else if file_type.is_symlink() {
+            Self::Replaced("a symlink")
+        } else if file_type.is_dir() {
+            Self::Replaced("a directory")
+        } else {
+            Self::Replaced("something other than a regular file")
+        })
+    }
+}
+
+/// What a file had before the installer replaced it
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct Original {
+    contents: Contents,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// The original bytes, as text when they are UTF-8 so receipts stay readable
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+enum Contents {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Original {
+    /// What the regular file at `path` has, `None` if there is nothing there
+    pub(crate) async fn read(path: &Path) -> Result<Option<Self>, ActionErrorKind> {
+        let metadata = match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ActionErrorKind::GettingMetadata(path.to_owned(), e)),
+        };
+        if !metadata.is_file() {
+            return Err(ActionErrorKind::PathWasNotFile(path.to_owned()));
+        }
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|e| ActionErrorKind::Read(path.to_owned(), e))?;
+        let contents = match String::from_utf8(bytes) {
+            Ok(text) => Contents::Text(text),
+            Err(e) => Contents::Bytes(e.into_bytes()),
+        };
+        Ok(Some(Self {
+            contents,
+            mode: metadata.permissions().mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        }))
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        match &self.contents {
+            Contents::Text(text) => text.as_bytes(),
+            Contents::Bytes(bytes) => bytes,
+        }
+    }
+
+    /// Put the original back at `path` exactly, replacing whatever is there
+    pub(crate) async fn restore(&self, path: &Path) -> Result<(), ActionErrorKind> {
+        replace(
+            path,
+            self.bytes(),
+            Some(self.mode),
+            Some(Uid::from_raw(self.uid)),
+            Some(Gid::from_raw(self.gid)),
+        )
+        .await
+    }
+}
+
+/**
+Write `buf` to `path` through a temporary file beside it renamed over it, so `path` never holds
+anything but the old or the new contents
+
+The temporary file is given `mode` (exactly, regardless of the umask) and owner before the rename.
+Without a mode, it gets the one any newly created file would.
+*/
+pub(crate) async fn replace(
+    path: &Path,
+    buf: &[u8],
+    mode: Option<u32>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+) -> Result<(), ActionErrorKind> {
+    let temp_file_path = temp_path_beside(path);
+    let written = write_temp_file(&temp_file_path, buf, mode, uid, gid).await;
+    let renamed = match written {
+        Ok(()) => tokio::fs::rename(&temp_file_path, path)
+            .await
+            .map_err(|e| ActionErrorKind::Rename(temp_file_path.clone(), path.to_owned(), e)),
+        Err(e) => Err(e),
+    };
+    if renamed.is_err() {
+        let _ = remove_file(&temp_file_path).await;
+    }
+    renamed
+}
+
+async fn write_temp_file(
+    temp_file_path: &Path,
+    buf: &[u8],
+    mode: Option<u32>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+) -> Result<(), ActionErrorKind> {
+    let mut temp_file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(if mode.is_some() { 0o600 } else { 0o666 })
+        .open(temp_file_path)
+        .await
+        .map_err(|e| ActionErrorKind::Open(temp_file_path.to_owned(), e))?;
+    temp_file
+        .write_all(buf)
+        .await
+        .map_err(|e| ActionErrorKind::Write(temp_file_path.to_owned(), e))?;
+    temp_file
+        .flush()
+        .await
+        .map_err(|e| ActionErrorKind::Flush(temp_file_path.to_owned(), e))?;
+    chown(temp_file_path, uid, gid)
+        .map_err(|e| ActionErrorKind::Chown(temp_file_path.to_owned(), e))?;
+    // After the `chown`, which clears setuid and setgid bits
+    if let Some(mode) = mode {
+        tokio::fs::set_permissions(temp_file_path, PermissionsExt::from_mode(mode))
+            .await
+            .map_err(|e| ActionErrorKind::SetPermissions(mode, temp_file_path.to_owned(), e))?;
+    }
+    Ok(())
+}
+
+/// A path for a temporary file beside `path`, so it can be renamed over it atomically
+pub(crate) fn temp_path_beside(path: &Path) -> PathBuf {
+    let parent_dir = path.parent().expect("File must be in a directory");
+    parent_dir.join(format!(
+        "nix-installer-tmp.{}",
+        rand::thread_rng().gen::<u32>()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn restores_bytes_and_mode_exactly() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let test_file = temp_dir.path().join("restores_bytes_and_mode_exactly");
+        for original_bytes in [b"Some text\n".to_vec(), vec![0xff, 0x00, 0xfe, b'\n']] {
+            tokio::fs::write(&test_file, &original_bytes).await?;
+            tokio::fs::set_permissions(&test_file, PermissionsExt::from_mode(0o4751)).await?;
+            let original = Original::read(&test_file)
+                .await?
+                .expect("The file was just written");
+            // It is kept in the receipt
+            let original: Original = serde_json::from_str(&serde_json::to_string(&original)?)?;
+
+            replace(&test_file, b"Replaced", Some(0o644), None, None).await?;
+            assert_eq!(tokio::fs::read(&test_file).await?, b"Replaced");
+
+            original.restore(&test_file).await?;
+            assert_eq!(tokio::fs::read(&test_file).await?, original_bytes);
+            let metadata = tokio::fs::metadata(&test_file).await?;
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o4751);
+            assert_eq!(metadata.uid(), nix::unistd::geteuid().as_raw());
+        }
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())?.count(),
+            1,
+            "No temporary files should be left behind"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_nothing_where_there_is_nothing() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        assert_eq!(
+            Original::read(&temp_dir.path().join("missing")).await?,
+            None
+        );
+        assert!(matches!(
+            Original::read(temp_dir.path()).await,
+            Err(ActionErrorKind::PathWasNotFile(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,17 +1,27 @@
-use std::path::PathBuf;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     action::{ActionError, ActionErrorKind, ActionTag, StatefulAction},
-    execute_command, set_env,
+    env, execute_command,
 };
 
 use glob::glob;
 
-use tokio::{io::AsyncWriteExt, process::Command};
+use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{Action, ActionDescription};
 
+/// The profile `nix` and `nss-cacert` are installed into
+const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+/// The packages installed into [`DEFAULT_PROFILE`], by the names `nix-env` knows them by
+const PROFILE_PACKAGES: &[&str] = &["nix", "nss-cacert"];
+/// Who `nix-env` runs as, whose home gets `~/.nix-profile` and `~/.nix-defexpr`
+const ROOT_USER: &str = "root";
+
 /**
 Setup the default Nix profile with `nss-cacert` and `nix` itself.
  */
@@ -25,6 +35,29 @@ impl SetupDefaultProfile {
     pub async fn plan(unpacked_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
         Ok(Self { unpacked_path }.into())
     }
+
+    /// `program` with the `HOME` and `USER` of [`ROOT_USER`] from the user database
+    ///
+    /// Not inherited, as `sudo` keeps the caller's `HOME` on some distributions, which would set
+    /// up their `~/.nix-profile` and `~/.nix-defexpr` instead.
+    fn command(&self, program: impl AsRef<OsStr>) -> Result<Command, ActionError> {
+        let home = nix::unistd::User::from_name(ROOT_USER)
+            .ok()
+            .flatten()
+            .map(|user| user.dir)
+            .ok_or_else(|| {
+                Self::error(SetupDefaultProfileError::NoHome {
+                    user: ROOT_USER.to_string(),
+                })
+            })?;
+        let mut command = Command::new(program);
+        command
+            .process_group(0)
+            .stdin(std::process::Stdio::null())
+            .env(env::HOME.name, home)
+            .env(env::USER.name, ROOT_USER);
+        Ok(command)
+    }
 }
 
 #[async_trait::async_trait]
@@ -51,61 +84,49 @@ impl Action for SetupDefaultProfile {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
-        // Find an `nix` package
-        let nix_pkg_glob = format!("{}/nix-*/store/*-nix-*.*.*", self.unpacked_path.display());
-        let mut found_nix_pkg = None;
-        for entry in glob(&nix_pkg_glob).map_err(Self::error)? {
-            match entry {
-                Ok(path) => {
-                    // If we are curing, the user may have multiple of these installed
-                    if let Some(_existing) = found_nix_pkg {
-                        return Err(Self::error(SetupDefaultProfileError::MultipleNixPackages))?;
-                    } else {
-                        found_nix_pkg = Some(path);
-                    }
-                    break;
-                },
-                Err(_) => continue, /* Ignore it */
-            };
-        }
-        let nix_pkg = if let Some(nix_pkg) = found_nix_pkg {
-            tokio::fs::read_link(&nix_pkg)
+        // Find the `nix` package, a partial install cured over may have left others
+        let nix_pkg_glob = format!("{}/nix-*/store/*-nix-*", self.unpacked_path.display());
+        let nix_candidates = glob(&nix_pkg_glob)
+            .map_err(Self::error)?
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let nix_pkg = match select_package(&nix_candidates, "nix", "bin/nix-env") {
+            Some(nix_pkg) => tokio::fs::read_link(&nix_pkg)
                 .await
                 .map_err(|e| ActionErrorKind::ReadSymlink(nix_pkg, e))
-                .map_err(Self::error)?
-        } else {
-            return Err(Self::error(SetupDefaultProfileError::NoNix));
+                .map_err(Self::error)?,
+            None => {
+                return Err(Self::error(SetupDefaultProfileError::NoNix {
+                    glob: nix_pkg_glob,
+                    found: nix_candidates,
+                }))
+            },
         };
 
         // Find an `nss-cacert` package, add it too.
         let nss_ca_cert_pkg_glob = format!(
-            "{}/nix-*/store/*-nss-cacert-*.*",
+            "{}/nix-*/store/*-nss-cacert-*",
             self.unpacked_path.display()
         );
-        let mut found_nss_ca_cert_pkg = None;
-        for entry in glob(&nss_ca_cert_pkg_glob).map_err(Self::error)? {
-            match entry {
-                Ok(path) => {
-                    // If we are curing, the user may have multiple of these installed
-                    if let Some(_existing) = found_nss_ca_cert_pkg {
-                        return Err(Self::error(
-                            SetupDefaultProfileError::MultipleNssCaCertPackages,
-                        ))?;
-                    } else {
-                        found_nss_ca_cert_pkg = Some(path);
-                    }
-                    break;
-                },
-                Err(_) => continue, /* Ignore it */
-            };
-        }
-        let nss_ca_cert_pkg = if let Some(nss_ca_cert_pkg) = found_nss_ca_cert_pkg {
-            tokio::fs::read_link(&nss_ca_cert_pkg)
+        let nss_ca_cert_candidates = glob(&nss_ca_cert_pkg_glob)
+            .map_err(Self::error)?
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let nss_ca_cert_pkg = match select_package(
+            &nss_ca_cert_candidates,
+            "nss-cacert",
+            "etc/ssl/certs/ca-bundle.crt",
+        ) {
+            Some(nss_ca_cert_pkg) => tokio::fs::read_link(&nss_ca_cert_pkg)
                 .await
                 .map_err(|e| ActionErrorKind::ReadSymlink(nss_ca_cert_pkg, e))
-                .map_err(Self::error)?
-        } else {
-            return Err(Self::error(SetupDefaultProfileError::NoNssCacert));
+                .map_err(Self::error)?,
+            None => {
+                return Err(Self::error(SetupDefaultProfileError::NoNssCacert {
+                    glob: nss_ca_cert_pkg_glob,
+                    found: nss_ca_cert_candidates,
+                }))
+            },
         };
 
         let found_nix_paths = glob::glob(&format!("{}/nix-*", self.unpacked_path.display()))
@@ -121,45 +142,17 @@ impl Action for SetupDefaultProfile {
             .await
             .map_err(|e| ActionErrorKind::Read(reginfo_path.to_path_buf(), e))
             .map_err(Self::error)?;
-        let mut load_db_command = Command::new(nix_pkg.join("bin/nix-store"));
-        load_db_command.process_group(0);
+        let mut load_db_command = self.command(nix_pkg.join("bin/nix-store"))?;
         load_db_command.arg("--load-db");
         load_db_command.stdin(std::process::Stdio::piped());
         load_db_command.stdout(std::process::Stdio::piped());
         load_db_command.stderr(std::process::Stdio::piped());
-        load_db_command.env(
-            "HOME",
-            dirs::home_dir().ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
-        );
         tracing::trace!(
             "Executing `{:?}` with stdin from `{}`",
             load_db_command.as_std(),
             reginfo_path.display()
         );
-        let mut handle = load_db_command
-            .spawn()
-            .map_err(|e| ActionErrorKind::command(&load_db_command, e))
-            .map_err(Self::error)?;
-
-        let mut stdin = handle.stdin.take().unwrap();
-        stdin
-            .write_all(&reginfo)
-            .await
-            .map_err(|e| ActionErrorKind::Write(PathBuf::from("/dev/stdin"), e))
-            .map_err(Self::error)?;
-        stdin
-            .flush()
-            .await
-            .map_err(|e| ActionErrorKind::Write(PathBuf::from("/dev/stdin"), e))
-            .map_err(Self::error)?;
-        drop(stdin);
-        tracing::trace!(
-            "Wrote `{}` to stdin of `nix-store --load-db`",
-            reginfo_path.display()
-        );
-
-        let output = handle
-            .wait_with_output()
+        let output = crate::command_runner::output_with_stdin(&mut load_db_command, &reginfo)
             .await
             .map_err(|e| ActionErrorKind::command(&load_db_command, e))
             .map_err(Self::error)?;
@@ -172,18 +165,11 @@ impl Action for SetupDefaultProfile {
 
         // Install `nix` itself into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
-                .process_group(0)
+            self.command(nix_pkg.join("bin/nix-env"))?
                 .arg("-i")
                 .arg(&nix_pkg)
-                .stdin(std::process::Stdio::null())
-                .env(
-                    "HOME",
-                    dirs::home_dir()
-                        .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
-                )
                 .env(
-                    "NIX_SSL_CERT_FILE",
+                    env::NIX_SSL_CERT_FILE.name,
                     nss_ca_cert_pkg.join("etc/ssl/certs/ca-bundle.crt"),
                 ), /* This is apparently load bearing... */
         )
@@ -192,28 +178,18 @@ impl Action for SetupDefaultProfile {
 
         // Install `nix` itself into the store
         execute_command(
-            Command::new(nix_pkg.join("bin/nix-env"))
-                .process_group(0)
+            self.command(nix_pkg.join("bin/nix-env"))?
                 .arg("-i")
                 .arg(&nss_ca_cert_pkg)
-                .stdin(std::process::Stdio::null())
-                .env(
-                    "HOME",
-                    dirs::home_dir()
-                        .ok_or_else(|| Self::error(SetupDefaultProfileError::NoRootHome))?,
-                )
                 .env(
-                    "NIX_SSL_CERT_FILE",
+                    env::NIX_SSL_CERT_FILE.name,
                     nss_ca_cert_pkg.join("etc/ssl/certs/ca-bundle.crt"),
                 ), /* This is apparently load bearing... */
         )
         .await
         .map_err(Self::error)?;
 
-        set_env(
-            "NIX_SSL_CERT_FILE",
-            "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
-        );
+        env::NIX_SSL_CERT_FILE.set("/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt");
 
         Ok(())
     }
@@ -221,31 +197,118 @@ impl Action for SetupDefaultProfile {
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
             "Unset the default Nix profile".to_string(),
-            vec![],
+            vec![format!(
+                "Uninstall {} from `{DEFAULT_PROFILE}`",
+                PROFILE_PACKAGES
+                    .iter()
+                    .map(|package| format!("`{package}`"))
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            )],
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
-        std::env::remove_var("NIX_SSL_CERT_FILE");
+        env::NIX_SSL_CERT_FILE.remove();
+
+        let Some(nix_env) = profile_nix_env(Path::new(DEFAULT_PROFILE)) else {
+            // Reverting removed the store before, and the profile with it
+            tracing::debug!(
+                "`{DEFAULT_PROFILE}` has no `nix-env`, the store was already removed, nothing to uninstall"
+            );
+            return Ok(());
+        };
+        execute_command(
+            self.command(&nix_env)?
+                .arg("--profile")
+                .arg(DEFAULT_PROFILE)
+                .arg("--uninstall")
+                .args(PROFILE_PACKAGES),
+        )
+        .await
+        .map_err(Self::error)?;
 
         Ok(())
     }
 }
 
+/// The `nix-env` of `profile` in the store, so uninstalling `nix` from the profile doesn't remove it from under itself
+fn profile_nix_env(profile: &Path) -> Option<PathBuf> {
+    std::fs::canonicalize(profile.join("bin/nix-env")).ok()
+}
+
+/**
+The newest of `candidates` named `<hash>-<name>-<version>` which have `required` inside
+
+Candidates with the same version are told apart by their path, so which is picked never depends on
+the order of the directory. Other outputs of the package (such as `nix-2.24.0-man`) and packages
+merely starting with `name` don't parse.
+*/
+fn select_package(candidates: &[PathBuf], name: &str, required: &str) -> Option<PathBuf> {
+    let mut packages = candidates
+        .iter()
+        .filter_map(|candidate| Some((package_version(candidate, name)?, candidate)))
+        .filter(|(_, candidate)| candidate.join(required).exists())
+        .collect::<Vec<_>>();
+    packages.sort();
+    let count = packages.len();
+    let (_, newest) = packages.pop()?;
+    if count > 1 {
+        tracing::info!(
+            "Found {count} `{name}` packages, using the newest, `{}`",
+            newest.display()
+        );
+    }
+    Some(newest.clone())
+}
+
+/// The version of the store path `path` if it is the package `name`, as its numeric components
+fn package_version(path: &Path, name: &str) -> Option<Vec<u64>> {
+    let file_name = path.file_name()?.to_str()?;
+    let (hash, rest) = file_name.split_once('-')?;
+    if hash.len() != 32 || !hash.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let version = rest.strip_prefix(name)?.strip_prefix('-')?;
+    if version.contains('-') {
+        return None;
+    }
+    let components = version.split('.').collect::<Vec<_>>();
+    let (last, init) = components.split_last()?;
+    let mut numbers = init
+        .iter()
+        .map(|component| component.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    // Unstable versions have a suffix, such as `2.25.0pre20240807_1a2b3c4d`
+    let digits = last
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(last.len());
+    numbers.push(last[..digits].parse().ok()?);
+    (numbers.len() >= 2).then_some(numbers)
+}
+
+fn listed(paths: &[PathBuf]) -> String {
+    if paths.is_empty() {
+        return "nothing".to_string();
+    }
+    paths
+        .iter()
+        .map(|path| format!("`{}`", path.display()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum SetupDefaultProfileError {
-    #[error("Unarchived Nix store did not appear to include a `nss-cacert` location")]
-    NoNssCacert,
-    #[error("Unarchived Nix store did not appear to include a `nix` location")]
-    NoNix,
-    #[error("No root home found to place channel configuration in")]
-    NoRootHome,
-    #[error("Unarchived Nix store appears to contain multiple `nss-ca-cert` packages, cannot select one")]
-    MultipleNssCaCertPackages,
-    #[error("Unarchived Nix store appears to contain multiple `nix` packages, cannot select one")]
-    MultipleNixPackages,
+    #[error("Unarchived Nix store did not appear to include a `nss-cacert` package with a CA bundle, `{glob}` found {}", listed(.found))]
+    NoNssCacert { glob: String, found: Vec<PathBuf> },
+    #[error("Unarchived Nix store did not appear to include a `nix` package with `bin/nix-env`, `{glob}` found {}\n\
+        The unpacked Nix tarball may be missing, check whether fetching and unpacking it failed before", listed(.found))]
+    NoNix { glob: String, found: Vec<PathBuf> },
+    #[error("No home directory found for `{user}`, whose profile `nix-env` sets up")]
+    NoHome { user: String },
 }
 
 impl From<SetupDefaultProfileError> for ActionErrorKind {
@@ -253,3 +316,153 @@ impl From<SetupDefaultProfileError> for ActionErrorKind {
         ActionErrorKind::Custom(Box::new(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use nix::unistd::User;
+
+    use super::*;
+
+    const HASH: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+    fn package(store: &Path, name: &str, inside: Option<&str>) -> eyre::Result<PathBuf> {
+        let path = store.join(format!("{HASH}-{name}"));
+        std::fs::create_dir_all(&path)?;
+        if let Some(inside) = inside {
+            std::fs::create_dir_all(path.join(inside).parent().unwrap())?;
+            std::fs::write(path.join(inside), "")?;
+        }
+        Ok(path)
+    }
+
+    #[test]
+    fn selects_newest_package_with_binaries() -> eyre::Result<()> {
+        let store = tempfile::tempdir()?;
+        let candidates = vec![
+            package(store.path(), "nix-2.24.0-man", Some("bin/nix-env"))?,
+            package(store.path(), "nix-2.18.1", Some("bin/nix-env"))?,
+            package(
+                store.path(),
+                "nix-2.25.0pre20240807_1a2b3c4d",
+                Some("bin/nix-env"),
+            )?,
+            package(store.path(), "nix-2.26.0", None)?,
+            package(store.path(), "nix-info-1.0.0", Some("bin/nix-env"))?,
+            package(store.path(), "nix-2.9.2", Some("bin/nix-env"))?,
+        ];
+        assert_eq!(
+            select_package(&candidates, "nix", "bin/nix-env"),
+            Some(
+                store
+                    .path()
+                    .join(format!("{HASH}-nix-2.25.0pre20240807_1a2b3c4d"))
+            )
+        );
+        // Not in the order of the directory
+        let reversed = candidates.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            select_package(&reversed, "nix", "bin/nix-env"),
+            select_package(&candidates, "nix", "bin/nix-env")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn selects_nss_cacert_by_version() -> eyre::Result<()> {
+        let store = tempfile::tempdir()?;
+        let bundle = "etc/ssl/certs/ca-bundle.crt";
+        let candidates = vec![
+            package(store.path(), "nss-cacert-3.98", Some(bundle))?,
+            package(store.path(), "nss-cacert-3.101", Some(bundle))?,
+        ];
+        assert_eq!(
+            select_package(&candidates, "nss-cacert", bundle),
+            Some(store.path().join(format!("{HASH}-nss-cacert-3.101")))
+        );
+        Ok(())
+    }
+
+    /// The variables set on `command`, rather than inherited
+    fn envs(command: &Command) -> HashMap<String, Option<String>> {
+        command
+            .as_std()
+            .get_envs()
+            .map(|(name, value)| {
+                (
+                    name.to_string_lossy().into_owned(),
+                    value.map(|value| value.to_string_lossy().into_owned()),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_as_root_from_the_user_database() -> eyre::Result<()> {
+        let action = SetupDefaultProfile::plan("/nix/temp-install-dir".into())
+            .await?
+            .action;
+        let root = User::from_name("root")?.expect("`root` exists");
+        let set = envs(&action.command("nix-env")?);
+        assert_eq!(set["HOME"], Some(root.dir.display().to_string()));
+        assert_eq!(set["USER"], Some("root".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn reads_receipts_recording_a_user() -> eyre::Result<()> {
+        let action: SetupDefaultProfile =
+            serde_json::from_str(r#"{"unpacked_path":"/nix/temp-install-dir","user":"root"}"#)?;
+        assert_eq!(action.unpacked_path, Path::new("/nix/temp-install-dir"));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_nix_env_of_profile_in_store() -> eyre::Result<()> {
+        let nix = tempfile::tempdir()?;
+        let profile = nix.path().join("var/nix/profiles/default");
+        assert_eq!(profile_nix_env(&profile), None);
+
+        let package = package(&nix.path().join("store"), "nix-2.24.0", Some("bin/nix-env"))?;
+        std::fs::create_dir_all(profile.parent().unwrap())?;
+        std::os::unix::fs::symlink(&package, &profile)?;
+        assert_eq!(
+            profile_nix_env(&profile),
+            Some(std::fs::canonicalize(package.join("bin/nix-env"))?)
+        );
+
+        // The store was removed earlier in the revert
+        std::fs::remove_dir_all(nix.path().join("store"))?;
+        assert_eq!(profile_nix_env(&profile), None);
+        Ok(())
+    }
+
+    #[test]
+    fn lists_what_was_found_without_package() -> eyre::Result<()> {
+        let store = tempfile::tempdir()?;
+        let candidates = vec![package(store.path(), "nix-2.24.0-dev", None)?];
+        assert_eq!(select_package(&candidates, "nix", "bin/nix-env"), None);
+        let glob = "/nix/temp-install-dir/nix-*/store/*-nix-*".to_string();
+        let error = SetupDefaultProfileError::NoNix {
+            glob: glob.clone(),
+            found: candidates,
+        }
+        .to_string();
+        assert!(
+            error.starts_with(&format!(
+                "Unarchived Nix store did not appear to include a `nix` package with `bin/nix-env`, `{glob}` found `{}/{HASH}-nix-2.24.0-dev`\n",
+                store.path().display()
+            )),
+            "{error}"
+        );
+        assert!(error.contains("fetching and unpacking"));
+        assert!(SetupDefaultProfileError::NoNssCacert {
+            glob,
+            found: vec![]
+        }
+        .to_string()
+        .ends_with("found nothing"));
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::action::ActionErrorKind;
+
+pub(crate) const NIX_STORE_DIR: &str = "/nix/store";
+/// How many entries of an existing store to look at, the store directory itself is always included
+const SAMPLE_SIZE: usize = 64;
+/// How many of the sampled paths to show
+const SAMPLE_EXAMPLES: usize = 3;
+
+/**
+The group owning an existing store, as found by sampling its entries
+
+Store paths of a multi-user install are owned by `root`, only the store directory (and the odd
+path a build left behind) is owned by the build group, so `root` is never counted.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct StoreGroupSample {
+    /// The most common GID other than `root`'s
+    pub gid: u32,
+    /// How many of the sampled entries are owned by [`gid`](StoreGroupSample::gid)
+    pub matching: usize,
+    pub sampled: usize,
+    pub examples: Vec<PathBuf>,
+}
+
+impl StoreGroupSample {
+    /// Sample `store` and the first of its entries, `None` if it does not exist or only `root` owns them
+    pub(crate) fn take(store: &Path) -> Option<Self> {
+        // Sorted so the same store samples (and plans) the same regardless of directory order
+        let mut entries = std::fs::read_dir(store)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        let mut paths = vec![store.to_path_buf()];
+        paths.extend(entries.into_iter().take(SAMPLE_SIZE));
+
+        let mut owners = BTreeMap::<u32, Vec<PathBuf>>::new();
+        let mut sampled = 0;
+        for path in paths {
+            let Ok(metadata) = path.symlink_metadata() else {
+                continue;
+            };
+            sampled += 1;
+            if metadata.gid() != 0 {
+                owners.entry(metadata.gid()).or_default().push(path);
+            }
+        }
+
+        let (gid, mut examples) = owners
+            .into_iter()
+            .max_by_key(|(gid, paths)| (paths.len(), std::cmp::Reverse(*gid)))?;
+        let matching = examples.len();
+        examples.truncate(SAMPLE_EXAMPLES);
+        Some(Self {
+            gid,
+            matching,
+            sampled,
+            examples,
+        })
+    }
+}
+
+impl std::fmt::Display for StoreGroupSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} sampled entries of the existing store are owned by GID {} (such as {})",
+            self.matching,
+            self.sampled,
+            self.gid,
+            self.examples
+                .iter()
+                .map(|example| format!("`{}`", example.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// What to do about an existing store owned by a different GID than the planned build group's
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum StoreGroupDecision {
+    /// Create the build group with the GID of the store
+    Adopt { sample: StoreGroupSample },
+    /// Change the group of the store to the planned GID, see [`ReownNixStore`](crate::action::base::ReownNixStore)
+    Regroup { sample: StoreGroupSample },
+}
+
+impl StoreGroupDecision {
+    /**
+    Decide what to do about `sample` given the `planned_gid` of the build group
+
+    Adopting the store's GID is preferred, it is only impossible when `adoptable` is false (the GID
+    belongs to some other group, or the build group already exists with another GID). Changing the
+    group of the store can take minutes on large stores, so it is only done when asked for with
+    `regroup`.
+    */
+    pub(crate) fn decide(
+        sample: Option<StoreGroupSample>,
+        planned_gid: u32,
+        adoptable: bool,
+        regroup: bool,
+    ) -> Result<Option<Self>, ActionErrorKind> {
+        let Some(sample) = sample.filter(|sample| sample.gid != planned_gid) else {
+            return Ok(None);
+        };
+        if regroup {
+            Ok(Some(Self::Regroup { sample }))
+        } else if adoptable {
+            Ok(Some(Self::Adopt { sample }))
+        } else {
+            Err(ActionErrorKind::StoreGroupMismatch(sample.gid, planned_gid))
+        }
+    }
+}
+
+impl std::fmt::Display for StoreGroupDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Adopt { sample } => {
+                write!(f, "Adopt GID {} for the build group, {sample}", sample.gid)
+            },
+            Self::Regroup { sample } => write!(
+                f,
+                "Change the group of the existing store from GID {}, {sample}",
+                sample.gid
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{lchown, symlink};
+
+    use super::*;
+
+    const OLD_GID: u32 = 30000;
+    const PLANNED_GID: u32 = 3000;
+
+    /// A store left behind by an install whose build group had [`OLD_GID`]
+    fn store() -> eyre::Result<(tempfile::TempDir, PathBuf)> {
+        let temp_dir = tempfile::tempdir()?;
+        let store = temp_dir.path().join("store");
+        let hello = store.join("lwp4z4v7fgwvw2kbhi6hx4i9sm2bjgqy-hello-2.12.1");
+        std::fs::create_dir_all(hello.join("bin"))?;
+        std::fs::write(hello.join("bin/hello"), "")?;
+        std::fs::write(
+            store.join("aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.38-27.drv"),
+            "",
+        )?;
+        symlink(hello.join("bin/hello"), store.join("result"))?;
+
+        lchown(&store, Some(0), Some(OLD_GID))?;
+        lchown(store.join("result"), Some(0), Some(OLD_GID))?;
+        lchown(hello.join("bin/hello"), Some(0), Some(OLD_GID))?;
+        Ok((temp_dir, store))
+    }
+
+    #[test]
+    fn adopts_or_rejects_the_store_gid() -> eyre::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, changing ownership needs root");
+            return Ok(());
+        }
+        let (_temp_dir, store) = store()?;
+
+        let sample = StoreGroupSample::take(&store).expect("Expected a sample");
+        assert_eq!(sample.gid, OLD_GID);
+        // The store and `result`, the rest are owned by `root` (and `bin/hello` is not sampled)
+        assert_eq!(sample.matching, 2);
+        assert_eq!(sample.sampled, 4);
+        assert!(sample.examples.contains(&store));
+
+        assert_eq!(
+            StoreGroupDecision::decide(Some(sample.clone()), PLANNED_GID, true, false)?,
+            Some(StoreGroupDecision::Adopt {
+                sample: sample.clone()
+            })
+        );
+        assert!(matches!(
+            StoreGroupDecision::decide(Some(sample.clone()), PLANNED_GID, false, false),
+            Err(ActionErrorKind::StoreGroupMismatch(OLD_GID, PLANNED_GID))
+        ));
+        assert_eq!(
+            StoreGroupDecision::decide(Some(sample.clone()), PLANNED_GID, false, true)?,
+            Some(StoreGroupDecision::Regroup {
+                sample: sample.clone()
+            })
+        );
+        // Nothing to decide when the store already matches, or there is none
+        assert_eq!(
+            StoreGroupDecision::decide(Some(sample), OLD_GID, false, false)?,
+            None
+        );
+        assert_eq!(
+            StoreGroupDecision::decide(None, PLANNED_GID, false, false)?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::{span, Span};
+
+use crate::{
+    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    env,
+};
+
+/// The `nix-store` of the default profile, which matches the store's schema
+pub(crate) const NIX_STORE_BINARY: &str = "/nix/var/nix/profiles/default/bin/nix-store";
+const NIX_STORE_DIR: &str = "/nix/store";
+/// How many paths to pass to a single `nix-store --check-validity` invocation
+const CHECK_VALIDITY_BATCH_SIZE: usize = 512;
+
+/**
+The outcome of `nix-store --verify`, along with any store paths the database does not know about
+
+`nix-store --verify` itself fixes some problems (such as dropping registrations of unreferenced,
+missing paths), those are listed but not considered inconsistencies.
+*/
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StoreVerifyReport {
+    /// Registered paths missing on disk which Nix removed from the database
+    pub invalidated: Vec<String>,
+    /// Registered paths missing on disk which other valid paths still reference
+    pub missing: Vec<String>,
+    /// Paths on disk which are not registered in the database
+    pub extra: Vec<String>,
+    /// Paths whose contents do not match the hash in the database
+    pub modified: Vec<String>,
+    /// Paths which were repaired (only with `--repair`)
+    pub repaired: Vec<String>,
+    /// Errors and warnings which were not recognized
+    pub errors: Vec<String>,
+}
+
+impl StoreVerifyReport {
+    /// Parse the (stderr) output of `nix-store --verify`
+    ///
+    /// Different Nix versions quote paths differently (`` `path' `` and `'path'`), so only the store path itself is matched on.
+    pub fn parse(output: &str) -> Self {
+        let mut report = Self::default();
+        for line in output.lines().map(str::trim) {
+            let Some(path) = store_path_in(line) else {
+                if line.starts_with("error:") || line.starts_with("warning:") {
+                    report.errors.push(line.to_string());
+                }
+                continue;
+            };
+            let (bucket, entry) = if line.contains("disappeared, removing from database") {
+                (&mut report.invalidated, path)
+            } else if line.contains("disappeared, but it still has valid referrers") {
+                (&mut report.missing, path)
+            } else if line.contains("was modified!") {
+                (&mut report.modified, path)
+            } else if line.starts_with("repairing path") {
+                (&mut report.repaired, path)
+            } else if line.starts_with("error:") || line.starts_with("warning:") {
+                (&mut report.errors, line)
+            } else {
+                continue;
+            };
+            if !bucket.iter().any(|existing| existing == entry) {
+                bucket.push(entry.to_string());
+            }
+        }
+        report
+    }
+
+    /// Missing or modified paths which were not repaired
+    pub fn unrepaired(&self) -> Vec<&str> {
+        self.missing
+            .iter()
+            .chain(&self.modified)
+            .filter(|path| !self.repaired.contains(path))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// If the database and the store on disk agree, possibly after Nix or `--repair` fixed them up
+    pub fn is_consistent(&self) -> bool {
+        self.unrepaired().is_empty() && self.extra.is_empty() && self.errors.is_empty()
+    }
+
+    /// If the database references paths which are missing or corrupted, or Nix could not complete the check
+    ///
+    /// Extra (unregistered) paths alone are garbage `nix-collect-garbage` will remove, so they are not considered bad.
+    pub fn is_badly_inconsistent(&self) -> bool {
+        !self.unrepaired().is_empty() || !self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for StoreVerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_consistent() && self.invalidated.is_empty() && self.repaired.is_empty() {
+            return write!(f, "The Nix store database is consistent");
+        }
+        let sections = [
+            ("Missing, but still referenced", &self.missing),
+            ("Modified", &self.modified),
+            ("Unregistered", &self.extra),
+            ("Missing, removed from the database", &self.invalidated),
+            ("Repaired", &self.repaired),
+            ("Errors", &self.errors),
+        ];
+        let mut first = true;
+        for (heading, entries) in sections {
+            if entries.is_empty() {
+                continue;
+            }
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{heading} ({}):", entries.len())?;
+            for entry in entries {
+                write!(f, "\n* {entry}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the `/nix/store/...` path in a line of `nix-store` output, stripping any quoting
+fn store_path_in(line: &str) -> Option<&str> {
+    let start = line.find(NIX_STORE_DIR)?;
+    let rest = &line[start..];
+    let end = rest
+        .find(|c: char| c == '\'' || c == '`' || c == '"' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    let path = rest[..end].trim_end_matches("...");
+    (path.len() > NIX_STORE_DIR.len() + 1).then_some(path)
+}
+
+/// The `nix-store` to verify with, preferring the default profile's
+pub(crate) fn nix_store_binary() -> Option<PathBuf> {
+    let default_profile = PathBuf::from(NIX_STORE_BINARY);
+    if default_profile.exists() {
+        Some(default_profile)
+    } else {
+        which::which("nix-store").ok()
+    }
+}
+
+/// Run `nix-store --verify` (optionally with `--repair`) and find any unregistered store paths
+#[tracing::instrument(level = "debug")]
+pub(crate) async fn verify_store(
+    nix_store: &Path,
+    repair: bool,
+) -> Result<StoreVerifyReport, ActionErrorKind> {
+    let mut command = Command::new(nix_store);
+    command.process_group(0);
+    command.arg("--verify");
+    if repair {
+        command.arg("--repair");
+    }
+    // Always talk to the local store directly, like the daemon does
+    command.env(env::NIX_REMOTE.name, "local");
+    command.stdin(std::process::Stdio::null());
+    if let Some(home) = dirs::home_dir() {
+        command.env(env::HOME.name, home);
+    }
+    tracing::trace!("Executing `{:?}`", command.as_std());
+    let output = crate::command_runner::output(&mut command)
+        .await
+        .map_err(|e| ActionErrorKind::command(&command, e))?;
+
+    // `nix-store --verify` exits non-zero when it finds problems it could not fix, the report covers those
+    let mut report = StoreVerifyReport::parse(&format!(
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ));
+    if !output.status.success() && report.is_consistent() {
+        return Err(ActionErrorKind::command_output(&command, output));
+    }
+
+    report.extra = unregistered_paths(nix_store).await?;
+    Ok(report)
+}
+
+/// Store paths on disk which the database does not consider valid
+async fn unregistered_paths(nix_store: &Path) -> Result<Vec<String>, ActionErrorKind> {
+    let mut entries = tokio::fs::read_dir(NIX_STORE_DIR)
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(PathBuf::from(NIX_STORE_DIR), e))?;
+    let mut paths = vec![];
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| ActionErrorKind::ReadDir(PathBuf::from(NIX_STORE_DIR), e))?
+    {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Skip `.links`, lock files and other bookkeeping Nix keeps in the store directory
+        if name.starts_with('.') || name.ends_with(".lock") || name.ends_with(".chroot") {
+            continue;
+        }
+        paths.push(format!("{NIX_STORE_DIR}/{name}"));
+    }
+    paths.sort();
+
+    let mut unregistered = vec![];
+    for batch in paths.chunks(CHECK_VALIDITY_BATCH_SIZE) {
+        let mut command = Command::new(nix_store);
+        command.process_group(0);
+        command.args(["--check-validity", "--print-invalid"]);
+        command.args(batch);
+        command.env(env::NIX_REMOTE.name, "local");
+        command.stdin(std::process::Stdio::null());
+        let output = crate::execute_command(&mut command).await?;
+        unregistered.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(ToString::to_string),
+        );
+    }
+    Ok(unregistered)
+}
+
+/**
+Verify the Nix store database matches the store on disk, optionally repairing it
+
+Run after the store is populated, so resuming an install which crashed partway through unpacking
+the store is caught instead of leaving a broken database behind.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct VerifyNixStore {
+    repair: bool,
+    force: bool,
+    /// The result of the last verification, if it ran
+    #[serde(default)]
+    report: Option<StoreVerifyReport>,
+}
+
+impl VerifyNixStore {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(repair: bool, force: bool) -> Result<StatefulAction<Self>, ActionError> {
+        Ok(Self {
+            repair,
+            force,
+            report: None,
+        }
+        .into())
+    }
+
+    pub fn report(&self) -> Option<&StoreVerifyReport> {
+        self.report.as_ref()
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "verify_nix_store")]
+impl Action for VerifyNixStore {
+    fn action_tag() -> ActionTag {
+        ActionTag("verify_nix_store")
+    }
+    fn tracing_synopsis(&self) -> String {
+        if self.repair {
+            "Verify and repair the Nix store database".to_string()
+        } else {
+            "Verify the Nix store database".to_string()
+        }
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "verify_nix_store",
+            repair = self.repair,
+            force = self.force,
+        )
+    }
+
+    fn depends_on(&self) -> Vec<&'static str> {
+        // Loading the store database is part of setting up the default profile
+        vec!["setup_default_profile"]
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Run `nix-store --verify{}` and check for unregistered store paths",
+            if self.repair { " --repair" } else { "" }
+        )];
+        if !self.force {
+            explanation.push(
+                "Refuses to continue if paths are missing or modified (use `--force` to continue anyway, or `--repair-store`)".to_string(),
+            );
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let nix_store =
+            nix_store_binary().ok_or_else(|| Self::error(VerifyNixStoreError::NoNixStore))?;
+        let report = verify_store(&nix_store, self.repair)
+            .await
+            .map_err(Self::error)?;
+        self.report = Some(report.clone());
+
+        if report.is_badly_inconsistent() {
+            if !self.force {
+                return Err(Self::error(VerifyNixStoreError::Inconsistent(report)));
+            }
+            tracing::warn!(
+                "Continuing with an inconsistent Nix store database due to `--force`:\n{report}"
+            );
+        } else if !report.is_consistent() {
+            tracing::warn!("{report}");
+        } else {
+            tracing::debug!("{report}");
+        }
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        // Verification does not leave anything behind to revert
+        Ok(())
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyNixStoreError {
+    #[error("Could not find a `nix-store` binary to verify the Nix store database with")]
+    NoNixStore,
+    #[error("The Nix store database is inconsistent with `/nix/store`, pass `--repair-store` to attempt a repair or `--force` to continue anyway\n{0}")]
+    Inconsistent(StoreVerifyReport),
+}
+
+impl From<VerifyNixStoreError> for ActionErrorKind {
+    fn from(val: VerifyNixStoreError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NIX_2_3: &str = include_str!("../../../tests/fixtures/nix-store-verify/nix-2.3.txt");
+    const NIX_2_18: &str = include_str!("../../../tests/fixtures/nix-store-verify/nix-2.18.txt");
+    const NIX_2_18_REPAIR: &str =
+        include_str!("../../../tests/fixtures/nix-store-verify/nix-2.18-repair.txt");
+    const NIX_2_18_CONSISTENT: &str =
+        include_str!("../../../tests/fixtures/nix-store-verify/nix-2.18-consistent.txt");
+
+    #[test]
+    fn parses_backtick_quoting() {
+        let report = StoreVerifyReport::parse(NIX_2_3);
+        assert_eq!(
+            report.invalidated,
+            vec!["/nix/store/8yf2f0vbl1dqmx7l6d2kdyyrfwmcamll-hello-2.10"]
+        );
+        assert_eq!(
+            report.missing,
+            vec!["/nix/store/0c9wzvqw8zxllgr0v5g0vrz9hbz3dzsy-glibc-2.30"]
+        );
+        assert_eq!(
+            report.modified,
+            vec!["/nix/store/rw7bx7ak2p02ljm3z4hhpkjlr8rzg6xz-bash-4.4-p23"]
+        );
+        assert_eq!(report.errors, vec!["warning: not all errors were fixed"]);
+        assert!(report.is_badly_inconsistent());
+    }
+
+    #[test]
+    fn parses_single_quoting() {
+        let report = StoreVerifyReport::parse(NIX_2_18);
+        assert_eq!(
+            report.invalidated,
+            vec!["/nix/store/lwp4z4v7fgwvw2kbhi6hx4i9sm2bjgqy-hello-2.12.1"]
+        );
+        assert_eq!(
+            report.missing,
+            vec!["/nix/store/aw2fw9ag10wr9pf0qk4nk5sxi0q0bn56-glibc-2.38-27"]
+        );
+        assert_eq!(
+            report.modified,
+            vec!["/nix/store/4bj2kxdm1462fzcc2i2s4dn33g2angcc-bash-5.2-p15"]
+        );
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.is_badly_inconsistent());
+    }
+
+    #[test]
+    fn repaired_paths_are_not_inconsistent() {
+        let report = StoreVerifyReport::parse(NIX_2_18_REPAIR);
+        assert_eq!(report.missing, report.repaired);
+        assert!(report.unrepaired().is_empty());
+        assert!(report.is_consistent());
+        assert!(!report.is_badly_inconsistent());
+    }
+
+    #[test]
+    fn parses_consistent_store() {
+        let report = StoreVerifyReport::parse(NIX_2_18_CONSISTENT);
+        assert_eq!(report, StoreVerifyReport::default());
+        assert!(report.is_consistent());
+        assert_eq!(report.to_string(), "The Nix store database is consistent");
+    }
+
+    #[test]
+    fn extra_paths_alone_are_not_bad() {
+        let report = StoreVerifyReport {
+            extra: vec!["/nix/store/00000000000000000000000000000000-garbage".into()],
+            ..Default::default()
+        };
+        assert!(!report.is_consistent());
+        assert!(!report.is_badly_inconsistent());
+    }
+}
@@ -1,12 +1,12 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{
     action::{
-        base::SetupDefaultProfile,
-        common::{ConfigureShellProfile, PlaceNixConfiguration},
+        base::{CreateCaBundle, SetupDefaultProfile, VerifyNixStore},
+        common::{ConfigureShellProfile, ConfigureUserNix, PlaceMotd, PlaceNixConfiguration},
         Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
     },
-    planner::ShellProfileLocations,
+    planner::ShellProfiles,
     settings::{CommonSettings, SCRATCH_DIR},
 };
 
@@ -14,18 +14,25 @@ use tracing::{span, Instrument, Span};
 
 /**
 Configure Nix and start it
+
+Planners plan its steps as separate actions with [`ConfigureNix::plan_actions`], so each can be
+skipped or reordered on its own. This is kept so existing receipts still install and revert.
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct ConfigureNix {
     setup_default_profile: StatefulAction<SetupDefaultProfile>,
     configure_shell_profile: Option<StatefulAction<ConfigureShellProfile>>,
     place_nix_configuration: StatefulAction<PlaceNixConfiguration>,
+    #[serde(default)]
+    create_ca_bundle: Option<StatefulAction<CreateCaBundle>>,
+    #[serde(default)]
+    verify_nix_store: Option<StatefulAction<VerifyNixStore>>,
 }
 
 impl ConfigureNix {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
-        shell_profile_locations: ShellProfileLocations,
+        shell_profiles: impl Into<ShellProfiles>,
         settings: &CommonSettings,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let setup_default_profile = SetupDefaultProfile::plan(PathBuf::from(SCRATCH_DIR))
@@ -33,31 +40,107 @@ impl ConfigureNix {
             .map_err(Self::error)?;
 
         let configure_shell_profile = if settings.modify_profile {
-            Some(
-                ConfigureShellProfile::plan(shell_profile_locations)
+            let configure_shell_profile = match shell_profiles.into() {
+                ShellProfiles::System(locations) => {
+                    ConfigureShellProfile::plan(
+                        locations.with_targets(
+                            &settings.profile_targets,
+                            settings.replace_profile_targets,
+                        ),
+                        settings.nix_ssl_cert_file(),
+                        settings.nix_conf_dir.clone(),
+                        settings.posix_only_profile,
+                    )
                     .await
-                    .map_err(Self::error)?,
-            )
+                },
+                // `--profile-targets` name profiles every user reads, which are left alone
+                ShellProfiles::User(home) => {
+                    ConfigureShellProfile::plan_for_user(
+                        home,
+                        settings.nix_ssl_cert_file(),
+                        settings.nix_conf_dir.clone(),
+                        settings.posix_only_profile,
+                    )
+                    .await
+                },
+            };
+            Some(configure_shell_profile.map_err(Self::error)?)
         } else {
             None
         };
-        let place_nix_configuration = PlaceNixConfiguration::plan(
-            settings.nix_build_group_name.clone(),
-            settings.proxy.clone(),
-            settings.ssl_cert_file.clone(),
-            settings.extra_conf.clone(),
-            settings.force,
-        )
-        .await
-        .map_err(Self::error)?;
+        let place_nix_configuration = PlaceNixConfiguration::plan(settings)
+            .await
+            .map_err(Self::error)?;
+        let create_ca_bundle = match &settings.ssl_cert_file {
+            Some(ssl_cert_file) if settings.append_corp_ca => Some(
+                CreateCaBundle::plan(ssl_cert_file, settings.conf_dir(), settings.force)
+                    .await
+                    .map_err(Self::error)?,
+            ),
+            _ => None,
+        };
+        let verify_nix_store = VerifyNixStore::plan(settings.repair_store, settings.force)
+            .await
+            .map_err(Self::error)?;
 
         Ok(Self {
             place_nix_configuration,
             setup_default_profile,
             configure_shell_profile,
+            create_ca_bundle,
+            verify_nix_store: Some(verify_nix_store),
         }
         .into())
     }
+
+    /// Plan the steps of configuring Nix as separate actions, in the order [`ConfigureNix`] runs them
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan_actions(
+        shell_profiles: impl Into<ShellProfiles>,
+        settings: &CommonSettings,
+    ) -> Result<Vec<StatefulAction<Box<dyn Action>>>, ActionError> {
+        let Self {
+            setup_default_profile,
+            configure_shell_profile,
+            place_nix_configuration,
+            create_ca_bundle,
+            verify_nix_store,
+        } = Self::plan(shell_profiles, settings).await?.action;
+
+        let mut actions = vec![
+            setup_default_profile.boxed(),
+            place_nix_configuration.boxed(),
+        ];
+        if let Some(configure_shell_profile) = configure_shell_profile {
+            actions.push(configure_shell_profile.boxed());
+        }
+        // Opt in, so only planned as its own action
+        if !settings.user_nix_conf.is_empty() || settings.prompt_integration {
+            actions.push(
+                ConfigureUserNix::plan(
+                    &ConfigureUserNix::target_users(),
+                    &settings.user_nix_conf,
+                    settings.prompt_integration,
+                )
+                .await?
+                .boxed(),
+            );
+        }
+        if settings.write_motd {
+            actions.push(
+                PlaceMotd::plan(Path::new("/"), &settings.conf_dir(), settings.force)
+                    .await?
+                    .boxed(),
+            );
+        }
+        if let Some(create_ca_bundle) = create_ca_bundle {
+            actions.push(create_ca_bundle.boxed());
+        }
+        if let Some(verify_nix_store) = verify_nix_store {
+            actions.push(verify_nix_store.boxed());
+        }
+        Ok(actions)
+    }
 }
 
 #[async_trait::async_trait]
@@ -79,6 +162,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            create_ca_bundle,
+            verify_nix_store,
         } = &self;
 
         let mut buf = setup_default_profile.describe_execute();
@@ -86,6 +171,12 @@ impl Action for ConfigureNix {
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_execute());
         }
+        if let Some(create_ca_bundle) = create_ca_bundle {
+            buf.append(&mut create_ca_bundle.describe_execute());
+        }
+        if let Some(verify_nix_store) = verify_nix_store {
+            buf.append(&mut verify_nix_store.describe_execute());
+        }
         buf
     }
 
@@ -95,6 +186,8 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            create_ca_bundle,
+            verify_nix_store,
         } = self;
 
         if let Some(configure_shell_profile) = configure_shell_profile {
@@ -147,6 +240,16 @@ impl Action for ConfigureNix {
             )?;
         };
 
+        // The public CAs are only in the default profile once it is set up
+        if let Some(create_ca_bundle) = create_ca_bundle {
+            create_ca_bundle.try_execute().await.map_err(Self::error)?;
+        }
+
+        // The store database was just loaded (or, when resuming, may have been left half loaded)
+        if let Some(verify_nix_store) = verify_nix_store {
+            verify_nix_store.try_execute().await.map_err(Self::error)?;
+        }
+
         Ok(())
     }
 
@@ -155,9 +258,14 @@ impl Action for ConfigureNix {
             setup_default_profile,
             place_nix_configuration,
             configure_shell_profile,
+            create_ca_bundle,
+            verify_nix_store: _,
         } = &self;
 
         let mut buf = Vec::default();
+        if let Some(create_ca_bundle) = create_ca_bundle {
+            buf.append(&mut create_ca_bundle.describe_revert());
+        }
         if let Some(configure_shell_profile) = configure_shell_profile {
             buf.append(&mut configure_shell_profile.describe_revert());
         }
@@ -167,9 +275,35 @@ impl Action for ConfigureNix {
         buf
     }
 
+    fn retainable_kinds(&self) -> Vec<&'static str> {
+        let mut kinds = vec!["setup_default_profile", "place_nix_configuration"];
+        if self.configure_shell_profile.is_some() {
+            kinds.push("configure_shell_profile");
+        }
+        kinds
+    }
+
+    fn retain(&mut self, kind: &str) -> Vec<String> {
+        let retained = match kind {
+            "setup_default_profile" => self.setup_default_profile.retain(),
+            "place_nix_configuration" => self.place_nix_configuration.retain(),
+            "configure_shell_profile" => self
+                .configure_shell_profile
+                .as_mut()
+                .and_then(|configure_shell_profile| configure_shell_profile.retain()),
+            _ => None,
+        };
+        retained.into_iter().collect()
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
+        if let Some(create_ca_bundle) = &mut self.create_ca_bundle {
+            if let Err(err) = create_ca_bundle.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Some(configure_shell_profile) = &mut self.configure_shell_profile {
             if let Err(err) = configure_shell_profile.try_revert().await {
                 errors.push(err);
@@ -194,3 +328,167 @@ impl Action for ConfigureNix {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::ConfigureNix;
+    use crate::{
+        planner::{FishShellProfileLocations, ShellProfileLocations},
+        settings::CommonSettings,
+    };
+
+    #[tokio::test]
+    async fn plan_actions_matches_meta_action() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.force = true;
+
+        let meta = ConfigureNix::plan(ShellProfileLocations::default(), &settings).await?;
+        let actions =
+            ConfigureNix::plan_actions(ShellProfileLocations::default(), &settings).await?;
+
+        let kinds = actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "setup_default_profile",
+                "place_nix_configuration",
+                "configure_shell_profile",
+                "verify_nix_store"
+            ]
+        );
+
+        let descriptions = |descriptions: Vec<crate::action::ActionDescription>| {
+            descriptions
+                .into_iter()
+                .map(|description| (description.description, description.explanation))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            descriptions(
+                actions
+                    .iter()
+                    .flat_map(|action| action.describe_execute())
+                    .collect()
+            ),
+            descriptions(meta.describe_execute())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_modify_profile_leaves_shell_profiles() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        settings.force = true;
+        settings.modify_profile(false);
+
+        let actions =
+            ConfigureNix::plan_actions(ShellProfileLocations::default(), &settings).await?;
+        assert!(actions
+            .iter()
+            .all(|action| action.inner_typetag_name() != "configure_shell_profile"));
+        // Nothing to undo on uninstall either
+        let meta = ConfigureNix::plan(ShellProfileLocations::default(), &settings).await?;
+        assert!(meta.action.configure_shell_profile.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nix_conf_dir_moves_every_artifact() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let nix_conf_dir = temp_dir.path().join("nix-conf");
+        let mut settings = CommonSettings::default().await?;
+        settings.force = true;
+        settings.nix_conf_dir = Some(nix_conf_dir.clone());
+        settings.ssl_cert_file = Some(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssl/corp-bundle.pem"),
+        );
+        settings.append_corp_ca = true;
+        settings.builders = vec!["ssh://builder x86_64-linux".parse()?];
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![temp_dir.path().join("bashrc")],
+            zsh: vec![],
+        };
+
+        let actions = ConfigureNix::plan_actions(locations, &settings).await?;
+        let plan = serde_json::to_string(&actions)?;
+
+        assert!(
+            !plan.contains("/etc/nix/"),
+            "Expected nothing in `/etc/nix`:\n{plan}"
+        );
+        for expected in [
+            format!("\"path\":\"{}\"", nix_conf_dir.display()),
+            format!("\"path\":\"{}\"", nix_conf_dir.join("nix.conf").display()),
+            format!("\"path\":\"{}\"", nix_conf_dir.join("machines").display()),
+            format!(
+                "\"path\":\"{}\"",
+                nix_conf_dir.join("ca-bundle.crt").display()
+            ),
+            // The settings pointing into it
+            format!("@{}", nix_conf_dir.join("machines").display()),
+            format!(
+                "\"ssl-cert-file\":\"{}",
+                nix_conf_dir.join("ca-bundle.crt").display()
+            ),
+            // The shells exporting it
+            format!(
+                "export NIX_CONF_DIR=\\\"${{NIX_CONF_DIR:-{}}}\\\"",
+                nix_conf_dir.display()
+            ),
+        ] {
+            assert!(
+                plan.contains(&expected),
+                "Expected `{expected}` in:\n{plan}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn profile_targets_are_recorded() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let zprofile = temp_dir.path().join("zprofile");
+        let mut settings = CommonSettings::default().await?;
+        settings.force = true;
+        settings.profile_targets = vec![zprofile.clone()];
+        let locations = || ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![temp_dir.path().join("bashrc")],
+            zsh: vec![],
+        };
+
+        let added =
+            serde_json::to_string(&ConfigureNix::plan_actions(locations(), &settings).await?)?;
+        for expected in ["bashrc", "zprofile"] {
+            let expected = format!("\"path\":\"{}\"", temp_dir.path().join(expected).display());
+            assert!(
+                added.contains(&expected),
+                "Expected `{expected}` in:\n{added}"
+            );
+        }
+
+        settings.replace_profile_targets = true;
+        let replaced =
+            serde_json::to_string(&ConfigureNix::plan_actions(locations(), &settings).await?)?;
+        assert!(replaced.contains(&format!("\"path\":\"{}\"", zprofile.display())));
+        assert!(
+            !replaced.contains("bashrc"),
+            "Expected no `bashrc` in:\n{replaced}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use tracing::{span, Span};
+
+use crate::action::{
+    base::CreateFile, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+    StatefulAction,
+};
+
+/// The machines file, in the Nix configuration directory
+pub(crate) const NIX_MACHINES_NAME: &str = "machines";
+
+/// Operating systems Nix can build for, the second half of a system type like `x86_64-linux`
+const KNOWN_SYSTEM_KERNELS: &[&str] = &["linux", "darwin", "freebsd", "netbsd", "openbsd"];
+
+/**
+A remote builder, one line of the Nix machines file
+
+Fields follow the [machines file syntax](https://nixos.org/manual/nix/stable/advanced-topics/distributed-builds.html),
+unset fields render as `-`.
+*/
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde_with::SerializeDisplay, serde_with::DeserializeFromStr,
+)]
+pub struct NixMachine {
+    pub uri: String,
+    pub systems: Vec<String>,
+    pub ssh_key: Option<PathBuf>,
+    pub max_jobs: Option<u32>,
+    pub speed_factor: Option<u32>,
+    pub supported_features: Vec<String>,
+    pub mandatory_features: Vec<String>,
+    pub public_host_key: Option<String>,
+}
+
+impl FromStr for NixMachine {
+    type Err = NixMachineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        if fields.is_empty() || fields.len() > 8 {
+            return Err(NixMachineError::FieldCount(s.to_string(), fields.len()));
+        }
+        let field = |index: usize| fields.get(index).copied().filter(|field| *field != "-");
+        let list = |index: usize| {
+            field(index)
+                .map(|field| field.split(',').map(ToString::to_string).collect())
+                .unwrap_or_default()
+        };
+        let number = |index: usize, name: &'static str| {
+            field(index)
+                .map(|field| {
+                    field
+                        .parse::<u32>()
+                        .map_err(|_| NixMachineError::NotANumber(name, field.to_string()))
+                })
+                .transpose()
+        };
+
+        let uri = fields[0].to_string();
+        if !uri.contains("://") {
+            return Err(NixMachineError::Uri(uri));
+        }
+        let systems: Vec<String> = list(1);
+        for system in &systems {
+            match system.split_once('-') {
+                Some((arch, kernel))
+                    if !arch.is_empty() && KNOWN_SYSTEM_KERNELS.contains(&kernel) => {},
+                _ => return Err(NixMachineError::SystemType(system.clone())),
+            }
+        }
+
+        Ok(Self {
+            uri,
+            systems,
+            ssh_key: field(2).map(PathBuf::from),
+            max_jobs: number(3, "maximum jobs")?,
+            speed_factor: number(4, "speed factor")?,
+            supported_features: list(5),
+            mandatory_features: list(6),
+            public_host_key: field(7).map(ToString::to_string),
+        })
+    }
+}
+
+impl std::fmt::Display for NixMachine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_dash = |field: Option<String>| field.unwrap_or_else(|| "-".to_string());
+        let list = |list: &Vec<String>| (!list.is_empty()).then(|| list.join(","));
+        let mut fields = vec![
+            self.uri.clone(),
+            or_dash(list(&self.systems)),
+            or_dash(self.ssh_key.as_ref().map(|key| key.display().to_string())),
+            or_dash(self.max_jobs.map(|jobs| jobs.to_string())),
+            or_dash(self.speed_factor.map(|factor| factor.to_string())),
+            or_dash(list(&self.supported_features)),
+            or_dash(list(&self.mandatory_features)),
+            or_dash(self.public_host_key.clone()),
+        ];
+        while fields.len() > 1 && fields.last().map(String::as_str) == Some("-") {
+            fields.pop();
+        }
+        f.write_str(&fields.join(" "))
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum NixMachineError {
+    #[error("Builder `{0}` has {1} fields, expected between 1 and 8")]
+    FieldCount(String, usize),
+    #[error("Builder URI `{0}` is not a store URI like `ssh://user@host`")]
+    Uri(String),
+    #[error("Builder system type `{0}` is not valid, expected something like `x86_64-linux`")]
+    SystemType(String),
+    #[error("Builder {0} `{1}` is not a number")]
+    NotANumber(&'static str, String),
+}
+
+/**
+Configure remote builders in `/etc/nix/machines`
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureRemoteBuilders {
+    machines: Vec<NixMachine>,
+    create_file: StatefulAction<CreateFile>,
+}
+
+impl ConfigureRemoteBuilders {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        machines: Vec<NixMachine>,
+        nix_conf_dir: &Path,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        for machine in &machines {
+            if let Some(ssh_key) = &machine.ssh_key {
+                if !ssh_key.exists() {
+                    tracing::warn!(
+                        "SSH key `{}` for builder `{}` does not exist, builds on it will fail until it is provided",
+                        ssh_key.display(),
+                        machine.uri,
+                    );
+                }
+            }
+        }
+
+        let buf = machines
+            .iter()
+            .map(|machine| format!("{machine}\n"))
+            .collect::<String>();
+        let path = nix_conf_dir.join(NIX_MACHINES_NAME);
+        let create_file = CreateFile::plan(path, None, None, 0o0644, buf, force)
+            .await
+            .map_err(Self::error)?;
+
+        Ok(Self {
+            machines,
+            create_file,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_remote_builders")]
+impl Action for ConfigureRemoteBuilders {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_remote_builders")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Configure remote builders in `{}`",
+            self.create_file.action.path.display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_remote_builders",
+            machines = self.machines.len(),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.machines
+                .iter()
+                .map(|machine| format!("Add builder `{machine}`"))
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_file.try_execute().await.map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Remove the remote builders in `{}`",
+                self.create_file.action.path.display()
+            ),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        self.create_file.try_revert().await.map_err(Self::error)?;
+
+        Ok(())
+    }
+}
+
+impl From<NixMachineError> for ActionErrorKind {
+    fn from(val: NixMachineError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn machine_round_trip() -> eyre::Result<()> {
+        let line = "ssh://user@host x86_64-linux,aarch64-linux /key 8 1 kvm,big-parallel";
+        let machine: NixMachine = line.parse()?;
+        assert_eq!(machine.uri, "ssh://user@host");
+        assert_eq!(machine.systems, vec!["x86_64-linux", "aarch64-linux"]);
+        assert_eq!(machine.ssh_key, Some(PathBuf::from("/key")));
+        assert_eq!(machine.max_jobs, Some(8));
+        assert_eq!(machine.speed_factor, Some(1));
+        assert_eq!(machine.supported_features, vec!["kvm", "big-parallel"]);
+        assert!(machine.mandatory_features.is_empty());
+        assert_eq!(machine.to_string(), line);
+        assert_eq!(machine.to_string().parse::<NixMachine>()?, machine);
+        Ok(())
+    }
+
+    #[test]
+    fn machine_dashes_round_trip() -> eyre::Result<()> {
+        let machine: NixMachine = "ssh-ng://host - - - - - benchmark".parse()?;
+        assert!(machine.systems.is_empty());
+        assert_eq!(machine.ssh_key, None);
+        assert_eq!(machine.mandatory_features, vec!["benchmark"]);
+        assert_eq!(machine.to_string(), "ssh-ng://host - - - - - benchmark");
+        Ok(())
+    }
+
+    #[test]
+    fn machine_rejects_invalid() {
+        assert!(matches!(
+            "".parse::<NixMachine>(),
+            Err(NixMachineError::FieldCount(_, 0))
+        ));
+        assert!(matches!(
+            "ssh://a b c d e f g h i".parse::<NixMachine>(),
+            Err(NixMachineError::FieldCount(_, 9))
+        ));
+        assert!(matches!(
+            "host x86_64-linux".parse::<NixMachine>(),
+            Err(NixMachineError::Uri(_))
+        ));
+        assert!(matches!(
+            "ssh://host x86_64-plan9".parse::<NixMachine>(),
+            Err(NixMachineError::SystemType(_))
+        ));
+        assert!(matches!(
+            "ssh://host x86_64-linux - eight".parse::<NixMachine>(),
+            Err(NixMachineError::NotANumber(_, _))
+        ));
+    }
+}
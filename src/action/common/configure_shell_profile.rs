@@ -1,16 +1,42 @@
 use crate::action::base::{create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile};
 use crate::action::{
-    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction,
 };
-use crate::planner::ShellProfileLocations;
+use crate::env;
+use crate::planner::{FishShellProfileLocations, ShellProfileLocations};
 
-use nix::unistd::User;
+use nix::unistd::{Group, User};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use tokio::process::Command;
 use tokio::task::JoinSet;
 use tracing::{span, Instrument, Span};
 
 const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
 const PROFILE_NIX_FILE_FISH: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish";
+/// What the hook of an install without a daemon sources, relative to the user's home directory
+const USER_PROFILE_NIX_FILE_SHELL: &str = ".nix-profile/etc/profile.d/nix.sh";
+/// What `nix.sh` prepends to `PATH`, relative to the user's home directory
+const USER_NIX_BIN_DIR: &str = ".nix-profile/bin";
+/// The dotfiles bash and zsh read, relative to the user's home directory
+const USER_BASH_PROFILES: &[&str] = &[".profile", ".bashrc"];
+const USER_ZSH_PROFILES: &[&str] = &[".zshrc"];
+/// What `nix-daemon.sh` prepends to `PATH`, before `~/.nix-profile/bin`
+pub(crate) const NIX_BIN_DIR: &str = "/nix/var/nix/profiles/default/bin";
+/// How often a Nix entry may be on `PATH`, once for the default profile and once by hand is usual
+pub(crate) const MAX_NIX_PATH_REPEATS: usize = 2;
+/// The CA bundles `nix-daemon.sh` falls back to when `NIX_SSL_CERT_FILE` is unset, in order
+const SSL_CERT_FILE_FALLBACKS: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/certs/ca-bundle.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
+];
+/// Set (not exported) by a hook once it sourced Nix, so the other profiles read by the same shell skip it
+const HOOK_GUARD: &str = "__NIX_PROFILE_SOURCED";
+/// The shells which may read `/etc/profile`, every one found must parse the hook
+const POSIX_SHELLS: &[&[&str]] = &[&["sh"], &["dash"], &["ash"], &["busybox", "ash"]];
 
 /**
 Configure any detected shell profiles to include Nix support
@@ -26,23 +52,38 @@ impl ConfigureShellProfile {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(
         locations: ShellProfileLocations,
+        ssl_cert_file: Option<PathBuf>,
+        nix_conf_dir: Option<PathBuf>,
+        posix_only: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let mut create_or_insert_files = Vec::default();
         let mut create_directories = Vec::default();
 
-        let shell_buf = format!(
-            "\n\
-            # Nix\n\
-            if [ -e '{PROFILE_NIX_FILE_SHELL}' ]; then\n\
-            {inde}. '{PROFILE_NIX_FILE_SHELL}'\n\
-            fi\n\
-            # End Nix\n
-        \n",
-            inde = "    ", // indent
+        check_hook_paths(
+            [ssl_cert_file.as_deref(), nix_conf_dir.as_deref()]
+                .into_iter()
+                .flatten(),
+        )
+        .map_err(Self::error)?;
+        let shell_buf = render_shell_hook(
+            ssl_cert_file.as_deref(),
+            nix_conf_dir.as_deref(),
+            posix_only,
         );
+        check_posix_syntax(&shell_buf).await.map_err(Self::error)?;
 
         for profile_target in locations.bash.iter().chain(locations.zsh.iter()) {
-            let profile_target_path = Path::new(profile_target);
+            // Package updates may replace the rc file and the hook with it, not a fragment of our own
+            let fragment = fragment_of(profile_target).await;
+            if let Some(fragment) = &fragment {
+                tracing::debug!(
+                    "`{}` sources the fragments in `{}`, hooking it with `{}`",
+                    profile_target.display(),
+                    fragment.parent().expect("Is in a directory").display(),
+                    fragment.display()
+                );
+            }
+            let profile_target_path = fragment.as_deref().unwrap_or(profile_target);
             if let Some(parent) = profile_target_path.parent() {
                 if !parent.exists() {
                     create_directories.push(
@@ -66,16 +107,7 @@ impl ConfigureShellProfile {
             }
         }
 
-        let fish_buf = format!(
-            "\n\
-            # Nix\n\
-            if test -e '{PROFILE_NIX_FILE_FISH}'\n\
-            {inde}. '{PROFILE_NIX_FILE_FISH}'\n\
-            end\n\
-            # End Nix\n\
-        \n",
-            inde = "    ", // indent
-        );
+        let fish_buf = render_fish_hook(ssl_cert_file.as_deref(), nix_conf_dir.as_deref());
 
         for fish_prefix in &locations.fish.confd_prefixes {
             let fish_prefix_path = PathBuf::from(fish_prefix);
@@ -136,9 +168,19 @@ impl ConfigureShellProfile {
             );
         }
 
+        // Shells which read none of the global profiles need a hook of their own
+        if let Some(user) = sudo_user() {
+            if let Some((user_directories, user_file)) =
+                plan_user_hook(&user, ssl_cert_file.as_deref(), nix_conf_dir.as_deref()).await?
+            {
+                create_directories.extend(user_directories);
+                create_or_insert_files.push(user_file);
+            }
+        }
+
         // If the `$GITHUB_PATH` environment exists, we're almost certainly running on Github
         // Actions, and almost certainly wants the relevant `$PATH` additions added.
-        if let Ok(github_path) = std::env::var("GITHUB_PATH") {
+        if let Some(github_path) = env::GITHUB_PATH.get() {
             let mut buf = "/nix/var/nix/profiles/default/bin\n".to_string();
             // Actions runners operate as `runner` user by default
             if let Ok(Some(runner)) = User::from_name("runner") {
@@ -170,6 +212,565 @@ impl ConfigureShellProfile {
         }
         .into())
     }
+
+    /// The files executing inserts the hook into, those already hooked are skipped
+    pub(crate) fn unhooked_paths(&self) -> Vec<&Path> {
+        self.create_or_insert_into_files
+            .iter()
+            .filter(|create_or_insert_into_file| {
+                create_or_insert_into_file.state != ActionState::Completed
+            })
+            .map(|create_or_insert_into_file| create_or_insert_into_file.action.path())
+            .collect()
+    }
+
+    /**
+    Plan hooking Nix into the dotfiles in `home` (`~/.profile`, `~/.bashrc` and `~/.zshrc`) instead
+    of the profiles in `/etc`, for installs without a daemon
+
+    The hook sources `~/.nix-profile/etc/profile.d/nix.sh` rather than `nix-daemon.sh`. The files
+    are owned by the owner of `home`, and reverting removes only the hook from them.
+    */
+    #[tracing::instrument(level = "debug", skip_all, fields(home = %home.display()))]
+    pub async fn plan_for_user(
+        home: PathBuf,
+        ssl_cert_file: Option<PathBuf>,
+        nix_conf_dir: Option<PathBuf>,
+        posix_only: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        check_hook_paths(
+            [
+                Some(home.as_path()),
+                ssl_cert_file.as_deref(),
+                nix_conf_dir.as_deref(),
+            ]
+            .into_iter()
+            .flatten(),
+        )
+        .map_err(Self::error)?;
+        let uid = home
+            .metadata()
+            .map_err(|e| Self::error(ActionErrorKind::GettingMetadata(home.clone(), e)))?
+            .uid();
+        let mut user = User::from_uid(uid.into())
+            .map_err(|e| Self::error(ActionErrorKind::GettingUserId(uid.to_string(), e)))?
+            .ok_or_else(|| Self::error(ActionErrorKind::NoUser(uid.to_string())))?;
+        user.dir = home;
+
+        let shell_buf = render_user_shell_hook(
+            &user.dir,
+            ssl_cert_file.as_deref(),
+            nix_conf_dir.as_deref(),
+            posix_only,
+        );
+        check_posix_syntax(&shell_buf).await.map_err(Self::error)?;
+
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: USER_BASH_PROFILES
+                .iter()
+                .map(|profile| user.dir.join(profile))
+                .collect(),
+            zsh: USER_ZSH_PROFILES
+                .iter()
+                .map(|profile| user.dir.join(profile))
+                .collect(),
+        };
+
+        let mut create_directories = Vec::default();
+        let mut create_or_insert_files = Vec::default();
+        for profile in USER_BASH_PROFILES.iter().chain(USER_ZSH_PROFILES) {
+            let (user_directories, user_file) =
+                plan_user_file(&user, Path::new(profile), shell_buf.clone())
+                    .await
+                    .map_err(Self::error)?;
+            create_directories.extend(user_directories);
+            create_or_insert_files.push(user_file);
+        }
+
+        Ok(Self {
+            locations,
+            create_directories,
+            create_or_insert_into_files: create_or_insert_files,
+        }
+        .into())
+    }
+}
+
+/// Refuse the first of `paths` which isn't UTF-8, rather than mangling it into the hook
+fn check_hook_paths<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Result<(), ActionErrorKind> {
+    match paths.into_iter().find(|path| path.to_str().is_none()) {
+        Some(path) => Err(ActionErrorKind::UnrepresentableHookPath(path.to_path_buf())),
+        None => Ok(()),
+    }
+}
+
+/// If `path` is a file of its own in a directory every file of which is read, such as `/etc/profile.d`
+///
+/// These are created even when absent, as opposed to rc files such as `/etc/bashrc` which are shared
+/// with the distribution. Reverting removes a created file, rather than leaving it empty.
+fn is_drop_in(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir.to_string_lossy().ends_with(".d"))
+}
+
+/**
+The fragment to hook the rc file `rc` with instead of itself, if it sources every file (with some
+extension) of the directory named after it, such as `/etc/bash.bashrc.d` on openSUSE or
+`/etc/zshrc.d` on Fedora
+
+The fragment is a drop-in, so reverting removes it.
+*/
+async fn fragment_of(rc: &Path) -> Option<PathBuf> {
+    if is_drop_in(rc) {
+        return None;
+    }
+    let mut fragment_dir = rc.as_os_str().to_owned();
+    fragment_dir.push(".d");
+    let fragment_dir = PathBuf::from(fragment_dir);
+    if !fragment_dir.is_dir() {
+        return None;
+    }
+    let contents = tokio::fs::read(rc).await.ok()?;
+    let name = sourced_fragment_name(&String::from_utf8_lossy(&contents), &fragment_dir)?;
+    Some(fragment_dir.join(name))
+}
+
+/// A name for a fragment `rc` would source from `fragment_dir`, if it loops over its files sourcing them
+///
+/// Only loops globbing `*` or `*.<extension>` are recognized, so the name can be sure to match.
+fn sourced_fragment_name(rc: &str, fragment_dir: &Path) -> Option<String> {
+    let prefix = format!("{}/", fragment_dir.display());
+    let mut pattern: Option<&str> = None;
+    for line in rc.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        let words = line
+            .split(|c: char| c.is_whitespace() || c == ';')
+            .filter(|word| !word.is_empty())
+            .map(|word| word.trim_matches('"'))
+            .collect::<Vec<_>>();
+        if words.first() == Some(&"for") {
+            pattern = words
+                .iter()
+                .find_map(|word| word.strip_prefix(prefix.as_str()));
+        }
+        if let Some(pattern) = pattern {
+            if words.iter().any(|word| *word == "." || *word == "source") {
+                let extension = pattern.strip_prefix('*')?;
+                if extension.contains(['*', '?', '[', '/']) {
+                    return None;
+                }
+                return Some(format!("nix{extension}"));
+            }
+        }
+        if words.contains(&"done") {
+            pattern = None;
+        }
+    }
+    None
+}
+
+/// The variables a hook exports unless already set, in the order they are exported
+///
+/// `nix-daemon.sh` only falls back to the store's CA bundle when `NIX_SSL_CERT_FILE` is unset, and
+/// `nix` only finds a configuration moved with `--nix-conf-dir` through `NIX_CONF_DIR`.
+fn hook_defaults<'a>(
+    ssl_cert_file: Option<&'a Path>,
+    nix_conf_dir: Option<&'a Path>,
+) -> Vec<(&'static str, &'a Path)> {
+    [
+        ("NIX_SSL_CERT_FILE", ssl_cert_file),
+        ("NIX_CONF_DIR", nix_conf_dir),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name, value?)))
+    .collect()
+}
+
+/// The hook for the POSIX shell profiles, bash and zsh read the same one
+///
+/// It sticks to POSIX shell, as `/etc/profile.d/nix.sh` may be read by `dash` or `busybox ash`.
+/// With `posix_only` it also avoids what some older shells lack: `[`, `-e` and `export NAME=value`.
+fn render_shell_hook(
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+    posix_only: bool,
+) -> String {
+    render_hook_sourcing(
+        PROFILE_NIX_FILE_SHELL,
+        NIX_BIN_DIR,
+        ssl_cert_file,
+        nix_conf_dir,
+        posix_only,
+    )
+}
+
+/// The hook for the dotfiles in `home` of an install without a daemon, see [`render_shell_hook`]
+fn render_user_shell_hook(
+    home: &Path,
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+    posix_only: bool,
+) -> String {
+    render_hook_sourcing(
+        &home.join(USER_PROFILE_NIX_FILE_SHELL).display().to_string(),
+        &home.join(USER_NIX_BIN_DIR).display().to_string(),
+        ssl_cert_file,
+        nix_conf_dir,
+        posix_only,
+    )
+}
+
+/// A hook sourcing `file`, which puts `bin_dir` on `PATH`
+fn render_hook_sourcing(
+    file: &str,
+    bin_dir: &str,
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+    posix_only: bool,
+) -> String {
+    if posix_only {
+        return render_posix_only_hook(file, bin_dir, ssl_cert_file, nix_conf_dir);
+    }
+    let shell_defaults = hook_defaults(ssl_cert_file, nix_conf_dir)
+        .into_iter()
+        .map(|(name, value)| {
+            let escaped = value
+                .display()
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('$', "\\$")
+                .replace('`', "\\`");
+            format!(
+                "{inde}export {name}=\"${{{name}:-{escaped}}}\"\n",
+                inde = "    ",
+            )
+        })
+        .collect::<String>();
+    format!(
+        "\n\
+        # Nix\n\
+        if [ -e '{quoted_file}' ]; then\n\
+        {shell_defaults}\
+        {source}\
+        fi\n\
+        # End Nix\n
+        \n",
+        quoted_file = single_quoted(file),
+        source = render_posix_source(file, bin_dir),
+    )
+}
+
+fn render_posix_only_hook(
+    file: &str,
+    bin_dir: &str,
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+) -> String {
+    let shell_defaults = hook_defaults(ssl_cert_file, nix_conf_dir)
+        .into_iter()
+        .map(|(name, value)| {
+            let quoted = single_quoted(&value.display().to_string());
+            format!(
+                "{inde}if test -z \"${{{name}-}}\"; then\n\
+                {inde}{inde}{name}='{quoted}'\n\
+                {inde}fi\n\
+                {inde}export {name}\n",
+                inde = "    ",
+            )
+        })
+        .collect::<String>();
+    format!(
+        "\n\
+        # Nix\n\
+        if test -r '{quoted_file}'; then\n\
+        {shell_defaults}\
+        {source}\
+        fi\n\
+        # End Nix\n\
+        \n",
+        quoted_file = single_quoted(file),
+        source = render_posix_source(file, bin_dir),
+    )
+}
+
+/// Source `file` unless this shell (see [`HOOK_GUARD`]) or a parent shell (putting `bin_dir` on `PATH`) already did, which would put Nix on `PATH` once more
+///
+/// The guard is not exported, so a shell whose `PATH` was reset (eg. by `su`) still sources `file`.
+/// Only `case` and `${NAME+word}`, which every POSIX shell (and the Bourne shell before them) has.
+fn render_posix_source(file: &str, bin_dir: &str) -> String {
+    format!(
+        "{inde}case \"${{{HOOK_GUARD}-}}:${{PATH-}}:${{NIX_PROFILES+set}}\" in\n\
+        {inde}{inde}1:*) ;;\n\
+        {inde}{inde}*':{bin_dir}:'*set) ;;\n\
+        {inde}{inde}*) . '{file}'; {HOOK_GUARD}=1 ;;\n\
+        {inde}esac\n",
+        bin_dir = single_quoted(bin_dir),
+        file = single_quoted(file),
+        inde = "    ",
+    )
+}
+
+/// `value` escaped to go between single quotes in a POSIX shell
+fn single_quoted(value: &str) -> String {
+    value.replace('\'', "'\\''")
+}
+
+/// Parse `hook` without running it (`-n`) with each of the [`POSIX_SHELLS`] found
+async fn check_posix_syntax(hook: &str) -> Result<(), ActionErrorKind> {
+    let mut checked = Vec::new();
+    for shell in POSIX_SHELLS {
+        let Ok(program) = which::which(shell[0]) else {
+            continue;
+        };
+        // `sh` is often `dash`, and `ash` often `busybox`
+        let resolved = program.canonicalize().unwrap_or_else(|_| program.clone());
+        if checked.contains(&resolved) {
+            continue;
+        }
+        checked.push(resolved);
+
+        let mut command = Command::new(&program);
+        command.args(&shell[1..]).arg("-n");
+        let output = crate::command_runner::output_with_stdin(&mut command, hook.as_bytes())
+            .await
+            .map_err(|e| ActionErrorKind::command(&command, e))?;
+        if !output.status.success() {
+            return Err(ActionErrorKind::InvalidShellHook {
+                shell: shell.join(" "),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        tracing::trace!(shell = shell.join(" "), "Shell profile hook parses");
+    }
+    Ok(())
+}
+
+fn render_fish_hook(ssl_cert_file: Option<&Path>, nix_conf_dir: Option<&Path>) -> String {
+    let fish_defaults = hook_defaults(ssl_cert_file, nix_conf_dir)
+        .into_iter()
+        .map(|(name, value)| {
+            let escaped = value
+                .display()
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('\'', "\\'");
+            format!(
+                "{inde}set --query {name}; or set --export {name} '{escaped}'\n",
+                inde = "    ",
+            )
+        })
+        .collect::<String>();
+    format!(
+        "\n\
+        # Nix\n\
+        if test -e '{PROFILE_NIX_FILE_FISH}'\n\
+        {fish_defaults}\
+        {inde}# Unless a parent shell already did, which would put Nix on `PATH` once more\n\
+        {inde}if not contains {NIX_BIN_DIR} $PATH; or not set --query NIX_PROFILES\n\
+        {inde}{inde}. '{PROFILE_NIX_FILE_FISH}'\n\
+        {inde}end\n\
+        end\n\
+        # End Nix\n\
+    \n",
+        inde = "    ", // indent
+    )
+}
+
+/// A nushell string literal of `value`, raw when it contains a `'`
+///
+/// A raw string ends at a `'` followed by as many `#` as it started with, so it starts with one more
+/// than any `'` in `value` is followed by.
+fn nushell_quote(value: &str) -> String {
+    if !value.contains('\'') {
+        return format!("'{value}'");
+    }
+    let hashes = value
+        .split('\'')
+        .skip(1)
+        .map(|after| after.len() - after.trim_start_matches('#').len())
+        .max()
+        .unwrap_or_default()
+        + 1;
+    let hashes = "#".repeat(hashes);
+    format!("r{hashes}'{value}'{hashes}")
+}
+
+/// Nushell reads no POSIX profiles, so this does in nushell what `nix-daemon.sh` does
+fn render_nushell_hook(ssl_cert_file: Option<&Path>, nix_conf_dir: Option<&Path>) -> String {
+    let nix_conf_dir = nix_conf_dir
+        .map(|nix_conf_dir| {
+            format!(
+                "{inde}if 'NIX_CONF_DIR' not-in $env {{ $env.NIX_CONF_DIR = {} }}\n",
+                nushell_quote(&nix_conf_dir.display().to_string()),
+                inde = "    ",
+            )
+        })
+        .unwrap_or_default();
+    let bundles = ssl_cert_file
+        .map(|ssl_cert_file| ssl_cert_file.display().to_string())
+        .into_iter()
+        .chain(SSL_CERT_FILE_FALLBACKS.iter().map(ToString::to_string))
+        .map(|bundle| nushell_quote(&bundle))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "\n\
+        # Nix\n\
+        if ('{PROFILE_NIX_FILE_SHELL}' | path exists) {{\n\
+        {inde}$env.NIX_PROFILES = $\"/nix/var/nix/profiles/default ($env.HOME)/.nix-profile\"\n\
+        {inde}if 'NIX_SSL_CERT_FILE' not-in $env {{\n\
+        {inde}{inde}let bundles = [{bundles}] | where {{|bundle| $bundle | path exists }}\n\
+        {inde}{inde}if ($bundles | length) > 0 {{ $env.NIX_SSL_CERT_FILE = ($bundles | first) }}\n\
+        {inde}}}\n\
+        {nix_conf_dir}\
+        {inde}let path = if ($env.PATH | describe) == 'string' {{ $env.PATH | split row (char esep) }} else {{ $env.PATH }}\n\
+        {inde}$env.PATH = ($path | prepend [$\"($env.HOME)/.nix-profile/bin\" '/nix/var/nix/profiles/default/bin'] | uniq)\n\
+        }}\n\
+        # End Nix\n\
+    \n",
+        inde = "    ", // indent
+    )
+}
+
+/// The Nix entries of `path` (a `PATH`) on it more than [`MAX_NIX_PATH_REPEATS`] times, with how often
+///
+/// Trailing slashes and empty entries are ignored, an entry is Nix's if it is in `/nix` or a profile
+/// of a user (`~/.nix-profile/bin` or `~/.local/state/nix/profile/bin`).
+pub(crate) fn nix_path_duplicates(path: &str) -> Vec<(String, usize)> {
+    let mut counts = indexmap::IndexMap::<&str, usize>::new();
+    for entry in path.split(':') {
+        let entry = entry.trim_end_matches('/');
+        let nix = entry.starts_with("/nix/")
+            || entry.ends_with("/.nix-profile/bin")
+            || entry.ends_with("/nix/profile/bin");
+        if nix {
+            *counts.entry(entry).or_default() += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > MAX_NIX_PATH_REPEATS)
+        .map(|(entry, count)| (entry.to_string(), count))
+        .collect()
+}
+
+/// The user who ran the installer with `sudo`, their login shell decides which per user hook they get
+pub(crate) fn sudo_user() -> Option<User> {
+    let name = env::SUDO_USER.get()?;
+    if name == "root" {
+        return None;
+    }
+    User::from_name(&name).ok().flatten()
+}
+
+/// Where `shell` reads per user configuration, relative to the home directory, and the hook to put there
+fn user_hook_location(
+    shell: &Path,
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+) -> Option<(PathBuf, String)> {
+    match shell.file_name()?.to_str()? {
+        "fish" => Some((
+            PathBuf::from(".config/fish/conf.d/nix.fish"),
+            render_fish_hook(ssl_cert_file, nix_conf_dir),
+        )),
+        // `$nu.default-config-dir`
+        "nu" => Some((
+            if cfg!(target_os = "macos") {
+                PathBuf::from("Library/Application Support/nushell/env.nu")
+            } else {
+                PathBuf::from(".config/nushell/env.nu")
+            },
+            render_nushell_hook(ssl_cert_file, nix_conf_dir),
+        )),
+        _ => None,
+    }
+}
+
+/// Plan a hook owned by `user` in their own configuration, if their login shell needs one
+async fn plan_user_hook(
+    user: &User,
+    ssl_cert_file: Option<&Path>,
+    nix_conf_dir: Option<&Path>,
+) -> Result<
+    Option<(
+        Vec<StatefulAction<CreateDirectory>>,
+        StatefulAction<CreateOrInsertIntoFile>,
+    )>,
+    ActionError,
+> {
+    let Some((relative_path, buf)) = user_hook_location(&user.shell, ssl_cert_file, nix_conf_dir)
+    else {
+        return Ok(None);
+    };
+    plan_user_file(user, &relative_path, buf).await.map(Some)
+}
+
+/// Plan appending `buf` to a file owned by `user`, relative to their home directory
+///
+/// Missing directories are created owned by `user` too, an existing file keeps its group and mode.
+pub(crate) async fn plan_user_file(
+    user: &User,
+    relative_path: &Path,
+    buf: String,
+) -> Result<
+    (
+        Vec<StatefulAction<CreateDirectory>>,
+        StatefulAction<CreateOrInsertIntoFile>,
+    ),
+    ActionError,
+> {
+    let group = Group::from_gid(user.gid)
+        .ok()
+        .flatten()
+        .map(|group| group.name);
+    let path = user.dir.join(relative_path);
+
+    // Created top down, so they are removed bottom up (and only if still empty)
+    let mut create_directories = vec![];
+    let mut missing = path
+        .ancestors()
+        .skip(1)
+        .take_while(|ancestor| *ancestor != user.dir && !ancestor.exists())
+        .collect::<Vec<_>>();
+    missing.reverse();
+    for directory in missing {
+        create_directories.push(
+            CreateDirectory::plan(directory, user.name.clone(), group.clone(), 0o755, false)
+                .await?,
+        );
+    }
+
+    let (file_group, mode) = match path.metadata() {
+        Ok(metadata) => (
+            Group::from_gid(metadata.gid().into())
+                .ok()
+                .flatten()
+                .map(|group| group.name),
+            None,
+        ),
+        Err(_) => (group, Some(0o644)),
+    };
+    let create_or_insert_into_file = CreateOrInsertIntoFile::plan(
+        &path,
+        user.name.clone(),
+        file_group,
+        mode,
+        buf,
+        create_or_insert_into_file::Position::End,
+    )
+    .await?;
+
+    Ok((create_directories, create_or_insert_into_file))
 }
 
 #[async_trait::async_trait]
@@ -207,14 +808,14 @@ impl Action for ConfigureShellProfile {
         {
             let span = tracing::Span::current().clone();
             let mut create_or_insert_into_file_clone = create_or_insert_into_file.clone();
-            let _abort_handle = set.spawn(async move {
+            let _abort_handle = set.spawn(crate::path_policy::propagate(async move {
                 create_or_insert_into_file_clone
                     .try_execute()
                     .instrument(span)
                     .await
                     .map_err(Self::error)?;
                 Result::<_, ActionError>::Ok((idx, create_or_insert_into_file_clone))
-            });
+            }));
         }
 
         while let Some(result) = set.join_next().await {
@@ -256,10 +857,10 @@ impl Action for ConfigureShellProfile {
             self.create_or_insert_into_files.iter_mut().enumerate()
         {
             let mut create_or_insert_file_clone = create_or_insert_into_file.clone();
-            let _abort_handle = set.spawn(async move {
+            let _abort_handle = set.spawn(crate::leftovers::propagate(async move {
                 create_or_insert_file_clone.try_revert().await?;
                 Result::<_, _>::Ok((idx, create_or_insert_file_clone))
-            });
+            }));
         }
 
         while let Some(result) = set.join_next().await {
@@ -273,7 +874,8 @@ impl Action for ConfigureShellProfile {
             };
         }
 
-        for create_directory in self.create_directories.iter_mut() {
+        // Nested directories are planned parent first
+        for create_directory in self.create_directories.iter_mut().rev() {
             if let Err(err) = create_directory.try_revert().await {
                 errors.push(err);
             }
@@ -291,3 +893,614 @@ impl Action for ConfigureShellProfile {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use nix::unistd::{getuid, User};
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::planner::FishShellProfileLocations;
+
+    #[test]
+    fn renders_nushell_hook() {
+        let hook = render_nushell_hook(Some(Path::new("/etc/corp's-ca.pem")), None);
+        assert!(hook.starts_with("\n# Nix\n"));
+        assert!(hook.ends_with("# End Nix\n\n"));
+        assert!(hook.contains(
+            "let bundles = [r#'/etc/corp's-ca.pem'# '/etc/ssl/certs/ca-certificates.crt' "
+        ));
+        assert!(hook.contains("'/nix/var/nix/profiles/default/bin'] | uniq)"));
+        assert_eq!(
+            hook.matches('{').count(),
+            hook.matches('}').count(),
+            "Unbalanced braces in:\n{hook}"
+        );
+    }
+
+    #[test]
+    fn quotes_for_nushell() {
+        assert_eq!(nushell_quote("/etc/ca.pem"), "'/etc/ca.pem'");
+        assert_eq!(
+            nushell_quote("/etc/corp's-ca.pem"),
+            "r#'/etc/corp's-ca.pem'#"
+        );
+        assert_eq!(nushell_quote("/etc/a'#b.pem"), "r##'/etc/a'#b.pem'##");
+        assert_eq!(
+            nushell_quote("/etc/a'##b'#.pem"),
+            "r###'/etc/a'##b'#.pem'###"
+        );
+    }
+
+    #[test]
+    fn renders_fish_hook() {
+        assert_eq!(
+            render_fish_hook(Some(Path::new("/etc/corp's-ca.pem")), None),
+            "\n\
+            # Nix\n\
+            if test -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish'\n\
+            \x20   set --query NIX_SSL_CERT_FILE; or set --export NIX_SSL_CERT_FILE '/etc/corp\\'s-ca.pem'\n\
+            \x20   # Unless a parent shell already did, which would put Nix on `PATH` once more\n\
+            \x20   if not contains /nix/var/nix/profiles/default/bin $PATH; or not set --query NIX_PROFILES\n\
+            \x20       . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish'\n\
+            \x20   end\n\
+            end\n\
+            # End Nix\n\
+            \n"
+        );
+    }
+
+    #[test]
+    fn renders_posix_only_hook() {
+        assert_eq!(
+            render_shell_hook(Some(Path::new("/etc/corp's-ca.pem")), None, true),
+            "\n\
+            # Nix\n\
+            if test -r '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'; then\n\
+            \x20   if test -z \"${NIX_SSL_CERT_FILE-}\"; then\n\
+            \x20       NIX_SSL_CERT_FILE='/etc/corp'\\''s-ca.pem'\n\
+            \x20   fi\n\
+            \x20   export NIX_SSL_CERT_FILE\n\
+            \x20   case \"${__NIX_PROFILE_SOURCED-}:${PATH-}:${NIX_PROFILES+set}\" in\n\
+            \x20       1:*) ;;\n\
+            \x20       *':/nix/var/nix/profiles/default/bin:'*set) ;;\n\
+            \x20       *) . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'; __NIX_PROFILE_SOURCED=1 ;;\n\
+            \x20   esac\n\
+            fi\n\
+            # End Nix\n\
+            \n"
+        );
+    }
+
+    #[tokio::test]
+    async fn shell_hooks_parse_as_posix_shell() -> eyre::Result<()> {
+        let awkward = Path::new("/etc/corp's \"ca\" $HOME `id` \\.pem");
+        for posix_only in [false, true] {
+            for (ssl_cert_file, nix_conf_dir) in [
+                (None, None),
+                (Some(awkward), None),
+                (Some(awkward), Some(Path::new("/etc/nix files"))),
+            ] {
+                let hook = render_shell_hook(ssl_cert_file, nix_conf_dir, posix_only);
+                if let Err(err) = check_posix_syntax(&hook).await {
+                    panic!("{err} in:\n{hook}");
+                }
+            }
+        }
+
+        // Only meaningful where a POSIX shell is found at all
+        if which::which("sh").is_ok() {
+            assert!(matches!(
+                check_posix_syntax("if [ -e /nix ]; then\n. /nix/profile\n").await,
+                Err(ActionErrorKind::InvalidShellHook { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sources_once_across_nested_shells() -> eyre::Result<()> {
+        if which::which("sh").is_err() {
+            return Ok(());
+        }
+        let temp_dir = tempfile::tempdir()?;
+        // Prepends like `nix-daemon.sh`, without its own guard
+        let profile = temp_dir.path().join("nix-daemon.sh");
+        std::fs::write(
+            &profile,
+            format!("export NIX_PROFILES=/nix/var/nix/profiles/default\nexport PATH=\"{NIX_BIN_DIR}:$PATH\"\n"),
+        )?;
+        let source = render_posix_source(&profile.display().to_string(), NIX_BIN_DIR);
+        // A login shell, and two nested ones inheriting its environment
+        let script = format!("{source}{source}{source}printf '%s' \"$PATH\"");
+        let output = Command::new("sh")
+            .args(["-c", &script])
+            .env("PATH", "/usr/bin:/bin")
+            .env_remove("NIX_PROFILES")
+            .output()
+            .await?;
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{NIX_BIN_DIR}:/usr/bin:/bin")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sources_once_per_shell_and_not_without_file() -> eyre::Result<()> {
+        if which::which("sh").is_err() {
+            return Ok(());
+        }
+        let temp_dir = tempfile::tempdir()?;
+        let profile = temp_dir.path().join("nix-daemon.sh");
+        for posix_only in [false, true] {
+            let hook = render_hook_sourcing(
+                &profile.display().to_string(),
+                NIX_BIN_DIR,
+                None,
+                None,
+                posix_only,
+            );
+            assert!(hook.contains(&format!("{HOOK_GUARD}=1")), "{hook}");
+            // As when `/etc/profile.d/nix.sh` and `/etc/bashrc` are both read, then `PATH` is reset
+            let script = format!(
+                "SOURCED=0\n{hook}PATH=/usr/bin:/bin\n{hook}printf '%s:%s' \"$SOURCED\" \"${{{HOOK_GUARD}-}}\""
+            );
+            let run = || {
+                Command::new("sh")
+                    .args(["-c", &script])
+                    .env_remove(HOOK_GUARD)
+                    .output()
+            };
+
+            std::fs::write(&profile, "SOURCED=$((SOURCED + 1))\n")?;
+            let output = run().await?;
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "1:1");
+
+            // Nix was uninstalled without reverting the hook
+            std::fs::remove_file(&profile)?;
+            let output = run().await?;
+            assert!(output.status.success());
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "0:");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn defaults_leave_values_already_set() -> eyre::Result<()> {
+        if which::which("sh").is_err() {
+            return Ok(());
+        }
+        let temp_dir = tempfile::tempdir()?;
+        let profile = temp_dir.path().join("nix-daemon.sh");
+        std::fs::write(&profile, "")?;
+        for posix_only in [false, true] {
+            let hook = render_hook_sourcing(
+                &profile.display().to_string(),
+                NIX_BIN_DIR,
+                Some(Path::new("/etc/ssl/corp-ca.pem")),
+                Some(Path::new("/etc/nix")),
+                posix_only,
+            );
+            let script = format!("{hook}printf '%s:%s' \"$NIX_SSL_CERT_FILE\" \"$NIX_CONF_DIR\"");
+            let run = |ssl_cert_file: Option<&str>| {
+                let mut command = Command::new("sh");
+                command
+                    .args(["-c", &script])
+                    .env_remove("NIX_CONF_DIR")
+                    .env_remove("NIX_SSL_CERT_FILE");
+                if let Some(ssl_cert_file) = ssl_cert_file {
+                    command.env("NIX_SSL_CERT_FILE", ssl_cert_file);
+                }
+                command.output()
+            };
+
+            let output = run(None).await?;
+            assert!(output.status.success());
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                "/etc/ssl/corp-ca.pem:/etc/nix"
+            );
+
+            // As set by a user level manager like home-manager, or an outer shell
+            let output = run(Some("/etc/pki/tls/certs/ca-bundle.crt")).await?;
+            assert!(output.status.success());
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                "/etc/pki/tls/certs/ca-bundle.crt:/etc/nix"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reverts_hooks_of_older_receipts() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let bashrc = temp_dir.path().join("bashrc");
+        // As written before hooks guarded against being sourced twice
+        let old_hook = "\n\
+            # Nix\n\
+            if [ -e '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh' ]; then\n\
+            \x20   . '/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh'\n\
+            fi\n\
+            # End Nix\n\
+            \n";
+        assert_ne!(old_hook, render_shell_hook(None, None, false));
+        std::fs::write(&bashrc, format!("{old_hook}umask 022\nexport EDITOR=vi\n"))?;
+        let mut action: StatefulAction<CreateOrInsertIntoFile> =
+            serde_json::from_value(serde_json::json!({
+                "action": {
+                    "path": bashrc,
+                    "user": null,
+                    "group": null,
+                    "mode": 0o644,
+                    "buf": old_hook,
+                    "position": "Beginning",
+                },
+                "state": "Completed",
+            }))?;
+
+        action.try_revert().await?;
+        assert_eq!(
+            std::fs::read_to_string(&bashrc)?,
+            "umask 022\nexport EDITOR=vi\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn finds_nix_path_duplicates() {
+        // Thousands of characters of enterprise tooling
+        let tooling = (0..400)
+            .map(|n| format!("/opt/vendor-{n}/bin"))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(tooling.len() > 5000);
+        assert_eq!(nix_path_duplicates(&tooling), vec![]);
+
+        let usual = format!("/home/alice/.nix-profile/bin:{NIX_BIN_DIR}:{tooling}:{NIX_BIN_DIR}/");
+        assert_eq!(nix_path_duplicates(&usual), vec![]);
+
+        let nested = format!(
+            "{NIX_BIN_DIR}::/home/alice/.nix-profile/bin:{NIX_BIN_DIR}/:/home/alice/.nix-profile/bin:{tooling}:{NIX_BIN_DIR}:/usr/bin:/usr/bin:/usr/bin:/home/alice/.nix-profile/bin/:"
+        );
+        assert_eq!(
+            nix_path_duplicates(&nested),
+            vec![
+                (NIX_BIN_DIR.to_string(), 3),
+                ("/home/alice/.nix-profile/bin".to_string(), 3)
+            ]
+        );
+        assert_eq!(
+            nix_path_duplicates(
+                "/home/bob/.local/state/nix/profile/bin:/home/bob/.local/state/nix/profile/bin:/home/bob/.local/state/nix/profile/bin"
+            ),
+            vec![("/home/bob/.local/state/nix/profile/bin".to_string(), 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_fish_hooks_where_fish_is_configured() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let sysconf = temp_dir.path().join("etc/fish");
+        std::fs::create_dir_all(sysconf.join("conf.d"))?;
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![sysconf.clone(), temp_dir.path().join("opt/local/etc/fish")],
+                vendor_confd_prefixes: vec![temp_dir.path().join("usr/share/fish")],
+                ..Default::default()
+            },
+            bash: vec![],
+            zsh: vec![],
+        };
+
+        let action = ConfigureShellProfile::plan(locations, None, None, false).await?;
+        // Leaving out hooks for the user running the tests, or for `$GITHUB_PATH`
+        let mut fish_files = action
+            .inner()
+            .create_or_insert_into_files
+            .iter()
+            .filter(|file| file.inner().path().starts_with(temp_dir.path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fish_files.len(),
+            1,
+            "Only fish installs which exist get a hook"
+        );
+        let nix_fish = sysconf.join("conf.d/nix.fish");
+        assert_eq!(fish_files[0].inner().path(), nix_fish);
+        let buf = fish_files[0].inner().buf();
+        assert_eq!(buf, render_fish_hook(None, None));
+        assert!(buf.contains(PROFILE_NIX_FILE_FISH), "{buf}");
+        assert!(!buf.contains(PROFILE_NIX_FILE_SHELL), "{buf}");
+
+        fish_files[0].try_execute().await?;
+        assert_eq!(
+            std::fs::read_to_string(&nix_fish)?,
+            render_fish_hook(None, None)
+        );
+        fish_files[0].try_revert().await?;
+        assert!(!nix_fish.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_profile_d_drop_in() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let etc = temp_dir.path().join("etc");
+        std::fs::create_dir_all(etc.join("profile.d"))?;
+        let nix_sh = etc.join("profile.d/nix.sh");
+        // As on macOS, which has no `/etc/profile.d` until it is created
+        std::fs::create_dir_all(temp_dir.path().join("macos/etc"))?;
+        let macos_nix_sh = temp_dir.path().join("macos/etc/profile.d/nix.sh");
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![nix_sh.clone(), macos_nix_sh.clone()],
+            zsh: vec![],
+        };
+        assert!(is_drop_in(&nix_sh));
+        assert!(!is_drop_in(&etc.join("bash.bashrc")));
+
+        let action = ConfigureShellProfile::plan(locations, None, None, false).await?;
+        let mut directories = action.inner().create_directories.clone();
+        assert_eq!(directories.len(), 1);
+        let mut planned = action
+            .inner()
+            .create_or_insert_into_files
+            .iter()
+            .filter(|file| file.inner().path().starts_with(temp_dir.path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[0].inner().path(), nix_sh);
+        assert_eq!(planned[1].inner().path(), macos_nix_sh);
+
+        directories[0].try_execute().await?;
+        assert!(temp_dir.path().join("macos/etc/profile.d").is_dir());
+        for file in &mut planned {
+            file.try_execute().await?;
+        }
+        for path in [&nix_sh, &macos_nix_sh] {
+            assert_eq!(std::fs::metadata(path)?.permissions().mode() & 0o777, 0o644);
+        }
+        for file in &mut planned {
+            file.try_revert().await?;
+        }
+        directories[0].try_revert().await?;
+        assert!(!nix_sh.exists());
+        assert!(etc.join("profile.d").is_dir());
+        assert!(!temp_dir.path().join("macos/etc/profile.d").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn finds_sourced_fragment_directories() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/shell-profile");
+        let fixture = |name: &str| std::fs::read_to_string(fixtures.join(name)).unwrap();
+        assert_eq!(
+            sourced_fragment_name(
+                &fixture("opensuse-bash.bashrc"),
+                Path::new("/etc/bash.bashrc.d")
+            ),
+            Some("nix.sh".into())
+        );
+        assert_eq!(
+            sourced_fragment_name(&fixture("fedora-zshrc"), Path::new("/etc/zshrc.d")),
+            Some("nix.zsh".into())
+        );
+        // Sources `/etc/profile.d`, which is hooked on its own, but no `/etc/bashrc.d`
+        assert_eq!(
+            sourced_fragment_name(&fixture("fedora-bashrc"), Path::new("/etc/bashrc.d")),
+            None
+        );
+        // Mentioned but not looped over, or looped over without sourcing
+        assert_eq!(
+            sourced_fragment_name(
+                "# for s in /etc/zshrc.d/*.zsh; do source $s; done\n",
+                Path::new("/etc/zshrc.d")
+            ),
+            None
+        );
+        assert_eq!(
+            sourced_fragment_name(
+                "for s in /etc/zshrc.d/*.zsh; do echo $s; done\nsource /etc/zshrc.local\n",
+                Path::new("/etc/zshrc.d")
+            ),
+            None
+        );
+        // The name could not be sure to match
+        assert_eq!(
+            sourced_fragment_name(
+                "for s in /etc/zshrc.d/[0-9]*.zsh; do source $s; done\n",
+                Path::new("/etc/zshrc.d")
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_fragment_directories_instead_of_rc_files() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let etc = temp_dir.path().join("etc");
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/shell-profile");
+        let opensuse = std::fs::read_to_string(fixtures.join("opensuse-bash.bashrc"))?
+            .replace("/etc/", &format!("{}/", etc.display()));
+        std::fs::create_dir_all(etc.join("bash.bashrc.d"))?;
+        std::fs::write(etc.join("bash.bashrc"), &opensuse)?;
+        // Fedora's `/etc/zshrc`, without the directory it sources
+        let fedora = std::fs::read_to_string(fixtures.join("fedora-zshrc"))?
+            .replace("/etc/", &format!("{}/", etc.display()));
+        std::fs::write(etc.join("zshrc"), &fedora)?;
+        let locations = ShellProfileLocations {
+            fish: FishShellProfileLocations {
+                confd_prefixes: vec![],
+                vendor_confd_prefixes: vec![],
+                ..Default::default()
+            },
+            bash: vec![etc.join("bash.bashrc")],
+            zsh: vec![etc.join("zshrc")],
+        };
+
+        let action = ConfigureShellProfile::plan(locations, None, None, false).await?;
+        let mut planned = action
+            .inner()
+            .create_or_insert_into_files
+            .iter()
+            .filter(|file| file.inner().path().starts_with(temp_dir.path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            planned
+                .iter()
+                .map(|file| file.inner().path().to_path_buf())
+                .collect::<Vec<_>>(),
+            [etc.join("bash.bashrc.d/nix.sh"), etc.join("zshrc")]
+        );
+
+        planned[0].try_execute().await?;
+        assert_eq!(std::fs::read_to_string(etc.join("bash.bashrc"))?, opensuse);
+        assert!(std::fs::read_to_string(etc.join("bash.bashrc.d/nix.sh"))?
+            .contains(PROFILE_NIX_FILE_SHELL));
+        planned[0].try_revert().await?;
+        assert!(!etc.join("bash.bashrc.d/nix.sh").exists());
+        assert!(etc.join("bash.bashrc.d").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn only_shells_without_global_profiles_get_user_hooks() {
+        let (fish, _) = user_hook_location(Path::new("/usr/bin/fish"), None, None).unwrap();
+        assert_eq!(fish, PathBuf::from(".config/fish/conf.d/nix.fish"));
+        let (nu, _) = user_hook_location(Path::new("/home/me/.cargo/bin/nu"), None, None).unwrap();
+        assert!(nu.ends_with("nushell/env.nu"));
+        assert!(user_hook_location(Path::new("/bin/bash"), None, None).is_none());
+        assert!(user_hook_location(Path::new("/usr/bin/zsh"), None, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn user_hook_reverts_only_its_fragment() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut user = User::from_uid(getuid())?.expect("The current user should exist");
+        user.dir = temp_dir.path().to_path_buf();
+        user.shell = PathBuf::from("/usr/bin/nu");
+
+        let (mut create_directories, mut create_or_insert_into_file) =
+            plan_user_hook(&user, None, None)
+                .await?
+                .expect("Nushell needs a hook");
+        for create_directory in &mut create_directories {
+            create_directory.try_execute().await?;
+        }
+        create_or_insert_into_file.try_execute().await?;
+
+        let env_nu = user.dir.join(if cfg!(target_os = "macos") {
+            "Library/Application Support/nushell/env.nu"
+        } else {
+            ".config/nushell/env.nu"
+        });
+        let hook = std::fs::read_to_string(&env_nu)?;
+        assert_eq!(hook, render_nushell_hook(None, None));
+
+        // The user keeps configuring nushell after the install
+        let user_config = "$env.EDITOR = 'hx'\n";
+        std::fs::write(&env_nu, format!("{hook}{user_config}"))?;
+
+        create_or_insert_into_file.try_revert().await?;
+        for create_directory in create_directories.iter_mut().rev() {
+            create_directory.try_revert().await?;
+        }
+        assert_eq!(std::fs::read_to_string(&env_nu)?, user_config);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_the_hook_cannot_hold() -> eyre::Result<()> {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+        let non_utf8 = PathBuf::from(OsString::from_vec(b"/home/j\xf6rg".to_vec()));
+        let err = ConfigureShellProfile::plan_for_user(non_utf8.clone(), None, None, false)
+            .await
+            .expect_err("A home which isn't UTF-8 can't be sourced from the hook");
+        assert!(
+            matches!(err.kind(), ActionErrorKind::UnrepresentableHookPath(path) if *path == non_utf8),
+            "{err}"
+        );
+        assert!(err.kind().to_string().contains("(not UTF-8)"));
+
+        let err = ConfigureShellProfile::plan(
+            ShellProfileLocations::default(),
+            Some(non_utf8.join("ca.crt")),
+            None,
+            false,
+        )
+        .await
+        .expect_err("A CA bundle which isn't UTF-8 can't be exported from the hook");
+        assert!(matches!(
+            err.kind(),
+            ActionErrorKind::UnrepresentableHookPath(_)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plans_user_dotfiles_without_daemon() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let bashrc = home.path().join(".bashrc");
+        let user_config = "alias ll='ls -l'\n";
+        std::fs::write(&bashrc, user_config)?;
+
+        let mut action =
+            ConfigureShellProfile::plan_for_user(home.path().to_path_buf(), None, None, false)
+                .await?;
+        let dotfiles = [".profile", ".bashrc", ".zshrc"].map(|name| home.path().join(name));
+        assert_eq!(
+            action
+                .inner()
+                .create_or_insert_into_files
+                .iter()
+                .map(|file| file.inner().path().to_path_buf())
+                .collect::<Vec<_>>(),
+            dotfiles
+        );
+        let hook = action.inner().create_or_insert_into_files[0]
+            .inner()
+            .buf()
+            .to_string();
+        assert!(hook.contains(&format!(
+            ". '{}/.nix-profile/etc/profile.d/nix.sh'",
+            home.path().display()
+        )));
+        assert!(!hook.contains(PROFILE_NIX_FILE_SHELL), "{hook}");
+
+        action.try_execute().await?;
+        for dotfile in &dotfiles {
+            assert!(std::fs::read_to_string(dotfile)?.contains(&hook));
+            assert_eq!(std::fs::metadata(dotfile)?.uid(), getuid().as_raw());
+        }
+
+        // The user keeps configuring bash after the install
+        let more_user_config = "export EDITOR=vi\n";
+        let mut edited = std::fs::read_to_string(&bashrc)?;
+        edited.push_str(more_user_config);
+        std::fs::write(&bashrc, edited)?;
+
+        action.try_revert().await?;
+        assert_eq!(
+            std::fs::read_to_string(&bashrc)?,
+            format!("{user_config}{more_user_config}")
+        );
+        assert!(!home.path().join(".profile").exists());
+        assert!(!home.path().join(".zshrc").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use nix::unistd::{Uid, User};
+use nix_config_parser::NixConfig;
+use tracing::{span, Span};
+
+use crate::action::{
+    base::{
+        create_or_merge_nix_config::CreateOrMergeNixConfigError, CreateDirectory,
+        CreateOrInsertIntoFile,
+    },
+    common::configure_shell_profile::{plan_user_file, sudo_user},
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+/// Where `nix` reads per user settings, relative to the home directory
+const USER_NIX_CONF: &str = ".config/nix/nix.conf";
+
+/// Marks the shell prompt of `nix-shell` and `nix develop`, which set `IN_NIX_SHELL`
+const PROMPT_HOOK: &str = "\n\
+    # Nix\n\
+    if [ -n \"${IN_NIX_SHELL:-}\" ]; then\n\
+    \x20   PS1=\"(nix-shell) ${PS1:-}\"\n\
+    fi\n\
+    # End Nix\n";
+
+/**
+Configure `nix` for `root` and the user who ran the installer with `sudo`
+
+Settings passed with `--user-nix-conf` are appended to their `~/.config/nix/nix.conf`, and with
+`--prompt-integration` a hook marking the prompt of `nix-shell` to the `~/.bashrc` (or `~/.zshrc`)
+of their login shell. Only what was appended is removed on uninstall.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureUserNix {
+    users: Vec<String>,
+    settings: IndexMap<String, String>,
+    prompt_integration: bool,
+    create_directories: Vec<StatefulAction<CreateDirectory>>,
+    create_or_insert_into_files: Vec<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl ConfigureUserNix {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        users: &[User],
+        user_nix_conf: &[String],
+        prompt_integration: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let settings = NixConfig::parse_string(user_nix_conf.join("\n"), None)
+            .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
+            .map_err(Self::error)?
+            .settings()
+            .clone();
+
+        let mut create_directories = vec![];
+        let mut create_or_insert_into_files = vec![];
+        for user in users {
+            let nix_conf = user.dir.join(USER_NIX_CONF);
+            let pending = unset_settings(&settings, &nix_conf).map_err(Self::error)?;
+            if !pending.is_empty() {
+                let (directories, file) =
+                    plan_user_file(user, Path::new(USER_NIX_CONF), render_nix_conf(&pending))
+                        .await?;
+                create_directories.extend(directories);
+                create_or_insert_into_files.push(file);
+            }
+
+            if prompt_integration {
+                match prompt_rc(&user.shell) {
+                    Some(rc) => {
+                        let (directories, file) =
+                            plan_user_file(user, Path::new(rc), PROMPT_HOOK.to_string()).await?;
+                        create_directories.extend(directories);
+                        create_or_insert_into_files.push(file);
+                    },
+                    None => tracing::warn!(
+                        "Not integrating the prompt of `{}`, whose login shell `{}` is neither bash nor zsh",
+                        user.name,
+                        user.shell.display()
+                    ),
+                }
+            }
+        }
+
+        Ok(Self {
+            users: users.iter().map(|user| user.name.clone()).collect(),
+            settings,
+            prompt_integration,
+            create_directories,
+            create_or_insert_into_files,
+        }
+        .into())
+    }
+
+    /// `root`, and the user who ran the installer with `sudo`
+    pub(crate) fn target_users() -> Vec<User> {
+        User::from_uid(Uid::from_raw(0))
+            .ok()
+            .flatten()
+            .into_iter()
+            .chain(sudo_user())
+            .collect()
+    }
+
+    /// The files which no longer have what was configured in them, with what they lack
+    pub(crate) fn verify(&self) -> Vec<(PathBuf, String)> {
+        let mut missing = vec![];
+        for create_or_insert_into_file in &self.create_or_insert_into_files {
+            let file = create_or_insert_into_file.inner();
+            let path = file.path().to_path_buf();
+            // Read as bytes, rc files may have been written in some legacy encoding
+            let contents = std::fs::read(&path).unwrap_or_default();
+            if !path.ends_with(USER_NIX_CONF) {
+                let hook = file.buf().as_bytes();
+                if !contents.windows(hook.len()).any(|window| window == hook) {
+                    missing.push((path, "the prompt hook".to_string()));
+                }
+                continue;
+            }
+            // Settings later in the file win, so check what `nix` will see
+            let contents = String::from_utf8_lossy(&contents).into_owned();
+            let effective = NixConfig::parse_string(contents, None)
+                .map(|config| config.settings().clone())
+                .unwrap_or_default();
+            for (name, value) in &self.settings {
+                if effective.get(name) != Some(value) {
+                    missing.push((path.clone(), format!("{name} = {value}")));
+                }
+            }
+        }
+        missing
+    }
+}
+
+/// The `settings` the `nix.conf` at `path` doesn't already have, refusing to override those it sets otherwise
+fn unset_settings(
+    settings: &IndexMap<String, String>,
+    path: &Path,
+) -> Result<IndexMap<String, String>, CreateOrMergeNixConfigError> {
+    let existing = match path.exists() {
+        true => NixConfig::parse_file(path)?.settings().clone(),
+        false => IndexMap::new(),
+    };
+    let conflicting = settings
+        .iter()
+        .filter(|(name, value)| {
+            existing
+                .get(*name)
+                .is_some_and(|existing| existing != *value)
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    if !conflicting.is_empty() {
+        return Err(CreateOrMergeNixConfigError::UnmergeableConfig(
+            conflicting,
+            path.to_path_buf(),
+        ));
+    }
+    Ok(settings
+        .iter()
+        .filter(|(name, _)| !existing.contains_key(*name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect())
+}
+
+fn render_nix_conf(settings: &IndexMap<String, String>) -> String {
+    let settings = settings
+        .iter()
+        .map(|(name, value)| format!("{name} = {value}\n"))
+        .collect::<String>();
+    format!("\n# Nix\n{settings}# End Nix\n")
+}
+
+/// The interactive configuration of a login shell the prompt hook goes in, relative to the home directory
+fn prompt_rc(shell: &Path) -> Option<&'static str> {
+    match shell.file_name()?.to_str()? {
+        "bash" => Some(".bashrc"),
+        "zsh" => Some(".zshrc"),
+        _ => None,
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_user_nix")]
+impl Action for ConfigureUserNix {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_user_nix")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Configure `nix` for `{}`", self.users.join("`, `"))
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "configure_user_nix",
+            users = self.users.join(","),
+            prompt_integration = self.prompt_integration,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = self
+            .settings
+            .iter()
+            .map(|(name, value)| format!("Set `{name} = {value}` in `~/{USER_NIX_CONF}`"))
+            .collect::<Vec<_>>();
+        if self.prompt_integration {
+            explanation.push("Mark the prompt of shells in `nix-shell` or `nix develop`".into());
+        }
+        for create_or_insert_into_file in &self.create_or_insert_into_files {
+            if let Some(val) = create_or_insert_into_file.describe_execute().first() {
+                explanation.push(val.description.clone())
+            }
+        }
+        vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        for create_directory in &mut self.create_directories {
+            create_directory.try_execute().await?;
+        }
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            create_or_insert_into_file.try_execute().await?;
+        }
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![];
+        for create_or_insert_into_file in &self.create_or_insert_into_files {
+            if let Some(val) = create_or_insert_into_file.describe_revert().first() {
+                explanation.push(val.description.clone())
+            }
+        }
+        vec![ActionDescription::new(
+            format!("Unconfigure `nix` for `{}`", self.users.join("`, `")),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        for create_or_insert_into_file in &mut self.create_or_insert_into_files {
+            if let Err(err) = create_or_insert_into_file.try_revert().await {
+                errors.push(err);
+            }
+        }
+        // Created top down, removed bottom up (and only if still empty)
+        for create_directory in self.create_directories.iter_mut().rev() {
+            if let Err(err) = create_directory.try_revert().await {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use nix::unistd::getuid;
+
+    use super::*;
+
+    fn temp_user(home: &Path) -> eyre::Result<User> {
+        let mut user = User::from_uid(getuid())?.expect("The current user should exist");
+        user.dir = home.to_path_buf();
+        user.shell = PathBuf::from("/bin/bash");
+        Ok(user)
+    }
+
+    #[tokio::test]
+    async fn creates_owned_files_and_reverts_them() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let user = temp_user(home.path())?;
+
+        let mut action = ConfigureUserNix::plan(
+            std::slice::from_ref(&user),
+            &["accept-flake-config = true".into()],
+            true,
+        )
+        .await?;
+        action.try_execute().await?;
+
+        let nix_conf = home.path().join(USER_NIX_CONF);
+        assert_eq!(
+            std::fs::read_to_string(&nix_conf)?,
+            "\n# Nix\naccept-flake-config = true\n# End Nix\n"
+        );
+        for path in [
+            nix_conf.clone(),
+            home.path().join(".config/nix"),
+            home.path().join(".bashrc"),
+        ] {
+            let metadata = path.metadata()?;
+            assert_eq!(metadata.uid(), user.uid.as_raw(), "{}", path.display());
+            assert_eq!(metadata.gid(), user.gid.as_raw(), "{}", path.display());
+        }
+        assert_eq!(
+            std::fs::read_to_string(home.path().join(".bashrc"))?,
+            PROMPT_HOOK
+        );
+        assert_eq!(action.inner().verify(), []);
+
+        action.try_revert().await?;
+        assert!(!nix_conf.exists());
+        assert!(!home.path().join(".config").exists());
+        assert!(!home.path().join(".bashrc").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verifies_hook_in_non_utf8_rc_file() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let user = temp_user(home.path())?;
+        let bashrc = home.path().join(".bashrc");
+        let existing = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/shell-profile/invalid-utf8.bashrc"
+        ))?;
+        std::fs::write(&bashrc, &existing)?;
+
+        let mut action = ConfigureUserNix::plan(std::slice::from_ref(&user), &[], true).await?;
+        action.try_execute().await?;
+        assert_eq!(action.inner().verify(), []);
+
+        std::fs::write(&bashrc, &existing)?;
+        assert_eq!(
+            action.inner().verify(),
+            [(bashrc, "the prompt hook".to_string())]
+        );
+        Ok(())
+    }
+
+    // Other filesystems, such as APFS, refuse names which aren't UTF-8
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn configures_home_which_is_not_utf8() -> eyre::Result<()> {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+        let temp_dir = tempfile::tempdir()?;
+        let mut name = temp_dir.path().as_os_str().to_os_string().into_vec();
+        name.extend(b"/j\xf6rg");
+        let home = PathBuf::from(OsString::from_vec(name));
+        std::fs::create_dir(&home)?;
+        let user = temp_user(&home)?;
+
+        let mut action = ConfigureUserNix::plan(
+            std::slice::from_ref(&user),
+            &["accept-flake-config = true".into()],
+            true,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(std::fs::read_to_string(home.join(".bashrc"))?, PROMPT_HOOK);
+
+        // Recorded in the receipt and read back, reverting finds the same files
+        let receipt = serde_json::to_string(&action)?;
+        let mut action: StatefulAction<ConfigureUserNix> = serde_json::from_str(&receipt)?;
+        assert_eq!(action.inner().verify(), []);
+        action.try_revert().await?;
+        assert!(!home.join(".bashrc").exists());
+        assert!(!home.join(".config").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merges_with_existing_user_conf() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let user = temp_user(home.path())?;
+        let nix_conf = home.path().join(USER_NIX_CONF);
+        std::fs::create_dir_all(nix_conf.parent().unwrap())?;
+        let existing = "max-jobs = 4\nexperimental-features = nix-command flakes\n";
+        std::fs::write(&nix_conf, existing)?;
+
+        let mut action = ConfigureUserNix::plan(
+            std::slice::from_ref(&user),
+            &["max-jobs = 4".into(), "accept-flake-config = true".into()],
+            false,
+        )
+        .await?;
+        action.try_execute().await?;
+        assert_eq!(
+            std::fs::read_to_string(&nix_conf)?,
+            format!("{existing}\n# Nix\naccept-flake-config = true\n# End Nix\n")
+        );
+        assert_eq!(action.inner().verify(), []);
+
+        // Edited since, `nix` no longer sees the setting
+        std::fs::write(
+            &nix_conf,
+            format!(
+                "{}accept-flake-config = false\n",
+                std::fs::read_to_string(&nix_conf)?
+            ),
+        )?;
+        assert_eq!(
+            action.inner().verify(),
+            [(nix_conf.clone(), "accept-flake-config = true".to_string())]
+        );
+
+        let conflicting =
+            ConfigureUserNix::plan(std::slice::from_ref(&user), &["max-jobs = 8".into()], false)
+                .await;
+        assert!(conflicting.is_err());
+
+        action.try_revert().await?;
+        assert_eq!(
+            std::fs::read_to_string(&nix_conf)?,
+            format!("{existing}accept-flake-config = false\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prompt_hook_parses_as_posix_shell() -> eyre::Result<()> {
+        if which::which("sh").is_err() {
+            return Ok(());
+        }
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-n");
+        let output =
+            crate::command_runner::output_with_stdin(&mut command, PROMPT_HOOK.as_bytes()).await?;
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(prompt_rc(Path::new("/bin/zsh")), Some(".zshrc"));
+        assert_eq!(prompt_rc(Path::new("/usr/bin/fish")), None);
+        Ok(())
+    }
+}
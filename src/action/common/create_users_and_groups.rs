@@ -1,12 +1,22 @@
 use crate::{
     action::{
-        base::{AddUserToGroup, CreateGroup, CreateUser},
-        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        base::{
+            store_group::NIX_STORE_DIR, AddUserToGroup, CreateGroup, CreateUser, Reown,
+            ReownNixStore, StoreGroupDecision, StoreGroupSample,
+        },
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag,
+        StatefulAction,
     },
+    os::nss::{invalidate_caches, wait_for_resolution, NssUserBackend, UserBackend},
     settings::CommonSettings,
 };
+use nix::unistd::{Gid, Group};
+use std::{path::Path, time::Duration};
 use tracing::{span, Span};
 
+const NSS_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(30);
+const NSS_RESOLUTION_INTERVAL: Duration = Duration::from_millis(500);
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateUsersAndGroups {
     nix_build_user_count: u32,
@@ -17,15 +27,77 @@ pub struct CreateUsersAndGroups {
     create_group: StatefulAction<CreateGroup>,
     create_users: Vec<StatefulAction<CreateUser>>,
     add_users_to_groups: Vec<StatefulAction<AddUserToGroup>>,
+    /// What was decided about an existing store owned by another GID
+    #[serde(default)]
+    store_group: Option<StoreGroupDecision>,
+    #[serde(default)]
+    reown_nix_store: Option<StatefulAction<ReownNixStore>>,
 }
 
 impl CreateUsersAndGroups {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(settings: CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(mut settings: CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        if !settings.create_users {
+            return Self::plan_provisioned(settings);
+        }
+
+        // A kept (or adopted) store is group owned by the build group it was installed with
+        let sample = StoreGroupSample::take(Path::new(NIX_STORE_DIR));
+        let adoptable = sample.as_ref().is_some_and(|sample| {
+            match Group::from_name(&settings.nix_build_group_name)
+                .ok()
+                .flatten()
+            {
+                Some(group) => group.gid.as_raw() == sample.gid,
+                None => Group::from_gid(Gid::from_raw(sample.gid))
+                    .ok()
+                    .flatten()
+                    .is_none(),
+            }
+        });
+        let store_group = StoreGroupDecision::decide(
+            sample,
+            settings.nix_build_group_id,
+            adoptable,
+            settings.regroup_store,
+        )
+        .map_err(Self::error)?;
+        let mut reown_nix_store = None;
+        match &store_group {
+            Some(StoreGroupDecision::Adopt { sample }) => {
+                tracing::info!(
+                    "Adopting GID {} of the existing store for the build group `{}` instead of GID {}, {sample}",
+                    sample.gid,
+                    settings.nix_build_group_name,
+                    settings.nix_build_group_id,
+                );
+                settings.nix_build_group_id = sample.gid;
+            },
+            Some(StoreGroupDecision::Regroup { sample }) => {
+                reown_nix_store = Some(ReownNixStore::plan(
+                    NIX_STORE_DIR,
+                    Reown {
+                        uid: None,
+                        gid: Some((sample.gid, settings.nix_build_group_id)),
+                    },
+                )?);
+            },
+            None => (),
+        }
+
         let create_group = CreateGroup::plan(
             settings.nix_build_group_name.clone(),
             settings.nix_build_group_id,
         )?;
+        // Every user's primary group is the group as resolved, which may have been adopted
+        let gid = create_group.inner().gid;
+        check_unclaimed(
+            &NssUserBackend,
+            &settings.nix_build_user_prefix,
+            settings.nix_build_user_count,
+            settings.nix_build_user_id_base,
+        )
+        .map_err(Self::error)?;
         let mut create_users = Vec::with_capacity(settings.nix_build_user_count as usize);
         let mut add_users_to_groups = Vec::with_capacity(settings.nix_build_user_count as usize);
         for index in 1..=settings.nix_build_user_count {
@@ -34,7 +106,7 @@ impl CreateUsersAndGroups {
                     format!("{}{index}", settings.nix_build_user_prefix),
                     settings.nix_build_user_id_base + index,
                     settings.nix_build_group_name.clone(),
-                    settings.nix_build_group_id,
+                    gid,
                     format!("Nix build user {index}"),
                 )
                 .await
@@ -45,7 +117,7 @@ impl CreateUsersAndGroups {
                     format!("{}{index}", settings.nix_build_user_prefix),
                     settings.nix_build_user_id_base + index,
                     settings.nix_build_group_name.clone(),
-                    settings.nix_build_group_id,
+                    gid,
                 )
                 .await
                 .map_err(Self::error)?,
@@ -54,15 +126,192 @@ impl CreateUsersAndGroups {
         Ok(Self {
             nix_build_user_count: settings.nix_build_user_count,
             nix_build_group_name: settings.nix_build_group_name,
-            nix_build_group_id: settings.nix_build_group_id,
+            nix_build_group_id: gid,
             nix_build_user_prefix: settings.nix_build_user_prefix,
             nix_build_user_id_base: settings.nix_build_user_id_base,
             create_group,
             create_users,
             add_users_to_groups,
+            store_group,
+            reown_nix_store,
         }
         .into())
     }
+
+    /// Plan using the build users identity management provisioned, see [`CommonSettings::create_users`]
+    fn plan_provisioned(settings: CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        let ProvisionedBuildUsers {
+            group_name,
+            gid,
+            users,
+        } = ProvisionedBuildUsers::find(
+            &NssUserBackend,
+            &settings.nix_build_user_prefix,
+            settings.nix_build_user_count,
+            &settings.nix_build_group_name,
+        )
+        .map_err(Self::error)?;
+        tracing::debug!(
+            "Using {} provisioned build users of group `{group_name}` (GID {gid})",
+            users.len()
+        );
+        let create_users = users
+            .into_iter()
+            .zip(1..)
+            .map(|((name, uid), index)| {
+                CreateUser::provisioned(
+                    name,
+                    uid,
+                    group_name.clone(),
+                    gid,
+                    format!("Nix build user {index}"),
+                )
+            })
+            .collect();
+        Ok(Self {
+            nix_build_user_count: settings.nix_build_user_count,
+            nix_build_group_name: group_name.clone(),
+            nix_build_group_id: gid,
+            nix_build_user_prefix: settings.nix_build_user_prefix,
+            nix_build_user_id_base: settings.nix_build_user_id_base,
+            create_group: CreateGroup::provisioned(group_name, gid),
+            create_users,
+            add_users_to_groups: Vec::new(),
+            store_group: None,
+            reown_nix_store: None,
+        }
+        .into())
+    }
+
+    /// `settings` with the group the provisioned build users share (see [`CommonSettings::create_users`]),
+    /// for planning the rest of the install such as `build-users-group` in `nix.conf`
+    pub fn resolve_settings(settings: &CommonSettings) -> Result<CommonSettings, ActionError> {
+        let mut settings = settings.clone();
+        if !settings.create_users {
+            let provisioned = ProvisionedBuildUsers::find(
+                &NssUserBackend,
+                &settings.nix_build_user_prefix,
+                settings.nix_build_user_count,
+                &settings.nix_build_group_name,
+            )
+            .map_err(Self::error)?;
+            settings.nix_build_group_name = provisioned.group_name;
+            settings.nix_build_group_id = provisioned.gid;
+        }
+        Ok(settings)
+    }
+
+    /// The build users and group are provisioned by identity management, not by this action
+    fn is_provisioned(&self) -> bool {
+        self.create_group.state == ActionState::Skipped
+    }
+}
+
+/// Build users provisioned by identity management, see [`CommonSettings::create_users`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ProvisionedBuildUsers {
+    /// The group listing every one of the users as a member
+    pub(crate) group_name: String,
+    pub(crate) gid: u32,
+    /// The names and UIDs of the users, in order
+    pub(crate) users: Vec<(String, u32)>,
+}
+
+impl ProvisionedBuildUsers {
+    /**
+    Find the `count` users named by `prefix`, numbered like `{prefix}1` or zero padded (up to three
+    digits) like `{prefix}01`, and the group they share
+
+    `nix-daemon` picks build users among the members of its `build-users-group`, so the group must
+    list them all. The group `group_name` is preferred, then the primary group of the users.
+    */
+    pub(crate) fn find(
+        backend: &impl UserBackend,
+        prefix: &str,
+        count: u32,
+        group_name: &str,
+    ) -> Result<Self, ActionErrorKind> {
+        let mut users = Vec::with_capacity(count as usize);
+        let mut missing = Vec::new();
+        for index in 1..=count {
+            let found = (1..=3)
+                .map(|width| format!("{prefix}{index:0width$}"))
+                .find_map(|name| backend.user_ids(&name).map(|ids| (name, ids)));
+            match found {
+                Some((name, (uid, gid))) => users.push((name, uid, gid)),
+                None => missing.push(format!("{prefix}{index}")),
+            }
+        }
+        if !missing.is_empty() {
+            return Err(ActionErrorKind::MissingBuildUsers(prefix.into(), missing));
+        }
+
+        let mut candidates = vec![group_name.to_string()];
+        if let Some(primary) = users
+            .first()
+            .and_then(|(_, _, gid)| backend.group_name(*gid))
+            .filter(|primary| primary != group_name)
+        {
+            candidates.push(primary);
+        }
+        let shared = candidates.iter().find_map(|candidate| {
+            let gid = backend.group_gid(candidate)?;
+            let members = backend.group_members(candidate);
+            users
+                .iter()
+                .all(|(name, _, _)| members.contains(name))
+                .then(|| (candidate.clone(), gid))
+        });
+        let Some((group_name, gid)) = shared else {
+            return Err(ActionErrorKind::NoSharedBuildGroup(candidates));
+        };
+        Ok(Self {
+            group_name,
+            gid,
+            users: users
+                .into_iter()
+                .map(|(name, uid, _)| (name, uid))
+                .collect(),
+        })
+    }
+}
+
+/**
+Ensure creating `count` users named by `prefix`, with UIDs counting up from `uid_base + 1`, takes
+over no other accounts
+
+Existing users with both the planned name and UID are adopted, like those of an earlier install.
+*/
+pub(crate) fn check_unclaimed(
+    backend: &impl UserBackend,
+    prefix: &str,
+    count: u32,
+    uid_base: u32,
+) -> Result<(), ActionErrorKind> {
+    let mut collisions = Vec::new();
+    for index in 1..=count {
+        let name = format!("{prefix}{index}");
+        let uid = uid_base + index;
+        if let Some((existing, _)) = backend.user_ids(&name) {
+            if existing != uid {
+                collisions.push(format!(
+                    "`{name}` exists with UID {existing} instead of {uid}"
+                ));
+            }
+        }
+        if let Some(other) = backend.user_name(uid) {
+            if other != name {
+                collisions.push(format!(
+                    "UID {uid} planned for `{name}` belongs to `{other}`"
+                ));
+            }
+        }
+    }
+    if collisions.is_empty() {
+        Ok(())
+    } else {
+        Err(ActionErrorKind::BuildUserCollisions(collisions))
+    }
 }
 
 #[async_trait::async_trait]
@@ -72,7 +321,12 @@ impl Action for CreateUsersAndGroups {
         ActionTag("create_users_and_group")
     }
     fn tracing_synopsis(&self) -> String {
-        if self.create_users.is_empty() {
+        if self.is_provisioned() {
+            format!(
+                "Use the provisioned build users of group `{}` (GID {})",
+                self.nix_build_group_name, self.nix_build_group_id
+            )
+        } else if self.create_users.is_empty() {
             format!("Create build group (GID {})", self.nix_build_group_id)
         } else {
             format!(
@@ -106,6 +360,8 @@ impl Action for CreateUsersAndGroups {
             create_group,
             create_users,
             add_users_to_groups,
+            store_group,
+            reown_nix_store,
         } = &self;
 
         let mut create_users_descriptions = Vec::new();
@@ -125,11 +381,20 @@ impl Action for CreateUsersAndGroups {
         let mut explanation = vec![
             format!("The Nix daemon requires system users (and a group they share) which it can act as in order to build"),
         ];
+        if let Some(store_group) = store_group {
+            explanation.push(store_group.to_string());
+        }
         if let Some(val) = create_group.describe_execute().first() {
             explanation.push(val.description.clone())
         }
         explanation.append(&mut create_users_descriptions);
         explanation.append(&mut add_user_to_group_descriptions);
+        if let Some(val) = reown_nix_store
+            .as_ref()
+            .and_then(|reown_nix_store| reown_nix_store.describe_execute().first().cloned())
+        {
+            explanation.push(val.description)
+        }
 
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
@@ -145,10 +410,15 @@ impl Action for CreateUsersAndGroups {
             nix_build_group_id: _,
             nix_build_user_prefix: _,
             nix_build_user_id_base: _,
+            store_group: _,
+            reown_nix_store,
         } = self;
 
         // Create group
         create_group.try_execute().await?;
+        if let Some(reown_nix_store) = reown_nix_store {
+            reown_nix_store.try_execute().await.map_err(Self::error)?;
+        }
 
         // Mac is apparently not threadsafe here...
         use target_lexicon::OperatingSystem;
@@ -206,6 +476,28 @@ impl Action for CreateUsersAndGroups {
             add_user_to_group.try_execute().await.map_err(Self::error)?;
         }
 
+        // On hosts running `nscd` or `sssd` the new users may be negatively cached, and `nix-daemon`
+        // would fail its first builds with "user nixbld1 not found"
+        if !matches!(
+            OperatingSystem::host(),
+            OperatingSystem::MacOSX { .. } | OperatingSystem::Darwin
+        ) {
+            invalidate_caches().await;
+            let users = create_users
+                .iter()
+                .map(|create_user| create_user.inner().name.clone())
+                .collect::<Vec<_>>();
+            wait_for_resolution(
+                &NssUserBackend,
+                &users,
+                &[create_group.inner().name.clone()],
+                NSS_RESOLUTION_TIMEOUT,
+                NSS_RESOLUTION_INTERVAL,
+            )
+            .await
+            .map_err(|unresolved| Self::error(ActionErrorKind::NssLookupTimeout(unresolved)))?;
+        }
+
         Ok(())
     }
 
@@ -219,6 +511,8 @@ impl Action for CreateUsersAndGroups {
             create_group,
             create_users,
             add_users_to_groups,
+            store_group: _,
+            reown_nix_store,
         } = &self;
         let mut create_users_descriptions = Vec::new();
         for create_user in create_users {
@@ -237,13 +531,24 @@ impl Action for CreateUsersAndGroups {
         let mut explanation = vec![
             format!("The Nix daemon requires system users (and a group they share) which it can act as in order to build"),
         ];
+        if let Some(val) = reown_nix_store
+            .as_ref()
+            .and_then(|reown_nix_store| reown_nix_store.describe_revert().first().cloned())
+        {
+            explanation.push(val.description)
+        }
         if let Some(val) = create_group.describe_revert().first() {
             explanation.push(val.description.clone())
         }
         explanation.append(&mut create_users_descriptions);
         explanation.append(&mut add_user_to_group_descriptions);
 
-        if create_users.is_empty() {
+        if self.is_provisioned() {
+            vec![ActionDescription::new(
+                "Leave the provisioned build users and group in place".to_string(),
+                explanation,
+            )]
+        } else if create_users.is_empty() {
             vec![ActionDescription::new(
                 "Remove Nix group".to_string(),
                 explanation,
@@ -270,6 +575,12 @@ impl Action for CreateUsersAndGroups {
         //     add_user_to_group.try_revert().await?;
         // }
 
+        if let Some(reown_nix_store) = &mut self.reown_nix_store {
+            if let Err(err) = reown_nix_store.try_revert().await {
+                errors.push(err);
+            }
+        }
+
         // Create group
         if let Err(err) = self.create_group.try_revert().await {
             errors.push(err);
@@ -287,3 +598,163 @@ impl Action for CreateUsersAndGroups {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Accounts as identity management (or an earlier install) left them
+    #[derive(Default)]
+    struct FakeAccounts {
+        /// By name, the UID and primary GID
+        users: BTreeMap<String, (u32, u32)>,
+        /// By name, the GID and members
+        groups: BTreeMap<String, (u32, Vec<String>)>,
+    }
+
+    impl FakeAccounts {
+        fn provisioned(prefix: &str, count: u32, group: &str, gid: u32) -> Self {
+            let mut accounts = Self::default();
+            let names = (1..=count)
+                .map(|index| format!("{prefix}{index:02}"))
+                .collect::<Vec<_>>();
+            for (index, name) in (1..).zip(&names) {
+                accounts.users.insert(name.clone(), (70_000 + index, gid));
+            }
+            accounts.groups.insert(group.into(), (gid, names));
+            accounts
+        }
+    }
+
+    impl UserBackend for FakeAccounts {
+        fn user_exists(&self, name: &str) -> bool {
+            self.users.contains_key(name)
+        }
+
+        fn group_exists(&self, name: &str) -> bool {
+            self.groups.contains_key(name)
+        }
+
+        fn group_gid(&self, name: &str) -> Option<u32> {
+            self.groups.get(name).map(|(gid, _)| *gid)
+        }
+
+        fn group_name(&self, gid: u32) -> Option<String> {
+            self.groups
+                .iter()
+                .find(|(_, (existing, _))| *existing == gid)
+                .map(|(name, _)| name.clone())
+        }
+
+        fn user_ids(&self, name: &str) -> Option<(u32, u32)> {
+            self.users.get(name).copied()
+        }
+
+        fn user_name(&self, uid: u32) -> Option<String> {
+            self.users
+                .iter()
+                .find(|(_, (existing, _))| *existing == uid)
+                .map(|(name, _)| name.clone())
+        }
+
+        fn group_members(&self, name: &str) -> Vec<String> {
+            self.groups
+                .get(name)
+                .map(|(_, members)| members.clone())
+                .unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn adopts_provisioned_build_users_by_prefix() {
+        let accounts = FakeAccounts::provisioned("svc_nixbld", 32, "svc_nix", 5000);
+
+        // The configured group doesn't exist, the one the users share is found instead
+        let provisioned = ProvisionedBuildUsers::find(&accounts, "svc_nixbld", 32, "nixbld")
+            .expect("The users are provisioned");
+        assert_eq!(provisioned.group_name, "svc_nix");
+        assert_eq!(provisioned.gid, 5000);
+        assert_eq!(provisioned.users.len(), 32);
+        assert_eq!(provisioned.users[0], ("svc_nixbld01".to_string(), 70_001));
+        assert_eq!(provisioned.users[31], ("svc_nixbld32".to_string(), 70_032));
+
+        // Fewer than provisioned are fine, more are not
+        assert!(ProvisionedBuildUsers::find(&accounts, "svc_nixbld", 8, "nixbld").is_ok());
+        match ProvisionedBuildUsers::find(&accounts, "svc_nixbld", 34, "nixbld") {
+            Err(ActionErrorKind::MissingBuildUsers(prefix, missing)) => {
+                assert_eq!(prefix, "svc_nixbld");
+                assert_eq!(missing, ["svc_nixbld33", "svc_nixbld34"]);
+            },
+            other => panic!("Expected missing build users, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn needs_a_group_listing_every_provisioned_user() {
+        let mut accounts = FakeAccounts::provisioned("svc_nixbld", 4, "svc_nix", 5000);
+        // A configured group which lists them is preferred over their primary group
+        accounts.groups.insert(
+            "nixbld".into(),
+            (
+                30_000,
+                (1..=4).map(|index| format!("svc_nixbld0{index}")).collect(),
+            ),
+        );
+        let provisioned =
+            ProvisionedBuildUsers::find(&accounts, "svc_nixbld", 4, "nixbld").unwrap();
+        assert_eq!(
+            (provisioned.group_name.as_str(), provisioned.gid),
+            ("nixbld", 30_000)
+        );
+
+        accounts.groups.get_mut("nixbld").unwrap().1.pop();
+        accounts.groups.get_mut("svc_nix").unwrap().1.remove(0);
+        match ProvisionedBuildUsers::find(&accounts, "svc_nixbld", 4, "nixbld") {
+            Err(ActionErrorKind::NoSharedBuildGroup(tried)) => {
+                assert_eq!(tried, ["nixbld", "svc_nix"])
+            },
+            other => panic!("Expected no shared group, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn creating_build_users_refuses_other_accounts() {
+        let mut accounts = FakeAccounts::default();
+        assert!(check_unclaimed(&accounts, "svc_nixbld", 32, 30_000).is_ok());
+
+        // Left by an earlier install, so adopted
+        accounts
+            .users
+            .insert("svc_nixbld1".into(), (30_001, 30_000));
+        assert!(check_unclaimed(&accounts, "svc_nixbld", 32, 30_000).is_ok());
+
+        accounts.users.insert("svc_nixbld2".into(), (1234, 100));
+        accounts.users.insert("alice".into(), (30_003, 100));
+        match check_unclaimed(&accounts, "svc_nixbld", 32, 30_000) {
+            Err(ActionErrorKind::BuildUserCollisions(collisions)) => assert_eq!(
+                collisions,
+                [
+                    "`svc_nixbld2` exists with UID 1234 instead of 30002",
+                    "UID 30003 planned for `svc_nixbld3` belongs to `alice`",
+                ]
+            ),
+            other => panic!("Expected collisions, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn provisioned_accounts_are_left_in_place() {
+        let create_group = CreateGroup::provisioned("svc_nix".into(), 5000);
+        assert_eq!(create_group.state, ActionState::Skipped);
+        let create_user = CreateUser::provisioned(
+            "svc_nixbld01".into(),
+            70_001,
+            "svc_nix".into(),
+            5000,
+            "Nix build user 1".into(),
+        );
+        assert_eq!(create_user.state, ActionState::Skipped);
+    }
+}
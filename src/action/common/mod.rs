@@ -2,18 +2,26 @@
 
 pub(crate) mod configure_init_service;
 pub(crate) mod configure_nix;
+pub(crate) mod configure_remote_builders;
 pub(crate) mod configure_shell_profile;
+pub(crate) mod configure_user_nix;
 pub(crate) mod create_nix_tree;
 pub(crate) mod create_users_and_groups;
 pub(crate) mod delete_users;
+pub(crate) mod place_motd;
 pub(crate) mod place_nix_configuration;
 pub(crate) mod provision_nix;
 
-pub use configure_init_service::{ConfigureInitService, ConfigureNixDaemonServiceError};
+pub use configure_init_service::{
+    ConfigureInitService, ConfigureNixDaemonServiceError, DaemonLimits,
+};
 pub use configure_nix::ConfigureNix;
+pub use configure_remote_builders::{ConfigureRemoteBuilders, NixMachine, NixMachineError};
 pub use configure_shell_profile::ConfigureShellProfile;
+pub use configure_user_nix::ConfigureUserNix;
 pub use create_nix_tree::CreateNixTree;
 pub use create_users_and_groups::CreateUsersAndGroups;
 pub use delete_users::DeleteUsersInGroup;
+pub use place_motd::PlaceMotd;
 pub use place_nix_configuration::PlaceNixConfiguration;
 pub use provision_nix::ProvisionNix;
@@ -0,0 +1,284 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use tracing::{span, Span};
+
+use crate::action::{
+    base::{create_or_insert_into_file::Position, CreateFile, CreateOrInsertIntoFile},
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+const MOTD_DIR: &str = "etc/motd.d";
+const MOTD_DIR_FILE_NAME: &str = "nix";
+const MOTD: &str = "etc/motd";
+/// Present where `update-motd` runs scripts to build the message of the day
+const UPDATE_MOTD_DIR: &str = "etc/update-motd.d";
+const NIX_BIN: &str = "/nix/var/nix/profiles/default/bin/nix";
+const NIX_MANUAL_URL: &str = "https://nixos.org/manual/nix/stable/";
+
+/// Where the notice goes on a system with its root at `root`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MotdLayout {
+    /// A file of its own in `/etc/motd.d`, which `pam_motd` shows after `/etc/motd`
+    Directory(PathBuf),
+    /// A block appended to `/etc/motd`
+    Append(PathBuf),
+}
+
+impl MotdLayout {
+    pub(crate) fn detect(root: &Path) -> Self {
+        let motd_dir = root.join(MOTD_DIR);
+        if root.join(UPDATE_MOTD_DIR).is_dir() {
+            tracing::debug!("Using a static notice, not an `update-motd` script");
+        }
+        if motd_dir.is_dir() {
+            Self::Directory(motd_dir.join(MOTD_DIR_FILE_NAME))
+        } else {
+            Self::Append(root.join(MOTD))
+        }
+    }
+}
+
+/**
+Tell every user logging in that Nix is installed, in the message of the day
+
+The notice is a file of its own in `/etc/motd.d` where that is read, otherwise a block appended to
+`/etc/motd`. Either is removed exactly on uninstall.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct PlaceMotd {
+    create_file: Option<StatefulAction<CreateFile>>,
+    create_or_insert_into_file: Option<StatefulAction<CreateOrInsertIntoFile>>,
+}
+
+impl PlaceMotd {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        root: &Path,
+        nix_conf_dir: &Path,
+        force: bool,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        let notice = render_motd(nix_conf_dir);
+        let (create_file, create_or_insert_into_file) = match MotdLayout::detect(root) {
+            MotdLayout::Directory(path) => (
+                Some(
+                    CreateFile::plan(path, None, None, 0o0644, notice, force)
+                        .await
+                        .map_err(Self::error)?,
+                ),
+                None,
+            ),
+            MotdLayout::Append(path) => {
+                // An existing message of the day keeps its mode
+                let mode = path
+                    .metadata()
+                    .map(|metadata| metadata.mode() & 0o7777)
+                    .unwrap_or(0o0644);
+                let block = format!("\n--- Nix ---\n{notice}--- End Nix ---\n");
+                (
+                    None,
+                    Some(
+                        CreateOrInsertIntoFile::plan(path, None, None, mode, block, Position::End)
+                            .await
+                            .map_err(Self::error)?,
+                    ),
+                )
+            },
+        };
+
+        Ok(Self {
+            create_file,
+            create_or_insert_into_file,
+        }
+        .into())
+    }
+
+    fn path(&self) -> &Path {
+        match (&self.create_file, &self.create_or_insert_into_file) {
+            (Some(create_file), _) => &create_file.inner().path,
+            (None, Some(create_or_insert_into_file)) => create_or_insert_into_file.inner().path(),
+            (None, None) => Path::new(MOTD),
+        }
+    }
+}
+
+/// The notice, pointing at the same `nix` and configuration as the shell profile hooks
+fn render_motd(nix_conf_dir: &Path) -> String {
+    format!(
+        "Nix is installed, run `nix --help` in a new shell to get started.\n\
+        {inde}Command:       {NIX_BIN}\n\
+        {inde}Configuration: {nix_conf}\n\
+        {inde}Documentation: {NIX_MANUAL_URL}\n",
+        nix_conf = nix_conf_dir.join("nix.conf").display(),
+        inde = "  ",
+    )
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "place_motd")]
+impl Action for PlaceMotd {
+    fn action_tag() -> ActionTag {
+        ActionTag("place_motd")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!(
+            "Tell users logging in that Nix is installed, in `{}`",
+            self.path().display()
+        )
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "place_motd",
+            path = tracing::field::display(self.path().display()),
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(self.tracing_synopsis(), vec![])]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        if let Some(create_file) = &mut self.create_file {
+            create_file.try_execute().await.map_err(Self::error)?;
+        }
+        if let Some(create_or_insert_into_file) = &mut self.create_or_insert_into_file {
+            create_or_insert_into_file
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Remove the notice that Nix is installed from `{}`",
+                self.path().display()
+            ),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        if let Some(create_file) = &mut self.create_file {
+            if let Err(err) = create_file.try_revert().await {
+                errors.push(err);
+            }
+        }
+        if let Some(create_or_insert_into_file) = &mut self.create_or_insert_into_file {
+            if let Err(err) = create_or_insert_into_file.try_revert().await {
+                errors.push(err);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_layout() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc"))?;
+        assert_eq!(
+            MotdLayout::detect(root.path()),
+            MotdLayout::Append(root.path().join("etc/motd"))
+        );
+
+        // Still a static notice where `update-motd` runs scripts
+        std::fs::create_dir_all(root.path().join(UPDATE_MOTD_DIR))?;
+        assert_eq!(
+            MotdLayout::detect(root.path()),
+            MotdLayout::Append(root.path().join("etc/motd"))
+        );
+
+        std::fs::create_dir_all(root.path().join(MOTD_DIR))?;
+        assert_eq!(
+            MotdLayout::detect(root.path()),
+            MotdLayout::Directory(root.path().join("etc/motd.d/nix"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn places_and_removes_motd_d_file() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let motd_dir = root.path().join(MOTD_DIR);
+        std::fs::create_dir_all(&motd_dir)?;
+        std::fs::write(motd_dir.join("welcome"), "Welcome\n")?;
+
+        let mut action = PlaceMotd::plan(root.path(), Path::new("/etc/nix"), false).await?;
+        action.try_execute().await?;
+        let notice = std::fs::read_to_string(motd_dir.join("nix"))?;
+        assert!(notice.contains(NIX_BIN), "{notice}");
+        assert!(notice.contains("/etc/nix/nix.conf"), "{notice}");
+        assert!(!root.path().join(MOTD).exists());
+
+        action.try_revert().await?;
+        assert!(!motd_dir.join("nix").exists());
+        assert_eq!(
+            std::fs::read_to_string(motd_dir.join("welcome"))?,
+            "Welcome\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn appends_and_removes_motd_block() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let motd = root.path().join(MOTD);
+        std::fs::create_dir_all(motd.parent().unwrap())?;
+        let existing = "Authorized use only.\n";
+        std::fs::write(&motd, existing)?;
+
+        let mut action = PlaceMotd::plan(root.path(), Path::new("/srv/nix-conf"), false).await?;
+        action.try_execute().await?;
+        let contents = std::fs::read_to_string(&motd)?;
+        assert!(contents.starts_with(existing), "{contents}");
+        assert!(contents.contains("\n--- Nix ---\n"), "{contents}");
+        assert!(contents.ends_with("--- End Nix ---\n"), "{contents}");
+        assert!(contents.contains("/srv/nix-conf/nix.conf"), "{contents}");
+
+        // Edited by an admin since
+        std::fs::write(&motd, format!("{contents}Maintenance on Sunday.\n"))?;
+        action.try_revert().await?;
+        assert_eq!(
+            std::fs::read_to_string(&motd)?,
+            format!("{existing}Maintenance on Sunday.\n")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_created_motd() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc"))?;
+
+        let mut action = PlaceMotd::plan(root.path(), Path::new("/etc/nix"), false).await?;
+        action.try_execute().await?;
+        assert!(root.path().join(MOTD).exists());
+        action.try_revert().await?;
+        assert!(!root.path().join(MOTD).exists());
+        Ok(())
+    }
+}
@@ -1,59 +1,67 @@
 use tracing::{span, Span};
-use url::Url;
 
 use crate::action::base::create_or_merge_nix_config::CreateOrMergeNixConfigError;
-use crate::action::base::{CreateDirectory, CreateOrMergeNixConfig};
+use crate::action::base::{CreateDirectory, CreateOrMergeNixConfig, NIX_CA_BUNDLE_NAME};
+use crate::action::common::configure_remote_builders::NIX_MACHINES_NAME;
+use crate::action::common::ConfigureRemoteBuilders;
 use crate::action::{
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
-use crate::parse_ssl_cert;
-use crate::settings::UrlOrPathOrString;
+use crate::os::hardlinks::{self, HardlinkProbe};
+use crate::settings::{CommonSettings, UrlOrPathOrString};
+use crate::{check_pem_bundle, http};
 use indexmap::map::Entry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-const NIX_CONF_FOLDER: &str = "/etc/nix";
-const NIX_CONF: &str = "/etc/nix/nix.conf";
+const NIX_CONF_NAME: &str = "nix.conf";
+const AUTO_OPTIMISE_STORE: &str = "auto-optimise-store";
+/// How long fetching an `--extra-conf` URL may take
+const EXTRA_CONF_TIMEOUT: Duration = Duration::from_secs(60);
 
 /**
-Place the `/etc/nix.conf` file
+Place the `/etc/nix/nix.conf` file, or `nix.conf` in the directory passed with `--nix-conf-dir`
  */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct PlaceNixConfiguration {
     create_directory: StatefulAction<CreateDirectory>,
     create_or_merge_nix_config: StatefulAction<CreateOrMergeNixConfig>,
+    #[serde(default)]
+    configure_remote_builders: Option<StatefulAction<ConfigureRemoteBuilders>>,
+    /// How many hardlinks the filesystem of `/nix` allowed, probed when `auto-optimise-store` was to be set
+    #[serde(default)]
+    hardlink_probe: Option<HardlinkProbe>,
 }
 
 impl PlaceNixConfiguration {
     #[tracing::instrument(level = "debug", skip_all)]
-    pub async fn plan(
-        nix_build_group_name: String,
-        proxy: Option<Url>,
-        ssl_cert_file: Option<PathBuf>,
-        extra_conf: Vec<UrlOrPathOrString>,
-        force: bool,
-    ) -> Result<StatefulAction<Self>, ActionError> {
+    pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
+        let nix_conf_dir = settings.conf_dir();
+        let CommonSettings {
+            nix_build_group_name,
+            proxy,
+            ssl_cert_file,
+            append_corp_ca,
+            extra_conf,
+            builders,
+            force,
+            #[cfg(target_os = "linux")]
+            relax_unsupported_settings,
+            ..
+        } = settings;
+
         let mut extra_conf_text = vec![];
         for extra in extra_conf {
             let buf = match &extra {
                 UrlOrPathOrString::Url(url) => match url.scheme() {
                     "https" | "http" => {
-                        let mut buildable_client = reqwest::Client::builder();
-                        if let Some(proxy) = &proxy {
-                            buildable_client = buildable_client.proxy(
-                                reqwest::Proxy::all(proxy.clone())
-                                    .map_err(ActionErrorKind::Reqwest)
-                                    .map_err(Self::error)?,
-                            )
-                        }
-                        if let Some(ssl_cert_file) = &ssl_cert_file {
-                            let ssl_cert =
-                                parse_ssl_cert(ssl_cert_file).await.map_err(Self::error)?;
-                            buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                        }
-                        let client = buildable_client
-                            .build()
-                            .map_err(ActionErrorKind::Reqwest)
-                            .map_err(Self::error)?;
+                        let client = http::client(
+                            proxy.as_ref(),
+                            ssl_cert_file.as_deref(),
+                            EXTRA_CONF_TIMEOUT,
+                        )
+                        .await
+                        .map_err(Self::error)?;
                         let req = client
                             .get(url.clone())
                             .build()
@@ -89,8 +97,13 @@ impl PlaceNixConfiguration {
             .map_err(CreateOrMergeNixConfigError::ParseNixConfig)
             .map_err(Self::error)?;
         let settings = nix_config.settings_mut();
+        let explicit_auto_optimise_store =
+            settings.get(AUTO_OPTIMISE_STORE).map(String::as_str) == Some("true");
 
-        settings.insert("build-users-group".to_string(), nix_build_group_name);
+        settings.insert(
+            "build-users-group".to_string(),
+            nix_build_group_name.clone(),
+        );
         let experimental_features = ["nix-command", "flakes", "repl-flake"];
         match settings.entry("experimental-features".to_string()) {
             Entry::Occupied(mut slot) => {
@@ -109,7 +122,7 @@ impl PlaceNixConfiguration {
 
         // https://github.com/DeterminateSystems/nix-installer/issues/449#issuecomment-1551782281
         #[cfg(not(target_os = "macos"))]
-        settings.insert("auto-optimise-store".to_string(), "true".to_string());
+        settings.insert(AUTO_OPTIMISE_STORE.to_string(), "true".to_string());
 
         settings.insert(
             "bash-prompt-prefix".to_string(),
@@ -117,9 +130,15 @@ impl PlaceNixConfiguration {
         );
         settings.insert("max-jobs".to_string(), "auto".to_string());
         if let Some(ssl_cert_file) = ssl_cert_file {
-            let ssl_cert_file_canonical = ssl_cert_file
-                .canonicalize()
-                .map_err(|e| Self::error(ActionErrorKind::Canonicalize(ssl_cert_file, e)))?;
+            check_pem_bundle(ssl_cert_file).await.map_err(Self::error)?;
+            let ssl_cert_file_canonical = if *append_corp_ca {
+                // Created from `ssl_cert_file` once the default profile is set up
+                nix_conf_dir.join(NIX_CA_BUNDLE_NAME)
+            } else {
+                ssl_cert_file.canonicalize().map_err(|e| {
+                    Self::error(ActionErrorKind::Canonicalize(ssl_cert_file.clone(), e))
+                })?
+            };
             settings.insert(
                 "ssl-cert-file".to_string(),
                 ssl_cert_file_canonical.display().to_string(),
@@ -130,15 +149,78 @@ impl PlaceNixConfiguration {
             "nixpkgs=flake:nixpkgs".to_string(),
         );
 
-        let create_directory = CreateDirectory::plan(NIX_CONF_FOLDER, None, None, 0o0755, force)
-            .await
-            .map_err(Self::error)?;
-        let create_or_merge_nix_config = CreateOrMergeNixConfig::plan(NIX_CONF, nix_config)
+        #[cfg(target_os = "linux")]
+        {
+            use crate::os::kernel_features;
+
+            let unsupported = kernel_features::unsupported(settings, |feature| feature.probe());
+            if !unsupported.is_empty() {
+                let reasons = unsupported
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                if !*relax_unsupported_settings {
+                    return Err(Self::error(ActionErrorKind::UnsupportedSettings(reasons)));
+                }
+                tracing::warn!(
+                    "Turning off settings the kernel lacks features for: {}",
+                    reasons.join("; ")
+                );
+                kernel_features::relax(settings, &unsupported);
+            }
+        }
+
+        // Optimising the store hardlinks identical files, which some filesystems allow few of
+        let hardlink_probe = match settings.get(AUTO_OPTIMISE_STORE).map(String::as_str) {
+            Some("true") => match hardlinks::probe(
+                &hardlinks::probe_dir(Path::new("/nix")),
+                hardlinks::PROBE_LINKS,
+            ) {
+                Ok(probe) => Some(probe),
+                Err(err) => {
+                    tracing::debug!(%err, "Could not probe how many hardlinks `/nix` allows");
+                    None
+                },
+            },
+            _ => None,
+        };
+        if let Some(probe) = hardlink_probe.filter(HardlinkProbe::too_low) {
+            if explicit_auto_optimise_store {
+                tracing::warn!(
+                    "Setting `{AUTO_OPTIMISE_STORE}` as passed, but {probe}: builds may fail once optimising the store runs out of links"
+                );
+            } else {
+                tracing::warn!("Not setting `{AUTO_OPTIMISE_STORE}`, {probe}");
+                settings.shift_remove(AUTO_OPTIMISE_STORE);
+            }
+        }
+
+        let configure_remote_builders = if builders.is_empty() {
+            None
+        } else {
+            settings.insert(
+                "builders".to_string(),
+                format!("@{}", nix_conf_dir.join(NIX_MACHINES_NAME).display()),
+            );
+            Some(
+                ConfigureRemoteBuilders::plan(builders.clone(), &nix_conf_dir, *force)
+                    .await
+                    .map_err(Self::error)?,
+            )
+        };
+
+        let create_directory = CreateDirectory::plan(&nix_conf_dir, None, None, 0o0755, *force)
             .await
             .map_err(Self::error)?;
+        let create_or_merge_nix_config =
+            CreateOrMergeNixConfig::plan(nix_conf_dir.join(NIX_CONF_NAME), nix_config)
+                .await
+                .map_err(Self::error)?;
         Ok(Self {
             create_directory,
             create_or_merge_nix_config,
+            configure_remote_builders,
+            hardlink_probe,
         }
         .into())
     }
@@ -151,7 +233,10 @@ impl Action for PlaceNixConfiguration {
         ActionTag("place_nix_configuration")
     }
     fn tracing_synopsis(&self) -> String {
-        format!("Place the Nix configuration in `{NIX_CONF}`")
+        format!(
+            "Place the Nix configuration in `{}`",
+            self.create_or_merge_nix_config.action.path.display()
+        )
     }
 
     fn tracing_span(&self) -> Span {
@@ -162,6 +247,8 @@ impl Action for PlaceNixConfiguration {
         let Self {
             create_or_merge_nix_config,
             create_directory,
+            configure_remote_builders,
+            hardlink_probe: _,
         } = self;
 
         let mut explanation = vec![
@@ -175,6 +262,11 @@ impl Action for PlaceNixConfiguration {
         for val in create_or_merge_nix_config.describe_execute().iter() {
             explanation.push(val.description.clone())
         }
+        if let Some(configure_remote_builders) = configure_remote_builders {
+            for val in configure_remote_builders.describe_execute().iter() {
+                explanation.push(val.description.clone())
+            }
+        }
 
         vec![ActionDescription::new(self.tracing_synopsis(), explanation)]
     }
@@ -189,13 +281,22 @@ impl Action for PlaceNixConfiguration {
             .try_execute()
             .await
             .map_err(Self::error)?;
+        if let Some(configure_remote_builders) = &mut self.configure_remote_builders {
+            configure_remote_builders
+                .try_execute()
+                .await
+                .map_err(Self::error)?;
+        }
 
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
         vec![ActionDescription::new(
-            format!("Remove the Nix configuration in `{NIX_CONF}`"),
+            format!(
+                "Remove the Nix configuration in `{}`",
+                self.create_or_merge_nix_config.action.path.display()
+            ),
             vec![
                 "This file is read by the Nix daemon to set its configuration options at runtime."
                     .to_string(),
@@ -206,6 +307,11 @@ impl Action for PlaceNixConfiguration {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let mut errors = vec![];
+        if let Some(configure_remote_builders) = &mut self.configure_remote_builders {
+            if let Err(err) = configure_remote_builders.try_revert().await {
+                errors.push(err);
+            }
+        }
         if let Err(err) = self.create_or_merge_nix_config.try_revert().await {
             errors.push(err);
         }
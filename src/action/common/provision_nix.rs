@@ -4,9 +4,11 @@ use super::CreateNixTree;
 use crate::{
     action::{
         base::{FetchAndUnpackNix, MoveUnpackedNix},
-        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag,
+        StatefulAction,
     },
     settings::{CommonSettings, SCRATCH_DIR},
+    temp_artifacts,
 };
 use std::path::PathBuf;
 
@@ -23,13 +25,26 @@ pub struct ProvisionNix {
 impl ProvisionNix {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(settings: &CommonSettings) -> Result<StatefulAction<Self>, ActionError> {
-        let fetch_nix = FetchAndUnpackNix::plan(
-            settings.nix_package_url.clone(),
+        let mut fetch_nix = FetchAndUnpackNix::plan(
+            settings.nix_package(),
             PathBuf::from(SCRATCH_DIR),
             settings.proxy.clone(),
             settings.ssl_cert_file.clone(),
+            settings.preferred_ip_family(),
+            settings.max_buffer_size,
+            settings.download_connections,
         )
         .await?;
+        fetch_nix
+            .action
+            .set_sha256(settings.nix_package_sha256.clone())?;
+        fetch_nix
+            .action
+            .set_wait_for_clock_sync(settings.wait_for_clock_sync);
+        fetch_nix
+            .action
+            .set_download_attempts(settings.download_attempts);
+        fetch_nix.action.estimate_size().await;
 
         let create_nix_tree = CreateNixTree::plan().await.map_err(Self::error)?;
         let move_unpacked_nix = MoveUnpackedNix::plan(PathBuf::from(SCRATCH_DIR))
@@ -76,12 +91,19 @@ impl Action for ProvisionNix {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        // The unpacked tarball is removed if a previous attempt stopped early, so fetch and move it again
+        if self.fetch_nix.state == ActionState::Completed && !self.fetch_nix.inner().dest().exists()
+        {
+            self.fetch_nix.state = ActionState::Uncompleted;
+            self.move_unpacked_nix.state = ActionState::Uncompleted;
+        }
+
         // We fetch nix while doing the rest, then move it over.
         let mut fetch_nix_clone = self.fetch_nix.clone();
-        let fetch_nix_handle = tokio::task::spawn(async {
+        let fetch_nix_handle = tokio::task::spawn(temp_artifacts::propagate(async {
             fetch_nix_clone.try_execute().await.map_err(Self::error)?;
             Result::<_, ActionError>::Ok(fetch_nix_clone)
-        });
+        }));
 
         self.create_nix_tree
             .try_execute()
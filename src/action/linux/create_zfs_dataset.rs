@@ -0,0 +1,256 @@
+use indexmap::IndexMap;
+use tracing::{span, Span};
+
+use crate::action::base::CreateFile;
+use crate::action::linux::{StartSystemdUnit, SystemctlDaemonReload};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+use crate::execute_command;
+use crate::os::tools;
+
+pub(crate) const NIX_MOUNT_UNIT: &str = "/etc/systemd/system/nix.mount";
+
+/**
+Create a ZFS dataset for `/nix` with a legacy mountpoint, and a `nix.mount` unit ordered before the Nix daemon
+
+Keeping the store in its own dataset, outside of `ROOT` on `zsys` systems, keeps it out of root filesystem
+snapshots. The dataset name and properties are kept so uninstalling destroys exactly what was created.
+ */
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct CreateZfsDataset {
+    dataset: String,
+    properties: IndexMap<String, String>,
+    create_mount_unit: StatefulAction<CreateFile>,
+    systemctl_daemon_reload: StatefulAction<SystemctlDaemonReload>,
+    start_nix_mount: StatefulAction<StartSystemdUnit>,
+}
+
+impl CreateZfsDataset {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(
+        dataset: String,
+        properties: IndexMap<String, String>,
+    ) -> Result<StatefulAction<Self>, ActionError> {
+        if tools::find("zfs").is_none() {
+            return Err(Self::error(CreateZfsDatasetError::NoZfs));
+        }
+        if !dataset.contains('/') {
+            return Err(Self::error(CreateZfsDatasetError::InvalidDataset(dataset)));
+        }
+        for (key, value) in &properties {
+            if key.is_empty() || value.is_empty() || key == "mountpoint" {
+                return Err(Self::error(CreateZfsDatasetError::InvalidProperty(
+                    format!("{key}={value}"),
+                )));
+            }
+        }
+
+        let exists = crate::command_runner::output(
+            tools::command("zfs")
+                .args(["list", "-H", "-o", "name", &dataset])
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null()),
+        )
+        .await
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+        if exists {
+            return Err(Self::error(CreateZfsDatasetError::DatasetExists(dataset)));
+        }
+
+        let create_mount_unit = CreateFile::plan(
+            NIX_MOUNT_UNIT,
+            None,
+            None,
+            0o0644,
+            mount_unit(&dataset),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+        let systemctl_daemon_reload = SystemctlDaemonReload::plan().await.map_err(Self::error)?;
+        let start_nix_mount = StartSystemdUnit::plan("nix.mount", true)
+            .await
+            .map_err(Self::error)?;
+
+        Ok(Self {
+            dataset,
+            properties,
+            create_mount_unit,
+            systemctl_daemon_reload,
+            start_nix_mount,
+        }
+        .into())
+    }
+}
+
+/// A mount unit for a dataset with `mountpoint=legacy`, which ZFS will not mount on its own
+fn mount_unit(dataset: &str) -> String {
+    format!(
+        "\
+        [Unit]\n\
+        Description=Mount the `{dataset}` ZFS dataset on `/nix`\n\
+        DefaultDependencies=no\n\
+        Wants=zfs-import.target\n\
+        After=zfs-import.target\n\
+        Before=local-fs.target nix-daemon.service nix-daemon.socket\n\
+        \n\
+        [Mount]\n\
+        What={dataset}\n\
+        Where=/nix\n\
+        Type=zfs\n\
+        \n\
+        [Install]\n\
+        WantedBy=local-fs.target\n\
+        RequiredBy=nix-daemon.service\n\
+        RequiredBy=nix-daemon.socket\n\
+        "
+    )
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "create_zfs_dataset")]
+impl Action for CreateZfsDataset {
+    fn action_tag() -> ActionTag {
+        ActionTag("create_zfs_dataset")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Create a ZFS dataset `{}` for `/nix`", self.dataset)
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "create_zfs_dataset",
+            dataset = %self.dataset,
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        let mut explanation = vec![format!(
+            "Create `{}` with `mountpoint=legacy` and properties:",
+            self.dataset
+        )];
+        explanation.extend(
+            self.properties
+                .iter()
+                .map(|(key, value)| format!("  * `{key}={value}`")),
+        );
+        explanation.push(format!(
+            "Mount it on `/nix` with `{NIX_MOUNT_UNIT}`, ordered before `nix-daemon.service`"
+        ));
+        let properties = std::iter::once("mountpoint=legacy".to_string())
+            .chain(
+                self.properties
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}")),
+            )
+            .collect::<Vec<_>>()
+            .join(", ");
+        vec![ActionDescription::new(
+            format!("{} ({properties})", self.tracing_synopsis()),
+            explanation,
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        let mut command = tools::command("zfs");
+        command.process_group(0);
+        command.arg("create");
+        command.args(["-o", "mountpoint=legacy"]);
+        for (key, value) in &self.properties {
+            command.arg("-o").arg(format!("{key}={value}"));
+        }
+        command.arg(&self.dataset);
+        command.stdin(std::process::Stdio::null());
+        execute_command(&mut command).await.map_err(Self::error)?;
+
+        self.create_mount_unit
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+        self.systemctl_daemon_reload
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+        self.start_nix_mount
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!(
+                "Unmount and destroy the ZFS dataset `{}`, removing `{NIX_MOUNT_UNIT}`",
+                self.dataset
+            ),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+
+        if let Err(err) = self.start_nix_mount.try_revert().await {
+            errors.push(err);
+        }
+        if let Err(err) = self.create_mount_unit.try_revert().await {
+            errors.push(err);
+        }
+        if let Err(err) = self.systemctl_daemon_reload.try_revert().await {
+            errors.push(err);
+        }
+        // Only the dataset that was created, never recursively
+        if let Err(err) = execute_command(
+            tools::command("zfs")
+                .process_group(0)
+                .arg("destroy")
+                .arg(&self.dataset)
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        .map_err(Self::error)
+        {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum CreateZfsDatasetError {
+    #[error("The `zfs` command is required to create a ZFS dataset for `/nix`")]
+    NoZfs,
+    #[error("`{0}` is not a dataset name like `rpool/nix`")]
+    InvalidDataset(String),
+    #[error(
+        "ZFS property `{0}` is not valid, expected `KEY=VALUE` (the mountpoint is always `legacy`)"
+    )]
+    InvalidProperty(String),
+    #[error("The ZFS dataset `{0}` already exists, pass a different `--zfs-dataset` or `--no-zfs-dataset` to use `/nix` as it is")]
+    DatasetExists(String),
+}
+
+impl From<CreateZfsDatasetError> for ActionErrorKind {
+    fn from(val: CreateZfsDatasetError) -> Self {
+        ActionErrorKind::Custom(Box::new(val))
+    }
+}
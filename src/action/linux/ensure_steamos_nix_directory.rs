@@ -1,11 +1,11 @@
 use std::path::{Path, PathBuf};
 
 use tokio::fs::create_dir;
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -22,7 +22,7 @@ pub struct EnsureSteamosNixDirectory;
 impl EnsureSteamosNixDirectory {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
-        if which::which("steamos-readonly").is_err() {
+        if tools::find("steamos-readonly").is_none() {
             return Err(Self::error(ActionErrorKind::MissingSteamosBinary(
                 "steamos-readonly".into(),
             )));
@@ -63,7 +63,7 @@ impl Action for EnsureSteamosNixDirectory {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("steamos-readonly")
+            tools::command("steamos-readonly")
                 .process_group(0)
                 .arg("disable")
                 .stdin(std::process::Stdio::null()),
@@ -78,7 +78,7 @@ impl Action for EnsureSteamosNixDirectory {
             .map_err(Self::error)?;
 
         execute_command(
-            Command::new("steamos-readonly")
+            tools::command("steamos-readonly")
                 .process_group(0)
                 .arg("enable")
                 .stdin(std::process::Stdio::null()),
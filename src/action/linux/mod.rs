@@ -1,11 +1,15 @@
+pub(crate) mod create_zfs_dataset;
 pub(crate) mod ensure_steamos_nix_directory;
 pub(crate) mod provision_selinux;
+pub(crate) mod raise_limits;
 pub(crate) mod revert_clean_steamos_nix_offload;
 pub(crate) mod start_systemd_unit;
 pub(crate) mod systemctl_daemon_reload;
 
+pub use create_zfs_dataset::{CreateZfsDataset, CreateZfsDatasetError};
 pub use ensure_steamos_nix_directory::EnsureSteamosNixDirectory;
 pub use provision_selinux::ProvisionSelinux;
+pub use raise_limits::{RaiseLimits, RaisedSysctl};
 pub use revert_clean_steamos_nix_offload::RevertCleanSteamosNixOffload;
 pub use start_systemd_unit::{StartSystemdUnit, StartSystemdUnitError};
 pub use systemctl_daemon_reload::SystemctlDaemonReload;
@@ -1,11 +1,11 @@
 use std::path::{Path, PathBuf};
 
 use tokio::fs::{create_dir_all, remove_file};
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -22,6 +22,7 @@ pub struct ProvisionSelinux {
 impl ProvisionSelinux {
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn plan(policy_path: PathBuf) -> Result<StatefulAction<Self>, ActionError> {
+        crate::path_policy::check(&policy_path).map_err(Self::error)?;
         let this = Self { policy_path };
 
         // Note: `restorecon` requires us to not just skip this, even if everything is in place.
@@ -59,6 +60,7 @@ impl Action for ProvisionSelinux {
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
+        crate::path_policy::check(&self.policy_path).map_err(Self::error)?;
         if self.policy_path.exists() {
             // Rebuild it.
             remove_existing_policy(&self.policy_path)
@@ -79,14 +81,14 @@ impl Action for ProvisionSelinux {
             .map_err(Self::error)?;
 
         execute_command(
-            Command::new("semodule")
+            tools::command("semodule")
                 .arg("--install")
                 .arg(&self.policy_path),
         )
         .await
         .map_err(Self::error)?;
 
-        execute_command(Command::new("restorecon").args(["-FR", "/nix"]))
+        execute_command(tools::command("restorecon").args(["-FR", "/nix"]))
             .await
             .map_err(Self::error)?;
 
@@ -113,13 +115,13 @@ impl Action for ProvisionSelinux {
 }
 
 async fn remove_existing_policy(policy_path: &Path) -> Result<(), ActionErrorKind> {
-    execute_command(Command::new("semodule").arg("--remove").arg("nix")).await?;
+    execute_command(tools::command("semodule").arg("--remove").arg("nix")).await?;
 
     remove_file(&policy_path)
         .await
         .map_err(|e| ActionErrorKind::Remove(policy_path.into(), e))?;
 
-    execute_command(Command::new("restorecon").args(["-FR", "/nix"])).await?;
+    execute_command(tools::command("restorecon").args(["-FR", "/nix"])).await?;
 
     Ok(())
 }
@@ -0,0 +1,210 @@
+use std::path::Path;
+
+use tracing::{span, Span};
+
+use crate::{
+    action::{
+        base::CreateFile, Action, ActionDescription, ActionError, ActionErrorKind, ActionTag,
+        StatefulAction,
+    },
+    os::limits::{self, Limit, SYSCTLS, SYSCTL_FRAGMENT},
+};
+
+/// A sysctl raised by [`RaiseLimits`], and what it was before
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RaisedSysctl {
+    pub name: String,
+    pub previous: u64,
+    pub raised_to: u64,
+}
+
+/**
+Raise the open file and inotify sysctls Nix builds need, see [`limits`](crate::os::limits)
+
+They are raised right away and in a `sysctl.d` fragment for later boots, never lowered. On revert the
+fragment is removed and each is set back to what it was, unless it was changed again since.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct RaiseLimits {
+    raised: Vec<RaisedSysctl>,
+    create_file: StatefulAction<CreateFile>,
+}
+
+impl RaiseLimits {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan() -> Result<StatefulAction<Self>, ActionError> {
+        let root = Path::new("/");
+        let to_raise = SYSCTLS
+            .iter()
+            .filter_map(|limit| {
+                let previous = limits::read_sysctl(root, limit.name)?;
+                (previous < limit.raised_to).then_some((*limit, previous))
+            })
+            .collect::<Vec<_>>();
+        let create_file = CreateFile::plan(
+            SYSCTL_FRAGMENT,
+            None,
+            None,
+            0o0644,
+            limits::render_sysctl_fragment(
+                &to_raise
+                    .iter()
+                    .map(|(limit, _)| *limit)
+                    .collect::<Vec<Limit>>(),
+            ),
+            false,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        let action = Self {
+            raised: to_raise
+                .into_iter()
+                .map(|(limit, previous)| RaisedSysctl {
+                    name: limit.name.to_string(),
+                    previous,
+                    raised_to: limit.raised_to,
+                })
+                .collect(),
+            create_file,
+        };
+        if action.raised.is_empty() {
+            tracing::debug!("The open file and inotify limits are already raised");
+            return Ok(StatefulAction::completed(action));
+        }
+        Ok(StatefulAction::uncompleted(action))
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "raise_limits")]
+impl Action for RaiseLimits {
+    fn action_tag() -> ActionTag {
+        ActionTag("raise_limits")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Raise the open file and inotify limits for Nix builds in `{SYSCTL_FRAGMENT}`")
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(
+            tracing::Level::DEBUG,
+            "raise_limits",
+            path = SYSCTL_FRAGMENT
+        )
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            self.raised
+                .iter()
+                .map(|raised| {
+                    format!(
+                        "Raise `{}` from {} to {}",
+                        raised.name, raised.previous, raised.raised_to
+                    )
+                })
+                .collect(),
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_file.try_execute().await.map_err(Self::error)?;
+        apply(Path::new("/"), &self.raised).map_err(Self::error)?;
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Remove `{SYSCTL_FRAGMENT}`, setting the limits it raised back"),
+            vec![],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        self.create_file.try_revert().await.map_err(Self::error)?;
+        restore(Path::new("/"), &self.raised).map_err(Self::error)?;
+        Ok(())
+    }
+}
+
+/// Set each sysctl of `raised` to what it is raised to, on the system with its root at `root`
+fn apply(root: &Path, raised: &[RaisedSysctl]) -> Result<(), ActionErrorKind> {
+    for raised in raised {
+        let path = limits::sysctl_path(root, &raised.name);
+        std::fs::write(&path, raised.raised_to.to_string())
+            .map_err(|e| ActionErrorKind::Write(path, e))?;
+    }
+    Ok(())
+}
+
+/// Set each sysctl of `raised` back to what it was, unless it is no longer what it was raised to
+fn restore(root: &Path, raised: &[RaisedSysctl]) -> Result<(), ActionErrorKind> {
+    for raised in raised {
+        match limits::read_sysctl(root, &raised.name) {
+            Some(current) if current == raised.raised_to => {
+                let path = limits::sysctl_path(root, &raised.name);
+                std::fs::write(&path, raised.previous.to_string())
+                    .map_err(|e| ActionErrorKind::Write(path, e))?;
+            },
+            current => tracing::debug!(
+                "`{}` is {current:?} rather than the {} it was raised to, leaving it",
+                raised.name,
+                raised.raised_to
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn raises_and_restores_sysctls() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        let raised = SYSCTLS[1..]
+            .iter()
+            .map(|limit| RaisedSysctl {
+                name: limit.name.to_string(),
+                previous: 8192,
+                raised_to: limit.raised_to,
+            })
+            .collect::<Vec<_>>();
+        std::fs::create_dir_all(root.join("proc/sys/fs/inotify"))?;
+        for raised in &raised {
+            std::fs::write(limits::sysctl_path(root, &raised.name), "8192\n")?;
+        }
+
+        apply(root, &raised)?;
+        assert_eq!(
+            limits::read_sysctl(root, "fs.inotify.max_user_watches"),
+            Some(524_288)
+        );
+        assert_eq!(
+            limits::read_sysctl(root, "fs.inotify.max_user_instances"),
+            Some(1_024)
+        );
+
+        // Changed again since, so left as it is
+        std::fs::write(
+            limits::sysctl_path(root, "fs.inotify.max_user_instances"),
+            "4096\n",
+        )?;
+        restore(root, &raised)?;
+        assert_eq!(
+            limits::read_sysctl(root, "fs.inotify.max_user_watches"),
+            Some(8192)
+        );
+        assert_eq!(
+            limits::read_sysctl(root, "fs.inotify.max_user_instances"),
+            Some(4096)
+        );
+        Ok(())
+    }
+}
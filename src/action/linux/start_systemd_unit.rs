@@ -1,8 +1,8 @@
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionState, ActionTag, StatefulAction};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription};
 
@@ -22,11 +22,10 @@ impl StartSystemdUnit {
         enable: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let unit = unit.as_ref();
-        let mut command = Command::new("systemctl");
+        let mut command = tools::command("systemctl");
         command.arg("is-active");
         command.arg(unit);
-        let output = command
-            .output()
+        let output = crate::command_runner::output(&mut command)
             .await
             .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
 
@@ -43,6 +42,9 @@ impl StartSystemdUnit {
                 enable,
             },
             state,
+            id: None,
+            timing: None,
+            reverted_at: None,
         })
     }
 }
@@ -77,7 +79,7 @@ impl Action for StartSystemdUnit {
             true => {
                 // TODO(@Hoverbear): Handle proxy vars
                 execute_command(
-                    Command::new("systemctl")
+                    tools::command("systemctl")
                         .process_group(0)
                         .arg("enable")
                         .arg("--now")
@@ -90,7 +92,7 @@ impl Action for StartSystemdUnit {
             false => {
                 // TODO(@Hoverbear): Handle proxy vars
                 execute_command(
-                    Command::new("systemctl")
+                    tools::command("systemctl")
                         .process_group(0)
                         .arg("start")
                         .arg(&unit)
@@ -117,7 +119,7 @@ impl Action for StartSystemdUnit {
 
         if self.enable {
             if let Err(e) = execute_command(
-                Command::new("systemctl")
+                tools::command("systemctl")
                     .process_group(0)
                     .arg("disable")
                     .arg(&self.unit)
@@ -132,7 +134,7 @@ impl Action for StartSystemdUnit {
 
         // We do both to avoid an error doing `disable --now` if the user did stop it already somehow.
         if let Err(e) = execute_command(
-            Command::new("systemctl")
+            tools::command("systemctl")
                 .process_group(0)
                 .arg("stop")
                 .arg(&self.unit)
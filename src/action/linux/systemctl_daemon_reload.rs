@@ -1,10 +1,10 @@
 use std::path::Path;
 
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription, StatefulAction};
 
@@ -21,7 +21,7 @@ impl SystemctlDaemonReload {
             return Err(Self::error(ActionErrorKind::SystemdMissing));
         }
 
-        if which::which("systemctl").is_err() {
+        if tools::find("systemctl").is_none() {
             return Err(Self::error(ActionErrorKind::SystemdMissing));
         }
 
@@ -50,7 +50,7 @@ impl Action for SystemctlDaemonReload {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("systemctl")
+            tools::command("systemctl")
                 .process_group(0)
                 .arg("daemon-reload")
                 .stdin(std::process::Stdio::null()),
@@ -68,7 +68,7 @@ impl Action for SystemctlDaemonReload {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("systemctl")
+            tools::command("systemctl")
                 .process_group(0)
                 .arg("daemon-reload")
                 .stdin(std::process::Stdio::null()),
@@ -1,10 +1,10 @@
 use std::path::{Path, PathBuf};
 
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription};
 
@@ -34,7 +34,7 @@ impl BootstrapLaunchctlService {
         let path = path.as_ref().to_path_buf();
 
         let is_present = {
-            let mut command = Command::new("launchctl");
+            let mut command = tools::command("launchctl");
             command.process_group(0);
             command.arg("print");
             command.arg(format!("{domain}/{service}"));
@@ -42,8 +42,7 @@ impl BootstrapLaunchctlService {
             command.stdin(std::process::Stdio::null());
             command.stdout(std::process::Stdio::piped());
             command.stderr(std::process::Stdio::piped());
-            let command_output = command
-                .output()
+            let command_output = crate::command_runner::output(&mut command)
                 .await
                 .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
             // We presume that success means it's found
@@ -116,7 +115,7 @@ impl Action for BootstrapLaunchctlService {
 
         if *is_disabled {
             execute_command(
-                Command::new("launchctl")
+                tools::command("launchctl")
                     .process_group(0)
                     .arg("enable")
                     .arg(&format!("{domain}/{service}"))
@@ -128,7 +127,7 @@ impl Action for BootstrapLaunchctlService {
 
         if !*is_present {
             execute_command(
-                Command::new("launchctl")
+                tools::command("launchctl")
                     .process_group(0)
                     .arg("bootstrap")
                     .arg(&domain)
@@ -156,7 +155,7 @@ impl Action for BootstrapLaunchctlService {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("launchctl")
+            tools::command("launchctl")
                 .process_group(0)
                 .arg("bootout")
                 .arg(&self.domain)
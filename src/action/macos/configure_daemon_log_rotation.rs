@@ -0,0 +1,159 @@
+use tracing::{span, Span};
+
+use crate::action::{
+    base::{CreateDirectory, CreateFile},
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+/// Where the Nix daemon's output goes when it is rotated, launchd otherwise hands it to unified logging
+pub const DAEMON_LOG_DIR: &str = "/var/log/nix";
+pub const DAEMON_LOG_PATH: &str = "/var/log/nix/nix-daemon.log";
+const NEWSYSLOG_DEST: &str = "/etc/newsyslog.d/org.nixos.nix-daemon.conf";
+/// How many rotated logs `newsyslog` keeps
+const ROTATED_COUNT: u32 = 7;
+/// The size, in kilobytes, at which `newsyslog` rotates the log
+const ROTATED_SIZE_KB: u32 = 10240;
+
+/**
+Rotate the log the Nix daemon writes to [`DAEMON_LOG_PATH`] with `newsyslog`
+
+[`ConfigureInitService`](crate::action::common::ConfigureInitService) points the daemon's
+`StandardOutPath`/`StandardErrorPath` at it.
+*/
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ConfigureDaemonLogRotation {
+    create_directory: StatefulAction<CreateDirectory>,
+    create_file: StatefulAction<CreateFile>,
+}
+
+impl ConfigureDaemonLogRotation {
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn plan(force: bool) -> Result<StatefulAction<Self>, ActionError> {
+        let create_directory =
+            CreateDirectory::plan(DAEMON_LOG_DIR, "root".to_string(), None, 0o0755, true)
+                .await
+                .map_err(Self::error)?;
+        let create_file = CreateFile::plan(
+            NEWSYSLOG_DEST,
+            "root".to_string(),
+            "wheel".to_string(),
+            0o0644,
+            render_newsyslog_entry(),
+            force,
+        )
+        .await
+        .map_err(Self::error)?;
+
+        Ok(Self {
+            create_directory,
+            create_file,
+        }
+        .into())
+    }
+}
+
+#[async_trait::async_trait]
+#[typetag::serde(name = "configure_daemon_log_rotation")]
+impl Action for ConfigureDaemonLogRotation {
+    fn action_tag() -> ActionTag {
+        ActionTag("configure_daemon_log_rotation")
+    }
+    fn tracing_synopsis(&self) -> String {
+        format!("Rotate the Nix daemon's log `{DAEMON_LOG_PATH}`")
+    }
+
+    fn tracing_span(&self) -> Span {
+        span!(tracing::Level::DEBUG, "configure_daemon_log_rotation",)
+    }
+
+    fn execute_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            self.tracing_synopsis(),
+            vec![
+                format!("Create `{DAEMON_LOG_DIR}`"),
+                format!(
+                    "Write `{NEWSYSLOG_DEST}`, keeping {ROTATED_COUNT} logs of up to {} MiB",
+                    ROTATED_SIZE_KB / 1024
+                ),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(&mut self) -> Result<(), ActionError> {
+        self.create_directory
+            .try_execute()
+            .await
+            .map_err(Self::error)?;
+        self.create_file.try_execute().await.map_err(Self::error)?;
+
+        Ok(())
+    }
+
+    fn revert_description(&self) -> Vec<ActionDescription> {
+        vec![ActionDescription::new(
+            format!("Stop rotating the Nix daemon's log `{DAEMON_LOG_PATH}`"),
+            vec![
+                format!("Remove `{NEWSYSLOG_DEST}`"),
+                format!("Remove `{DAEMON_LOG_DIR}` and the logs in it"),
+            ],
+        )]
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn revert(&mut self) -> Result<(), ActionError> {
+        let mut errors = vec![];
+        if let Err(err) = self.create_file.try_revert().await {
+            errors.push(err);
+        }
+        if let Err(err) = self.create_directory.try_revert().await {
+            errors.push(err);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else if errors.len() == 1 {
+            Err(errors
+                .into_iter()
+                .next()
+                .expect("Expected 1 len Vec to have at least 1 item"))
+        } else {
+            Err(Self::error(ActionErrorKind::MultipleChildren(errors)))
+        }
+    }
+}
+
+/// The `newsyslog.conf(5)` entry, `N` as launchd (not a pid file) owns the daemon, `J` to compress with bzip2
+fn render_newsyslog_entry() -> String {
+    format!(
+        "# Managed by nix-installer\n\
+        # logfilename\t[owner:group]\tmode\tcount\tsize\twhen\tflags\n\
+        {DAEMON_LOG_PATH}\troot:wheel\t644\t{ROTATED_COUNT}\t{ROTATED_SIZE_KB}\t*\tNJ\n"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_newsyslog_entry() {
+        let entry = render_newsyslog_entry();
+        let rule = entry
+            .lines()
+            .find(|line| !line.starts_with('#'))
+            .expect("Expected a rotation rule");
+        assert_eq!(
+            rule.split('\t').collect::<Vec<_>>(),
+            [
+                "/var/log/nix/nix-daemon.log",
+                "root:wheel",
+                "644",
+                "7",
+                "10240",
+                "*",
+                "NJ"
+            ]
+        );
+    }
+}
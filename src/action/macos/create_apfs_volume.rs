@@ -1,4 +1,3 @@
-use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
 use tokio::process::Command;
@@ -8,7 +7,7 @@ use crate::action::{ActionError, ActionTag, StatefulAction};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
-use crate::os::darwin::{DiskUtilApfsListOutput, DiskUtilInfoOutput};
+use crate::os::darwin::{diskutil_apfs_list, diskutil_info};
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateApfsVolume {
@@ -24,23 +23,13 @@ impl CreateApfsVolume {
         name: String,
         case_sensitive: bool,
     ) -> Result<StatefulAction<Self>, ActionError> {
-        let output =
-            execute_command(Command::new("/usr/sbin/diskutil").args(["apfs", "list", "-plist"]))
-                .await
-                .map_err(Self::error)?;
-
-        let parsed: DiskUtilApfsListOutput =
-            plist::from_bytes(&output.stdout).map_err(Self::error)?;
-        for container in parsed.containers {
-            for volume in container.volumes {
-                if volume.name.as_ref() == Some(&name) {
-                    return Ok(StatefulAction::completed(Self {
-                        disk: disk.as_ref().to_path_buf(),
-                        name,
-                        case_sensitive,
-                    }));
-                }
-            }
+        let parsed = diskutil_apfs_list().await.map_err(Self::error)?;
+        if parsed.volume(&name).is_some() {
+            return Ok(StatefulAction::completed(Self {
+                disk: disk.as_ref().to_path_buf(),
+                name,
+                case_sensitive,
+            }));
         }
 
         Ok(StatefulAction::uncompleted(Self {
@@ -124,18 +113,7 @@ impl Action for CreateApfsVolume {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         let currently_mounted = {
-            let buf = execute_command(
-                Command::new("/usr/sbin/diskutil")
-                    .process_group(0)
-                    .args(["info", "-plist"])
-                    .arg(&self.name)
-                    .stdin(std::process::Stdio::null()),
-            )
-            .await
-            .map_err(Self::error)?
-            .stdout;
-            let the_plist: DiskUtilInfoOutput =
-                plist::from_reader(Cursor::new(buf)).map_err(Self::error)?;
+            let the_plist = diskutil_info(&self.name).await.map_err(Self::error)?;
 
             the_plist.mount_point.is_some()
         };
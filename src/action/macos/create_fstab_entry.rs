@@ -42,6 +42,7 @@ impl CreateFstabEntry {
         planned_create_apfs_volume: &StatefulAction<CreateApfsVolume>,
     ) -> Result<StatefulAction<Self>, ActionError> {
         let fstab_path = Path::new(FSTAB_PATH);
+        crate::path_policy::check(fstab_path).map_err(Self::error)?;
 
         if fstab_path.exists() {
             let fstab_buf = tokio::fs::read_to_string(&fstab_path)
@@ -122,6 +123,7 @@ impl Action for CreateFstabEntry {
             existing_entry,
         } = self;
         let fstab_path = Path::new(FSTAB_PATH);
+        crate::path_policy::check(fstab_path).map_err(Self::error)?;
         let uuid = match get_uuid_for_label(apfs_volume_label)
             .await
             .map_err(Self::error)?
@@ -2,14 +2,14 @@ use serde::{Deserialize, Serialize};
 use tracing::{span, Span};
 
 use std::path::PathBuf;
-use tokio::{
-    fs::{remove_file, OpenOptions},
-    io::AsyncWriteExt,
-    process::Command,
-};
+use tokio::fs::remove_file;
 
+use crate::os::tools;
 use crate::{
-    action::{Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction},
+    action::{
+        base::replaced_file::{self, Original},
+        Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+    },
     execute_command,
 };
 
@@ -20,6 +20,9 @@ pub struct CreateNixHookService {
     path: PathBuf,
     service_label: String,
     needs_bootout: bool,
+    /// What a plist overwritten had, see [`replaced_file`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<Original>,
 }
 
 impl CreateNixHookService {
@@ -31,24 +34,11 @@ impl CreateNixHookService {
             ),
             service_label: "systems.determinate.nix-installer.nix-hook".into(),
             needs_bootout: false,
+            original: None,
         };
 
         // If the service is currently loaded or running, we need to unload it during execute (since we will then recreate it and reload it)
-        // This `launchctl` command may fail if the service isn't loaded
-        let mut check_loaded_command = Command::new("launchctl");
-        check_loaded_command.process_group(0);
-        check_loaded_command.arg("print");
-        check_loaded_command.arg(format!("system/{}", this.service_label));
-        tracing::trace!(
-            command = format!("{:?}", check_loaded_command.as_std()),
-            "Executing"
-        );
-        let check_loaded_output = check_loaded_command
-            .output()
-            .await
-            .map_err(|e| ActionErrorKind::command(&check_loaded_command, e))
-            .map_err(Self::error)?;
-        this.needs_bootout = check_loaded_output.status.success();
+        this.needs_bootout = is_loaded(&this.service_label).await.map_err(Self::error)?;
         if this.needs_bootout {
             tracing::debug!(
                 "Detected loaded service `{}` which needs unload before replacing `{}`",
@@ -122,11 +112,12 @@ impl Action for CreateNixHookService {
             path,
             service_label,
             needs_bootout,
+            original,
         } = self;
 
         if *needs_bootout {
             execute_command(
-                Command::new("launchctl")
+                tools::command("launchctl")
                     .process_group(0)
                     .arg("bootout")
                     .arg(format!("system/{service_label}")),
@@ -137,32 +128,55 @@ impl Action for CreateNixHookService {
 
         let generated_plist = generate_plist(service_label).await.map_err(Self::error)?;
 
-        let mut options = OpenOptions::new();
-        options.create(true).write(true).read(true);
-
-        let mut file = options
-            .open(&path)
-            .await
-            .map_err(|e| Self::error(ActionErrorKind::Open(path.to_owned(), e)))?;
-
         let mut buf = Vec::new();
         plist::to_writer_xml(&mut buf, &generated_plist).map_err(Self::error)?;
-        file.write_all(&buf)
+        if original.is_none() {
+            *original = Original::read(path).await.map_err(Self::error)?;
+        }
+        replaced_file::replace(path, &buf, None, None, None)
             .await
-            .map_err(|e| Self::error(ActionErrorKind::Write(path.to_owned(), e)))?;
+            .map_err(Self::error)?;
 
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        let description = match self.original {
+            Some(_) => format!(
+                "Unload the service `{}` and restore the original file `{}`",
+                self.service_label,
+                self.path.display()
+            ),
+            None => format!(
+                "Unload the service `{}` and delete file `{}`",
+                self.service_label,
+                self.path.display()
+            ),
+        };
         vec![ActionDescription::new(
-            format!("Delete file `{}`", self.path.display()),
-            vec![format!("Delete file `{}`", self.path.display())],
+            description.clone(),
+            vec![description],
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
+        // Otherwise it stays loaded until the next boot, and may run `repair` again
+        if is_loaded(&self.service_label).await.map_err(Self::error)? {
+            execute_command(
+                tools::command("launchctl")
+                    .process_group(0)
+                    .arg("bootout")
+                    .arg(format!("system/{}", self.service_label)),
+            )
+            .await
+            .map_err(Self::error)?;
+        }
+
+        if let Some(original) = self.original.take() {
+            original.restore(&self.path).await.map_err(Self::error)?;
+            return Ok(());
+        }
         remove_file(&self.path)
             .await
             .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.to_owned(), e)))?;
@@ -171,6 +185,22 @@ impl Action for CreateNixHookService {
     }
 }
 
+/// Whether the service `service_label` is loaded, `launchctl print` fails if it isn't
+async fn is_loaded(service_label: &str) -> Result<bool, ActionErrorKind> {
+    let mut check_loaded_command = tools::command("launchctl");
+    check_loaded_command.process_group(0);
+    check_loaded_command.arg("print");
+    check_loaded_command.arg(format!("system/{service_label}"));
+    tracing::trace!(
+        command = format!("{:?}", check_loaded_command.as_std()),
+        "Executing"
+    );
+    let check_loaded_output = crate::command_runner::output(&mut check_loaded_command)
+        .await
+        .map_err(|e| ActionErrorKind::command(&check_loaded_command, e))?;
+    Ok(check_loaded_output.status.success())
+}
+
 /// This function must be able to operate at both plan and execute time.
 async fn generate_plist(service_label: &str) -> Result<LaunchctlHookPlist, ActionErrorKind> {
     let plist = LaunchctlHookPlist {
@@ -224,3 +254,35 @@ impl From<CreateNixHookServiceError> for ActionErrorKind {
         ActionErrorKind::Custom(Box::new(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plist_repairs_profiles_at_boot() -> eyre::Result<()> {
+        let plist = generate_plist("systems.determinate.nix-installer.nix-hook").await?;
+        let mut buf = Vec::new();
+        plist::to_writer_xml(&mut buf, &plist)?;
+        assert_eq!(plist::from_bytes::<LaunchctlHookPlist>(&buf)?, plist);
+        let xml = String::from_utf8(buf)?;
+        assert!(xml.contains("<key>Label</key>"), "{xml}");
+        assert!(xml.contains("<key>ProgramArguments</key>"), "{xml}");
+        // Loaded at boot and started again until it succeeds, which it can't before `/nix` is mounted
+        assert!(!plist.keep_alive.successful_exit);
+        assert_eq!(plist.program_arguments[..2], ["/bin/sh", "-c"]);
+
+        let (wait, invocation) = plist.program_arguments[2]
+            .split_once(" && ")
+            .expect("The service waits for the installer");
+        assert_eq!(wait, "/bin/wait4path /nix/nix-installer");
+        let cli = crate::cli::NixInstallerCli::try_parse_from(invocation.split_whitespace())?;
+        assert!(matches!(
+            cli.subcommand,
+            Some(crate::cli::subcommand::NixInstallerSubcommand::Repair(_))
+        ));
+        Ok(())
+    }
+}
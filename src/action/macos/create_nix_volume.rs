@@ -187,8 +187,7 @@ impl Action for CreateNixVolume {
             command.stderr(std::process::Stdio::null());
             command.stdout(std::process::Stdio::null());
             tracing::trace!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store volume existence");
-            let output = command
-                .output()
+            let output = crate::command_runner::output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
@@ -232,8 +231,7 @@ impl Action for CreateNixVolume {
             command.stderr(std::process::Stdio::null());
             command.stdout(std::process::Stdio::null());
             tracing::trace!(%retry_tokens, command = ?command.as_std(), "Checking for Nix Store mount path existence");
-            let output = command
-                .output()
+            let output = crate::command_runner::output(&mut command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&command, e))
                 .map_err(Self::error)?;
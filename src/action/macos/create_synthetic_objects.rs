@@ -1,11 +1,22 @@
+use std::path::Path;
+
 use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::execute_command;
 
-use crate::action::{Action, ActionDescription, ActionError, ActionTag, StatefulAction};
+use crate::action::{
+    Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
+};
+
+/// The synthetic object the installer defines, which `apfs.util` may only create on the next boot
+const NIX_SYNTHETIC_OBJECT: &str = "/nix";
 
-/// Create the synthetic objects defined in `/etc/synthetic.conf`
+/** Create the synthetic objects defined in `/etc/synthetic.conf`
+
+If `apfs.util` can't create `/nix` without a reboot, the install stops there [until
+after one](crate::RebootCheckpoint), when this runs again to check it was.
+ */
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct CreateSyntheticObjects;
 
@@ -57,6 +68,12 @@ impl Action for CreateSyntheticObjects {
         .await
         .ok(); // Deliberate
 
+        if !Path::new(NIX_SYNTHETIC_OBJECT).exists() {
+            return Err(Self::error(ActionErrorKind::NeedsReboot(format!(
+                "`apfs.util` could not create `{NIX_SYNTHETIC_OBJECT}` from `/etc/synthetic.conf` until the next boot"
+            ))));
+        }
+
         Ok(())
     }
 
@@ -2,15 +2,13 @@ use serde::{Deserialize, Serialize};
 use tracing::{span, Span};
 
 use std::path::{Path, PathBuf};
-use tokio::{
-    fs::{remove_file, OpenOptions},
-    io::AsyncWriteExt,
-    process::Command,
-};
+use tokio::fs::remove_file;
 
 use crate::action::{
+    base::replaced_file::{self, Original},
     Action, ActionDescription, ActionError, ActionErrorKind, ActionTag, StatefulAction,
 };
+use crate::os::tools;
 
 use super::get_uuid_for_label;
 
@@ -24,6 +22,9 @@ pub struct CreateVolumeService {
     mount_point: PathBuf,
     encrypt: bool,
     needs_bootout: bool,
+    /// What a plist overwritten had, see [`replaced_file`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<Original>,
 }
 
 impl CreateVolumeService {
@@ -45,19 +46,19 @@ impl CreateVolumeService {
             mount_point,
             encrypt,
             needs_bootout: false,
+            original: None,
         };
 
         // If the service is currently loaded or running, we need to unload it during execute (since we will then recreate it and reload it)
         // This `launchctl` command may fail if the service isn't loaded
-        let mut check_loaded_command = Command::new("launchctl");
+        let mut check_loaded_command = tools::command("launchctl");
         check_loaded_command.arg("print");
         check_loaded_command.arg(format!("system/{}", this.mount_service_label));
         tracing::trace!(
             command = format!("{:?}", check_loaded_command.as_std()),
             "Executing"
         );
-        let check_loaded_output = check_loaded_command
-            .output()
+        let check_loaded_output = crate::command_runner::output(&mut check_loaded_command)
             .await
             .map_err(|e| ActionErrorKind::command(&check_loaded_command, e))
             .map_err(Self::error)?;
@@ -181,18 +182,18 @@ impl Action for CreateVolumeService {
             mount_point,
             encrypt,
             needs_bootout,
+            original,
         } = self;
 
         if *needs_bootout {
-            let mut unload_command = Command::new("launchctl");
+            let mut unload_command = tools::command("launchctl");
             unload_command.arg("bootout");
             unload_command.arg(format!("system/{mount_service_label}"));
             tracing::trace!(
                 command = format!("{:?}", unload_command.as_std()),
                 "Executing"
             );
-            let unload_output = unload_command
-                .output()
+            let unload_output = crate::command_runner::output(&mut unload_command)
                 .await
                 .map_err(|e| ActionErrorKind::command(&unload_command, e))
                 .map_err(Self::error)?;
@@ -225,32 +226,35 @@ impl Action for CreateVolumeService {
         .await
         .map_err(Self::error)?;
 
-        let mut options = OpenOptions::new();
-        options.create(true).write(true).read(true);
-
-        let mut file = options
-            .open(&path)
-            .await
-            .map_err(|e| Self::error(ActionErrorKind::Open(path.to_owned(), e)))?;
-
         let mut buf = Vec::new();
         plist::to_writer_xml(&mut buf, &generated_plist).map_err(Self::error)?;
-        file.write_all(&buf)
+        if original.is_none() {
+            *original = Original::read(path).await.map_err(Self::error)?;
+        }
+        replaced_file::replace(path, &buf, None, None, None)
             .await
-            .map_err(|e| Self::error(ActionErrorKind::Write(path.to_owned(), e)))?;
+            .map_err(Self::error)?;
 
         Ok(())
     }
 
     fn revert_description(&self) -> Vec<ActionDescription> {
+        let description = match self.original {
+            Some(_) => format!("Restore the original file `{}`", self.path.display()),
+            None => format!("Delete file `{}`", self.path.display()),
+        };
         vec![ActionDescription::new(
-            format!("Delete file `{}`", self.path.display()),
-            vec![format!("Delete file `{}`", self.path.display())],
+            description.clone(),
+            vec![description],
         )]
     }
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
+        if let Some(original) = self.original.take() {
+            original.restore(&self.path).await.map_err(Self::error)?;
+            return Ok(());
+        }
         remove_file(&self.path)
             .await
             .map_err(|e| Self::error(ActionErrorKind::Remove(self.path.to_owned(), e)))?;
@@ -1,4 +1,3 @@
-use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
 use tokio::process::Command;
@@ -8,7 +7,7 @@ use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
-use crate::os::darwin::DiskUtilInfoOutput;
+use crate::os::darwin::diskutil_info;
 
 /**
 Enable ownership on a volume
@@ -55,18 +54,7 @@ impl Action for EnableOwnership {
         let Self { path } = self;
 
         let should_enable_ownership = {
-            let buf = execute_command(
-                Command::new("/usr/sbin/diskutil")
-                    .process_group(0)
-                    .args(["info", "-plist"])
-                    .arg(&path)
-                    .stdin(std::process::Stdio::null()),
-            )
-            .await
-            .map_err(Self::error)?
-            .stdout;
-            let the_plist: DiskUtilInfoOutput =
-                plist::from_reader(Cursor::new(buf)).map_err(Self::error)?;
+            let the_plist = diskutil_info(&path).await.map_err(Self::error)?;
 
             !the_plist.global_permissions_enabled
         };
@@ -4,7 +4,7 @@ use crate::{
         ActionState, ActionTag, StatefulAction,
     },
     execute_command,
-    os::darwin::DiskUtilApfsListOutput,
+    os::darwin::diskutil_apfs_list,
 };
 use rand::Rng;
 use std::{
@@ -48,10 +48,10 @@ impl EncryptApfsVolume {
         command.stdin(Stdio::null());
         command.stdout(Stdio::null());
         command.stderr(Stdio::null());
-        if command
-            .status()
+        if crate::command_runner::output(&mut command)
             .await
             .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?
+            .status
             .success()
         {
             // The user has a password matching what we would create.
@@ -72,24 +72,14 @@ impl EncryptApfsVolume {
         }
 
         // Ensure if the disk already exists, that it's encrypted
-        let output =
-            execute_command(Command::new("/usr/sbin/diskutil").args(["apfs", "list", "-plist"]))
-                .await
-                .map_err(Self::error)?;
-
-        let parsed: DiskUtilApfsListOutput =
-            plist::from_bytes(&output.stdout).map_err(Self::error)?;
-        for container in parsed.containers {
-            for volume in container.volumes {
-                if volume.name.as_ref() == Some(&name) {
-                    if volume.encryption {
-                        return Err(Self::error(
-                            EncryptApfsVolumeError::ExistingVolumeNotEncrypted(name, disk),
-                        ));
-                    } else {
-                        return Ok(StatefulAction::completed(Self { disk, name }));
-                    }
-                }
+        let parsed = diskutil_apfs_list().await.map_err(Self::error)?;
+        if let Some(volume) = parsed.volume(&name) {
+            if volume.encryption {
+                return Err(Self::error(
+                    EncryptApfsVolumeError::ExistingVolumeNotEncrypted(name, disk),
+                ));
+            } else {
+                return Ok(StatefulAction::completed(Self { disk, name }));
             }
         }
 
@@ -1,10 +1,10 @@
 use std::process::Output;
 
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionErrorKind, ActionTag, StatefulAction};
 use crate::execute_command;
+use crate::os::{darwin::launchctl_list_entry, tools};
 
 use crate::action::{Action, ActionDescription};
 
@@ -26,41 +26,14 @@ impl KickstartLaunchctlService {
         let domain = domain.as_ref().to_string();
         let service = service.as_ref().to_string();
 
-        let mut service_exists = false;
-        let mut service_started = false;
-        let mut command = Command::new("launchctl");
-        command.process_group(0);
-        command.arg("print");
-        command.arg(&service);
-        command.arg("-plist");
-        command.stdin(std::process::Stdio::null());
-        command.stdout(std::process::Stdio::piped());
-        command.stderr(std::process::Stdio::piped());
-        let output = command
-            .output()
+        // `launchctl print` is neither stable nor meant to be parsed, a loaded job has a PID in
+        // the table of `launchctl list` while it is running
+        let service_started = launchctl_list_entry(&service)
             .await
-            .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
-        if output.status.success() {
-            service_exists = true;
-
-            let output_string = String::from_utf8(output.stdout).map_err(Self::error)?;
-            // We are looking for a line containing "state = " with some trailing content
-            // The output is not a JSON or a plist
-            // MacOS's man pages explicitly tell us not to try to parse this output
-            // MacOS's man pages explicitly tell us this output is not stable
-            // Yet, here we are, doing exactly that.
-            for output_line in output_string.lines() {
-                let output_line_trimmed = output_line.trim();
-                if output_line_trimmed.starts_with("state") {
-                    if output_line_trimmed.contains("running") {
-                        service_started = true;
-                    }
-                    break;
-                }
-            }
-        }
+            .map_err(Self::error)?
+            .is_some_and(|entry| entry.pid.is_some());
 
-        if service_exists && service_started {
+        if service_started {
             return Ok(StatefulAction::completed(Self { domain, service }));
         }
 
@@ -99,7 +72,7 @@ impl Action for KickstartLaunchctlService {
         let Self { domain, service } = self;
 
         execute_command(
-            Command::new("launchctl")
+            tools::command("launchctl")
                 .process_group(0)
                 .args(["kickstart", "-k"])
                 .arg(format!("{domain}/{service}"))
@@ -121,15 +94,14 @@ impl Action for KickstartLaunchctlService {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         // MacOs doesn't offer an "ensure-stopped" like they do with Kickstart
-        let mut command = Command::new("launchctl");
+        let mut command = tools::command("launchctl");
         command.process_group(0);
         command.arg("stop");
         command.arg(format!("{}/{}", self.domain, self.service));
         command.stdin(std::process::Stdio::null());
         let command_str = format!("{:?}", command.as_std());
 
-        let output = command
-            .output()
+        let output = crate::command_runner::output(&mut command)
             .await
             .map_err(|e| Self::error(ActionErrorKind::command(&command, e)))?;
 
@@ -2,6 +2,7 @@
 */
 
 pub(crate) mod bootstrap_launchctl_service;
+pub(crate) mod configure_daemon_log_rotation;
 pub(crate) mod create_apfs_volume;
 pub(crate) mod create_fstab_entry;
 pub(crate) mod create_nix_hook_service;
@@ -16,6 +17,9 @@ pub(crate) mod set_tmutil_exclusions;
 pub(crate) mod unmount_apfs_volume;
 
 pub use bootstrap_launchctl_service::BootstrapLaunchctlService;
+pub use configure_daemon_log_rotation::{
+    ConfigureDaemonLogRotation, DAEMON_LOG_DIR, DAEMON_LOG_PATH,
+};
 pub use create_apfs_volume::CreateApfsVolume;
 pub use create_nix_hook_service::CreateNixHookService;
 pub use create_nix_volume::{CreateNixVolume, NIX_VOLUME_MOUNTD_DEST};
@@ -24,78 +28,33 @@ pub use create_volume_service::CreateVolumeService;
 pub use enable_ownership::{EnableOwnership, EnableOwnershipError};
 pub use encrypt_apfs_volume::EncryptApfsVolume;
 pub use kickstart_launchctl_service::KickstartLaunchctlService;
-use serde::Deserialize;
 pub use set_tmutil_exclusion::SetTmutilExclusion;
 pub use set_tmutil_exclusions::SetTmutilExclusions;
-use tokio::process::Command;
 pub use unmount_apfs_volume::UnmountApfsVolume;
+
+use std::path::Path;
+
 use uuid::Uuid;
 
-use crate::execute_command;
+use crate::os::darwin::{diskutil_apfs_list, service_is_disabled_in, LAUNCHD_DISABLED_DIR};
 
 use super::ActionErrorKind;
 
+/// The UUID of the APFS volume named `apfs_volume_label`, `None` if there is none
 async fn get_uuid_for_label(apfs_volume_label: &str) -> Result<Option<Uuid>, ActionErrorKind> {
-    let mut command = Command::new("/usr/sbin/diskutil");
-    command.process_group(0);
-    command.arg("info");
-    command.arg("-plist");
-    command.arg(apfs_volume_label);
-    command.stdin(std::process::Stdio::null());
-    command.stdout(std::process::Stdio::piped());
-
-    let command_str = format!("{:?}", command.as_std());
-
-    tracing::trace!(command = command_str, "Executing");
-    let output = command
-        .output()
-        .await
-        .map_err(|e| ActionErrorKind::command(&command, e))?;
-
-    let parsed: DiskUtilApfsInfoOutput = plist::from_bytes(&output.stdout)?;
-
-    if let Some(error_message) = parsed.error_message {
-        let expected_not_found = format!("Could not find disk: {apfs_volume_label}");
-        if error_message.contains(&expected_not_found) {
-            Ok(None)
-        } else {
-            Err(ActionErrorKind::DiskUtilInfoError {
-                command: command_str,
-                message: error_message,
-            })
-        }
-    } else if let Some(uuid) = parsed.volume_uuid {
-        Ok(Some(uuid))
-    } else {
-        Err(ActionErrorKind::command_output(&command, output))
-    }
-}
-
-#[derive(Deserialize, Clone, Debug)]
-#[serde(rename_all = "PascalCase")]
-struct DiskUtilApfsInfoOutput {
-    #[serde(rename = "ErrorMessage")]
-    error_message: Option<String>,
-    #[serde(rename = "VolumeUUID")]
-    volume_uuid: Option<Uuid>,
+    let list = diskutil_apfs_list().await?;
+    Ok(list
+        .volume(apfs_volume_label)
+        .and_then(|volume| volume.volume_uuid))
 }
 
+/// If `service` of `domain` is disabled, from the overrides database `launchctl print-disabled` prints
 #[tracing::instrument]
 pub(crate) async fn service_is_disabled(
     domain: &str,
     service: &str,
 ) -> Result<bool, ActionErrorKind> {
-    let output = execute_command(
-        Command::new("launchctl")
-            .arg("print-disabled")
-            .arg(domain)
-            .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped()),
-    )
-    .await?;
-    let utf8_output = String::from_utf8_lossy(&output.stdout);
-    let is_disabled = utf8_output.contains(&format!("\"{service}\" => disabled"));
+    let is_disabled = service_is_disabled_in(Path::new(LAUNCHD_DISABLED_DIR), domain, service)?;
     tracing::trace!(is_disabled, "Service disabled status");
     Ok(is_disabled)
 }
@@ -1,10 +1,10 @@
 use std::path::{Path, PathBuf};
 
-use tokio::process::Command;
 use tracing::{span, Span};
 
 use crate::action::{ActionError, ActionTag, StatefulAction};
 use crate::execute_command;
+use crate::os::tools;
 
 use crate::action::{Action, ActionDescription};
 
@@ -67,7 +67,7 @@ impl Action for SetTmutilExclusion {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn execute(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("tmutil")
+            tools::command("tmutil")
                 .process_group(0)
                 .arg("addexclusion")
                 .arg(&self.path)
@@ -86,7 +86,7 @@ impl Action for SetTmutilExclusion {
     #[tracing::instrument(level = "debug", skip_all)]
     async fn revert(&mut self) -> Result<(), ActionError> {
         execute_command(
-            Command::new("tmutil")
+            tools::command("tmutil")
                 .process_group(0)
                 .arg("removeexclusion")
                 .arg(&self.path)
@@ -1,4 +1,3 @@
-use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
 use tokio::process::Command;
@@ -8,7 +7,7 @@ use crate::action::{ActionError, ActionTag, StatefulAction};
 use crate::execute_command;
 
 use crate::action::{Action, ActionDescription};
-use crate::os::darwin::DiskUtilInfoOutput;
+use crate::os::darwin::diskutil_info;
 
 /**
 Unmount an APFS volume
@@ -58,18 +57,7 @@ impl Action for UnmountApfsVolume {
         let Self { disk: _, name } = self;
 
         let currently_mounted = {
-            let buf = execute_command(
-                Command::new("/usr/sbin/diskutil")
-                    .process_group(0)
-                    .args(["info", "-plist"])
-                    .arg(&name)
-                    .stdin(std::process::Stdio::null()),
-            )
-            .await
-            .map_err(Self::error)?
-            .stdout;
-            let the_plist: DiskUtilInfoOutput =
-                plist::from_reader(Cursor::new(buf)).map_err(Self::error)?;
+            let the_plist = diskutil_info(&name).await.map_err(Self::error)?;
 
             the_plist.mount_point.is_some()
         };
@@ -100,18 +88,7 @@ impl Action for UnmountApfsVolume {
         let Self { disk: _, name } = self;
 
         let currently_mounted = {
-            let buf = execute_command(
-                Command::new("/usr/sbin/diskutil")
-                    .process_group(0)
-                    .args(["info", "-plist"])
-                    .arg(&name)
-                    .stdin(std::process::Stdio::null()),
-            )
-            .await
-            .map_err(Self::error)?
-            .stdout;
-            let the_plist: DiskUtilInfoOutput =
-                plist::from_reader(Cursor::new(buf)).map_err(Self::error)?;
+            let the_plist = diskutil_info(&name).await.map_err(Self::error)?;
 
             the_plist.mount_point.is_some()
         };
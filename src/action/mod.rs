@@ -194,12 +194,15 @@ pub mod linux;
 pub mod macos;
 mod stateful;
 
-pub use stateful::{ActionState, StatefulAction};
+pub(crate) use stateful::{assign_ids, key as action_key};
+pub use stateful::{ActionState, ActionTiming, StatefulAction};
 use std::{error::Error, process::Output};
 use tokio::task::JoinError;
 use tracing::Span;
 
-use crate::{error::HasExpectedErrors, settings::UrlOrPathError, CertificateError};
+use crate::{
+    error::HasExpectedErrors, http::HttpClientError, settings::UrlOrPathError, CertificateError,
+};
 
 /// An action which can be reverted or completed, with an action state
 ///
@@ -247,6 +250,23 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
     /// This is called by [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) through [`StatefulAction::try_revert`] which handles tracing as well as if the action needs to revert based on its `action_state`.
     async fn revert(&mut self) -> Result<(), ActionError>;
 
+    /// The kinds of sub-[`Action`]s which can be individually retained with [`retain`][Action::retain]
+    ///
+    /// Only meta-actions which contain sub-actions that are useful to keep on their own need to implement this.
+    fn retainable_kinds(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    /// Skip reverting any sub-[`Action`]s of the given kind, returning the synopsis of each retained action
+    fn retain(&mut self, _kind: &str) -> Vec<String> {
+        Vec::new()
+    }
+    /// The kinds of [`Action`]s which must come earlier in a plan than this one, either on their own or as a sub-[`Action`]
+    ///
+    /// Checked when planning, so the steps of a plan can be skipped or reordered without silently breaking one another.
+    fn depends_on(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
     fn stateful(self) -> StatefulAction<Self>
     where
         Self: Sized,
@@ -254,6 +274,9 @@ pub trait Action: Send + Sync + std::fmt::Debug + dyn_clone::DynClone {
         StatefulAction {
             action: self,
             state: ActionState::Uncompleted,
+            id: None,
+            timing: None,
+            reverted_at: None,
         }
     }
 
@@ -330,6 +353,15 @@ impl ActionError {
         &self.action_tag
     }
 
+    /// Why the system must be rebooted, if that is what stopped this action (or one of its children)
+    pub fn needs_reboot(&self) -> Option<&str> {
+        match &self.kind {
+            ActionErrorKind::NeedsReboot(reason) => Some(reason),
+            ActionErrorKind::Child(child) => child.needs_reboot(),
+            _ => None,
+        }
+    }
+
     #[cfg(feature = "diagnostics")]
     pub fn diagnostic(&self) -> String {
         use crate::diagnostics::ErrorDiagnostic;
@@ -365,6 +397,9 @@ pub enum ActionErrorKind {
     /// An error to do with certificates
     #[error(transparent)]
     Certificate(#[from] CertificateError),
+    /// An error building the HTTP client
+    #[error(transparent)]
+    HttpClient(#[from] HttpClientError),
     /// A child error
     #[error(transparent)]
     Child(Box<ActionError>),
@@ -412,6 +447,8 @@ pub enum ActionErrorKind {
     PathWasNotFile(std::path::PathBuf),
     #[error("Path `{0}` exists, but is not a directory, consider removing it with `rm {0}`")]
     PathWasNotDirectory(std::path::PathBuf),
+    #[error("Path `{0}` is not one of the Nix dotfiles in the home directory, refusing to change its owner")]
+    PathWasNotNixDotfile(std::path::PathBuf),
     #[error("Getting metadata for {0}`")]
     GettingMetadata(std::path::PathBuf, #[source] std::io::Error),
     #[error("Creating directory `{0}`")]
@@ -426,6 +463,10 @@ pub enum ActionErrorKind {
     SetPermissions(u32, std::path::PathBuf, #[source] std::io::Error),
     #[error("Remove file `{0}`")]
     Remove(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Refusing to remove `{0}` while filesystems are mounted under it, unmount them first (eg. `umount {}`):\n{}", .1.first().map(|path| path.display().to_string()).unwrap_or_default(), .1.iter().map(|path| format!("* `{}`", path.display())).collect::<Vec<_>>().join("\n"))]
+    MountsUnder(std::path::PathBuf, Vec<std::path::PathBuf>),
+    #[error("Refusing to remove `{0}`, it is on a different filesystem than the directory being removed")]
+    CrossesFilesystem(std::path::PathBuf),
     #[error("Copying file `{0}` to `{1}`")]
     Copy(
         std::path::PathBuf,
@@ -468,10 +509,29 @@ pub enum ActionErrorKind {
     NoUser(String),
     #[error("Getting gid for group `{0}`")]
     GettingGroupId(String, #[source] nix::errno::Errno),
-    #[error("Group `{0}` existed but had a different gid ({1}) than planned ({2})")]
+    #[error("Group `{0}` existed but had a different gid ({1}) than planned ({2}). Pass `--nix-build-group-id {1}` to adopt it, or `--nix-build-group-name` to create a group of another name")]
     GroupGidMismatch(String, u32, u32),
+    #[error("GID {0} planned for group `{2}` already belongs to group `{1}`")]
+    GroupGidTaken(u32, String, String),
+    #[error("The provisioned build users {} don't exist, `--no-create-users` needs identity management to provide `{0}1` (or `{0}01`) and onward, as many as `--nix-build-user-count`", .1.iter().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(", "))]
+    MissingBuildUsers(String, Vec<String>),
+    #[error("No group lists all of the provisioned build users as members, `nix-daemon` takes its build users from the members of its `build-users-group`. Tried {}", .0.iter().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(", "))]
+    NoSharedBuildGroup(Vec<String>),
+    #[error("Creating the build users would take over other accounts: {}. Pass `--nix-build-user-prefix` or `--nix-build-user-id-base` to avoid them, or `--no-create-users` if they are provisioned build users", .0.join("; "))]
+    BuildUserCollisions(Vec<String>),
+    #[error("The existing `/nix/store` is owned by GID {0} rather than the planned build group GID ({1}), and GID {0} cannot be adopted as it belongs to another group. Pass `--regroup-store` to change the group of the store to GID {1} (this can take minutes on large stores)")]
+    StoreGroupMismatch(u32, u32),
+    #[error("The kernel lacks features the requested Nix settings need: {}. Pass `--relax-unsupported-settings` to turn them off instead", .0.join("; "))]
+    UnsupportedSettings(Vec<String>),
+    #[error("Cancelled by user")]
+    Cancelled,
+    /// The system must be rebooted before the action can finish, which stops the install at a [checkpoint](crate::RebootCheckpoint)
+    #[error("The system must be rebooted to continue: {0}")]
+    NeedsReboot(String),
     #[error("Getting group `{0}`")]
     NoGroup(String),
+    #[error("Users or groups `{}` could not be resolved via NSS after creation, if `nscd` or `sssd` is running consider invalidating its caches", .0.join("`, `"))]
+    NssLookupTimeout(Vec<String>),
     #[error("Chowning path `{0}`")]
     Chown(std::path::PathBuf, #[source] nix::errno::Errno),
     #[error("Glob globbing error")]
@@ -556,8 +616,16 @@ pub enum ActionErrorKind {
         See https://github.com/DeterminateSystems/nix-installer#without-systemd-linux-only for documentation on usage and drawbacks.\
         ")]
     SystemdMissing,
-    #[error("`{command}` failed, message: {message}")]
-    DiskUtilInfoError { command: String, message: String },
+    #[error("Could not parse the output of `{command}`: {message}\nThe output was:\n{output}")]
+    UnparsableOutput {
+        command: String,
+        message: String,
+        output: String,
+    },
+    #[error("The shell profile hook does not parse with `{shell}`, refusing to write it, as every login would fail: {stderr}")]
+    InvalidShellHook { shell: String, stderr: String },
+    #[error("`{}` can't be written into the shell profile hook, which is UTF-8 text", crate::lossless_path::Display(.0))]
+    UnrepresentableHookPath(std::path::PathBuf),
     #[error(transparent)]
     UrlOrPathError(#[from] UrlOrPathError),
     #[error("Request error")]
@@ -568,6 +636,8 @@ pub enum ActionErrorKind {
     ),
     #[error("Unknown url scheme")]
     UnknownUrlScheme,
+    #[error(transparent)]
+    PathPolicy(#[from] crate::path_policy::PathPolicyViolation),
 }
 
 impl ActionErrorKind {
@@ -596,6 +666,14 @@ impl HasExpectedErrors for ActionErrorKind {
             | Self::PathGroupMismatch(_, _, _)
             | Self::PathModeMismatch(_, _, _) => Some(Box::new(self)),
             Self::SystemdMissing => Some(Box::new(self)),
+            Self::MountsUnder(_, _) | Self::CrossesFilesystem(_) => Some(Box::new(self)),
+            Self::GroupGidMismatch(_, _, _) | Self::GroupGidTaken(_, _, _) => Some(Box::new(self)),
+            Self::MissingBuildUsers(_, _)
+            | Self::NoSharedBuildGroup(_)
+            | Self::BuildUserCollisions(_) => Some(Box::new(self)),
+            Self::StoreGroupMismatch(_, _) => Some(Box::new(self)),
+            Self::UnsupportedSettings(_) => Some(Box::new(self)),
+            Self::PathPolicy(_) => Some(Box::new(self)),
             _ => None,
         }
     }
@@ -618,6 +696,8 @@ impl crate::diagnostics::ErrorDiagnostic for ActionErrorKind {
             | Self::GettingMetadata(path, _)
             | Self::CreateDirectory(path, _)
             | Self::PathWasNotFile(path)
+            | Self::PathWasNotNixDotfile(path)
+            | Self::UnrepresentableHookPath(path)
             | Self::Remove(path, _) => {
                 vec![path.to_string_lossy().to_string()]
             },
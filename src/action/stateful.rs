@@ -1,7 +1,10 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use tracing::{Instrument, Span};
 
 use super::{Action, ActionDescription, ActionError, ActionTag};
+use crate::timestamp::Timestamp;
 
 /// A wrapper around an [`Action`](crate::action::Action) which tracks the [`ActionState`] and
 /// handles some tracing output
@@ -9,6 +12,22 @@ use super::{Action, ActionDescription, ActionError, ActionTag};
 pub struct StatefulAction<A> {
     pub(crate) action: A,
     pub(crate) state: ActionState,
+    /// A stable identifier of the action among those of its plan, see [`assign_ids`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    /// When the action last ran as a step of an install, `None` for nested actions and those which never ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timing: Option<ActionTiming>,
+    /// When the action was reverted on its own, see [`InstallPlan::revert_action`](crate::InstallPlan::revert_action)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reverted_at: Option<Timestamp>,
+}
+
+/// When a step of an install started and finished
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct ActionTiming {
+    pub started_at: Timestamp,
+    pub finished_at: Timestamp,
 }
 
 impl<A> From<A> for StatefulAction<A>
@@ -19,6 +38,9 @@ where
         Self {
             action,
             state: ActionState::Uncompleted,
+            id: None,
+            timing: None,
+            reverted_at: None,
         }
     }
 }
@@ -27,6 +49,47 @@ impl StatefulAction<Box<dyn Action>> {
     pub fn inner_typetag_name(&self) -> &'static str {
         self.action.typetag_name()
     }
+    /// The stable identifier of this action, `None` until its plan [assigned ids](assign_ids)
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+    /// When this action was reverted on its own, `None` unless it was with [`InstallPlan::revert_action`](crate::InstallPlan::revert_action)
+    pub fn reverted_at(&self) -> Option<Timestamp> {
+        self.reverted_at
+    }
+    /// The kind of this action followed by its [`KEY_FIELDS`], the basis of its [`id`][StatefulAction::id]
+    fn stable_key(&self) -> String {
+        let kind = self.inner_typetag_name();
+        match serde_json::to_value(&self.action)
+            .ok()
+            .and_then(|action| key(&action))
+        {
+            Some(key) => format!("{kind}:{key}"),
+            None => kind.to_string(),
+        }
+    }
+    /// The kinds of this action and its sub-actions which can be retained, see [`retain`][StatefulAction::retain]
+    pub fn retainable_kinds(&self) -> Vec<&'static str> {
+        let mut kinds = vec![self.inner_typetag_name()];
+        kinds.extend(self.action.retainable_kinds());
+        kinds
+    }
+    /// The kinds of actions which must come before this one, see [`Action::depends_on`]
+    pub fn depends_on(&self) -> Vec<&'static str> {
+        self.action.depends_on()
+    }
+    /// Skip reverting this action (or its sub-actions) if they are of the given kind, returning the synopsis of each retained action
+    pub fn retain(&mut self, kind: &str) -> Vec<String> {
+        if self.inner_typetag_name() == kind {
+            if self.state == ActionState::Uncompleted {
+                return vec![];
+            }
+            self.state = ActionState::Skipped;
+            vec![self.tracing_synopsis()]
+        } else {
+            self.action.retain(kind)
+        }
+    }
     pub fn tracing_synopsis(&self) -> String {
         self.action.tracing_synopsis()
     }
@@ -138,6 +201,9 @@ where
         StatefulAction {
             action: Box::new(self.action),
             state: self.state,
+            id: self.id,
+            timing: self.timing,
+            reverted_at: self.reverted_at,
         }
     }
     /// A description of what this action would do during execution
@@ -231,6 +297,9 @@ where
         Self {
             state: ActionState::Completed,
             action,
+            id: None,
+            timing: None,
+            reverted_at: None,
         }
     }
 
@@ -238,17 +307,84 @@ where
         Self {
             state: ActionState::Skipped,
             action,
+            id: None,
+            timing: None,
+            reverted_at: None,
         }
     }
 
+    /// Skip reverting this action if it was executed, returning its synopsis if so
+    pub(crate) fn retain(&mut self) -> Option<String> {
+        if self.state == ActionState::Uncompleted {
+            return None;
+        }
+        self.state = ActionState::Skipped;
+        Some(self.tracing_synopsis())
+    }
+
     pub fn uncompleted(action: A) -> Self {
         Self {
             state: ActionState::Uncompleted,
             action,
+            id: None,
+            timing: None,
+            reverted_at: None,
         }
     }
 }
 
+/// The fields of a serialized action which tell it apart from others of its kind
+pub(crate) const KEY_FIELDS: &[&str] = &[
+    "path",
+    "name",
+    "groupname",
+    "unit",
+    "service_label",
+    "label",
+    "disk",
+];
+
+/// What identifies a serialized `action` among others of its kind, its [`KEY_FIELDS`] joined by spaces
+pub(crate) fn key(action: &serde_json::Value) -> Option<String> {
+    let parts = KEY_FIELDS
+        .iter()
+        .filter_map(|field| action.get(*field))
+        .filter(|value| !value.is_null())
+        .map(|value| match value {
+            serde_json::Value::String(string) => string.clone(),
+            // A path which isn't UTF-8, see `crate::lossless_path`
+            serde_json::Value::Object(object) if object.contains_key("bytes") => object
+                .get("lossy")
+                .and_then(|lossy| lossy.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            other => other.to_string(),
+        })
+        .collect::<Vec<_>>();
+    (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+/**
+Give each of `actions` an id derived from its kind and [`KEY_FIELDS`]
+
+Ids only change when what an action acts on does, not with unrelated settings, so logs, progress
+and receipts of different runs (or machines) can be correlated. A repeated id gets a `#2`, `#3`, ...
+suffix in plan order.
+*/
+pub(crate) fn assign_ids(actions: &mut [StatefulAction<Box<dyn Action>>]) {
+    let mut seen = BTreeMap::<String, usize>::new();
+    for action in actions {
+        let key = action.stable_key();
+        let count = seen.entry(key.clone()).or_default();
+        *count += 1;
+        action.id = Some(if *count == 1 {
+            key
+        } else {
+            format!("{key}#{count}")
+        });
+    }
+}
+
 /** The state of an [`Action`](crate::action::Action)
 */
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Copy)]
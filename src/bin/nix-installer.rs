@@ -21,5 +21,10 @@ async fn main() -> eyre::Result<ExitCode> {
 
     cli.instrumentation.setup()?;
 
-    cli.execute().await
+    let result = cli.execute().await;
+
+    #[cfg(feature = "telemetry")]
+    nix_installer::telemetry::shutdown();
+
+    result
 }
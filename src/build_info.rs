@@ -0,0 +1,166 @@
+/*! What this `nix-installer` was built from, for telling binaries apart when triaging
+
+Downstream packagers patch this crate (adding planners, changing defaults), so besides the version
+this records the commit (from `build.rs`, when built from a git checkout), the target, the enabled
+features and the [`Action`](crate::action::Action) and [`Planner`](crate::planner::Planner) kinds
+compiled in. It is shown by `nix-installer --version --format json`, logged first and recorded in
+receipts and diagnostics.
+*/
+
+use std::fmt;
+
+use crate::{action::Action, planner::Planner};
+
+/// How many characters of the commit [`BuildInfo`]'s summary shows
+const SHORT_SHA_LEN: usize = 12;
+
+/// What this binary was built from, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// The commit built, `None` when not built from a git checkout
+    pub git_sha: Option<String>,
+    /// Whether tracked files differed from [`git_sha`](Self::git_sha), `None` when unknown
+    pub git_dirty: Option<bool>,
+    pub target: String,
+    pub features: Vec<String>,
+    /// The kinds of [`Action`] which can be planned or read from receipts
+    pub actions: Vec<String>,
+    /// The names of [`Planner`]s which can be planned with or read from receipts
+    pub planners: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let features = [
+            ("cli", cfg!(feature = "cli")),
+            ("diagnostics", cfg!(feature = "diagnostics")),
+            ("telemetry", cfg!(feature = "telemetry")),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| feature.to_string())
+        .collect();
+
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_sha: option_env!("NIX_INSTALLER_BUILD_GIT_SHA").map(String::from),
+            git_dirty: option_env!("NIX_INSTALLER_BUILD_GIT_DIRTY").map(|dirty| dirty == "true"),
+            target: env!("NIX_INSTALLER_BUILD_TARGET").to_string(),
+            features,
+            actions: registered::<Box<dyn Action>>("action"),
+            planners: registered::<Box<dyn Planner>>("planner"),
+        }
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (", self.version)?;
+        match &self.git_sha {
+            Some(sha) => write!(f, "{}", &sha[..sha.len().min(SHORT_SHA_LEN)])?,
+            None => write!(f, "unknown commit")?,
+        }
+        if self.git_dirty == Some(true) {
+            write!(f, "-dirty")?;
+        }
+        write!(f, ", {}", self.target)?;
+        if !self.features.is_empty() {
+            write!(f, ", features: {}", self.features.join(","))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The names registered with `typetag` for `T`, internally tagged by `tag`
+///
+/// `typetag` doesn't expose its registry, but lists it when asked for a name it doesn't know.
+fn registered<T: serde::de::DeserializeOwned>(tag: &str) -> Vec<String> {
+    let err = match serde_json::from_value::<T>(serde_json::json!({ tag: "" })) {
+        Ok(_) => return Vec::new(),
+        Err(err) => err.to_string(),
+    };
+    let Some((_, expected)) = err.split_once("expected") else {
+        return Vec::new();
+    };
+    let mut names = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(String::from)
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn lists_compiled_in_kinds() {
+        let info = BuildInfo::current();
+        assert!(info.actions.iter().any(|kind| kind == "provision_nix"));
+        assert!(info
+            .actions
+            .iter()
+            .any(|kind| kind == "create_users_and_group"));
+        assert!(!info.actions.iter().any(|kind| kind.is_empty()));
+        #[cfg(target_os = "linux")]
+        assert!(info.planners.iter().any(|name| name == "linux"));
+        #[cfg(target_os = "macos")]
+        assert!(info.planners.iter().any(|name| name == "macos"));
+        assert!(info.features.iter().any(|feature| feature == "cli"));
+    }
+
+    #[test]
+    fn summarizes_on_one_line() {
+        let mut info = BuildInfo {
+            version: "0.14.0".into(),
+            git_sha: Some("0123456789abcdef0123456789abcdef01234567".into()),
+            git_dirty: Some(true),
+            target: "x86_64-unknown-linux-gnu".into(),
+            features: vec!["cli".into(), "diagnostics".into()],
+            actions: vec!["provision_nix".into()],
+            planners: vec!["linux".into()],
+        };
+        assert_eq!(
+            info.to_string(),
+            "0.14.0 (0123456789ab-dirty, x86_64-unknown-linux-gnu, features: cli,diagnostics)"
+        );
+        info.git_sha = None;
+        info.git_dirty = None;
+        info.features.clear();
+        assert_eq!(
+            info.to_string(),
+            "0.14.0 (unknown commit, x86_64-unknown-linux-gnu)"
+        );
+    }
+
+    /// Catches kinds or planners added or dropped unnoticed, the git fields differ per build and the
+    /// features per test run so aren't compared (the kinds include those registered by tests)
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn matches_snapshot() -> eyre::Result<()> {
+        let snapshot = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/build-info/x86_64-linux.json");
+        let mut info = serde_json::to_value(BuildInfo::current())?;
+        info["git_sha"] = serde_json::Value::Null;
+        info["git_dirty"] = serde_json::Value::Null;
+        info["features"] = serde_json::Value::Null;
+        let info = serde_json::to_string_pretty(&info)? + "\n";
+        if std::env::var_os("NIX_INSTALLER_UPDATE_SNAPSHOTS").is_some() {
+            std::fs::create_dir_all(snapshot.parent().expect("The snapshot is in a directory"))?;
+            std::fs::write(&snapshot, &info)?;
+        }
+        assert_eq!(
+            info,
+            std::fs::read_to_string(&snapshot)?,
+            "The build info changed, regenerate `{}` with `NIX_INSTALLER_UPDATE_SNAPSHOTS=1 cargo test` if that is intended",
+            snapshot.display()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+/*! Cancellation of the executing [`InstallPlan`](crate::InstallPlan)
+
+The plan checks its `cancel_channel` between actions, actions which run for a long time (such as
+[`ReownNixStore`](crate::action::base::ReownNixStore)) check [`requested`] themselves so they can
+stop at a point they can resume from.
+*/
+
+use std::{cell::RefCell, future::Future};
+
+use tokio::sync::broadcast::{error::TryRecvError, Receiver};
+
+tokio::task_local! {
+    static CURRENT: RefCell<Receiver<()>>;
+}
+
+/// Run `fut` (an action of the plan) able to see cancellations sent along `cancel_channel`
+pub(crate) async fn scope<F: Future>(cancel_channel: Option<&Receiver<()>>, fut: F) -> F::Output {
+    match cancel_channel {
+        Some(cancel_channel) => {
+            CURRENT
+                .scope(RefCell::new(cancel_channel.resubscribe()), fut)
+                .await
+        },
+        None => fut.await,
+    }
+}
+
+/// If the executing plan was cancelled, always `false` outside of [`scope`]
+pub(crate) fn requested() -> bool {
+    CURRENT
+        .try_with(|cancel_channel| {
+            cancel_channel.borrow_mut().try_recv() != Err(TryRecvError::Empty)
+        })
+        .unwrap_or(false)
+}
@@ -40,6 +40,13 @@ pub struct Instrumentation {
     /// See https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html#directives
     #[clap(long = "log-directive", global = true, env = "NIX_INSTALLER_LOG_DIRECTIVES", value_delimiter = ',', num_args = 0..)]
     pub log_directives: Vec<Directive>,
+    /// Export metrics and tracing spans to this OpenTelemetry (OTLP/HTTP) collector, like `http://localhost:4318`
+    ///
+    /// Telemetry is strictly opt-in: nothing is sent unless this or `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+    /// Other `OTEL_EXPORTER_OTLP_*` variables (such as headers) are honored.
+    #[cfg(feature = "telemetry")]
+    #[clap(long, env = "NIX_INSTALLER_TELEMETRY_ENDPOINT", global = true)]
+    pub telemetry_endpoint: Option<url::Url>,
 }
 
 impl Instrumentation {
@@ -59,6 +66,12 @@ impl Instrumentation {
             .with(filter_layer)
             .with(ErrorLayer::default());
 
+        #[cfg(feature = "telemetry")]
+        let registry = registry.with(
+            crate::telemetry::init(self.telemetry_endpoint.as_ref())?
+                .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)),
+        );
+
         match self.logger {
             Logger::Compact => {
                 let fmt_layer = self.fmt_layer_compact();
@@ -78,6 +91,9 @@ impl Instrumentation {
             },
         }
 
+        // First, so every log tells which binary wrote it
+        tracing::debug!("nix-installer {}", crate::build_info::BuildInfo::current());
+
         Ok(())
     }
 
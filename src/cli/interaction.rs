@@ -1,4 +1,7 @@
-use std::io::{stdin, stdout, BufRead, Write};
+use std::{
+    io::{stderr, stdin, stdout, BufRead, IsTerminal, Write},
+    time::Duration,
+};
 
 use eyre::{eyre, WrapErr};
 use owo_colors::OwoColorize;
@@ -88,3 +91,40 @@ pub(crate) async fn clean_exit_with_message(message: impl AsRef<str>) -> ! {
     eprintln!("{}", message.as_ref());
     std::process::exit(0)
 }
+
+/// The most often the progress line is redrawn
+const PROGRESS_REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Redraws the [progress](crate::progress) of the step running as a line on stderr until dropped
+pub(crate) struct ProgressLine(tokio::task::JoinHandle<()>);
+
+/// Show the [progress](crate::progress) of each step, only if stderr is a terminal
+pub(crate) fn render_progress() -> Option<ProgressLine> {
+    if !stderr().is_terminal() {
+        return None;
+    }
+    let mut progress = crate::progress::subscribe();
+    Some(ProgressLine(tokio::spawn(async move {
+        while progress.changed().await.is_ok() {
+            let line = progress
+                .borrow_and_update()
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            {
+                let mut stderr = stderr().lock();
+                // Redrawn in place, nothing is left behind once the step is done
+                let _ = write!(stderr, "\r\x1b[2K{line}");
+                let _ = stderr.flush();
+            }
+            tokio::time::sleep(PROGRESS_REDRAW_INTERVAL).await;
+        }
+    })))
+}
+
+impl Drop for ProgressLine {
+    fn drop(&mut self) {
+        self.0.abort();
+        eprint!("\r\x1b[2K");
+    }
+}
@@ -0,0 +1,365 @@
+/*! The questions `nix-installer install --interactive` asks, and the settings the answers make
+
+The answers only ever change the [`CommonSettings`] (and on macOS the volume encryption of the
+planner) the flags would, so everything after is the same as installing with the
+[equivalent flags](Answers::flags). Asking goes through a function given the question, so the flow
+is tested without a terminal.
+*/
+
+use std::io::Write;
+
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+use url::Url;
+
+use crate::settings::{CommonSettings, UrlOrPathOrString};
+
+/// What the questions are about, shown before the first
+pub(crate) const INTRODUCTION: &str = "\
+    Nix is installed for every user of the machine, with the `nix` command and flakes enabled.\n\
+    Answer with enter to keep the default in brackets.";
+
+/// A binary cache to substitute from, besides `cache.nixos.org`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct BinaryCache {
+    pub(crate) url: Url,
+    /// The key signing its paths, as `name:base64`
+    pub(crate) public_key: String,
+}
+
+/// The answers to the questions, which can be saved to skip asking them on other machines
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Answers {
+    /// Added to `trusted-users`, so they can use binary caches and settings of their own
+    #[serde(default)]
+    pub(crate) trusted_user: Option<String>,
+    #[serde(default)]
+    pub(crate) binary_cache: Option<BinaryCache>,
+    pub(crate) modify_profile: bool,
+    /// Only asked on macOS, where Nix lives in a volume of its own
+    #[serde(default)]
+    pub(crate) encrypt: Option<bool>,
+}
+
+impl Answers {
+    /**
+    Ask the questions with `ask`, which is given the question and the default answer and returns
+    the answer (empty for the default)
+
+    `invoking_user` is the user who ran the installer with `sudo`, if any, who is the one offered
+    trust. Invalid answers are asked again.
+    */
+    pub(crate) fn ask(
+        mut ask: impl FnMut(&str, &str) -> eyre::Result<String>,
+        invoking_user: Option<&str>,
+        macos: bool,
+    ) -> eyre::Result<Self> {
+        let trusted_user = match invoking_user {
+            Some(user) if user != "root" => {
+                let question = format!(
+                    "Trust `{user}`, letting them use binary caches and settings the daemon would otherwise refuse?"
+                );
+                yes_no(&mut ask, &question, false)?.then(|| user.to_string())
+            },
+            _ => None,
+        };
+
+        let binary_cache = loop {
+            let url = ask(
+                "Add a binary cache? Enter its URL, or nothing to only use `cache.nixos.org`",
+                "",
+            )?;
+            if url.trim().is_empty() {
+                break None;
+            }
+            match Url::parse(url.trim()) {
+                Ok(url) if matches!(url.scheme(), "https" | "http" | "s3" | "ssh") => {
+                    let public_key = loop {
+                        let public_key =
+                            ask("The public key signing its paths, as `name:base64`", "")?;
+                        if valid_public_key(public_key.trim()) {
+                            break public_key.trim().to_string();
+                        }
+                    };
+                    break Some(BinaryCache { url, public_key });
+                },
+                _ => continue,
+            }
+        };
+
+        let modify_profile = yes_no(
+            &mut ask,
+            "Load Nix in new shells by adding it to the shell profiles in `/etc`?",
+            true,
+        )?;
+
+        let encrypt = if macos {
+            Some(yes_no(&mut ask, "Encrypt the Nix volume?", false)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            trusted_user,
+            binary_cache,
+            modify_profile,
+            encrypt,
+        })
+    }
+
+    /// The `nix.conf` lines the answers add
+    fn extra_conf(&self) -> Vec<String> {
+        let mut extra_conf = vec![];
+        if let Some(trusted_user) = &self.trusted_user {
+            extra_conf.push(format!("extra-trusted-users = {trusted_user}"));
+        }
+        if let Some(BinaryCache { url, public_key }) = &self.binary_cache {
+            extra_conf.push(format!("extra-substituters = {url}"));
+            extra_conf.push(format!("extra-trusted-public-keys = {public_key}"));
+        }
+        extra_conf
+    }
+
+    /// Change `settings` as the [equivalent flags](Answers::flags) would
+    pub(crate) fn apply(&self, settings: &mut CommonSettings) {
+        settings.modify_profile(self.modify_profile);
+        settings
+            .extra_conf
+            .extend(self.extra_conf().into_iter().map(UrlOrPathOrString::String));
+    }
+
+    /// The `nix-installer install` arguments making the same settings without asking
+    pub(crate) fn flags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if self.encrypt.is_some() {
+            flags.push("macos".to_string());
+        }
+        for line in self.extra_conf() {
+            flags.push("--extra-conf".to_string());
+            flags.push(line);
+        }
+        if !self.modify_profile {
+            flags.push("--no-modify-profile".to_string());
+        }
+        if let Some(encrypt) = self.encrypt {
+            flags.push("--encrypt".to_string());
+            flags.push(encrypt.to_string());
+        }
+        flags
+    }
+
+    /// [`flags`](Answers::flags) as a command line to paste into a shell
+    pub(crate) fn command_line(&self) -> String {
+        std::iter::once("nix-installer install".to_string())
+            .chain(self.flags().iter().map(|flag| shell_quote(flag)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn yes_no(
+    ask: &mut impl FnMut(&str, &str) -> eyre::Result<String>,
+    question: &str,
+    default: bool,
+) -> eyre::Result<bool> {
+    loop {
+        let answer = ask(question, if default { "Y/n" } else { "y/N" })?;
+        match answer.trim().to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+/// Whether `public_key` looks like a Nix public key, `name:base64`
+fn valid_public_key(public_key: &str) -> bool {
+    match public_key.split_once(':') {
+        Some((name, key)) => {
+            !name.is_empty()
+                && !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '='))
+        },
+        None => false,
+    }
+}
+
+/// Quote `arg` for a POSIX shell, unless it needs none
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@,+".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Ask `question` on the terminal, see [`Answers::ask`]
+pub(crate) fn ask_terminal(question: &str, default: &str) -> eyre::Result<String> {
+    let mut stdout = std::io::stdout();
+    if default.is_empty() {
+        write!(stdout, "{} ", question.bold())?;
+    } else {
+        write!(stdout, "{} [{default}] ", question.bold())?;
+    }
+    stdout.flush()?;
+    super::interaction::read_line().wrap_err("Reading the answer")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Answers with `answers` in order, recording the questions
+    struct Script<'a> {
+        answers: std::slice::Iter<'a, &'a str>,
+        asked: Vec<String>,
+    }
+
+    impl<'a> Script<'a> {
+        fn new(answers: &'a [&'a str]) -> Self {
+            Self {
+                answers: answers.iter(),
+                asked: vec![],
+            }
+        }
+
+        fn ask(&mut self, question: &str, _default: &str) -> eyre::Result<String> {
+            self.asked.push(question.to_string());
+            self.answers
+                .next()
+                .map(|answer| answer.to_string())
+                .ok_or_else(|| eyre::eyre!("Asked more than was scripted: {question}"))
+        }
+    }
+
+    #[test]
+    fn defaults_change_nothing() -> eyre::Result<()> {
+        let mut script = Script::new(&["", "", ""]);
+        let answers = Answers::ask(
+            |question, default| script.ask(question, default),
+            Some("alice"),
+            false,
+        )?;
+        assert_eq!(script.asked.len(), 3);
+        assert_eq!(
+            answers,
+            Answers {
+                trusted_user: None,
+                binary_cache: None,
+                modify_profile: true,
+                encrypt: None,
+            }
+        );
+        assert!(answers.flags().is_empty());
+        assert_eq!(answers.command_line(), "nix-installer install");
+        Ok(())
+    }
+
+    #[test]
+    fn asks_again_until_answers_are_valid() -> eyre::Result<()> {
+        let mut script = Script::new(&[
+            "maybe",
+            "yes",
+            "not a url",
+            "https://cache.example.com",
+            "no-colon",
+            "cache.example.com-1:c2lnbmluZyBrZXk=",
+            "n",
+            "y",
+        ]);
+        let answers = Answers::ask(
+            |question, default| script.ask(question, default),
+            Some("alice"),
+            true,
+        )?;
+        assert_eq!(script.asked.len(), 8);
+        assert_eq!(
+            answers,
+            Answers {
+                trusted_user: Some("alice".into()),
+                binary_cache: Some(BinaryCache {
+                    url: "https://cache.example.com".parse()?,
+                    public_key: "cache.example.com-1:c2lnbmluZyBrZXk=".into(),
+                }),
+                modify_profile: false,
+                encrypt: Some(true),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn does_not_offer_to_trust_root() -> eyre::Result<()> {
+        for invoking_user in [None, Some("root")] {
+            let mut script = Script::new(&["", ""]);
+            let answers = Answers::ask(
+                |question, default| script.ask(question, default),
+                invoking_user,
+                false,
+            )?;
+            assert!(script
+                .asked
+                .iter()
+                .all(|question| !question.contains("Trust")));
+            assert_eq!(answers.trusted_user, None);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_make_the_same_settings_as_their_flags() -> eyre::Result<()> {
+        use clap::Parser;
+
+        let answers = Answers {
+            trusted_user: Some("alice".into()),
+            binary_cache: Some(BinaryCache {
+                url: "https://cache.example.com".parse()?,
+                public_key: "cache.example.com-1:c2lnbmluZyBrZXk=".into(),
+            }),
+            modify_profile: false,
+            encrypt: None,
+        };
+        let mut settings = CommonSettings::default().await?;
+        answers.apply(&mut settings);
+
+        let cli = crate::cli::NixInstallerCli::try_parse_from(
+            ["nix-installer", "install"]
+                .map(String::from)
+                .into_iter()
+                .chain(answers.flags()),
+        )?;
+        let Some(crate::cli::subcommand::NixInstallerSubcommand::Install(install)) = cli.subcommand
+        else {
+            eyre::bail!("Expected the flags to parse as `install`");
+        };
+        assert_eq!(settings.modify_profile, install.settings.modify_profile);
+        assert_eq!(settings.extra_conf, install.settings.extra_conf);
+        assert_eq!(
+            answers.command_line(),
+            "nix-installer install --extra-conf 'extra-trusted-users = alice' --extra-conf 'extra-substituters = https://cache.example.com/' --extra-conf 'extra-trusted-public-keys = cache.example.com-1:c2lnbmluZyBrZXk=' --no-modify-profile"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn saved_answers_read_back() -> eyre::Result<()> {
+        let answers = Answers {
+            trusted_user: None,
+            binary_cache: None,
+            modify_profile: false,
+            encrypt: Some(false),
+        };
+        assert_eq!(
+            serde_json::from_str::<Answers>(&serde_json::to_string(&answers)?)?,
+            answers
+        );
+        Ok(())
+    }
+}
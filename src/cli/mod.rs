@@ -4,12 +4,13 @@
 
 pub(crate) mod arg;
 mod interaction;
+mod interactive;
 pub(crate) mod subcommand;
 
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser};
 use eyre::WrapErr;
 use owo_colors::OwoColorize;
-use std::{ffi::CString, process::ExitCode};
+use std::{collections::BTreeSet, ffi::CString, process::ExitCode};
 use tokio::sync::broadcast::{Receiver, Sender};
 
 use self::subcommand::NixInstallerSubcommand;
@@ -25,13 +26,27 @@ The Determinate Nix installer
 A fast, friendly, and reliable tool to help you use Nix with Flakes everywhere.
 */
 #[derive(Debug, Parser)]
-#[clap(version)]
+#[clap(version, disable_version_flag = true, arg_required_else_help = true)]
 pub struct NixInstallerCli {
+    /// Print version (with `--format json`, what this binary was built from)
+    #[clap(short = 'V', long, action(ArgAction::SetTrue))]
+    pub version: bool,
+
+    /// The format to print the version in
+    #[clap(long, value_enum, default_value_t = VersionFormat::Human, requires = "version")]
+    pub format: VersionFormat,
+
     #[clap(flatten)]
     pub instrumentation: arg::Instrumentation,
 
     #[clap(subcommand)]
-    pub subcommand: NixInstallerSubcommand,
+    pub subcommand: Option<NixInstallerSubcommand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum VersionFormat {
+    Human,
+    Json,
 }
 
 #[async_trait::async_trait]
@@ -39,20 +54,61 @@ impl CommandExecute for NixInstallerCli {
     #[tracing::instrument(level = "trace", skip_all)]
     async fn execute(self) -> eyre::Result<ExitCode> {
         let Self {
+            version,
+            format,
             instrumentation: _,
             subcommand,
         } = self;
 
+        if version {
+            let build_info = crate::build_info::BuildInfo::current();
+            match format {
+                VersionFormat::Human => println!("nix-installer {build_info}"),
+                VersionFormat::Json => println!("{}", serde_json::to_string_pretty(&build_info)?),
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        crate::env::warn_unexpected(&flag_vars(&Self::command()));
+
+        // Only flags, such as `-v`, were given
+        let Some(subcommand) = subcommand else {
+            Self::command().print_help()?;
+            return Ok(ExitCode::from(2));
+        };
+
         match subcommand {
             NixInstallerSubcommand::Plan(plan) => plan.execute().await,
             NixInstallerSubcommand::SelfTest(self_test) => self_test.execute().await,
             NixInstallerSubcommand::Install(install) => install.execute().await,
             NixInstallerSubcommand::Repair(restore_shell) => restore_shell.execute().await,
             NixInstallerSubcommand::Uninstall(revert) => revert.execute().await,
+            NixInstallerSubcommand::ClaimReceipt(claim_receipt) => claim_receipt.execute().await,
+            NixInstallerSubcommand::RevertAction(revert_action) => revert_action.execute().await,
+            NixInstallerSubcommand::PreviewUpgrade(preview_upgrade) => {
+                preview_upgrade.execute().await
+            },
+            NixInstallerSubcommand::CleanupUser(cleanup_user) => cleanup_user.execute().await,
+            NixInstallerSubcommand::Doctor(doctor) => doctor.execute().await,
+            NixInstallerSubcommand::GenerateFirstBootUnit(generate_first_boot_unit) => {
+                generate_first_boot_unit.execute().await
+            },
+            NixInstallerSubcommand::Convert(convert) => convert.execute().await,
+            NixInstallerSubcommand::Capabilities(capabilities) => capabilities.execute().await,
         }
     }
 }
 
+/// The environment variables of the flags of `command` and its subcommands
+pub(crate) fn flag_vars(command: &clap::Command) -> BTreeSet<String> {
+    command
+        .get_arguments()
+        .filter_map(|arg| arg.get_env())
+        .map(|env| env.to_string_lossy().into_owned())
+        .chain(command.get_subcommands().flat_map(flag_vars))
+        .collect()
+}
+
 pub(crate) async fn signal_channel() -> eyre::Result<(Sender<()>, Receiver<()>)> {
     let (sender, receiver) = tokio::sync::broadcast::channel(100);
 
@@ -104,20 +160,7 @@ pub fn ensure_root() -> eyre::Result<()> {
 
         let mut env_list = vec![];
         for (key, value) in std::env::vars() {
-            let preserve = match key.as_str() {
-                // Rust logging/backtrace bits we use
-                "RUST_LOG" | "RUST_BACKTRACE" => true,
-                // CI
-                "GITHUB_PATH" => true,
-                // Used for detecting what command to suggest for sourcing Nix
-                "SHELL" => true,
-                // Proxy settings (automatically picked up by Reqwest)
-                "HTTP_PROXY" | "http_proxy" | "HTTPS_PROXY" | "https_proxy" => true,
-                // Our own environments
-                key if key.starts_with("NIX_INSTALLER") => true,
-                _ => false,
-            };
-            if preserve {
+            if crate::env::preserved_by_sudo(&key) {
                 env_list.push(format!("{key}={value}"));
             }
         }
@@ -150,3 +193,28 @@ pub fn ensure_root() -> eyre::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version_format_needs_version() {
+        let cli =
+            NixInstallerCli::try_parse_from(["nix-installer", "--version", "--format", "json"])
+                .unwrap();
+        assert!(cli.version);
+        assert_eq!(cli.format, VersionFormat::Json);
+        assert!(cli.subcommand.is_none());
+        assert!(NixInstallerCli::try_parse_from([
+            "nix-installer",
+            "--format",
+            "json",
+            "capabilities"
+        ])
+        .is_err());
+        let cli = NixInstallerCli::try_parse_from(["nix-installer", "capabilities"]).unwrap();
+        assert_eq!(cli.format, VersionFormat::Human);
+        assert!(cli.subcommand.is_some());
+    }
+}
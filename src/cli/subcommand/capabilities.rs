@@ -0,0 +1,244 @@
+use std::{any::TypeId, path::PathBuf, process::ExitCode};
+
+use clap::{Arg, ArgAction, CommandFactory, Parser};
+use semver::{Version, VersionReq};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::{
+    cli::{CommandExecute, NixInstallerCli},
+    plan::current_version,
+    BuiltinPlanner,
+};
+
+/// The version of the output of `capabilities`, bumped only when a field changes meaning or is removed
+const CAPABILITIES_VERSION: u64 = 1;
+
+/**
+Describe what this installer supports, in a machine readable format
+
+Wrappers should branch on this instead of parsing `--help`: it lists the installer version, the
+planners, every subcommand with its flags (their types, defaults and environment variables), and
+the receipt versions it writes and reads.
+*/
+#[derive(Debug, Parser)]
+pub struct Capabilities {
+    /// The format to describe the capabilities in
+    #[clap(long, value_enum, default_value_t = CapabilitiesFormat::Json)]
+    pub format: CapabilitiesFormat,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum CapabilitiesFormat {
+    Json,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Capabilities {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { format } = self;
+
+        let default_planner = BuiltinPlanner::default()
+            .await
+            .ok()
+            .map(|planner| planner.typetag_name());
+        let capabilities = capabilities(&current_version()?, default_planner);
+        match format {
+            CapabilitiesFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&capabilities)?)
+            },
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The capabilities of the installer at `version`, with the planner it would default to on this host
+fn capabilities(version: &Version, default_planner: Option<&str>) -> Value {
+    let command = NixInstallerCli::command();
+    let planners = command
+        .find_subcommand("install")
+        .map(|install| {
+            install
+                .get_subcommands()
+                .map(|planner| planner.get_name().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    json!({
+        "capabilities_version": CAPABILITIES_VERSION,
+        "version": version.to_string(),
+        "planners": planners,
+        "default_planner": default_planner,
+        "receipt": {
+            "writes": version.to_string(),
+            "reads": readable_receipt_versions(version).to_string(),
+            // Receipts of the upstream installer are translated with `convert`, best effort
+            "translates_upstream": true,
+            // Timestamps are RFC 3339 in UTC, see `crate::timestamp`, and installs are told apart by a UUID
+            "timestamps": "rfc3339-utc",
+            "install_id": "uuid",
+            // Where an install stopped for a reboot is recorded when `/nix` can't be created yet
+            "reboot_checkpoint": crate::plan::REBOOT_CHECKPOINT_LOCATION,
+        },
+        "command": describe_command(&command),
+        "environment": crate::env::inventory(),
+    })
+}
+
+/// The receipt versions [`InstallPlan::check_compatible`](crate::InstallPlan::check_compatible)
+/// accepts at `version`, those whose caret requirement it matches
+fn readable_receipt_versions(version: &Version) -> VersionReq {
+    let oldest = match (version.major, version.minor) {
+        (0, 0) => version.clone(),
+        (0, minor) => Version::new(0, minor, 0),
+        (major, _) => Version::new(major, 0, 0),
+    };
+    format!(">={oldest}, <={version}")
+        .parse()
+        .expect("A range of versions is a valid requirement")
+}
+
+fn describe_command(command: &clap::Command) -> Value {
+    let args = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .map(describe_arg)
+        .collect::<Vec<_>>();
+    let subcommands = command
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_hide_set())
+        .map(|subcommand| {
+            (
+                subcommand.get_name().to_string(),
+                describe_command(subcommand),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "args": args,
+        "subcommands": subcommands,
+    })
+}
+
+fn describe_arg(arg: &Arg) -> Value {
+    let value_type = value_type(arg);
+    let possible_values = match value_type {
+        "enum" => arg
+            .get_possible_values()
+            .iter()
+            .map(|value| value.get_name().to_string())
+            .collect(),
+        _ => vec![],
+    };
+    json!({
+        "name": arg.get_id().as_str(),
+        "long": arg.get_long(),
+        "short": arg.get_short(),
+        "env": arg.get_env().map(|env| env.to_string_lossy()),
+        "type": value_type,
+        "possible_values": possible_values,
+        "multiple": matches!(arg.get_action(), ArgAction::Append),
+        "default": arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy())
+            .collect::<Vec<_>>(),
+        "required": arg.is_required_set(),
+        "global": arg.is_global_set(),
+    })
+}
+
+/// The type of the values of `arg`, from its value parser
+fn value_type(arg: &Arg) -> &'static str {
+    match arg.get_action() {
+        ArgAction::SetTrue | ArgAction::SetFalse => return "bool",
+        ArgAction::Count => return "count",
+        _ => (),
+    }
+    let type_id = arg.get_value_parser().type_id();
+    let integers = [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i64>(),
+    ];
+    if type_id == TypeId::of::<bool>() {
+        "bool"
+    } else if integers.iter().any(|integer| type_id == *integer) {
+        "integer"
+    } else if type_id == TypeId::of::<PathBuf>() {
+        "path"
+    } else if type_id == TypeId::of::<Url>() {
+        "url"
+    } else if !arg.get_possible_values().is_empty() {
+        "enum"
+    } else {
+        "string"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_receipts_it_is_compatible_with() -> eyre::Result<()> {
+        let reads = readable_receipt_versions(&Version::new(0, 14, 2));
+        assert_eq!(reads.to_string(), ">=0.14.0, <=0.14.2");
+        for receipt in ["0.14.0", "0.14.2", "0.13.9", "0.14.3", "0.15.0"] {
+            let receipt = Version::parse(receipt)?;
+            let compatible =
+                VersionReq::parse(&receipt.to_string())?.matches(&Version::new(0, 14, 2));
+            assert_eq!(reads.matches(&receipt), compatible, "{receipt}");
+        }
+        assert_eq!(
+            readable_receipt_versions(&Version::new(1, 2, 0)).to_string(),
+            ">=1.0.0, <=1.2.0"
+        );
+        Ok(())
+    }
+
+    /// Arguments only the non-default features add, left out of the snapshot so it holds for every
+    /// feature set
+    const FEATURE_GATED_ARGS: &[&str] = &["telemetry_endpoint"];
+
+    fn without_feature_gated_args(command: &mut Value) {
+        if let Some(args) = command["args"].as_array_mut() {
+            args.retain(|arg| {
+                !FEATURE_GATED_ARGS.contains(&arg["name"].as_str().unwrap_or_default())
+            });
+        }
+        if let Some(subcommands) = command["subcommands"].as_object_mut() {
+            subcommands
+                .values_mut()
+                .for_each(without_feature_gated_args);
+        }
+    }
+
+    /// Changes to the CLI change this snapshot, regenerate it with `NIX_INSTALLER_UPDATE_SNAPSHOTS=1`
+    /// and review the difference for breaking changes
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn matches_snapshot() -> eyre::Result<()> {
+        let snapshot = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/capabilities/x86_64-linux.json");
+        let mut capabilities = capabilities(&current_version()?, Some("linux"));
+        without_feature_gated_args(&mut capabilities["command"]);
+        let capabilities = serde_json::to_string_pretty(&capabilities)? + "\n";
+        if std::env::var_os("NIX_INSTALLER_UPDATE_SNAPSHOTS").is_some() {
+            std::fs::create_dir_all(snapshot.parent().expect("The snapshot is in a directory"))?;
+            std::fs::write(&snapshot, &capabilities)?;
+        }
+        assert_eq!(
+            capabilities,
+            std::fs::read_to_string(&snapshot)?,
+            "The capabilities changed, regenerate `{}` with `NIX_INSTALLER_UPDATE_SNAPSHOTS=1 cargo test` if that is intended",
+            snapshot.display()
+        );
+        Ok(())
+    }
+}
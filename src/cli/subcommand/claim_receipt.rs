@@ -0,0 +1,43 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use color_eyre::eyre::WrapErr;
+
+use crate::{
+    cli::{ensure_root, CommandExecute},
+    plan::RECEIPT_LOCATION,
+    InstallPlan,
+};
+
+/// Record this host in a receipt, such as one baked into an image, so it can be uninstalled here
+#[derive(Debug, Parser)]
+pub struct ClaimReceipt {
+    #[clap(default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for ClaimReceipt {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { receipt } = self;
+
+        ensure_root()?;
+
+        let install_receipt_string = tokio::fs::read_to_string(&receipt)
+            .await
+            .wrap_err("Reading receipt")?;
+        let mut plan: InstallPlan =
+            serde_json::from_str(&install_receipt_string).wrap_err("Parsing receipt")?;
+
+        plan.claim().await;
+        tracing::info!("Claiming `{}` for this host", receipt.display());
+
+        let plan_json = serde_json::to_string_pretty(&plan).wrap_err("Serializing receipt")?;
+        tokio::fs::write(&receipt, format!("{plan_json}\n"))
+            .await
+            .wrap_err("Writing receipt")?;
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
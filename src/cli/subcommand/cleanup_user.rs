@@ -0,0 +1,195 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{ArgAction, Parser};
+use color_eyre::eyre::WrapErr;
+use nix::unistd::User;
+use owo_colors::OwoColorize;
+
+use crate::{
+    action::ActionState,
+    cli::{
+        ensure_root,
+        interaction::{self, PromptChoice},
+        CommandExecute,
+    },
+    os::per_user::{self, NIX_STATE_DIR},
+    plan::RECEIPT_LOCATION,
+};
+
+/**
+Remove what Nix and the installer left behind for a single user, such as one who left, keeping the install
+
+Their profiles and garbage collector roots, the Nix dotfiles in their home, and the hooks the installer
+added to their own files are removed.
+*/
+#[derive(Debug, Parser)]
+pub struct CleanupUser {
+    /// The user to clean up after, who may no longer exist
+    pub name: String,
+
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_NO_CONFIRM",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub no_confirm: bool,
+
+    /// Also delete the store paths which only the profiles of the user kept alive
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_GC",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub gc: bool,
+
+    /// Clean up after a user the install configured nothing for, whose profiles Nix created
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_ANY_USER",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub any_user: bool,
+
+    #[clap(long, default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for CleanupUser {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            name,
+            no_confirm,
+            gc,
+            any_user,
+            receipt,
+        } = self;
+
+        ensure_root()?;
+
+        if !per_user::valid_name(&name) || name == "root" {
+            eprintln!("{}", format!("Refusing to clean up after `{name}`").red());
+            return Ok(ExitCode::FAILURE);
+        }
+
+        let mut install_receipt = match tokio::fs::read_to_string(&receipt).await {
+            Ok(install_receipt) => Some(
+                serde_json::from_str::<serde_json::Value>(&install_receipt)
+                    .wrap_err("Parsing receipt")?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).wrap_err("Reading receipt"),
+        };
+        let managed = install_receipt
+            .as_ref()
+            .map(per_user::managed_users)
+            .unwrap_or_default();
+        if !managed.contains(&name) && !any_user {
+            eprintln!(
+                "{}",
+                format!(
+                    "The install recorded in `{}` configured nothing for `{name}`, pass `--any-user` to clean up after them anyway",
+                    receipt.display()
+                )
+                .red()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+
+        let home = User::from_name(&name).ok().flatten().map(|user| user.dir);
+        if home.is_none() {
+            tracing::info!(
+                "`{name}` no longer exists, only cleaning up after them in `{NIX_STATE_DIR}`"
+            );
+        }
+        let paths = per_user::scan(Path::new(NIX_STATE_DIR), &name, home.as_deref());
+        let mut actions = match &install_receipt {
+            Some(install_receipt) => per_user::receipt_actions(install_receipt, &name)
+                .wrap_err("Parsing the actions of the receipt")?,
+            None => vec![],
+        };
+        let generations = per_user::generations(
+            &Path::new(NIX_STATE_DIR)
+                .join("profiles/per-user")
+                .join(&name),
+        );
+
+        if paths.is_empty() && actions.is_empty() {
+            println!("Nothing to clean up after `{name}`");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        if !no_confirm {
+            let mut steps = paths
+                .iter()
+                .map(|path| format!("* Remove `{}`", path.display()))
+                .collect::<Vec<_>>();
+            steps.extend(actions.iter().flat_map(|(_, action)| {
+                action
+                    .describe_revert()
+                    .into_iter()
+                    .map(|description| format!("* {}", description.description))
+            }));
+            if gc {
+                steps.push(format!(
+                    "* Delete the store paths only the {} profile generations of `{name}` kept alive",
+                    generations.len()
+                ));
+            }
+            let question = format!(
+                "{}\n\n{}",
+                format!("Clean up after `{name}`:").bold(),
+                steps.join("\n")
+            );
+            match interaction::prompt(question, PromptChoice::Yes, true).await? {
+                PromptChoice::Yes => (),
+                PromptChoice::No | PromptChoice::Explain => {
+                    interaction::clean_exit_with_message("Okay, didn't do anything! Bye!").await
+                },
+            }
+        }
+
+        let mut reverted = false;
+        for (pointer, action) in &mut actions {
+            action
+                .try_revert()
+                .await
+                .wrap_err_with(|| format!("Reverting `{}`", action.tracing_synopsis()))?;
+            // So uninstalling doesn't revert it a second time
+            if let Some(state) = install_receipt
+                .as_mut()
+                .and_then(|install_receipt| install_receipt.pointer_mut(pointer))
+                .and_then(|action| action.get_mut("state"))
+            {
+                *state = serde_json::to_value(ActionState::Uncompleted)?;
+                reverted = true;
+            }
+        }
+        if let (true, Some(install_receipt)) = (reverted, &install_receipt) {
+            let install_receipt =
+                serde_json::to_string_pretty(install_receipt).wrap_err("Serializing receipt")?;
+            tokio::fs::write(&receipt, format!("{install_receipt}\n"))
+                .await
+                .wrap_err("Writing receipt")?;
+        }
+
+        for path in &paths {
+            per_user::remove(path)?;
+        }
+
+        if gc {
+            let deleted = per_user::collect_garbage(&generations).await?;
+            tracing::info!("Deleted {} store paths", deleted.len());
+        }
+
+        println!("Cleaned up after `{name}`");
+        Ok(ExitCode::SUCCESS)
+    }
+}
@@ -0,0 +1,190 @@
+use std::process::ExitCode;
+
+use crate::{
+    cli::{
+        ensure_root,
+        interaction::{self, PromptChoice},
+        signal_channel, CommandExecute,
+    },
+    convert::{single_user_hooks, Conversion, InstallMode, Owner},
+    error::HasExpectedErrors,
+    plan::RECEIPT_LOCATION,
+    settings::CommonSettings,
+    InstallPlan, NixInstallerError,
+};
+use clap::{ArgAction, Parser};
+use color_eyre::eyre::{eyre, WrapErr};
+use nix::unistd::{Uid, User};
+use owo_colors::OwoColorize;
+
+/**
+Convert an existing install between single-user and multi-user, keeping its store
+
+Multi-user installs must have a `nix-installer` receipt. Single-user installs without one (such as
+those of the upstream single-user installer) get a new receipt describing the converted install.
+*/
+#[derive(Debug, Parser)]
+pub struct Convert {
+    /// The mode to convert to
+    #[clap(long, value_enum)]
+    pub to: InstallMode,
+
+    /// The user to give the store to when converting to single-user
+    #[clap(long, env = "SUDO_USER")]
+    pub user: Option<String>,
+
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_NO_CONFIRM",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub no_confirm: bool,
+
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_EXPLAIN",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub explain: bool,
+
+    /// Proceed even if the receipt was recorded on a different host
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_IGNORE_HOST_MISMATCH",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub ignore_host_mismatch: bool,
+
+    /// Used to plan the build users, `nix.conf` and daemon of a multi-user install
+    #[clap(flatten)]
+    pub settings: CommonSettings,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Convert {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            to,
+            user,
+            no_confirm,
+            explain,
+            ignore_host_mismatch,
+            settings,
+        } = self;
+
+        ensure_root()?;
+
+        let receipt = match tokio::fs::read_to_string(RECEIPT_LOCATION).await {
+            Ok(receipt) => {
+                let plan: InstallPlan =
+                    serde_json::from_str(&receipt).wrap_err("Parsing receipt")?;
+                if let Err(err) = plan.check_compatible() {
+                    eprintln!("{}", err.red());
+                    return Ok(ExitCode::FAILURE);
+                }
+                if let Err(err) = plan.check_host().await {
+                    if ignore_host_mismatch {
+                        tracing::warn!("{err}");
+                    } else {
+                        eprintln!("{}", err.red());
+                        return Ok(ExitCode::FAILURE);
+                    }
+                }
+                Some(plan)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).wrap_err("Reading receipt"),
+        };
+
+        let user = match user {
+            Some(name) => {
+                let user = User::from_name(&name)
+                    .wrap_err_with(|| format!("Looking up user `{name}`"))?
+                    .ok_or_else(|| eyre!("No user named `{name}`"))?;
+                Some(Owner {
+                    uid: user.uid.as_raw(),
+                    gid: user.gid.as_raw(),
+                })
+            },
+            None => None,
+        };
+
+        let conversion = match Conversion::plan(receipt, to, user, settings).await {
+            Ok(conversion) => conversion,
+            Err(err) => {
+                if let Some(expected) = err.expected() {
+                    eprintln!("{}", expected.red());
+                    return Ok(ExitCode::FAILURE);
+                }
+                return Err(err)?;
+            },
+        };
+
+        if !no_confirm {
+            let mut currently_explaining = explain;
+            loop {
+                match interaction::prompt(
+                    conversion.describe(currently_explaining),
+                    PromptChoice::Yes,
+                    currently_explaining,
+                )
+                .await?
+                {
+                    PromptChoice::Yes => break,
+                    PromptChoice::Explain => currently_explaining = true,
+                    PromptChoice::No => {
+                        interaction::clean_exit_with_message("Okay, didn't do anything! Bye!").await
+                    },
+                }
+            }
+        }
+
+        let store = conversion.store;
+        let (_tx, rx) = signal_channel().await?;
+        match conversion.convert(rx).await {
+            Ok(_) => (),
+            Err(err @ NixInstallerError::ActionRevert(_)) => {
+                tracing::error!(
+                    "Reverting the actions of the current mode failed, the store was not converted"
+                );
+                return Err(err)?;
+            },
+            Err(err) => {
+                if let Some(expected) = err.expected() {
+                    eprintln!("{}", expected.red());
+                    return Ok(ExitCode::FAILURE);
+                }
+                return Err(err)?;
+            },
+        }
+
+        println!("{}\n", format!("Nix was converted to {to}!").green().bold());
+        if to == InstallMode::MultiUser {
+            let hooks = User::from_uid(Uid::from_raw(store.uid))
+                .ok()
+                .flatten()
+                .map(|user| single_user_hooks(&user.dir))
+                .unwrap_or_default();
+            if !hooks.is_empty() {
+                println!(
+                    "{}\n{}\n",
+                    "These still load the single-user `nix.sh`, remove those lines as the system shell profile now sets Nix up:".bold(),
+                    hooks
+                        .iter()
+                        .map(|hook| format!("* `{}`", hook.display()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+            }
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
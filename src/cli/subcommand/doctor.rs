@@ -0,0 +1,1138 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::IsTerminal,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::Parser;
+use eyre::WrapErr;
+use owo_colors::OwoColorize;
+use tokio::process::Command;
+
+use crate::{
+    action::base::NIX_CA_BUNDLE_NAME,
+    cli::{
+        interaction::{self, PromptChoice},
+        CommandExecute,
+    },
+    env,
+    os::{
+        home_ownership,
+        user_environment::{self, UserEnvironment},
+    },
+    plan::RECEIPT_LOCATION,
+    planner::ShellProfileLocations,
+    settings::NIX_CONF_DIR,
+    InstallPlan,
+};
+
+const NIX_DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+const PROFILE_NIX_FILE_SHELL: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh";
+const PROFILE_NIX_FILE_FISH: &str = "/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish";
+/// The bundles `nix-daemon.sh` falls back to when `NIX_SSL_CERT_FILE` is unset
+const DEFAULT_SSL_CERT_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/certs/ca-bundle.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/nix/var/nix/profiles/default/etc/ssl/certs/ca-bundle.crt",
+];
+
+/**
+Explain common problems with an existing install, and how to fix them
+
+Run this as the user having trouble, not under `sudo`, so it sees their shell and `PATH`. Only
+`--fix` needs `sudo`.
+*/
+#[derive(Debug, Parser)]
+pub struct Doctor {
+    /// The receipt describing what was installed
+    #[clap(long, default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+    /// Give Nix dotfiles in the home directory which are owned by `root` back to their user
+    #[clap(long, default_value = "false")]
+    pub fix: bool,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for Doctor {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { receipt, fix } = self;
+
+        let plan = match tokio::fs::read_to_string(&receipt).await {
+            Ok(receipt) => {
+                Some(serde_json::from_str::<InstallPlan>(&receipt).wrap_err("Parsing receipt")?)
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!(
+                    "{}",
+                    format!(
+                        "No receipt at `{}`, checking against a default install",
+                        receipt.display()
+                    )
+                    .yellow()
+                );
+                None
+            },
+            Err(err) => return Err(err).wrap_err("Reading receipt"),
+        };
+
+        if let Some(summary) = plan.as_ref().and_then(install_summary) {
+            println!("{summary}");
+        }
+
+        let expectations = Expectations::new(plan.as_ref())?;
+        let observations = Observations::gather(&expectations).await;
+        if let Some(layering) = layering(&observations) {
+            println!("{layering}");
+        }
+        let findings = diagnose(&expectations, &observations);
+
+        if findings.is_empty() {
+            println!("{}", "No known problems found".green());
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        for (index, finding) in findings.iter().enumerate() {
+            println!("{}", finding.display(index + 1));
+        }
+
+        if fix && !observations.root_owned_dotfiles.is_empty() {
+            return fix_root_owned_dotfiles(&observations.root_owned_dotfiles);
+        }
+
+        if findings.iter().any(|finding| finding.repairable) && std::io::stdin().is_terminal() {
+            let question = "Some of these can be fixed by running `nix-installer repair`";
+            if interaction::prompt(question, PromptChoice::Yes, true).await? == PromptChoice::Yes {
+                return repair().await;
+            }
+        }
+
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// The id of the install and when it happened, to correlate with logs and diagnostics
+fn install_summary(plan: &InstallPlan) -> Option<String> {
+    let mut summary = format!("Install `{}`", plan.install_id?);
+    if let Some(planned_at) = plan.planned_at {
+        summary += &format!(", planned at {planned_at}");
+    }
+    if let Some(installed_at) = plan.installed_at {
+        summary += &format!(", installed at {installed_at}");
+    }
+    Some(summary)
+}
+
+async fn repair() -> eyre::Result<ExitCode> {
+    let current_exe = std::env::current_exe().wrap_err("Finding the current executable")?;
+    let mut command = if nix::unistd::Uid::effective().is_root() {
+        Command::new(current_exe)
+    } else {
+        let mut command = Command::new("sudo");
+        command.arg(current_exe);
+        command
+    };
+    let status = command
+        .arg("repair")
+        .status()
+        .await
+        .wrap_err("Running `nix-installer repair`")?;
+    Ok(if status.success() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn fix_root_owned_dotfiles(paths: &[PathBuf]) -> eyre::Result<ExitCode> {
+    if !nix::unistd::Uid::effective().is_root() {
+        eprintln!(
+            "{}",
+            "Changing the owner of files owned by `root` needs `root`, run `sudo nix-installer doctor --fix`"
+                .red()
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    let user = home_ownership::target_user()
+        .ok_or_else(|| eyre::eyre!("Could not find the user whose home to fix"))?;
+    let changed = home_ownership::fix(&user.dir, paths, user.uid.as_raw(), user.gid.as_raw())
+        .wrap_err("Fixing the owner of Nix dotfiles")?;
+    println!(
+        "{}",
+        format!("Gave {changed} entries back to `{}`", user.name).green()
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+/// What an install should have set up, from its receipt if there is one
+#[derive(Debug, Clone)]
+pub(crate) struct Expectations {
+    /// The shell profiles were modified to put Nix on the `PATH`
+    modify_profile: bool,
+    /// The init system managing the daemon, `None` when no daemon is started
+    init: Option<Init>,
+    /// The CA bundle the install was told to use
+    ssl_cert_file: Option<PathBuf>,
+    /// The APFS volume holding `/nix` on macOS
+    volume_label: Option<String>,
+    /// Where `nix.conf` was written
+    nix_conf_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Init {
+    Systemd,
+    Launchd,
+}
+
+impl Expectations {
+    fn new(plan: Option<&InstallPlan>) -> eyre::Result<Self> {
+        let Some(plan) = plan else {
+            return Ok(Self::guess());
+        };
+        let settings = plan
+            .planner
+            .settings()
+            .wrap_err("Reading settings from the receipt")?;
+        Ok(Self::from_settings(&settings))
+    }
+
+    fn from_settings(settings: &HashMap<String, serde_json::Value>) -> Self {
+        let init = match settings.get("init").and_then(|init| init.as_str()) {
+            Some(init) if init.eq_ignore_ascii_case("systemd") => Some(Init::Systemd),
+            Some(init) if init.eq_ignore_ascii_case("launchd") => Some(Init::Launchd),
+            // The macOS planner always uses launchd and does not record it
+            None if settings.contains_key("volume_label") => Some(Init::Launchd),
+            _ => None,
+        };
+        Self {
+            modify_profile: settings
+                .get("modify_profile")
+                .and_then(|modify_profile| modify_profile.as_bool())
+                .unwrap_or(true),
+            init,
+            ssl_cert_file: settings
+                .get("ssl_cert_file")
+                .and_then(|ssl_cert_file| ssl_cert_file.as_str())
+                .map(PathBuf::from),
+            volume_label: settings
+                .get("volume_label")
+                .and_then(|volume_label| volume_label.as_str())
+                .map(ToString::to_string),
+            nix_conf_dir: settings
+                .get("nix_conf_dir")
+                .and_then(|nix_conf_dir| nix_conf_dir.as_str())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(NIX_CONF_DIR)),
+        }
+    }
+
+    fn guess() -> Self {
+        let (init, volume_label) = if cfg!(target_os = "macos") {
+            (Some(Init::Launchd), Some("Nix Store".to_string()))
+        } else if Path::new("/run/systemd/system").exists() {
+            (Some(Init::Systemd), None)
+        } else {
+            (None, None)
+        };
+        Self {
+            modify_profile: true,
+            init,
+            ssl_cert_file: None,
+            volume_label,
+            nix_conf_dir: PathBuf::from(NIX_CONF_DIR),
+        }
+    }
+}
+
+/// What was found on the system, gathered up front so probes are plain functions
+#[derive(Debug, Clone)]
+pub(crate) struct Observations {
+    /// `nix` is on this user's `PATH`
+    nix_on_path: bool,
+    /// The name of this user's login shell, like `zsh`
+    shell: Option<String>,
+    /// The shells with a system profile sourcing Nix
+    profiles_sourcing_nix: BTreeSet<&'static str>,
+    /// Whether the daemon is running, `None` when there is no init system to ask
+    daemon_running: Option<bool>,
+    daemon_socket: SocketState,
+    ssl_cert_file: Option<SslCertFile>,
+    nix_mount: NixMount,
+    /// Nix dotfiles in the user's home which are owned by `root`
+    root_owned_dotfiles: Vec<PathBuf>,
+    /// home-manager or direnv setting up the user's shells after the system hook
+    user_environment: UserEnvironment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SocketState {
+    Reachable,
+    Missing,
+    PermissionDenied,
+    Refused,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SslCertFile {
+    /// Where the path came from, like `NIX_SSL_CERT_FILE`
+    source: &'static str,
+    path: PathBuf,
+    exists: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NixMount {
+    /// `/nix` does not exist
+    Missing,
+    /// `/nix` is a directory on the root filesystem
+    RootFilesystem,
+    ReadOnly,
+    Mounted,
+}
+
+impl Observations {
+    async fn gather(expectations: &Expectations) -> Self {
+        let shell = env::SHELL.get().and_then(|shell| {
+            Path::new(&shell)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        });
+
+        let daemon_running = match expectations.init {
+            Some(Init::Systemd) => Some(
+                command_succeeds("systemctl", &["is-active", "--quiet", "nix-daemon.socket"]).await
+                    || command_succeeds(
+                        "systemctl",
+                        &["is-active", "--quiet", "nix-daemon.service"],
+                    )
+                    .await,
+            ),
+            Some(Init::Launchd) => {
+                Some(command_succeeds("launchctl", &["print", "system/org.nixos.nix-daemon"]).await)
+            },
+            None => None,
+        };
+
+        let user = home_ownership::target_user();
+
+        Self {
+            nix_on_path: which::which("nix").is_ok(),
+            shell,
+            profiles_sourcing_nix: profiles_sourcing_nix(&ShellProfileLocations::default()).await,
+            daemon_running,
+            daemon_socket: socket_state(Path::new(NIX_DAEMON_SOCKET)),
+            ssl_cert_file: ssl_cert_file(expectations),
+            nix_mount: nix_mount(Path::new("/nix")),
+            root_owned_dotfiles: user
+                .as_ref()
+                .map(|user| home_ownership::scan(&user.dir))
+                .unwrap_or_default(),
+            user_environment: user
+                .as_ref()
+                .map(|user| user_environment::scan(&user.dir, &user.name))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+async fn command_succeeds(program: &str, args: &[&str]) -> bool {
+    crate::command_runner::output(
+        Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await
+    .map(|output| output.status.success())
+    .unwrap_or(false)
+}
+
+async fn profiles_sourcing_nix(locations: &ShellProfileLocations) -> BTreeSet<&'static str> {
+    let fish = locations
+        .fish
+        .confd_prefixes
+        .iter()
+        .map(|prefix| prefix.join(&locations.fish.confd_suffix))
+        .chain(
+            locations
+                .fish
+                .vendor_confd_prefixes
+                .iter()
+                .map(|prefix| prefix.join(&locations.fish.vendor_confd_suffix)),
+        )
+        .collect::<Vec<_>>();
+
+    let mut sourcing = BTreeSet::new();
+    for (shell, profiles, needle) in [
+        ("bash", &locations.bash, PROFILE_NIX_FILE_SHELL),
+        ("zsh", &locations.zsh, PROFILE_NIX_FILE_SHELL),
+        ("fish", &fish, PROFILE_NIX_FILE_FISH),
+    ] {
+        for profile in profiles {
+            if let Ok(contents) = tokio::fs::read(profile).await {
+                if contents
+                    .windows(needle.len())
+                    .any(|window| window == needle.as_bytes())
+                {
+                    sourcing.insert(shell);
+                    break;
+                }
+            }
+        }
+    }
+    sourcing
+}
+
+fn socket_state(socket: &Path) -> SocketState {
+    match std::os::unix::net::UnixStream::connect(socket) {
+        Ok(_) => SocketState::Reachable,
+        Err(err) => match err.kind() {
+            std::io::ErrorKind::NotFound => SocketState::Missing,
+            std::io::ErrorKind::PermissionDenied => SocketState::PermissionDenied,
+            _ => SocketState::Refused,
+        },
+    }
+}
+
+fn ssl_cert_file(expectations: &Expectations) -> Option<SslCertFile> {
+    let (source, path) = if let Some(path) = env::NIX_SSL_CERT_FILE.get_os() {
+        (env::NIX_SSL_CERT_FILE.name, PathBuf::from(path))
+    } else if let Some(path) = &expectations.ssl_cert_file {
+        ("--ssl-cert-file", path.clone())
+    } else {
+        let path = DEFAULT_SSL_CERT_FILES
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())?;
+        ("the system default", path)
+    };
+    Some(SslCertFile {
+        source,
+        exists: path.exists(),
+        path,
+    })
+}
+
+fn nix_mount(nix: &Path) -> NixMount {
+    let (Ok(nix_metadata), Ok(root_metadata)) = (nix.metadata(), Path::new("/").metadata()) else {
+        return NixMount::Missing;
+    };
+    if nix_metadata.dev() == root_metadata.dev() {
+        return NixMount::RootFilesystem;
+    }
+    match nix::sys::statvfs::statvfs(nix) {
+        Ok(stat) if stat.flags().contains(nix::sys::statvfs::FsFlags::ST_RDONLY) => {
+            NixMount::ReadOnly
+        },
+        _ => NixMount::Mounted,
+    }
+}
+
+/// How urgent a [`Finding`] is, findings are listed most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Severity {
+    /// Nix cannot be used at all
+    Critical,
+    /// Some of Nix does not work
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Critical => write!(f, "critical"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Finding {
+    /// The [`Probe::name`] which found this
+    probe: &'static str,
+    severity: Severity,
+    /// The symptom the user is likely seeing
+    summary: String,
+    explanation: String,
+    /// Commands which fix the problem
+    fixes: Vec<String>,
+    /// `nix-installer repair` fixes the problem
+    repairable: bool,
+}
+
+impl Finding {
+    fn display(&self, rank: usize) -> String {
+        let severity = match self.severity {
+            Severity::Critical => self.severity.to_string().red().to_string(),
+            Severity::Warning => self.severity.to_string().yellow().to_string(),
+        };
+        let mut buf = format!(
+            "{rank}. [{severity}] {}\n   {}\n",
+            self.summary.bold(),
+            self.explanation
+        );
+        if !self.fixes.is_empty() {
+            buf.push_str("   To fix it:\n");
+            for fix in &self.fixes {
+                buf.push_str(&format!("     {}\n", fix.cyan()));
+            }
+        }
+        buf
+    }
+}
+
+/// A check for one known failure signature
+pub(crate) struct Probe {
+    name: &'static str,
+    check: fn(&Expectations, &Observations) -> Option<Finding>,
+}
+
+/// Each known failure signature, add new ones here
+const PROBES: &[Probe] = &[
+    Probe {
+        name: "nix_volume_unmounted",
+        check: nix_volume_unmounted,
+    },
+    Probe {
+        name: "daemon_not_running",
+        check: daemon_not_running,
+    },
+    Probe {
+        name: "daemon_socket_unreachable",
+        check: daemon_socket_unreachable,
+    },
+    Probe {
+        name: "shell_profile_missing",
+        check: shell_profile_missing,
+    },
+    Probe {
+        name: "command_not_found",
+        check: command_not_found,
+    },
+    Probe {
+        name: "ssl_cert_file_missing",
+        check: ssl_cert_file_missing,
+    },
+    Probe {
+        name: "root_owned_dotfiles",
+        check: root_owned_dotfiles,
+    },
+    Probe {
+        name: "home_manager_ssl_cert_file",
+        check: home_manager_ssl_cert_file,
+    },
+    Probe {
+        name: "home_manager_nix_path",
+        check: home_manager_nix_path,
+    },
+];
+
+/// Run every [`Probe`], returning what they found ranked by [`Severity`]
+pub(crate) fn diagnose(expectations: &Expectations, observations: &Observations) -> Vec<Finding> {
+    let mut findings = PROBES
+        .iter()
+        .filter_map(|probe| {
+            (probe.check)(expectations, observations).map(|finding| Finding {
+                probe: probe.name,
+                ..finding
+            })
+        })
+        .collect::<Vec<_>>();
+    findings.sort_by_key(|finding| finding.severity);
+    findings
+}
+
+fn finding(
+    severity: Severity,
+    summary: impl Into<String>,
+    explanation: impl Into<String>,
+) -> Finding {
+    Finding {
+        probe: "",
+        severity,
+        summary: summary.into(),
+        explanation: explanation.into(),
+        fixes: vec![],
+        repairable: false,
+    }
+}
+
+fn nix_volume_unmounted(
+    expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    let volume_label = expectations.volume_label.as_ref()?;
+    let explanation = match observations.nix_mount {
+        NixMount::Mounted => return None,
+        NixMount::ReadOnly => format!("The `{volume_label}` volume is mounted on `/nix` read only"),
+        NixMount::Missing | NixMount::RootFilesystem => {
+            format!("The `{volume_label}` volume is not mounted on `/nix`, which is read only without it")
+        },
+    };
+    Some(Finding {
+        fixes: vec![
+            "sudo launchctl kickstart -k system/org.nixos.darwin-store".into(),
+            format!("sudo diskutil mount -mountPoint /nix \"{volume_label}\""),
+        ],
+        ..finding(Severity::Critical, "Read-only file system", explanation)
+    })
+}
+
+fn daemon_not_running(expectations: &Expectations, observations: &Observations) -> Option<Finding> {
+    if observations.daemon_running != Some(false) {
+        return None;
+    }
+    let fixes = match expectations.init? {
+        Init::Systemd => vec![
+            "sudo systemctl enable --now nix-daemon.socket".into(),
+            "sudo systemctl restart nix-daemon.service".into(),
+        ],
+        Init::Launchd => vec![
+            "sudo launchctl bootstrap system /Library/LaunchDaemons/org.nixos.nix-daemon.plist"
+                .into(),
+            "sudo launchctl kickstart -k system/org.nixos.nix-daemon".into(),
+        ],
+    };
+    Some(Finding {
+        fixes,
+        ..finding(
+            Severity::Critical,
+            "Cannot connect to the Nix daemon",
+            "The Nix daemon is not running, so commands run by users other than `root` fail",
+        )
+    })
+}
+
+fn daemon_socket_unreachable(
+    expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    // A stopped daemon is reported by `daemon_not_running`
+    if expectations.init.is_none() || observations.daemon_running == Some(false) {
+        return None;
+    }
+    let restart = match expectations.init? {
+        Init::Systemd => "sudo systemctl restart nix-daemon.socket nix-daemon.service",
+        Init::Launchd => "sudo launchctl kickstart -k system/org.nixos.nix-daemon",
+    };
+    match observations.daemon_socket {
+        SocketState::Reachable => None,
+        SocketState::PermissionDenied => Some(Finding {
+            fixes: vec![
+                format!("ls -l {NIX_DAEMON_SOCKET}  # expect srw-rw-rw-"),
+                format!(
+                    "grep allowed-users {}",
+                    expectations.nix_conf_dir.join("nix.conf").display()
+                ),
+                restart.into(),
+            ],
+            ..finding(
+                Severity::Critical,
+                "Cannot connect to the Nix daemon: permission denied",
+                format!("This user may not open `{NIX_DAEMON_SOCKET}`, check its permissions and `allowed-users`"),
+            )
+        }),
+        SocketState::Missing | SocketState::Refused => Some(Finding {
+            fixes: vec![restart.into()],
+            ..finding(
+                Severity::Critical,
+                "Cannot connect to the Nix daemon",
+                format!("The daemon is running but `{NIX_DAEMON_SOCKET}` is missing or stale"),
+            )
+        }),
+    }
+}
+
+fn shell_profile_missing(
+    expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    if !expectations.modify_profile || !observations.profiles_sourcing_nix.is_empty() {
+        return None;
+    }
+    Some(Finding {
+        fixes: vec!["sudo nix-installer repair".into()],
+        repairable: true,
+        ..finding(
+            Severity::Critical,
+            "nix: command not found",
+            "None of the system shell profiles source Nix anymore, often after an OS upgrade replaced them",
+        )
+    })
+}
+
+fn command_not_found(expectations: &Expectations, observations: &Observations) -> Option<Finding> {
+    if observations.nix_on_path {
+        return None;
+    }
+    // A missing profile is reported by `shell_profile_missing`
+    if expectations.modify_profile && observations.profiles_sourcing_nix.is_empty() {
+        return None;
+    }
+    let shell = observations.shell.as_deref().unwrap_or("sh");
+    let profile = if shell == "fish" {
+        PROFILE_NIX_FILE_FISH
+    } else {
+        PROFILE_NIX_FILE_SHELL
+    };
+    let explanation = if observations.profiles_sourcing_nix.contains(shell) {
+        "This shell was started before Nix was installed, or does not read the system profile"
+            .to_string()
+    } else {
+        format!(
+            "Nix is set up for {} but this user's shell is `{shell}`",
+            observations
+                .profiles_sourcing_nix
+                .iter()
+                .map(|shell| format!("`{shell}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    };
+    Some(Finding {
+        fixes: vec![format!(". {profile}  # or open a new terminal")],
+        ..finding(Severity::Warning, "nix: command not found", explanation)
+    })
+}
+
+fn ssl_cert_file_missing(
+    _expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    let summary = "SSL peer certificate or SSH remote key was not OK";
+    match &observations.ssl_cert_file {
+        Some(SslCertFile { exists: true, .. }) => None,
+        Some(SslCertFile { source, path, .. }) => Some(Finding {
+            fixes: vec![
+                "export NIX_SSL_CERT_FILE=/path/to/ca-bundle.crt".into(),
+                "sudo nix-installer install --ssl-cert-file /path/to/ca-bundle.crt".into(),
+            ],
+            ..finding(
+                Severity::Warning,
+                summary,
+                format!(
+                    "The CA bundle `{}` from {source} does not exist",
+                    path.display()
+                ),
+            )
+        }),
+        None => Some(Finding {
+            fixes: vec!["export NIX_SSL_CERT_FILE=/path/to/ca-bundle.crt".into()],
+            ..finding(
+                Severity::Warning,
+                summary,
+                "No CA bundle was found, install your distribution's `ca-certificates` package",
+            )
+        }),
+    }
+}
+
+fn root_owned_dotfiles(
+    _expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    if observations.root_owned_dotfiles.is_empty() {
+        return None;
+    }
+    let paths = observations
+        .root_owned_dotfiles
+        .iter()
+        .map(|path| format!("`{}`", path.display()))
+        .collect::<Vec<_>>();
+    Some(Finding {
+        fixes: vec![
+            "sudo nix-installer doctor --fix".into(),
+            format!("sudo chown -R $USER {}", paths.join(" ").replace('`', "")),
+        ],
+        ..finding(
+            Severity::Warning,
+            "Permission denied",
+            format!(
+                "{} {} owned by `root`, usually left behind by running `nix` under `sudo`",
+                paths.join(", "),
+                if paths.len() == 1 { "is" } else { "are" },
+            ),
+        )
+    })
+}
+
+/// The order shells are set up in when user level managers run after the system hook, later ones winning
+fn layering(observations: &Observations) -> Option<String> {
+    let environment = &observations.user_environment;
+    if environment.is_empty() {
+        return None;
+    }
+    let mut layers = vec![
+        "The system hook puts Nix on `PATH`, and sets `NIX_SSL_CERT_FILE` and `NIX_CONF_DIR` unless they are set"
+            .to_string(),
+    ];
+    if let Some(home_manager) = &environment.home_manager {
+        layers.push(format!(
+            "home-manager's `{}` replaces what it exports",
+            home_manager.path.display()
+        ));
+    }
+    if !environment.direnv_hooks.is_empty() {
+        layers.push(format!(
+            "direnv (and devenv through it), hooked from {}, changes it at each prompt in a directory with an `.envrc`",
+            environment
+                .direnv_hooks
+                .iter()
+                .map(|path| format!("`{}`", path.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    let mut buf = "Shells are set up in this order, later steps winning:\n".to_string();
+    for (index, layer) in layers.iter().enumerate() {
+        buf.push_str(&format!("  {}. {layer}\n", index + 1));
+    }
+    Some(buf)
+}
+
+fn home_manager_ssl_cert_file(
+    expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    let home_manager = observations.user_environment.home_manager.as_ref()?;
+    let exported = Path::new(home_manager.exports.get("NIX_SSL_CERT_FILE")?);
+    let configured = expectations.ssl_cert_file.as_deref()?;
+    // `--ssl-cert-file` may have been combined into a bundle next to `nix.conf`
+    if exported == configured || exported == expectations.nix_conf_dir.join(NIX_CA_BUNDLE_NAME) {
+        return None;
+    }
+    Some(Finding {
+        fixes: vec![
+            "home-manager switch  # after removing `NIX_SSL_CERT_FILE` from `home.sessionVariables`"
+                .into(),
+            format!(
+                "sudo nix-installer install --ssl-cert-file {}",
+                exported.display()
+            ),
+        ],
+        ..finding(
+            Severity::Warning,
+            "SSL peer certificate or SSH remote key was not OK",
+            format!(
+                "home-manager sets `NIX_SSL_CERT_FILE` to `{}` in `{}`, but the daemon uses `{}`, so shells and the daemon trust different CAs",
+                exported.display(),
+                home_manager.path.display(),
+                configured.display(),
+            ),
+        )
+    })
+}
+
+fn home_manager_nix_path(
+    _expectations: &Expectations,
+    observations: &Observations,
+) -> Option<Finding> {
+    let home_manager = observations.user_environment.home_manager.as_ref()?;
+    let nix_path = home_manager.exports.get("NIX_PATH")?;
+    Some(Finding {
+        fixes: vec![
+            "home-manager switch  # after removing `NIX_PATH` from `home.sessionVariables`".into(),
+        ],
+        ..finding(
+            Severity::Warning,
+            "`<nixpkgs>` is not the `nixpkgs` flake",
+            format!(
+                "home-manager sets `NIX_PATH` to `{nix_path}` in `{}`, which is searched before the `nix-path` of `nix.conf` (`nixpkgs=flake:nixpkgs` at install)",
+                home_manager.path.display(),
+            ),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expected() -> Expectations {
+        Expectations {
+            modify_profile: true,
+            init: Some(Init::Systemd),
+            ssl_cert_file: None,
+            volume_label: None,
+            nix_conf_dir: PathBuf::from(NIX_CONF_DIR),
+        }
+    }
+
+    fn healthy() -> Observations {
+        Observations {
+            nix_on_path: true,
+            shell: Some("bash".into()),
+            profiles_sourcing_nix: BTreeSet::from(["bash", "zsh"]),
+            daemon_running: Some(true),
+            daemon_socket: SocketState::Reachable,
+            ssl_cert_file: Some(SslCertFile {
+                source: "the system default",
+                path: "/etc/ssl/certs/ca-certificates.crt".into(),
+                exists: true,
+            }),
+            nix_mount: NixMount::RootFilesystem,
+            root_owned_dotfiles: vec![],
+            user_environment: UserEnvironment::default(),
+        }
+    }
+
+    fn home_manager(fixture: &str) -> eyre::Result<UserEnvironment> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/home-manager")
+            .join(fixture);
+        Ok(UserEnvironment {
+            home_manager: Some(user_environment::SessionVariables {
+                exports: user_environment::parse_exports(&std::fs::read_to_string(&path)?),
+                path,
+            }),
+            direnv_hooks: vec![],
+        })
+    }
+
+    fn probes(findings: &[Finding]) -> Vec<&'static str> {
+        findings.iter().map(|finding| finding.probe).collect()
+    }
+
+    #[test]
+    fn healthy_install_has_no_findings() {
+        assert_eq!(diagnose(&expected(), &healthy()), vec![]);
+    }
+
+    #[test]
+    fn nix_volume_unmounted() {
+        let expectations = Expectations {
+            init: Some(Init::Launchd),
+            volume_label: Some("Nix Store".into()),
+            ..expected()
+        };
+        let observations = Observations {
+            nix_mount: NixMount::RootFilesystem,
+            ..healthy()
+        };
+        let findings = diagnose(&expectations, &observations);
+        assert_eq!(probes(&findings), vec!["nix_volume_unmounted"]);
+        assert!(findings[0].fixes[1].contains("\"Nix Store\""));
+
+        let observations = Observations {
+            nix_mount: NixMount::Mounted,
+            ..healthy()
+        };
+        assert_eq!(diagnose(&expectations, &observations), vec![]);
+    }
+
+    #[test]
+    fn daemon_not_running() {
+        let observations = Observations {
+            daemon_running: Some(false),
+            daemon_socket: SocketState::Missing,
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        // The missing socket is a consequence, not reported on its own
+        assert_eq!(probes(&findings), vec!["daemon_not_running"]);
+        assert!(findings[0].fixes[0].contains("systemctl"));
+    }
+
+    #[test]
+    fn daemon_socket_unreachable() {
+        let observations = Observations {
+            daemon_socket: SocketState::PermissionDenied,
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        assert_eq!(probes(&findings), vec!["daemon_socket_unreachable"]);
+        assert!(findings[0].summary.contains("permission denied"));
+
+        // Without a daemon there is no socket to connect to
+        let expectations = Expectations {
+            init: None,
+            ..expected()
+        };
+        let observations = Observations {
+            daemon_running: None,
+            daemon_socket: SocketState::Missing,
+            ..healthy()
+        };
+        assert_eq!(diagnose(&expectations, &observations), vec![]);
+    }
+
+    #[test]
+    fn shell_profile_missing() {
+        let observations = Observations {
+            nix_on_path: false,
+            profiles_sourcing_nix: BTreeSet::new(),
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        assert_eq!(probes(&findings), vec!["shell_profile_missing"]);
+        assert!(findings[0].repairable);
+
+        let expectations = Expectations {
+            modify_profile: false,
+            ..expected()
+        };
+        let findings = diagnose(&expectations, &observations);
+        assert_eq!(probes(&findings), vec!["command_not_found"]);
+    }
+
+    #[test]
+    fn command_not_found() {
+        let observations = Observations {
+            nix_on_path: false,
+            shell: Some("fish".into()),
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        assert_eq!(probes(&findings), vec!["command_not_found"]);
+        assert!(findings[0].explanation.contains("`fish`"));
+        assert!(findings[0].fixes[0].contains(PROFILE_NIX_FILE_FISH));
+        assert!(!findings[0].repairable);
+    }
+
+    #[test]
+    fn ssl_cert_file_missing() {
+        let observations = Observations {
+            ssl_cert_file: Some(SslCertFile {
+                source: "NIX_SSL_CERT_FILE",
+                path: "/nonexistent/ca-bundle.crt".into(),
+                exists: false,
+            }),
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        assert_eq!(probes(&findings), vec!["ssl_cert_file_missing"]);
+        assert!(findings[0].explanation.contains("NIX_SSL_CERT_FILE"));
+
+        let observations = Observations {
+            ssl_cert_file: None,
+            ..healthy()
+        };
+        assert_eq!(
+            probes(&diagnose(&expected(), &observations)),
+            vec!["ssl_cert_file_missing"]
+        );
+    }
+
+    #[test]
+    fn root_owned_dotfiles() {
+        let observations = Observations {
+            root_owned_dotfiles: vec![
+                "/home/alice/.cache/nix".into(),
+                "/home/alice/.nix-profile".into(),
+            ],
+            ..healthy()
+        };
+        let findings = diagnose(&expected(), &observations);
+        assert_eq!(probes(&findings), vec!["root_owned_dotfiles"]);
+        assert!(findings[0]
+            .explanation
+            .starts_with("`/home/alice/.cache/nix`, `/home/alice/.nix-profile` are owned"));
+        assert_eq!(findings[0].fixes[0], "sudo nix-installer doctor --fix");
+    }
+
+    #[test]
+    fn home_manager_conflicts() -> eyre::Result<()> {
+        let expectations = Expectations {
+            ssl_cert_file: Some("/etc/ssl/corp-ca.pem".into()),
+            ..expected()
+        };
+        let observations = Observations {
+            user_environment: home_manager("hm-session-vars.sh")?,
+            ..healthy()
+        };
+        let findings = diagnose(&expectations, &observations);
+        assert_eq!(
+            probes(&findings),
+            vec!["home_manager_ssl_cert_file", "home_manager_nix_path"]
+        );
+        assert!(findings[0]
+            .explanation
+            .contains("`/etc/pki/tls/certs/ca-bundle.crt`"));
+        assert!(findings[1].explanation.contains("nixpkgs=/home/alice"));
+
+        // The same bundle, or none given at install, is no conflict
+        for ssl_cert_file in [Some("/etc/pki/tls/certs/ca-bundle.crt".into()), None] {
+            let expectations = Expectations {
+                ssl_cert_file,
+                ..expected()
+            };
+            assert_eq!(
+                probes(&diagnose(&expectations, &observations)),
+                vec!["home_manager_nix_path"]
+            );
+        }
+
+        let observations = Observations {
+            user_environment: home_manager("hm-session-vars-without-nix.sh")?,
+            ..healthy()
+        };
+        assert_eq!(diagnose(&expectations, &observations), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn explains_layering() -> eyre::Result<()> {
+        assert_eq!(layering(&healthy()), None);
+
+        let mut user_environment = home_manager("hm-session-vars-without-nix.sh")?;
+        user_environment.direnv_hooks = vec!["/home/alice/.bashrc".into()];
+        let layering = layering(&Observations {
+            user_environment,
+            ..healthy()
+        })
+        .expect("Layering is explained");
+        let steps = layering.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(steps.len(), 3, "{layering}");
+        assert!(steps[0].starts_with("  1. The system hook"));
+        assert!(steps[1].contains("hm-session-vars-without-nix.sh"));
+        assert!(steps[2].contains("`/home/alice/.bashrc`"));
+        Ok(())
+    }
+
+    #[test]
+    fn findings_are_ranked_by_severity() {
+        let observations = Observations {
+            ssl_cert_file: None,
+            daemon_running: Some(false),
+            ..healthy()
+        };
+        assert_eq!(
+            probes(&diagnose(&expected(), &observations)),
+            vec!["daemon_not_running", "ssl_cert_file_missing"]
+        );
+    }
+
+    #[test]
+    fn expectations_from_receipt_settings() {
+        let settings = HashMap::from([
+            ("modify_profile".to_string(), serde_json::json!(false)),
+            ("init".to_string(), serde_json::json!("Systemd")),
+            (
+                "ssl_cert_file".to_string(),
+                serde_json::json!("/etc/ca.crt"),
+            ),
+        ]);
+        let expectations = Expectations::from_settings(&settings);
+        assert!(!expectations.modify_profile);
+        assert_eq!(expectations.init, Some(Init::Systemd));
+        assert_eq!(
+            expectations.ssl_cert_file,
+            Some(PathBuf::from("/etc/ca.crt"))
+        );
+
+        assert_eq!(expectations.nix_conf_dir, PathBuf::from("/etc/nix"));
+
+        let settings =
+            HashMap::from([("volume_label".to_string(), serde_json::json!("Nix Store"))]);
+        assert_eq!(
+            Expectations::from_settings(&settings).init,
+            Some(Init::Launchd)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{ArgAction, Parser};
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{cli::CommandExecute, plan::RECEIPT_LOCATION, InstallPlan};
+
+/// The unit which installs Nix from the plan on first boot
+const INSTALL_UNIT_NAME: &str = "nix-installer-first-boot.service";
+/// The unit which uninstalls Nix when started, for deprovisioning
+const UNINSTALL_UNIT_NAME: &str = "nix-installer-uninstall.service";
+/// The Nix systems release tarballs are built for, as they appear in their names
+const NIX_SYSTEMS: &[&str] = &[
+    "x86_64-linux",
+    "aarch64-linux",
+    "i686-linux",
+    "x86_64-darwin",
+    "aarch64-darwin",
+];
+
+/**
+Generate a systemd unit which installs Nix from a plan on first boot
+
+For images which ship `nix-installer` and a plan (from `nix-installer plan`): the unit installs
+once, is skipped whenever a receipt exists, and disables itself afterwards. Enabling it is left to
+the image build, eg. `systemctl enable nix-installer-first-boot.service`.
+*/
+#[derive(Debug, Parser)]
+pub struct GenerateFirstBootUnit {
+    /// The plan to install, as it will be found in the image
+    #[clap(long)]
+    pub plan: PathBuf,
+
+    /// Where to write the units
+    #[clap(long, default_value = "/etc/systemd/system")]
+    pub out_dir: PathBuf,
+
+    /// The `nix-installer` the unit runs, as it will be found in the image (default: this executable)
+    #[clap(long)]
+    pub installer: Option<PathBuf>,
+
+    /// Also write `nix-installer-uninstall.service`, which uninstalls Nix when started
+    #[clap(long, action(ArgAction::SetTrue), default_value = "false")]
+    pub uninstall_unit: bool,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for GenerateFirstBootUnit {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            plan,
+            out_dir,
+            installer,
+            uninstall_unit,
+        } = self;
+
+        let plan = plan
+            .canonicalize()
+            .wrap_err_with(|| format!("Finding the plan `{}`", plan.display()))?;
+        let plan_json = tokio::fs::read_to_string(&plan)
+            .await
+            .wrap_err("Reading plan")?;
+        let plan_value: serde_json::Value =
+            serde_json::from_str(&plan_json).wrap_err("Parsing plan")?;
+        let install_plan: InstallPlan =
+            serde_json::from_value(plan_value.clone()).wrap_err("Parsing plan")?;
+        install_plan.check_compatible()?;
+        check_platform(&install_plan, &plan_value)?;
+
+        let installer = match installer {
+            Some(installer) => installer,
+            None => std::env::current_exe().wrap_err("Finding the current executable")?,
+        };
+
+        let mut units = vec![(
+            INSTALL_UNIT_NAME,
+            render_install_unit(&installer, &plan, plan_needs_network(&plan_value)),
+        )];
+        if uninstall_unit {
+            units.push((UNINSTALL_UNIT_NAME, render_uninstall_unit()));
+        }
+
+        tokio::fs::create_dir_all(&out_dir)
+            .await
+            .wrap_err_with(|| format!("Creating `{}`", out_dir.display()))?;
+        for (name, unit) in units {
+            let path = out_dir.join(name);
+            tokio::fs::write(&path, unit)
+                .await
+                .wrap_err_with(|| format!("Writing `{}`", path.display()))?;
+            println!("{}", path.display());
+        }
+
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// The plan must be for a systemd based Linux planner, and fetch Nix for this machine's architecture
+fn check_platform(plan: &InstallPlan, plan_value: &serde_json::Value) -> eyre::Result<()> {
+    let planner = plan.planner.typetag_name();
+    if !matches!(planner, "linux" | "steam-deck" | "ostree") {
+        return Err(eyre!(
+            "Plan uses the `{planner}` planner, first boot units are only supported on Linux"
+        ));
+    }
+
+    let host_system = format!("{}-linux", target_lexicon::HOST.architecture);
+    match plan_system(plan_value) {
+        Some(system) if system != host_system => Err(eyre!(
+            "Plan fetches Nix for `{system}`, but this machine is `{host_system}`"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Every Nix tarball the plan fetches, as URLs or paths
+fn plan_tarballs(value: &serde_json::Value) -> Vec<&str> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut found = match map.get("url_or_path") {
+                Some(serde_json::Value::Object(url_or_path)) => url_or_path
+                    .values()
+                    .filter_map(serde_json::Value::as_str)
+                    .collect(),
+                _ => vec![],
+            };
+            for (key, value) in map {
+                if key != "url_or_path" {
+                    found.extend(plan_tarballs(value));
+                }
+            }
+            found
+        },
+        serde_json::Value::Array(values) => values.iter().flat_map(plan_tarballs).collect(),
+        _ => vec![],
+    }
+}
+
+/// The Nix system of the first tarball the plan fetches, if its name says
+fn plan_system(value: &serde_json::Value) -> Option<&'static str> {
+    plan_tarballs(value).into_iter().find_map(|tarball| {
+        NIX_SYSTEMS
+            .iter()
+            .find(|system| tarball.contains(*system))
+            .copied()
+    })
+}
+
+/// Whether the plan downloads Nix, rather than using a tarball in the image
+fn plan_needs_network(value: &serde_json::Value) -> bool {
+    plan_tarballs(value)
+        .into_iter()
+        .any(|tarball| tarball.starts_with("https://") || tarball.starts_with("http://"))
+}
+
+fn render_install_unit(installer: &Path, plan: &Path, needs_network: bool) -> String {
+    let network = if needs_network {
+        "Wants=network-online.target\nAfter=network-online.target\n"
+    } else {
+        ""
+    };
+    format!(
+        "\
+        [Unit]\n\
+        Description=Install Nix on first boot\n\
+        ConditionPathExists=!{RECEIPT_LOCATION}\n\
+        {network}\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        RemainAfterExit=yes\n\
+        ExecStart=\"{installer}\" install --no-confirm \"{plan}\"\n\
+        ExecStartPost=systemctl disable {INSTALL_UNIT_NAME}\n\
+        StandardOutput=journal+console\n\
+        NoNewPrivileges=yes\n\
+        PrivateTmp=yes\n\
+        ProtectHome=read-only\n\
+        ProtectClock=yes\n\
+        ProtectHostname=yes\n\
+        RestrictRealtime=yes\n\
+        LockPersonality=yes\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n\
+        ",
+        installer = installer.display(),
+        plan = plan.display(),
+    )
+}
+
+fn render_uninstall_unit() -> String {
+    format!(
+        "\
+        [Unit]\n\
+        Description=Uninstall Nix\n\
+        ConditionPathExists={RECEIPT_LOCATION}\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart=/nix/nix-installer uninstall --no-confirm {RECEIPT_LOCATION}\n\
+        StandardOutput=journal+console\n\
+        NoNewPrivileges=yes\n\
+        PrivateTmp=yes\n\
+        "
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn fixture() -> eyre::Result<serde_json::Value> {
+        let fixture = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/linux/linux.json"
+        ))?;
+        Ok(serde_json::from_str(&fixture)?)
+    }
+
+    #[test]
+    fn renders_install_unit() {
+        let unit = render_install_unit(
+            Path::new("/usr/local/bin/nix-installer"),
+            Path::new("/etc/nix-installer/plan.json"),
+            true,
+        );
+        assert!(unit.contains("ConditionPathExists=!/nix/receipt.json\n"));
+        assert!(unit.contains("Wants=network-online.target\nAfter=network-online.target\n"));
+        assert!(unit.contains(
+            "ExecStart=\"/usr/local/bin/nix-installer\" install --no-confirm \"/etc/nix-installer/plan.json\"\n"
+        ));
+        assert!(unit.contains("ExecStartPost=systemctl disable nix-installer-first-boot.service\n"));
+        assert!(unit.contains("Type=oneshot\n"));
+
+        let offline = render_install_unit(
+            Path::new("/usr/local/bin/nix-installer"),
+            Path::new("/etc/nix-installer/plan.json"),
+            false,
+        );
+        assert!(!offline.contains("network-online.target"));
+    }
+
+    #[test]
+    fn renders_uninstall_unit() {
+        let unit = render_uninstall_unit();
+        assert!(unit.contains("ConditionPathExists=/nix/receipt.json\n"));
+        assert!(unit
+            .contains("ExecStart=/nix/nix-installer uninstall --no-confirm /nix/receipt.json\n"));
+    }
+
+    #[test]
+    fn network_follows_the_tarball() -> eyre::Result<()> {
+        let mut plan = fixture()?;
+        assert!(plan_needs_network(&plan));
+        assert_eq!(plan_system(&plan), Some("x86_64-linux"));
+
+        let fetch_nix = &mut plan["actions"][1]["action"]["fetch_nix"]["action"];
+        fetch_nix["url_or_path"] =
+            serde_json::json!({ "Path": "/opt/nix/nix-2.17.0-aarch64-linux.tar.xz" });
+        assert!(!plan_needs_network(&plan));
+        assert_eq!(plan_system(&plan), Some("aarch64-linux"));
+        Ok(())
+    }
+}
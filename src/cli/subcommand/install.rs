@@ -1,4 +1,5 @@
 use std::{
+    io::IsTerminal,
     os::unix::prelude::PermissionsExt,
     path::{Path, PathBuf},
     process::ExitCode,
@@ -9,12 +10,15 @@ use crate::{
     cli::{
         ensure_root,
         interaction::{self, PromptChoice},
+        interactive::{self, Answers},
         signal_channel, CommandExecute,
     },
+    env,
     error::HasExpectedErrors,
-    plan::RECEIPT_LOCATION,
+    os::{cloud_init, dependents},
+    plan::{REBOOT_CHECKPOINT_LOCATION, RECEIPT_LOCATION},
     planner::Planner,
-    settings::CommonSettings,
+    settings::{CommonSettings, UrlOrPath},
     BuiltinPlanner, InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
@@ -61,6 +65,43 @@ pub struct Install {
     )]
     pub explain: bool,
 
+    /// Leave temporary artifacts, such as the unpacked Nix tarball, in place and print where they are
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_KEEP_TEMP",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub keep_temp: bool,
+
+    /// Ask the handful of questions that matter instead of reading them from flags, needs a terminal
+    #[clap(
+        long,
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with_all = ["plan", "answers"]
+    )]
+    pub interactive: bool,
+
+    /// Save the answers of `--interactive` to this file, to install the same way elsewhere with `--answers`
+    #[clap(long, requires = "interactive")]
+    pub save_answers: Option<PathBuf>,
+
+    /// Install as answered to `--interactive` before, from the file `--save-answers` wrote
+    #[clap(long, env = "NIX_INSTALLER_ANSWERS", conflicts_with = "plan")]
+    pub answers: Option<PathBuf>,
+
+    /// Continue the install recorded in the receipt, such as after the reboot it stopped for, whatever the other flags say
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_RESUME",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        conflicts_with_all = ["plan", "interactive", "answers"]
+    )]
+    pub resume: bool,
+
     #[clap(env = "NIX_INSTALLER_PLAN")]
     pub plan: Option<PathBuf>,
 
@@ -73,37 +114,136 @@ impl CommandExecute for Install {
     #[tracing::instrument(level = "trace", skip_all)]
     async fn execute(self) -> eyre::Result<ExitCode> {
         let Self {
-            no_confirm,
+            mut no_confirm,
             plan,
             planner,
-            settings,
+            mut settings,
             explain,
+            keep_temp,
+            interactive,
+            save_answers,
+            answers,
+            resume,
         } = self;
 
         ensure_root()?;
 
-        let existing_receipt: Option<InstallPlan> = match Path::new(RECEIPT_LOCATION).exists() {
-            true => {
+        let answers = if interactive {
+            if !std::io::stdin().is_terminal() {
+                eprintln!(
+                    "{}",
+                    "`--interactive` needs a terminal to ask on, pass flags or `--answers` instead"
+                        .red()
+                );
+                return Ok(ExitCode::FAILURE);
+            }
+            println!("{}\n", interactive::INTRODUCTION);
+            let answers = Answers::ask(
+                interactive::ask_terminal,
+                env::SUDO_USER.get().as_deref(),
+                cfg!(target_os = "macos"),
+            )?;
+            println!(
+                "\nTo install the same way elsewhere, run:\n\n{}\n",
+                answers.command_line().bold()
+            );
+            if let Some(save_answers) = &save_answers {
+                let serialized =
+                    serde_json::to_string_pretty(&answers).wrap_err("Serializing answers")?;
+                tokio::fs::write(save_answers, format!("{serialized}\n"))
+                    .await
+                    .wrap_err_with(|| format!("Writing `{}`", save_answers.display()))?;
+                println!(
+                    "Saved the answers to `{}`, install with them elsewhere with `--answers`\n",
+                    save_answers.display()
+                );
+            }
+            // Whatever the flags say, the plan the answers make is shown before installing
+            no_confirm = false;
+            Some(answers)
+        } else if let Some(answers) = &answers {
+            let answers = tokio::fs::read_to_string(answers)
+                .await
+                .wrap_err_with(|| format!("Reading `{}`", answers.display()))?;
+            Some(serde_json::from_str::<Answers>(&answers).wrap_err("Parsing answers")?)
+        } else {
+            None
+        };
+        if let Some(answers) = &answers {
+            answers.apply(&mut settings);
+        }
+
+        let cloud_init = crate::os::cloud_init::detect();
+        if let Some(cloud_init) = &cloud_init {
+            if !no_confirm && !std::io::stdin().is_terminal() {
+                tracing::info!("Run by {cloud_init}, nothing can be confirmed, continuing without confirmation");
+                no_confirm = true;
+            }
+        }
+
+        // Until the reboot it stopped for, an install may have had nowhere to record its receipt but the checkpoint
+        let existing_receipt_location = [RECEIPT_LOCATION, REBOOT_CHECKPOINT_LOCATION]
+            .into_iter()
+            .find(|location| Path::new(location).exists());
+        let existing_receipt: Option<InstallPlan> = match existing_receipt_location {
+            Some(existing_receipt_location) => {
                 tracing::trace!("Reading existing receipt");
-                let install_plan_string = tokio::fs::read_to_string(&RECEIPT_LOCATION)
+                let install_plan_string = tokio::fs::read_to_string(&existing_receipt_location)
                     .await
                     .wrap_err("Reading plan")?;
                 Some(
                     serde_json::from_str(&install_plan_string).wrap_err_with(|| {
-                        format!("Unable to parse existing receipt `{RECEIPT_LOCATION}`, it may be from an incompatible version of `nix-installer`. Try running `/nix/nix-installer uninstall`, then installing again.")
+                        format!("Unable to parse existing receipt `{existing_receipt_location}`, it may be from an incompatible version of `nix-installer`. Try running `/nix/nix-installer uninstall`, then installing again.")
                     })?,
                 )
             },
-            false => None,
+            None => None,
+        };
+
+        // An install stopped for a reboot continues as planned, whatever the flags say now
+        let (resumed, existing_receipt) = match existing_receipt {
+            Some(existing_receipt) if resume || existing_receipt.reboot_checkpoint().is_some() => {
+                (Some(existing_receipt), None)
+            },
+            existing_receipt => (None, existing_receipt),
         };
+        if resume && resumed.is_none() {
+            eprintln!(
+                "{}",
+                format!("Nothing to resume, there is no receipt at `{RECEIPT_LOCATION}` or `{REBOOT_CHECKPOINT_LOCATION}`").red()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
 
         let uninstall_command = match Path::new("/nix/nix-installer").exists() {
             true => "/nix/nix-installer uninstall".into(),
             false => format!("curl --proto '=https' --tlsv1.2 -sSf -L https://install.determinate.systems/nix/tag/v{} | sh -s -- uninstall", env!("CARGO_PKG_VERSION")),
         };
 
-        let mut install_plan = match (planner, plan) {
-            (Some(planner), None) => {
+        let mut install_plan = match (resumed, planner, plan) {
+            (Some(resumed), _, _) => {
+                if let Err(e) = resumed.check_compatible() {
+                    eprintln!(
+                        "{}",
+                        format!("\
+                            {e}\n\
+                            \n\
+                            Found existing plan in `{}` which was created by a version incompatible `nix-installer`.\n\
+                            {EXISTING_INCOMPATIBLE_PLAN_GUIDANCE}\n\
+                        ", existing_receipt_location.unwrap_or(RECEIPT_LOCATION)).red()
+                    );
+                    return Ok(ExitCode::FAILURE)
+                }
+                if resumed.actions.iter().all(|v| v.state == ActionState::Completed) {
+                    eprintln!("{}", format!("Found existing plan in `{RECEIPT_LOCATION}`, already completed. Try uninstalling (`{uninstall_command}`) and reinstalling if Nix isn't working").yellow());
+                    return Ok(ExitCode::SUCCESS)
+                }
+                if let Some(checkpoint) = resumed.reboot_checkpoint() {
+                    println!("Continuing the install which stopped for a reboot at {}", checkpoint.recorded_at);
+                }
+                resumed
+            },
+            (None, Some(planner), None) => {
                 let chosen_planner: Box<dyn Planner> = planner.clone().boxed();
 
                 match existing_receipt {
@@ -146,16 +286,21 @@ impl CommandExecute for Install {
                     },
                 }
             },
-            (None, Some(plan_path)) => {
+            (None, None, Some(plan_path)) => {
                 let install_plan_string = tokio::fs::read_to_string(&plan_path)
                 .await
                 .wrap_err("Reading plan")?;
                 serde_json::from_str(&install_plan_string)?
             },
-            (None, None) => {
-                let builtin_planner = BuiltinPlanner::from_common_settings(settings.clone())
+            (None, None, None) => {
+                #[cfg_attr(not(target_os = "macos"), allow(unused_mut))]
+                let mut builtin_planner = BuiltinPlanner::from_common_settings(settings.clone())
                     .await
                     .map_err(|e| eyre::eyre!(e))?;
+                #[cfg(target_os = "macos")]
+                if let (Some(answers), BuiltinPlanner::Macos(macos)) = (&answers, &mut builtin_planner) {
+                    macos.encrypt = answers.encrypt;
+                }
 
                 match existing_receipt {
                     Some(existing_receipt) => {
@@ -200,7 +345,7 @@ impl CommandExecute for Install {
                     },
                 }
             },
-            (Some(_), Some(_)) => return Err(eyre!("`--plan` conflicts with passing a planner, a planner creates plans, so passing an existing plan doesn't make sense")),
+            (None, Some(_), Some(_)) => return Err(eyre!("`--plan` conflicts with passing a planner, a planner creates plans, so passing an existing plan doesn't make sense")),
         };
 
         if let Err(err) = install_plan.pre_install_check().await {
@@ -211,6 +356,15 @@ impl CommandExecute for Install {
             Err(err)?
         }
 
+        if install_plan.reads_stdin() && !no_confirm {
+            eprintln!(
+                "{}",
+                "The Nix package is read from stdin, confirmations cannot be, pass `--no-confirm`"
+                    .red()
+            );
+            return Ok(ExitCode::FAILURE);
+        }
+
         if !no_confirm {
             let mut currently_explaining = explain;
             loop {
@@ -233,9 +387,23 @@ impl CommandExecute for Install {
             }
         }
 
+        if cloud_init.is_some() {
+            warn_write_files_overlap(&install_plan)?;
+            wait_for_network(&settings).await;
+        }
+
         let (tx, rx1) = signal_channel().await?;
 
-        match install_plan.install(rx1).await {
+        install_plan.set_keep_temp(keep_temp);
+        let progress_line = interaction::render_progress();
+        let installed = install_plan.install(rx1).await;
+        drop(progress_line);
+        match installed {
+            // Nothing to revert, the install continues after the reboot
+            Err(err @ NixInstallerError::NeedsReboot { .. }) => {
+                eprintln!("{}", err.to_string().yellow());
+                return Ok(ExitCode::FAILURE);
+            },
             Err(err) => {
                 // Attempt to copy self to the store if possible, but since the install failed, this might not work, that's ok.
                 copy_self_to_nix_dir().await.ok();
@@ -316,19 +484,30 @@ impl CommandExecute for Install {
                 copy_self_to_nix_dir()
                     .await
                     .wrap_err("Copying `nix-installer` to `/nix/nix-installer`")?;
+                let shell_reminder = match env::SHELL.get() {
+                    Some(val) if val.contains("fish") => {
+                        ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish"
+                    },
+                    Some(_) | None => ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh",
+                };
                 println!(
                     "\
                     {success}\n\
                     To get started using Nix, open a new shell or run `{shell_reminder}`\n\
                     ",
                     success = "Nix was installed successfully!".green().bold(),
-                    shell_reminder = match std::env::var("SHELL") {
-                        Ok(val) if val.contains("fish") =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.fish".bold(),
-                        Ok(_) | Err(_) =>
-                            ". /nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh".bold(),
-                    },
+                    shell_reminder = shell_reminder.bold(),
                 );
+                if !modifies_profile(&install_plan) {
+                    println!(
+                        "\
+                        The shell profiles were left untouched (`--no-modify-profile`), add this line to the profile of your shell to load Nix in new shells:\n\
+                        \n\
+                        {shell_reminder}\n\
+                        ",
+                        shell_reminder = shell_reminder.bold(),
+                    );
+                }
             },
         }
 
@@ -343,3 +522,53 @@ async fn copy_self_to_nix_dir() -> Result<(), std::io::Error> {
     tokio::fs::set_permissions("/nix/nix-installer", PermissionsExt::from_mode(0o0755)).await?;
     Ok(())
 }
+
+/// Whether the plan hooks Nix into the shell profiles, plans whose settings can't be read did
+fn modifies_profile(install_plan: &InstallPlan) -> bool {
+    install_plan
+        .planner
+        .settings()
+        .ok()
+        .and_then(|settings| settings.get("modify_profile")?.as_bool())
+        .unwrap_or(true)
+}
+
+/// Warn about files the install writes which cloud-init's `write_files` also writes, whichever
+/// runs last wins
+fn warn_write_files_overlap(install_plan: &InstallPlan) -> eyre::Result<()> {
+    let declared = cloud_init::write_files_paths(Path::new("/"));
+    if declared.is_empty() {
+        return Ok(());
+    }
+    let planned = dependents::recorded_paths(&serde_json::to_value(install_plan)?);
+    for path in planned.intersection(&declared) {
+        tracing::warn!(
+            "`{}` is also in the `write_files` of the cloud config, one will overwrite the other depending on which runs last",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Wait a bounded time for the host Nix is fetched from to be reachable, cloud-init may run
+/// user data before the network is fully up
+async fn wait_for_network(settings: &CommonSettings) {
+    let UrlOrPath::Url(url) = settings.nix_package() else {
+        return;
+    };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return;
+    };
+    let up = cloud_init::wait_for_network(
+        || cloud_init::reachable(host, port, cloud_init::NETWORK_POLL_INTERVAL),
+        cloud_init::NETWORK_TIMEOUT,
+        cloud_init::NETWORK_POLL_INTERVAL,
+    )
+    .await;
+    if !up {
+        tracing::warn!(
+            "`{host}` was not reachable after {}s, continuing anyway",
+            cloud_init::NETWORK_TIMEOUT.as_secs()
+        );
+    }
+}
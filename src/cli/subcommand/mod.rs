@@ -1,6 +1,7 @@
 mod plan;
 use plan::Plan;
 mod install;
+mod plan_diff;
 use install::Install;
 mod repair;
 use repair::Repair;
@@ -8,6 +9,22 @@ mod uninstall;
 use uninstall::Uninstall;
 mod self_test;
 use self_test::SelfTest;
+mod claim_receipt;
+use claim_receipt::ClaimReceipt;
+mod preview_upgrade;
+use preview_upgrade::PreviewUpgrade;
+mod revert_action;
+use revert_action::RevertAction;
+mod cleanup_user;
+use cleanup_user::CleanupUser;
+mod doctor;
+use doctor::Doctor;
+mod generate_first_boot_unit;
+use generate_first_boot_unit::GenerateFirstBootUnit;
+mod convert;
+use convert::Convert;
+mod capabilities;
+use capabilities::Capabilities;
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, clap::Subcommand)]
@@ -17,4 +34,12 @@ pub enum NixInstallerSubcommand {
     Uninstall(Uninstall),
     SelfTest(SelfTest),
     Plan(Plan),
+    ClaimReceipt(ClaimReceipt),
+    RevertAction(RevertAction),
+    PreviewUpgrade(PreviewUpgrade),
+    CleanupUser(CleanupUser),
+    Doctor(Doctor),
+    GenerateFirstBootUnit(GenerateFirstBootUnit),
+    Convert(Convert),
+    Capabilities(Capabilities),
 }
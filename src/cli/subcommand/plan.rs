@@ -8,6 +8,8 @@ use owo_colors::OwoColorize;
 
 use crate::cli::CommandExecute;
 
+use super::plan_diff::PlanDiff;
+
 /**
 Emit a JSON install plan that can be manually edited before execution
 
@@ -16,7 +18,7 @@ Primarily intended for development, debugging, and handling install cases.
 #[derive(Debug, Parser)]
 pub struct Plan {
     #[clap(subcommand)]
-    pub planner: Option<BuiltinPlanner>,
+    pub subcommand: Option<PlanSubcommand>,
     /// Where to write the generated plan (in JSON format)
     #[clap(
         long = "out-file",
@@ -26,11 +28,24 @@ pub struct Plan {
     pub output: PathBuf,
 }
 
+#[derive(Debug, clap::Subcommand)]
+pub enum PlanSubcommand {
+    Diff(PlanDiff),
+    #[clap(flatten)]
+    Planner(BuiltinPlanner),
+}
+
 #[async_trait::async_trait]
 impl CommandExecute for Plan {
     #[tracing::instrument(level = "debug", skip_all, fields())]
     async fn execute(self) -> eyre::Result<ExitCode> {
-        let Self { planner, output } = self;
+        let Self { subcommand, output } = self;
+
+        let planner = match subcommand {
+            Some(PlanSubcommand::Diff(diff)) => return diff.execute().await,
+            Some(PlanSubcommand::Planner(planner)) => Some(planner),
+            None => None,
+        };
 
         ensure_root()?;
 
@@ -0,0 +1,490 @@
+use std::{collections::HashMap, path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use color_eyre::eyre::WrapErr;
+use owo_colors::OwoColorize;
+use serde_json::Value;
+
+use crate::{
+    action::action_key,
+    cli::{ensure_root, CommandExecute},
+    error::HasExpectedErrors,
+    BuiltinPlanner,
+};
+
+/// The exit code when the plans differ, errors exit with `1`
+pub(crate) const DIFFERENCES_EXIT_CODE: u8 = 2;
+
+/**
+Compare two plans or receipts, or one against what would be planned now
+
+Actions are aligned by their kind and identifying fields (such as the path of a file or the name
+of a user), then every added, removed, and changed action is listed. Like `diff(1)` it exits with
+`0` when there are no differences, `2` when there are and `1` on errors, so it can gate CI.
+*/
+#[derive(Debug, Parser)]
+pub struct PlanDiff {
+    /// The old plan or receipt
+    pub old: PathBuf,
+    /// The new plan or receipt (default: plan now, with the given planner and its flags)
+    pub new: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub planner: Option<BuiltinPlanner>,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for PlanDiff {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self { old, new, planner } = self;
+
+        let old_value = read_plan(&old).await?;
+        let new_value = match new {
+            Some(new) => read_plan(&new).await?,
+            None => {
+                ensure_root()?;
+                let planner = match planner {
+                    Some(planner) => planner,
+                    None => BuiltinPlanner::default().await?,
+                };
+                match planner.plan().await {
+                    Ok(plan) => serde_json::to_value(plan)?,
+                    Err(err) => {
+                        if let Some(expected) = err.expected() {
+                            eprintln!("{}", expected.red());
+                            return Ok(ExitCode::FAILURE);
+                        }
+                        return Err(err)?;
+                    },
+                }
+            },
+        };
+
+        let entries = diff_plans(&old_value, &new_value);
+        for entry in &entries {
+            println!("{entry}");
+        }
+
+        if entries.is_empty() {
+            println!("{}", "No differences".green());
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::from(DIFFERENCES_EXIT_CODE))
+        }
+    }
+}
+
+pub(crate) async fn read_plan(path: &PathBuf) -> eyre::Result<Value> {
+    let json = tokio::fs::read_to_string(path)
+        .await
+        .wrap_err_with(|| format!("Reading `{}`", path.display()))?;
+    serde_json::from_str(&json).wrap_err_with(|| format!("Parsing `{}`", path.display()))
+}
+
+/// A difference between two plans
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiffEntry {
+    Added(String),
+    Removed(String),
+    Changed(String, Vec<FieldChange>),
+}
+
+impl DiffEntry {
+    /// The action, as its kind and key, or `planner`
+    pub(crate) fn label(&self) -> &str {
+        match self {
+            DiffEntry::Added(label) | DiffEntry::Removed(label) | DiffEntry::Changed(label, _) => {
+                label
+            },
+        }
+    }
+
+    /// The kind of the action, or `planner`
+    pub(crate) fn kind(&self) -> &str {
+        self.label().split(' ').next().unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiffEntry::Added(label) => write!(f, "{}", format!("+ {label}").green()),
+            DiffEntry::Removed(label) => write!(f, "{}", format!("- {label}").red()),
+            DiffEntry::Changed(label, changes) => {
+                write!(f, "{}", format!("~ {label}").yellow())?;
+                for change in changes {
+                    write!(f, "\n    {change}")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// A field which differs between two aligned actions, by its dotted path inside the action
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct FieldChange {
+    pub(crate) field: String,
+    pub(crate) old: Option<Value>,
+    pub(crate) new: Option<Value>,
+}
+
+impl std::fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "(absent)".to_string(),
+        };
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.field,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// Align the actions of two plans, listing what was added, removed, and changed (planner settings included)
+pub(crate) fn diff_plans(old: &Value, new: &Value) -> Vec<DiffEntry> {
+    let mut entries = vec![];
+
+    let mut planner_changes = vec![];
+    diff_values("", &old["planner"], &new["planner"], &mut planner_changes);
+    if !planner_changes.is_empty() {
+        entries.push(DiffEntry::Changed("planner".into(), planner_changes));
+    }
+
+    let empty = vec![];
+    let old_actions = old["actions"].as_array().unwrap_or(&empty);
+    let new_actions = new["actions"].as_array().unwrap_or(&empty);
+    let old_keys = occurrence_keys(old_actions);
+    let new_keys = occurrence_keys(new_actions);
+    let old_by_key = old_keys
+        .iter()
+        .cloned()
+        .zip(old_actions)
+        .collect::<HashMap<_, _>>();
+
+    for (key, new_action) in new_keys.iter().zip(new_actions) {
+        match old_by_key.get(key) {
+            Some(old_action) => {
+                let mut changes = vec![];
+                diff_values("", old_action, new_action, &mut changes);
+                if !changes.is_empty() {
+                    entries.push(DiffEntry::Changed(label(new_action), changes));
+                }
+            },
+            None => entries.push(DiffEntry::Added(label(new_action))),
+        }
+    }
+    for (key, old_action) in old_keys.iter().zip(old_actions) {
+        if !new_keys.contains(key) {
+            entries.push(DiffEntry::Removed(label(old_action)));
+        }
+    }
+
+    entries
+}
+
+/// The kind of a planned action, with the `action` tag added by `typetag`
+fn kind(stateful: &Value) -> Option<&str> {
+    stateful["action"]["action"].as_str()
+}
+
+/// What identifies a (possibly nested) action among others of its kind
+fn key(stateful: &Value) -> Option<String> {
+    action_key(stateful.get("action")?)
+}
+
+fn label(stateful: &Value) -> String {
+    let kind = kind(stateful).unwrap_or("unknown");
+    match key(stateful) {
+        Some(key) => format!("{kind} `{key}`"),
+        None => kind.to_string(),
+    }
+}
+
+/// Alignment keys for a list of actions, the same kind and key occurring again is told apart by its count
+fn occurrence_keys(actions: &[Value]) -> Vec<(String, String, usize)> {
+    let mut seen = HashMap::<(String, String), usize>::new();
+    actions
+        .iter()
+        .map(|action| {
+            let kind = kind(action).unwrap_or_default().to_string();
+            let key = key(action).unwrap_or_default();
+            let count = seen.entry((kind.clone(), key.clone())).or_default();
+            *count += 1;
+            (kind, key, *count)
+        })
+        .collect()
+}
+
+/// The fields of a `StatefulAction`, of which plans only have the action and its state
+const STATEFUL_FIELDS: &[&str] = &["action", "state", "id", "timing", "reverted_at"];
+
+/// A `StatefulAction`, whose state says how far an install got rather than what it plans
+fn is_stateful(value: &Value) -> bool {
+    value
+        .as_object()
+        .map(|object| {
+            object.contains_key("action")
+                && object.contains_key("state")
+                && object
+                    .keys()
+                    .all(|key| STATEFUL_FIELDS.contains(&key.as_str()))
+        })
+        .unwrap_or(false)
+}
+
+fn join(prefix: &str, field: &str) -> String {
+    if prefix.is_empty() {
+        field.to_string()
+    } else {
+        format!("{prefix}.{field}")
+    }
+}
+
+fn diff_values(prefix: &str, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    match (old, new) {
+        (Value::Object(old_object), Value::Object(new_object)) => {
+            let skip_state = is_stateful(old) && is_stateful(new);
+            for (field, old_field) in old_object {
+                // Ids follow from the kind and key the actions are aligned by, timings from when it ran
+                if skip_state && ["state", "id", "timing", "reverted_at"].contains(&field.as_str())
+                {
+                    continue;
+                }
+                // The wrapper of a nested action adds nothing worth showing in the path
+                let path = if field == "action" && skip_state {
+                    prefix.to_string()
+                } else {
+                    join(prefix, field)
+                };
+                match new_object.get(field) {
+                    Some(new_field) => diff_values(&path, old_field, new_field, changes),
+                    None => changes.push(FieldChange {
+                        field: path,
+                        old: Some(old_field.clone()),
+                        new: None,
+                    }),
+                }
+            }
+            for (field, new_field) in new_object {
+                if skip_state && ["id", "timing", "reverted_at"].contains(&field.as_str()) {
+                    continue;
+                }
+                if !old_object.contains_key(field) {
+                    changes.push(FieldChange {
+                        field: join(prefix, field),
+                        old: None,
+                        new: Some(new_field.clone()),
+                    });
+                }
+            }
+        },
+        (Value::Array(old_items), Value::Array(new_items))
+            if old_items
+                .iter()
+                .chain(new_items)
+                .all(|item| key(item).is_some()) =>
+        {
+            // Nested actions, such as the users of `create_users_and_groups`, are aligned by key
+            let old_by_key = old_items
+                .iter()
+                .map(|item| (key(item).unwrap_or_default(), item))
+                .collect::<HashMap<_, _>>();
+            let new_keys = new_items
+                .iter()
+                .map(|item| key(item).unwrap_or_default())
+                .collect::<Vec<_>>();
+            for (new_key, new_item) in new_keys.iter().zip(new_items) {
+                let path = format!("{prefix}[{new_key}]");
+                match old_by_key.get(new_key) {
+                    Some(old_item) => diff_values(&path, old_item, new_item, changes),
+                    None => changes.push(FieldChange {
+                        field: path,
+                        old: None,
+                        new: Some(new_item["action"].clone()),
+                    }),
+                }
+            }
+            for old_item in old_items {
+                let old_key = key(old_item).unwrap_or_default();
+                if !new_keys.contains(&old_key) {
+                    changes.push(FieldChange {
+                        field: format!("{prefix}[{old_key}]"),
+                        old: Some(old_item["action"].clone()),
+                        new: None,
+                    });
+                }
+            }
+        },
+        (old, new) if old != new => changes.push(FieldChange {
+            field: prefix.to_string(),
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn stateful(action: Value) -> Value {
+        json!({ "action": action, "state": "Uncompleted" })
+    }
+
+    fn plan(actions: Vec<Value>) -> Value {
+        json!({
+            "version": "0.14.0",
+            "planner": { "planner": "linux", "settings": { "modify_profile": true } },
+            "actions": actions,
+        })
+    }
+
+    fn create_file(path: &str, buf: &str) -> Value {
+        stateful(json!({ "action": "create_file", "path": path, "mode": 420, "buf": buf }))
+    }
+
+    fn create_user(name: &str, uid: u32) -> Value {
+        stateful(json!({ "name": name, "uid": uid, "groupname": "nixbld", "gid": 30000 }))
+    }
+
+    #[test]
+    fn identical_plans_have_no_differences() {
+        let plan = plan(vec![create_file("/etc/nix/nix.conf", "a")]);
+        assert!(diff_plans(&plan, &plan).is_empty());
+    }
+
+    #[test]
+    fn receipt_state_is_not_a_difference() {
+        let old = plan(vec![json!({
+            "action": { "action": "create_file", "path": "/etc/nix/nix.conf", "mode": 420, "buf": "a" },
+            "state": "Completed",
+            "timing": {
+                "started_at": "2024-03-01T12:34:56.000Z",
+                "finished_at": "2024-03-01T12:34:57.000Z",
+            },
+        })]);
+        let new = plan(vec![create_file("/etc/nix/nix.conf", "a")]);
+        assert!(diff_plans(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn aligns_files_by_path_regardless_of_order() {
+        let old = plan(vec![
+            create_file("/etc/a", "a"),
+            create_file("/etc/b", "b"),
+            create_file("/etc/c", "c"),
+        ]);
+        let new = plan(vec![
+            create_file("/etc/b", "b"),
+            create_file("/etc/a", "changed"),
+            create_file("/etc/d", "d"),
+        ]);
+        assert_eq!(
+            diff_plans(&old, &new),
+            vec![
+                DiffEntry::Changed(
+                    "create_file `/etc/a`".into(),
+                    vec![FieldChange {
+                        field: "buf".into(),
+                        old: Some(json!("a")),
+                        new: Some(json!("changed")),
+                    }]
+                ),
+                DiffEntry::Added("create_file `/etc/d`".into()),
+                DiffEntry::Removed("create_file `/etc/c`".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn aligns_nested_users_by_name() {
+        let users = |users: Vec<Value>| {
+            stateful(json!({ "action": "create_users_and_groups", "create_users": users }))
+        };
+        let old = plan(vec![users(vec![
+            create_user("nixbld1", 30001),
+            create_user("nixbld2", 30002),
+        ])]);
+        let new = plan(vec![users(vec![
+            create_user("nixbld2", 30012),
+            create_user("nixbld3", 30003),
+        ])]);
+
+        let entries = diff_plans(&old, &new);
+        assert_eq!(entries.len(), 1);
+        let DiffEntry::Changed(label, changes) = &entries[0] else {
+            panic!("Expected a change, got {entries:?}");
+        };
+        assert_eq!(label, "create_users_and_groups");
+        let fields = changes
+            .iter()
+            .map(|change| change.field.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "create_users[nixbld2 nixbld].uid",
+                "create_users[nixbld3 nixbld]",
+                "create_users[nixbld1 nixbld]",
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_actions_align_by_occurrence() {
+        let old = plan(vec![
+            stateful(json!({ "action": "systemctl_daemon_reload" })),
+            stateful(json!({ "action": "systemctl_daemon_reload" })),
+        ]);
+        let new = plan(vec![stateful(
+            json!({ "action": "systemctl_daemon_reload" }),
+        )]);
+        assert_eq!(
+            diff_plans(&old, &new),
+            vec![DiffEntry::Removed("systemctl_daemon_reload".into())]
+        );
+    }
+
+    #[test]
+    fn reports_planner_settings() {
+        let old = plan(vec![]);
+        let mut new = plan(vec![]);
+        new["planner"]["settings"]["modify_profile"] = json!(false);
+        assert_eq!(
+            diff_plans(&old, &new),
+            vec![DiffEntry::Changed(
+                "planner".into(),
+                vec![FieldChange {
+                    field: "settings.modify_profile".into(),
+                    old: Some(json!(true)),
+                    new: Some(json!(false)),
+                }]
+            )]
+        );
+    }
+
+    #[test]
+    fn parses_new_plan_or_planner() {
+        let diff = PlanDiff::try_parse_from(["diff", "old.json", "new.json"]).unwrap();
+        assert_eq!(diff.new, Some(PathBuf::from("new.json")));
+        let diff = PlanDiff::try_parse_from(["diff", "old.json", "--"]).unwrap();
+        assert_eq!(diff.new, None);
+        assert!(diff.planner.is_none());
+        #[cfg(target_os = "linux")]
+        {
+            let diff =
+                PlanDiff::try_parse_from(["diff", "old.json", "linux", "--no-modify-profile"])
+                    .unwrap();
+            assert_eq!(diff.new, None);
+            assert!(matches!(diff.planner, Some(BuiltinPlanner::Linux(_))));
+        }
+    }
+}
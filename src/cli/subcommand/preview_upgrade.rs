@@ -0,0 +1,382 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::Parser;
+use owo_colors::OwoColorize;
+use serde_json::{json, Value};
+
+use crate::{
+    cli::{ensure_root, CommandExecute},
+    error::HasExpectedErrors,
+    plan::RECEIPT_LOCATION,
+    BuiltinPlanner,
+};
+
+use super::plan_diff::{diff_plans, read_plan, DiffEntry, DIFFERENCES_EXIT_CODE};
+
+/**
+Preview what running this installer over an existing install would change
+
+The receipt is compared with what would be planned now (see `plan diff`), and every change is
+classified by what it touches: only configuration, users and the daemon, or the Nix store. Exits
+with `0` without changes and `2` with changes, so a fleet can be checked before rolling out a new
+version.
+*/
+#[derive(Debug, Parser)]
+pub struct PreviewUpgrade {
+    /// The receipt of the existing install
+    #[clap(long, default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+    /// The format to show the changes in
+    #[clap(long, value_enum, default_value_t = PreviewFormat::Human)]
+    pub format: PreviewFormat,
+    /// The planner to plan with (default: the one of this host), with its flags
+    #[clap(subcommand)]
+    pub planner: Option<BuiltinPlanner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PreviewFormat {
+    Human,
+    Json,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for PreviewUpgrade {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            receipt,
+            format,
+            planner,
+        } = self;
+
+        ensure_root()?;
+
+        let receipt_value = read_plan(&receipt).await?;
+        let planner = match planner {
+            Some(planner) => planner,
+            None => BuiltinPlanner::default().await?,
+        };
+        let plan_value = match planner.plan().await {
+            Ok(plan) => serde_json::to_value(plan)?,
+            Err(err) => {
+                if let Some(expected) = err.expected() {
+                    eprintln!("{}", expected.red());
+                    return Ok(ExitCode::FAILURE);
+                }
+                return Err(err)?;
+            },
+        };
+
+        let changes = preview(&receipt_value, &plan_value);
+        match format {
+            PreviewFormat::Human => print!("{}", render_human(&changes)),
+            PreviewFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(&to_json(&changes))?)
+            },
+        }
+
+        if changes.is_empty() {
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::from(DIFFERENCES_EXIT_CODE))
+        }
+    }
+}
+
+/// What a change touches, from least to most disruptive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Risk {
+    /// Configuration files, such as `nix.conf` and shell profiles
+    Config,
+    /// Build users and groups, or the services running the daemon
+    UsersAndDaemon,
+    /// The Nix store, or the volume it lives in
+    Store,
+}
+
+impl Risk {
+    /// The risk of changing an action of `kind`, the planner settings are only configuration
+    ///
+    /// Kinds missing here (such as those of custom planners) are assumed to touch the store.
+    pub(crate) fn of(kind: &str) -> Self {
+        match kind {
+            "planner"
+            | "configure_daemon_log_rotation"
+            | "configure_nix"
+            | "configure_remote_builders"
+            | "configure_shell_profile"
+            | "configure_user_nix"
+            | "create_ca_bundle"
+            | "create_directory"
+            | "create_file"
+            | "create_or_insert_into_file"
+            | "create_or_merge_nix_config"
+            | "place_motd"
+            | "place_nix_configuration"
+            | "remove_directory"
+            | "set_tmutil_exclusion"
+            | "set_tmutil_exclusions" => Risk::Config,
+            "add_user_to_group"
+            | "bootstrap_launchctl_service"
+            | "configure_init_service"
+            | "create_group"
+            | "create_nix_hook_service"
+            | "create_user"
+            | "create_users_and_group"
+            | "delete_user"
+            | "delete_users_in_group"
+            | "kickstart_launchctl_service"
+            | "provision_selinux"
+            | "start_systemd_unit"
+            | "systemctl_daemon_reload" => Risk::UsersAndDaemon,
+            _ => Risk::Store,
+        }
+    }
+
+    /// Why a change of this risk is worth looking at, recorded with it
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Risk::Config => "changes configuration only",
+            Risk::UsersAndDaemon => "changes build users, groups or the daemon, which may restart",
+            Risk::Store => "changes the Nix store or the volume it lives in",
+        }
+    }
+
+    fn heading(&self) -> &'static str {
+        match self {
+            Risk::Config => "Configuration",
+            Risk::UsersAndDaemon => "Users and daemon",
+            Risk::Store => "Nix store",
+        }
+    }
+}
+
+/// A change running the installer again would make
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Change {
+    pub(crate) risk: Risk,
+    pub(crate) entry: DiffEntry,
+}
+
+/// The changes planning `plan` over the install of `receipt` would make, the most disruptive first
+pub(crate) fn preview(receipt: &Value, plan: &Value) -> Vec<Change> {
+    let mut changes = diff_plans(receipt, plan)
+        .into_iter()
+        .map(|entry| Change {
+            risk: Risk::of(entry.kind()),
+            entry,
+        })
+        .collect::<Vec<_>>();
+    // Stable, so the order of the plan is kept among changes of the same risk
+    changes.sort_by_key(|change| std::cmp::Reverse(change.risk));
+    changes
+}
+
+fn render_human(changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return format!("{}\n", "No changes".green());
+    }
+    let mut rendered = String::new();
+    let mut previous = None;
+    for change in changes {
+        if previous != Some(change.risk) {
+            if previous.is_some() {
+                rendered.push('\n');
+            }
+            rendered.push_str(&format!(
+                "{} ({})\n",
+                change.risk.heading().bold(),
+                change.risk.reason()
+            ));
+            previous = Some(change.risk);
+        }
+        for line in change.entry.to_string().lines() {
+            rendered.push_str(&format!("  {line}\n"));
+        }
+    }
+    rendered
+}
+
+/// The changes for machines, with the highest risk among them (`null` without changes)
+fn to_json(changes: &[Change]) -> Value {
+    json!({
+        "risk": changes.iter().map(|change| change.risk).max(),
+        "changes": changes
+            .iter()
+            .map(|Change { risk, entry }| {
+                let (change, fields) = match entry {
+                    DiffEntry::Added(_) => ("added", &[][..]),
+                    DiffEntry::Removed(_) => ("removed", &[][..]),
+                    DiffEntry::Changed(_, fields) => ("changed", &fields[..]),
+                };
+                json!({
+                    "action": entry.label(),
+                    "kind": entry.kind(),
+                    "change": change,
+                    "risk": risk,
+                    "reason": risk.reason(),
+                    "fields": fields,
+                })
+            })
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stateful(action: Value) -> Value {
+        json!({ "action": action, "state": "Uncompleted" })
+    }
+
+    fn completed(action: Value) -> Value {
+        json!({
+            "action": action,
+            "state": "Completed",
+            "id": "some-id",
+            "timing": {
+                "started_at": "2024-03-01T12:34:56.000Z",
+                "finished_at": "2024-03-01T12:34:57.000Z",
+            },
+        })
+    }
+
+    fn plan(actions: Vec<Value>) -> Value {
+        json!({
+            "version": "0.14.0",
+            "planner": { "planner": "linux", "settings": { "nix_build_user_count": 32 } },
+            "actions": actions,
+        })
+    }
+
+    fn create_file(path: &str, buf: &str) -> Value {
+        json!({ "action": "create_file", "path": path, "mode": 420, "buf": buf })
+    }
+
+    fn users(count: u32) -> Value {
+        json!({ "action": "create_users_and_group", "nix_build_user_count": count })
+    }
+
+    fn provision_nix(url: &str) -> Value {
+        json!({ "action": "provision_nix", "url": url })
+    }
+
+    #[test]
+    fn unchanged_install_previews_nothing() {
+        let receipt = plan(vec![
+            completed(users(32)),
+            completed(provision_nix("https://example.com/nix-2.24.tar.xz")),
+        ]);
+        let new = plan(vec![
+            stateful(users(32)),
+            stateful(provision_nix("https://example.com/nix-2.24.tar.xz")),
+        ]);
+        let changes = preview(&receipt, &new);
+        assert_eq!(changes, []);
+        assert_eq!(to_json(&changes), json!({ "risk": null, "changes": [] }));
+        assert!(render_human(&changes).contains("No changes"));
+    }
+
+    #[test]
+    fn groups_changes_by_risk() {
+        let receipt = plan(vec![
+            completed(create_file("/etc/nix/nix.conf", "a")),
+            completed(users(32)),
+            completed(provision_nix("https://example.com/nix-2.24.tar.xz")),
+        ]);
+        let mut new = plan(vec![
+            stateful(create_file("/etc/nix/nix.conf", "b")),
+            stateful(create_file("/etc/tmpfiles.d/nix-daemon.conf", "c")),
+            stateful(users(64)),
+            stateful(provision_nix("https://example.com/nix-2.25.tar.xz")),
+        ]);
+        new["planner"]["settings"]["nix_build_user_count"] = json!(64);
+
+        let changes = preview(&receipt, &new);
+        assert_eq!(
+            changes
+                .iter()
+                .map(|change| (change.risk, change.entry.label()))
+                .collect::<Vec<_>>(),
+            [
+                (Risk::Store, "provision_nix"),
+                (Risk::UsersAndDaemon, "create_users_and_group"),
+                (Risk::Config, "planner"),
+                (Risk::Config, "create_file `/etc/nix/nix.conf`"),
+                (
+                    Risk::Config,
+                    "create_file `/etc/tmpfiles.d/nix-daemon.conf`"
+                ),
+            ]
+        );
+
+        let rendered = render_human(&changes);
+        let headings = ["Nix store", "Users and daemon", "Configuration"]
+            .map(|heading| rendered.find(heading).expect(heading));
+        assert!(
+            headings.windows(2).all(|pair| pair[0] < pair[1]),
+            "{rendered}"
+        );
+
+        let json = to_json(&changes);
+        assert_eq!(json["risk"], "store");
+        assert_eq!(
+            json["changes"][4],
+            json!({
+                "action": "create_file `/etc/tmpfiles.d/nix-daemon.conf`",
+                "kind": "create_file",
+                "change": "added",
+                "risk": "config",
+                "reason": Risk::Config.reason(),
+                "fields": [],
+            })
+        );
+        assert_eq!(
+            json["changes"][1]["fields"],
+            json!([{ "field": "nix_build_user_count", "old": 32, "new": 64 }])
+        );
+    }
+
+    #[test]
+    fn configuration_only_changes_are_config() {
+        let receipt = plan(vec![completed(create_file("/etc/nix/nix.conf", "a"))]);
+        let new = plan(vec![
+            stateful(create_file("/etc/nix/nix.conf", "a")),
+            stateful(
+                json!({ "action": "configure_shell_profile", "create_or_insert_into_files": [] }),
+            ),
+        ]);
+        assert_eq!(to_json(&preview(&receipt, &new))["risk"], "config");
+    }
+
+    #[test]
+    fn unknown_kinds_are_assumed_to_touch_the_store() {
+        assert_eq!(Risk::of("my_custom_action"), Risk::Store);
+        assert_eq!(Risk::of("reown_nix_store"), Risk::Store);
+        assert_eq!(Risk::of("configure_init_service"), Risk::UsersAndDaemon);
+    }
+
+    #[test]
+    fn parses_format_and_planner() {
+        let preview = PreviewUpgrade::try_parse_from(["preview-upgrade"]).unwrap();
+        assert_eq!(preview.receipt, PathBuf::from(RECEIPT_LOCATION));
+        assert_eq!(preview.format, PreviewFormat::Human);
+        assert!(preview.planner.is_none());
+        #[cfg(target_os = "linux")]
+        {
+            let preview = PreviewUpgrade::try_parse_from([
+                "preview-upgrade",
+                "--format",
+                "json",
+                "linux",
+                "--no-modify-profile",
+            ])
+            .unwrap();
+            assert_eq!(preview.format, PreviewFormat::Json);
+            assert!(matches!(preview.planner, Some(BuiltinPlanner::Linux(_))));
+        }
+    }
+}
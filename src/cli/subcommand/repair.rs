@@ -1,11 +1,19 @@
-use std::process::ExitCode;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 use crate::{
     action::common::ConfigureShellProfile,
     cli::{ensure_root, CommandExecute},
+    path_policy::PathPolicy,
+    plan::RECEIPT_LOCATION,
     planner::{PlannerError, ShellProfileLocations},
+    settings::{nix_ssl_cert_file, NIX_CONF_DIR},
+    InstallPlan,
 };
 use clap::{ArgAction, Parser};
+use owo_colors::OwoColorize;
 
 /**
 Update the shell profiles to make Nix usable after system upgrades.
@@ -21,25 +29,118 @@ pub struct Repair {
         global = true
     )]
     pub no_confirm: bool,
+
+    /// Proceed even if the receipt was recorded on a different host
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_IGNORE_HOST_MISMATCH",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub ignore_host_mismatch: bool,
 }
 
 #[async_trait::async_trait]
 impl CommandExecute for Repair {
     #[tracing::instrument(level = "trace", skip_all)]
     async fn execute(self) -> eyre::Result<ExitCode> {
-        let Self { no_confirm: _ } = self;
+        let Self {
+            no_confirm: _,
+            ignore_host_mismatch,
+        } = self;
 
         ensure_root()?;
 
-        let mut reconfigure = ConfigureShellProfile::plan(ShellProfileLocations::default())
-            .await
-            .map_err(PlannerError::Action)?
-            .boxed();
+        let mut ssl_cert_file = None;
+        let mut nix_conf_dir = None;
+        let mut posix_only_profile = false;
+        let mut shell_profile_locations = ShellProfileLocations::default();
+        let mut path_policy = PathPolicy::default();
+        if let Ok(receipt) = tokio::fs::read_to_string(RECEIPT_LOCATION).await {
+            if let Ok(plan) = serde_json::from_str::<InstallPlan>(&receipt) {
+                // Keep pointing shells at the configuration and CA bundle chosen at install time
+                nix_conf_dir = plan.nix_conf_dir();
+                if let Ok(settings) = plan.planner.settings() {
+                    path_policy = PathPolicy::from_settings(&settings);
+                    posix_only_profile = settings
+                        .get("posix_only_profile")
+                        .and_then(|posix_only_profile| posix_only_profile.as_bool())
+                        .unwrap_or(false);
+                    // Reach the same profiles as the install did, without `--profile-targets` passed again
+                    let profile_targets = settings
+                        .get("profile_targets")
+                        .cloned()
+                        .and_then(|profile_targets| {
+                            serde_json::from_value::<Vec<PathBuf>>(profile_targets).ok()
+                        })
+                        .unwrap_or_default();
+                    let replace_profile_targets = settings
+                        .get("replace_profile_targets")
+                        .and_then(|replace_profile_targets| replace_profile_targets.as_bool())
+                        .unwrap_or(false);
+                    shell_profile_locations = shell_profile_locations
+                        .with_targets(&profile_targets, replace_profile_targets);
+                    let append_corp_ca = settings
+                        .get("append_corp_ca")
+                        .and_then(|append_corp_ca| append_corp_ca.as_bool())
+                        .unwrap_or(false);
+                    ssl_cert_file = settings
+                        .get("ssl_cert_file")
+                        .and_then(|ssl_cert_file| ssl_cert_file.as_str())
+                        .and_then(|ssl_cert_file| {
+                            nix_ssl_cert_file(
+                                Some(Path::new(ssl_cert_file)),
+                                append_corp_ca,
+                                nix_conf_dir.as_deref().unwrap_or(Path::new(NIX_CONF_DIR)),
+                            )
+                        });
+                }
+                if let Err(err) = plan.check_host().await {
+                    if ignore_host_mismatch {
+                        tracing::warn!("{err}");
+                    } else {
+                        eprintln!("{}", err.red());
+                        return Ok(ExitCode::FAILURE);
+                    }
+                }
+            }
+        }
+
+        // Only modifying what the install was allowed to
+        let reconfigure = crate::path_policy::with_policy(
+            path_policy.clone(),
+            ConfigureShellProfile::plan(
+                shell_profile_locations,
+                ssl_cert_file,
+                nix_conf_dir,
+                posix_only_profile,
+            ),
+        )
+        .await
+        .map_err(PlannerError::Action)?;
+        // Logged, so the output of the `launchd` service running this at boot tells when macOS
+        // updates replaced a profile
+        let unhooked = reconfigure
+            .action
+            .unhooked_paths()
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        let mut reconfigure = reconfigure.boxed();
 
-        if let Err(err) = reconfigure.try_execute().await {
+        if let Err(err) =
+            crate::path_policy::with_policy(path_policy, reconfigure.try_execute()).await
+        {
             println!("{:#?}", err);
             Ok(ExitCode::FAILURE)
         } else {
+            if unhooked.is_empty() {
+                tracing::info!("The shell profiles already load Nix");
+            }
+            for path in unhooked {
+                tracing::info!("Added Nix to `{}`", path.display());
+            }
             Ok(ExitCode::SUCCESS)
         }
     }
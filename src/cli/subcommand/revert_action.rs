@@ -0,0 +1,128 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{ArgAction, Parser};
+use color_eyre::eyre::WrapErr;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::{ensure_root, interaction, interaction::PromptChoice, CommandExecute},
+    error::HasExpectedErrors,
+    plan::RECEIPT_LOCATION,
+    InstallPlan, NixInstallerError,
+};
+
+/**
+Revert a single action of the receipt by its id, keeping the rest of the install
+
+The action stays in the receipt, marked as reverted. Actions which other executed actions depend on
+are refused. An unknown id lists the ids of the receipt.
+*/
+#[derive(Debug, Parser)]
+pub struct RevertAction {
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_NO_CONFIRM",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub no_confirm: bool,
+
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_EXPLAIN",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub explain: bool,
+
+    /// Proceed even if the receipt was recorded on a different host
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_IGNORE_HOST_MISMATCH",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub ignore_host_mismatch: bool,
+
+    /// The id of the action, such as `configure_shell_profile`
+    pub action_id: String,
+
+    #[clap(long, default_value = RECEIPT_LOCATION)]
+    pub receipt: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl CommandExecute for RevertAction {
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn execute(self) -> eyre::Result<ExitCode> {
+        let Self {
+            no_confirm,
+            explain,
+            ignore_host_mismatch,
+            action_id,
+            receipt,
+        } = self;
+
+        ensure_root()?;
+
+        let install_receipt_string = tokio::fs::read_to_string(&receipt)
+            .await
+            .wrap_err("Reading receipt")?;
+        let mut plan: InstallPlan =
+            serde_json::from_str(&install_receipt_string).wrap_err("Parsing receipt")?;
+
+        if let Err(err) = plan.check_host().await {
+            if ignore_host_mismatch {
+                tracing::warn!("{err}");
+            } else {
+                eprintln!("{}", err.red());
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+
+        let mut currently_explaining = explain;
+        loop {
+            let description = match plan.describe_revert_action(&action_id, currently_explaining) {
+                Ok(description) => description,
+                Err(err) => return expected_or(err),
+            };
+            if no_confirm {
+                break;
+            }
+            match interaction::prompt(description, PromptChoice::Yes, currently_explaining).await? {
+                PromptChoice::Yes => break,
+                PromptChoice::Explain => currently_explaining = true,
+                PromptChoice::No => {
+                    interaction::clean_exit_with_message("Okay, didn't do anything! Bye!").await
+                },
+            }
+        }
+
+        let reverted = plan.revert_action(&action_id).await;
+        // Also when reverting failed part way, so the receipt shows the action in progress
+        let plan_json = serde_json::to_string_pretty(&plan).wrap_err("Serializing receipt")?;
+        tokio::fs::write(&receipt, format!("{plan_json}\n"))
+            .await
+            .wrap_err("Writing receipt")?;
+
+        match reverted {
+            Ok(synopsis) => {
+                println!("{}", format!("Reverted: {synopsis}").green().bold());
+                Ok(ExitCode::SUCCESS)
+            },
+            Err(err) => expected_or(err),
+        }
+    }
+}
+
+/// Print `err` and fail if it is expected, otherwise return it
+fn expected_or(err: NixInstallerError) -> eyre::Result<ExitCode> {
+    if let Some(expected) = err.expected() {
+        eprintln!("{}", expected.red());
+        return Ok(ExitCode::FAILURE);
+    }
+    Err(err)?
+}
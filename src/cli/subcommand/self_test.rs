@@ -2,7 +2,7 @@ use std::process::ExitCode;
 
 use clap::Parser;
 
-use crate::{cli::CommandExecute, NixInstallerError};
+use crate::{cli::CommandExecute, plan::RECEIPT_LOCATION, InstallPlan, NixInstallerError};
 
 /// Run a self test of Nix to ensure that an install is working
 #[derive(Debug, Parser)]
@@ -12,9 +12,16 @@ pub struct SelfTest {}
 impl CommandExecute for SelfTest {
     #[tracing::instrument(level = "debug", skip_all, fields())]
     async fn execute(self) -> eyre::Result<ExitCode> {
-        crate::self_test::self_test()
+        // Follow a configuration moved with `--nix-conf-dir`, and check what was configured for users
+        let plan = tokio::fs::read_to_string(RECEIPT_LOCATION)
             .await
-            .map_err(NixInstallerError::SelfTest)?;
+            .ok()
+            .and_then(|receipt| serde_json::from_str::<InstallPlan>(&receipt).ok());
+        match plan {
+            Some(plan) => crate::self_test::self_test_plan(&plan).await,
+            None => crate::self_test::self_test(None).await,
+        }
+        .map_err(NixInstallerError::SelfTest)?;
 
         tracing::info!(
             shells = ?crate::self_test::Shell::discover()
@@ -7,7 +7,9 @@ use std::{
 use crate::{
     cli::{ensure_root, interaction::PromptChoice, signal_channel},
     error::HasExpectedErrors,
+    os::{containers, dependents},
     plan::{current_version, RECEIPT_LOCATION},
+    upstream_receipt::{self, Translation},
     InstallPlan, NixInstallerError,
 };
 use clap::{ArgAction, Parser};
@@ -38,6 +40,48 @@ pub struct Uninstall {
     )]
     pub explain: bool,
 
+    /// Proceed even if the receipt was recorded on a different host
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_IGNORE_HOST_MISMATCH",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        global = true
+    )]
+    pub ignore_host_mismatch: bool,
+
+    /// Keep the artifacts of an action kind from the receipt (eg. `configure_shell_profile`) instead of reverting them
+    #[clap(long, action = ArgAction::Append, env = "NIX_INSTALLER_EXCEPT")]
+    pub except: Vec<String>,
+
+    /// Proceed even if services outside of the install run programs from `/nix`, which uninstalling breaks
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_BREAK_DEPENDENTS",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub break_dependents: bool,
+
+    /// Stop the services found running programs from `/nix` before uninstalling
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_STOP_DEPENDENTS",
+        action(ArgAction::SetTrue),
+        default_value = "false",
+        requires = "break_dependents"
+    )]
+    pub stop_dependents: bool,
+
+    /// Proceed even if containers keep images, volumes or their filesystems in `/nix`, which uninstalling removes
+    #[clap(
+        long,
+        env = "NIX_INSTALLER_BREAK_CONTAINERS",
+        action(ArgAction::SetTrue),
+        default_value = "false"
+    )]
+    pub break_containers: bool,
+
     #[clap(default_value = RECEIPT_LOCATION)]
     pub receipt: PathBuf,
 }
@@ -50,6 +94,11 @@ impl CommandExecute for Uninstall {
             no_confirm,
             receipt,
             explain,
+            ignore_host_mismatch,
+            except,
+            break_dependents,
+            stop_dependents,
+            break_containers,
         } = self;
 
         ensure_root()?;
@@ -112,9 +161,52 @@ impl CommandExecute for Uninstall {
             .await
             .wrap_err("Reading receipt")?;
 
-        let mut plan: InstallPlan = match serde_json::from_str(&install_receipt_string) {
-            Ok(plan) => plan,
-            Err(plan_err) => {
+        let translation = upstream_receipt::translate(&install_receipt_string).await?;
+        let mut plan: InstallPlan = match (
+            translation,
+            serde_json::from_str(&install_receipt_string),
+        ) {
+            (Some(translation), _) => {
+                let Translation {
+                    plan,
+                    upstream_version,
+                    skipped,
+                } = translation;
+                eprintln!(
+                    "{}",
+                    format!("The receipt was written by the upstream `nix-installer` version `{upstream_version}`, it was translated to be uninstalled by this one").yellow()
+                );
+                if !skipped.is_empty() {
+                    let skipped = format!(
+                        "{}
+{}
+
+These will not be reverted, review and clean them up by hand.",
+                        "The following actions of the receipt could not be translated:".bold(),
+                        skipped
+                            .iter()
+                            .map(|skipped| format!("* {skipped}"))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    );
+                    if no_confirm {
+                        eprintln!("{skipped}");
+                    } else {
+                        match interaction::prompt(skipped, PromptChoice::No, true).await? {
+                            PromptChoice::Yes => (),
+                            PromptChoice::No | PromptChoice::Explain => {
+                                interaction::clean_exit_with_message(
+                                    "Okay, didn't do anything! Bye!",
+                                )
+                                .await
+                            },
+                        }
+                    }
+                }
+                plan
+            },
+            (None, Ok(plan)) => plan,
+            (None, Err(plan_err)) => {
                 #[derive(serde::Deserialize)]
                 struct MinimalPlan {
                     version: semver::Version,
@@ -154,6 +246,23 @@ impl CommandExecute for Uninstall {
             return Ok(ExitCode::FAILURE);
         }
 
+        if let Err(err) = plan.check_host().await {
+            if ignore_host_mismatch {
+                tracing::warn!("{err}");
+            } else {
+                eprintln!("{}", err.red());
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+
+        let retained = match plan.retain(&except) {
+            Ok(retained) => retained,
+            Err(err) => {
+                eprintln!("{}", err.red());
+                return Ok(ExitCode::FAILURE);
+            },
+        };
+
         if let Err(err) = plan.pre_uninstall_check().await {
             if let Some(expected) = err.expected() {
                 eprintln!("{}", expected.red());
@@ -162,13 +271,65 @@ impl CommandExecute for Uninstall {
             Err(err)?
         }
 
-        if !no_confirm {
+        let ours = dependents::recorded_paths(
+            &serde_json::to_value(&plan).wrap_err("Serializing the receipt")?,
+        );
+        let dependents = dependents::scan(dependents::unit_dirs(), &ours);
+        if let Err(err) = dependents::check(&dependents, break_dependents) {
+            eprintln!("{}", err.red());
+            return Ok(ExitCode::FAILURE);
+        }
+        let mut dependents_warning = if dependents.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "{}\n{}\n\n",
+                if stop_dependents {
+                    "These services run programs from `/nix`, they will be stopped:"
+                } else {
+                    "These services run programs from `/nix`, they will break:"
+                }
+                .yellow()
+                .bold(),
+                dependents
+                    .iter()
+                    .map(|dependent| format!("* {dependent}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )
+        };
+
+        let container_uses = containers::scan(Path::new("/nix")).await;
+        if let Err(err) = containers::check(&container_uses, break_containers) {
+            eprintln!("{}", err.red());
+            return Ok(ExitCode::FAILURE);
+        }
+        if !container_uses.is_empty() {
+            dependents_warning += &format!(
+                "{}\n{}\n\n",
+                "These containers use `/nix`, they will break:"
+                    .yellow()
+                    .bold(),
+                container_uses
+                    .iter()
+                    .map(|container_use| format!("* {container_use}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+
+        if no_confirm {
+            eprint!("{dependents_warning}");
+        } else {
             let mut currently_explaining = explain;
             loop {
                 match interaction::prompt(
-                    plan.describe_uninstall(currently_explaining)
-                        .await
-                        .map_err(|e| eyre!(e))?,
+                    format!(
+                        "{dependents_warning}{}",
+                        plan.describe_uninstall(currently_explaining)
+                            .await
+                            .map_err(|e| eyre!(e))?
+                    ),
                     PromptChoice::Yes,
                     currently_explaining,
                 )
@@ -183,6 +344,16 @@ impl CommandExecute for Uninstall {
             }
         }
 
+        if stop_dependents {
+            for dependent in &dependents {
+                tracing::info!("Stopping `{}`", dependent.unit);
+                dependent
+                    .stop()
+                    .await
+                    .wrap_err_with(|| format!("Stopping `{}`", dependent.unit))?;
+            }
+        }
+
         let (_tx, rx) = signal_channel().await?;
 
         let res = plan.uninstall(rx).await;
@@ -207,6 +378,28 @@ impl CommandExecute for Uninstall {
             ",
             success = "Nix was uninstalled successfully!".green().bold(),
         );
+        if !retained.is_empty() {
+            println!(
+                "{}\n{}\n",
+                "The following were intentionally kept:".bold(),
+                retained
+                    .iter()
+                    .map(|synopsis| format!("* {synopsis}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        if !plan.leftovers().is_empty() {
+            println!(
+                "{}\n{}\n",
+                "The following changed since the install and were left in place, clean them up by hand if they are no longer needed:".bold(),
+                plan.leftovers()
+                    .iter()
+                    .map(|leftover| format!("* {leftover}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
 
         Ok(ExitCode::SUCCESS)
     }
@@ -0,0 +1,395 @@
+/*! Every external command the installer runs goes through a [`CommandRunner`]
+
+Commands are run by the [`RealRunner`] unless another runner is chosen with [`set_runner`], and
+whichever runs them, each run for a plan is recorded in its audit log as it finishes: its argv, when it started, its working directory,
+the environment variables set for it, its exit code and how long it took. The log is kept in the
+receipt and summarized (without arguments) in diagnostics. Commands run outside of the plan's
+calls (see [`recording`]), such as probes of another plan, are not recorded.
+
+A [`MockRunner`] answers commands with canned results instead of running them, so planning and
+executing can be exercised in CI without root:
+
+```rust,no_run
+use std::sync::Arc;
+use nix_installer::command_runner::{set_runner, MockRunner};
+
+set_runner(Arc::new(
+    MockRunner::default().respond(&["systemctl", "is-active"], 3, "inactive\n"),
+));
+```
+*/
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::{ExitStatus, Output, Stdio},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::timestamp::Timestamp;
+
+/// Something which runs external commands to completion
+#[async_trait::async_trait]
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    /// Run `command`, writing `stdin` to it if any, and collect its output
+    ///
+    /// With `stdin` the output of `command` is always collected, as `Command::output` does.
+    async fn output(&self, command: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<Output>;
+}
+
+#[async_trait::async_trait]
+impl<R: CommandRunner + ?Sized> CommandRunner for Arc<R> {
+    async fn output(&self, command: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<Output> {
+        (**self).output(command, stdin).await
+    }
+}
+
+/// Runs commands for real
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RealRunner;
+
+#[async_trait::async_trait]
+impl CommandRunner for RealRunner {
+    async fn output(&self, command: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<Output> {
+        let Some(stdin) = stdin else {
+            return command.output().await;
+        };
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = command.spawn()?;
+        if let Some(mut handle) = child.stdin.take() {
+            handle.write_all(stdin).await?;
+            handle.flush().await?;
+        }
+        child.wait_with_output().await
+    }
+}
+
+/// Answers commands with canned results, running none of them
+///
+/// Commands without a [`respond`](MockRunner::respond)ed result succeed without output.
+#[derive(Debug, Default, Clone)]
+pub struct MockRunner {
+    responses: Vec<(Vec<String>, i32, Vec<u8>)>,
+}
+
+impl MockRunner {
+    /// Answer commands whose argv starts with `prefix` with `exit_code` and `stdout`
+    ///
+    /// The program is matched by its file name, as most are run by their absolute path. The first
+    /// matching response is used.
+    pub fn respond(mut self, prefix: &[&str], exit_code: i32, stdout: impl Into<Vec<u8>>) -> Self {
+        self.responses.push((
+            prefix.iter().map(ToString::to_string).collect(),
+            exit_code,
+            stdout.into(),
+        ));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl CommandRunner for MockRunner {
+    async fn output(
+        &self,
+        command: &mut Command,
+        _stdin: Option<&[u8]>,
+    ) -> std::io::Result<Output> {
+        let mut argv = argv(command);
+        if let Some(program) = argv.first_mut() {
+            if let Some(file_name) = PathBuf::from(&*program).file_name() {
+                *program = file_name.to_string_lossy().into_owned();
+            }
+        }
+        let (exit_code, stdout) = self
+            .responses
+            .iter()
+            .find(|(prefix, _, _)| argv.starts_with(prefix))
+            .map(|(_, exit_code, stdout)| (*exit_code, stdout.clone()))
+            .unwrap_or_default();
+        Ok(Output {
+            status: ExitStatus::from_raw(exit_code << 8),
+            stdout,
+            stderr: vec![],
+        })
+    }
+}
+
+/// An external command which was run, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CommandRecord {
+    pub argv: Vec<String>,
+    /// `None` in receipts predating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// The variables set for the command (`None` when removed), not the environment it inherited
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, Option<String>>,
+    /// `None` if it could not be run, or was killed by a signal
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+impl CommandRecord {
+    fn new(
+        command: &Command,
+        result: &std::io::Result<Output>,
+        started_at: Timestamp,
+        duration: Duration,
+    ) -> Self {
+        let std = command.as_std();
+        Self {
+            argv: argv(command),
+            started_at: Some(started_at),
+            cwd: std.get_current_dir().map(PathBuf::from),
+            env: std
+                .get_envs()
+                .map(|(name, value)| {
+                    (
+                        name.to_string_lossy().into_owned(),
+                        value.map(|value| value.to_string_lossy().into_owned()),
+                    )
+                })
+                .collect(),
+            exit_code: result.as_ref().ok().and_then(|output| output.status.code()),
+            duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        }
+    }
+
+    /// The file name of the program, what diagnostics report of the command
+    pub fn program(&self) -> String {
+        self.argv
+            .first()
+            .map(|program| {
+                PathBuf::from(program)
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| program.clone())
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn argv(command: &Command) -> Vec<String> {
+    let std = command.as_std();
+    std::iter::once(std.get_program())
+        .chain(std.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Records every command `inner` runs
+#[derive(Debug, Default)]
+pub struct RecordingRunner<R> {
+    inner: R,
+    log: Arc<Mutex<Vec<CommandRecord>>>,
+}
+
+impl<R> RecordingRunner<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            log: Default::default(),
+        }
+    }
+
+    /// The commands run so far, in the order they finished
+    pub fn records(&self) -> Vec<CommandRecord> {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: CommandRunner> CommandRunner for RecordingRunner<R> {
+    async fn output(&self, command: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<Output> {
+        let (started_at, start) = (Timestamp::now(), Instant::now());
+        let result = self.inner.output(command, stdin).await;
+        let record = CommandRecord::new(command, &result, started_at, start.elapsed());
+        tracing::debug!(
+            argv = ?record.argv,
+            exit_code = ?record.exit_code,
+            duration_ms = record.duration_ms,
+            "Ran command"
+        );
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(record);
+        result
+    }
+}
+
+/// The runner commands are run with, the [`RealRunner`] unless set
+static RUNNER: RwLock<Option<Arc<dyn CommandRunner>>> = RwLock::new(None);
+
+tokio::task_local! {
+    /// The audit log of the plan being planned or executed
+    static LOG: Arc<Mutex<Vec<CommandRecord>>>;
+}
+
+/// Run commands with `runner` from now on
+pub fn set_runner(runner: Arc<dyn CommandRunner>) {
+    *RUNNER.write().unwrap_or_else(PoisonError::into_inner) = Some(runner);
+}
+
+/// The current runner, recording to the audit log of the plan if there is one
+fn runner() -> RecordingRunner<Arc<dyn CommandRunner>> {
+    let inner = RUNNER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| Arc::new(RealRunner));
+    let log = LOG.try_with(Clone::clone).unwrap_or_default();
+    RecordingRunner { inner, log }
+}
+
+/// Run `fut`, recording the commands it runs after `commands`, and return them alongside its output
+pub(crate) async fn recording<F: Future>(
+    commands: Vec<CommandRecord>,
+    fut: F,
+) -> (F::Output, Vec<CommandRecord>) {
+    let log = Arc::new(Mutex::new(commands));
+    let output = LOG.scope(log.clone(), fut).await;
+    let commands = std::mem::take(&mut *log.lock().unwrap_or_else(PoisonError::into_inner));
+    (output, commands)
+}
+
+/// The commands of the plan being planned or executed, in the order they finished
+///
+/// Empty outside of [`recording`].
+pub fn audit_log() -> Vec<CommandRecord> {
+    LOG.try_with(|log| log.lock().unwrap_or_else(PoisonError::into_inner).clone())
+        .unwrap_or_default()
+}
+
+/// Run `command` with the current runner, see [`set_runner`]
+pub(crate) async fn output(command: &mut Command) -> std::io::Result<Output> {
+    debug_assert_not_archiver(command);
+    crate::env::scrub(command);
+    runner().output(command, None).await
+}
+
+/// Run `command` with the current runner, writing `stdin` to it
+pub(crate) async fn output_with_stdin(
+    command: &mut Command,
+    stdin: &[u8],
+) -> std::io::Result<Output> {
+    debug_assert_not_archiver(command);
+    crate::env::scrub(command);
+    runner().output(command, Some(stdin)).await
+}
+
+/// Hosts without `tar` or `xz` can install Nix, so none of the [`ARCHIVERS`](crate::os::tools::ARCHIVERS) are run
+fn debug_assert_not_archiver(command: &Command) {
+    debug_assert!(
+        !crate::os::tools::is_archiver(command.as_std().get_program()),
+        "`{}` is an archiver, unpack in process instead",
+        command.as_std().get_program().to_string_lossy()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_commands() -> eyre::Result<()> {
+        let runner = RecordingRunner::new(RealRunner);
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "read line; test \"$line\" = \"$EXPECTED\""])
+            .env("EXPECTED", "hello")
+            .current_dir("/");
+        let output = runner.output(&mut command, Some(b"hello\n")).await?;
+        assert!(output.status.success());
+        runner
+            .output(Command::new("sh").args(["-c", "exit 3"]), None)
+            .await?;
+        assert!(runner
+            .output(&mut Command::new("/nonexistent/program"), None)
+            .await
+            .is_err());
+
+        let records = runner.records();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| (record.argv.clone(), record.exit_code))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    vec![
+                        "sh".to_string(),
+                        "-c".to_string(),
+                        "read line; test \"$line\" = \"$EXPECTED\"".to_string()
+                    ],
+                    Some(0)
+                ),
+                (vec!["sh".into(), "-c".into(), "exit 3".into()], Some(3)),
+                (vec!["/nonexistent/program".into()], None),
+            ]
+        );
+        assert_eq!(records[0].cwd, Some(PathBuf::from("/")));
+        assert_eq!(
+            records[0].env,
+            BTreeMap::from([("EXPECTED".to_string(), Some("hello".to_string()))])
+        );
+        assert_eq!(records[2].program(), "program");
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "is an archiver")]
+    async fn refuses_to_run_archivers() {
+        let _ = output(Command::new("/usr/bin/tar").args(["-xf", "nix.tar.xz"])).await;
+    }
+
+    #[tokio::test]
+    async fn mock_runs_nothing() -> eyre::Result<()> {
+        let runner = RecordingRunner::new(
+            MockRunner::default()
+                .respond(&["systemctl", "is-active"], 3, "inactive\n")
+                .respond(&["systemctl"], 1, ""),
+        );
+        let output = runner
+            .output(
+                Command::new("/usr/bin/systemctl").args(["is-active", "nix-daemon.service"]),
+                None,
+            )
+            .await?;
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"inactive\n");
+        let output = runner
+            .output(Command::new("systemctl").arg("daemon-reload"), None)
+            .await?;
+        assert_eq!(output.status.code(), Some(1));
+        // Would fail if it were run
+        let output = runner
+            .output(&mut Command::new("/nonexistent/useradd"), None)
+            .await?;
+        assert!(output.status.success());
+
+        assert_eq!(
+            runner
+                .records()
+                .iter()
+                .map(|record| record.exit_code)
+                .collect::<Vec<_>>(),
+            [Some(3), Some(1), Some(0)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,583 @@
+/*! Conversion of an existing install between single-user and multi-user modes
+
+A [`Conversion`] keeps the store, and changes everything around it using the existing actions:
+
+* The actions of the receipt which only belong to the current mode are reverted (stopping the
+  daemon, removing the build users and group, and the `nix.conf` naming them).
+* The store (and `/nix/var`) is re-owned in bulk with [`ReownNixStore`].
+* The actions of the new mode are planned against the re-owned store and run.
+
+The receipt is updated as it goes, so it always describes what is installed and an uninstall
+reverts the conversion as well.
+*/
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use owo_colors::OwoColorize;
+use tokio::sync::broadcast::Receiver;
+
+use crate::{
+    action::{
+        base::{Reown, ReownNixStore},
+        common::PlaceNixConfiguration,
+        Action, ActionDescription, StatefulAction,
+    },
+    plan::{current_version, within_plan, write_receipt},
+    planner::BuiltinPlanner,
+    settings::CommonSettings,
+    HostFingerprint, InstallPlan, NixInstallerError,
+};
+
+/// Action kinds a multi-user install has, and a single-user one does not
+const MULTI_USER_KINDS: &[&str] = &[
+    "create_users_and_group",
+    "place_nix_configuration",
+    "configure_daemon_log_rotation",
+    "configure_init_service",
+];
+/// Action kinds a single-user install (as converted to) has, and a multi-user one does not
+const SINGLE_USER_KINDS: &[&str] = &["reown_nix_store", "place_nix_configuration"];
+/// The profile the daemon of a multi-user install is run from
+const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/default";
+
+/// How an install is used, see [`Conversion`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallMode {
+    /// The store is owned by a user, who runs Nix without a daemon
+    SingleUser,
+    /// The store is owned by `root`, builds are run by the daemon as the build users
+    MultiUser,
+}
+
+impl std::fmt::Display for InstallMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SingleUser => write!(f, "single-user"),
+            Self::MultiUser => write!(f, "multi-user"),
+        }
+    }
+}
+
+/// The owner and group of a store, or of the user a store is converted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Owner {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl InstallMode {
+    /// The mode of the store at `store` and its owner, `None` if there is no store
+    pub(crate) fn detect(store: &Path) -> Option<(Self, Owner)> {
+        let metadata = store.metadata().ok()?;
+        let owner = Owner {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        };
+        let mode = if owner.uid == 0 {
+            Self::MultiUser
+        } else {
+            Self::SingleUser
+        };
+        Some((mode, owner))
+    }
+}
+
+/**
+Plan re-owning the store (and `/nix/var`) under `nix_root` for a conversion to `to`
+
+Converting to single-user gives `store`'s paths to `user`, the store directory's build group
+becomes the user's group. Converting to multi-user gives everything `store`'s owner has to `root`.
+*/
+pub(crate) fn plan_reown(
+    nix_root: &Path,
+    to: InstallMode,
+    store: Owner,
+    user: Owner,
+) -> Result<Vec<StatefulAction<Box<dyn Action>>>, NixInstallerError> {
+    let (store_reown, var_reown) = match to {
+        InstallMode::SingleUser => (
+            Reown {
+                uid: Some((0, user.uid)),
+                gid: (store.gid != 0).then_some((store.gid, user.gid)),
+            },
+            Reown {
+                uid: Some((0, user.uid)),
+                gid: None,
+            },
+        ),
+        InstallMode::MultiUser => {
+            let reown = Reown {
+                uid: Some((store.uid, 0)),
+                gid: (store.gid != 0).then_some((store.gid, 0)),
+            };
+            (reown, reown)
+        },
+    };
+    Ok(vec![
+        ReownNixStore::plan(nix_root.join("store"), store_reown)
+            .map_err(NixInstallerError::Action)?
+            .boxed(),
+        ReownNixStore::plan(nix_root.join("var"), var_reown)
+            .map_err(NixInstallerError::Action)?
+            .boxed(),
+    ])
+}
+
+/// The indexes of the actions among `kinds` which belong to the mode other than `to`, in the order to revert them
+pub(crate) fn retired(kinds: &[&str], to: InstallMode) -> Vec<usize> {
+    let retire = match to {
+        InstallMode::SingleUser => MULTI_USER_KINDS,
+        InstallMode::MultiUser => SINGLE_USER_KINDS,
+    };
+    kinds
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, kind)| retire.contains(kind))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// The actions of a fresh multi-user `planned` which a converted install lacks, given the kinds it has
+pub(crate) fn adopted(
+    planned: Vec<StatefulAction<Box<dyn Action>>>,
+    kinds: &[&str],
+) -> Vec<StatefulAction<Box<dyn Action>>> {
+    planned
+        .into_iter()
+        .filter(|action| {
+            let kind = action.inner_typetag_name();
+            MULTI_USER_KINDS.contains(&kind)
+                || (kind == "configure_shell_profile" && !kinds.contains(&kind))
+        })
+        .collect()
+}
+
+/// Shell profiles of `home` which still load the single-user `nix.sh`, the single-user installer adds this
+pub(crate) fn single_user_hooks(home: &Path) -> Vec<PathBuf> {
+    [".profile", ".bash_profile", ".bash_login", ".zshenv"]
+        .iter()
+        .map(|name| home.join(name))
+        .filter(|path| {
+            std::fs::read(path)
+                .map(|buf| {
+                    String::from_utf8_lossy(&buf).contains(".nix-profile/etc/profile.d/nix.sh")
+                })
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/**
+Convert an existing install to another [`InstallMode`]
+
+Run in three stages, as the new mode's actions can only be planned once the old mode's are reverted
+(its `nix.conf` cannot be merged into) and the store is re-owned (or creating the build group would
+refuse the store's group).
+*/
+#[derive(Debug)]
+pub(crate) struct Conversion {
+    pub(crate) to: InstallMode,
+    /// The owner of the store before converting
+    pub(crate) store: Owner,
+    /// The receipt, or a new one if the install has none
+    plan: InstallPlan,
+    /// Indexes of the actions of [`plan`](Conversion::plan) to revert, in order
+    retire: Vec<usize>,
+    /// Re-owns the store, empty if reverting the receipt's re-own does this
+    reown: Vec<StatefulAction<Box<dyn Action>>>,
+    /// Used to plan the actions of the new mode
+    settings: CommonSettings,
+}
+
+impl Conversion {
+    /// Plan converting the install described by `receipt` (if it has one) to `to`, `user` is who a single-user store is given to
+    pub(crate) async fn plan(
+        receipt: Option<InstallPlan>,
+        to: InstallMode,
+        user: Option<Owner>,
+        settings: CommonSettings,
+    ) -> Result<Self, NixInstallerError> {
+        let nix_root = Path::new("/nix");
+        let Some((mode, store)) = InstallMode::detect(&nix_root.join("store")) else {
+            return Err(NixInstallerError::NoStoreToConvert);
+        };
+        if mode == to {
+            return Err(NixInstallerError::AlreadyInMode(to));
+        }
+
+        let plan = match receipt {
+            Some(receipt) => receipt,
+            None if to == InstallMode::MultiUser => {
+                let planner = BuiltinPlanner::from_common_settings(settings.clone())
+                    .await?
+                    .boxed();
+                let tools = planner.resolve_tools().await?;
+                InstallPlan {
+                    version: current_version()?,
+                    build: Some(crate::build_info::BuildInfo::current()),
+                    actions: Vec::new(),
+                    planner,
+                    #[cfg(feature = "diagnostics")]
+                    diagnostic_data: None,
+                    host_fingerprint: None,
+                    tools,
+                    commands: Vec::new(),
+                    install_id: None,
+                    planned_at: Some(crate::timestamp::Timestamp::now()),
+                    installed_at: None,
+                    reboot_checkpoint: None,
+                    keep_temp: false,
+                    receipt_location: None,
+                    leftovers: Vec::new(),
+                }
+            },
+            None => return Err(NixInstallerError::ConversionNeedsReceipt),
+        };
+
+        let kinds = plan
+            .actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        let retire = retired(&kinds, to);
+        let reown = match to {
+            InstallMode::SingleUser => {
+                if !kinds.contains(&"create_users_and_group") {
+                    return Err(NixInstallerError::ConversionNeedsReceipt);
+                }
+                let user = user.ok_or(NixInstallerError::ConversionNeedsUser)?;
+                plan_reown(nix_root, to, store, user)?
+            },
+            InstallMode::MultiUser => {
+                if !Path::new(DEFAULT_PROFILE).exists() {
+                    return Err(NixInstallerError::NoDefaultProfile(PathBuf::from(
+                        DEFAULT_PROFILE,
+                    )));
+                }
+                // Reverting the re-own of an earlier conversion gives the store back to `root`
+                if kinds.contains(&"reown_nix_store") {
+                    Vec::new()
+                } else {
+                    plan_reown(nix_root, to, store, Owner { uid: 0, gid: 0 })?
+                }
+            },
+        };
+
+        Ok(Self {
+            to,
+            store,
+            plan,
+            retire,
+            reown,
+            settings,
+        })
+    }
+
+    pub(crate) fn describe(&self, explain: bool) -> String {
+        let describe = |descriptions: Vec<ActionDescription>| {
+            descriptions
+                .into_iter()
+                .map(
+                    |ActionDescription {
+                         description,
+                         explanation,
+                     }| {
+                        let mut buf = format!("* {description}");
+                        if explain {
+                            for line in explanation {
+                                buf.push_str(&format!("\n  {line}"));
+                            }
+                        }
+                        buf
+                    },
+                )
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let planned = match self.to {
+            InstallMode::SingleUser => "* Place a `nix.conf` without a build users group",
+            InstallMode::MultiUser => {
+                "* Create the build users and group, place the `nix.conf` naming them, and configure the init system to run the daemon"
+            },
+        };
+
+        format!(
+            "\
+            Nix conversion plan to {to} (v{version})\n\
+            \n\
+            Reverted actions:\n\
+            {reverted}\n\
+            \n\
+            Planned actions:\n\
+            {reown}\n\
+            {planned}\n\
+            ",
+            to = self.to.bold(),
+            version = self.plan.version,
+            reverted = describe(
+                self.retire
+                    .iter()
+                    .flat_map(|index| self.plan.actions[*index].describe_revert())
+                    .collect()
+            ),
+            reown = describe(
+                self.reown
+                    .iter()
+                    .flat_map(|action| action.describe_execute())
+                    .collect()
+            ),
+        )
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(to = %self.to))]
+    pub(crate) async fn convert(
+        self,
+        cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<InstallPlan, NixInstallerError> {
+        let (tools, policy, commands) = (
+            self.plan.tools.clone(),
+            self.plan.path_policy()?,
+            self.plan.commands.clone(),
+        );
+        let (converted, commands) = within_plan(
+            tools,
+            policy,
+            commands,
+            self.convert_with_tools(cancel_channel.into()),
+        )
+        .await;
+        let mut converted = converted?;
+        converted.commands = commands;
+        Ok(converted)
+    }
+
+    async fn convert_with_tools(
+        mut self,
+        mut cancel_channel: Option<Receiver<()>>,
+    ) -> Result<InstallPlan, NixInstallerError> {
+        let mut errors = Vec::new();
+        for index in &self.retire {
+            let action = &mut self.plan.actions[*index];
+            tracing::info!("Revert: {}", action.tracing_synopsis());
+            if let Err(err) =
+                crate::cancellation::scope(cancel_channel.as_ref(), action.try_revert()).await
+            {
+                errors.push(err);
+            }
+        }
+        if !errors.is_empty() {
+            self.record().await;
+            return Err(NixInstallerError::ActionRevert(errors));
+        }
+        let mut retire = std::mem::take(&mut self.retire);
+        retire.sort_unstable();
+        for index in retire.into_iter().rev() {
+            self.plan.actions.remove(index);
+        }
+
+        let reown = std::mem::take(&mut self.reown);
+        self.execute(reown, &mut cancel_channel).await?;
+
+        let planned = match self.to {
+            InstallMode::SingleUser => {
+                let mut settings = self.settings.clone();
+                // Nix refuses to run without a daemon when this names a group
+                settings.nix_build_group_name = String::new();
+                vec![PlaceNixConfiguration::plan(&settings)
+                    .await
+                    .map_err(NixInstallerError::Action)?
+                    .boxed()]
+            },
+            InstallMode::MultiUser => {
+                let kinds = self
+                    .plan
+                    .actions
+                    .iter()
+                    .map(|action| action.inner_typetag_name())
+                    .collect::<Vec<_>>();
+                let planned = self.plan.planner.plan().await?;
+                adopted(planned, &kinds)
+            },
+        };
+        self.execute(planned, &mut cancel_channel).await?;
+
+        self.record().await;
+        Ok(self.plan)
+    }
+
+    /// Append `actions` to the receipt and run them
+    async fn execute(
+        &mut self,
+        actions: Vec<StatefulAction<Box<dyn Action>>>,
+        cancel_channel: &mut Option<Receiver<()>>,
+    ) -> Result<(), NixInstallerError> {
+        let start = self.plan.actions.len();
+        self.plan.actions.extend(actions);
+        for index in start..self.plan.actions.len() {
+            if let Some(cancel_channel) = cancel_channel {
+                if cancel_channel.try_recv()
+                    != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
+                {
+                    self.record().await;
+                    return Err(NixInstallerError::Cancelled);
+                }
+            }
+            let action = &mut self.plan.actions[index];
+            tracing::info!("Step: {}", action.tracing_synopsis());
+            if let Err(err) =
+                crate::cancellation::scope(cancel_channel.as_ref(), action.try_execute()).await
+            {
+                self.record().await;
+                return Err(NixInstallerError::Action(err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the receipt as it is now
+    async fn record(&mut self) {
+        self.plan.host_fingerprint = Some(HostFingerprint::current().await);
+        if let Err(err) = write_receipt(self.plan.clone()).await {
+            tracing::error!("Error saving receipt: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::MetadataExt;
+
+    use nix::unistd::{chown, Gid, Uid};
+
+    use super::*;
+
+    const MULTI_USER_RECEIPT: &[&str] = &[
+        "create_directory",
+        "provision_nix",
+        "create_users_and_group",
+        "setup_default_profile",
+        "place_nix_configuration",
+        "configure_shell_profile",
+        "create_directory",
+        "configure_init_service",
+        "remove_directory",
+    ];
+
+    #[test]
+    fn retires_the_actions_of_the_other_mode() {
+        assert_eq!(
+            retired(MULTI_USER_RECEIPT, InstallMode::SingleUser),
+            [7, 4, 2]
+        );
+        assert_eq!(retired(MULTI_USER_RECEIPT, InstallMode::MultiUser), [4]);
+
+        // As left by converting to single-user
+        let converted = [
+            "create_directory",
+            "provision_nix",
+            "setup_default_profile",
+            "configure_shell_profile",
+            "reown_nix_store",
+            "reown_nix_store",
+            "place_nix_configuration",
+        ];
+        assert_eq!(retired(&converted, InstallMode::MultiUser), [6, 5, 4]);
+    }
+
+    #[test]
+    fn finds_single_user_hooks() -> eyre::Result<()> {
+        let home = tempfile::TempDir::new()?;
+        std::fs::write(
+            home.path().join(".profile"),
+            "if [ -e /home/alice/.nix-profile/etc/profile.d/nix.sh ]; then . /home/alice/.nix-profile/etc/profile.d/nix.sh; fi # added by Nix installer\n",
+        )?;
+        std::fs::write(home.path().join(".zshenv"), "export EDITOR=vi\n")?;
+
+        assert_eq!(
+            single_user_hooks(home.path()),
+            [home.path().join(".profile")]
+        );
+        Ok(())
+    }
+
+    fn owners(root: &Path) -> Vec<(u32, u32)> {
+        let mut owners = walkdir::WalkDir::new(root)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.path().symlink_metadata().ok())
+            .map(|metadata| (metadata.uid(), metadata.gid()))
+            .collect::<Vec<_>>();
+        owners.sort();
+        owners.dedup();
+        owners
+    }
+
+    /// Converts a multi-user tree in a temporary root to single-user and back
+    #[tokio::test]
+    async fn reowns_between_modes() -> eyre::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, changing owners needs root");
+            return Ok(());
+        }
+        let (build_gid, user) = (
+            30000,
+            Owner {
+                uid: 1234,
+                gid: 5678,
+            },
+        );
+        let root = tempfile::TempDir::new()?;
+        let nix_root = root.path();
+        for dir in ["store/abc-hello/bin", "var/nix/profiles", "var/nix/db"] {
+            std::fs::create_dir_all(nix_root.join(dir))?;
+        }
+        std::fs::write(nix_root.join("store/abc-hello/bin/hello"), "")?;
+        std::os::unix::fs::symlink(
+            "/nix/store/abc-hello",
+            nix_root.join("var/nix/profiles/default"),
+        )?;
+        chown(
+            &nix_root.join("store"),
+            Some(Uid::from_raw(0)),
+            Some(Gid::from_raw(build_gid)),
+        )?;
+
+        let (mode, store) = InstallMode::detect(&nix_root.join("store")).expect("Expected a store");
+        assert_eq!(mode, InstallMode::MultiUser);
+
+        let mut to_single_user = plan_reown(nix_root, InstallMode::SingleUser, store, user)?;
+        for action in to_single_user.iter_mut() {
+            action.try_execute().await?;
+        }
+        assert_eq!(
+            InstallMode::detect(&nix_root.join("store")),
+            Some((InstallMode::SingleUser, user))
+        );
+        assert_eq!(owners(&nix_root.join("store")), [(1234, 0), (1234, 5678)]);
+        assert_eq!(owners(&nix_root.join("var")), [(1234, 0)]);
+
+        // A native single-user store, as left by the single-user installer
+        chown(
+            &nix_root.join("store/abc-hello"),
+            Some(Uid::from_raw(user.uid)),
+            Some(Gid::from_raw(user.gid)),
+        )?;
+        let mut to_multi_user = plan_reown(
+            nix_root,
+            InstallMode::MultiUser,
+            user,
+            Owner { uid: 0, gid: 0 },
+        )?;
+        for action in to_multi_user.iter_mut() {
+            action.try_execute().await?;
+        }
+        assert_eq!(owners(nix_root), [(0, 0)]);
+        Ok(())
+    }
+}
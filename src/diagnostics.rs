@@ -10,9 +10,11 @@ use std::{path::PathBuf, time::Duration};
 use os_release::OsRelease;
 use reqwest::Url;
 
+use uuid::Uuid;
+
 use crate::{
-    action::ActionError, parse_ssl_cert, planner::PlannerError, settings::InstallSettingsError,
-    CertificateError, NixInstallerError,
+    action::ActionError, env, http, planner::PlannerError, settings::InstallSettingsError,
+    timestamp::Timestamp, CertificateError, NixInstallerError,
 };
 
 /// The static of an action attempt
@@ -34,8 +36,16 @@ pub enum DiagnosticAction {
 /// A report sent to an endpoint
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct DiagnosticReport {
+    /// The id of the install reported on, see [`InstallPlan::install_id`](crate::InstallPlan::install_id)
+    #[serde(default)]
+    pub install_id: Option<Uuid>,
+    #[serde(default)]
+    pub reported_at: Option<Timestamp>,
     pub attribution: Option<String>,
     pub version: String,
+    /// What the binary was built from, `None` for reports predating it
+    #[serde(default)]
+    pub build: Option<crate::build_info::BuildInfo>,
     pub planner: String,
     pub configured_settings: Vec<String>,
     pub os_name: String,
@@ -46,13 +56,31 @@ pub struct DiagnosticReport {
     pub status: DiagnosticStatus,
     /// Generally this includes the [`strum::IntoStaticStr`] representation of the error, we take special care not to include parameters of the error (which may include secrets)
     pub failure_chain: Option<Vec<String>>,
+    /// The external commands run, see [`audit_log`](crate::command_runner::audit_log)
+    #[serde(default)]
+    pub commands: Vec<DiagnosticCommand>,
+}
+
+/// An external command which was run, without its arguments (which may include secrets)
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct DiagnosticCommand {
+    pub program: String,
+    #[serde(default)]
+    pub started_at: Option<Timestamp>,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
 }
 
 /// A preparation of data to be sent to the `endpoint`.
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
 pub struct DiagnosticData {
+    #[serde(default)]
+    install_id: Option<Uuid>,
     attribution: Option<String>,
     version: String,
+    /// What the binary was built from, `None` for diagnostics predating it
+    #[serde(default)]
+    build: Option<crate::build_info::BuildInfo>,
     planner: String,
     configured_settings: Vec<String>,
     os_name: String,
@@ -70,9 +98,11 @@ impl DiagnosticData {
         attribution: Option<String>,
         endpoint: Option<String>,
         planner: String,
-        configured_settings: Vec<String>,
+        mut configured_settings: Vec<String>,
         ssl_cert_file: Option<PathBuf>,
     ) -> Result<Self, DiagnosticError> {
+        // Collected from a `HashMap`, whose order differs between runs
+        configured_settings.sort();
         let endpoint = match endpoint {
             Some(endpoint) => diagnostic_endpoint_parser(&endpoint)?,
             None => None,
@@ -81,12 +111,14 @@ impl DiagnosticData {
             Ok(os_release) => (os_release.name, os_release.version),
             Err(_) => ("unknown".into(), "unknown".into()),
         };
-        let is_ci = is_ci::cached()
-            || std::env::var("NIX_INSTALLER_CI").unwrap_or_else(|_| "0".into()) == "1";
+        let is_ci =
+            is_ci::cached() || env::NIX_INSTALLER_CI.get().unwrap_or_else(|| "0".into()) == "1";
         Ok(Self {
+            install_id: None,
             attribution,
             endpoint,
             version: env!("CARGO_PKG_VERSION").into(),
+            build: Some(crate::build_info::BuildInfo::current()),
             planner,
             configured_settings,
             os_name,
@@ -98,6 +130,11 @@ impl DiagnosticData {
         })
     }
 
+    /// Report on the install `install_id`, see [`InstallPlan::install_id`](crate::InstallPlan::install_id)
+    pub fn set_install_id(&mut self, install_id: Uuid) {
+        self.install_id = Some(install_id);
+    }
+
     pub fn failure(mut self, err: &NixInstallerError) -> Self {
         let mut failure_chain = vec![];
         let diagnostic = err.diagnostic();
@@ -135,8 +172,10 @@ impl DiagnosticData {
 
     pub fn report(&self, action: DiagnosticAction, status: DiagnosticStatus) -> DiagnosticReport {
         let Self {
+            install_id,
             attribution,
             version,
+            build,
             planner,
             configured_settings,
             os_name,
@@ -148,8 +187,11 @@ impl DiagnosticData {
             failure_chain,
         } = self;
         DiagnosticReport {
+            install_id: *install_id,
+            reported_at: Some(Timestamp::now()),
             attribution: attribution.clone(),
             version: version.clone(),
+            build: build.clone(),
             planner: planner.clone(),
             configured_settings: configured_settings.clone(),
             os_name: os_name.clone(),
@@ -159,6 +201,15 @@ impl DiagnosticData {
             action,
             status,
             failure_chain: failure_chain.clone(),
+            commands: crate::command_runner::audit_log()
+                .iter()
+                .map(|record| DiagnosticCommand {
+                    program: record.program(),
+                    started_at: record.started_at,
+                    exit_code: record.exit_code,
+                    duration_ms: record.duration_ms,
+                })
+                .collect(),
         }
     }
 
@@ -178,20 +229,24 @@ impl DiagnosticData {
         match endpoint.scheme() {
             "https" | "http" => {
                 tracing::debug!("Sending diagnostic to `{endpoint}`");
-                let mut buildable_client = reqwest::Client::builder();
-                if let Some(ssl_cert_file) = &self.ssl_cert_file {
-                    let ssl_cert = parse_ssl_cert(ssl_cert_file).await.ok();
-                    if let Some(ssl_cert) = ssl_cert {
-                        buildable_client = buildable_client.add_root_certificate(ssl_cert);
-                    }
-                }
-                let client = buildable_client.build().map_err(DiagnosticError::Reqwest)?;
+                let client = match http::client(
+                    None,
+                    self.ssl_cert_file.as_deref(),
+                    Duration::from_millis(3000),
+                )
+                .await
+                {
+                    Ok(client) => client,
+                    Err(err) => {
+                        tracing::info!(%err, "Failed to send diagnostic to `{endpoint}`, continuing");
+                        return Ok(());
+                    },
+                };
 
                 let res = client
                     .post(endpoint.clone())
                     .body(serialized)
                     .header("Content-Type", "application/json")
-                    .timeout(Duration::from_millis(3000))
                     .send()
                     .await;
 
@@ -0,0 +1,303 @@
+/*! Every environment variable the installer reads, sets in its own process, or passes on to the commands it runs
+
+All access goes through the [`EnvVar`]s here, so this module is the inventory of the environment
+the installer depends on. The `NIX_INSTALLER_*` equivalents of the flags are not listed, clap reads
+those, and a flag passed on the command line takes precedence over its variable.
+
+`NIX_*` variables other than those are removed from the environment of every command the installer
+runs, a stray `NIX_CONF_DIR` in the shell of an admin would otherwise change what `nix-store` does.
+*/
+
+use std::{
+    collections::BTreeSet,
+    ffi::{OsStr, OsString},
+};
+
+use serde_json::{json, Value};
+use tokio::process::Command;
+
+/// An environment variable, and why the installer uses it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EnvVar {
+    pub(crate) name: &'static str,
+    pub(crate) purpose: &'static str,
+}
+
+impl EnvVar {
+    const fn new(name: &'static str, purpose: &'static str) -> Self {
+        Self { name, purpose }
+    }
+
+    pub(crate) fn get(&self) -> Option<String> {
+        std::env::var(self.name).ok()
+    }
+
+    pub(crate) fn get_os(&self) -> Option<OsString> {
+        std::env::var_os(self.name)
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.get_os().is_some()
+    }
+
+    /// Set the variable in the installer's own process, and so for every command it runs after
+    #[tracing::instrument(level = "debug", skip_all, fields(
+        k = self.name,
+        v = %value.as_ref().to_string_lossy(),
+    ))]
+    pub(crate) fn set(&self, value: impl AsRef<OsStr>) {
+        debug_assert!(SETS.contains(self), "`{}` is not in `SETS`", self.name);
+        tracing::trace!("Setting env");
+        std::env::set_var(self.name, value.as_ref());
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(k = self.name))]
+    pub(crate) fn remove(&self) {
+        debug_assert!(SETS.contains(self), "`{}` is not in `SETS`", self.name);
+        tracing::trace!("Removing env");
+        std::env::remove_var(self.name);
+    }
+}
+
+pub(crate) const SUDO_USER: EnvVar = EnvVar::new(
+    "SUDO_USER",
+    "The user who ran the installer with `sudo`, whose home and shell get configured",
+);
+pub(crate) const SHELL: EnvVar = EnvVar::new(
+    "SHELL",
+    "The shell to suggest sourcing Nix in, and which `doctor` checks",
+);
+pub(crate) const PATH: EnvVar = EnvVar::new(
+    "PATH",
+    "Searched for the system tools the installer runs, before the standard locations",
+);
+pub(crate) const GITHUB_PATH: EnvVar = EnvVar::new(
+    "GITHUB_PATH",
+    "On GitHub Actions, the file to add Nix to `PATH` in for later steps",
+);
+pub(crate) const WSL_DISTRO_NAME: EnvVar = EnvVar::new(
+    "WSL_DISTRO_NAME",
+    "Detecting WSL, which needs systemd enabled",
+);
+pub(crate) const WSL_INTEROP: EnvVar =
+    EnvVar::new("WSL_INTEROP", "Telling WSL2 (which has it) from WSL1");
+pub(crate) const NIX_SSL_CERT_FILE: EnvVar = EnvVar::new(
+    "NIX_SSL_CERT_FILE",
+    "The CA bundle Nix uses, which `doctor` checks, set once the default profile has one",
+);
+pub(crate) const NIX_INSTALLER_CI: EnvVar = EnvVar::new(
+    "NIX_INSTALLER_CI",
+    "Marks diagnostics as sent from CI, passed along through `sudo`",
+);
+pub(crate) const HOME: EnvVar = EnvVar::new(
+    "HOME",
+    "The home of `root`, for the Nix commands setting up the store",
+);
+pub(crate) const USER: EnvVar = EnvVar::new(
+    "USER",
+    "`root`, for the Nix commands setting up the default profile",
+);
+pub(crate) const NIX_REMOTE: EnvVar = EnvVar::new(
+    "NIX_REMOTE",
+    "`local`, so verifying the store talks to it directly rather than to the daemon",
+);
+pub(crate) const HTTPS_PROXY: EnvVar = EnvVar::new(
+    "HTTPS_PROXY",
+    "The proxy of the HTTP client and of the daemon, unless `--proxy` is passed, also `https_proxy`, `ALL_PROXY`, `all_proxy`, `HTTP_PROXY` and `http_proxy`",
+);
+pub(crate) const NO_PROXY: EnvVar = EnvVar::new(
+    "NO_PROXY",
+    "The hosts the HTTP client and the daemon reach without the proxy, also `no_proxy`",
+);
+/// The variables naming the proxy, in the order they are looked at
+pub(crate) const PROXIES: &[EnvVar] = &[
+    EnvVar::new("https_proxy", HTTPS_PROXY.purpose),
+    HTTPS_PROXY,
+    EnvVar::new("all_proxy", HTTPS_PROXY.purpose),
+    EnvVar::new("ALL_PROXY", HTTPS_PROXY.purpose),
+    EnvVar::new("http_proxy", HTTPS_PROXY.purpose),
+    EnvVar::new("HTTP_PROXY", HTTPS_PROXY.purpose),
+];
+pub(crate) const NO_PROXIES: &[EnvVar] = &[EnvVar::new("no_proxy", NO_PROXY.purpose), NO_PROXY];
+pub(crate) const OTEL_EXPORTER_OTLP_ENDPOINT: EnvVar = EnvVar::new(
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "Where to export telemetry to, with the `telemetry` feature",
+);
+pub(crate) const OTEL_EXPORTER_OTLP_TRACES_ENDPOINT: EnvVar = EnvVar::new(
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    "Where to export telemetry to, if `OTEL_EXPORTER_OTLP_ENDPOINT` is unset",
+);
+pub(crate) const OTEL_EXPORTER_OTLP_METRICS_ENDPOINT: EnvVar = EnvVar::new(
+    "OTEL_EXPORTER_OTLP_METRICS_ENDPOINT",
+    "Where to export telemetry to, if neither of the others is set",
+);
+
+/// Read by the installer, in the order they are listed for each purpose
+pub(crate) const READS: &[EnvVar] = &[
+    SUDO_USER,
+    SHELL,
+    PATH,
+    GITHUB_PATH,
+    WSL_DISTRO_NAME,
+    WSL_INTEROP,
+    NIX_SSL_CERT_FILE,
+    NIX_INSTALLER_CI,
+    HTTPS_PROXY,
+    NO_PROXY,
+    EnvVar::new(
+        "CLOUD_INIT*",
+        "Any of these means cloud-init runs the installer, see `install`",
+    ),
+    OTEL_EXPORTER_OTLP_ENDPOINT,
+    OTEL_EXPORTER_OTLP_TRACES_ENDPOINT,
+    OTEL_EXPORTER_OTLP_METRICS_ENDPOINT,
+    EnvVar::new(
+        "RUST_LOG",
+        "Filters the logs, unless `--log-directive` is passed",
+    ),
+    EnvVar::new("RUST_BACKTRACE", "Captures backtraces of errors"),
+];
+
+/// Set in the installer's own process
+pub(crate) const SETS: &[EnvVar] = &[NIX_SSL_CERT_FILE];
+
+/// Set for the commands the installer runs, which inherit everything else but [`scrub`]bed variables
+pub(crate) const PROPAGATES: &[EnvVar] = &[HOME, USER, NIX_REMOTE, NIX_SSL_CERT_FILE];
+
+/// Which variables are kept when the installer re-runs itself with `sudo`, which clears the others
+pub(crate) fn preserved_by_sudo(name: &str) -> bool {
+    match name {
+        "RUST_LOG" | "RUST_BACKTRACE" => true,
+        "GITHUB_PATH" | "SHELL" => true,
+        // Picked up by the HTTP client
+        "HTTP_PROXY" | "http_proxy" | "HTTPS_PROXY" | "https_proxy" => true,
+        // Passed on to the daemon with the proxy
+        "ALL_PROXY" | "all_proxy" | "NO_PROXY" | "no_proxy" => true,
+        name if name.starts_with("NIX_INSTALLER") => true,
+        // Only used with the `telemetry` feature
+        name if name.starts_with("OTEL_") => true,
+        _ => false,
+    }
+}
+
+/// The names of the variables set in the installer's own process
+pub(crate) fn names() -> Vec<String> {
+    std::env::vars_os()
+        .map(|(name, _)| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// The `NIX_*` variables among `present` which are neither inventoried nor the variable of a flag
+pub(crate) fn unexpected(
+    present: impl IntoIterator<Item = String>,
+    flag_vars: &BTreeSet<String>,
+) -> Vec<String> {
+    let mut unexpected = present
+        .into_iter()
+        .filter(|name| name.starts_with("NIX_"))
+        .filter(|name| {
+            !flag_vars.contains(name)
+                && !READS
+                    .iter()
+                    .chain(PROPAGATES)
+                    .any(|known| known.name == name)
+        })
+        .collect::<Vec<_>>();
+    unexpected.sort();
+    unexpected
+}
+
+/// Warn about the `NIX_*` variables set at startup which the installer doesn't know
+pub(crate) fn warn_unexpected(flag_vars: &BTreeSet<String>) {
+    let unexpected = unexpected(names(), flag_vars);
+    if !unexpected.is_empty() {
+        tracing::warn!(
+            "Ignoring `{}` in the environment, it is not used by the installer and is removed from the commands it runs",
+            unexpected.join("`, `")
+        );
+    }
+}
+
+/// Remove the `NIX_*` variables `command` would inherit, other than those it [`PROPAGATES`]
+pub(crate) fn scrub(command: &mut Command) {
+    let explicit = command
+        .as_std()
+        .get_envs()
+        .map(|(name, _)| name.to_os_string())
+        .collect::<Vec<_>>();
+    for name in names() {
+        let inherited = !explicit.iter().any(|explicit| *explicit == *name);
+        if inherited
+            && name.starts_with("NIX_")
+            && !PROPAGATES.iter().any(|known| known.name == name)
+        {
+            command.env_remove(&name);
+        }
+    }
+}
+
+/// The inventory, as `capabilities` describes it
+pub(crate) fn inventory() -> Value {
+    let describe = |vars: &[EnvVar]| {
+        vars.iter()
+            .map(|var| json!({ "name": var.name, "purpose": var.purpose }))
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "reads": describe(READS),
+        "sets": describe(SETS),
+        "propagates": describe(PROPAGATES),
+        "scrubbed_from_commands": "NIX_*",
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_unexpected_nix_vars() {
+        let flag_vars = BTreeSet::from(["NIX_INSTALLER_NO_CONFIRM".to_string()]);
+        assert_eq!(
+            unexpected(
+                [
+                    "NIX_CONF_DIR",
+                    "NIX_INSTALLER_NO_CONFIRM",
+                    "NIX_INSTALLER_NO_CONFRIM",
+                    "NIX_SSL_CERT_FILE",
+                    "HOME",
+                ]
+                .map(String::from),
+                &flag_vars
+            ),
+            ["NIX_CONF_DIR", "NIX_INSTALLER_NO_CONFRIM"]
+        );
+    }
+
+    #[test]
+    fn preserves_only_what_sudo_needs() {
+        assert!(preserved_by_sudo("NIX_INSTALLER_NO_CONFIRM"));
+        assert!(preserved_by_sudo("https_proxy"));
+        assert!(!preserved_by_sudo("NIX_CONF_DIR"));
+        assert!(!preserved_by_sudo("LD_PRELOAD"));
+    }
+
+    #[tokio::test]
+    async fn disallowed_vars_do_not_reach_commands() -> eyre::Result<()> {
+        // Unique to this test, as the environment is shared by every test
+        std::env::set_var("NIX_INSTALLER_TEST_STRAY", "leaked");
+        let mut command = Command::new("sh");
+        command
+            .args([
+                "-c",
+                "printf '%s %s' \"${NIX_INSTALLER_TEST_STRAY-unset}\" \"$NIX_REMOTE\"",
+            ])
+            .env(NIX_REMOTE.name, "local");
+        let output = crate::command_runner::output(&mut command).await?;
+        std::env::remove_var("NIX_INSTALLER_TEST_STRAY");
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "unset local");
+        Ok(())
+    }
+}
@@ -52,6 +52,9 @@ pub enum NixInstallerError {
     /// An error occurring when a signal is issued along [`InstallPlan::install`](crate::InstallPlan::install)'s `cancel_channel` argument
     #[error("Cancelled by user")]
     Cancelled,
+    /// An [`Action`](crate::action::Action) needs the system rebooted, see [`RebootCheckpoint`](crate::RebootCheckpoint)
+    #[error("The system must be rebooted to continue installing: {reason}\n\nAfter rebooting, run the installer again with `install --resume` to continue, the progress so far is recorded in `{}`", .checkpoint.display())]
+    NeedsReboot { reason: String, checkpoint: PathBuf },
     /// Semver error
     #[error("Semantic Versioning error")]
     SemVer(
@@ -91,6 +94,44 @@ pub enum NixInstallerError {
     /// This version of `nix-installer` is not compatible with this plan's version
     #[error("`nix-installer` version `{}` is not compatible with this plan's version `{}`", .binary, .plan)]
     IncompatibleVersion { binary: Version, plan: Version },
+    /// An action kind passed to [`InstallPlan::retain`](crate::InstallPlan::retain) is not in the plan
+    #[error("`{kind}` is not an action kind in this receipt, expected one of: {}", .available.join(", "))]
+    UnknownActionKind {
+        kind: String,
+        available: Vec<String>,
+    },
+    /// An id passed to [`InstallPlan::revert_action`](crate::InstallPlan::revert_action) is not in the plan
+    #[error("`{id}` is not the id of an action in this receipt, expected one of: {}", .available.join(", "))]
+    UnknownActionId { id: String, available: Vec<String> },
+    /// The action passed to [`InstallPlan::revert_action`](crate::InstallPlan::revert_action) has nothing to revert
+    #[error("`{0}` was never executed or is already reverted, there is nothing to revert")]
+    ActionNotExecuted(String),
+    /// Executed actions need the action passed to [`InstallPlan::revert_action`](crate::InstallPlan::revert_action)
+    #[error("Reverting `{id}` would break the actions which depend on it:\n{}\nRevert those first, or uninstall", .dependents.iter().map(|dependent| format!("* {dependent}")).collect::<Vec<_>>().join("\n"))]
+    ActionDependents { id: String, dependents: Vec<String> },
+    /// The plan was installed on a different host
+    #[error("This receipt was recorded on a different host ({recorded}) than this one ({current}), reverting it may remove the wrong things.\nIf this is expected (eg. the install was part of an image), pass `--ignore-host-mismatch` or run `nix-installer claim-receipt`")]
+    HostMismatch {
+        recorded: Box<crate::HostFingerprint>,
+        current: Box<crate::HostFingerprint>,
+    },
+    #[error("There is no Nix store at `/nix/store` to convert")]
+    NoStoreToConvert,
+    #[error("Nix is already installed in {0} mode")]
+    AlreadyInMode(crate::convert::InstallMode),
+    #[error("Converting needs the receipt of a multi-user install by `nix-installer`, so the build users, group and daemon it set up can be reverted")]
+    ConversionNeedsReceipt,
+    #[error("Converting to single-user needs the user to give the store to, pass `--user`")]
+    ConversionNeedsUser,
+    /// The daemon of a multi-user install is run from the default profile
+    #[error("`{0}` does not exist, the daemon is run from it. Point it at the Nix of the single-user install with `nix-env --profile {0} --set \"$(readlink -f ~/.nix-profile/bin/nix | cut -d/ -f1-4)\"` and try again")]
+    NoDefaultProfile(PathBuf),
+    /// Services outside of the install which run programs out of `/nix`, see [`crate::os::dependents`]
+    #[error("Services outside of the install run programs from `/nix`, uninstalling would break them:\n{}\nPass `--break-dependents` to uninstall anyway, with `--stop-dependents` to stop them first", .0.iter().map(|dependent| format!("* {dependent}")).collect::<Vec<_>>().join("\n"))]
+    Dependents(Vec<String>),
+    /// Containers with images, volumes or filesystems under `/nix`, see [`crate::os::containers`]
+    #[error("Containers use `/nix`, uninstalling would remove files out from under them:\n{}\nPass `--break-containers` to uninstall anyway", .0.iter().map(|container_use| format!("* {container_use}")).collect::<Vec<_>>().join("\n"))]
+    Containers(Vec<String>),
 }
 
 pub(crate) trait HasExpectedErrors: std::error::Error + Sized + Send + Sync {
@@ -107,6 +148,7 @@ impl HasExpectedErrors for NixInstallerError {
             NixInstallerError::CopyingSelf(_) => None,
             NixInstallerError::SerializingReceipt(_) => None,
             this @ NixInstallerError::Cancelled => Some(Box::new(this)),
+            this @ NixInstallerError::NeedsReboot { .. } => Some(Box::new(this)),
             NixInstallerError::SemVer(_) => None,
             NixInstallerError::Planner(planner_error) => planner_error.expected(),
             NixInstallerError::InstallSettings(_) => None,
@@ -115,6 +157,18 @@ impl HasExpectedErrors for NixInstallerError {
             this @ NixInstallerError::IncompatibleVersion { binary: _, plan: _ } => {
                 Some(Box::new(this))
             },
+            this @ NixInstallerError::HostMismatch { .. } => Some(Box::new(this)),
+            this @ NixInstallerError::UnknownActionKind { .. } => Some(Box::new(this)),
+            this @ NixInstallerError::UnknownActionId { .. } => Some(Box::new(this)),
+            this @ NixInstallerError::ActionNotExecuted(_) => Some(Box::new(this)),
+            this @ NixInstallerError::ActionDependents { .. } => Some(Box::new(this)),
+            this @ NixInstallerError::NoStoreToConvert => Some(Box::new(this)),
+            this @ NixInstallerError::AlreadyInMode(_) => Some(Box::new(this)),
+            this @ NixInstallerError::ConversionNeedsReceipt => Some(Box::new(this)),
+            this @ NixInstallerError::ConversionNeedsUser => Some(Box::new(this)),
+            this @ NixInstallerError::NoDefaultProfile(_) => Some(Box::new(this)),
+            this @ NixInstallerError::Dependents(_) => Some(Box::new(this)),
+            this @ NixInstallerError::Containers(_) => Some(Box::new(this)),
             #[cfg(feature = "diagnostics")]
             NixInstallerError::Diagnostic(_) => None,
         }
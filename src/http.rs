@@ -0,0 +1,183 @@
+/*! The HTTP client every request of the installer is made with
+
+Fetching Nix, the connectivity preflight, fetching `--extra-conf` and sending diagnostics all go
+through [`client_builder`], so they agree on the proxy (`--proxy`, otherwise the `HTTP(S)_PROXY`
+environment), the CA bundle (`--ssl-cert-file`), the timeouts and the user agent.
+*/
+
+use std::{path::Path, time::Duration};
+
+use reqwest::{Client, ClientBuilder};
+use url::Url;
+
+use crate::{parse_ssl_cert, CertificateError};
+
+pub(crate) const USER_AGENT: &str = concat!("nix-installer/", env!("CARGO_PKG_VERSION"));
+/// How long connecting (including through a proxy) may take, whatever the request
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An error building the HTTP client
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum HttpClientError {
+    #[error("Invalid proxy `{0}`")]
+    Proxy(Url, #[source] reqwest::Error),
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+    #[error("Building the HTTP client")]
+    Build(#[source] reqwest::Error),
+}
+
+/// A client builder going through `proxy` and trusting `ssl_cert_file`, for callers which configure it further
+pub(crate) async fn client_builder(
+    proxy: Option<&Url>,
+    ssl_cert_file: Option<&Path>,
+) -> Result<ClientBuilder, HttpClientError> {
+    let mut builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(CONNECT_TIMEOUT);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy.clone())
+                .map_err(|e| HttpClientError::Proxy(proxy.clone(), e))?,
+        );
+    }
+    if let Some(ssl_cert_file) = ssl_cert_file {
+        for ssl_cert in parse_ssl_cert(ssl_cert_file).await? {
+            builder = builder.add_root_certificate(ssl_cert);
+        }
+    }
+    Ok(builder)
+}
+
+/// A client going through `proxy` and trusting `ssl_cert_file`, whose requests fail after `timeout`
+///
+/// Without a `timeout` only connecting is bounded, as downloading Nix may take long.
+pub(crate) async fn client(
+    proxy: Option<&Url>,
+    ssl_cert_file: Option<&Path>,
+    timeout: impl Into<Option<Duration>>,
+) -> Result<Client, HttpClientError> {
+    let mut builder = client_builder(proxy, ssl_cert_file).await?;
+    if let Some(timeout) = timeout.into() {
+        builder = builder.timeout(timeout);
+    }
+    builder.build().map_err(HttpClientError::Build)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    use super::*;
+
+    /// Accept one connection on localhost, send back what it was sent and answer with `response`, or
+    /// never answer without one
+    async fn serve_once(
+        response: Option<&'static [u8]>,
+    ) -> std::io::Result<(Url, oneshot::Receiver<String>)> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?)
+            .parse()
+            .expect("A socket address makes a valid URL");
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                return;
+            };
+            let mut buf = [0; 4096];
+            let read = stream.read(&mut buf).await.unwrap_or(0);
+            let _ = tx.send(String::from_utf8_lossy(&buf[..read]).into_owned());
+            match response {
+                Some(response) => {
+                    let _ = stream.write_all(response).await;
+                },
+                None => tokio::time::sleep(Duration::from_secs(30)).await,
+            }
+        });
+        Ok((url, rx))
+    }
+
+    #[tokio::test]
+    async fn sends_requests_through_proxy() -> eyre::Result<()> {
+        let (proxy, request) =
+            serve_once(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")).await?;
+        let client = client(Some(&proxy), None, Duration::from_secs(5)).await?;
+        let body = client
+            .get("http://releases.example.invalid/nix.tar.xz")
+            .send()
+            .await?
+            .text()
+            .await?;
+        assert_eq!(body, "ok");
+
+        let request = request.await?;
+        assert!(
+            request.starts_with("GET http://releases.example.invalid/nix.tar.xz HTTP/1.1\r\n"),
+            "{request}"
+        );
+        assert!(
+            request
+                .to_lowercase()
+                .contains(&format!("user-agent: {USER_AGENT}\r\n")),
+            "{request}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tunnels_https_through_proxy() -> eyre::Result<()> {
+        let (proxy, request) = serve_once(Some(b"HTTP/1.1 403 Forbidden\r\n\r\n")).await?;
+        let client = client(Some(&proxy), None, Duration::from_secs(5)).await?;
+        assert!(client
+            .get("https://releases.example.invalid/nix.tar.xz")
+            .send()
+            .await
+            .is_err());
+
+        let request = request.await?;
+        assert!(
+            request.starts_with("CONNECT releases.example.invalid:443 HTTP/1.1\r\n"),
+            "{request}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_responses() -> eyre::Result<()> {
+        let (proxy, _request) = serve_once(None).await?;
+        let client = client(Some(&proxy), None, Duration::from_millis(200)).await?;
+        let err = client
+            .get("http://releases.example.invalid/nix.tar.xz")
+            .send()
+            .await
+            .expect_err("The proxy never answers");
+        assert!(err.is_timeout(), "{err:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trusts_custom_ca() -> eyre::Result<()> {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssl");
+        for ssl_cert_file in ["corp-bundle.pem", "corp-ca.der"] {
+            client(None, Some(&fixtures.join(ssl_cert_file)), None).await?;
+        }
+
+        let temp_dir = tempfile::tempdir()?;
+        let not_a_cert = temp_dir.path().join("not-a-cert.pem");
+        std::fs::write(&not_a_cert, "not a certificate")?;
+        // Taken for a `der` certificate, which is only parsed as the client is built
+        assert!(matches!(
+            client(None, Some(&not_a_cert), None).await,
+            Err(HttpClientError::Build(_))
+        ));
+        assert!(matches!(
+            client(None, Some(&temp_dir.path().join("missing.pem")), None).await,
+            Err(HttpClientError::Certificate(CertificateError::Read(..)))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+/*! What uninstalling left in place, as it changed since the install
+
+Reverting actions [`report`] files they created which were since replaced (eg. by a symlink to a
+copy managed elsewhere, or a directory) or edited, instead of removing someone else's content
+through them. [`InstallPlan::uninstall`](crate::InstallPlan::uninstall) collects them, see
+[`InstallPlan::leftovers`](crate::InstallPlan::leftovers), to be cleaned up by hand.
+*/
+
+use std::{
+    fmt,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+type Registry = Arc<Mutex<Vec<Leftover>>>;
+
+tokio::task_local! {
+    static CURRENT: Registry;
+}
+
+/// A path reverting left in place, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leftover {
+    pub path: PathBuf,
+    /// Why it was left, such as `it was replaced by a symlink since the install`
+    pub reason: String,
+}
+
+impl fmt::Display for Leftover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}`, {}",
+            crate::lossless_path::Display(&self.path),
+            self.reason
+        )
+    }
+}
+
+/// Report a path the reverting action leaves in place, it is logged either way
+///
+/// Is only logged outside of [`collect`], such as when an action is reverted directly.
+pub(crate) fn report(path: impl Into<PathBuf>, reason: impl Into<String>) {
+    let leftover = Leftover {
+        path: path.into(),
+        reason: reason.into(),
+    };
+    tracing::warn!("Leaving {leftover}");
+    let _ = CURRENT.try_with(|registry| {
+        registry
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(leftover)
+    });
+}
+
+/// Run `fut`, returning what it [`report`]ed alongside its output
+pub(crate) async fn collect<F: Future>(fut: F) -> (F::Output, Vec<Leftover>) {
+    let registry = Registry::default();
+    let output = CURRENT.scope(registry.clone(), fut).await;
+    let leftovers = std::mem::take(&mut *registry.lock().unwrap_or_else(PoisonError::into_inner));
+    (output, leftovers)
+}
+
+/// Carry the registry of the reverting plan step into a future which is spawned as its own task
+pub(crate) fn propagate<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let current = CURRENT.try_with(Clone::clone).ok();
+    async move {
+        match current {
+            Some(current) => CURRENT.scope(current, fut).await,
+            None => fut.await,
+        }
+    }
+}
@@ -70,21 +70,39 @@ match plan.install(None).await {
 */
 
 pub mod action;
+pub mod build_info;
+mod cancellation;
 #[cfg(feature = "cli")]
 pub mod cli;
+pub mod command_runner;
+mod convert;
 #[cfg(feature = "diagnostics")]
 pub mod diagnostics;
+mod env;
 mod error;
+mod http;
+mod leftovers;
+mod lossless_path;
 mod os;
+pub mod path_policy;
 mod plan;
 pub mod planner;
+pub mod progress;
 pub mod self_test;
 pub mod settings;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+mod temp_artifacts;
+#[cfg(all(test, target_os = "linux"))]
+mod test_support;
+pub mod timestamp;
+mod upstream_receipt;
 
-use std::{ffi::OsStr, path::Path, process::Output};
+use std::{path::Path, process::Output};
 
 pub use error::NixInstallerError;
-pub use plan::InstallPlan;
+pub use leftovers::Leftover;
+pub use plan::{HostComparison, HostFingerprint, InstallPlan, RebootCheckpoint};
 use planner::BuiltinPlanner;
 
 use reqwest::Certificate;
@@ -95,8 +113,7 @@ use crate::action::{Action, ActionErrorKind};
 #[tracing::instrument(level = "debug", skip_all, fields(command = %format!("{:?}", command.as_std())))]
 async fn execute_command(command: &mut Command) -> Result<Output, ActionErrorKind> {
     tracing::trace!("Executing");
-    let output = command
-        .output()
+    let output = crate::command_runner::output(command)
         .await
         .map_err(|e| ActionErrorKind::command(command, e))?;
     match output.status.success() {
@@ -105,28 +122,48 @@ async fn execute_command(command: &mut Command) -> Result<Output, ActionErrorKin
     }
 }
 
-#[tracing::instrument(level = "debug", skip_all, fields(
-    k = %k.as_ref().to_string_lossy(),
-    v = %v.as_ref().to_string_lossy(),
-))]
-fn set_env(k: impl AsRef<OsStr>, v: impl AsRef<OsStr>) {
-    tracing::trace!("Setting env");
-    std::env::set_var(k.as_ref(), v.as_ref());
-}
-
-async fn parse_ssl_cert(ssl_cert_file: &Path) -> Result<Certificate, CertificateError> {
+/// Every certificate in `ssl_cert_file`, a PEM bundle or a single `der` certificate
+async fn parse_ssl_cert(ssl_cert_file: &Path) -> Result<Vec<Certificate>, CertificateError> {
     let cert_buf = tokio::fs::read(ssl_cert_file)
         .await
         .map_err(|e| CertificateError::Read(ssl_cert_file.to_path_buf(), e))?;
     // We actually try them since things could be `.crt` and `pem` format or `der` format
-    let cert = if let Ok(cert) = Certificate::from_pem(cert_buf.as_slice()) {
-        cert
+    if let Some(certs) = parse_pem_bundle(&cert_buf) {
+        Ok(certs)
     } else if let Ok(cert) = Certificate::from_der(cert_buf.as_slice()) {
-        cert
+        Ok(vec![cert])
     } else {
-        return Err(CertificateError::UnknownCertFormat);
-    };
-    Ok(cert)
+        Err(CertificateError::UnknownCertFormat)
+    }
+}
+
+/// Ensure `ssl_cert_file` is a PEM bundle, the only format Nix reads from `ssl-cert-file`
+async fn check_pem_bundle(ssl_cert_file: &Path) -> Result<(), CertificateError> {
+    let cert_buf = tokio::fs::read(ssl_cert_file)
+        .await
+        .map_err(|e| CertificateError::Read(ssl_cert_file.to_path_buf(), e))?;
+    match parse_pem_bundle(&cert_buf) {
+        Some(_) => Ok(()),
+        None => Err(CertificateError::NotPem(ssl_cert_file.to_path_buf())),
+    }
+}
+
+/// The certificates of a PEM bundle, `None` if there are none or any is malformed
+fn parse_pem_bundle(buf: &[u8]) -> Option<Vec<Certificate>> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = std::str::from_utf8(buf).ok()?;
+    let mut certs = Vec::new();
+    let mut rest = text;
+    while let Some(begin) = rest.find("-----BEGIN CERTIFICATE-----") {
+        let end = begin + rest[begin..].find(END)? + END.len();
+        certs.push(Certificate::from_pem(&rest.as_bytes()[begin..end]).ok()?);
+        rest = &rest[end..];
+    }
+    if certs.is_empty() {
+        None
+    } else {
+        Some(certs)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -137,4 +174,37 @@ pub enum CertificateError {
     Read(std::path::PathBuf, #[source] std::io::Error),
     #[error("Unknown certificate format, `der` and `pem` supported")]
     UnknownCertFormat,
+    #[error("`{0}` is not a PEM certificate bundle, which Nix requires for `ssl-cert-file`")]
+    NotPem(std::path::PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_every_certificate_of_a_pem_bundle() -> eyre::Result<()> {
+        let bundle =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssl/corp-bundle.pem");
+        assert_eq!(parse_ssl_cert(&bundle).await?.len(), 2);
+        check_pem_bundle(&bundle).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn der_certificates_are_not_pem_bundles() -> eyre::Result<()> {
+        let der = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/ssl/corp-ca.der");
+        assert_eq!(parse_ssl_cert(&der).await?.len(), 1);
+        assert!(matches!(
+            check_pem_bundle(&der).await,
+            Err(CertificateError::NotPem(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_pem_bundles_are_rejected() {
+        assert!(parse_pem_bundle(b"-----BEGIN CERTIFICATE-----\nMIIB\n").is_none());
+        assert!(parse_pem_bundle(b"").is_none());
+    }
 }
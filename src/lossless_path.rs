@@ -0,0 +1,148 @@
+/*! Paths as recorded in receipts, without losing those which aren't UTF-8
+
+Paths which are UTF-8 are recorded as plain strings, as they always were, so receipts stay readable
+and older ones read back. Others (such as a home directory named in a legacy locale) are recorded as
+their bytes, with a lossy rendering alongside for whoever reads the receipt, which is never read
+back. Use with `#[serde(with = "crate::lossless_path")]`, or [`option`] for an `Option<PathBuf>`.
+*/
+
+use std::{
+    ffi::OsString,
+    fmt,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Recorded {
+    Text(String),
+    Bytes {
+        bytes: Vec<u8>,
+        #[serde(default, skip_deserializing)]
+        lossy: String,
+    },
+}
+
+impl From<&Path> for Recorded {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(text) => Recorded::Text(text.to_string()),
+            None => Recorded::Bytes {
+                bytes: path.as_os_str().as_bytes().to_vec(),
+                lossy: path.to_string_lossy().into_owned(),
+            },
+        }
+    }
+}
+
+impl From<Recorded> for PathBuf {
+    fn from(recorded: Recorded) -> Self {
+        match recorded {
+            Recorded::Text(text) => PathBuf::from(text),
+            Recorded::Bytes { bytes, .. } => PathBuf::from(OsString::from_vec(bytes)),
+        }
+    }
+}
+
+pub(crate) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    Recorded::from(path).serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Recorded::deserialize(deserializer).map(PathBuf::from)
+}
+
+/// For an `Option<PathBuf>`, with `#[serde(with = "crate::lossless_path::option")]`
+pub(crate) mod option {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        path: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        path.as_deref().map(Recorded::from).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        Option::<Recorded>::deserialize(deserializer).map(|path| path.map(PathBuf::from))
+    }
+}
+
+/// Shows `path` lossily, marked as such when it isn't UTF-8, for messages to humans
+pub(crate) struct Display<'a>(pub(crate) &'a Path);
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.to_str() {
+            Some(text) => write!(f, "{text}"),
+            None => write!(f, "{} (not UTF-8)", self.0.to_string_lossy()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Recording {
+        #[serde(with = "crate::lossless_path")]
+        path: PathBuf,
+        #[serde(default, with = "crate::lossless_path::option")]
+        resolved: Option<PathBuf>,
+    }
+
+    fn non_utf8() -> PathBuf {
+        PathBuf::from(OsString::from_vec(b"/home/j\xf6rg/.bashrc".to_vec()))
+    }
+
+    #[test]
+    fn records_utf8_paths_as_strings() -> eyre::Result<()> {
+        let recording = Recording {
+            path: "/etc/bashrc".into(),
+            resolved: None,
+        };
+        let json = serde_json::to_string(&recording)?;
+        assert_eq!(json, r#"{"path":"/etc/bashrc","resolved":null}"#);
+        assert_eq!(serde_json::from_str::<Recording>(&json)?, recording);
+        // As written before paths were recorded losslessly
+        assert_eq!(
+            serde_json::from_str::<Recording>(r#"{"path":"/etc/bashrc"}"#)?,
+            recording
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn records_other_paths_as_bytes() -> eyre::Result<()> {
+        let recording = Recording {
+            path: non_utf8(),
+            resolved: Some(non_utf8()),
+        };
+        let json = serde_json::to_value(&recording)?;
+        assert_eq!(json["path"]["lossy"], "/home/j\u{fffd}rg/.bashrc");
+        assert_eq!(
+            serde_json::from_value::<Recording>(json.clone())?,
+            recording
+        );
+        // Only the bytes are read back
+        let mut edited = json;
+        edited["path"]["lossy"] = "/somewhere/else".into();
+        assert_eq!(serde_json::from_value::<Recording>(edited)?, recording);
+        Ok(())
+    }
+
+    #[test]
+    fn marks_lossy_display() {
+        assert_eq!(Display(Path::new("/etc/bashrc")).to_string(), "/etc/bashrc");
+        assert_eq!(
+            Display(&non_utf8()).to_string(),
+            "/home/j\u{fffd}rg/.bashrc (not UTF-8)"
+        );
+    }
+}
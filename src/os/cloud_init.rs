@@ -0,0 +1,268 @@
+/*! Detecting installs run by cloud-init, from the user data of AWS, GCP or Azure instances
+
+These run without a TTY (so nothing can be confirmed), possibly before the network is fully up, and
+alongside cloud-init's own `write_files`, which may write the same shell profiles.
+*/
+
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::env;
+
+const RUN_DIR: &str = "run/cloud-init";
+/// Written by cloud-init once it finishes booting the instance
+const RESULT_FILE: &str = "run/cloud-init/result.json";
+/// The cloud cloud-init detected the instance runs on (eg. `aws`, `gce`, `azure`)
+const CLOUD_ID_FILE: &str = "run/cloud-init/cloud-id";
+/// The cloud configs of this instance, as passed and as merged by cloud-init
+const CLOUD_CONFIG_FILES: &[&str] = &[
+    "var/lib/cloud/instance/cloud-config.txt",
+    "var/lib/cloud/instance/user-data.txt",
+];
+const ENV_PREFIX: &str = "CLOUD_INIT";
+/// The name of cloud-init's processes, as in `/proc/<pid>/comm`
+const PROCESS_NAME: &str = "cloud-init";
+
+/// How long to wait for the network when run by cloud-init
+pub(crate) const NETWORK_TIMEOUT: Duration = Duration::from_secs(120);
+pub(crate) const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An install run by cloud-init
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CloudInit {
+    /// The cloud it detected, if it recorded one
+    pub(crate) cloud: Option<String>,
+}
+
+impl std::fmt::Display for CloudInit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.cloud {
+            Some(cloud) => write!(f, "cloud-init (on `{cloud}`)"),
+            None => write!(f, "cloud-init"),
+        }
+    }
+}
+
+/// If the installer is run by cloud-init on this machine
+pub(crate) fn detect() -> Option<CloudInit> {
+    detect_at(Path::new("/"), &env::names(), &ancestor_names())
+}
+
+/// If the installer is run by cloud-init, given the filesystem at `root`, the names of the set
+/// environment variables, and of the processes it was started by
+pub(crate) fn detect_at(root: &Path, env: &[String], ancestors: &[String]) -> Option<CloudInit> {
+    let env_marker = env.iter().any(|name| name.starts_with(ENV_PREFIX));
+    let ancestor = ancestors.iter().any(|name| name == PROCESS_NAME);
+    // Still booting the instance, which is when user data runs
+    let booting = root.join(RUN_DIR).is_dir() && !root.join(RESULT_FILE).exists();
+    if !(env_marker || ancestor || booting) {
+        return None;
+    }
+    let cloud = std::fs::read_to_string(root.join(CLOUD_ID_FILE))
+        .ok()
+        .map(|cloud_id| cloud_id.trim().to_string())
+        .filter(|cloud_id| !cloud_id.is_empty());
+    Some(CloudInit { cloud })
+}
+
+/// The names of the processes this one was started by, nearest first
+fn ancestor_names() -> Vec<String> {
+    let mut names = vec![];
+    let mut pid = std::process::id();
+    // Bounded, in case of a cycle in a broken `/proc`
+    for _ in 0..64 {
+        let Some(ppid) = std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .ok()
+            .and_then(|stat| parent_pid(&stat))
+        else {
+            break;
+        };
+        if ppid == 0 {
+            break;
+        }
+        if let Ok(comm) = std::fs::read_to_string(format!("/proc/{ppid}/comm")) {
+            names.push(comm.trim().to_string());
+        }
+        pid = ppid;
+    }
+    names
+}
+
+/// The parent PID from `/proc/<pid>/stat`, after the command name which may contain spaces
+fn parent_pid(stat: &str) -> Option<u32> {
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The paths cloud-init's `write_files` writes, in the cloud configs of this instance under `root`
+pub(crate) fn write_files_paths(root: &Path) -> BTreeSet<PathBuf> {
+    CLOUD_CONFIG_FILES
+        .iter()
+        .filter_map(|file| std::fs::read_to_string(root.join(file)).ok())
+        .flat_map(|config| parse_write_files_paths(&config))
+        .collect()
+}
+
+/// The `path`s of the `write_files` entries of a cloud config, without a YAML parser
+///
+/// Only the block style cloud configs are written in is understood.
+fn parse_write_files_paths(config: &str) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    let mut in_write_files = false;
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        // A top level key ends the previous one
+        if !line.starts_with([' ', '\t', '-']) {
+            in_write_files = line.trim_end() == "write_files:";
+            continue;
+        }
+        if !in_write_files {
+            continue;
+        }
+        if let Some(path) = trimmed
+            .trim_start_matches('-')
+            .trim_start()
+            .strip_prefix("path:")
+        {
+            let path = path.trim().trim_matches(['"', '\'']);
+            if path.starts_with('/') {
+                paths.push(PathBuf::from(path));
+            }
+        }
+    }
+    paths
+}
+
+/// Wait until `reachable`, polling every `interval`, returning if it became so before `timeout`
+pub(crate) async fn wait_for_network<F, Fut>(
+    mut reachable: F,
+    timeout: Duration,
+    interval: Duration,
+) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if reachable().await {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tracing::debug!("Waiting for the network to come up");
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// If a TCP connection to `host` on `port` can be made within `timeout`
+pub(crate) async fn reachable(host: &str, port: u16, timeout: Duration) -> bool {
+    matches!(
+        tokio::time::timeout(timeout, tokio::net::TcpStream::connect((host, port))).await,
+        Ok(Ok(_))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn detects_markers() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let no_env: &[String] = &[];
+        assert_eq!(detect_at(root.path(), no_env, &["sshd".into()]), None);
+
+        // Started by cloud-init, or with its environment
+        assert_eq!(
+            detect_at(root.path(), no_env, &["sh".into(), "cloud-init".into()]),
+            Some(CloudInit { cloud: None })
+        );
+        assert_eq!(
+            detect_at(root.path(), &["CLOUD_INIT_INSTANCE_ID".into()], &[]),
+            Some(CloudInit { cloud: None })
+        );
+
+        // While it is still booting the instance
+        std::fs::create_dir_all(root.path().join(RUN_DIR))?;
+        std::fs::write(root.path().join(CLOUD_ID_FILE), "aws\n")?;
+        assert_eq!(
+            detect_at(root.path(), no_env, &[]),
+            Some(CloudInit {
+                cloud: Some("aws".into())
+            })
+        );
+        std::fs::write(root.path().join(RESULT_FILE), "{}")?;
+        assert_eq!(detect_at(root.path(), no_env, &[]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_parent_pid() {
+        assert_eq!(parent_pid("1234 (cloud-init) S 1 1234 1234 0"), Some(1));
+        assert_eq!(parent_pid("99 (a) b (c)) R 42 99 99 0"), Some(42));
+        assert_eq!(parent_pid("garbage"), None);
+    }
+
+    #[test]
+    fn finds_write_files_paths() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let instance = root.path().join("var/lib/cloud/instance");
+        std::fs::create_dir_all(&instance)?;
+        std::fs::write(
+            instance.join("cloud-config.txt"),
+            "#cloud-config\n\
+            packages:\n\
+            \x20 - path\n\
+            write_files:\n\
+            \x20 - path: /etc/profile.d/company.sh\n\
+            \x20   content: |\n\
+            \x20     export PATH=\"$PATH:/opt/bin\"\n\
+            \x20 - content: 'export EDITOR=vim'\n\
+            \x20   path: \"/etc/bash.bashrc\"\n\
+            - path: /etc/zshrc\n\
+            runcmd:\n\
+            \x20 - [ sh, -c, 'echo path: /etc/not-written' ]\n",
+        )?;
+        assert_eq!(
+            write_files_paths(root.path()),
+            BTreeSet::from([
+                PathBuf::from("/etc/bash.bashrc"),
+                PathBuf::from("/etc/profile.d/company.sh"),
+                PathBuf::from("/etc/zshrc"),
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waits_for_network() {
+        let attempts = AtomicU32::new(0);
+        let up = wait_for_network(
+            || async { attempts.fetch_add(1, Ordering::SeqCst) >= 2 },
+            Duration::from_secs(5),
+            Duration::from_millis(1),
+        )
+        .await;
+        assert!(up);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let up = wait_for_network(
+            || async { false },
+            Duration::from_millis(20),
+            Duration::from_millis(5),
+        )
+        .await;
+        assert!(!up);
+    }
+}
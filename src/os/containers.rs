@@ -0,0 +1,268 @@
+/*! Container runtimes keeping images, volumes or the filesystems of running containers in `/nix`
+
+Nix built OCI layers are often used straight from the store, and podman's storage is sometimes
+redirected under `/nix`. Uninstalling then removes files out from under running containers. The
+runtimes are asked where they store things when they are installed, and overlay mounts are read from
+the mount table, a machine without either finds nothing.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    os::{mounts::unescape_octal, tools},
+    NixInstallerError,
+};
+
+/// The runtimes asked where they store things, and how, one path per line of output
+const RUNTIMES: &[(&str, &[&str])] = &[
+    (
+        "podman",
+        &[
+            "info",
+            "--format",
+            "{{.Store.GraphRoot}}\n{{.Store.VolumePath}}",
+        ],
+    ),
+    ("docker", &["info", "--format", "{{.DockerRootDir}}"]),
+];
+/// How long a runtime may take to answer, `docker info` waits on an unresponsive daemon
+const RUNTIME_TIMEOUT: Duration = Duration::from_secs(10);
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Something a container runtime keeps under `/nix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ContainerUse {
+    /// The runtime stores its images, volumes or containers here
+    Storage { runtime: String, path: PathBuf },
+    /// The root filesystem of a running container, an overlay with layers under `/nix`
+    Overlay {
+        mount_point: PathBuf,
+        lowerdirs: Vec<PathBuf>,
+    },
+}
+
+impl std::fmt::Display for ContainerUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Storage { runtime, path } => {
+                write!(f, "`{runtime}` stores containers in `{}`", path.display())
+            },
+            Self::Overlay {
+                mount_point,
+                lowerdirs,
+            } => write!(
+                f,
+                "The container filesystem at `{}` is layered on `{}`",
+                mount_point.display(),
+                lowerdirs
+                    .iter()
+                    .map(|lowerdir| lowerdir.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join("`, `")
+            ),
+        }
+    }
+}
+
+/// What container runtimes keep under `nix`, nothing where no runtime is installed or running
+pub(crate) async fn scan(nix: &Path) -> Vec<ContainerUse> {
+    let mut found = vec![];
+    for (runtime, args) in RUNTIMES {
+        if let Some(output) = runtime_output(runtime, args).await {
+            found.extend(storage_under(runtime, &output, nix));
+        }
+    }
+    if let Ok(mountinfo) = tokio::fs::read_to_string(MOUNTINFO).await {
+        found.extend(overlays_under(&mountinfo, nix));
+    }
+    found
+}
+
+/// The output of `runtime` with `args`, if it is installed and answers successfully in time
+async fn runtime_output(runtime: &str, args: &[&str]) -> Option<String> {
+    let program = tools::find(runtime)?;
+    let mut command = tokio::process::Command::new(program);
+    command
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let output = match tokio::time::timeout(RUNTIME_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => output,
+        Ok(Ok(output)) => {
+            tracing::debug!(
+                stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                "`{runtime}` could not describe its storage"
+            );
+            return None;
+        },
+        Ok(Err(err)) => {
+            tracing::debug!(%err, "Could not run `{runtime}`");
+            return None;
+        },
+        Err(_) => {
+            tracing::debug!("`{runtime}` did not describe its storage in time");
+            return None;
+        },
+    };
+    String::from_utf8(output.stdout).ok()
+}
+
+/// The storage paths under `nix` in the output of `runtime`, one per line
+fn storage_under(runtime: &str, output: &str, nix: &Path) -> Vec<ContainerUse> {
+    let mut found: Vec<ContainerUse> = vec![];
+    for line in output.lines() {
+        let path = PathBuf::from(line.trim());
+        if !path.starts_with(nix) {
+            continue;
+        }
+        let storage = ContainerUse::Storage {
+            runtime: runtime.to_string(),
+            path,
+        };
+        if !found.contains(&storage) {
+            found.push(storage);
+        }
+    }
+    found
+}
+
+/// Overlay mounts in `mountinfo` with lower directories under `nix`, and those directories
+///
+/// The superblock options follow the filesystem type and source after the `-` separator, in them
+/// `lowerdir` lists the layers separated by `:`, a literal `:` or `,` is escaped with a `\`.
+pub(crate) fn overlays_under(mountinfo: &str, nix: &Path) -> Vec<ContainerUse> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let mount_point = fields.get(4)?;
+            let separator = fields.iter().skip(6).position(|field| *field == "-")? + 6;
+            if *fields.get(separator + 1)? != "overlay" {
+                return None;
+            }
+            let options = unescape_octal(fields.get(separator + 3)?);
+            let lowerdirs = split_escaped(&options, ',')
+                .into_iter()
+                .filter_map(|option| option.strip_prefix("lowerdir=").map(str::to_string))
+                .flat_map(|lowerdir| split_escaped(&lowerdir, ':'))
+                .map(|lowerdir| PathBuf::from(lowerdir.replace("\\:", ":").replace("\\,", ",")))
+                .filter(|lowerdir| lowerdir.starts_with(nix))
+                .collect::<Vec<_>>();
+            if lowerdirs.is_empty() {
+                return None;
+            }
+            Some(ContainerUse::Overlay {
+                mount_point: PathBuf::from(unescape_octal(mount_point)),
+                lowerdirs,
+            })
+        })
+        .collect()
+}
+
+/// Split `value` on each `separator` not escaped with a `\`, leaving escapes in place
+fn split_escaped(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut escaped = false;
+    for c in value.chars() {
+        if c == separator && !escaped {
+            parts.push(std::mem::take(&mut part));
+            continue;
+        }
+        escaped = c == '\\' && !escaped;
+        part.push(c);
+    }
+    parts.push(part);
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// Refuse to uninstall from under containers unless `break_containers`
+pub(crate) fn check(
+    container_uses: &[ContainerUse],
+    break_containers: bool,
+) -> Result<(), NixInstallerError> {
+    if container_uses.is_empty() || break_containers {
+        return Ok(());
+    }
+    Err(NixInstallerError::Containers(
+        container_uses.iter().map(ToString::to_string).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MOUNTINFO_CONTAINERS: &str =
+        include_str!("../../tests/fixtures/linux/mountinfo-containers.txt");
+
+    #[test]
+    fn finds_overlays_layered_on_nix() {
+        assert_eq!(
+            overlays_under(MOUNTINFO_CONTAINERS, Path::new("/nix")),
+            [
+                ContainerUse::Overlay {
+                    mount_point: "/run/containers/storage/overlay-containers/91c/rootfs".into(),
+                    lowerdirs: vec![
+                        "/nix/store/0a1b-layer-1".into(),
+                        "/nix/store/2c3d-layer-2".into(),
+                    ],
+                },
+                ContainerUse::Overlay {
+                    mount_point: "/nix/var/containers/storage/overlay/7d1/merged".into(),
+                    lowerdirs: vec![
+                        "/nix/var/containers/storage/overlay/l/Q:R".into(),
+                        "/nix/var/containers/storage/overlay/l/S".into(),
+                    ],
+                },
+            ]
+        );
+        assert!(overlays_under(MOUNTINFO_CONTAINERS, Path::new("/opt/nix")).is_empty());
+        assert!(overlays_under("garbage\n\n", Path::new("/nix")).is_empty());
+    }
+
+    #[test]
+    fn splits_escaped_separators() {
+        assert_eq!(
+            split_escaped("rw,lowerdir=/a\\,b:/c\\:d,userxattr", ','),
+            ["rw", "lowerdir=/a\\,b:/c\\:d", "userxattr"]
+        );
+        assert_eq!(split_escaped("/a\\:b::/c", ':'), ["/a\\:b", "/c"]);
+        assert_eq!(split_escaped("/a\\\\:/b", ':'), ["/a\\\\", "/b"]);
+    }
+
+    #[test]
+    fn finds_storage_under_nix() {
+        let podman = "/nix/var/containers/storage\n/nix/var/containers/storage/volumes\n";
+        assert_eq!(
+            storage_under("podman", podman, Path::new("/nix")),
+            [
+                ContainerUse::Storage {
+                    runtime: "podman".into(),
+                    path: "/nix/var/containers/storage".into(),
+                },
+                ContainerUse::Storage {
+                    runtime: "podman".into(),
+                    path: "/nix/var/containers/storage/volumes".into(),
+                },
+            ]
+        );
+        assert!(storage_under("docker", "/var/lib/docker\n", Path::new("/nix")).is_empty());
+        assert!(storage_under("docker", "", Path::new("/nix")).is_empty());
+    }
+
+    #[test]
+    fn requires_breaking_containers_explicitly() {
+        let uses = storage_under("podman", "/nix/podman\n", Path::new("/nix"));
+        let err = check(&uses, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("* `podman` stores containers in `/nix/podman`"));
+        assert!(check(&uses, true).is_ok());
+        assert!(check(&[], false).is_ok());
+    }
+}
@@ -1,11 +1,36 @@
-use std::path::PathBuf;
+/*! Typed output of the `diskutil` and `launchctl` commands
+
+The human readable output of these is localized and changes between macOS releases, so only their
+machine readable forms are parsed: the `-plist` output of `diskutil`, the table of `launchctl list`
+and the overrides database behind `launchctl print-disabled`. Parse failures include the raw output.
+*/
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use serde::de::DeserializeOwned;
+use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::{action::ActionErrorKind, execute_command, os::tools};
+
+/// Where launchd records which services are disabled, read by `launchctl print-disabled`
+pub(crate) const LAUNCHD_DISABLED_DIR: &str = "/var/db/com.apple.xpc.launchd";
 
 #[derive(serde::Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct DiskUtilInfoOutput {
     pub parent_whole_disk: String,
     pub global_permissions_enabled: bool,
+    /// `None` when unmounted, newer releases report an empty `MountPoint` rather than none
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub mount_point: Option<PathBuf>,
+    #[cfg(target_os = "macos")]
+    #[serde(default, rename = "VolumeUUID")]
+    pub volume_uuid: Option<String>,
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -14,6 +39,16 @@ pub struct DiskUtilApfsListOutput {
     pub containers: Vec<DiskUtilApfsContainer>,
 }
 
+impl DiskUtilApfsListOutput {
+    /// The volume named `name` in any of the containers
+    pub fn volume(&self, name: &str) -> Option<&DiskUtilApfsListVolume> {
+        self.containers
+            .iter()
+            .flat_map(|container| &container.volumes)
+            .find(|volume| volume.name.as_deref() == Some(name))
+    }
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct DiskUtilApfsContainer {
@@ -25,4 +60,282 @@ pub struct DiskUtilApfsContainer {
 pub struct DiskUtilApfsListVolume {
     pub name: Option<String>,
     pub encryption: bool,
+    #[serde(default, rename = "APFSVolumeUUID")]
+    pub volume_uuid: Option<Uuid>,
+}
+
+/// A job in the table `launchctl list` prints, which is neither localized nor changed between releases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LaunchctlListEntry {
+    /// `None` when the job is not running
+    pub pid: Option<u32>,
+    /// The exit status of its last run, negative if it was killed by a signal
+    pub last_exit_status: Option<i64>,
+    pub label: String,
+}
+
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let path = <Option<PathBuf> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(path.filter(|path| !path.as_os_str().is_empty()))
+}
+
+/// Deserialize the `-plist` output of `command`, failures include the raw output
+pub(crate) fn parse_plist<T: DeserializeOwned>(
+    command: &Command,
+    output: &[u8],
+) -> Result<T, ActionErrorKind> {
+    plist::from_bytes(output).map_err(|e| ActionErrorKind::UnparsableOutput {
+        command: format!("{:?}", command.as_std()),
+        message: e.to_string(),
+        output: String::from_utf8_lossy(output).into_owned(),
+    })
+}
+
+/// `diskutil info -plist` of `target`, a mount point, device or volume name
+pub(crate) async fn diskutil_info(
+    target: impl AsRef<OsStr>,
+) -> Result<DiskUtilInfoOutput, ActionErrorKind> {
+    let mut command = Command::new("/usr/sbin/diskutil");
+    command
+        .process_group(0)
+        .args(["info", "-plist"])
+        .arg(target)
+        .stdin(std::process::Stdio::null());
+    let output = execute_command(&mut command).await?;
+    parse_plist(&command, &output.stdout)
+}
+
+/// `diskutil apfs list -plist`
+pub(crate) async fn diskutil_apfs_list() -> Result<DiskUtilApfsListOutput, ActionErrorKind> {
+    let mut command = Command::new("/usr/sbin/diskutil");
+    command
+        .process_group(0)
+        .args(["apfs", "list", "-plist"])
+        .stdin(std::process::Stdio::null());
+    let output = execute_command(&mut command).await?;
+    parse_plist(&command, &output.stdout)
+}
+
+/// Parse the table printed by `launchctl list`, with or without its `PID Status Label` header
+pub(crate) fn parse_launchctl_list(output: &str) -> Result<Vec<LaunchctlListEntry>, String> {
+    let mut entries = vec![];
+    for line in output.lines() {
+        let mut columns = line.split_whitespace();
+        let (Some(pid), Some(status), Some(label)) =
+            (columns.next(), columns.next(), columns.next())
+        else {
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Err(format!("Expected three columns in `{line}`"));
+        };
+        if (pid, status, label) == ("PID", "Status", "Label") {
+            continue;
+        }
+        let pid = match pid {
+            "-" => None,
+            pid => Some(
+                pid.parse()
+                    .map_err(|e| format!("Parsing the PID of `{line}`: {e}"))?,
+            ),
+        };
+        let last_exit_status = match status {
+            "-" => None,
+            status => Some(
+                status
+                    .parse()
+                    .map_err(|e| format!("Parsing the status of `{line}`: {e}"))?,
+            ),
+        };
+        entries.push(LaunchctlListEntry {
+            pid,
+            last_exit_status,
+            label: label.to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+/// The job labelled `label` from `launchctl list`, `None` if it is not loaded
+pub(crate) async fn launchctl_list_entry(
+    label: &str,
+) -> Result<Option<LaunchctlListEntry>, ActionErrorKind> {
+    let mut command = tools::command("launchctl");
+    command
+        .process_group(0)
+        .arg("list")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+    let output = execute_command(&mut command).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let entries =
+        parse_launchctl_list(&stdout).map_err(|message| ActionErrorKind::UnparsableOutput {
+            command: format!("{:?}", command.as_std()),
+            message,
+            output: stdout.to_string(),
+        })?;
+    Ok(entries.into_iter().find(|entry| entry.label == label))
+}
+
+/// The overrides database of a launchd `domain`, such as `system` or `gui/501`
+pub(crate) fn disabled_overrides_path(dir: &Path, domain: &str) -> PathBuf {
+    match domain.split_once('/') {
+        Some((_, uid)) => dir.join(format!("disabled.{uid}.plist")),
+        None => dir.join("disabled.plist"),
+    }
+}
+
+/// If `service` of `domain` is disabled in the overrides database in `dir`
+pub(crate) fn service_is_disabled_in(
+    dir: &Path,
+    domain: &str,
+    service: &str,
+) -> Result<bool, ActionErrorKind> {
+    let path = disabled_overrides_path(dir, domain);
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        // No service of the domain was ever disabled (or enabled)
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(ActionErrorKind::Read(path, e)),
+    };
+    let overrides: BTreeMap<String, bool> =
+        plist::from_bytes(&buf).map_err(|e| ActionErrorKind::UnparsableOutput {
+            command: format!("reading `{}`", path.display()),
+            message: e.to_string(),
+            output: String::from_utf8_lossy(&buf).into_owned(),
+        })?;
+    Ok(overrides.get(service).copied().unwrap_or(false))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DISKUTIL_INFO_MONTEREY: &[u8] =
+        include_bytes!("../../tests/fixtures/darwin/diskutil-info-12.plist");
+    const DISKUTIL_INFO_SONOMA: &[u8] =
+        include_bytes!("../../tests/fixtures/darwin/diskutil-info-14.plist");
+    const DISKUTIL_APFS_LIST_VENTURA: &[u8] =
+        include_bytes!("../../tests/fixtures/darwin/diskutil-apfs-list-13.plist");
+    const LAUNCHCTL_LIST_MONTEREY: &str =
+        include_str!("../../tests/fixtures/darwin/launchctl-list-12.txt");
+    const LAUNCHCTL_LIST_SONOMA: &str =
+        include_str!("../../tests/fixtures/darwin/launchctl-list-14.txt");
+    const DISABLED_SONOMA: &[u8] = include_bytes!("../../tests/fixtures/darwin/disabled-14.plist");
+
+    #[test]
+    fn parses_diskutil_info() -> eyre::Result<()> {
+        let command = Command::new("/usr/sbin/diskutil");
+
+        let monterey: DiskUtilInfoOutput = parse_plist(&command, DISKUTIL_INFO_MONTEREY)?;
+        assert_eq!(monterey.parent_whole_disk, "disk1");
+        assert!(monterey.global_permissions_enabled);
+        assert_eq!(monterey.mount_point, Some(PathBuf::from("/nix")));
+        #[cfg(target_os = "macos")]
+        assert_eq!(
+            monterey.volume_uuid.as_deref(),
+            Some("3C2B4E8A-7F1D-4B5E-9C3A-2D6E8F0A1B4C")
+        );
+
+        let sonoma: DiskUtilInfoOutput = parse_plist(&command, DISKUTIL_INFO_SONOMA)?;
+        assert_eq!(sonoma.parent_whole_disk, "disk3");
+        assert!(!sonoma.global_permissions_enabled);
+        assert_eq!(sonoma.mount_point, None);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_diskutil_apfs_list() -> eyre::Result<()> {
+        let command = Command::new("/usr/sbin/diskutil");
+        let list: DiskUtilApfsListOutput = parse_plist(&command, DISKUTIL_APFS_LIST_VENTURA)?;
+
+        let nix = list.volume("Nix Store").expect("the Nix Store volume");
+        assert!(nix.encryption);
+        assert_eq!(
+            nix.volume_uuid,
+            Some(Uuid::parse_str("3C2B4E8A-7F1D-4B5E-9C3A-2D6E8F0A1B4C")?)
+        );
+        assert!(list.volume("Macintosh HD - Data").is_some());
+        assert!(list.volume("Not A Volume").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn unparsable_plists_include_the_output() {
+        let command = Command::new("/usr/sbin/diskutil");
+        let err = parse_plist::<DiskUtilInfoOutput>(&command, b"Impossible de trouver le disque")
+            .err()
+            .expect("an error");
+        assert!(
+            err.to_string().contains("Impossible de trouver le disque"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn parses_launchctl_list() -> eyre::Result<()> {
+        for output in [LAUNCHCTL_LIST_MONTEREY, LAUNCHCTL_LIST_SONOMA] {
+            let entries = parse_launchctl_list(output).map_err(|e| eyre::eyre!(e))?;
+            let daemon = entries
+                .iter()
+                .find(|entry| entry.label == "org.nixos.nix-daemon")
+                .expect("the Nix daemon");
+            assert!(daemon.pid.is_some());
+            assert_eq!(daemon.last_exit_status, Some(0));
+
+            let store = entries
+                .iter()
+                .find(|entry| entry.label == "org.nixos.darwin-store")
+                .expect("the store mounter");
+            assert_eq!(store.pid, None);
+        }
+
+        let killed =
+            parse_launchctl_list("-\t-9\tcom.example.killed\n").map_err(|e| eyre::eyre!(e))?;
+        assert_eq!(killed[0].last_exit_status, Some(-9));
+        assert!(parse_launchctl_list("PID\tStatus\tLabel\nnot a table\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reads_disabled_overrides() -> eyre::Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        assert!(!service_is_disabled_in(
+            dir.path(),
+            "system",
+            "org.nixos.nix-daemon"
+        )?);
+
+        std::fs::write(dir.path().join("disabled.plist"), DISABLED_SONOMA)?;
+        assert!(service_is_disabled_in(
+            dir.path(),
+            "system",
+            "org.nixos.nix-daemon"
+        )?);
+        assert!(!service_is_disabled_in(
+            dir.path(),
+            "system",
+            "com.openssh.sshd"
+        )?);
+        assert!(!service_is_disabled_in(
+            dir.path(),
+            "system",
+            "org.nixos.darwin-store"
+        )?);
+
+        assert_eq!(
+            disabled_overrides_path(dir.path(), "gui/501"),
+            dir.path().join("disabled.501.plist")
+        );
+        assert!(!service_is_disabled_in(
+            dir.path(),
+            "gui/501",
+            "org.nixos.nix-daemon"
+        )?);
+        Ok(())
+    }
 }
@@ -0,0 +1,403 @@
+/*! Services outside of the install which run programs out of `/nix`
+
+Binaries installed with `nix profile install` are often run by hand written systemd units (or
+launchd jobs), uninstalling removes them from under those services. Unit files are parsed from the
+standard directories directly, rather than asking systemd over D-Bus, so this works even when
+systemd isn't (fully) running.
+*/
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::{action::ActionErrorKind, os::tools, NixInstallerError};
+
+/// Where systemd loads units from, in order of precedence
+pub(crate) const SYSTEMD_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/run/systemd/system",
+    "/usr/local/lib/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+
+/// Where launchd loads system wide jobs from
+pub(crate) const LAUNCHD_DIRS: &[&str] = &["/Library/LaunchDaemons", "/Library/LaunchAgents"];
+
+/// Units and jobs of Nix itself, uninstalling removes them along with it
+const NIX_UNITS: &[&str] = &[
+    "nix-daemon.service",
+    "nix-daemon.socket",
+    "org.nixos.nix-daemon.plist",
+    "org.nixos.darwin-store.plist",
+    "systems.determinate.nix-installer.nix-hook.plist",
+];
+
+/// The settings of a systemd unit which run a command
+const EXEC_SETTINGS: &[&str] = &[
+    "ExecCondition",
+    "ExecStartPre",
+    "ExecStart",
+    "ExecStartPost",
+    "ExecReload",
+    "ExecStop",
+    "ExecStopPost",
+];
+
+/// A unit (or job) which runs programs out of `/nix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Dependent {
+    /// The unit file (or plist) which references `/nix`, a drop-in of the unit if it is one
+    pub(crate) path: PathBuf,
+    /// The name of the unit, or the label of the launchd job
+    pub(crate) unit: String,
+    /// The paths under `/nix` it runs
+    pub(crate) references: Vec<String>,
+}
+
+impl std::fmt::Display for Dependent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` (`{}`) runs `{}`",
+            self.unit,
+            self.path.display(),
+            self.references.join("`, `")
+        )
+    }
+}
+
+impl Dependent {
+    fn is_launchd(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|extension| extension == "plist")
+    }
+
+    /// Stop the unit (or job), so it doesn't keep running binaries which are about to be removed
+    pub(crate) async fn stop(&self) -> Result<(), ActionErrorKind> {
+        let mut command = if self.is_launchd() {
+            let mut command = tools::command("launchctl");
+            command.arg("bootout").arg(format!("system/{}", self.unit));
+            command
+        } else {
+            let mut command = tools::command("systemctl");
+            command.arg("stop").arg(&self.unit);
+            command
+        };
+        crate::execute_command(command.stdin(std::process::Stdio::null())).await?;
+        Ok(())
+    }
+}
+
+/// The directories units are loaded from on this system
+pub(crate) fn unit_dirs() -> &'static [&'static str] {
+    if cfg!(target_os = "macos") {
+        LAUNCHD_DIRS
+    } else {
+        SYSTEMD_UNIT_DIRS
+    }
+}
+
+/// Every absolute path recorded in the actions of a serialized plan, the files it created among them
+///
+/// The commands the install ran are left out, the programs they ran aren't ours.
+pub(crate) fn recorded_paths(plan: &Value) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    collect_paths(&plan["actions"], &mut paths);
+    paths
+}
+
+fn collect_paths(value: &Value, paths: &mut BTreeSet<PathBuf>) {
+    match value {
+        Value::String(string) if string.starts_with('/') => {
+            paths.insert(PathBuf::from(string));
+        },
+        Value::Array(values) => values.iter().for_each(|value| collect_paths(value, paths)),
+        Value::Object(object) => object
+            .values()
+            .for_each(|value| collect_paths(value, paths)),
+        _ => (),
+    }
+}
+
+/// Units in `dirs` which run programs out of `/nix`, other than those of Nix and those in `ours`
+///
+/// A unit in more than one of `dirs` is only reported from the first, as systemd would load it.
+pub(crate) fn scan(dirs: &[impl AsRef<Path>], ours: &BTreeSet<PathBuf>) -> Vec<Dependent> {
+    let mut seen = BTreeSet::new();
+    let mut found = vec![];
+    for dir in dirs {
+        let mut entries = match dir.as_ref().read_dir() {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        for path in entries {
+            let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            // Drop-ins in `$UNIT.d/` can override the commands of a unit
+            let (unit, files) = match file_name.strip_suffix(".d") {
+                Some(unit) if path.is_dir() => (unit.to_string(), drop_ins(&path)),
+                _ => (file_name.to_string(), vec![path.clone()]),
+            };
+            if NIX_UNITS.contains(&unit.as_str()) {
+                continue;
+            }
+            for file in files {
+                if ours.contains(&file) || !seen.insert(file_key(&unit, &file)) {
+                    continue;
+                }
+                let Ok(contents) = std::fs::read_to_string(&file) else {
+                    continue;
+                };
+                let (unit, references) = match unit.strip_suffix(".plist") {
+                    Some(label) => (label.to_string(), plist_references(&contents)),
+                    None => (unit.clone(), unit_references(&contents)),
+                };
+                if !references.is_empty() {
+                    found.push(Dependent {
+                        path: file,
+                        unit,
+                        references,
+                    });
+                }
+            }
+        }
+    }
+    found
+}
+
+/// What a unit file (or drop-in) is overridden by in a directory of higher precedence
+fn file_key(unit: &str, file: &Path) -> String {
+    match file.parent().and_then(Path::file_name) {
+        Some(parent) if parent.to_string_lossy().ends_with(".d") => format!(
+            "{}/{}",
+            parent.to_string_lossy(),
+            file.file_name().unwrap_or_default().to_string_lossy()
+        ),
+        _ => unit.to_string(),
+    }
+}
+
+fn drop_ins(dir: &Path) -> Vec<PathBuf> {
+    let mut files = dir
+        .read_dir()
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|extension| extension == "conf")
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// The paths under `/nix` the `Exec*=` settings of a systemd unit run
+pub(crate) fn unit_references(contents: &str) -> Vec<String> {
+    let mut references = vec![];
+    let mut logical_line = String::new();
+    for line in contents.lines() {
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                logical_line.push_str(continued);
+                logical_line.push(' ');
+                continue;
+            },
+            None => logical_line.push_str(line),
+        }
+        let line = std::mem::take(&mut logical_line);
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let Some((setting, command)) = line.split_once('=') else {
+            continue;
+        };
+        if !EXEC_SETTINGS.contains(&setting.trim()) {
+            continue;
+        }
+        for word in command.split_whitespace() {
+            // Special executable prefixes, such as `-` to ignore failure
+            let word = word.trim_start_matches(['@', '-', ':', '+', '!', '|']);
+            if word.starts_with("/nix/") && !references.iter().any(|known| known == word) {
+                references.push(word.to_string());
+            }
+        }
+    }
+    references
+}
+
+/// The paths under `/nix` in the strings of an XML property list, such as its `ProgramArguments`
+pub(crate) fn plist_references(contents: &str) -> Vec<String> {
+    let mut references: Vec<String> = vec![];
+    for part in contents.split("<string>").skip(1) {
+        let Some((string, _)) = part.split_once("</string>") else {
+            continue;
+        };
+        let string = string.trim();
+        if string.starts_with("/nix/") && !references.iter().any(|known| known == string) {
+            references.push(string.to_string());
+        }
+    }
+    references
+}
+
+/// Refuse to uninstall from under `dependents` unless `break_dependents`
+pub(crate) fn check(
+    dependents: &[Dependent],
+    break_dependents: bool,
+) -> Result<(), NixInstallerError> {
+    if dependents.is_empty() || break_dependents {
+        return Ok(());
+    }
+    Err(NixInstallerError::Dependents(
+        dependents.iter().map(ToString::to_string).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)
+    }
+
+    #[test]
+    fn finds_units_running_nix_programs() -> eyre::Result<()> {
+        let etc = tempfile::TempDir::new()?;
+        let lib = tempfile::TempDir::new()?;
+        write(
+            &etc.path().join("hello.service"),
+            "[Service]\n\
+            ExecStartPre=-/nix/var/nix/profiles/per-user/ops/bin/hello --check\n\
+            ExecStart=/usr/bin/env \\\n  /nix/store/abc-hello/bin/hello --serve\n\
+            # ExecStart=/nix/store/commented-out/bin/hello\n",
+        )?;
+        write(
+            &etc.path().join("sshd.service.d/override.conf"),
+            "[Service]\nExecStart=\nExecStart=@/nix/store/def-openssh/bin/sshd sshd -D\n",
+        )?;
+        write(
+            &etc.path().join("nginx.service"),
+            "[Service]\nExecStart=/usr/sbin/nginx\n",
+        )?;
+        write(
+            &etc.path().join("nix-daemon.service"),
+            "[Service]\nExecStart=@/nix/store/ghi-nix/bin/nix-daemon nix-daemon --daemon\n",
+        )?;
+        write(
+            &etc.path().join("created.service"),
+            "[Service]\nExecStart=/nix/var/nix/profiles/default/bin/nix-store --gc\n",
+        )?;
+        // Shadowed by the unit of the same name in `etc`
+        write(
+            &lib.path().join("nginx.service"),
+            "[Service]\nExecStart=/nix/store/jkl-nginx/bin/nginx\n",
+        )?;
+
+        let ours = BTreeSet::from([etc.path().join("created.service")]);
+        let found = scan(&[etc.path(), lib.path()], &ours);
+        assert_eq!(
+            found,
+            [
+                Dependent {
+                    path: etc.path().join("hello.service"),
+                    unit: "hello.service".into(),
+                    references: vec![
+                        "/nix/var/nix/profiles/per-user/ops/bin/hello".into(),
+                        "/nix/store/abc-hello/bin/hello".into(),
+                    ],
+                },
+                Dependent {
+                    path: etc.path().join("sshd.service.d/override.conf"),
+                    unit: "sshd.service".into(),
+                    references: vec!["/nix/store/def-openssh/bin/sshd".into()],
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn finds_launchd_jobs_running_nix_programs() -> eyre::Result<()> {
+        let daemons = tempfile::TempDir::new()?;
+        write(
+            &daemons.path().join("com.example.hello.plist"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>com.example.hello</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>/nix/var/nix/profiles/default/bin/hello</string>
+    <string>--serve</string>
+  </array>
+</dict>
+</plist>
+"#,
+        )?;
+        write(
+            &daemons.path().join("org.nixos.nix-daemon.plist"),
+            "<string>/nix/var/nix/profiles/default/bin/nix-daemon</string>",
+        )?;
+
+        let found = scan(&[daemons.path()], &BTreeSet::new());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].unit, "com.example.hello");
+        assert_eq!(
+            found[0].references,
+            ["/nix/var/nix/profiles/default/bin/hello"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn recorded_paths_are_ours() {
+        let plan = serde_json::json!({
+            "actions": [{
+                "action": {"action": "create_file", "path": "/etc/systemd/system/nix.mount"},
+                "state": "Completed",
+            }],
+            "planner": {"settings": {"nix_build_group_name": "nixbld"}},
+        });
+        assert_eq!(
+            recorded_paths(&plan),
+            BTreeSet::from([PathBuf::from("/etc/systemd/system/nix.mount")])
+        );
+    }
+
+    #[test]
+    fn dependents_need_break_dependents() {
+        let dependent = Dependent {
+            path: "/etc/systemd/system/hello.service".into(),
+            unit: "hello.service".into(),
+            references: vec!["/nix/store/abc-hello/bin/hello".into()],
+        };
+
+        assert!(check(&[], false).is_ok());
+        assert!(check(std::slice::from_ref(&dependent), true).is_ok());
+        match check(&[dependent], false) {
+            Err(NixInstallerError::Dependents(units)) => assert_eq!(
+                units,
+                ["`hello.service` (`/etc/systemd/system/hello.service`) runs `/nix/store/abc-hello/bin/hello`"]
+            ),
+            other => panic!("Expected `Dependents`, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/*! Telling when this binary runs on hardware of another architecture
+
+An `x86_64` build run under Rosetta on Apple silicon, or an `i686` build in a 32 bit userland on a
+64 bit kernel, would install Nix for the architecture it runs as, so everything Nix builds would be
+emulated (or refuse to build, for packages without 32 bit support). The installer for the hardware
+installs the right Nix, so rather than guess at a tarball this one fails pointing at it.
+*/
+
+/// Where the installer binaries are published, by Nix system
+pub(crate) const INSTALLER_URL_BASE: &str = "https://install.determinate.systems/nix";
+
+/// This binary is built for [`binary`](Self::binary), but the hardware is [`native`](Self::native)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchitectureMismatch {
+    /// The Nix system this binary is built for, like `x86_64-darwin`
+    pub binary: &'static str,
+    /// The Nix system of the hardware, like `aarch64-darwin`
+    pub native: &'static str,
+    /// How the binary runs anyway, like `under Rosetta`
+    pub how: &'static str,
+}
+
+impl ArchitectureMismatch {
+    /// The installer built for the hardware
+    pub fn installer_url(&self) -> String {
+        format!("{INSTALLER_URL_BASE}/nix-installer-{}", self.native)
+    }
+}
+
+/// The Nix system this binary is built for, `None` for those Nix has no tarball for
+pub(crate) fn binary_system() -> Option<&'static str> {
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        Some("x86_64-linux")
+    } else if cfg!(all(target_os = "linux", target_arch = "x86")) {
+        Some("i686-linux")
+    } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+        Some("aarch64-linux")
+    } else if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+        Some("x86_64-darwin")
+    } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        Some("aarch64-darwin")
+    } else {
+        None
+    }
+}
+
+/// From the value of the `sysctl.proc_translated` sysctl, `None` on Macs without Rosetta
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub(crate) fn rosetta(
+    proc_translated: Option<&str>,
+    binary: &'static str,
+) -> Option<ArchitectureMismatch> {
+    (proc_translated?.trim() == "1").then_some(ArchitectureMismatch {
+        binary,
+        native: "aarch64-darwin",
+        how: "under Rosetta",
+    })
+}
+
+/// From the `machine` `uname` reports, the architecture of the kernel rather than of this process
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn compat_userland(machine: &str, binary: &'static str) -> Option<ArchitectureMismatch> {
+    let native = match machine {
+        "x86_64" | "amd64" => "x86_64-linux",
+        "aarch64" | "arm64" => "aarch64-linux",
+        "i386" | "i486" | "i586" | "i686" => "i686-linux",
+        _ => return None,
+    };
+    (native != binary).then_some(ArchitectureMismatch {
+        binary,
+        native,
+        how: "in a userland of another architecture",
+    })
+}
+
+/// Whether this process runs on hardware of another architecture than it is built for
+#[cfg(target_os = "linux")]
+pub(crate) fn detect() -> Option<ArchitectureMismatch> {
+    let uname = nix::sys::utsname::uname().ok()?;
+    compat_userland(&uname.machine().to_string_lossy(), binary_system()?)
+}
+
+/// Whether this process runs on hardware of another architecture than it is built for
+#[cfg(target_os = "macos")]
+pub(crate) fn detect() -> Option<ArchitectureMismatch> {
+    use sysctl::Sysctl;
+
+    // Missing on Macs without Rosetta
+    let proc_translated = sysctl::Ctl::new("sysctl.proc_translated")
+        .and_then(|ctl| ctl.value_string())
+        .ok();
+    rosetta(proc_translated.as_deref(), binary_system()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_rosetta() {
+        let mismatch = rosetta(Some("1"), "x86_64-darwin").expect("Rosetta is detected");
+        assert_eq!(mismatch.native, "aarch64-darwin");
+        assert_eq!(
+            mismatch.installer_url(),
+            "https://install.determinate.systems/nix/nix-installer-aarch64-darwin"
+        );
+        assert_eq!(rosetta(Some("0"), "aarch64-darwin"), None);
+        assert_eq!(rosetta(None, "x86_64-darwin"), None);
+    }
+
+    #[test]
+    fn detects_userland_of_another_architecture() {
+        let mismatch =
+            compat_userland("x86_64", "i686-linux").expect("The 32 bit userland is detected");
+        assert_eq!(mismatch.native, "x86_64-linux");
+        assert_eq!(
+            compat_userland("aarch64", "x86_64-linux").map(|mismatch| mismatch.native),
+            Some("aarch64-linux")
+        );
+        assert_eq!(compat_userland("x86_64", "x86_64-linux"), None);
+        assert_eq!(compat_userland("aarch64", "aarch64-linux"), None);
+        // As under `linux32`, which reports the personality's architecture
+        assert_eq!(compat_userland("i686", "i686-linux"), None);
+        // Nix has no tarball to point at
+        assert_eq!(compat_userland("armv7l", "aarch64-linux"), None);
+    }
+}
@@ -0,0 +1,211 @@
+/*! How many hardlinks a file may have on the filesystem `/nix` is on
+
+`auto-optimise-store` deduplicates the store by hardlinking identical files, popular files (such as
+empty ones) end up with thousands of links. Some network filesystems and older HFS derived ones
+allow only a few, builds then fail once optimising the store runs out of them.
+*/
+
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
+
+use nix::errno::Errno;
+
+/// How many links the probe makes at most, a filesystem allowing this many is fine for optimising the store
+pub(crate) const PROBE_LINKS: u32 = 256;
+const PROBE_DIR_PREFIX: &str = ".nix-installer-hardlink-probe";
+
+/// What probing a filesystem for how many hardlinks a file may have found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct HardlinkProbe {
+    /// How many links the probe file had when the probe stopped
+    pub links: u32,
+    /// If the filesystem refused another link, rather than the probe stopping at [`PROBE_LINKS`]
+    pub limited: bool,
+}
+
+impl HardlinkProbe {
+    /// If `auto-optimise-store` would run out of links
+    pub fn too_low(&self) -> bool {
+        self.limited && self.links < PROBE_LINKS
+    }
+}
+
+impl std::fmt::Display for HardlinkProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.links {
+            1 if self.limited => write!(f, "the filesystem does not support hardlinks"),
+            links if self.limited => {
+                write!(f, "the filesystem allows only {links} links to a file")
+            },
+            links => write!(f, "the filesystem allows at least {links} links to a file"),
+        }
+    }
+}
+
+/// The directory the probe runs in for `path`, which may not exist yet: its nearest existing ancestor
+pub(crate) fn probe_dir(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|ancestor| ancestor.is_dir())
+        .unwrap_or(Path::new("/"))
+        .to_path_buf()
+}
+
+/// Probe the filesystem of `dir` by linking a file up to `max_links` times, see [`probe_with`]
+pub(crate) fn probe(dir: &Path, max_links: u32) -> std::io::Result<HardlinkProbe> {
+    probe_with(dir, max_links, |original, link| {
+        std::fs::hard_link(original, link)
+    })
+}
+
+/// Probe the filesystem of `dir` with `link`, in a directory of its own which is always removed
+pub(crate) fn probe_with(
+    dir: &Path,
+    max_links: u32,
+    link: impl Fn(&Path, &Path) -> std::io::Result<()>,
+) -> std::io::Result<HardlinkProbe> {
+    let probe_dir = ProbeDir::create(dir)?;
+    let original = probe_dir.path.join("file");
+    std::fs::write(&original, b"")?;
+
+    let mut links = 1;
+    while links < max_links {
+        match link(&original, &probe_dir.path.join(format!("link-{links}"))) {
+            Ok(()) => links += 1,
+            // Too many links, or none at all
+            Err(err)
+                if [Errno::EMLINK, Errno::EPERM, Errno::ENOTSUP]
+                    .into_iter()
+                    .any(|errno| err.raw_os_error() == Some(errno as i32)) =>
+            {
+                return Ok(HardlinkProbe {
+                    links,
+                    limited: true,
+                })
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(HardlinkProbe {
+        links,
+        limited: false,
+    })
+}
+
+/// The directory a probe links in, removed with everything in it when dropped
+struct ProbeDir {
+    path: PathBuf,
+}
+
+impl ProbeDir {
+    fn create(dir: &Path) -> std::io::Result<Self> {
+        let path = dir.join(format!("{PROBE_DIR_PREFIX}-{}", process::id()));
+        // Left behind by a probe which was killed
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ProbeDir {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.path) {
+            tracing::warn!(
+                %err,
+                "Could not remove `{}` the hardlink probe was in",
+                self.path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// On a tmpfs where there is one, as `/nix` often is in containers
+    fn tmpfs_dir() -> std::io::Result<tempfile::TempDir> {
+        match Path::new("/dev/shm").is_dir() {
+            true => tempfile::tempdir_in("/dev/shm"),
+            false => tempfile::tempdir(),
+        }
+    }
+
+    fn is_empty(dir: &Path) -> std::io::Result<bool> {
+        Ok(std::fs::read_dir(dir)?.next().is_none())
+    }
+
+    #[test]
+    fn probes_up_to_bound_and_cleans_up() -> eyre::Result<()> {
+        let dir = tmpfs_dir()?;
+        let probe = probe(dir.path(), 16)?;
+        assert_eq!(
+            probe,
+            HardlinkProbe {
+                links: 16,
+                limited: false
+            }
+        );
+        assert!(!probe.too_low());
+        assert!(is_empty(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_low_limits_and_cleans_up() -> eyre::Result<()> {
+        let dir = tmpfs_dir()?;
+        let probe = probe_with(dir.path(), PROBE_LINKS, |original, link| {
+            if link.ends_with("link-8") {
+                return Err(std::io::Error::from_raw_os_error(Errno::EMLINK as i32));
+            }
+            std::fs::hard_link(original, link)
+        })?;
+        assert_eq!(
+            probe,
+            HardlinkProbe {
+                links: 8,
+                limited: true
+            }
+        );
+        assert!(probe.too_low());
+        assert_eq!(
+            probe.to_string(),
+            "the filesystem allows only 8 links to a file"
+        );
+        assert!(is_empty(dir.path())?);
+
+        let probe = probe_with(dir.path(), PROBE_LINKS, |_, _| {
+            Err(std::io::Error::from_raw_os_error(Errno::EPERM as i32))
+        })?;
+        assert_eq!(
+            probe.to_string(),
+            "the filesystem does not support hardlinks"
+        );
+        assert!(is_empty(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn cleans_up_on_failure() -> eyre::Result<()> {
+        let dir = tmpfs_dir()?;
+        let result = probe_with(dir.path(), PROBE_LINKS, |original, link| {
+            if link.ends_with("link-4") {
+                return Err(std::io::Error::from_raw_os_error(Errno::ENOSPC as i32));
+            }
+            std::fs::hard_link(original, link)
+        });
+        assert!(result.is_err());
+        assert!(is_empty(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn probes_nearest_existing_ancestor() -> eyre::Result<()> {
+        let dir = tmpfs_dir()?;
+        assert_eq!(probe_dir(&dir.path().join("nix/store")), dir.path());
+        Ok(())
+    }
+}
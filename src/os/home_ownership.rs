@@ -0,0 +1,241 @@
+/*! Nix dotfiles in a home directory which are owned by `root`
+
+Running `sudo nix-env -i` or `sudo nix build` once leaves `root` owned files in the invoking user's
+home (`sudo` keeps `HOME` on many distributions), after which every unprivileged `nix` command fails
+with permission errors. Only the known Nix paths are looked at, and symlinks are never followed, so
+nothing outside of them (or outside of the home directory) is ever changed.
+*/
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid, User};
+
+use crate::{action::ActionErrorKind, env};
+
+/// The paths Nix creates in a home directory, relative to it
+pub(crate) const NIX_DOTFILES: &[&str] = &[
+    ".cache/nix",
+    ".config/nix",
+    ".local/state/nix",
+    ".nix-channels",
+    ".nix-defexpr",
+    ".nix-profile",
+];
+
+/// The user whose home to check, the one who ran `sudo` when running under it
+pub(crate) fn target_user() -> Option<User> {
+    match env::SUDO_USER.get() {
+        Some(name) if name != "root" => User::from_name(&name).ok().flatten(),
+        _ => User::from_uid(Uid::current()).ok().flatten(),
+    }
+}
+
+/// The known Nix paths in `home` containing anything owned by `root`, the topmost of each
+pub(crate) fn scan(home: &Path) -> Vec<PathBuf> {
+    let mut found = vec![];
+    for dotfile in NIX_DOTFILES {
+        let Some(path) = contained(home, dotfile) else {
+            continue;
+        };
+        collect_root_owned(&path, &mut found);
+    }
+    found
+}
+
+/// `home` joined with `relative`, unless a directory between them is a symlink (which could lead out of `home`)
+pub(crate) fn contained(home: &Path, relative: &str) -> Option<PathBuf> {
+    let path = home.join(relative);
+    let mut parent = path.parent()?;
+    while parent != home {
+        if parent.symlink_metadata().ok()?.file_type().is_symlink() {
+            tracing::debug!(
+                "Not checking `{}`, `{}` is a symlink",
+                path.display(),
+                parent.display()
+            );
+            return None;
+        }
+        parent = parent.parent()?;
+    }
+    path.symlink_metadata().is_ok().then_some(path)
+}
+
+fn collect_root_owned(path: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return;
+    };
+    if metadata.uid() == 0 {
+        found.push(path.to_path_buf());
+        return;
+    }
+    if metadata.is_dir() {
+        let Ok(entries) = path.read_dir() else {
+            return;
+        };
+        for entry in entries.flatten() {
+            collect_root_owned(&entry.path(), found);
+        }
+    }
+}
+
+/// Give everything owned by `root` in `paths` (as found by [`scan`]) to `uid` and `gid`, returning how many entries changed
+pub(crate) fn fix(
+    home: &Path,
+    paths: &[PathBuf],
+    uid: u32,
+    gid: u32,
+) -> Result<usize, ActionErrorKind> {
+    let mut changed = 0;
+    for path in paths {
+        // Only ever what `scan` could have found
+        let known = NIX_DOTFILES
+            .iter()
+            .filter_map(|dotfile| contained(home, dotfile))
+            .any(|dotfile| path.starts_with(dotfile));
+        if !known {
+            return Err(ActionErrorKind::PathWasNotNixDotfile(path.clone()));
+        }
+        changed += chown_root_owned(path, uid, gid)?;
+    }
+    Ok(changed)
+}
+
+fn chown_root_owned(path: &Path, uid: u32, gid: u32) -> Result<usize, ActionErrorKind> {
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+    let mut changed = 0;
+    if metadata.uid() == 0 {
+        // Changes a symlink itself, never what it points to
+        fchownat(
+            None,
+            path,
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .map_err(|e| ActionErrorKind::Chown(path.to_path_buf(), e))?;
+        changed += 1;
+    }
+    if metadata.is_dir() {
+        let entries = path
+            .read_dir()
+            .map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?;
+            changed += chown_root_owned(&entry.path(), uid, gid)?;
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::{lchown, symlink};
+
+    use super::*;
+
+    const USER: u32 = 4321;
+
+    /// A home owned by [`USER`], with `root` owned entries mixed in
+    fn home() -> eyre::Result<(tempfile::TempDir, PathBuf)> {
+        let temp_dir = tempfile::tempdir()?;
+        let home = temp_dir.path().join("home");
+        let outside = temp_dir.path().join("outside");
+        for dir in [
+            "home/.cache/nix/fetcher-cache-v1",
+            "home/.nix-defexpr/channels",
+            "home/.config",
+            "home/.local/state/nix/profiles",
+            "home/projects",
+            "outside/nix",
+        ] {
+            std::fs::create_dir_all(temp_dir.path().join(dir))?;
+        }
+        std::fs::write(home.join(".cache/nix/fetcher-cache-v1/db.sqlite"), "")?;
+        std::fs::write(home.join(".local/state/nix/profiles/profile"), "")?;
+        std::fs::write(home.join("projects/build"), "")?;
+        std::fs::write(outside.join("nix/nix.conf"), "")?;
+        // A `.config` leading out of the home directory, which must not be touched
+        std::fs::remove_dir(home.join(".config"))?;
+        symlink(&outside, home.join(".config"))?;
+        symlink("/nix/var/nix/profiles/default", home.join(".nix-profile"))?;
+
+        for entry in walkdir(temp_dir.path()) {
+            lchown(&entry, Some(USER), Some(USER))?;
+        }
+        // What `sudo nix-env -i` and `sudo nix build` leave behind
+        for root_owned in [
+            ".cache/nix/fetcher-cache-v1",
+            ".cache/nix/fetcher-cache-v1/db.sqlite",
+            ".nix-defexpr/channels",
+            ".nix-profile",
+            "projects/build",
+        ] {
+            lchown(home.join(root_owned), Some(0), Some(0))?;
+        }
+        lchown(outside.join("nix/nix.conf"), Some(0), Some(0))?;
+        Ok((temp_dir, home))
+    }
+
+    fn walkdir(path: &Path) -> Vec<PathBuf> {
+        let mut paths = vec![path.to_path_buf()];
+        if path.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false) {
+            for entry in path.read_dir().unwrap().flatten() {
+                paths.extend(walkdir(&entry.path()));
+            }
+        }
+        paths
+    }
+
+    fn uid(path: &Path) -> u32 {
+        path.symlink_metadata().unwrap().uid()
+    }
+
+    #[test]
+    fn finds_and_fixes_root_owned_dotfiles() -> eyre::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, changing ownership needs root");
+            return Ok(());
+        }
+        let (temp_dir, home) = home()?;
+
+        let found = scan(&home);
+        assert_eq!(
+            found,
+            vec![
+                home.join(".cache/nix/fetcher-cache-v1"),
+                home.join(".nix-defexpr/channels"),
+                home.join(".nix-profile"),
+            ]
+        );
+
+        assert_eq!(fix(&home, &found, USER, USER)?, 4);
+        assert!(scan(&home).is_empty());
+        assert_eq!(
+            uid(&home.join(".cache/nix/fetcher-cache-v1/db.sqlite")),
+            USER
+        );
+        // The symlink itself changed owner, not the profile it points to
+        assert_eq!(uid(&home.join(".nix-profile")), USER);
+        // Nothing outside the Nix dotfiles, or outside the home, changed
+        assert_eq!(uid(&home.join("projects/build")), 0);
+        assert_eq!(uid(&temp_dir.path().join("outside/nix/nix.conf")), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_fix_other_paths() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let home = temp_dir.path();
+        std::fs::create_dir_all(home.join("projects"))?;
+        assert!(matches!(
+            fix(home, &[home.join("projects")], USER, USER),
+            Err(ActionErrorKind::PathWasNotNixDotfile(_))
+        ));
+        Ok(())
+    }
+}
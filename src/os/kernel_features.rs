@@ -0,0 +1,228 @@
+/*! Kernel features which settings of `nix.conf` rely on
+
+Newer Nix features need kernel support older (often enterprise) kernels lack, the daemon then fails
+to start once installed. Each entry of [`REQUIREMENTS`] names the setting (and value) which needs a
+[`KernelFeature`], so settings passed with `--extra-conf` are checked before anything is installed.
+*/
+
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+/// A kernel feature a setting of `nix.conf` needs, see [`KernelFeature::probe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KernelFeature {
+    /// Builds run with a range of UIDs mapped into a user namespace
+    UserNamespaces,
+    /// The unified (v2) cgroup hierarchy the daemon puts builds into
+    CgroupsV2,
+}
+
+impl std::fmt::Display for KernelFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UserNamespaces => write!(f, "user namespaces"),
+            Self::CgroupsV2 => write!(f, "the unified (v2) cgroup hierarchy"),
+        }
+    }
+}
+
+impl KernelFeature {
+    /// If the kernel (with `/proc` and `/sys` under `root`) has this feature
+    pub(crate) fn probe_at(&self, root: &Path) -> bool {
+        match self {
+            Self::UserNamespaces => {
+                root.join("proc/self/ns/user").exists()
+                    && std::fs::read_to_string(root.join("proc/sys/user/max_user_namespaces"))
+                        .ok()
+                        .and_then(|max| max.trim().parse::<u64>().ok())
+                        .is_some_and(|max| max > 0)
+            },
+            Self::CgroupsV2 => root.join("sys/fs/cgroup/cgroup.controllers").exists(),
+        }
+    }
+
+    pub(crate) fn probe(&self) -> bool {
+        self.probe_at(Path::new("/"))
+    }
+}
+
+/// A setting which needs a kernel feature when it includes `value`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Requirement {
+    pub(crate) setting: &'static str,
+    /// `true` for boolean settings, otherwise one of the whitespace separated values of a list
+    pub(crate) value: &'static str,
+    pub(crate) feature: KernelFeature,
+}
+
+impl std::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{} = {}` needs {}",
+            self.setting, self.value, self.feature
+        )
+    }
+}
+
+pub(crate) const REQUIREMENTS: &[Requirement] = &[
+    Requirement {
+        setting: "auto-allocate-uids",
+        value: "true",
+        feature: KernelFeature::UserNamespaces,
+    },
+    Requirement {
+        setting: "system-features",
+        value: "uid-range",
+        feature: KernelFeature::UserNamespaces,
+    },
+    Requirement {
+        setting: "extra-system-features",
+        value: "uid-range",
+        feature: KernelFeature::UserNamespaces,
+    },
+    Requirement {
+        setting: "use-cgroups",
+        value: "true",
+        feature: KernelFeature::CgroupsV2,
+    },
+    Requirement {
+        setting: "experimental-features",
+        value: "cgroups",
+        feature: KernelFeature::CgroupsV2,
+    },
+    Requirement {
+        setting: "extra-experimental-features",
+        value: "cgroups",
+        feature: KernelFeature::CgroupsV2,
+    },
+];
+
+/// The [`REQUIREMENTS`] `settings` has which `probe` finds the kernel lacks
+pub(crate) fn unsupported(
+    settings: &IndexMap<String, String>,
+    probe: impl Fn(KernelFeature) -> bool,
+) -> Vec<Requirement> {
+    REQUIREMENTS
+        .iter()
+        .filter(|requirement| {
+            settings.get(requirement.setting).is_some_and(|value| {
+                value
+                    .split_whitespace()
+                    .any(|value| value == requirement.value)
+            })
+        })
+        .filter(|requirement| !probe(requirement.feature))
+        .copied()
+        .collect()
+}
+
+/// Drop the `unsupported` values from `settings`, turning booleans off
+pub(crate) fn relax(settings: &mut IndexMap<String, String>, unsupported: &[Requirement]) {
+    for requirement in unsupported {
+        let Some(value) = settings.get_mut(requirement.setting) else {
+            continue;
+        };
+        *value = if requirement.value == "true" {
+            "false".to_string()
+        } else {
+            value
+                .split_whitespace()
+                .filter(|value| *value != requirement.value)
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+        pairs
+            .iter()
+            .map(|(setting, value)| (setting.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn requires_features_of_requested_settings() {
+        let requested = settings(&[
+            ("auto-allocate-uids", "true"),
+            ("experimental-features", "nix-command flakes cgroups"),
+            ("use-cgroups", "false"),
+            ("system-features", "kvm big-parallel"),
+        ]);
+
+        assert_eq!(unsupported(&requested, |_| true), []);
+        assert_eq!(
+            unsupported(&requested, |feature| feature != KernelFeature::CgroupsV2),
+            [REQUIREMENTS[4]]
+        );
+        assert_eq!(
+            unsupported(&requested, |_| false)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "`auto-allocate-uids = true` needs user namespaces",
+                "`experimental-features = cgroups` needs the unified (v2) cgroup hierarchy"
+            ]
+        );
+    }
+
+    #[test]
+    fn relaxes_unsupported_settings() {
+        let mut requested = settings(&[
+            ("auto-allocate-uids", "true"),
+            ("experimental-features", "nix-command cgroups flakes"),
+        ]);
+        let missing = unsupported(&requested, |_| false);
+        relax(&mut requested, &missing);
+
+        assert_eq!(
+            requested,
+            settings(&[
+                ("auto-allocate-uids", "false"),
+                ("experimental-features", "nix-command flakes"),
+            ])
+        );
+        assert_eq!(unsupported(&requested, |_| false), []);
+    }
+
+    #[test]
+    fn probes_user_namespaces() -> eyre::Result<()> {
+        let root = tempfile::TempDir::new()?;
+        assert!(!KernelFeature::UserNamespaces.probe_at(root.path()));
+
+        std::fs::create_dir_all(root.path().join("proc/self/ns"))?;
+        std::fs::create_dir_all(root.path().join("proc/sys/user"))?;
+        std::fs::write(root.path().join("proc/self/ns/user"), "")?;
+        std::fs::write(root.path().join("proc/sys/user/max_user_namespaces"), "0\n")?;
+        assert!(!KernelFeature::UserNamespaces.probe_at(root.path()));
+
+        std::fs::write(
+            root.path().join("proc/sys/user/max_user_namespaces"),
+            "63207\n",
+        )?;
+        assert!(KernelFeature::UserNamespaces.probe_at(root.path()));
+        Ok(())
+    }
+
+    #[test]
+    fn probes_cgroups_v2() -> eyre::Result<()> {
+        let root = tempfile::TempDir::new()?;
+        // A v1 hierarchy has a directory per controller instead
+        std::fs::create_dir_all(root.path().join("sys/fs/cgroup/memory"))?;
+        assert!(!KernelFeature::CgroupsV2.probe_at(root.path()));
+
+        std::fs::write(
+            root.path().join("sys/fs/cgroup/cgroup.controllers"),
+            "cpu memory pids\n",
+        )?;
+        assert!(KernelFeature::CgroupsV2.probe_at(root.path()));
+        Ok(())
+    }
+}
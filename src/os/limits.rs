@@ -0,0 +1,240 @@
+/*! The open file and inotify limits Nix builds run into, and what `--raise-limits` raises them to
+
+Large builds open many files, and their test suites watch many with inotify. Some distributions
+leave the limits low enough for such builds to fail with "too many open files" long after the
+install. Planners warn of each limit below its [`minimum`](Limit::minimum), the self-test checks
+them again, and [`RaiseLimits`](crate::action::linux::RaiseLimits) raises the sysctls while the
+daemon gets a `LimitNOFILE=` of its own.
+*/
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// A limit, the least Nix builds are comfortable with and what `--raise-limits` raises it to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limit {
+    pub(crate) name: &'static str,
+    pub(crate) minimum: u64,
+    pub(crate) raised_to: u64,
+}
+
+/// The sysctls checked, and raised in [`SYSCTL_FRAGMENT`]
+pub(crate) const SYSCTLS: &[Limit] = &[
+    Limit {
+        name: "fs.file-max",
+        minimum: 65_536,
+        raised_to: 1_048_576,
+    },
+    Limit {
+        name: "fs.inotify.max_user_watches",
+        minimum: 65_536,
+        raised_to: 524_288,
+    },
+    Limit {
+        name: "fs.inotify.max_user_instances",
+        minimum: 128,
+        raised_to: 1_024,
+    },
+];
+
+/// The hard limit of systemd's `DefaultLimitNOFILE=`, which services can raise their own limit to
+///
+/// It is not changed for every service, the daemon gets a `LimitNOFILE=` of
+/// [`raised_to`](Limit::raised_to) instead.
+pub(crate) const SERVICE_NOFILE: Limit = Limit {
+    name: "DefaultLimitNOFILE",
+    minimum: 65_536,
+    raised_to: 1_048_576,
+};
+
+/// The hard limit of `DefaultLimitNOFILE=` when systemd is not configured otherwise
+const SYSTEMD_DEFAULT_NOFILE_HARD: u64 = 524_288;
+
+/// Read after the distribution's fragments, and before `99-sysctl.conf` which is the admin's
+pub(crate) const SYSCTL_FRAGMENT: &str = "/etc/sysctl.d/60-nix-installer.conf";
+
+const PROC_SYS: &str = "proc/sys";
+const SYSTEMD_SYSTEM_CONF: &str = "etc/systemd/system.conf";
+const SYSTEMD_SYSTEM_CONF_D: &str = "etc/systemd/system.conf.d";
+
+/// A limit below its [`minimum`](Limit::minimum)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LowLimit {
+    pub(crate) limit: Limit,
+    pub(crate) value: u64,
+}
+
+impl fmt::Display for LowLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is {}, large Nix builds may fail with \"too many open files\" below {}",
+            self.limit.name, self.value, self.limit.minimum
+        )
+    }
+}
+
+/// Where the sysctl `name` is read and written, on a system with its root at `root`
+pub(crate) fn sysctl_path(root: &Path, name: &str) -> PathBuf {
+    root.join(PROC_SYS).join(name.replace('.', "/"))
+}
+
+/// The value of the sysctl `name`, `None` when it can't be read (such as in some containers)
+pub(crate) fn read_sysctl(root: &Path, name: &str) -> Option<u64> {
+    std::fs::read_to_string(sysctl_path(root, name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The hard limit of systemd's `DefaultLimitNOFILE=`, the last one set in its configuration wins
+fn service_nofile_hard(root: &Path) -> u64 {
+    let mut confs = vec![root.join(SYSTEMD_SYSTEM_CONF)];
+    if let Ok(entries) = std::fs::read_dir(root.join(SYSTEMD_SYSTEM_CONF_D)) {
+        let mut drop_ins = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "conf")
+            })
+            .collect::<Vec<_>>();
+        drop_ins.sort();
+        confs.extend(drop_ins);
+    }
+    confs
+        .iter()
+        .filter_map(|conf| std::fs::read_to_string(conf).ok())
+        .flat_map(|conf| {
+            conf.lines()
+                .filter_map(|line| line.trim().strip_prefix("DefaultLimitNOFILE="))
+                .map(parse_nofile_hard)
+                .collect::<Vec<_>>()
+        })
+        .last()
+        .flatten()
+        .unwrap_or(SYSTEMD_DEFAULT_NOFILE_HARD)
+}
+
+/// The hard limit of a `SOFT:HARD` (or a single value for both) `LimitNOFILE=`
+fn parse_nofile_hard(value: &str) -> Option<u64> {
+    let hard = value.trim().rsplit(':').next()?;
+    match hard {
+        "infinity" => Some(u64::MAX),
+        hard => hard.parse().ok(),
+    }
+}
+
+/// The limits of the system with its root at `root` below their minimum, the sysctls which can't
+/// be read are skipped and the services' limit only checked with `systemd`
+pub(crate) fn low_limits(root: &Path, systemd: bool) -> Vec<LowLimit> {
+    let mut low = SYSCTLS
+        .iter()
+        .filter_map(|limit| {
+            Some(LowLimit {
+                limit: *limit,
+                value: read_sysctl(root, limit.name)?,
+            })
+        })
+        .collect::<Vec<_>>();
+    if systemd {
+        low.push(LowLimit {
+            limit: SERVICE_NOFILE,
+            value: service_nofile_hard(root),
+        });
+    }
+    low.retain(|low| low.value < low.limit.minimum);
+    low
+}
+
+/// The `sysctl.d` fragment raising each of `limits` to its [`raised_to`](Limit::raised_to)
+pub(crate) fn render_sysctl_fragment(limits: &[Limit]) -> String {
+    let mut buf = String::from(
+        "# Raised by nix-installer for large Nix builds, removed by `/nix/nix-installer uninstall`\n",
+    );
+    for limit in limits {
+        buf.push_str(&format!("{} = {}\n", limit.name, limit.raised_to));
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(root: &Path, path: &str, contents: &str) -> std::io::Result<()> {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().expect("Not the root"))?;
+        std::fs::write(path, contents)
+    }
+
+    #[test]
+    fn finds_low_limits() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        write(root, "proc/sys/fs/file-max", "9223372036854775807\n")?;
+        write(root, "proc/sys/fs/inotify/max_user_watches", "8192\n")?;
+        write(root, "proc/sys/fs/inotify/max_user_instances", "128\n")?;
+        // Unread without systemd
+        write(root, "etc/systemd/system.conf", "DefaultLimitNOFILE=1024\n")?;
+
+        assert_eq!(
+            low_limits(root, false),
+            [LowLimit {
+                limit: SYSCTLS[1],
+                value: 8192,
+            }]
+        );
+        assert_eq!(
+            low_limits(root, true)[1],
+            LowLimit {
+                limit: SERVICE_NOFILE,
+                value: 1024,
+            }
+        );
+        assert_eq!(
+            low_limits(root, false)[0].to_string(),
+            "`fs.inotify.max_user_watches` is 8192, large Nix builds may fail with \"too many open files\" below 65536"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reads_the_last_default_nofile() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        assert_eq!(service_nofile_hard(root), SYSTEMD_DEFAULT_NOFILE_HARD);
+
+        write(
+            root,
+            "etc/systemd/system.conf",
+            "[Manager]\n#DefaultLimitNOFILE=1024:524288\nDefaultLimitNOFILE=1024:4096\n",
+        )?;
+        assert_eq!(service_nofile_hard(root), 4096);
+        write(
+            root,
+            "etc/systemd/system.conf.d/10-limits.conf",
+            "[Manager]\nDefaultLimitNOFILE=2048\n",
+        )?;
+        write(
+            root,
+            "etc/systemd/system.conf.d/20-limits.conf",
+            "[Manager]\nDefaultLimitNOFILE=1024:infinity\n",
+        )?;
+        assert_eq!(service_nofile_hard(root), u64::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn renders_sysctl_fragment() {
+        assert_eq!(
+            render_sysctl_fragment(&SYSCTLS[1..]),
+            "# Raised by nix-installer for large Nix builds, removed by `/nix/nix-installer uninstall`\n\
+            fs.inotify.max_user_watches = 524288\n\
+            fs.inotify.max_user_instances = 1024\n"
+        );
+    }
+}
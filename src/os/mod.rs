@@ -1 +1,15 @@
+pub(crate) mod cloud_init;
+pub(crate) mod containers;
 pub mod darwin;
+pub(crate) mod dependents;
+pub mod emulation;
+pub(crate) mod hardlinks;
+pub(crate) mod home_ownership;
+#[cfg(target_os = "linux")]
+pub(crate) mod kernel_features;
+pub(crate) mod limits;
+pub(crate) mod mounts;
+pub(crate) mod nss;
+pub(crate) mod per_user;
+pub(crate) mod tools;
+pub(crate) mod user_environment;
@@ -0,0 +1,307 @@
+/*! Guard rails for removing directories which may have other filesystems mounted inside them
+
+Users mount caches or overlay stores under `/nix`, removing it recursively would then either fail
+partway through or delete what is on the mounted filesystem.
+*/
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::action::ActionErrorKind;
+
+/// An entry of the mount table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mount {
+    pub(crate) mount_point: PathBuf,
+    /// Such as `ext4`, `tmpfs`, or `apfs`
+    pub(crate) fs_type: String,
+}
+
+/// The mount points strictly below `path`, which must be unmounted before it can be removed
+pub(crate) async fn mounts_under(path: &Path) -> Result<Vec<PathBuf>, ActionErrorKind> {
+    // Mount points are listed by their real path
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mounts = mounts().await?;
+    let mut found = mounts
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .filter(|mount_point| *mount_point != path && mount_point.starts_with(&path))
+        .collect::<Vec<_>>();
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+/// Refuse to continue if anything is mounted below `path`
+pub(crate) async fn ensure_no_mounts_under(path: &Path) -> Result<(), ActionErrorKind> {
+    let mounts = mounts_under(path).await?;
+    if mounts.is_empty() {
+        Ok(())
+    } else {
+        Err(ActionErrorKind::MountsUnder(path.to_path_buf(), mounts))
+    }
+}
+
+/// The mount `path` is on, the last mounted of those with the longest mount point containing it
+pub(crate) async fn mount_containing(path: &Path) -> Result<Option<Mount>, ActionErrorKind> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mounts = mounts().await?;
+    Ok(mounts
+        .into_iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count()))
+}
+
+#[cfg(target_os = "linux")]
+async fn mounts() -> Result<Vec<Mount>, ActionErrorKind> {
+    const MOUNTINFO: &str = "/proc/self/mountinfo";
+    let mountinfo = tokio::fs::read_to_string(MOUNTINFO)
+        .await
+        .map_err(|e| ActionErrorKind::Read(MOUNTINFO.into(), e))?;
+    Ok(parse_mountinfo(&mountinfo))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn mounts() -> Result<Vec<Mount>, ActionErrorKind> {
+    let mut command = tokio::process::Command::new("/sbin/mount");
+    command.process_group(0);
+    let output = crate::execute_command(&mut command).await?;
+    let output = String::from_utf8(output.stdout).map_err(ActionErrorKind::FromUtf8)?;
+    Ok(parse_mount_output(&output))
+}
+
+/// Mounts from `/proc/self/mountinfo`, where the fifth field is the mount point with whitespace and
+/// backslashes octal escaped, and the filesystem type follows the `-` separating the optional fields
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let mount_point = fields.get(4)?;
+            let separator = fields.iter().skip(6).position(|field| *field == "-")? + 6;
+            Some(Mount {
+                mount_point: PathBuf::from(unescape_octal(mount_point)),
+                fs_type: fields.get(separator + 1)?.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn unescape_octal(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let escape = rest.get(index + 1..index + 4);
+        match escape.and_then(|digits| u8::from_str_radix(digits, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(char::from(byte));
+                rest = &rest[index + 4..];
+            },
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            },
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Mounts from the output of `mount` on MacOS, where each line looks like
+/// `/dev/disk3s7 on /nix (apfs, local, journaled, nobrowse)`
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+fn parse_mount_output(output: &str) -> Vec<Mount> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (_device, rest) = line.split_once(" on ")?;
+            let (mount_point, options) = rest.rsplit_once(" (")?;
+            let fs_type = options.split([',', ')']).next()?;
+            Some(Mount {
+                mount_point: PathBuf::from(mount_point),
+                fs_type: fs_type.to_string(),
+            })
+        })
+        .collect()
+}
+
+/**
+Remove `path` and everything in it, without ever descending into a directory on another filesystem
+
+This backs up [`ensure_no_mounts_under`], in case something was mounted since it was checked or
+the mount table did not list it. The walk runs on a blocking thread, a populated `/nix` takes a while.
+*/
+pub(crate) async fn remove_dir_all_same_filesystem(path: &Path) -> Result<(), ActionErrorKind> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let metadata = path
+            .symlink_metadata()
+            .map_err(|e| ActionErrorKind::GettingMetadata(path.clone(), e))?;
+        remove_contents_on(&path, metadata.dev())?;
+        std::fs::remove_dir(&path).map_err(|e| ActionErrorKind::Remove(path.clone(), e))
+    })
+    .await?
+}
+
+/// Remove the contents of `path`, but not `path` itself, refusing to leave the filesystem `dev`
+pub(crate) async fn remove_dir_contents_on(path: &Path, dev: u64) -> Result<(), ActionErrorKind> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || remove_contents_on(&path, dev)).await?
+}
+
+fn remove_contents_on(path: &Path, dev: u64) -> Result<(), ActionErrorKind> {
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+    if metadata.dev() != dev {
+        return Err(ActionErrorKind::CrossesFilesystem(path.to_path_buf()));
+    }
+
+    let entries = path
+        .read_dir()
+        .map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| ActionErrorKind::ReadDir(path.to_path_buf(), e))?;
+        let entry_path = entry.path();
+        let file_type = entry
+            .file_type()
+            .map_err(|e| ActionErrorKind::GettingMetadata(entry_path.clone(), e))?;
+        if file_type.is_dir() {
+            remove_contents_on(&entry_path, dev)?;
+            std::fs::remove_dir(&entry_path)
+                .map_err(|e| ActionErrorKind::Remove(entry_path.clone(), e))?;
+        } else {
+            std::fs::remove_file(&entry_path)
+                .map_err(|e| ActionErrorKind::Remove(entry_path.clone(), e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    fn mount(mount_point: &str, fs_type: &str) -> Mount {
+        Mount {
+            mount_point: PathBuf::from(mount_point),
+            fs_type: fs_type.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_mountinfo() {
+        let mountinfo = "\
+            22 1 254:0 / / rw,relatime shared:1 - ext4 /dev/vda rw\n\
+            43 22 254:0 /var/cache /nix/var/cache rw,relatime - ext4 /dev/vda rw\n\
+            44 22 0:45 / /nix/my\\040store rw - tmpfs tmpfs rw\n";
+        assert_eq!(
+            parse_mountinfo(mountinfo),
+            vec![
+                mount("/", "ext4"),
+                mount("/nix/var/cache", "ext4"),
+                mount("/nix/my store", "tmpfs"),
+            ]
+        );
+        assert_eq!(unescape_octal("a\\134b\\0"), "a\\b\\0");
+    }
+
+    #[test]
+    fn parses_mount_output() {
+        let output = "\
+            /dev/disk3s1s1 on / (apfs, sealed, local, read-only, journaled)\n\
+            /dev/disk3s7 on /nix (apfs, local, journaled, nobrowse)\n\
+            /dev/disk5s1 on /nix/my store (apfs, local, nodev, nosuid)\n";
+        assert_eq!(
+            parse_mount_output(output),
+            vec![
+                mount("/", "apfs"),
+                mount("/nix", "apfs"),
+                mount("/nix/my store", "apfs"),
+            ]
+        );
+    }
+
+    /// Run `mount`, returning `false` where this test cannot mount (not root, or not Linux)
+    fn try_mount(args: &[&str]) -> bool {
+        if !cfg!(target_os = "linux") || !nix::unistd::geteuid().is_root() {
+            return false;
+        }
+        matches!(
+            std::process::Command::new("mount").args(args).status(),
+            Ok(status) if status.success()
+        )
+    }
+
+    fn umount(path: &Path) {
+        let _ = std::process::Command::new("umount").arg(path).status();
+    }
+
+    #[tokio::test]
+    async fn refuses_with_a_bind_mount_under() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path().join("nix");
+        let cache = temp_dir.path().join("cache");
+        let mount_point = root.join("var/cache");
+        std::fs::create_dir_all(&mount_point)?;
+        std::fs::create_dir_all(&cache)?;
+        std::fs::write(cache.join("keep"), "")?;
+
+        if !try_mount(&[
+            "--bind",
+            cache.to_str().unwrap(),
+            mount_point.to_str().unwrap(),
+        ]) {
+            eprintln!("Skipping, cannot bind mount here");
+            return Ok(());
+        }
+        let refused = ensure_no_mounts_under(&root).await;
+        let found = mounts_under(&root).await;
+        umount(&mount_point);
+
+        assert_eq!(found?, vec![mount_point.clone()]);
+        match refused {
+            Err(ActionErrorKind::MountsUnder(path, mounts)) => {
+                assert_eq!(path, root);
+                assert_eq!(mounts, vec![mount_point]);
+            },
+            other => panic!("Expected a refusal, got {other:?}"),
+        }
+        assert!(cache.join("keep").exists());
+        ensure_no_mounts_under(&root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removal_stops_at_another_filesystem() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let root = temp_dir.path().join("nix");
+        let mount_point = root.join("store");
+        std::fs::create_dir_all(&mount_point)?;
+
+        if !try_mount(&["-t", "tmpfs", "tmpfs", mount_point.to_str().unwrap()]) {
+            eprintln!("Skipping, cannot mount a tmpfs here");
+            return Ok(());
+        }
+        std::fs::write(mount_point.join("keep"), "")?;
+        let removed = remove_dir_all_same_filesystem(&root).await;
+        let kept = mount_point.join("keep").exists();
+        umount(&mount_point);
+
+        assert!(matches!(
+            removed,
+            Err(ActionErrorKind::CrossesFilesystem(path)) if path == mount_point
+        ));
+        assert!(kept, "Contents of the other filesystem should be untouched");
+
+        remove_dir_all_same_filesystem(&root).await?;
+        assert!(!root.exists());
+        Ok(())
+    }
+}
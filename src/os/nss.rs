@@ -0,0 +1,237 @@
+/*! Helpers for dealing with Name Service Switch caching daemons (`nscd`, `sssd`)
+
+When one of these daemons is running, freshly created users and groups may not be visible to
+`getpwnam`/`getgrnam` until their (negative) cache entries expire.
+*/
+
+use std::{path::Path, time::Duration};
+
+use nix::unistd::{Gid, Group, Uid, User};
+
+use crate::execute_command;
+use crate::os::tools;
+
+const NSCD_PIDFILES: &[&str] = &["/run/nscd/nscd.pid", "/var/run/nscd/nscd.pid"];
+const SSSD_PIDFILES: &[&str] = &["/run/sssd.pid", "/var/run/sssd.pid"];
+
+/// How users and groups are looked up, pluggable so the wait loop can be tested
+pub(crate) trait UserBackend {
+    fn user_exists(&self, name: &str) -> bool;
+    fn group_exists(&self, name: &str) -> bool;
+    /// The GID of the group `name`, if it exists
+    fn group_gid(&self, name: &str) -> Option<u32>;
+    /// The name of the group with `gid`, if there is one
+    fn group_name(&self, gid: u32) -> Option<String>;
+    /// The UID and primary GID of the user `name`, if it exists
+    fn user_ids(&self, name: &str) -> Option<(u32, u32)>;
+    /// The name of the user with `uid`, if there is one
+    fn user_name(&self, uid: u32) -> Option<String>;
+    /// The users listed as members of the group `name`, which `nix-daemon` picks build users from
+    fn group_members(&self, name: &str) -> Vec<String>;
+}
+
+/// Looks up users and groups through NSS, the same way `nix-daemon` will
+pub(crate) struct NssUserBackend;
+
+impl UserBackend for NssUserBackend {
+    fn user_exists(&self, name: &str) -> bool {
+        matches!(User::from_name(name), Ok(Some(_)))
+    }
+
+    fn group_exists(&self, name: &str) -> bool {
+        matches!(Group::from_name(name), Ok(Some(_)))
+    }
+
+    fn group_gid(&self, name: &str) -> Option<u32> {
+        Group::from_name(name)
+            .ok()
+            .flatten()
+            .map(|group| group.gid.as_raw())
+    }
+
+    fn group_name(&self, gid: u32) -> Option<String> {
+        Group::from_gid(Gid::from_raw(gid))
+            .ok()
+            .flatten()
+            .map(|group| group.name)
+    }
+
+    fn user_ids(&self, name: &str) -> Option<(u32, u32)> {
+        User::from_name(name)
+            .ok()
+            .flatten()
+            .map(|user| (user.uid.as_raw(), user.gid.as_raw()))
+    }
+
+    fn user_name(&self, uid: u32) -> Option<String> {
+        User::from_uid(Uid::from_raw(uid))
+            .ok()
+            .flatten()
+            .map(|user| user.name)
+    }
+
+    fn group_members(&self, name: &str) -> Vec<String> {
+        Group::from_name(name)
+            .ok()
+            .flatten()
+            .map(|group| group.mem)
+            .unwrap_or_default()
+    }
+}
+
+async fn service_running(unit: &str, pidfiles: &[&str]) -> bool {
+    if pidfiles.iter().any(|pidfile| Path::new(pidfile).exists()) {
+        return true;
+    }
+    if tools::find("systemctl").is_none() {
+        return false;
+    }
+    crate::command_runner::output(
+        tools::command("systemctl")
+            .args(["is-active", "--quiet", unit])
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await
+    .map(|output| output.status.success())
+    .unwrap_or(false)
+}
+
+/// Invalidate the passwd and group caches of any running `nscd` or `sssd`
+///
+/// Failures are logged, not returned, callers should follow up with [`wait_for_resolution`].
+#[tracing::instrument(level = "debug")]
+pub(crate) async fn invalidate_caches() {
+    if service_running("nscd", NSCD_PIDFILES).await {
+        tracing::debug!("Detected `nscd`, invalidating passwd and group caches");
+        if let Err(err) = execute_command(
+            tools::command("nscd")
+                .args(["-i", "passwd", "-i", "group"])
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        {
+            tracing::warn!(%err, "Could not invalidate `nscd` caches, will wait for them to expire");
+        }
+    }
+    if service_running("sssd", SSSD_PIDFILES).await {
+        tracing::debug!("Detected `sssd`, invalidating user and group caches");
+        if let Err(err) = execute_command(
+            tools::command("sss_cache")
+                .arg("-UG")
+                .stdin(std::process::Stdio::null()),
+        )
+        .await
+        {
+            tracing::warn!(%err, "Could not invalidate `sssd` caches, will wait for them to expire");
+        }
+    }
+}
+
+/// Wait until every user and group resolves, returning the names which still did not after `timeout`
+pub(crate) async fn wait_for_resolution(
+    backend: &impl UserBackend,
+    users: &[String],
+    groups: &[String],
+    timeout: Duration,
+    interval: Duration,
+) -> Result<(), Vec<String>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let unresolved = users
+            .iter()
+            .filter(|user| !backend.user_exists(user))
+            .chain(groups.iter().filter(|group| !backend.group_exists(group)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if unresolved.is_empty() {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(unresolved);
+        }
+        tracing::debug!(
+            ?unresolved,
+            "Waiting for users and groups to be resolvable via NSS"
+        );
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Resolves only after a number of lookups, like a stale negative cache entry
+    struct StaleBackend {
+        lookups_until_visible: u32,
+        lookups: AtomicU32,
+    }
+
+    impl UserBackend for StaleBackend {
+        fn user_exists(&self, _name: &str) -> bool {
+            self.lookups.fetch_add(1, Ordering::SeqCst) >= self.lookups_until_visible
+        }
+
+        fn group_exists(&self, _name: &str) -> bool {
+            true
+        }
+
+        fn group_gid(&self, _name: &str) -> Option<u32> {
+            None
+        }
+
+        fn group_name(&self, _gid: u32) -> Option<String> {
+            None
+        }
+
+        fn user_ids(&self, _name: &str) -> Option<(u32, u32)> {
+            None
+        }
+
+        fn user_name(&self, _uid: u32) -> Option<String> {
+            None
+        }
+
+        fn group_members(&self, _name: &str) -> Vec<String> {
+            vec![]
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_after_retry() {
+        let backend = StaleBackend {
+            lookups_until_visible: 3,
+            lookups: AtomicU32::new(0),
+        };
+        let result = wait_for_resolution(
+            &backend,
+            &["nixbld1".into()],
+            &["nixbld".into()],
+            Duration::from_secs(5),
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(backend.lookups.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn reports_unresolved_after_timeout() {
+        let backend = StaleBackend {
+            lookups_until_visible: u32::MAX,
+            lookups: AtomicU32::new(0),
+        };
+        let result = wait_for_resolution(
+            &backend,
+            &["nixbld1".into(), "nixbld2".into()],
+            &["nixbld".into()],
+            Duration::from_millis(20),
+            Duration::from_millis(5),
+        )
+        .await;
+        assert_eq!(result, Err(vec!["nixbld1".into(), "nixbld2".into()]));
+    }
+}
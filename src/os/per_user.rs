@@ -0,0 +1,381 @@
+/*! What Nix and the installer leave behind for a single user, to clean up after one who left
+
+Their profiles and garbage collector roots under `/nix/var/nix`, the Nix dotfiles in their home,
+and the files the installer created or inserted into for them as recorded in the receipt. Nothing
+else of the install is touched, and like [`home_ownership`](super::home_ownership) symlinks in the
+home are never followed.
+*/
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::{
+    action::{
+        base::{verify_nix_store::NIX_STORE_BINARY, CreateDirectory, CreateOrInsertIntoFile},
+        Action, ActionErrorKind, ActionState, StatefulAction,
+    },
+    execute_command,
+    os::home_ownership::{contained, NIX_DOTFILES},
+};
+
+pub(crate) const NIX_STATE_DIR: &str = "/nix/var/nix";
+/// The directories of `NIX_STATE_DIR` holding a directory of their own per user
+const PER_USER_DIRS: &[&str] = &["profiles/per-user", "gcroots/per-user"];
+
+/// If `name` can only name a single entry of a directory
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// The paths to remove for `name`, whose home is `home` if they still have one
+pub(crate) fn scan(nix_state_dir: &Path, name: &str, home: Option<&Path>) -> Vec<PathBuf> {
+    let mut found = PER_USER_DIRS
+        .iter()
+        .map(|dir| nix_state_dir.join(dir).join(name))
+        .filter(|path| path.symlink_metadata().is_ok())
+        .collect::<Vec<_>>();
+    if let Some(home) = home {
+        found.extend(
+            NIX_DOTFILES
+                .iter()
+                .filter_map(|dotfile| contained(home, dotfile)),
+        );
+    }
+    found
+}
+
+/// The store paths the generations of the profiles in `profiles_dir` point at
+pub(crate) fn generations(profiles_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = profiles_dir.read_dir() else {
+        return vec![];
+    };
+    let generations = entries
+        .flatten()
+        .filter_map(|entry| std::fs::read_link(entry.path()).ok())
+        .filter(|target| target.starts_with("/nix/store"))
+        .collect::<BTreeSet<_>>();
+    generations.into_iter().collect()
+}
+
+/// Remove `path`, or the symlink it is, with everything in it
+pub(crate) fn remove(path: &Path) -> Result<(), ActionErrorKind> {
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|e| ActionErrorKind::GettingMetadata(path.to_path_buf(), e))?;
+    // Neither follows symlinks
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .map_err(|e| ActionErrorKind::Remove(path.to_path_buf(), e))
+}
+
+/// The users the install of `receipt` configured something for
+pub(crate) fn managed_users(receipt: &Value) -> BTreeSet<String> {
+    let mut users = BTreeSet::new();
+    visit(receipt, String::new(), &mut |_, value| {
+        if value.get("action_name").and_then(Value::as_str) == Some("configure_user_nix") {
+            if let Some(names) = value.get("users").and_then(Value::as_array) {
+                users.extend(names.iter().filter_map(Value::as_str).map(String::from));
+            }
+        }
+        if let Some(user) = owned_action(value) {
+            users.insert(user.to_string());
+        }
+    });
+    users
+}
+
+/// An action of a receipt, with the JSON pointer to it
+pub(crate) type ReceiptAction = (String, StatefulAction<Box<dyn Action>>);
+
+/// The executed actions of `receipt` which created or inserted into files owned by `name`, in the
+/// order to revert them
+pub(crate) fn receipt_actions(
+    receipt: &Value,
+    name: &str,
+) -> Result<Vec<ReceiptAction>, serde_json::Error> {
+    let mut found = vec![];
+    visit(receipt, String::new(), &mut |pointer, value| {
+        if owned_action(value) == Some(name) {
+            found.push((pointer.to_string(), value.clone()));
+        }
+    });
+
+    let mut actions = vec![];
+    for (pointer, value) in found {
+        let action = if value["action"].get("buf").is_some() {
+            serde_json::from_value::<StatefulAction<CreateOrInsertIntoFile>>(value)?.boxed()
+        } else {
+            serde_json::from_value::<StatefulAction<CreateDirectory>>(value)?.boxed()
+        };
+        if action.state == ActionState::Completed {
+            actions.push((pointer, action));
+        }
+    }
+    // Directories were created before what went in them
+    actions.reverse();
+    Ok(actions)
+}
+
+/// The user a nested `create_or_insert_into_file` or `create_directory` of a receipt is owned by
+fn owned_action(value: &Value) -> Option<&str> {
+    let action = value.get("action")?;
+    value.get("state")?;
+    let kind = action.get("buf").is_some() || action.get("is_mountpoint").is_some();
+    kind.then(|| action.get("user")?.as_str()).flatten()
+}
+
+/// Call `f` with every value of `value`, and its JSON pointer
+fn visit(value: &Value, pointer: String, f: &mut impl FnMut(&str, &Value)) {
+    f(&pointer, value);
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                visit(value, format!("{pointer}/{key}"), f);
+            }
+        },
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                visit(value, format!("{pointer}/{index}"), f);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Delete the store paths in the closures of `generations` which nothing else keeps alive, returning them
+pub(crate) async fn collect_garbage(
+    generations: &[PathBuf],
+) -> Result<Vec<String>, ActionErrorKind> {
+    if generations.is_empty() {
+        return Ok(vec![]);
+    }
+    let closure = execute_command(
+        Command::new(NIX_STORE_BINARY)
+            .args(["--query", "--requisites"])
+            .args(generations)
+            .stdin(Stdio::null()),
+    )
+    .await?;
+    let dead = execute_command(
+        Command::new(NIX_STORE_BINARY)
+            .args(["--gc", "--print-dead"])
+            .stdin(Stdio::null()),
+    )
+    .await?;
+    let deletable = deletable(
+        &String::from_utf8_lossy(&closure.stdout),
+        &String::from_utf8_lossy(&dead.stdout),
+    );
+    if !deletable.is_empty() {
+        execute_command(
+            Command::new(NIX_STORE_BINARY)
+                .arg("--delete")
+                .args(&deletable)
+                .stdin(Stdio::null()),
+        )
+        .await?;
+    }
+    Ok(deletable)
+}
+
+/// The dead paths, as printed by `nix-store --gc --print-dead`, which are in `closure`
+fn deletable(closure: &str, dead: &str) -> Vec<String> {
+    let closure = closure.lines().map(str::trim).collect::<BTreeSet<_>>();
+    dead.lines()
+        .map(str::trim)
+        .filter(|path| closure.contains(path))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn validates_names() {
+        assert!(valid_name("alice"));
+        assert!(valid_name("first.last"));
+        assert!(!valid_name(""));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("../alice"));
+    }
+
+    #[test]
+    fn removes_exactly_per_user_artifacts() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let state = root.path().join("nix/var/nix");
+        let alice_profiles = state.join("profiles/per-user/alice");
+        std::fs::create_dir_all(&alice_profiles)?;
+        symlink(
+            "/nix/store/aaaa-user-environment",
+            alice_profiles.join("profile-1-link"),
+        )?;
+        symlink(
+            "/nix/store/bbbb-user-environment",
+            alice_profiles.join("profile-2-link"),
+        )?;
+        symlink("profile-2-link", alice_profiles.join("profile"))?;
+        std::fs::create_dir_all(state.join("profiles/per-user/bob"))?;
+        std::fs::create_dir_all(state.join("gcroots/per-user/alice"))?;
+        std::fs::create_dir_all(state.join("gcroots/per-user/bob"))?;
+
+        let home = root.path().join("home/alice");
+        std::fs::create_dir_all(home.join(".nix-defexpr/channels"))?;
+        std::fs::create_dir_all(home.join(".cache/nix"))?;
+        std::fs::write(home.join(".nix-channels"), "")?;
+        std::fs::write(home.join(".bashrc"), "")?;
+        symlink(alice_profiles.join("profile"), home.join(".nix-profile"))?;
+        // Leads out of the home, where nothing may be removed
+        let elsewhere = root.path().join("elsewhere");
+        std::fs::create_dir_all(elsewhere.join("state/nix"))?;
+        symlink(&elsewhere, home.join(".local"))?;
+
+        assert_eq!(
+            generations(&alice_profiles),
+            [
+                PathBuf::from("/nix/store/aaaa-user-environment"),
+                PathBuf::from("/nix/store/bbbb-user-environment"),
+            ]
+        );
+        let found = scan(&state, "alice", Some(&home));
+        assert_eq!(
+            found,
+            [
+                alice_profiles.clone(),
+                state.join("gcroots/per-user/alice"),
+                home.join(".cache/nix"),
+                home.join(".nix-channels"),
+                home.join(".nix-defexpr"),
+                home.join(".nix-profile"),
+            ]
+        );
+        for path in &found {
+            remove(path)?;
+        }
+        for path in &found {
+            assert!(path.symlink_metadata().is_err(), "{}", path.display());
+        }
+        assert!(state.join("profiles/per-user/bob").is_dir());
+        assert!(state.join("gcroots/per-user/bob").is_dir());
+        assert!(home.join(".bashrc").is_file());
+        assert!(home.join(".cache").is_dir());
+        assert!(elsewhere.join("state/nix").is_dir());
+
+        // Without a home any longer
+        assert_eq!(scan(&state, "alice", None), Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reverts_receipt_actions_of_user() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        let fish_conf_d = home.path().join(".config/fish/conf.d");
+        let nix_fish = fish_conf_d.join("nix.fish");
+        std::fs::create_dir_all(&fish_conf_d)?;
+        std::fs::write(&nix_fish, "# Nix\n# End Nix\n")?;
+        let receipt = serde_json::json!({
+            "actions": [
+                {
+                    "action": {
+                        "action_name": "configure_user_nix",
+                        "users": ["root", "alice"],
+                    },
+                    "state": "Completed",
+                },
+                {
+                    "action": {
+                        "action_name": "configure_shell_profile",
+                        "create_directories": [{
+                            "action": {
+                                "path": fish_conf_d,
+                                "user": "alice",
+                                "group": null,
+                                "mode": 493,
+                                "is_mountpoint": false,
+                                "force_prune_on_revert": false,
+                            },
+                            "state": "Completed",
+                        }],
+                        "create_or_insert_into_files": [
+                            {
+                                "action": {
+                                    "path": nix_fish,
+                                    "user": "alice",
+                                    "group": null,
+                                    "mode": 420,
+                                    "buf": "# Nix\n# End Nix\n",
+                                    "position": "Beginning",
+                                    "inserted": true,
+                                    "created": true,
+                                },
+                                "state": "Completed",
+                            },
+                            {
+                                "action": {
+                                    "path": "/etc/bashrc",
+                                    "user": null,
+                                    "group": null,
+                                    "mode": 420,
+                                    "buf": "# Nix\n# End Nix\n",
+                                    "position": "Beginning",
+                                },
+                                "state": "Completed",
+                            },
+                        ],
+                    },
+                    "state": "Completed",
+                },
+            ],
+        });
+
+        assert_eq!(
+            managed_users(&receipt),
+            BTreeSet::from(["alice".to_string(), "root".to_string()])
+        );
+        assert!(receipt_actions(&receipt, "bob")?.is_empty());
+
+        let mut actions = receipt_actions(&receipt, "alice")?;
+        assert_eq!(
+            actions
+                .iter()
+                .map(|(pointer, _)| pointer.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "/actions/1/action/create_or_insert_into_files/0",
+                "/actions/1/action/create_directories/0",
+            ]
+        );
+        for (_, action) in &mut actions {
+            action.try_revert().await?;
+        }
+        assert!(!nix_fish.exists());
+        assert!(!fish_conf_d.exists());
+        assert!(home.path().join(".config/fish").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_only_dead_paths_of_closure() {
+        let closure =
+            "/nix/store/aaaa-user-environment\n/nix/store/cccc-hello\n/nix/store/dddd-glibc\n";
+        let dead =
+            "/nix/store/aaaa-user-environment\n/nix/store/cccc-hello\n/nix/store/eeee-other\n";
+        assert_eq!(
+            deletable(closure, dead),
+            ["/nix/store/aaaa-user-environment", "/nix/store/cccc-hello"]
+        );
+    }
+}
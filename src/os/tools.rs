@@ -0,0 +1,318 @@
+/*! The external tools the install runs, like `useradd` or `systemctl`
+
+They are resolved to absolute paths while planning, so a tool missing from a minimal host (or only
+in `/usr/sbin`, which a non-login `root` shell may not have on its `PATH`) is reported up front,
+together with every other missing tool. The resolved paths are recorded in the plan, and used by
+[`command`] within [`with_resolved`], which the plan wraps planning and executing its actions in.
+*/
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    future::Future,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use crate::{env, planner::MissingTool, settings::ToolPath};
+
+/// Where tools are searched for after the directories on `PATH`
+pub(crate) const STANDARD_LOCATIONS: &[&str] = &[
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// What managing users and groups needs on Linux, where either of the `shadow-utils` or `busybox` flavors will do
+pub(crate) const LINUX_USER_TOOLS: &[&[&str]] = &[
+    &["useradd", "adduser"],
+    &["userdel", "deluser"],
+    &["groupadd", "addgroup"],
+    &["groupdel", "delgroup"],
+    &["gpasswd", "addgroup"],
+    &["gpasswd", "delgroup"],
+    &["groups"],
+];
+
+/// What managing the daemon needs with systemd
+pub(crate) const SYSTEMD_TOOLS: &[&[&str]] = &[&["systemctl"], &["systemd-tmpfiles"]];
+
+/// Archivers which are never run, Nix is unpacked in process by
+/// [`FetchAndUnpackNix`](crate::action::base::FetchAndUnpackNix) so minimal hosts need none of them
+pub(crate) const ARCHIVERS: &[&str] = &[
+    "tar", "xz", "unxz", "xzcat", "zstd", "unzstd", "zstdcat", "gzip", "gunzip",
+];
+
+/// Whether `program`, by its file name, is one of the [`ARCHIVERS`]
+pub(crate) fn is_archiver(program: &OsStr) -> bool {
+    Path::new(program)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| ARCHIVERS.contains(&name))
+}
+
+tokio::task_local! {
+    /// The tools resolved for the plan being planned or executed
+    static RESOLVED: BTreeMap<String, PathBuf>;
+}
+
+/// Run `fut` with `tools` as the ones resolved for the plan, see [`find`]
+pub(crate) async fn with_resolved<F: Future>(
+    tools: BTreeMap<String, PathBuf>,
+    fut: F,
+) -> F::Output {
+    RESOLVED.scope(tools, fut).await
+}
+
+/// The absolute path of `name`, as resolved for the plan, otherwise searched for
+///
+/// Plans from before tools were resolved have none recorded, and nothing is resolved outside of [`with_resolved`].
+pub(crate) fn find(name: &str) -> Option<PathBuf> {
+    let resolved = RESOLVED
+        .try_with(|resolved| resolved.get(name).cloned())
+        .ok()
+        .flatten();
+    resolved.or_else(|| search(name, &search_dirs(env::PATH.get_os())))
+}
+
+/// A command running `name` by its absolute path, see [`find`]
+pub(crate) fn command(name: &str) -> Command {
+    match find(name) {
+        Some(path) => Command::new(path),
+        None => Command::new(name),
+    }
+}
+
+/// Resolve the `requirements`, each satisfied by any one of its alternatives, to absolute paths
+///
+/// `overrides` take precedence over the directories on `PATH`, which take precedence over the
+/// [`STANDARD_LOCATIONS`]. Every requirement not satisfied is returned at once.
+pub(crate) fn resolve(
+    requirements: &[&[&str]],
+    overrides: &[ToolPath],
+) -> Result<BTreeMap<String, PathBuf>, Vec<MissingTool>> {
+    resolve_in(requirements, overrides, &search_dirs(env::PATH.get_os()))
+}
+
+fn resolve_in(
+    requirements: &[&[&str]],
+    overrides: &[ToolPath],
+    dirs: &[PathBuf],
+) -> Result<BTreeMap<String, PathBuf>, Vec<MissingTool>> {
+    let mut resolved = BTreeMap::new();
+    let mut missing = vec![];
+
+    for requirement in requirements {
+        let mut looked_in = vec![];
+        for name in *requirement {
+            if resolved.contains_key(*name) {
+                continue;
+            }
+            match overrides.iter().find(|tool| tool.name == *name) {
+                Some(tool) if is_executable(&tool.path) => {
+                    resolved.insert(name.to_string(), tool.path.clone());
+                },
+                // An override which does not work is not silently replaced by what is on `PATH`
+                Some(tool) => looked_in.push(tool.path.clone()),
+                None => match search(name, dirs) {
+                    Some(path) => {
+                        resolved.insert(name.to_string(), path);
+                    },
+                    None => {
+                        for dir in dirs {
+                            if !looked_in.contains(dir) {
+                                looked_in.push(dir.clone());
+                            }
+                        }
+                    },
+                },
+            }
+        }
+        if !requirement.iter().any(|name| resolved.contains_key(*name)) {
+            let missing_tool = MissingTool {
+                alternatives: requirement.iter().map(ToString::to_string).collect(),
+                looked_in,
+            };
+            if !missing.contains(&missing_tool) {
+                missing.push(missing_tool);
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(missing)
+    }
+}
+
+/// The directories on `path`, followed by any [`STANDARD_LOCATIONS`] not already among them
+fn search_dirs(path: Option<OsString>) -> Vec<PathBuf> {
+    let mut dirs = path
+        .map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default();
+    dirs.retain(|dir| dir.is_absolute());
+    for location in STANDARD_LOCATIONS {
+        let location = PathBuf::from(location);
+        if !dirs.contains(&location) {
+            dirs.push(location);
+        }
+    }
+    dirs
+}
+
+fn search(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter()
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tool(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn resolution_order() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let on_path = temp_dir.path().join("bin");
+        let sbin = temp_dir.path().join("sbin");
+        let elsewhere = temp_dir.path().join("opt");
+        for dir in [&on_path, &sbin, &elsewhere] {
+            std::fs::create_dir(dir)?;
+        }
+        let dirs = [on_path.clone(), sbin.clone()];
+
+        tool(&sbin, "useradd");
+        let systemctl = tool(&on_path, "systemctl");
+        tool(&sbin, "systemctl");
+        let groupadd = tool(&elsewhere, "groupadd");
+        tool(&sbin, "groupadd");
+        // Not executable, so not a candidate
+        std::fs::write(on_path.join("useradd"), "")?;
+
+        let resolved = resolve_in(
+            &[&["useradd", "adduser"], &["systemctl"], &["groupadd"]],
+            &[ToolPath {
+                name: "groupadd".into(),
+                path: groupadd.clone(),
+            }],
+            &dirs,
+        )
+        .map_err(|missing| eyre::eyre!("{missing:?}"))?;
+
+        assert_eq!(
+            resolved,
+            BTreeMap::from([
+                ("useradd".to_string(), sbin.join("useradd")),
+                ("systemctl".to_string(), systemctl),
+                ("groupadd".to_string(), groupadd),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_tools_are_reported_together() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dirs = [temp_dir.path().join("bin"), temp_dir.path().join("sbin")];
+        std::fs::create_dir(&dirs[0])?;
+        tool(&dirs[0], "groupadd");
+        let broken_override = temp_dir.path().join("missing/systemctl");
+
+        let missing = resolve_in(
+            &[&["useradd", "adduser"], &["groupadd"], &["systemctl"]],
+            &[ToolPath {
+                name: "systemctl".into(),
+                path: broken_override.clone(),
+            }],
+            &dirs,
+        )
+        .expect_err("Tools should be missing");
+
+        assert_eq!(
+            missing,
+            vec![
+                MissingTool {
+                    alternatives: vec!["useradd".into(), "adduser".into()],
+                    looked_in: dirs.to_vec(),
+                },
+                MissingTool {
+                    alternatives: vec!["systemctl".into()],
+                    looked_in: vec![broken_override],
+                },
+            ]
+        );
+        let error = crate::planner::PlannerError::MissingTools(missing);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Missing required tools: useradd or adduser (looked in {}, {}), systemctl (looked in {})",
+                dirs[0].display(),
+                dirs[1].display(),
+                temp_dir.path().join("missing/systemctl").display(),
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn archivers_are_never_required() {
+        for requirement in [
+            LINUX_USER_TOOLS,
+            SYSTEMD_TOOLS,
+            &[&["launchctl"], &["tmutil"]],
+        ]
+        .concat()
+        {
+            for name in requirement {
+                assert!(!is_archiver(name.as_ref()), "`{name}` is required");
+            }
+        }
+        assert!(is_archiver("/usr/bin/tar".as_ref()));
+        assert!(!is_archiver("/usr/bin/systemctl".as_ref()));
+    }
+
+    #[test]
+    fn searches_standard_locations_after_path() {
+        let dirs = search_dirs(Some("/opt/bin:/usr/bin:relative".into()));
+        assert_eq!(dirs[0], PathBuf::from("/opt/bin"));
+        assert_eq!(dirs[1], PathBuf::from("/usr/bin"));
+        assert_eq!(dirs.len(), STANDARD_LOCATIONS.len() + 1);
+        assert!(dirs.contains(&PathBuf::from("/usr/sbin")));
+    }
+
+    #[tokio::test]
+    async fn resolved_tools_are_scoped_to_the_plan() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let tool = tool(temp_dir.path(), "nix-installer-test-tool");
+        let tools = BTreeMap::from([("nix-installer-test-tool".to_string(), tool.clone())]);
+
+        assert_eq!(find("nix-installer-test-tool"), None);
+        let found = with_resolved(tools, async {
+            tokio::task::yield_now().await;
+            find("nix-installer-test-tool")
+        })
+        .await;
+        assert_eq!(found, Some(tool));
+        assert_eq!(find("nix-installer-test-tool"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+/*! User level environment managers layering over the system hook
+
+home-manager exports its `home.sessionVariables` from `hm-session-vars.sh`, sourced from the user's
+own profiles, and direnv (which devenv loads through) changes the environment at each prompt. Both
+run after the hook in the system profiles, so whatever they export wins in shells, though not in the
+daemon, which only reads `nix.conf` and its own environment.
+*/
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Where home-manager puts `hm-session-vars.sh`, relative to the home directory
+pub(crate) const HM_SESSION_VARS: &[&str] = &[
+    ".nix-profile/etc/profile.d/hm-session-vars.sh",
+    ".local/state/nix/profile/etc/profile.d/hm-session-vars.sh",
+    ".local/state/home-manager/gcroots/current-home/home-path/etc/profile.d/hm-session-vars.sh",
+];
+
+/// The shell startup files a direnv hook is looked for in, relative to the home directory
+const STARTUP_FILES: &[&str] = &[
+    ".bashrc",
+    ".bash_profile",
+    ".profile",
+    ".zshrc",
+    ".zprofile",
+    ".config/fish/config.fish",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct UserEnvironment {
+    /// home-manager's session variables, if it manages this user's shells
+    pub(crate) home_manager: Option<SessionVariables>,
+    /// The shell startup files hooking direnv
+    pub(crate) direnv_hooks: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SessionVariables {
+    pub(crate) path: PathBuf,
+    /// The variables exported, with their values as written (unquoted, but not expanded)
+    pub(crate) exports: BTreeMap<String, String>,
+}
+
+impl UserEnvironment {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.home_manager.is_none() && self.direnv_hooks.is_empty()
+    }
+}
+
+/// The managers set up for `user`, whose home is `home`
+pub(crate) fn scan(home: &Path, user: &str) -> UserEnvironment {
+    // The NixOS and nix-darwin modules install into a profile outside of the home directory
+    let per_user = PathBuf::from(format!(
+        "/etc/profiles/per-user/{user}/etc/profile.d/hm-session-vars.sh"
+    ));
+    let home_manager = HM_SESSION_VARS
+        .iter()
+        .map(|relative| home.join(relative))
+        .chain([per_user])
+        .find_map(|path| {
+            let contents = std::fs::read_to_string(&path).ok()?;
+            Some(SessionVariables {
+                exports: parse_exports(&contents),
+                path,
+            })
+        });
+    let direnv_hooks = STARTUP_FILES
+        .iter()
+        .map(|relative| home.join(relative))
+        .filter(|path| {
+            std::fs::read_to_string(path)
+                .map(|contents| hooks_direnv(&contents))
+                .unwrap_or(false)
+        })
+        .collect();
+    UserEnvironment {
+        home_manager,
+        direnv_hooks,
+    }
+}
+
+/// The variables `script` exports with `export NAME=value`, the last value of each
+pub(crate) fn parse_exports(script: &str) -> BTreeMap<String, String> {
+    script
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.trim().strip_prefix("export ")?.split_once('=')?;
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return None;
+            }
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+                .unwrap_or(value);
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// `contents` (of a shell startup file) runs `direnv hook`, ignoring comments
+fn hooks_direnv(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        line.contains("direnv hook") || line.contains("direnv export")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/home-manager")
+            .join(name)
+    }
+
+    #[test]
+    fn parses_session_variables() -> eyre::Result<()> {
+        let exports = parse_exports(&std::fs::read_to_string(fixture("hm-session-vars.sh"))?);
+        assert_eq!(
+            exports.get("NIX_SSL_CERT_FILE").map(String::as_str),
+            Some("/etc/pki/tls/certs/ca-bundle.crt")
+        );
+        assert_eq!(
+            exports.get("NIX_PATH").map(String::as_str),
+            Some("nixpkgs=/home/alice/.nix-defexpr/channels/nixpkgs${NIX_PATH:+:$NIX_PATH}")
+        );
+        assert!(exports.contains_key("PATH"));
+        assert!(exports.contains_key("__HM_SESS_VARS_SOURCED"));
+
+        let exports = parse_exports(&std::fs::read_to_string(fixture(
+            "hm-session-vars-without-nix.sh",
+        ))?);
+        assert_eq!(exports.get("PAGER").map(String::as_str), Some("less -R"));
+        assert!(!exports.keys().any(|name| name.starts_with("NIX_")));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_home_manager_and_direnv() -> eyre::Result<()> {
+        let home = tempfile::tempdir()?;
+        assert!(scan(home.path(), "nobody-in-particular").is_empty());
+
+        let profile_d = home.path().join(".nix-profile/etc/profile.d");
+        std::fs::create_dir_all(&profile_d)?;
+        std::fs::copy(
+            fixture("hm-session-vars.sh"),
+            profile_d.join("hm-session-vars.sh"),
+        )?;
+        std::fs::copy(fixture("bashrc"), home.path().join(".bashrc"))?;
+        std::fs::write(
+            home.path().join(".zshrc"),
+            "# eval \"$(direnv hook zsh)\"\n",
+        )?;
+
+        let found = scan(home.path(), "nobody-in-particular");
+        let home_manager = found.home_manager.expect("The session variables are found");
+        assert_eq!(home_manager.path, profile_d.join("hm-session-vars.sh"));
+        assert!(home_manager.exports.contains_key("NIX_PATH"));
+        // The commented out hook doesn't count
+        assert_eq!(found.direnv_hooks, vec![home.path().join(".bashrc")]);
+        Ok(())
+    }
+}
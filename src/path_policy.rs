@@ -0,0 +1,313 @@
+/*! Which paths an install may modify, from `--allow-path` and `--deny-path`
+
+The actions creating or writing files, directories and service units [`check`] their paths against
+the policy of the plan (set for its calls with [`with_policy`]) both when planned, so a violation fails planning (and `--explain`) before
+anything is changed, and again right before writing, in case the filesystem changed in between.
+
+Paths are compared by where they really lead: the longest existing ancestor is canonicalized (and
+a dangling symlink followed to its target), so a symlink can neither lead a write out of an allowed
+directory nor around a denied one. A path under a denied one is refused even when it is also under
+an allowed one.
+*/
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::action::ActionErrorKind;
+
+/// How many symlinks [`resolve`] follows before giving up, like Linux's `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+tokio::task_local! {
+    /// The policy of the plan being planned or executed
+    static CURRENT: PathPolicy;
+}
+
+/// The paths an install may modify, everything unless [`allow`](Self::allow) is given
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Only paths under these may be modified, when there are any
+    pub allow: Vec<PathBuf>,
+    /// Paths under these may never be modified
+    pub deny: Vec<PathBuf>,
+}
+
+impl PathPolicy {
+    /// The policy of [`CommonSettings`](crate::settings::CommonSettings) as listed by a planner's settings
+    pub(crate) fn from_settings(settings: &HashMap<String, serde_json::Value>) -> Self {
+        let paths = |key: &str| {
+            settings
+                .get(key)
+                .and_then(|paths| serde_json::from_value::<Vec<PathBuf>>(paths.clone()).ok())
+                .unwrap_or_default()
+        };
+        Self {
+            allow: paths("allow_path"),
+            deny: paths("deny_path"),
+        }
+    }
+
+    /// Refuse `path` if it leads under a denied path, or outside of every allowed one
+    pub fn check(&self, path: &Path) -> Result<(), PathPolicyViolation> {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return Ok(());
+        }
+        let resolved =
+            resolve(path).ok_or_else(|| PathPolicyViolation::Unresolvable(path.to_path_buf()))?;
+        for rule in &self.deny {
+            let resolved_rule = resolve(rule).unwrap_or_else(|| rule.clone());
+            if path.starts_with(rule) || resolved.starts_with(&resolved_rule) {
+                return Err(PathPolicyViolation::Denied {
+                    path: path.to_path_buf(),
+                    resolved,
+                    rule: rule.clone(),
+                });
+            }
+        }
+        if self.allow.is_empty() {
+            return Ok(());
+        }
+        let allowed = self.allow.iter().any(|rule| {
+            let resolved_rule = resolve(rule).unwrap_or_else(|| rule.clone());
+            resolved.starts_with(resolved_rule)
+        });
+        if !allowed {
+            return Err(PathPolicyViolation::NotAllowed {
+                path: path.to_path_buf(),
+                resolved,
+                allowed: self.allow.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Run `fut` with `policy` as the one of the plan, see [`check`]
+pub(crate) async fn with_policy<F: Future>(policy: PathPolicy, fut: F) -> F::Output {
+    CURRENT.scope(policy, fut).await
+}
+
+/// Carry the policy of the plan into a future which is spawned as its own task
+pub(crate) fn propagate<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let current = CURRENT.try_with(Clone::clone).ok();
+    async move {
+        match current {
+            Some(current) => CURRENT.scope(current, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// Refuse `path` if the policy of the plan being planned or executed doesn't allow modifying it
+///
+/// Outside of [`with_policy`] there is no policy, and nothing is refused.
+pub(crate) fn check(path: &Path) -> Result<(), ActionErrorKind> {
+    CURRENT
+        .try_with(|policy| policy.check(path))
+        .unwrap_or(Ok(()))
+        .map_err(ActionErrorKind::from)
+}
+
+/// Where writing `path` would really write, `None` if it is relative, has `..` or loops
+fn resolve(path: &Path) -> Option<PathBuf> {
+    resolve_following(path, MAX_SYMLINKS)
+}
+
+fn resolve_following(path: &Path, symlinks: usize) -> Option<PathBuf> {
+    if !path.is_absolute()
+        || path
+            .components()
+            .any(|component| component == Component::ParentDir)
+    {
+        return None;
+    }
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Some(
+                missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |resolved, name| resolved.join(name)),
+            );
+        }
+        let parent = existing.parent()?;
+        // A dangling symlink, writing through it creates its target
+        if let Ok(target) = std::fs::read_link(existing) {
+            let target = missing
+                .iter()
+                .rev()
+                .fold(parent.join(target), |target, name| target.join(name));
+            return resolve_following(&normalize(&target)?, symlinks.checked_sub(1)?);
+        }
+        missing.push(existing.file_name()?);
+        existing = parent;
+    }
+}
+
+/// `path` with `..` applied lexically, for symlink targets like `../etc/sudoers.d`
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                // `..` of `/` is `/`
+                if normalized.parent().is_some() {
+                    normalized.pop();
+                }
+            },
+            Component::CurDir => (),
+            component => normalized.push(component),
+        }
+    }
+    normalized.is_absolute().then_some(normalized)
+}
+
+/// A path the `--allow-path` and `--deny-path` policy doesn't allow modifying
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum PathPolicyViolation {
+    #[error("Refusing to modify `{}`{}, it is under `{}` which `--deny-path` forbids", .path.display(), leads_to(.path, .resolved), .rule.display())]
+    Denied {
+        path: PathBuf,
+        resolved: PathBuf,
+        rule: PathBuf,
+    },
+    #[error("Refusing to modify `{}`{}, it is outside of every `--allow-path` ({})", .path.display(), leads_to(.path, .resolved), .allowed.iter().map(|allowed| format!("`{}`", allowed.display())).collect::<Vec<_>>().join(", "))]
+    NotAllowed {
+        path: PathBuf,
+        resolved: PathBuf,
+        allowed: Vec<PathBuf>,
+    },
+    #[error("Refusing to modify `{}`, it can't be checked against `--allow-path` and `--deny-path` as it is relative, has `..` or its symlinks loop", .0.display())]
+    Unresolvable(PathBuf),
+}
+
+fn leads_to(path: &Path, resolved: &Path) -> String {
+    if path == resolved {
+        String::new()
+    } else {
+        format!(" (which leads to `{}`)", resolved.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn policy(allow: &[&Path], deny: &[&Path]) -> PathPolicy {
+        PathPolicy {
+            allow: allow.iter().map(|path| path.to_path_buf()).collect(),
+            deny: deny.iter().map(|path| path.to_path_buf()).collect(),
+        }
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        assert!(PathPolicy::default()
+            .check(Path::new("/etc/sudoers.d/nix"))
+            .is_ok());
+    }
+
+    #[test]
+    fn denies_before_allowing() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path().canonicalize()?;
+        let etc = root.join("etc");
+        let sudoers_d = etc.join("sudoers.d");
+        std::fs::create_dir_all(&sudoers_d)?;
+        let policy = policy(&[&etc, &root.join("nix")], &[&sudoers_d]);
+
+        policy.check(&etc.join("nix/nix.conf"))?;
+        policy.check(&root.join("nix/store"))?;
+        assert!(matches!(
+            policy.check(&sudoers_d.join("nix")),
+            Err(PathPolicyViolation::Denied { .. })
+        ));
+        assert!(matches!(
+            policy.check(&root.join("home/alice/.bashrc")),
+            Err(PathPolicyViolation::NotAllowed { .. })
+        ));
+        // Only whole components match
+        assert!(policy.check(&root.join("etc2/profile")).is_err());
+        assert!(matches!(
+            policy.check(&etc.join("../home/alice/.bashrc")),
+            Err(PathPolicyViolation::Unresolvable(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn follows_symlinks_out_of_allowed_paths() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path().canonicalize()?;
+        let etc = root.join("etc");
+        let home = root.join("home/alice");
+        std::fs::create_dir_all(&etc)?;
+        std::fs::create_dir_all(&home)?;
+        // An allowed directory linking into a denied one
+        symlink(&home, etc.join("nix"))?;
+        // A dangling link, writing through it would create its target
+        symlink("../home/alice/.profile", etc.join("profile"))?;
+
+        let policy = policy(&[&etc], &[&root.join("home")]);
+        let err = policy
+            .check(&etc.join("nix/nix.conf"))
+            .expect_err("The symlink leads into a denied directory");
+        assert!(
+            err.to_string()
+                .contains(&format!("leads to `{}`", home.join("nix.conf").display())),
+            "{err}"
+        );
+        assert!(matches!(
+            policy.check(&etc.join("profile")),
+            Err(PathPolicyViolation::Denied { .. })
+        ));
+
+        // Out of an allowed directory without a deny rule
+        let policy = self::policy(&[&etc], &[]);
+        assert!(matches!(
+            policy.check(&etc.join("nix/nix.conf")),
+            Err(PathPolicyViolation::NotAllowed { .. })
+        ));
+
+        // Looping links can't be resolved
+        symlink(etc.join("loop"), etc.join("loop"))?;
+        assert!(matches!(
+            policy.check(&etc.join("loop")),
+            Err(PathPolicyViolation::Unresolvable(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reads_policy_from_settings() {
+        let settings = HashMap::from([
+            (
+                "allow_path".to_string(),
+                serde_json::json!(["/etc", "/nix"]),
+            ),
+            (
+                "deny_path".to_string(),
+                serde_json::json!(["/etc/sudoers.d"]),
+            ),
+        ]);
+        assert_eq!(
+            PathPolicy::from_settings(&settings),
+            policy(
+                &[Path::new("/etc"), Path::new("/nix")],
+                &[Path::new("/etc/sudoers.d")]
+            )
+        );
+        assert_eq!(
+            PathPolicy::from_settings(&HashMap::new()),
+            PathPolicy::default()
+        );
+    }
+}
@@ -1,15 +1,27 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::BTreeMap, future::Future, path::PathBuf, str::FromStr};
 
 use crate::{
-    action::{Action, ActionDescription, StatefulAction},
-    planner::{BuiltinPlanner, Planner},
+    action::{assign_ids, Action, ActionDescription, ActionState, ActionTiming, StatefulAction},
+    command_runner::CommandRecord,
+    leftovers::Leftover,
+    path_policy::PathPolicy,
+    planner::{check_action_order, BuiltinPlanner, Planner},
+    settings::UrlOrPath,
+    temp_artifacts::TempArtifacts,
+    timestamp::Timestamp,
     NixInstallerError,
 };
 use owo_colors::OwoColorize;
 use semver::{Version, VersionReq};
 use tokio::sync::broadcast::Receiver;
+use uuid::Uuid;
 
 pub const RECEIPT_LOCATION: &str = "/nix/receipt.json";
+/// Where a plan stopped for a reboot is recorded when `/nix` can't be created until after it (eg. on macOS)
+pub const REBOOT_CHECKPOINT_LOCATION: &str = "/etc/nix-installer-checkpoint.json";
+
+/// [`Action`] kinds which are still meaningful after the Nix store is removed
+const RETAINABLE_WITHOUT_STORE: &[&str] = &["provision_nix", "create_users_and_group"];
 
 /**
 A set of [`Action`]s, along with some metadata, which can be carried out to drive an install or
@@ -19,12 +31,72 @@ revert
 pub struct InstallPlan {
     pub(crate) version: Version,
 
+    /// What the binary which last planned or installed this was built from, `None` for plans predating it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) build: Option<crate::build_info::BuildInfo>,
+
+    /// Correlates the receipt with the progress events, audit log and diagnostics of the install, `None` for plans predating it
+    #[serde(default)]
+    pub(crate) install_id: Option<Uuid>,
+
+    /// When the plan was made, `None` for plans predating it
+    #[serde(default)]
+    pub(crate) planned_at: Option<Timestamp>,
+
+    /// When the install last finished, `None` until it did
+    #[serde(default)]
+    pub(crate) installed_at: Option<Timestamp>,
+
     pub(crate) actions: Vec<StatefulAction<Box<dyn Action>>>,
 
     pub(crate) planner: Box<dyn Planner>,
 
     #[cfg(feature = "diagnostics")]
     pub(crate) diagnostic_data: Option<crate::diagnostics::DiagnosticData>,
+
+    /// The host this plan was installed on, `None` for receipts predating fingerprints
+    #[serde(default)]
+    pub(crate) host_fingerprint: Option<HostFingerprint>,
+
+    /// The absolute paths of the external tools the plan runs, see [`Planner::resolve_tools`]
+    #[serde(default)]
+    pub(crate) tools: BTreeMap<String, PathBuf>,
+
+    /// The external commands run, see [`audit_log`](crate::command_runner::audit_log)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) commands: Vec<CommandRecord>,
+
+    /// Where the install stopped for a reboot, `None` unless it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reboot_checkpoint: Option<RebootCheckpoint>,
+
+    /// Leave temporary artifacts in place after installing, see [`set_keep_temp`][InstallPlan::set_keep_temp]
+    #[serde(skip)]
+    pub(crate) keep_temp: bool,
+
+    /// Record the receipt somewhere other than [`RECEIPT_LOCATION`], see [`set_receipt_location`][InstallPlan::set_receipt_location]
+    #[serde(skip)]
+    pub(crate) receipt_location: Option<PathBuf>,
+
+    /// What uninstalling left in place, see [`leftovers`][InstallPlan::leftovers]
+    #[serde(skip)]
+    pub(crate) leftovers: Vec<Leftover>,
+}
+
+/**
+Where an install stopped because an action needs the system rebooted (such as macOS creating `/nix`
+from `/etc/synthetic.conf`)
+
+Nothing is reverted. The plan is recorded (at [`REBOOT_CHECKPOINT_LOCATION`] if `/nix` can't be
+created yet) and [installing](InstallPlan::install) it again after the reboot continues with the
+step which needed it.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct RebootCheckpoint {
+    /// The id of the step which needs the reboot, `None` for plans predating ids
+    pub step: Option<String>,
+    pub reason: String,
+    pub recorded_at: Timestamp,
 }
 
 impl InstallPlan {
@@ -35,15 +107,34 @@ impl InstallPlan {
         let diagnostic_data = Some(planner.diagnostic_data().await?);
 
         let planner = planner.boxed();
-        let actions = planner.plan().await?;
+        let tools = planner.resolve_tools().await?;
+        let policy = PathPolicy::from_settings(&planner.settings()?);
+        let (actions, commands) =
+            within_plan(tools.clone(), policy, Vec::new(), planner.plan()).await;
+        let mut actions = actions?;
+        check_action_order(&actions)?;
+        assign_ids(&mut actions);
 
-        Ok(Self {
+        let mut plan = Self {
             planner,
             actions,
             version: current_version()?,
+            build: Some(crate::build_info::BuildInfo::current()),
+            install_id: None,
+            planned_at: Some(Timestamp::now()),
+            installed_at: None,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
-        })
+            host_fingerprint: None,
+            tools,
+            commands,
+            reboot_checkpoint: None,
+            keep_temp: false,
+            receipt_location: None,
+            leftovers: Vec::new(),
+        };
+        plan.ensure_install_id();
+        Ok(plan)
     }
 
     pub async fn plan<P>(planner: P) -> Result<Self, NixInstallerError>
@@ -56,14 +147,51 @@ impl InstallPlan {
         // Some Action `plan` calls may fail if we don't do these checks
         planner.pre_install_check().await?;
 
-        let actions = planner.plan().await?;
-        Ok(Self {
+        let tools = planner.resolve_tools().await?;
+        // Actions check their paths when planned, so a violation fails before anything changes
+        let policy = PathPolicy::from_settings(&planner.settings()?);
+        let (actions, commands) =
+            within_plan(tools.clone(), policy, Vec::new(), planner.plan()).await;
+        let mut actions = actions?;
+        check_action_order(&actions)?;
+        assign_ids(&mut actions);
+        let mut plan = Self {
             planner: planner.boxed(),
             actions,
             version: current_version()?,
+            build: Some(crate::build_info::BuildInfo::current()),
+            install_id: None,
+            planned_at: Some(Timestamp::now()),
+            installed_at: None,
             #[cfg(feature = "diagnostics")]
             diagnostic_data,
-        })
+            host_fingerprint: None,
+            tools,
+            commands,
+            reboot_checkpoint: None,
+            keep_temp: false,
+            receipt_location: None,
+            leftovers: Vec::new(),
+        };
+        plan.ensure_install_id();
+        Ok(plan)
+    }
+
+    /// The id correlating this install across its receipt, progress events, audit log and diagnostics
+    pub fn install_id(&self) -> Option<Uuid> {
+        self.install_id
+    }
+
+    /// Give the plan an install id if it has none (such as those predating them), and its diagnostics the same
+    fn ensure_install_id(&mut self) -> Uuid {
+        let install_id = *self
+            .install_id
+            .get_or_insert_with(|| uuid::Builder::from_random_bytes(rand::random()).into_uuid());
+        #[cfg(feature = "diagnostics")]
+        if let Some(diagnostic_data) = &mut self.diagnostic_data {
+            diagnostic_data.set_install_id(install_id);
+        }
+        install_id
     }
 
     pub async fn pre_uninstall_check(&self) -> Result<(), NixInstallerError> {
@@ -150,29 +278,80 @@ impl InstallPlan {
         Ok(buf)
     }
 
-    #[tracing::instrument(level = "debug", skip_all)]
+    #[tracing::instrument(level = "debug", skip_all, fields(install_id))]
     pub async fn install(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        let (tools, policy, commands) = (
+            self.tools.clone(),
+            self.path_policy()?,
+            self.commands.clone(),
+        );
+        let (result, commands) = within_plan(
+            tools,
+            policy,
+            commands,
+            self.install_with_tools(cancel_channel.into()),
+        )
+        .await;
+        self.commands = commands;
+        result
+    }
+
+    async fn install_with_tools(
+        &mut self,
+        cancel_channel: Option<Receiver<()>>,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
         self.planner.pre_install_check().await?;
+        // Plans and receipts predating ids have none
+        assign_ids(&mut self.actions);
+        let install_id = self.ensure_install_id();
+        tracing::Span::current().record("install_id", tracing::field::display(install_id));
+        if let Some(checkpoint) = self.reboot_checkpoint.take() {
+            tracing::info!(
+                %install_id,
+                "Continuing after the reboot needed at {}: {}",
+                checkpoint.recorded_at,
+                checkpoint.reason
+            );
+        }
+
+        self.host_fingerprint = Some(HostFingerprint::current().await);
+        // A plan may be installed by a different binary than the one which made it
+        self.build = Some(crate::build_info::BuildInfo::current());
 
+        #[cfg(feature = "telemetry")]
+        let (metrics, planner_name) = (
+            crate::telemetry::Metrics::global(),
+            self.planner.typetag_name(),
+        );
+        #[cfg(feature = "telemetry")]
+        metrics.install_started(planner_name);
+
+        // Removes whatever is left over on every exit path, including panics
+        let temp_artifacts = TempArtifacts::new(self.keep_temp);
         let Self { actions, .. } = self;
-        let mut cancel_channel = cancel_channel.into();
+        let mut cancel_channel = cancel_channel;
 
         // This is **deliberately sequential**.
         // Actions which are parallelizable are represented by "group actions" like CreateUsers
         // The plan itself represents the concept of the sequence of stages.
-        for action in actions {
+        for (step, action) in actions.iter_mut().enumerate() {
             if let Some(ref mut cancel_channel) = cancel_channel {
                 if cancel_channel.try_recv()
                     != Err(tokio::sync::broadcast::error::TryRecvError::Empty)
                 {
+                    self.clean_up_temp_artifacts(&temp_artifacts);
+                    self.host_fingerprint = Some(HostFingerprint::current().await);
                     if let Err(err) = write_receipt(self.clone()).await {
                         tracing::error!("Error saving receipt: {:?}", err);
                     }
 
+                    #[cfg(feature = "telemetry")]
+                    metrics.install_finished(planner_name, false);
+
                     #[cfg(feature = "diagnostics")]
                     if let Some(diagnostic_data) = &self.diagnostic_data {
                         diagnostic_data
@@ -188,11 +367,56 @@ impl InstallPlan {
                 }
             }
 
-            tracing::info!("Step: {}", action.tracing_synopsis());
-            if let Err(err) = action.try_execute().await {
+            tracing::info!(
+                %install_id,
+                id = action.id(),
+                "Step: {}",
+                action.tracing_synopsis()
+            );
+            #[cfg(feature = "telemetry")]
+            let started = std::time::Instant::now();
+            // Steps done by an earlier attempt keep the timing of when they ran
+            let runs = !matches!(action.state, ActionState::Completed | ActionState::Skipped);
+            let started_at = Timestamp::now();
+            let result = temp_artifacts
+                .scope(
+                    step,
+                    crate::cancellation::scope(cancel_channel.as_ref(), action.try_execute()),
+                )
+                .await;
+            if runs {
+                action.timing = Some(ActionTiming {
+                    started_at,
+                    finished_at: Timestamp::now(),
+                });
+            }
+            #[cfg(feature = "telemetry")]
+            metrics.action_finished(
+                action.action.typetag_name(),
+                started.elapsed(),
+                result.is_ok(),
+            );
+            if let Err(err) = result {
+                let step = action.id().map(ToString::to_string);
+                self.clean_up_temp_artifacts(&temp_artifacts);
+                self.host_fingerprint = Some(HostFingerprint::current().await);
+                // Stopped cleanly, to continue from the same step after the reboot
+                if let Some(reason) = err.needs_reboot() {
+                    let reason = reason.to_string();
+                    tracing::info!(%install_id, id = step, "Needs a reboot: {reason}");
+                    self.reboot_checkpoint = Some(RebootCheckpoint {
+                        step,
+                        reason: reason.clone(),
+                        recorded_at: Timestamp::now(),
+                    });
+                    let checkpoint = write_reboot_checkpoint(self.clone()).await?;
+                    return Err(NixInstallerError::NeedsReboot { reason, checkpoint });
+                }
                 if let Err(err) = write_receipt(self.clone()).await {
                     tracing::error!("Error saving receipt: {:?}", err);
                 }
+                #[cfg(feature = "telemetry")]
+                metrics.install_finished(planner_name, false);
                 let err = NixInstallerError::Action(err);
                 #[cfg(feature = "diagnostics")]
                 if let Some(diagnostic_data) = &self.diagnostic_data {
@@ -210,9 +434,28 @@ impl InstallPlan {
             }
         }
 
+        temp_artifacts.clean_up();
+        // `/nix` only now is on the filesystem (or volume) it will stay on
+        self.host_fingerprint = Some(HostFingerprint::current().await);
+        self.installed_at = Some(Timestamp::now());
         write_receipt(self.clone()).await?;
+        if self.receipt_location.is_none() {
+            match tokio::fs::remove_file(REBOOT_CHECKPOINT_LOCATION).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(NixInstallerError::RecordingReceipt(
+                        PathBuf::from(REBOOT_CHECKPOINT_LOCATION),
+                        e,
+                    ))
+                },
+            }
+        }
 
-        if let Err(err) = crate::self_test::self_test()
+        #[cfg(feature = "telemetry")]
+        metrics.install_finished(planner_name, true);
+
+        if let Err(err) = crate::self_test::self_test_plan(self)
             .await
             .map_err(NixInstallerError::SelfTest)
         {
@@ -245,6 +488,39 @@ impl InstallPlan {
         Ok(())
     }
 
+    /// Leave temporary artifacts (such as the unpacked Nix tarball) in place when the install stops, logging their locations
+    pub fn set_keep_temp(&mut self, keep_temp: bool) {
+        self.keep_temp = keep_temp;
+    }
+
+    /// Record the receipt at `location` instead of [`RECEIPT_LOCATION`], such as for plans installing somewhere other than the host
+    pub fn set_receipt_location(&mut self, location: impl Into<PathBuf>) {
+        self.receipt_location = Some(location.into());
+    }
+
+    /// Where the receipt is recorded
+    pub fn receipt_location(&self) -> PathBuf {
+        self.receipt_location
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(RECEIPT_LOCATION))
+    }
+
+    /// Where the install stopped for a reboot, if it did
+    pub fn reboot_checkpoint(&self) -> Option<&RebootCheckpoint> {
+        self.reboot_checkpoint.as_ref()
+    }
+
+    /// Remove temporary artifacts after stopping early, the steps which created them run again if the install is resumed
+    fn clean_up_temp_artifacts(&mut self, temp_artifacts: &TempArtifacts) {
+        for step in temp_artifacts.clean_up() {
+            if let Some(action) = self.actions.get_mut(step) {
+                if action.state == ActionState::Completed {
+                    action.state = ActionState::Progress;
+                }
+            }
+        }
+    }
+
     #[tracing::instrument(level = "debug", skip_all)]
     pub async fn describe_uninstall(&self, explain: bool) -> Result<String, NixInstallerError> {
         let Self {
@@ -321,16 +597,42 @@ impl InstallPlan {
         Ok(buf)
     }
 
-    #[tracing::instrument(level = "debug", skip_all)]
+    #[tracing::instrument(level = "debug", skip_all, fields(install_id))]
     pub async fn uninstall(
         &mut self,
         cancel_channel: impl Into<Option<Receiver<()>>>,
+    ) -> Result<(), NixInstallerError> {
+        // Actions don't check their paths on revert, a receipt whose settings can't be read is still uninstalled
+        let (tools, policy, commands) = (
+            self.tools.clone(),
+            self.path_policy().unwrap_or_default(),
+            self.commands.clone(),
+        );
+        let (result, commands) = within_plan(
+            tools,
+            policy,
+            commands,
+            self.uninstall_with_tools(cancel_channel.into()),
+        )
+        .await;
+        self.commands = commands;
+        result
+    }
+
+    async fn uninstall_with_tools(
+        &mut self,
+        cancel_channel: Option<Receiver<()>>,
     ) -> Result<(), NixInstallerError> {
         self.check_compatible()?;
         self.planner.pre_uninstall_check().await?;
+        assign_ids(&mut self.actions);
+        let install_id = self.ensure_install_id();
+        tracing::Span::current().record("install_id", tracing::field::display(install_id));
 
-        let Self { actions, .. } = self;
-        let mut cancel_channel = cancel_channel.into();
+        let Self {
+            actions, leftovers, ..
+        } = self;
+        let mut cancel_channel = cancel_channel;
         let mut errors = vec![];
 
         // This is **deliberately sequential**.
@@ -359,8 +661,19 @@ impl InstallPlan {
                 }
             }
 
-            tracing::info!("Revert: {}", action.tracing_synopsis());
-            if let Err(errs) = action.try_revert().await {
+            tracing::info!(
+                %install_id,
+                id = action.id(),
+                "Revert: {}",
+                action.tracing_synopsis()
+            );
+            let (reverted, left) = crate::leftovers::collect(crate::cancellation::scope(
+                cancel_channel.as_ref(),
+                action.try_revert(),
+            ))
+            .await;
+            leftovers.extend(left);
+            if let Err(errs) = reverted {
                 errors.push(errs);
             }
         }
@@ -396,6 +709,12 @@ impl InstallPlan {
         }
     }
 
+    /// What [uninstalling](InstallPlan::uninstall) left in place as it changed since the install,
+    /// such as a file replaced by a symlink, to be cleaned up by hand
+    pub fn leftovers(&self) -> &[Leftover] {
+        &self.leftovers
+    }
+
     pub fn check_compatible(&self) -> Result<(), NixInstallerError> {
         let self_version_string = self.version.to_string();
         let req = VersionReq::parse(&self_version_string)
@@ -410,13 +729,475 @@ impl InstallPlan {
             })
         }
     }
+
+    /// Ensure the plan was installed on this host, receipts copied between machines (eg. in golden images) could revert the wrong things
+    ///
+    /// Only warns when the host was renamed or its machine ID regenerated, see [`HostFingerprint::compare`].
+    pub async fn check_host(&self) -> Result<(), NixInstallerError> {
+        let Some(recorded) = &self.host_fingerprint else {
+            return Ok(());
+        };
+        let current = HostFingerprint::current().await;
+        match recorded.compare(&current) {
+            HostComparison::Same => Ok(()),
+            HostComparison::Changed(changes) => {
+                tracing::warn!(
+                    "This host changed since the receipt was recorded ({}), continuing as only a changed `/nix` filesystem is refused",
+                    changes.join(", ")
+                );
+                Ok(())
+            },
+            HostComparison::Different => Err(NixInstallerError::HostMismatch {
+                recorded: Box::new(recorded.clone()),
+                current: Box::new(current),
+            }),
+        }
+    }
+
+    /// The paths this plan may modify, from the `--allow-path` and `--deny-path` it was made with
+    pub(crate) fn path_policy(&self) -> Result<PathPolicy, NixInstallerError> {
+        Ok(PathPolicy::from_settings(&self.planner.settings()?))
+    }
+
+    /// The directory passed with `--nix-conf-dir` when this plan was made, `None` when it is `/etc/nix`
+    pub fn nix_conf_dir(&self) -> Option<PathBuf> {
+        self.planner
+            .settings()
+            .ok()?
+            .get("nix_conf_dir")?
+            .as_str()
+            .map(PathBuf::from)
+    }
+
+    /// If the Nix package is read from stdin (`--nix-package-url -`), leaving nothing to answer prompts with
+    pub fn reads_stdin(&self) -> bool {
+        let nix_package_url = self
+            .planner
+            .settings()
+            .ok()
+            .and_then(|settings| settings.get("nix_package_url").cloned());
+        nix_package_url.is_some() && nix_package_url == serde_json::to_value(UrlOrPath::Stdin).ok()
+    }
+
+    /// The kinds of [`Action`]s in this plan which can be passed to [`retain`][InstallPlan::retain]
+    pub fn retainable_kinds(&self) -> Vec<&'static str> {
+        let mut kinds = self
+            .actions
+            .iter()
+            .flat_map(|action| action.retainable_kinds())
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds.dedup();
+        kinds
+    }
+
+    /// Skip reverting any [`Action`]s of the given kinds during [`uninstall`][InstallPlan::uninstall], returning the synopsis of each retained action
+    pub fn retain(&mut self, kinds: &[String]) -> Result<Vec<String>, NixInstallerError> {
+        let available = self.retainable_kinds();
+        for kind in kinds {
+            if !available.contains(&kind.as_str()) {
+                return Err(NixInstallerError::UnknownActionKind {
+                    kind: kind.clone(),
+                    available: available.iter().map(ToString::to_string).collect(),
+                });
+            }
+        }
+
+        let mut retained = Vec::new();
+        for kind in kinds {
+            for action in self.actions.iter_mut() {
+                retained.extend(action.retain(kind));
+            }
+        }
+
+        // Everything else depends on the store, keeping it around without one leaves a broken integration
+        let store_removed = self.actions.iter().any(|action| {
+            action.inner_typetag_name() == "provision_nix" && action.state != ActionState::Skipped
+        });
+        if store_removed {
+            for kind in kinds {
+                if !RETAINABLE_WITHOUT_STORE.contains(&kind.as_str()) {
+                    tracing::warn!(
+                        "Keeping `{kind}` while the Nix store is removed, it will reference paths which no longer exist"
+                    );
+                }
+            }
+        }
+
+        Ok(retained)
+    }
+
+    /**
+    Revert only the action with `id` (see [`StatefulAction::id`]), returning its synopsis
+
+    Refused while other executed actions depend on it. The action is kept in the plan, marked
+    [reverted](StatefulAction::reverted_at) and [skipped](ActionState::Skipped) so neither
+    [`install`][InstallPlan::install] nor [`uninstall`][InstallPlan::uninstall] touch it again.
+    */
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn revert_action(&mut self, id: &str) -> Result<String, NixInstallerError> {
+        self.check_compatible()?;
+        let index = self.revertible(id)?;
+
+        let (tools, policy, commands) = (
+            self.tools.clone(),
+            self.path_policy().unwrap_or_default(),
+            self.commands.clone(),
+        );
+        let action = &mut self.actions[index];
+        tracing::info!(id, "Revert: {}", action.tracing_synopsis());
+        let (result, commands) = within_plan(tools, policy, commands, action.try_revert()).await;
+        result.map_err(|err| NixInstallerError::ActionRevert(vec![err]))?;
+        action.state = ActionState::Skipped;
+        action.reverted_at = Some(Timestamp::now());
+        let synopsis = action.tracing_synopsis();
+        self.commands = commands;
+        Ok(synopsis)
+    }
+
+    /// What [`revert_action`][InstallPlan::revert_action] would do with `id`, refusing as it would
+    pub fn describe_revert_action(
+        &mut self,
+        id: &str,
+        explain: bool,
+    ) -> Result<String, NixInstallerError> {
+        let index = self.revertible(id)?;
+        let actions = self.actions[index]
+            .describe_revert()
+            .into_iter()
+            .map(
+                |ActionDescription {
+                     description,
+                     explanation,
+                 }| {
+                    let mut buf = format!("* {description}");
+                    if explain {
+                        for line in explanation {
+                            buf.push_str(&format!("\n  {line}"));
+                        }
+                    }
+                    buf
+                },
+            )
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(format!(
+            "\
+            Revert `{id}` only, keeping the rest of the install:\n\
+            {actions}\n\
+            "
+        ))
+    }
+
+    /// The index of the action with `id`, if it can be reverted on its own
+    fn revertible(&mut self, id: &str) -> Result<usize, NixInstallerError> {
+        assign_ids(&mut self.actions);
+        let Some(index) = self
+            .actions
+            .iter()
+            .position(|action| action.id() == Some(id))
+        else {
+            return Err(NixInstallerError::UnknownActionId {
+                id: id.to_string(),
+                available: self
+                    .actions
+                    .iter()
+                    .filter_map(|action| action.id().map(ToString::to_string))
+                    .collect(),
+            });
+        };
+        if !matches!(
+            self.actions[index].state,
+            ActionState::Completed | ActionState::Progress
+        ) {
+            return Err(NixInstallerError::ActionNotExecuted(id.to_string()));
+        }
+        let dependents = self.dependents(index);
+        if !dependents.is_empty() {
+            return Err(NixInstallerError::ActionDependents {
+                id: id.to_string(),
+                dependents,
+            });
+        }
+        Ok(index)
+    }
+
+    /// The ids of the executed actions after the one at `index` which [depend on](Action::depends_on) it
+    ///
+    /// Everything but the [`RETAINABLE_WITHOUT_STORE`] kinds depends on the store.
+    fn dependents(&self, index: usize) -> Vec<String> {
+        let kinds = self.actions[index].retainable_kinds();
+        let provides_store = kinds.contains(&"provision_nix");
+        self.actions
+            .iter()
+            .skip(index + 1)
+            .filter(|action| matches!(action.state, ActionState::Completed | ActionState::Progress))
+            .filter(|action| {
+                action
+                    .depends_on()
+                    .iter()
+                    .any(|dependency| kinds.contains(dependency))
+                    || (provides_store
+                        && !RETAINABLE_WITHOUT_STORE.contains(&action.inner_typetag_name()))
+            })
+            .map(|action| {
+                action
+                    .id()
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| action.tracing_synopsis())
+            })
+            .collect()
+    }
+
+    /// Stamp the fingerprint of this host into the plan, see [`HostFingerprint::Unclaimed`]
+    pub async fn claim(&mut self) {
+        self.host_fingerprint = Some(HostFingerprint::current().await);
+    }
+}
+
+/// Identifies the host an [`InstallPlan`] was installed on
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HostFingerprint {
+    /// Installed into an image which has not booted yet, first boot tooling should run `nix-installer claim-receipt`
+    Unclaimed,
+    Host {
+        /// `/etc/machine-id` on Linux, `IOPlatformUUID` on MacOS
+        machine_id: Option<String>,
+        hostname: Option<String>,
+        /// The UUID of the filesystem holding `/nix`, the APFS volume on MacOS
+        #[serde(default)]
+        filesystem_id: Option<String>,
+    },
+}
+
+/// How the host an [`InstallPlan`] was installed on compares to this one, see [`HostFingerprint::compare`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostComparison {
+    Same,
+    /// The filesystem holding `/nix` is the one installed onto (or can't be identified), but
+    /// identifiers which legitimately change (on cloned VMs and cloud images) did, described in each entry
+    Changed(Vec<String>),
+    Different,
+}
+
+impl HostFingerprint {
+    pub async fn current() -> Self {
+        Self::Host {
+            machine_id: machine_id().await,
+            hostname: hostname(),
+            filesystem_id: filesystem_id().await,
+        }
+    }
+
+    /// Compare the recorded fingerprint (`self`) to the `current` one
+    ///
+    /// Only a changed filesystem ID makes it a different host, a changed machine ID or hostname
+    /// only changes the host. When either side has no filesystem ID (such as on overlays, tmpfs or
+    /// btrfs subvolumes) there is nothing to tell a different host by, so changes are only noted.
+    pub fn compare(&self, current: &Self) -> HostComparison {
+        if self == current {
+            return HostComparison::Same;
+        }
+        let (
+            Self::Host {
+                machine_id,
+                hostname,
+                filesystem_id,
+            },
+            Self::Host {
+                machine_id: current_machine_id,
+                hostname: current_hostname,
+                filesystem_id: current_filesystem_id,
+            },
+        ) = (self, current)
+        else {
+            return HostComparison::Different;
+        };
+
+        let mut changes = [
+            ("machine ID", machine_id, current_machine_id),
+            ("hostname", hostname, current_hostname),
+        ]
+        .into_iter()
+        .filter_map(|(name, recorded, current)| match (recorded, current) {
+            (Some(recorded), Some(current)) if recorded != current => Some(format!(
+                "the {name} changed from `{recorded}` to `{current}`"
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+        match (filesystem_id, current_filesystem_id) {
+            (Some(filesystem_id), Some(current_filesystem_id))
+                if filesystem_id != current_filesystem_id =>
+            {
+                return HostComparison::Different;
+            },
+            (Some(_), Some(_)) => (),
+            _ if changes.is_empty() => (),
+            _ => changes.push(
+                "the `/nix` filesystem could not be identified to confirm it is the same one"
+                    .to_string(),
+            ),
+        }
+        if changes.is_empty() {
+            HostComparison::Same
+        } else {
+            HostComparison::Changed(changes)
+        }
+    }
+
+    /// Whether `other` is the host this was recorded on, see [`compare`](HostFingerprint::compare)
+    pub fn matches(&self, other: &Self) -> bool {
+        self.compare(other) != HostComparison::Different
+    }
+}
+
+impl std::fmt::Display for HostFingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unclaimed => write!(f, "unclaimed image install"),
+            Self::Host {
+                machine_id,
+                hostname,
+                filesystem_id,
+            } => write!(
+                f,
+                "machine ID `{}`, hostname `{}`, `/nix` filesystem `{}`",
+                machine_id.as_deref().unwrap_or("unknown"),
+                hostname.as_deref().unwrap_or("unknown"),
+                filesystem_id.as_deref().unwrap_or("unknown")
+            ),
+        }
+    }
 }
 
-async fn write_receipt(plan: InstallPlan) -> Result<(), NixInstallerError> {
-    tokio::fs::create_dir_all("/nix")
+async fn machine_id() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        let output = crate::command_runner::output(
+            crate::os::tools::command("ioreg")
+                .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+                .stdin(std::process::Stdio::null()),
+        )
         .await
-        .map_err(|e| NixInstallerError::RecordingReceipt(PathBuf::from("/nix"), e))?;
-    let install_receipt_path = PathBuf::from(RECEIPT_LOCATION);
+        .ok()?;
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        stdout
+            .lines()
+            .find(|line| line.contains("\"IOPlatformUUID\""))
+            .and_then(|line| line.split('"').nth(3))
+            .map(ToString::to_string)
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        for path in ["/etc/machine-id", "/var/lib/dbus/machine-id"] {
+            if let Ok(machine_id) = tokio::fs::read_to_string(path).await {
+                let machine_id = machine_id.trim();
+                if !machine_id.is_empty() {
+                    return Some(machine_id.to_string());
+                }
+            }
+        }
+        None
+    }
+}
+
+async fn filesystem_id() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        let info = crate::os::darwin::diskutil_info("/nix").await.ok()?;
+        info.volume_uuid
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        filesystem_uuid(
+            std::path::Path::new("/nix"),
+            std::path::Path::new("/dev/disk/by-uuid"),
+        )
+    }
+}
+
+/// The UUID of the block device holding `path`, from the `by_uuid` links udev maintains
+///
+/// Filesystems without one of their own (such as overlays, tmpfs or btrfs subvolumes) have none.
+#[cfg(not(target_os = "macos"))]
+fn filesystem_uuid(path: &std::path::Path, by_uuid: &std::path::Path) -> Option<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let dev = path.metadata().ok()?.dev();
+    std::fs::read_dir(by_uuid)
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            entry
+                .path()
+                .metadata()
+                .map(|device| device.file_type().is_block_device() && device.rdev() == dev)
+                .unwrap_or(false)
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+}
+
+fn hostname() -> Option<String> {
+    let hostname = nix::unistd::gethostname().ok()?;
+    let hostname = hostname.to_str()?.trim();
+    (!hostname.is_empty()).then(|| hostname.to_string())
+}
+
+/// Run `fut` as one of a plan's calls, with its resolved `tools` and path `policy`, recording the
+/// commands it runs after the plan's `commands`
+pub(crate) async fn within_plan<F: Future>(
+    tools: BTreeMap<String, PathBuf>,
+    policy: PathPolicy,
+    commands: Vec<CommandRecord>,
+    fut: F,
+) -> (F::Output, Vec<CommandRecord>) {
+    crate::command_runner::recording(
+        commands,
+        crate::os::tools::with_resolved(tools, crate::path_policy::with_policy(policy, fut)),
+    )
+    .await
+}
+
+pub(crate) async fn write_receipt(plan: InstallPlan) -> Result<(), NixInstallerError> {
+    let location = plan.receipt_location();
+    write_receipt_to(plan, location).await
+}
+
+/// Record a plan stopped for a reboot, at [`REBOOT_CHECKPOINT_LOCATION`] if `/nix` can't be created until after it
+async fn write_reboot_checkpoint(plan: InstallPlan) -> Result<PathBuf, NixInstallerError> {
+    let location = plan.receipt_location();
+    match write_receipt(plan.clone()).await {
+        Ok(()) => Ok(location),
+        Err(err) if plan.receipt_location.is_none() => {
+            tracing::debug!(
+                ?err,
+                "Could not record the receipt at `{}`, recording it at `{REBOOT_CHECKPOINT_LOCATION}`",
+                location.display()
+            );
+            let location = PathBuf::from(REBOOT_CHECKPOINT_LOCATION);
+            write_receipt_to(plan, location.clone()).await?;
+            Ok(location)
+        },
+        Err(err) => Err(err),
+    }
+}
+
+async fn write_receipt_to(
+    mut plan: InstallPlan,
+    install_receipt_path: PathBuf,
+) -> Result<(), NixInstallerError> {
+    // Actions added after planning (such as by `convert`) need ids too
+    assign_ids(&mut plan.actions);
+    plan.ensure_install_id();
+    plan.commands = crate::command_runner::audit_log();
+    if let Some(parent) = install_receipt_path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| NixInstallerError::RecordingReceipt(parent.to_owned(), e))?;
+    }
     let self_json =
         serde_json::to_string_pretty(&plan).map_err(NixInstallerError::SerializingReceipt)?;
     tokio::fs::write(&install_receipt_path, format!("{self_json}\n"))
@@ -436,7 +1217,72 @@ pub fn current_version() -> Result<Version, NixInstallerError> {
 mod test {
     use semver::Version;
 
-    use crate::{planner::BuiltinPlanner, InstallPlan, NixInstallerError};
+    use super::{HostComparison, HostFingerprint};
+    use crate::{
+        action::{
+            assign_ids,
+            base::{CreateDirectory, CreateFile, SetupDefaultProfile, VerifyNixStore},
+            Action, ActionDescription, ActionError, ActionErrorKind, ActionState, ActionTag,
+            StatefulAction,
+        },
+        planner::{check_action_order, BuiltinPlanner, PlannerError},
+        InstallPlan, NixInstallerError,
+    };
+    #[cfg(target_os = "linux")]
+    use crate::{
+        planner::{linux::Linux, Planner},
+        settings::{CommonSettings, InitSystem},
+    };
+
+    /// Linux actions as planned with `settings`, without an init system the sandbox may lack
+    #[cfg(target_os = "linux")]
+    async fn planned(
+        settings: impl FnOnce(&mut CommonSettings),
+    ) -> eyre::Result<Vec<StatefulAction<Box<dyn Action>>>> {
+        let mut planner = Linux::default().await?;
+        planner.init.init = InitSystem::None;
+        planner.init.start_daemon = false;
+        settings(&mut planner.settings);
+        let mut actions = planner.plan().await?;
+        assign_ids(&mut actions);
+        Ok(actions)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn plans_are_deterministic() -> eyre::Result<()> {
+        let first = serde_json::to_string(&planned(|_| ()).await?)?;
+        let second = serde_json::to_string(&planned(|_| ()).await?)?;
+        assert_eq!(first, second);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn action_ids_are_stable() -> eyre::Result<()> {
+        let ids = |actions: &[StatefulAction<Box<dyn Action>>]| {
+            actions
+                .iter()
+                .map(|action| action.id().map(ToString::to_string))
+                .collect::<Vec<_>>()
+        };
+        let default = planned(|_| ()).await?;
+        // Only fields of the planned actions change, not what they act on
+        let changed = planned(|settings| {
+            settings.nix_build_user_count += 4;
+            settings.nix_build_user_id_base += 100;
+        })
+        .await?;
+
+        let default_ids = ids(&default);
+        assert_eq!(default_ids, ids(&changed));
+        assert!(default_ids.iter().all(Option::is_some));
+        let unique = default_ids
+            .iter()
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(unique.len(), default_ids.len());
+        Ok(())
+    }
 
     #[tokio::test]
     async fn ensure_version_allows_compatible() -> Result<(), NixInstallerError> {
@@ -452,6 +1298,545 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn uninstall_retains_kept_kinds() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let kept_file = temp_dir.path().join("kept_file");
+        let removed_dir = temp_dir.path().join("removed_dir");
+        let mut create_file =
+            CreateFile::plan(&kept_file, None, None, None, "Test".into(), false).await?;
+        create_file.try_execute().await?;
+        let mut create_directory =
+            CreateDirectory::plan(&removed_dir, None, None, None, false).await?;
+        create_directory.try_execute().await?;
+
+        let planner = BuiltinPlanner::default().await?;
+        let value = serde_json::json!({
+            "planner": planner.boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "actions": [create_directory.boxed(), create_file.boxed()],
+        });
+        let mut plan: InstallPlan = serde_json::from_value(value)?;
+
+        assert!(plan.retain(&["not_an_action".to_string()]).is_err());
+        let retained = plan.retain(&["create_file".to_string()])?;
+        assert_eq!(retained.len(), 1);
+        plan.uninstall(None).await?;
+
+        assert!(kept_file.exists(), "Kept file should still exist");
+        assert!(!removed_dir.exists(), "Directory should have been removed");
+        Ok(())
+    }
+
+    /// Plans nothing and checks nothing, for plans made of the actions of a test
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct NoChecks;
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "no-checks")]
+    impl crate::planner::Planner for NoChecks {
+        async fn default() -> Result<Self, PlannerError> {
+            Ok(Self)
+        }
+
+        async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+            Ok(vec![])
+        }
+
+        fn settings(
+            &self,
+        ) -> Result<
+            std::collections::HashMap<String, serde_json::Value>,
+            crate::settings::InstallSettingsError,
+        > {
+            Ok(Default::default())
+        }
+
+        async fn configured_settings(
+            &self,
+        ) -> Result<std::collections::HashMap<String, serde_json::Value>, PlannerError> {
+            Ok(Default::default())
+        }
+
+        #[cfg(feature = "diagnostics")]
+        async fn diagnostic_data(
+            &self,
+        ) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+            Ok(crate::diagnostics::DiagnosticData::new(
+                None,
+                None,
+                "no-checks".into(),
+                vec![],
+                None,
+            )?)
+        }
+    }
+
+    /// Plans creating `files`, never under `deny_path`
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct DenyingPaths {
+        files: Vec<std::path::PathBuf>,
+        deny_path: Vec<std::path::PathBuf>,
+    }
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "denying-paths")]
+    impl crate::planner::Planner for DenyingPaths {
+        async fn default() -> Result<Self, PlannerError> {
+            Ok(Self {
+                files: vec![],
+                deny_path: vec![],
+            })
+        }
+
+        async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+            let mut actions = vec![];
+            for file in &self.files {
+                actions.push(
+                    CreateFile::plan(file, None, None, None, "Test".into(), false)
+                        .await
+                        .map_err(PlannerError::Action)?
+                        .boxed(),
+                );
+            }
+            Ok(actions)
+        }
+
+        fn settings(
+            &self,
+        ) -> Result<
+            std::collections::HashMap<String, serde_json::Value>,
+            crate::settings::InstallSettingsError,
+        > {
+            Ok([(
+                "deny_path".to_string(),
+                serde_json::to_value(&self.deny_path)?,
+            )]
+            .into())
+        }
+
+        async fn configured_settings(
+            &self,
+        ) -> Result<std::collections::HashMap<String, serde_json::Value>, PlannerError> {
+            Ok(Default::default())
+        }
+
+        #[cfg(feature = "diagnostics")]
+        async fn diagnostic_data(
+            &self,
+        ) -> Result<crate::diagnostics::DiagnosticData, PlannerError> {
+            Ok(crate::diagnostics::DiagnosticData::new(
+                None,
+                None,
+                "denying-paths".into(),
+                vec![],
+                None,
+            )?)
+        }
+    }
+
+    #[tokio::test]
+    async fn path_policies_stay_with_their_plans() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let (one, two) = (temp_dir.path().join("one"), temp_dir.path().join("two"));
+        tokio::fs::create_dir_all(&one).await?;
+        tokio::fs::create_dir_all(&two).await?;
+        let planner = |file: &std::path::Path, denied: &std::path::Path| DenyingPaths {
+            files: vec![file.to_path_buf()],
+            deny_path: vec![denied.to_path_buf()],
+        };
+
+        let mut first = InstallPlan::plan(planner(&one.join("file"), &two)).await?;
+        let mut second = InstallPlan::plan(planner(&two.join("file"), &one)).await?;
+        assert!(matches!(
+            InstallPlan::plan(planner(&one.join("denied"), &one)).await,
+            Err(NixInstallerError::Planner(_))
+        ));
+        // Nothing is left over from the plans for what runs outside of them
+        CreateFile::plan(one.join("outside"), None, None, None, "Test".into(), false).await?;
+
+        first.set_receipt_location(temp_dir.path().join("first.json"));
+        second.set_receipt_location(temp_dir.path().join("second.json"));
+        let (first_installed, second_installed) =
+            tokio::join!(first.install(None), second.install(None));
+        first_installed?;
+        second_installed?;
+        assert!(one.join("file").exists());
+        assert!(two.join("file").exists());
+        Ok(())
+    }
+
+    /// Runs `echo` with `word`
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct Echoes {
+        word: String,
+    }
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "echoes")]
+    impl Action for Echoes {
+        fn action_tag() -> ActionTag {
+            "echoes".into()
+        }
+        fn tracing_synopsis(&self) -> String {
+            format!("Echo `{}`", self.word)
+        }
+        fn tracing_span(&self) -> tracing::Span {
+            tracing::span!(tracing::Level::DEBUG, "echoes")
+        }
+        fn execute_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn execute(&mut self) -> Result<(), ActionError> {
+            crate::execute_command(tokio::process::Command::new("echo").arg(&self.word))
+                .await
+                .map_err(Self::error)?;
+            Ok(())
+        }
+        fn revert_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn revert(&mut self) -> Result<(), ActionError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn receipts_record_only_their_own_commands() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let plan = |word: &str| -> eyre::Result<InstallPlan> {
+            let mut plan: InstallPlan = serde_json::from_value(serde_json::json!({
+                "planner": NoChecks.boxed(),
+                "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+                "actions": [StatefulAction::uncompleted(Echoes { word: word.into() }).boxed()],
+            }))?;
+            plan.set_receipt_location(temp_dir.path().join(format!("{word}.json")));
+            Ok(plan)
+        };
+        let (mut first, mut second) = (plan("first")?, plan("second")?);
+
+        // Outside of any plan
+        crate::command_runner::output(tokio::process::Command::new("echo").arg("probe")).await?;
+        let (first_installed, second_installed) =
+            tokio::join!(first.install(None), second.install(None));
+        first_installed?;
+        second_installed?;
+        first.install(None).await?;
+
+        for word in ["first", "second"] {
+            let receipt: InstallPlan = serde_json::from_str(
+                &tokio::fs::read_to_string(temp_dir.path().join(format!("{word}.json"))).await?,
+            )?;
+            // Besides those of the self test the install ends with
+            assert_eq!(
+                receipt
+                    .commands
+                    .iter()
+                    .filter(|record| record.program() == "echo")
+                    .map(|record| record.argv.clone())
+                    .collect::<Vec<_>>(),
+                [vec!["echo".to_string(), word.to_string()]],
+                "{word}"
+            );
+        }
+        Ok(())
+    }
+
+    /// Needs a reboot until `rebooted` exists, as macOS creating `/nix` from `/etc/synthetic.conf` may
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct NeedsRebootUntil {
+        rebooted: std::path::PathBuf,
+    }
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "needs_reboot_until")]
+    impl Action for NeedsRebootUntil {
+        fn action_tag() -> ActionTag {
+            "needs_reboot_until".into()
+        }
+        fn tracing_synopsis(&self) -> String {
+            "Needs a reboot".to_string()
+        }
+        fn tracing_span(&self) -> tracing::Span {
+            tracing::span!(tracing::Level::DEBUG, "needs_reboot_until")
+        }
+        fn execute_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn execute(&mut self) -> Result<(), ActionError> {
+            if !self.rebooted.exists() {
+                return Err(Self::error(ActionErrorKind::NeedsReboot(
+                    "Not rebooted yet".into(),
+                )));
+            }
+            Ok(())
+        }
+        fn revert_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn revert(&mut self) -> Result<(), ActionError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_for_reboot_and_continues_after() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let before = temp_dir.path().join("before");
+        let after = temp_dir.path().join("after");
+        let rebooted = temp_dir.path().join("rebooted");
+        let receipt = temp_dir.path().join("receipt.json");
+        let value = serde_json::json!({
+            "planner": NoChecks.boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "actions": [
+                CreateFile::plan(&before, None, None, None, "Test".into(), false).await?.boxed(),
+                StatefulAction::uncompleted(NeedsRebootUntil { rebooted: rebooted.clone() }).boxed(),
+                CreateFile::plan(&after, None, None, None, "Test".into(), false).await?.boxed(),
+            ],
+        });
+        let mut plan: InstallPlan = serde_json::from_value(value)?;
+        plan.set_receipt_location(&receipt);
+
+        match plan.install(None).await {
+            Err(NixInstallerError::NeedsReboot { checkpoint, .. }) => {
+                assert_eq!(checkpoint, receipt)
+            },
+            other => eyre::bail!("Should have stopped for a reboot, got {other:?}"),
+        }
+        assert!(before.exists(), "Steps before the reboot are not reverted");
+        assert!(!after.exists(), "Steps after the reboot wait for it");
+
+        let mut resumed: InstallPlan =
+            serde_json::from_str(&tokio::fs::read_to_string(&receipt).await?)?;
+        let checkpoint = resumed
+            .reboot_checkpoint()
+            .cloned()
+            .expect("The checkpoint should be recorded");
+        assert_eq!(checkpoint.step.as_deref(), resumed.actions[1].id());
+        assert_eq!(resumed.actions[0].state, ActionState::Completed);
+        assert_eq!(resumed.actions[1].state, ActionState::Progress);
+
+        tokio::fs::write(&rebooted, "").await?;
+        resumed.set_receipt_location(&receipt);
+        resumed.install(None).await?;
+
+        assert!(
+            after.exists(),
+            "Steps after the reboot run once it happened"
+        );
+        let finished: InstallPlan =
+            serde_json::from_str(&tokio::fs::read_to_string(&receipt).await?)?;
+        assert_eq!(finished.reboot_checkpoint(), None);
+        assert!(finished
+            .actions
+            .iter()
+            .all(|action| action.state == ActionState::Completed));
+        Ok(())
+    }
+
+    /// Needs a file created by an earlier action
+    #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+    struct NeedsCreatedFile;
+
+    #[async_trait::async_trait]
+    #[typetag::serde(name = "needs_created_file")]
+    impl Action for NeedsCreatedFile {
+        fn action_tag() -> ActionTag {
+            "needs_created_file".into()
+        }
+        fn tracing_synopsis(&self) -> String {
+            "Needs a created file".to_string()
+        }
+        fn tracing_span(&self) -> tracing::Span {
+            tracing::span!(tracing::Level::DEBUG, "needs_created_file")
+        }
+        fn execute_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn execute(&mut self) -> Result<(), ActionError> {
+            Ok(())
+        }
+        fn revert_description(&self) -> Vec<ActionDescription> {
+            vec![]
+        }
+        async fn revert(&mut self) -> Result<(), ActionError> {
+            Ok(())
+        }
+        fn depends_on(&self) -> Vec<&'static str> {
+            vec!["create_file"]
+        }
+    }
+
+    #[tokio::test]
+    async fn reverts_single_actions_unless_depended_on() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let file = temp_dir.path().join("file");
+        let directory = temp_dir.path().join("directory");
+        let receipt = temp_dir.path().join("receipt.json");
+        let value = serde_json::json!({
+            "planner": NoChecks.boxed(),
+            "version": Version::parse(env!("CARGO_PKG_VERSION"))?,
+            "actions": [
+                CreateFile::plan(&file, None, None, None, "Test".into(), false).await?.boxed(),
+                StatefulAction::uncompleted(NeedsCreatedFile).boxed(),
+                CreateDirectory::plan(&directory, None, None, 0o755, false).await?.boxed(),
+            ],
+        });
+        let mut plan: InstallPlan = serde_json::from_value(value)?;
+        plan.set_receipt_location(&receipt);
+        plan.install(None).await?;
+        let mut plan: InstallPlan =
+            serde_json::from_str(&tokio::fs::read_to_string(&receipt).await?)?;
+        let file_id = plan.actions[0].id().expect("Ids are assigned").to_string();
+        let directory_id = plan.actions[2].id().expect("Ids are assigned").to_string();
+
+        match plan.revert_action(&file_id).await {
+            Err(NixInstallerError::ActionDependents { id, dependents }) => {
+                assert_eq!(id, file_id);
+                assert_eq!(dependents, ["needs_created_file"]);
+            },
+            other => eyre::bail!("Should have been refused, got {other:?}"),
+        }
+        assert!(file.exists());
+        assert_eq!(plan.actions[0].state, ActionState::Completed);
+
+        plan.revert_action(&directory_id).await?;
+        assert!(!directory.exists());
+        assert!(file.exists(), "Only the one action is reverted");
+
+        // Kept in the receipt, marked reverted
+        let reverted: InstallPlan = serde_json::from_str(&serde_json::to_string(&plan)?)?;
+        assert_eq!(reverted.actions.len(), 3);
+        assert_eq!(reverted.actions[2].state, ActionState::Skipped);
+        assert!(reverted.actions[2].reverted_at().is_some());
+        assert_eq!(reverted.actions[0].reverted_at(), None);
+
+        assert!(matches!(
+            plan.revert_action(&directory_id).await,
+            Err(NixInstallerError::ActionNotExecuted(_))
+        ));
+        match plan.revert_action("create_directory:/nowhere").await {
+            Err(NixInstallerError::UnknownActionId { available, .. }) => {
+                assert_eq!(
+                    available,
+                    [file_id, "needs_created_file".into(), directory_id]
+                );
+            },
+            other => eyre::bail!("Should not have found the id, got {other:?}"),
+        }
+
+        // Installing again does not put it back
+        plan.install(None).await?;
+        assert!(!directory.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn action_order_is_checked() -> eyre::Result<()> {
+        let setup_default_profile = SetupDefaultProfile::plan("/nix/temp-install-dir".into())
+            .await?
+            .boxed();
+        let verify_nix_store = VerifyNixStore::plan(false, false).await?.boxed();
+
+        let out_of_order = [verify_nix_store.clone(), setup_default_profile.clone()];
+        assert!(matches!(
+            check_action_order(&out_of_order),
+            Err(PlannerError::ActionOrder(
+                "verify_nix_store",
+                "setup_default_profile"
+            ))
+        ));
+        check_action_order(&[setup_default_profile, verify_nix_store])?;
+        Ok(())
+    }
+
+    #[test]
+    fn host_fingerprint_matches() {
+        let host = |machine_id: Option<&str>, hostname: Option<&str>| HostFingerprint::Host {
+            machine_id: machine_id.map(ToString::to_string),
+            hostname: hostname.map(ToString::to_string),
+            filesystem_id: None,
+        };
+        assert!(host(Some("a"), Some("one")).matches(&host(Some("a"), Some("two"))));
+        assert!(host(Some("a"), Some("one")).matches(&host(Some("b"), Some("one"))));
+        assert!(host(None, Some("one")).matches(&host(Some("b"), Some("one"))));
+        assert!(host(None, None).matches(&host(None, None)));
+        assert!(!HostFingerprint::Unclaimed.matches(&host(Some("a"), Some("one"))));
+    }
+
+    #[test]
+    fn host_fingerprint_tiers_mismatches() {
+        let host =
+            |machine_id: &str, hostname: &str, filesystem_id: Option<&str>| HostFingerprint::Host {
+                machine_id: Some(machine_id.to_string()),
+                hostname: Some(hostname.to_string()),
+                filesystem_id: filesystem_id.map(ToString::to_string),
+            };
+        let recorded = host("a", "one", Some("fs"));
+
+        // (machine ID, hostname, filesystem ID) of the current host, and the decision
+        for (current, expected) in [
+            (host("a", "one", Some("fs")), HostComparison::Same),
+            (
+                host("b", "one", Some("fs")),
+                HostComparison::Changed(vec!["the machine ID changed from `a` to `b`".into()]),
+            ),
+            (
+                host("a", "two", Some("fs")),
+                HostComparison::Changed(vec!["the hostname changed from `one` to `two`".into()]),
+            ),
+            (
+                host("b", "two", Some("fs")),
+                HostComparison::Changed(vec![
+                    "the machine ID changed from `a` to `b`".into(),
+                    "the hostname changed from `one` to `two`".into(),
+                ]),
+            ),
+            (host("a", "one", Some("other")), HostComparison::Different),
+            (host("b", "two", Some("other")), HostComparison::Different),
+            // Without a filesystem ID to go by, changes are only noted
+            (host("a", "one", None), HostComparison::Same),
+            (
+                host("b", "two", None),
+                HostComparison::Changed(vec![
+                    "the machine ID changed from `a` to `b`".into(),
+                    "the hostname changed from `one` to `two`".into(),
+                    "the `/nix` filesystem could not be identified to confirm it is the same one"
+                        .into(),
+                ]),
+            ),
+        ] {
+            assert_eq!(recorded.compare(&current), expected, "{current}");
+        }
+
+        // Receipts from before filesystem IDs were recorded
+        assert_eq!(
+            host("a", "one", None).compare(&host("a", "one", Some("fs"))),
+            HostComparison::Same
+        );
+        assert_eq!(
+            HostFingerprint::Unclaimed.compare(&recorded),
+            HostComparison::Different
+        );
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn filesystem_uuid_is_found_by_device() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let by_uuid = temp_dir.path().join("by-uuid");
+        std::fs::create_dir(&by_uuid)?;
+        // Not the device of anything, and not a block device
+        std::os::unix::fs::symlink("/dev/null", by_uuid.join("0000-0000"))?;
+        assert_eq!(super::filesystem_uuid(temp_dir.path(), &by_uuid), None);
+        assert_eq!(
+            super::filesystem_uuid(temp_dir.path(), &temp_dir.path().join("missing")),
+            None
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn ensure_version_denies_incompatible() -> Result<(), NixInstallerError> {
         let planner = BuiltinPlanner::default().await?;
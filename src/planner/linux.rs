@@ -1,21 +1,31 @@
+use crate::env;
+use crate::os::tools;
 use crate::{
     action::{
-        base::{CreateDirectory, RemoveDirectory},
+        base::{
+            create_or_insert_into_file, CreateDirectory, CreateOrInsertIntoFile, RemoveDirectory,
+        },
         common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
-        linux::ProvisionSelinux,
+        linux::{CreateZfsDataset, ProvisionSelinux},
         StatefulAction,
     },
     error::HasExpectedErrors,
+    os::tools::{LINUX_USER_TOOLS, SYSTEMD_TOOLS},
     planner::{Planner, PlannerError},
     settings::CommonSettings,
     settings::{InitSettings, InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::Path};
+use indexmap::IndexMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 use tokio::process::Command;
 use which::which;
 
-use super::ShellProfileLocations;
+use super::{ShellProfileLocations, ShellProfiles};
 
 /// A planner for traditional, mutable Linux systems like Debian, RHEL, or Arch
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -25,6 +35,117 @@ pub struct Linux {
     pub settings: CommonSettings,
     #[cfg_attr(feature = "cli", clap(flatten))]
     pub init: InitSettings,
+    #[cfg_attr(feature = "cli", clap(flatten))]
+    #[serde(default)]
+    pub zfs: ZfsSettings,
+}
+
+/// The mode pacman gives `/etc/profile.d` scripts, they are sourced so must be readable but not executable
+const ARCH_PROFILE_D_MODE: u32 = 0o644;
+/// Prepended to `/etc/profile.d/nix.sh` on Arch, so it is not mistaken for a file from a package
+const ARCH_PROFILE_D_NOTE: &str = "\
+# Written by nix-installer, not owned by any pacman package (so `NoExtract` and pacman hooks leave it be).
+# Removed by `/nix/nix-installer uninstall`.
+";
+
+/// Default properties of a ZFS dataset created for `/nix`
+const DEFAULT_ZFS_DATASET_PROPERTIES: &[&str] =
+    &["compression=zstd", "com.sun:auto-snapshot=false"];
+
+/// Settings for giving `/nix` its own ZFS dataset, done by default when `/` is on ZFS
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::Parser))]
+pub struct ZfsSettings {
+    /// The ZFS dataset to create for `/nix` (default: `<root pool>/nix` when `/` is on ZFS)
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_ZFS_DATASET"))]
+    pub zfs_dataset: Option<String>,
+
+    /// Properties (`KEY=VALUE`) of the ZFS dataset created for `/nix`, its mountpoint is always `legacy`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long = "zfs-dataset-property",
+            action = clap::ArgAction::Append,
+            num_args = 0..,
+            env = "NIX_INSTALLER_ZFS_DATASET_PROPERTIES",
+            value_delimiter = ',',
+            default_values = DEFAULT_ZFS_DATASET_PROPERTIES,
+        )
+    )]
+    pub zfs_dataset_properties: Vec<String>,
+
+    /// Do not create a ZFS dataset for `/nix`, even when `/` is on ZFS
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action = clap::ArgAction::SetTrue,
+            default_value = "false",
+            env = "NIX_INSTALLER_NO_ZFS_DATASET"
+        )
+    )]
+    pub no_zfs_dataset: bool,
+}
+
+impl Default for ZfsSettings {
+    fn default() -> Self {
+        Self {
+            zfs_dataset: None,
+            zfs_dataset_properties: DEFAULT_ZFS_DATASET_PROPERTIES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            no_zfs_dataset: false,
+        }
+    }
+}
+
+impl ZfsSettings {
+    /// A listing of the settings, suitable for [`Planner::settings`](crate::planner::Planner::settings)
+    pub fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
+        let Self {
+            zfs_dataset,
+            zfs_dataset_properties,
+            no_zfs_dataset,
+        } = self;
+        let mut map = HashMap::default();
+
+        map.insert("zfs_dataset".into(), serde_json::to_value(zfs_dataset)?);
+        map.insert(
+            "zfs_dataset_properties".into(),
+            serde_json::to_value(zfs_dataset_properties)?,
+        );
+        map.insert(
+            "no_zfs_dataset".into(),
+            serde_json::to_value(no_zfs_dataset)?,
+        );
+        Ok(map)
+    }
+
+    /// The dataset to create, if any, given the dataset `/` is mounted from
+    pub fn dataset(&self, root_dataset: Option<&str>) -> Option<String> {
+        if self.no_zfs_dataset {
+            return None;
+        }
+        if let Some(zfs_dataset) = &self.zfs_dataset {
+            return Some(zfs_dataset.clone());
+        }
+        let pool = root_dataset?.split('/').next()?;
+        Some(format!("{pool}/nix"))
+    }
+
+    /// The dataset properties, later settings of the same property win
+    pub fn properties(&self) -> Result<IndexMap<String, String>, PlannerError> {
+        self.zfs_dataset_properties
+            .iter()
+            .map(|property| {
+                property
+                    .split_once('=')
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| LinuxErrorKind::InvalidZfsProperty(property.clone()).into())
+            })
+            .collect()
+    }
 }
 
 #[async_trait::async_trait]
@@ -34,10 +155,14 @@ impl Planner for Linux {
         Ok(Self {
             settings: CommonSettings::default().await?,
             init: InitSettings::default().await?,
+            zfs: ZfsSettings::default(),
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Provisioned build users may share a group of another name than the one configured
+        let settings =
+            CreateUsersAndGroups::resolve_settings(&self.settings).map_err(PlannerError::Action)?;
         let has_selinux = detect_selinux().await?;
 
         let mut plan = vec![];
@@ -49,24 +174,71 @@ impl Planner for Linux {
                 .boxed(),
         );
 
+        let root_dataset = detect_zfs_root().await?;
+        let nix_is_mountpoint = detect_mountpoint("/nix").await;
+        match self.zfs.dataset(root_dataset.as_deref()) {
+            Some(_) if nix_is_mountpoint && self.zfs.zfs_dataset.is_none() => {
+                tracing::debug!(
+                    "`/nix` is already a mount point, not creating a ZFS dataset for it"
+                )
+            },
+            Some(dataset) => {
+                if self.init.init != InitSystem::Systemd {
+                    return Err(LinuxErrorKind::ZfsDatasetRequiresSystemd(dataset).into());
+                }
+                plan.push(
+                    CreateZfsDataset::plan(dataset, self.zfs.properties()?)
+                        .await
+                        .map_err(PlannerError::Action)?
+                        .boxed(),
+                );
+            },
+            None => (),
+        }
+
         plan.push(
-            ProvisionNix::plan(&self.settings.clone())
+            ProvisionNix::plan(&settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
+            CreateUsersAndGroups::plan(settings.clone())
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
-        plan.push(
-            ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
+        let is_arch = detect_arch();
+        // Without a daemon, only the user installing uses Nix, the profiles in `/etc` are not theirs
+        let no_daemon_home = match crate::os::home_ownership::target_user() {
+            Some(user) if self.init.init == InitSystem::None => Some(user.dir),
+            _ => None,
+        };
+        let shell_profiles = match no_daemon_home.clone() {
+            Some(home) => ShellProfiles::User(home),
+            None if is_arch => arch_shell_profile_locations(Path::new("/"))?.into(),
+            None => ShellProfileLocations::default().into(),
+        };
+        plan.extend(
+            ConfigureNix::plan_actions(shell_profiles, &settings)
+                .await
+                .map_err(PlannerError::Action)?,
+        );
+        if is_arch && self.settings.modify_profile && no_daemon_home.is_none() {
+            plan.push(
+                CreateOrInsertIntoFile::plan(
+                    "/etc/profile.d/nix.sh",
+                    None,
+                    None,
+                    ARCH_PROFILE_D_MODE,
+                    ARCH_PROFILE_D_NOTE.to_string(),
+                    create_or_insert_into_file::Position::Beginning,
+                )
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
-        );
+            );
+        }
 
         if has_selinux {
             plan.push(
@@ -84,11 +256,23 @@ impl Planner for Linux {
                 .boxed(),
         );
 
+        plan.extend(
+            super::raise_limits(&self.settings, self.init.init == InitSystem::Systemd).await?,
+        );
+
         plan.push(
-            ConfigureInitService::plan(self.init.init, self.init.start_daemon)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            ConfigureInitService::plan(
+                self.init.init,
+                self.init.start_daemon,
+                self.settings.daemon_env.clone(),
+                self.settings.daemon_limits(),
+                self.settings.nix_ssl_cert_file(),
+                self.settings.nix_conf_dir.clone(),
+                self.settings.proxy.clone(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
         );
         plan.push(
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
@@ -101,11 +285,16 @@ impl Planner for Linux {
     }
 
     fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
-        let Self { settings, init } = self;
+        let Self {
+            settings,
+            init,
+            zfs,
+        } = self;
         let mut map = HashMap::default();
 
         map.extend(settings.settings()?);
         map.extend(init.settings()?);
+        map.extend(zfs.settings()?);
 
         Ok(map)
     }
@@ -139,9 +328,19 @@ impl Planner for Linux {
             self.settings.ssl_cert_file.clone(),
         )?)
     }
+    async fn resolve_tools(&self) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+        let mut requirements = LINUX_USER_TOOLS.to_vec();
+        if self.init.init == InitSystem::Systemd {
+            requirements.extend_from_slice(SYSTEMD_TOOLS);
+        }
+        super::resolve_tools(&requirements, &self.settings)
+    }
+
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
         check_not_wsl1()?;
 
+        super::check_no_mounts_under_nix().await?;
+
         if self.init.init == InitSystem::Systemd && self.init.start_daemon {
             check_systemd_active()?;
         }
@@ -156,10 +355,20 @@ impl Planner for Linux {
 
         check_not_wsl1()?;
 
+        super::check_native_architecture(&self.settings)?;
+
         if self.init.init == InitSystem::Systemd && self.init.start_daemon {
             check_systemd_active()?;
         }
 
+        super::check_scratch_space(Path::new("/nix"), &self.settings).await?;
+
+        super::check_build_users(&self.settings)?;
+
+        super::check_nix_conf_dir(&self.settings)?;
+
+        super::check_connectivity(&self.settings).await?;
+
         Ok(())
     }
 }
@@ -181,7 +390,7 @@ pub(crate) fn check_not_nixos() -> Result<(), PlannerError> {
 
 pub(crate) fn check_not_wsl1() -> Result<(), PlannerError> {
     // Detection strategies: https://patrickwu.space/wslconf/
-    if std::env::var("WSL_DISTRO_NAME").is_ok() && std::env::var("WSL_INTEROP").is_err() {
+    if env::WSL_DISTRO_NAME.is_set() && !env::WSL_INTEROP.is_set() {
         return Err(PlannerError::Wsl1);
     }
     Ok(())
@@ -190,8 +399,8 @@ pub(crate) fn check_not_wsl1() -> Result<(), PlannerError> {
 pub(crate) async fn detect_selinux() -> Result<bool, PlannerError> {
     if Path::new("/sys/fs/selinux").exists() && which("sestatus").is_ok() {
         // We expect systems with SELinux to have the normal SELinux tools.
-        let has_semodule = which("semodule").is_ok();
-        let has_restorecon = which("restorecon").is_ok();
+        let has_semodule = tools::find("semodule").is_some();
+        let has_restorecon = tools::find("restorecon").is_some();
         if !(has_semodule && has_restorecon) {
             Err(PlannerError::SelinuxRequirements)
         } else {
@@ -219,7 +428,7 @@ pub(crate) async fn check_nix_not_already_installed() -> Result<(), PlannerError
 
 pub(crate) fn check_systemd_active() -> Result<(), PlannerError> {
     if !Path::new("/run/systemd/system").exists() {
-        if std::env::var("WSL_DISTRO_NAME").is_ok() {
+        if env::WSL_DISTRO_NAME.is_set() {
             return Err(LinuxErrorKind::Wsl2SystemdNotActive)?;
         } else {
             return Err(LinuxErrorKind::SystemdNotActive)?;
@@ -229,6 +438,81 @@ pub(crate) fn check_systemd_active() -> Result<(), PlannerError> {
     Ok(())
 }
 
+pub(crate) fn detect_arch() -> bool {
+    os_release::OsRelease::new().is_ok_and(|os_release| os_release.id == "arch")
+}
+
+/**
+The shell profiles Arch actually reads, under `root`
+
+Arch only reads `/etc/bash.bashrc` (not `/etc/bashrc`) and `/etc/zsh/zshrc` (not `/etc/zshrc`),
+and does not ship a global zshrc, so zsh users would get nothing unless it is created. It is only
+created when zsh is installed.
+*/
+pub(crate) fn arch_shell_profile_locations(
+    root: &Path,
+) -> Result<ShellProfileLocations, LinuxErrorKind> {
+    let profile_d = root.join("etc/profile.d/nix.sh");
+    if let Ok(metadata) = std::fs::metadata(&profile_d) {
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode != ARCH_PROFILE_D_MODE {
+            return Err(LinuxErrorKind::ArchProfileDMode(profile_d, mode));
+        }
+    }
+
+    let has_zsh = ["usr/bin/zsh", "bin/zsh"]
+        .iter()
+        .any(|zsh| root.join(zsh).exists());
+
+    Ok(ShellProfileLocations {
+        bash: vec![profile_d, root.join("etc/bash.bashrc")],
+        zsh: if has_zsh {
+            vec![root.join("etc/zsh/zshrc")]
+        } else {
+            vec![]
+        },
+        ..ShellProfileLocations::default()
+    })
+}
+
+/// The dataset `/` is mounted from, if `/` is on ZFS
+pub(crate) async fn detect_zfs_root() -> Result<Option<String>, PlannerError> {
+    if tools::find("findmnt").is_none() {
+        return Ok(None);
+    }
+    let mut command = tools::command("findmnt");
+    command.args(["--noheadings", "--output", "FSTYPE,SOURCE", "--target", "/"]);
+    command.stdin(std::process::Stdio::null());
+    let output = crate::command_runner::output(&mut command)
+        .await
+        .map_err(|e| PlannerError::Command(format!("{:?}", command.as_std()), e))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_zfs_root(&String::from_utf8(output.stdout)?))
+}
+
+fn parse_zfs_root(findmnt_output: &str) -> Option<String> {
+    let mut fields = findmnt_output.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some("zfs"), Some(source)) => Some(source.to_string()),
+        _ => None,
+    }
+}
+
+async fn detect_mountpoint(path: &str) -> bool {
+    crate::command_runner::output(
+        tools::command("findmnt")
+            .args(["--noheadings", "--mountpoint", path])
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await
+    .map(|output| output.status.success())
+    .unwrap_or(false)
+}
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum LinuxErrorKind {
@@ -252,6 +536,12 @@ pub enum LinuxErrorKind {
         To use a `root`-only Nix install, consider passing `--init none`."
     )]
     Wsl2SystemdNotActive,
+    #[error("ZFS dataset property `{0}` is not valid, expected `KEY=VALUE`")]
+    InvalidZfsProperty(String),
+    #[error("Creating the ZFS dataset `{0}` for `/nix` requires `--init systemd` to mount it, pass `--no-zfs-dataset` to skip it")]
+    ZfsDatasetRequiresSystemd(String),
+    #[error("`{path}` has mode `{1:o}`, pacman expects `/etc/profile.d` scripts to be `{ARCH_PROFILE_D_MODE:o}`, fix it with `chmod {ARCH_PROFILE_D_MODE:o} {path}`", path = .0.display())]
+    ArchProfileDMode(PathBuf, u32),
 }
 
 impl HasExpectedErrors for LinuxErrorKind {
@@ -259,6 +549,9 @@ impl HasExpectedErrors for LinuxErrorKind {
         match self {
             LinuxErrorKind::SystemdNotActive => Some(Box::new(self)),
             LinuxErrorKind::Wsl2SystemdNotActive => Some(Box::new(self)),
+            LinuxErrorKind::InvalidZfsProperty(_) => Some(Box::new(self)),
+            LinuxErrorKind::ZfsDatasetRequiresSystemd(_) => Some(Box::new(self)),
+            LinuxErrorKind::ArchProfileDMode(_, _) => Some(Box::new(self)),
         }
     }
 }
@@ -268,3 +561,230 @@ impl From<LinuxErrorKind> for PlannerError {
         PlannerError::Custom(Box::new(v))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        command_runner::set_runner,
+        plan::RECEIPT_LOCATION,
+        settings::{UrlOrPath, SCRATCH_DIR},
+        test_support::{read, run_in_fake_root, snapshot, FakeRootRunner, NIX_TARBALL},
+        InstallPlan, NixInstallerError,
+    };
+    use std::sync::Arc;
+
+    /// The `nix` package of the fake root's Nix tarball, once moved into the store
+    const FAKE_ROOT_NIX: &str = "/nix/store/1b4cs0mx0g1qkkvxxrfnvq0z7yxdbxy1-nix-2.24.0";
+
+    /// A plan for the fake root, booted with systemd, with its Nix tarball
+    async fn fake_root_plan() -> eyre::Result<InstallPlan> {
+        std::fs::create_dir_all("/run/systemd/system")?;
+        std::fs::create_dir_all("/etc/systemd/system")?;
+        let mut planner = Linux::default().await?;
+        planner.settings.nix_package_url = UrlOrPath::Path(NIX_TARBALL.into());
+        planner.settings.skip_space_check = true;
+        #[cfg(feature = "diagnostics")]
+        {
+            planner.settings.diagnostic_endpoint = None;
+        }
+        Ok(InstallPlan::plan(planner).await?)
+    }
+
+    #[test]
+    fn installs_in_fake_root() -> eyre::Result<()> {
+        run_in_fake_root(module_path!(), "installs_in_fake_root", "linux", || async {
+            let mut plan = fake_root_plan().await?;
+            plan.install(None).await?;
+
+            let group = read("/etc/group")?;
+            let members = (1..=32)
+                .map(|index| format!("nixbld{index}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            assert!(
+                group.contains(&format!("nixbld:x:30000:{members}\n")),
+                "{group}"
+            );
+            let passwd = read("/etc/passwd")?;
+            assert!(
+                passwd.contains(
+                    "nixbld32:x:30032:30000:Nix build user 32:/var/empty:/sbin/nologin\n"
+                ),
+                "{passwd}"
+            );
+
+            assert_eq!(
+                std::fs::canonicalize("/nix/var/nix/profiles/default/bin/nix-env")?,
+                Path::new(FAKE_ROOT_NIX).join("bin/nix-env")
+            );
+            assert!(read("/etc/nix/nix.conf")?.contains("build-users-group = nixbld"));
+            assert!(read("/etc/bash.bashrc")?
+                .contains("/nix/var/nix/profiles/default/etc/profile.d/nix-daemon.sh"));
+            assert_eq!(
+                std::fs::read_link("/etc/systemd/system/nix-daemon.socket")?,
+                Path::new("/nix/var/nix/profiles/default/lib/systemd/system/nix-daemon.socket")
+            );
+            assert!(!Path::new(SCRATCH_DIR).exists());
+            assert!(Path::new(RECEIPT_LOCATION).exists());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn installs_offline_from_a_package_file_in_fake_root() -> eyre::Result<()> {
+        run_in_fake_root(
+            module_path!(),
+            "installs_offline_from_a_package_file_in_fake_root",
+            "linux",
+            || async {
+                std::fs::create_dir_all("/run/systemd/system")?;
+                std::fs::create_dir_all("/etc/systemd/system")?;
+                let mut planner = Linux::default().await?;
+                // Nothing can be fetched from here, the install must not try
+                planner.settings.nix_package_url =
+                    UrlOrPath::Url("https://nix-package.invalid/nix.tar.xz".parse()?);
+                planner.settings.nix_package_file = Some(NIX_TARBALL.into());
+                planner.settings.skip_space_check = true;
+                #[cfg(feature = "diagnostics")]
+                {
+                    planner.settings.diagnostic_endpoint = None;
+                }
+                let plan = InstallPlan::plan(planner).await?;
+                assert!(plan
+                    .describe_install(true)
+                    .await?
+                    .contains("without network access"));
+
+                // Installing the recorded plan reads the same tarball
+                let recorded = serde_json::to_string(&plan)?;
+                assert!(
+                    recorded.contains(&format!(r#""url_or_path":{{"Path":"{NIX_TARBALL}"}}"#)),
+                    "{recorded}"
+                );
+                let mut plan: InstallPlan = serde_json::from_str(&recorded)?;
+                plan.install(None).await?;
+
+                assert_eq!(
+                    std::fs::canonicalize("/nix/var/nix/profiles/default/bin/nix-env")?,
+                    Path::new(FAKE_ROOT_NIX).join("bin/nix-env")
+                );
+                Ok(())
+            },
+        )
+    }
+
+    #[test]
+    fn rolls_back_failed_install_in_fake_root() -> eyre::Result<()> {
+        run_in_fake_root(
+            module_path!(),
+            "rolls_back_failed_install_in_fake_root",
+            "linux",
+            || async {
+                // Configuring the daemon is the last step but one
+                set_runner(Arc::new(FakeRootRunner::default().respond(
+                    &["systemd-tmpfiles", "--create"],
+                    1,
+                    "",
+                )));
+                let mut plan = fake_root_plan().await?;
+                let before = snapshot(&["/etc", "/nix", "/root"])?;
+
+                let err = plan
+                    .install(None)
+                    .await
+                    .expect_err("`systemd-tmpfiles` failed");
+                assert!(matches!(err, NixInstallerError::Action(_)), "{err:?}");
+                assert!(read("/etc/group")?.contains("nixbld:"));
+
+                plan.uninstall(None).await?;
+                assert_eq!(snapshot(&["/etc", "/nix", "/root"])?, before);
+                Ok(())
+            },
+        )
+    }
+
+    #[test]
+    fn parses_zfs_root() {
+        assert_eq!(
+            parse_zfs_root("zfs    rpool/ROOT/ubuntu_a1b2c3\n"),
+            Some("rpool/ROOT/ubuntu_a1b2c3".into())
+        );
+        assert_eq!(parse_zfs_root("ext4   /dev/sda1\n"), None);
+        assert_eq!(parse_zfs_root(""), None);
+    }
+
+    #[test]
+    fn zfs_dataset_defaults_to_root_pool() -> eyre::Result<()> {
+        let mut zfs = ZfsSettings::default();
+        assert_eq!(
+            zfs.dataset(Some("rpool/ROOT/ubuntu_a1b2c3")),
+            Some("rpool/nix".into())
+        );
+        assert_eq!(zfs.dataset(None), None);
+
+        zfs.zfs_dataset = Some("tank/store/nix".into());
+        assert_eq!(zfs.dataset(None), Some("tank/store/nix".into()));
+
+        zfs.no_zfs_dataset = true;
+        assert_eq!(zfs.dataset(Some("rpool/ROOT/ubuntu_a1b2c3")), None);
+
+        let properties = ZfsSettings::default().properties()?;
+        assert_eq!(properties.get("compression"), Some(&"zstd".into()));
+        assert_eq!(
+            properties.get("com.sun:auto-snapshot"),
+            Some(&"false".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn arch_shell_profiles_without_zsh() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc/profile.d"))?;
+        std::fs::write(root.path().join("etc/bash.bashrc"), "")?;
+
+        let locations = arch_shell_profile_locations(root.path())?;
+        assert_eq!(
+            locations.bash,
+            vec![
+                root.path().join("etc/profile.d/nix.sh"),
+                root.path().join("etc/bash.bashrc"),
+            ]
+        );
+        assert!(locations.zsh.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn arch_shell_profiles_with_zsh() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc/profile.d"))?;
+        std::fs::create_dir_all(root.path().join("usr/bin"))?;
+        std::fs::write(root.path().join("usr/bin/zsh"), "")?;
+
+        let locations = arch_shell_profile_locations(root.path())?;
+        // Absent, so it is created
+        assert_eq!(locations.zsh, vec![root.path().join("etc/zsh/zshrc")]);
+        assert!(!locations.zsh[0].exists());
+        Ok(())
+    }
+
+    #[test]
+    fn arch_profile_d_must_not_be_executable() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let profile_d = root.path().join("etc/profile.d/nix.sh");
+        std::fs::create_dir_all(profile_d.parent().unwrap())?;
+        std::fs::write(&profile_d, "")?;
+
+        std::fs::set_permissions(&profile_d, PermissionsExt::from_mode(0o755))?;
+        assert!(matches!(
+            arch_shell_profile_locations(root.path()),
+            Err(LinuxErrorKind::ArchProfileDMode(_, 0o755))
+        ));
+
+        std::fs::set_permissions(&profile_d, PermissionsExt::from_mode(0o644))?;
+        assert!(arch_shell_profile_locations(root.path()).is_ok());
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, io::Cursor, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 #[cfg(feature = "cli")]
 use clap::ArgAction;
@@ -6,17 +9,19 @@ use tokio::process::Command;
 use which::which;
 
 use super::ShellProfileLocations;
+use crate::os::tools;
 use crate::planner::HasExpectedErrors;
 
 use crate::{
     action::{
         base::RemoveDirectory,
         common::{ConfigureInitService, ConfigureNix, CreateUsersAndGroups, ProvisionNix},
-        macos::{CreateNixHookService, CreateNixVolume, SetTmutilExclusions},
+        macos::{
+            ConfigureDaemonLogRotation, CreateNixHookService, CreateNixVolume, SetTmutilExclusions,
+        },
         StatefulAction,
     },
-    execute_command,
-    os::darwin::DiskUtilInfoOutput,
+    os::darwin::diskutil_info,
     planner::{Planner, PlannerError},
     settings::InstallSettingsError,
     settings::{CommonSettings, InitSystem},
@@ -61,18 +66,31 @@ pub struct Macos {
     /// The root disk of the target
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_ROOT_DISK"))]
     pub root_disk: Option<String>,
+    /// Don't re-add Nix to the shell profiles at boot, after macOS updates replaced them
+    ///
+    /// By default a `launchd` service runs `nix-installer repair` at every boot.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            action(ArgAction::SetFalse),
+            default_value = "true",
+            env = "NIX_INSTALLER_PROFILE_GUARD",
+            long = "no-profile-guard"
+        )
+    )]
+    #[serde(default = "profile_guard_default")]
+    pub profile_guard: bool,
+}
+
+/// Receipts predating `--no-profile-guard` always had the guard
+fn profile_guard_default() -> bool {
+    true
 }
 
 async fn default_root_disk() -> Result<String, PlannerError> {
-    let buf = execute_command(
-        Command::new("/usr/sbin/diskutil")
-            .args(["info", "-plist", "/"])
-            .stdin(std::process::Stdio::null()),
-    )
-    .await
-    .unwrap()
-    .stdout;
-    let the_plist: DiskUtilInfoOutput = plist::from_reader(Cursor::new(buf))?;
+    let the_plist = diskutil_info("/")
+        .await
+        .map_err(|e| PlannerError::Custom(Box::new(e)))?;
 
     Ok(the_plist.parent_whole_disk)
 }
@@ -87,38 +105,31 @@ impl Planner for Macos {
             case_sensitive: false,
             encrypt: None,
             volume_label: "Nix Store".into(),
+            profile_guard: true,
         })
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Provisioned build users may share a group of another name than the one configured
+        let settings =
+            CreateUsersAndGroups::resolve_settings(&self.settings).map_err(PlannerError::Action)?;
         let root_disk = match &self.root_disk {
             root_disk @ Some(_) => root_disk.clone(),
-            None => {
-                let buf = execute_command(
-                    Command::new("/usr/sbin/diskutil")
-                        .args(["info", "-plist", "/"])
-                        .stdin(std::process::Stdio::null()),
-                )
-                .await
-                .unwrap()
-                .stdout;
-                let the_plist: DiskUtilInfoOutput = plist::from_reader(Cursor::new(buf)).unwrap();
-
-                Some(the_plist.parent_whole_disk)
-            },
+            None => Some(default_root_disk().await?),
         };
 
         let encrypt = match self.encrypt {
             Some(choice) => choice,
             None => {
-                let output = Command::new("/usr/bin/fdesetup")
-                    .arg("isactive")
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .process_group(0)
-                    .output()
-                    .await
-                    .map_err(|e| PlannerError::Custom(Box::new(e)))?;
+                let output = crate::command_runner::output(
+                    Command::new("/usr/bin/fdesetup")
+                        .arg("isactive")
+                        .stdout(std::process::Stdio::null())
+                        .stderr(std::process::Stdio::null())
+                        .process_group(0),
+                )
+                .await
+                .map_err(|e| PlannerError::Custom(Box::new(e)))?;
 
                 let stdout = String::from_utf8_lossy(&output.stdout);
                 let stdout_trimmed = stdout.trim();
@@ -141,7 +152,7 @@ impl Planner for Macos {
             .boxed(),
         );
         plan.push(
-            ProvisionNix::plan(&self.settings)
+            ProvisionNix::plan(&settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
@@ -149,7 +160,7 @@ impl Planner for Macos {
         // Auto-allocate uids is broken on Mac. Tools like `whoami` don't work.
         // e.g. https://github.com/NixOS/nix/issues/8444
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
+            CreateUsersAndGroups::plan(settings.clone())
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
@@ -160,14 +171,13 @@ impl Planner for Macos {
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
-        plan.push(
-            ConfigureNix::plan(ShellProfileLocations::default(), &self.settings)
+        plan.extend(
+            ConfigureNix::plan_actions(ShellProfileLocations::default(), &settings)
                 .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                .map_err(PlannerError::Action)?,
         );
 
-        if self.settings.modify_profile {
+        if self.settings.modify_profile && self.profile_guard {
             plan.push(
                 CreateNixHookService::plan()
                     .await
@@ -176,11 +186,28 @@ impl Planner for Macos {
             );
         }
 
+        if self.settings.daemon_log_limit.is_some() {
+            plan.push(
+                ConfigureDaemonLogRotation::plan(self.settings.force)
+                    .await
+                    .map_err(PlannerError::Action)?
+                    .boxed(),
+            );
+        }
+
         plan.push(
-            ConfigureInitService::plan(InitSystem::Launchd, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            ConfigureInitService::plan(
+                InitSystem::Launchd,
+                true,
+                self.settings.daemon_env.clone(),
+                self.settings.daemon_limits(),
+                self.settings.nix_ssl_cert_file(),
+                self.settings.nix_conf_dir.clone(),
+                self.settings.proxy.clone(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
         );
         plan.push(
             RemoveDirectory::plan(crate::settings::SCRATCH_DIR)
@@ -199,6 +226,7 @@ impl Planner for Macos {
             volume_label,
             case_sensitive,
             root_disk,
+            profile_guard,
         } = self;
         let mut map = HashMap::default();
 
@@ -210,6 +238,7 @@ impl Planner for Macos {
             "case_sensitive".into(),
             serde_json::to_value(case_sensitive)?,
         );
+        map.insert("profile_guard".into(), serde_json::to_value(profile_guard)?);
 
         Ok(map)
     }
@@ -244,14 +273,27 @@ impl Planner for Macos {
         )?)
     }
 
+    async fn resolve_tools(&self) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+        // Everything else is run by its absolute path, as it ships with MacOS
+        super::resolve_tools(&[&["launchctl"], &["tmutil"]], &self.settings)
+    }
+
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
         check_nix_darwin_not_installed().await?;
 
+        super::check_no_mounts_under_nix().await?;
+
         Ok(())
     }
 
     async fn pre_install_check(&self) -> Result<(), PlannerError> {
-        check_not_running_in_rosetta()?;
+        super::check_native_architecture(&self.settings)?;
+
+        super::check_build_users(&self.settings)?;
+
+        super::check_nix_conf_dir(&self.settings)?;
+
+        super::check_connectivity(&self.settings).await?;
 
         Ok(())
     }
@@ -267,17 +309,18 @@ async fn check_nix_darwin_not_installed() -> Result<(), PlannerError> {
     let has_darwin_rebuild = which("darwin-rebuild").is_ok();
     let has_darwin_option = which("darwin-option").is_ok();
 
-    let activate_system_present = Command::new("launchctl")
-        .arg("print")
-        .arg("system/org.nixos.activate-system")
-        .process_group(0)
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()
-        .await
-        .map(|v| v.success())
-        .unwrap_or(false);
+    let activate_system_present = crate::command_runner::output(
+        tools::command("launchctl")
+            .arg("print")
+            .arg("system/org.nixos.activate-system")
+            .process_group(0)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await
+    .map(|output| output.status.success())
+    .unwrap_or(false);
 
     if activate_system_present || has_darwin_rebuild || has_darwin_option {
         return Err(MacosError::UninstallNixDarwin).map_err(|e| PlannerError::Custom(Box::new(e)));
@@ -286,26 +329,6 @@ async fn check_nix_darwin_not_installed() -> Result<(), PlannerError> {
     Ok(())
 }
 
-fn check_not_running_in_rosetta() -> Result<(), PlannerError> {
-    use sysctl::{Ctl, Sysctl};
-    const CTLNAME: &str = "sysctl.proc_translated";
-
-    match Ctl::new(CTLNAME) {
-        // This Mac doesn't have Rosetta!
-        Err(sysctl::SysctlError::NotFound(_)) => (),
-        Err(e) => Err(e)?,
-        Ok(ctl) => {
-            let str_val = ctl.value_string()?;
-
-            if str_val == "1" {
-                return Err(PlannerError::RosettaDetected);
-            }
-        },
-    }
-
-    Ok(())
-}
-
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug)]
 pub enum MacosError {
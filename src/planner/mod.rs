@@ -112,14 +112,21 @@ pub mod ostree;
 #[cfg(target_os = "linux")]
 pub mod steam_deck;
 
-use std::{collections::HashMap, path::PathBuf, string::FromUtf8Error};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    string::FromUtf8Error,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    action::{ActionError, StatefulAction},
+    action::{base::SizeEstimate, ActionError, ActionErrorKind, StatefulAction},
     error::HasExpectedErrors,
-    settings::{CommonSettings, InstallSettingsError},
+    http,
+    os::emulation::{self, ArchitectureMismatch},
+    settings::{self, CommonSettings, InstallSettingsError, UrlOrPath, UrlOrPathOrString},
     Action, InstallPlan, NixInstallerError,
 };
 
@@ -155,6 +162,14 @@ pub trait Planner: std::fmt::Debug + Send + Sync + dyn_clone::DynClone {
         Ok(())
     }
 
+    /// The external tools the plan runs, by their absolute paths
+    ///
+    /// These are recorded in the [`InstallPlan`], and used in place of searching `PATH` while it
+    /// is planned and executed.
+    async fn resolve_tools(&self) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+        Ok(BTreeMap::new())
+    }
+
     #[cfg(feature = "diagnostics")]
     async fn diagnostic_data(&self) -> Result<crate::diagnostics::DiagnosticData, PlannerError>;
 }
@@ -243,6 +258,21 @@ impl BuiltinPlanner {
         Ok(built)
     }
 
+    /// Hook Nix into the shell profiles, see [`CommonSettings::modify_profile`]
+    pub fn modify_profile(&mut self, toggle: bool) -> &mut Self {
+        match self {
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Linux(inner) => inner.settings.modify_profile(toggle),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::SteamDeck(inner) => inner.settings.modify_profile(toggle),
+            #[cfg(target_os = "linux")]
+            BuiltinPlanner::Ostree(inner) => inner.settings.modify_profile(toggle),
+            #[cfg(target_os = "macos")]
+            BuiltinPlanner::Macos(inner) => inner.settings.modify_profile(toggle),
+        };
+        self
+    }
+
     pub async fn configured_settings(
         &self,
     ) -> Result<HashMap<String, serde_json::Value>, PlannerError> {
@@ -326,6 +356,326 @@ impl BuiltinPlanner {
     }
 }
 
+/// Ensure each action comes after the actions it [depends on](crate::action::Action::depends_on)
+pub(crate) fn check_action_order(
+    actions: &[StatefulAction<Box<dyn Action>>],
+) -> Result<(), PlannerError> {
+    let mut planned = Vec::new();
+    for action in actions {
+        for dependency in action.depends_on() {
+            if !planned.contains(&dependency) {
+                return Err(PlannerError::ActionOrder(
+                    action.inner_typetag_name(),
+                    dependency,
+                ));
+            }
+        }
+        planned.extend(action.retainable_kinds());
+    }
+    Ok(())
+}
+
+/// Refuse to uninstall while anything is mounted under `/nix`, see [`crate::os::mounts`]
+pub(crate) async fn check_no_mounts_under_nix() -> Result<(), PlannerError> {
+    crate::os::mounts::ensure_no_mounts_under(Path::new("/nix"))
+        .await
+        .map_err(PlannerError::MountsUnderNix)
+}
+
+/// Roughly the space Nix needs while it is unpacked, the store paths of a release tarball and the tarball itself
+pub(crate) const SCRATCH_SPACE_NEEDED: u64 = 512 * 1024 * 1024;
+
+/// The filesystem Nix is unpacked onto, in the scratch directory under `/nix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ScratchFilesystem {
+    pub(crate) mount_point: PathBuf,
+    pub(crate) fs_type: String,
+    /// Total size, in bytes
+    pub(crate) size: u64,
+    /// Free space, in bytes (including any reserved for root, which the installer runs as)
+    pub(crate) free: u64,
+}
+
+impl ScratchFilesystem {
+    /// The filesystem containing `path`, or where it would be created
+    #[allow(clippy::unnecessary_cast)] // `statvfs` fields are not `u64` on every platform
+    pub(crate) async fn of(path: &Path) -> Option<Self> {
+        let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+        let stat = nix::sys::statvfs::statvfs(existing).ok()?;
+        let mount = crate::os::mounts::mount_containing(existing).await.ok()??;
+        Some(Self {
+            mount_point: mount.mount_point,
+            fs_type: mount.fs_type,
+            size: stat.blocks() as u64 * stat.fragment_size() as u64,
+            free: stat.blocks_free() as u64 * stat.fragment_size() as u64,
+        })
+    }
+
+    /// Why `needed` bytes do not fit, if they do not
+    ///
+    /// A tmpfs is judged by its size rather than its free space, freeing space on it would not be enough.
+    pub(crate) fn shortfall(&self, needed: u64) -> Option<String> {
+        let Self {
+            mount_point,
+            fs_type,
+            size,
+            free,
+        } = self;
+        if fs_type == "tmpfs" && *size < needed {
+            Some(format!(
+                "it is on a {} tmpfs mounted at `{}` and Nix needs about {} (a tmpfs can be grown with `mount -o remount,size=<SIZE> {}`)",
+                format_bytes(*size),
+                mount_point.display(),
+                format_bytes(needed),
+                mount_point.display(),
+            ))
+        } else if *free < needed {
+            Some(format!(
+                "only {} is free on the `{fs_type}` filesystem mounted at `{}` and Nix needs about {}",
+                format_bytes(*free),
+                mount_point.display(),
+                format_bytes(needed),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Ensure Nix fits on the filesystem it is unpacked onto, before anything is mutated
+///
+/// `nix_root` is where the contents of `/nix` are stored, which may not be `/nix` itself when it is bind mounted.
+pub(crate) async fn check_scratch_space(
+    nix_root: &Path,
+    settings: &CommonSettings,
+) -> Result<(), PlannerError> {
+    if settings.skip_space_check {
+        tracing::debug!("Skipping the scratch space check");
+        return Ok(());
+    }
+    let Some(filesystem) = ScratchFilesystem::of(nix_root).await else {
+        tracing::debug!(
+            "Could not find the filesystem of `{}`, skipping the scratch space check",
+            nix_root.display()
+        );
+        return Ok(());
+    };
+    tracing::debug!(
+        "Unpacking Nix onto the `{}` filesystem mounted at `{}` ({} free of {})",
+        filesystem.fs_type,
+        filesystem.mount_point.display(),
+        format_bytes(filesystem.free),
+        format_bytes(filesystem.size),
+    );
+    // The same figure the plan shows, see `FetchAndUnpackNix::size_estimate`
+    let needed = SizeEstimate::known(&settings.nix_package()).scratch_needed();
+    match filesystem.shortfall(needed) {
+        Some(reason) => Err(PlannerError::InsufficientScratchSpace(
+            nix_root.to_path_buf(),
+            reason,
+        )),
+        None => Ok(()),
+    }
+}
+
+/// A byte count in the largest binary unit it reaches, eg. `1.5 GiB`
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{value:.1} {unit}")
+}
+
+/// Refuse to install Nix for this binary's architecture on hardware of another, see [`emulation`]
+///
+/// Unless the tarball was picked with `--nix-package-url`, as for a 32 bit container on a 64 bit host.
+pub(crate) fn check_native_architecture(settings: &CommonSettings) -> Result<(), PlannerError> {
+    let Some(mismatch) = emulation::detect() else {
+        return Ok(());
+    };
+    let default_url = match mismatch.binary {
+        "x86_64-linux" => settings::NIX_X64_64_LINUX_URL,
+        "i686-linux" => settings::NIX_I686_LINUX_URL,
+        "aarch64-linux" => settings::NIX_AARCH64_LINUX_URL,
+        "x86_64-darwin" => settings::NIX_X64_64_DARWIN_URL,
+        _ => settings::NIX_AARCH64_DARWIN_URL,
+    };
+    if settings.nix_package().to_string() != default_url {
+        tracing::warn!(
+            "This `nix-installer` is built for `{}` but runs {} on `{}` hardware, installing `{}` as asked",
+            mismatch.binary,
+            mismatch.how,
+            mismatch.native,
+            settings.nix_package(),
+        );
+        return Ok(());
+    }
+    Err(PlannerError::ArchitectureMismatch(mismatch))
+}
+
+/// Refuse build users which cannot be created, and warn about unusual ones, see [`CommonSettings::check_build_users`]
+pub(crate) fn check_build_users(settings: &CommonSettings) -> Result<(), PlannerError> {
+    for warning in settings.check_build_users()? {
+        tracing::warn!("{warning}");
+    }
+    let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+    if let Some(note) = settings.build_parallelism_note(cpus) {
+        tracing::info!("{note}");
+    }
+    Ok(())
+}
+
+/// Ensure Nix's configuration can be written where it is planned to go, before anything is mutated
+///
+/// Some fleets mount `/etc/nix` read only, `--nix-conf-dir` is only suggested once that is confirmed.
+pub(crate) fn check_nix_conf_dir(settings: &CommonSettings) -> Result<(), PlannerError> {
+    let nix_conf_dir = settings.conf_dir();
+    if !nix_conf_dir.is_absolute() {
+        return Err(PlannerError::NixConfDir(
+            nix_conf_dir,
+            "it is not an absolute path".into(),
+        ));
+    }
+    match unwritable_reason(&nix_conf_dir) {
+        None => Ok(()),
+        Some(reason) if settings.nix_conf_dir.is_none() => Err(PlannerError::NixConfDir(
+            nix_conf_dir,
+            format!("{reason}, pass `--nix-conf-dir` to write it to a writable directory instead"),
+        )),
+        Some(reason) => Err(PlannerError::NixConfDir(nix_conf_dir, reason)),
+    }
+}
+
+/// Why `nix.conf` in `nix_conf_dir` (or the directory it would be created in) cannot be written, if it cannot
+fn unwritable_reason(nix_conf_dir: &Path) -> Option<String> {
+    let nix_conf = nix_conf_dir.join("nix.conf");
+    let existing = if nix_conf.exists() {
+        nix_conf.as_path()
+    } else {
+        nix_conf_dir
+            .ancestors()
+            .find(|ancestor| ancestor.exists())?
+    };
+    match nix::unistd::access(existing, nix::unistd::AccessFlags::W_OK) {
+        Ok(()) => None,
+        Err(nix::errno::Errno::EROFS) => Some(format!(
+            "`{}` is on a read only filesystem",
+            existing.display()
+        )),
+        Err(e) => Some(format!("`{}` is not writable ({e})", existing.display())),
+    }
+}
+
+const DEFAULT_SUBSTITUTER: &str = "https://cache.nixos.org";
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check every endpoint the install needs is reachable before anything is mutated
+///
+/// Skipped when the Nix package is a local path, as then nothing is fetched during the install.
+pub(crate) async fn check_connectivity(settings: &CommonSettings) -> Result<(), PlannerError> {
+    let tarball_url = match settings.nix_package() {
+        UrlOrPath::Url(url) if matches!(url.scheme(), "https" | "http") => url,
+        _ => {
+            tracing::debug!("Nix package is local, skipping connectivity preflight");
+            return Ok(());
+        },
+    };
+
+    let substituters = substituters(settings);
+
+    let client = http::client(
+        settings.proxy.as_ref(),
+        settings.ssl_cert_file.as_deref(),
+        CONNECTIVITY_TIMEOUT,
+    )
+    .await
+    .map_err(|e| PlannerError::Custom(Box::new(e)))?;
+
+    let mut unreachable = vec![];
+    // The tarball must actually exist, a substituter only needs to answer
+    let endpoints = std::iter::once((tarball_url, true))
+        .chain(substituters.into_iter().map(|url| (url, false)));
+    for (url, require_success) in endpoints {
+        let result = match client.head(url.clone()).send().await {
+            Ok(res) if require_success => res.error_for_status().map(|_| ()),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => tracing::debug!("Connectivity preflight: `{url}` is reachable"),
+            Err(e) => {
+                tracing::warn!("Connectivity preflight: `{url}` is unreachable: {e}");
+                unreachable.push((url, e.to_string()));
+            },
+        }
+    }
+
+    if unreachable.is_empty() {
+        Ok(())
+    } else {
+        Err(PlannerError::Unreachable(unreachable))
+    }
+}
+
+/// The HTTP substituters Nix will use, [`DEFAULT_SUBSTITUTER`] unless `--extra-conf` sets them
+///
+/// As in `nix.conf`, `substituters` replaces the ones set before it while `extra-substituters` adds to them.
+fn substituters(settings: &CommonSettings) -> Vec<url::Url> {
+    let mut substituters = vec![DEFAULT_SUBSTITUTER.to_string()];
+    for extra in &settings.extra_conf {
+        let UrlOrPathOrString::String(extra) = extra else {
+            continue;
+        };
+        let Ok(nix_config) = nix_config_parser::NixConfig::parse_string(extra.clone(), None) else {
+            continue;
+        };
+        for (key, value) in nix_config.settings() {
+            let value = value.split_whitespace().map(ToString::to_string);
+            match key.as_str() {
+                "substituters" => substituters = value.collect(),
+                "extra-substituters" => substituters.extend(value),
+                _ => (),
+            }
+        }
+    }
+    substituters
+        .into_iter()
+        .filter_map(|substituter| match url::Url::parse(&substituter) {
+            Ok(url) if matches!(url.scheme(), "https" | "http") => Some(url),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Where Nix is hooked into shells
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ShellProfiles {
+    /// The profiles every user's shells read
+    System(ShellProfileLocations),
+    /**
+    Only the dotfiles in this home directory, for installs without a daemon
+
+    See [`ConfigureShellProfile::plan_for_user`](crate::action::common::ConfigureShellProfile::plan_for_user).
+    */
+    User(PathBuf),
+}
+
+impl From<ShellProfileLocations> for ShellProfiles {
+    fn from(locations: ShellProfileLocations) -> Self {
+        Self::System(locations)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct ShellProfileLocations {
     pub fish: FishShellProfileLocations,
@@ -351,6 +701,27 @@ impl Default for ShellProfileLocations {
     }
 }
 
+impl ShellProfileLocations {
+    /**
+    Add `targets` (as given with `--profile-targets`) to the bash profiles, or with `replace` make them
+    the only bash and zsh profiles
+
+    The Fish profiles are always kept, as the POSIX shell hook written to `targets` would not parse there.
+    */
+    pub fn with_targets(mut self, targets: &[PathBuf], replace: bool) -> Self {
+        if replace {
+            self.bash.clear();
+            self.zsh.clear();
+        }
+        for target in targets {
+            if !self.bash.contains(target) && !self.zsh.contains(target) {
+                self.bash.push(target.clone());
+            }
+        }
+        self
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct FishShellProfileLocations {
     pub confd_suffix: PathBuf,
@@ -413,8 +784,8 @@ pub enum PlannerError {
     Plist(#[from] plist::Error),
     #[error(transparent)]
     Sysctl(#[from] sysctl::SysctlError),
-    #[error("Detected that this process is running under Rosetta, using Nix in Rosetta is not supported (Please open an issue with your use case)")]
-    RosettaDetected,
+    #[error("This `nix-installer` is built for `{}` but runs {} on `{}` hardware, where the Nix it installs would build everything emulated. Use the installer for `{}`, {}, or pass `--nix-package-url` to install a particular Nix anyway", .0.binary, .0.how, .0.native, .0.native, .0.installer_url())]
+    ArchitectureMismatch(ArchitectureMismatch),
     /// A Linux SELinux related error
     #[error("Unable to install on an SELinux system without common SELinux tooling, the binaries `restorecon`, and `semodule` are required")]
     SelinuxRequirements,
@@ -433,20 +804,100 @@ pub enum PlannerError {
     /// Failed to execute command
     #[error("Failed to execute command `{0}`")]
     Command(String, #[source] std::io::Error),
+    /// Endpoints required by the install could not be reached
+    #[error("Could not reach endpoints required to install, check your network and `--proxy` settings:\n{}", .0.iter().map(|(url, err)| format!("* `{url}`: {err}")).collect::<Vec<_>>().join("\n"))]
+    Unreachable(Vec<(url::Url, String)>),
+    /// An action was planned before one it depends on, see [`Action::depends_on`](crate::action::Action::depends_on)
+    #[error("The `{0}` action must be planned after a `{1}` action")]
+    ActionOrder(&'static str, &'static str),
+    /// Filesystems are mounted under `/nix`, so removing it could delete their contents
+    #[error(transparent)]
+    MountsUnderNix(ActionErrorKind),
+    /// The filesystem Nix is unpacked onto is too small for it, see [`check_scratch_space`]
+    #[error("Not enough space to unpack Nix into `{0}`, {1}. Mount a larger filesystem there, or pass `--skip-space-check` to try anyway")]
+    InsufficientScratchSpace(PathBuf, String),
+    /// Nix's configuration cannot be written, see [`check_nix_conf_dir`]
+    #[error("Cannot write the Nix configuration to `{0}`, {1}")]
+    NixConfDir(PathBuf, String),
+    /// External tools the install runs could not be found, see [`Planner::resolve_tools`]
+    #[error("Missing required tools: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    MissingTools(Vec<MissingTool>),
     #[cfg(feature = "diagnostics")]
     #[error(transparent)]
     Diagnostic(#[from] crate::diagnostics::DiagnosticError),
 }
 
+/// Resolve the external tools a plan runs for [`Planner::resolve_tools`], honoring `--tool-path`
+pub(crate) fn resolve_tools(
+    requirements: &[&[&str]],
+    settings: &CommonSettings,
+) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+    crate::os::tools::resolve(requirements, &settings.tool_paths)
+        .map_err(PlannerError::MissingTools)
+}
+
+/// With `--raise-limits`, the actions raising the limits Nix builds run into, otherwise a warning of
+/// each which is low, see [`limits`](crate::os::limits)
+#[cfg(target_os = "linux")]
+pub(crate) async fn raise_limits(
+    settings: &CommonSettings,
+    systemd: bool,
+) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+    use crate::action::{base::CreateDirectory, linux::RaiseLimits};
+
+    if !settings.raise_limits {
+        for low in crate::os::limits::low_limits(Path::new("/"), systemd) {
+            tracing::warn!("{low}, pass `--raise-limits` to raise it for Nix");
+        }
+        return Ok(vec![]);
+    }
+    Ok(vec![
+        CreateDirectory::plan("/etc/sysctl.d", None, None, 0o0755, false)
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+        RaiseLimits::plan()
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
+    ])
+}
+
+/// An external tool which could not be found, where any of `alternatives` would have done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingTool {
+    pub alternatives: Vec<String>,
+    /// Where it was searched for, an override passed with `--tool-path` is the only place looked
+    pub looked_in: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for MissingTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (looked in {})",
+            self.alternatives.join(" or "),
+            self.looked_in
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
 impl HasExpectedErrors for PlannerError {
     fn expected<'a>(&'a self) -> Option<Box<dyn std::error::Error + 'a>> {
         match self {
             this @ PlannerError::UnsupportedArchitecture(_) => Some(Box::new(this)),
             PlannerError::Action(_) => None,
+            this @ PlannerError::InstallSettings(
+                InstallSettingsError::BuildUserCount(_) | InstallSettingsError::BuildUserIds { .. },
+            ) => Some(Box::new(this)),
             PlannerError::InstallSettings(_) => None,
             PlannerError::Plist(_) => None,
             PlannerError::Sysctl(_) => None,
-            this @ PlannerError::RosettaDetected => Some(Box::new(this)),
+            this @ PlannerError::ArchitectureMismatch(_) => Some(Box::new(this)),
             PlannerError::OsRelease(_) => None,
             PlannerError::Utf8(_) => None,
             PlannerError::SelinuxRequirements => Some(Box::new(self)),
@@ -465,6 +916,12 @@ impl HasExpectedErrors for PlannerError {
             this @ PlannerError::NixExists => Some(Box::new(this)),
             this @ PlannerError::Wsl1 => Some(Box::new(this)),
             PlannerError::Command(_, _) => None,
+            this @ PlannerError::Unreachable(_) => Some(Box::new(this)),
+            PlannerError::ActionOrder(_, _) => None,
+            this @ PlannerError::MountsUnderNix(_) => Some(Box::new(this)),
+            this @ PlannerError::InsufficientScratchSpace(_, _) => Some(Box::new(this)),
+            this @ PlannerError::MissingTools(_) => Some(Box::new(this)),
+            this @ PlannerError::NixConfDir(_, _) => Some(Box::new(this)),
             #[cfg(feature = "diagnostics")]
             PlannerError::Diagnostic(diagnostic_error) => Some(Box::new(diagnostic_error)),
         }
@@ -478,3 +935,165 @@ impl crate::diagnostics::ErrorDiagnostic for PlannerError {
         static_str.to_string()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        check_connectivity, check_nix_conf_dir, format_bytes, substituters, PlannerError,
+        ScratchFilesystem, SCRATCH_SPACE_NEEDED,
+    };
+    use crate::settings::{CommonSettings, UrlOrPath, UrlOrPathOrString};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn filesystem(fs_type: &str, size: u64, free: u64) -> ScratchFilesystem {
+        ScratchFilesystem {
+            mount_point: PathBuf::from("/"),
+            fs_type: fs_type.to_string(),
+            size,
+            free,
+        }
+    }
+
+    #[test]
+    fn small_tmpfs_does_not_fit() {
+        let shortfall = filesystem("tmpfs", SCRATCH_SPACE_NEEDED / 2, SCRATCH_SPACE_NEEDED / 2)
+            .shortfall(SCRATCH_SPACE_NEEDED)
+            .expect("A tmpfs smaller than Nix should not fit");
+        assert!(shortfall.contains("256.0 MiB tmpfs mounted at `/`"));
+        assert!(shortfall.contains("mount -o remount,size=<SIZE> /"));
+    }
+
+    #[test]
+    fn full_filesystem_does_not_fit() {
+        let shortfall = filesystem("ext4", 100 * GIB, SCRATCH_SPACE_NEEDED - 1)
+            .shortfall(SCRATCH_SPACE_NEEDED)
+            .expect("A filesystem without enough free space should not fit");
+        assert!(shortfall.starts_with("only 512.0 MiB is free on the `ext4` filesystem"));
+    }
+
+    #[test]
+    fn roomy_filesystems_fit() {
+        assert_eq!(
+            filesystem("tmpfs", 2 * GIB, GIB).shortfall(SCRATCH_SPACE_NEEDED),
+            None
+        );
+        assert_eq!(
+            filesystem("xfs", 100 * GIB, SCRATCH_SPACE_NEEDED).shortfall(SCRATCH_SPACE_NEEDED),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn nix_conf_dir_must_be_writable() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut settings = CommonSettings::default().await?;
+
+        // Created on install, so only its nearest existing ancestor needs to be writable
+        settings.nix_conf_dir = Some(temp_dir.path().join("etc/nix"));
+        check_nix_conf_dir(&settings)?;
+
+        settings.nix_conf_dir = Some("etc/nix".into());
+        assert!(matches!(
+            check_nix_conf_dir(&settings),
+            Err(PlannerError::NixConfDir(path, _)) if path == Path::new("etc/nix")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(SCRATCH_SPACE_NEEDED), "512.0 MiB");
+        assert_eq!(format_bytes(3 * GIB), "3.0 GiB");
+    }
+
+    fn with_extra_conf(settings: &mut CommonSettings, extra_conf: &[&str]) {
+        settings.extra_conf = extra_conf
+            .iter()
+            .map(|conf| UrlOrPathOrString::String(conf.to_string()))
+            .collect();
+    }
+
+    fn urls(settings: &CommonSettings) -> Vec<String> {
+        substituters(settings)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn probes_the_effective_substituters() -> eyre::Result<()> {
+        let mut settings = CommonSettings::default().await?;
+        assert_eq!(urls(&settings), ["https://cache.nixos.org/"]);
+
+        with_extra_conf(
+            &mut settings,
+            &["extra-substituters = https://cache.example.com s3://bucket"],
+        );
+        assert_eq!(
+            urls(&settings),
+            ["https://cache.nixos.org/", "https://cache.example.com/"]
+        );
+
+        // A mirror replaces the default, extra ones are added after it
+        with_extra_conf(
+            &mut settings,
+            &[
+                "substituters = http://mirror.internal",
+                "extra-substituters = https://cache.example.com",
+            ],
+        );
+        assert_eq!(
+            urls(&settings),
+            ["http://mirror.internal/", "https://cache.example.com/"]
+        );
+
+        // Air gapped, or only substituting from local stores
+        with_extra_conf(&mut settings, &["substituters = file:///mnt/cache"]);
+        assert!(urls(&settings).is_empty());
+        with_extra_conf(&mut settings, &["substituters ="]);
+        assert!(urls(&settings).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connectivity_is_only_checked_for_the_effective_substituters() -> eyre::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_package_url =
+            UrlOrPath::Url(format!("http://127.0.0.1:{port}/nix.tar.xz").parse()?);
+        settings.proxy = None;
+        with_extra_conf(
+            &mut settings,
+            &[&format!("substituters = http://127.0.0.1:{port}")],
+        );
+        check_connectivity(&settings).await?;
+
+        with_extra_conf(&mut settings, &["substituters = http://127.0.0.1:1"]);
+        assert!(matches!(
+            check_connectivity(&settings).await,
+            Err(PlannerError::Unreachable(unreachable)) if unreachable.len() == 1
+        ));
+
+        // Nothing is fetched for a local package
+        settings.nix_package_url = UrlOrPath::Path("nix.tar.xz".into());
+        check_connectivity(&settings).await?;
+        Ok(())
+    }
+}
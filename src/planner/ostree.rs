@@ -6,12 +6,16 @@ use crate::{
         StatefulAction,
     },
     error::HasExpectedErrors,
+    os::tools::{LINUX_USER_TOOLS, SYSTEMD_TOOLS},
     planner::{Planner, PlannerError},
     settings::CommonSettings,
     settings::{InitSystem, InstallSettingsError},
     Action, BuiltinPlanner,
 };
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use super::{
     linux::{
@@ -43,6 +47,9 @@ impl Planner for Ostree {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Provisioned build users may share a group of another name than the one configured
+        let settings =
+            CreateUsersAndGroups::resolve_settings(&self.settings).map_err(PlannerError::Action)?;
         let has_selinux = detect_selinux().await?;
         let mut plan = vec![
             // Primarily for uninstall
@@ -79,7 +86,7 @@ impl Planner for Ostree {
             None,
             0o0644,
             nix_directory_buf,
-            false,
+            self.settings.force,
         )
         .await
         .map_err(PlannerError::Action)?;
@@ -115,7 +122,7 @@ impl Planner for Ostree {
             None,
             0o0644,
             create_bind_mount_buf,
-            false,
+            self.settings.force,
         )
         .await
         .map_err(PlannerError::Action)?;
@@ -144,7 +151,7 @@ impl Planner for Ostree {
             None,
             0o0644,
             ensure_symlinked_units_resolve_buf,
-            false,
+            self.settings.force,
         )
         .await
         .map_err(PlannerError::Action)?;
@@ -172,22 +179,21 @@ impl Planner for Ostree {
         );
 
         plan.push(
-            ProvisionNix::plan(&self.settings.clone())
+            ProvisionNix::plan(&settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
         plan.push(
-            CreateUsersAndGroups::plan(self.settings.clone())
+            CreateUsersAndGroups::plan(settings.clone())
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
         );
-        plan.push(
-            ConfigureNix::plan(shell_profile_locations, &self.settings)
+        plan.extend(
+            ConfigureNix::plan_actions(shell_profile_locations, &settings)
                 .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                .map_err(PlannerError::Action)?,
         );
 
         if has_selinux {
@@ -206,11 +212,21 @@ impl Planner for Ostree {
                 .boxed(),
         );
 
+        plan.extend(super::raise_limits(&self.settings, true).await?);
+
         plan.push(
-            ConfigureInitService::plan(InitSystem::Systemd, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            ConfigureInitService::plan(
+                InitSystem::Systemd,
+                true,
+                self.settings.daemon_env.clone(),
+                self.settings.daemon_limits(),
+                self.settings.nix_ssl_cert_file(),
+                self.settings.nix_conf_dir.clone(),
+                self.settings.proxy.clone(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
         );
         plan.push(
             StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
@@ -279,9 +295,15 @@ impl Planner for Ostree {
             self.settings.ssl_cert_file.clone(),
         )?)
     }
+    async fn resolve_tools(&self) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+        super::resolve_tools(&[LINUX_USER_TOOLS, SYSTEMD_TOOLS].concat(), &self.settings)
+    }
+
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
         check_not_wsl1()?;
 
+        super::check_no_mounts_under_nix().await?;
+
         check_systemd_active()?;
 
         Ok(())
@@ -294,8 +316,18 @@ impl Planner for Ostree {
 
         check_not_wsl1()?;
 
+        super::check_native_architecture(&self.settings)?;
+
         check_systemd_active()?;
 
+        super::check_scratch_space(&self.persistence, &self.settings).await?;
+
+        super::check_build_users(&self.settings)?;
+
+        super::check_nix_conf_dir(&self.settings)?;
+
+        super::check_connectivity(&self.settings).await?;
+
         Ok(())
     }
 }
@@ -96,10 +96,13 @@ To test on a specific build id of the Steam Deck:
 6. Safely turn off the VM!
 
 */
-use std::{collections::HashMap, path::PathBuf, process::Output};
-
-use tokio::process::Command;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    process::Output,
+};
 
+use crate::os::tools;
 use crate::{
     action::{
         base::{CreateDirectory, CreateFile, RemoveDirectory},
@@ -110,6 +113,7 @@ use crate::{
         },
         Action, StatefulAction,
     },
+    os::tools::{LINUX_USER_TOOLS, SYSTEMD_TOOLS},
     planner::{Planner, PlannerError},
     settings::{CommonSettings, InitSystem, InstallSettingsError},
     BuiltinPlanner,
@@ -146,6 +150,9 @@ impl Planner for SteamDeck {
     }
 
     async fn plan(&self) -> Result<Vec<StatefulAction<Box<dyn Action>>>, PlannerError> {
+        // Provisioned build users may share a group of another name than the one configured
+        let settings =
+            CreateUsersAndGroups::resolve_settings(&self.settings).map_err(PlannerError::Action)?;
         // Starting in roughly build ID `20230522.1000`, the Steam Deck has a `/home/.steamos/offload/nix` directory and `nix.mount` unit we can use instead of creating a mountpoint.
         let requires_nix_bind_mount = detect_requires_bind_mount().await?;
 
@@ -209,7 +216,7 @@ impl Planner for SteamDeck {
                 None,
                 0o0644,
                 nix_directory_buf,
-                false,
+                self.settings.force,
             )
             .await
             .map_err(PlannerError::Action)?;
@@ -245,7 +252,7 @@ impl Planner for SteamDeck {
                 None,
                 0o0644,
                 create_bind_mount_buf,
-                false,
+                self.settings.force,
             )
             .await
             .map_err(PlannerError::Action)?;
@@ -290,7 +297,7 @@ impl Planner for SteamDeck {
             None,
             0o0644,
             ensure_symlinked_units_resolve_buf,
-            false,
+            self.settings.force,
         )
         .await
         .map_err(PlannerError::Action)?;
@@ -320,23 +327,35 @@ impl Planner for SteamDeck {
         }
 
         actions.append(&mut vec![
-            ProvisionNix::plan(&self.settings.clone())
+            ProvisionNix::plan(&settings)
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
-            CreateUsersAndGroups::plan(self.settings.clone())
+            CreateUsersAndGroups::plan(settings.clone())
                 .await
                 .map_err(PlannerError::Action)?
                 .boxed(),
-            ConfigureNix::plan(shell_profile_locations, &self.settings)
+        ]);
+        actions.extend(
+            ConfigureNix::plan_actions(shell_profile_locations, &settings)
                 .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+                .map_err(PlannerError::Action)?,
+        );
+        actions.extend(super::raise_limits(&self.settings, true).await?);
+        actions.append(&mut vec![
             // Init is required for the steam-deck archetype to make the `/nix` mount
-            ConfigureInitService::plan(InitSystem::Systemd, true)
-                .await
-                .map_err(PlannerError::Action)?
-                .boxed(),
+            ConfigureInitService::plan(
+                InitSystem::Systemd,
+                true,
+                self.settings.daemon_env.clone(),
+                self.settings.daemon_limits(),
+                self.settings.nix_ssl_cert_file(),
+                self.settings.nix_conf_dir.clone(),
+                self.settings.proxy.clone(),
+            )
+            .await
+            .map_err(PlannerError::Action)?
+            .boxed(),
             StartSystemdUnit::plan("ensure-symlinked-units-resolve.service".to_string(), true)
                 .await
                 .map_err(PlannerError::Action)?
@@ -399,12 +418,18 @@ impl Planner for SteamDeck {
         )?)
     }
 
+    async fn resolve_tools(&self) -> Result<BTreeMap<String, PathBuf>, PlannerError> {
+        super::resolve_tools(&[LINUX_USER_TOOLS, SYSTEMD_TOOLS].concat(), &self.settings)
+    }
+
     async fn pre_uninstall_check(&self) -> Result<(), PlannerError> {
         super::linux::check_not_wsl1()?;
 
         // Unlike the Linux planner, the steam deck planner requires systemd
         super::linux::check_systemd_active()?;
 
+        super::check_no_mounts_under_nix().await?;
+
         Ok(())
     }
 
@@ -415,9 +440,19 @@ impl Planner for SteamDeck {
 
         super::linux::check_not_wsl1()?;
 
+        super::check_native_architecture(&self.settings)?;
+
         // Unlike the Linux planner, the steam deck planner requires systemd
         super::linux::check_systemd_active()?;
 
+        super::check_scratch_space(&self.persistence, &self.settings).await?;
+
+        super::check_build_users(&self.settings)?;
+
+        super::check_nix_conf_dir(&self.settings)?;
+
+        super::check_connectivity(&self.settings).await?;
+
         Ok(())
     }
 }
@@ -456,11 +491,10 @@ pub(crate) async fn detect_requires_bind_mount() -> Result<bool, PlannerError> {
 }
 
 async fn systemctl_status(unit: &str) -> Result<Output, PlannerError> {
-    let mut command = Command::new("systemctl");
+    let mut command = tools::command("systemctl");
     command.arg("status");
     command.arg(unit);
-    let output = command
-        .output()
+    let output = crate::command_runner::output(&mut command)
         .await
         .map_err(|e| PlannerError::Command(format!("{:?}", command.as_std()), e))?;
     Ok(output)
@@ -0,0 +1,185 @@
+/*! How far along long running steps are, like downloading and unpacking Nix
+
+Actions report their progress with a [`Tracker`], and anyone can [`subscribe`] to it: the CLI
+renders it as a line on a terminal, library consumers may render it however they like. Only the
+latest progress is kept, a subscriber which falls behind skips to it. `None` is sent once the step
+is done, whether it succeeded or not.
+
+The progress is of whichever plan is executing in this process.
+*/
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use tokio::sync::watch;
+
+use crate::planner::format_bytes;
+
+/// How far along a step is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// What is being done, like `Downloading Nix`
+    pub task: &'static str,
+    pub done: u64,
+    /// `None` when not known up front, like for a download without a `Content-Length`
+    pub total: Option<u64>,
+    pub unit: ProgressUnit,
+}
+
+/// What [`Progress`] is counted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressUnit {
+    Bytes,
+    /// Entries of an archive or directory
+    Entries,
+}
+
+impl Progress {
+    /// How much of the [`total`](Self::total) is done, in whole percent
+    pub fn percent(&self) -> Option<u64> {
+        self.total
+            .filter(|total| *total > 0)
+            .map(|total| self.done.min(total) * 100 / total)
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |count| match self.unit {
+            ProgressUnit::Bytes => format_bytes(count),
+            ProgressUnit::Entries => format!("{count} entries"),
+        };
+        write!(f, "{}: ", self.task)?;
+        match (self.percent(), self.total) {
+            (Some(percent), Some(total)) => {
+                write!(f, "{percent}% ({} of {})", count(self.done), count(total))
+            },
+            _ => write!(f, "{}", count(self.done)),
+        }
+    }
+}
+
+fn channel() -> &'static watch::Sender<Option<Progress>> {
+    static CURRENT: OnceLock<watch::Sender<Option<Progress>>> = OnceLock::new();
+    CURRENT.get_or_init(|| watch::Sender::new(None))
+}
+
+/// Be told of the progress of every step from now on, see the [module docs](self)
+pub fn subscribe() -> watch::Receiver<Option<Progress>> {
+    channel().subscribe()
+}
+
+/// Reports the progress of a step as it is [`advance`](Tracker::advance)d, and that it is done when dropped
+///
+/// It can be shared between the tasks doing the step, such as the connections of a download.
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    sender: watch::Sender<Option<Progress>>,
+    task: &'static str,
+    total: Option<u64>,
+    unit: ProgressUnit,
+    done: AtomicU64,
+}
+
+impl Tracker {
+    pub(crate) fn new(task: &'static str, total: Option<u64>, unit: ProgressUnit) -> Self {
+        Self::reporting_to(channel().clone(), task, total, unit)
+    }
+
+    fn reporting_to(
+        sender: watch::Sender<Option<Progress>>,
+        task: &'static str,
+        total: Option<u64>,
+        unit: ProgressUnit,
+    ) -> Self {
+        let tracker = Self {
+            sender,
+            task,
+            total,
+            unit,
+            done: AtomicU64::new(0),
+        };
+        tracker.report(0);
+        tracker
+    }
+
+    /// Count `by` more as done
+    pub(crate) fn advance(&self, by: u64) {
+        let done = self.done.fetch_add(by, Ordering::Relaxed) + by;
+        self.report(done);
+    }
+
+    fn report(&self, done: u64) {
+        self.sender.send_replace(Some(Progress {
+            task: self.task,
+            done,
+            total: self.total,
+            unit: self.unit,
+        }));
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.sender.send_replace(None);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shows_percent_when_the_total_is_known() {
+        let mut progress = Progress {
+            task: "Downloading Nix",
+            done: 3 * 1024 * 1024,
+            total: Some(12 * 1024 * 1024),
+            unit: ProgressUnit::Bytes,
+        };
+        assert_eq!(progress.percent(), Some(25));
+        assert_eq!(
+            progress.to_string(),
+            "Downloading Nix: 25% (3.0 MiB of 12.0 MiB)"
+        );
+        progress.total = None;
+        assert_eq!(progress.percent(), None);
+        assert_eq!(progress.to_string(), "Downloading Nix: 3.0 MiB");
+
+        let progress = Progress {
+            task: "Unpacking Nix",
+            done: 1234,
+            total: None,
+            unit: ProgressUnit::Entries,
+        };
+        assert_eq!(progress.to_string(), "Unpacking Nix: 1234 entries");
+    }
+
+    #[test]
+    fn reports_until_dropped() {
+        let (sender, progress) = watch::channel(None);
+        let tracker = Tracker::reporting_to(sender, "Unpacking Nix", None, ProgressUnit::Entries);
+        assert_eq!(
+            progress.borrow().as_ref().map(|progress| progress.done),
+            Some(0)
+        );
+        tracker.advance(4);
+        tracker.advance(6);
+        assert_eq!(
+            *progress.borrow(),
+            Some(Progress {
+                task: "Unpacking Nix",
+                done: 10,
+                total: None,
+                unit: ProgressUnit::Entries,
+            })
+        );
+        drop(tracker);
+        assert_eq!(*progress.borrow(), None);
+    }
+}
@@ -1,8 +1,21 @@
-use std::{process::Output, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    process::Output,
+    time::SystemTime,
+};
 
 use tokio::process::Command;
 use which::which;
 
+use crate::{
+    action::{
+        base::{verify_nix_store, StoreVerifyReport},
+        common::{configure_shell_profile, ConfigureUserNix},
+        ActionErrorKind, ActionState,
+    },
+    InstallPlan,
+};
+
 #[non_exhaustive]
 #[derive(thiserror::Error, Debug, strum::IntoStaticStr)]
 pub enum SelfTestError {
@@ -24,6 +37,23 @@ pub enum SelfTestError {
     },
     #[error(transparent)]
     SystemTime(#[from] std::time::SystemTimeError),
+    #[error("The Nix store database is inconsistent with `/nix/store`, try `nix-store --verify --repair`\n{0}")]
+    StoreInconsistent(StoreVerifyReport),
+    #[error("Verifying the Nix store database")]
+    StoreVerify(#[source] ActionErrorKind),
+    #[error("Nested `{shell}` shells have {} on `PATH`, a shell profile adds it again in every nested shell", entries.iter().map(|(entry, count)| format!("`{entry}` {count} times")).collect::<Vec<_>>().join(", "))]
+    PathDuplicated {
+        shell: Shell,
+        entries: Vec<(String, usize)>,
+    },
+    #[error("`{}` no longer has {missing}, configured with `--user-nix-conf` or `--prompt-integration`", path.display())]
+    UserConfiguration { path: PathBuf, missing: String },
+    #[error("`{name}` is {value}, large Nix builds may fail with \"too many open files\" below {minimum}, reinstall with `--raise-limits` or raise it yourself")]
+    LimitTooLow {
+        name: &'static str,
+        value: u64,
+        minimum: u64,
+    },
 }
 
 #[cfg(feature = "diagnostics")]
@@ -34,6 +64,11 @@ impl crate::diagnostics::ErrorDiagnostic for SelfTestError {
             Self::ShellFailed { shell, .. } => vec![shell.to_string()],
             Self::Command { shell, .. } => vec![shell.to_string()],
             Self::SystemTime(_) => vec![],
+            Self::StoreInconsistent(report) => vec![report.unrepaired().len().to_string()],
+            Self::StoreVerify(_) => vec![],
+            Self::PathDuplicated { shell, .. } => vec![shell.to_string()],
+            Self::UserConfiguration { .. } => vec![],
+            Self::LimitTooLow { name, .. } => vec![name.to_string()],
         };
         format!(
             "{}({})",
@@ -74,22 +109,20 @@ impl Shell {
         }
     }
 
+    /// The flag running a command in a shell which reads the profiles
+    fn profile_flag(&self) -> &'static str {
+        match &self {
+            // On Mac, `bash -ic nix` won't work, but `bash -lc nix` will.
+            Shell::Sh | Shell::Bash => "-lc",
+            Shell::Zsh | Shell::Fish => "-ic",
+        }
+    }
+
     #[tracing::instrument(skip_all)]
-    pub async fn self_test(&self) -> Result<(), SelfTestError> {
+    pub async fn self_test(&self, nix_conf_dir: Option<&Path>) -> Result<(), SelfTestError> {
         let executable = self.executable();
-        let mut command = match &self {
-            // On Mac, `bash -ic nix` won't work, but `bash -lc nix` will.
-            Shell::Sh | Shell::Bash => {
-                let mut command = Command::new(executable);
-                command.arg("-lc");
-                command
-            },
-            Shell::Zsh | Shell::Fish => {
-                let mut command = Command::new(executable);
-                command.arg("-ic");
-                command
-            },
-        };
+        let mut command = Command::new(executable);
+        command.arg(self.profile_flag());
 
         #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
         const SYSTEM: &str = "x86_64-linux";
@@ -102,6 +135,11 @@ impl Shell {
         #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
         const SYSTEM: &str = "aarch64-darwin";
 
+        // Shell profiles only export it for new shells, this may be running in one from before the install
+        if let Some(nix_conf_dir) = nix_conf_dir {
+            command.env("NIX_CONF_DIR", nix_conf_dir);
+        }
+
         let timestamp_millis = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
@@ -115,8 +153,7 @@ impl Shell {
             command = command_str,
             "Testing Nix install via `{executable}`"
         );
-        let output = command
-            .output()
+        let output = crate::command_runner::output(&mut command)
             .await
             .map_err(|error| SelfTestError::Command {
                 shell: *self,
@@ -124,13 +161,45 @@ impl Shell {
                 error,
             })?;
 
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err(SelfTestError::ShellFailed {
+        if !output.status.success() {
+            return Err(SelfTestError::ShellFailed {
+                shell: *self,
+                command: command_str,
+                output,
+            });
+        }
+        self.check_nested_path().await
+    }
+
+    /// Check the `PATH` of a shell started from another doesn't have the Nix entries yet again
+    async fn check_nested_path(&self) -> Result<(), SelfTestError> {
+        let (executable, flag) = (self.executable(), self.profile_flag());
+        let mut command = Command::new(executable);
+        command.args([flag, &format!("{executable} {flag} 'printenv PATH'")]);
+        let command_str = format!("{:?}", command.as_std());
+        let output = crate::command_runner::output(&mut command)
+            .await
+            .map_err(|error| SelfTestError::Command {
+                shell: *self,
+                command: command_str.clone(),
+                error,
+            })?;
+        if !output.status.success() {
+            return Err(SelfTestError::ShellFailed {
                 shell: *self,
                 command: command_str,
                 output,
+            });
+        }
+        let entries = configure_shell_profile::nix_path_duplicates(
+            String::from_utf8_lossy(&output.stdout).trim(),
+        );
+        if entries.is_empty() {
+            Ok(())
+        } else {
+            Err(SelfTestError::PathDuplicated {
+                shell: *self,
+                entries,
             })
         }
     }
@@ -149,21 +218,78 @@ impl Shell {
 }
 
 #[tracing::instrument(skip_all)]
-pub async fn self_test() -> Result<(), Vec<SelfTestError>> {
+pub async fn self_test(nix_conf_dir: Option<&Path>) -> Result<(), Vec<SelfTestError>> {
     let shells = Shell::discover();
 
     let mut failures = vec![];
 
     for shell in shells {
-        match shell.self_test().await {
+        match shell.self_test(nix_conf_dir).await {
             Ok(()) => (),
             Err(err) => failures.push(err),
         }
     }
 
+    // Only `root` can open the store database directly
+    if nix::unistd::Uid::effective().is_root() {
+        if let Some(nix_store) = verify_nix_store::nix_store_binary() {
+            match verify_nix_store::verify_store(&nix_store, false).await {
+                Ok(report) if report.is_badly_inconsistent() => {
+                    failures.push(SelfTestError::StoreInconsistent(report))
+                },
+                Ok(report) => tracing::debug!("{report}"),
+                Err(err) => failures.push(SelfTestError::StoreVerify(err)),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    failures.extend(
+        crate::os::limits::low_limits(Path::new("/"), Path::new("/run/systemd/system").is_dir())
+            .into_iter()
+            .map(|low| SelfTestError::LimitTooLow {
+                name: low.limit.name,
+                value: low.value,
+                minimum: low.limit.minimum,
+            }),
+    );
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// [`self_test`], and that what the `plan` configured for users is still in place
+#[tracing::instrument(skip_all)]
+pub async fn self_test_plan(plan: &InstallPlan) -> Result<(), Vec<SelfTestError>> {
+    let mut failures = self_test(plan.nix_conf_dir().as_deref())
+        .await
+        .err()
+        .unwrap_or_default();
+    failures.extend(self_test_user_configuration(plan));
+
     if failures.is_empty() {
         Ok(())
     } else {
         Err(failures)
     }
 }
+
+fn self_test_user_configuration(plan: &InstallPlan) -> Vec<SelfTestError> {
+    plan.actions
+        .iter()
+        .filter(|action| {
+            action.inner_typetag_name() == "configure_user_nix"
+                && action.state == ActionState::Completed
+        })
+        .filter_map(|action| {
+            serde_json::to_value(&action.action)
+                .and_then(serde_json::from_value::<ConfigureUserNix>)
+                .ok()
+        })
+        .flat_map(|configure_user_nix| configure_user_nix.verify())
+        .map(|(path, missing)| SelfTestError::UserConfiguration { path, missing })
+        .collect()
+}
@@ -1,6 +1,12 @@
 /*! Configurable knobs and their related errors
 */
-use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 #[cfg(feature = "cli")]
 use clap::{
@@ -9,8 +15,47 @@ use clap::{
 };
 use url::Url;
 
+use crate::action::common::{DaemonLimits, NixMachine};
+
 pub const SCRATCH_DIR: &str = "/nix/temp-install-dir";
 
+/// Default [`max_buffer_size`](CommonSettings::max_buffer_size), 4 MiB
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+fn default_max_buffer_size() -> usize {
+    DEFAULT_MAX_BUFFER_SIZE
+}
+
+/// Settings predating [`create_users`](CommonSettings::create_users) always created the build users
+fn default_create_users() -> bool {
+    true
+}
+
+/// Default [`download_connections`](CommonSettings::download_connections), a single stream
+pub const DEFAULT_DOWNLOAD_CONNECTIONS: u8 = 1;
+
+fn default_download_connections() -> u8 {
+    DEFAULT_DOWNLOAD_CONNECTIONS
+}
+
+/// Default [`download_attempts`](CommonSettings::download_attempts)
+pub const DEFAULT_DOWNLOAD_ATTEMPTS: u8 = 3;
+
+fn default_download_attempts() -> u8 {
+    DEFAULT_DOWNLOAD_ATTEMPTS
+}
+
+/// The [`nix_build_user_count`](CommonSettings::nix_build_user_count) allowed, each build user is an account
+pub const BUILD_USER_COUNT_RANGE: RangeInclusive<u32> = 1..=512;
+/// More build users than this are unusual enough to warn about
+const MANY_BUILD_USERS: u32 = 64;
+/// The highest ID a build user may get, IDs from 501 are those of people's accounts
+#[cfg(target_os = "macos")]
+pub const MAX_BUILD_USER_ID: u32 = 500;
+/// The highest ID a build user may get, below `nobody` and within the 65536 IDs containers usually map
+#[cfg(not(target_os = "macos"))]
+pub const MAX_BUILD_USER_ID: u32 = 65_533;
+
 /// Default [`nix_package_url`](CommonSettings::nix_package_url) for Linux x86_64
 pub const NIX_X64_64_LINUX_URL: &str =
     "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-x86_64-linux.tar.xz";
@@ -27,6 +72,77 @@ pub const NIX_X64_64_DARWIN_URL: &str =
 pub const NIX_AARCH64_DARWIN_URL: &str =
     "https://releases.nixos.org/nix/nix-2.18.1/nix-2.18.1-aarch64-darwin.tar.xz";
 
+/// An IP address family
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IpFamily::Ipv4 => write!(f, "IPv4"),
+            IpFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Where Nix reads `nix.conf` from, unless `--nix-conf-dir` moves it
+pub const NIX_CONF_DIR: &str = "/etc/nix";
+
+/// The CA bundle Nix should use, `ssl_cert_file` itself or the bundle it is appended to with `append_corp_ca`
+pub fn nix_ssl_cert_file(
+    ssl_cert_file: Option<&Path>,
+    append_corp_ca: bool,
+    nix_conf_dir: &Path,
+) -> Option<PathBuf> {
+    let ssl_cert_file = ssl_cert_file?;
+    if append_corp_ca {
+        Some(nix_conf_dir.join(crate::action::base::NIX_CA_BUNDLE_NAME))
+    } else {
+        Some(
+            ssl_cert_file
+                .canonicalize()
+                .unwrap_or_else(|_| ssl_cert_file.to_path_buf()),
+        )
+    }
+}
+
+/// Parses a `--profile-targets`, refusing relative paths, which would be written relative to wherever the installer ran
+pub fn absolute_profile_target(input: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(input);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(format!(
+            "`{input}` is relative, shell profile targets must be absolute paths"
+        ))
+    }
+}
+
+/// Parses a `--nix-package-file`, which must be an existing file, made absolute so the plan reads the same one from anywhere
+pub fn nix_package_file(input: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(input);
+    if !path.is_file() {
+        return Err(format!("`{input}` does not exist or is not a file"));
+    }
+    std::path::absolute(&path).map_err(|e| format!("Making `{input}` absolute: {e}"))
+}
+
+/// Parses a `--allow-path` or `--deny-path`, refusing relative paths, which couldn't be compared to what is written
+pub fn absolute_policy_path(input: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(input);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(format!(
+            "`{input}` is relative, allowed and denied paths must be absolute"
+        ))
+    }
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum InitSystem {
@@ -73,6 +189,87 @@ pub struct CommonSettings {
     )]
     pub modify_profile: bool,
 
+    /// Write only the most conservative POSIX shell to the shell profiles, even those only bash or zsh read
+    ///
+    /// For systems where `/etc/profile` is read by `dash` or `busybox ash`, or by older shells still.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_POSIX_ONLY_PROFILE"
+        )
+    )]
+    #[serde(default)]
+    pub posix_only_profile: bool,
+
+    /// Also write the shell hook to this shell profile, for setups the defaults miss (such as a custom `/etc/zsh/zprofile`), may be repeated
+    ///
+    /// Must be absolute. Nix is sourced with POSIX shell, so only profiles read by `sh` compatible shells fit.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action = ArgAction::Append,
+            value_delimiter = ',',
+            value_parser = absolute_profile_target,
+            global = true,
+            env = "NIX_INSTALLER_PROFILE_TARGETS"
+        )
+    )]
+    #[serde(default)]
+    pub profile_targets: Vec<PathBuf>,
+
+    /// Write the shell hook only to the `--profile-targets`, instead of to them and the default bash and zsh profiles
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            requires = "profile_targets",
+            env = "NIX_INSTALLER_REPLACE_PROFILE_TARGETS"
+        )
+    )]
+    #[serde(default)]
+    pub replace_profile_targets: bool,
+
+    /// Settings (`key = value`) for the `~/.config/nix/nix.conf` of `root` and the user who ran the installer with `sudo`
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_USER_NIX_CONF", global = true))]
+    #[serde(default)]
+    pub user_nix_conf: Vec<String>,
+
+    /// Mark the prompt of shells in `nix-shell` or `nix develop`, in the `~/.bashrc` or `~/.zshrc` of `root` and the user who ran the installer with `sudo`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_PROMPT_INTEGRATION"
+        )
+    )]
+    #[serde(default)]
+    pub prompt_integration: bool,
+
+    /// Tell every user logging in that Nix is installed, in `/etc/motd.d/nix` or a block appended to `/etc/motd`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_WRITE_MOTD"
+        )
+    )]
+    #[serde(default)]
+    pub write_motd: bool,
+
     /// The Nix build group name
     #[cfg_attr(
         feature = "cli",
@@ -142,6 +339,23 @@ pub struct CommonSettings {
     )]
     pub nix_build_user_id_base: u32,
 
+    /// Use build users (and their group) already provisioned by identity management, named by `--nix-build-user-prefix`, instead of creating them
+    ///
+    /// The users may be numbered with zero padding (such as `svc_nixbld01`), they are used with the
+    /// UIDs they have and are left in place when uninstalling.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            action(ArgAction::SetFalse),
+            default_value = "true",
+            global = true,
+            env = "NIX_INSTALLER_CREATE_USERS",
+            long = "no-create-users"
+        )
+    )]
+    #[serde(default = "default_create_users")]
+    pub create_users: bool,
+
     /// The Nix package URL
     #[cfg_attr(
         feature = "cli",
@@ -179,18 +393,182 @@ pub struct CommonSettings {
     )]
     pub nix_package_url: UrlOrPath,
 
+    /// The SHA-256 (in hex) the Nix package must have, required when it is read from stdin with `--nix-package-url -`
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_NIX_PACKAGE_SHA256", global = true)
+    )]
+    #[serde(default)]
+    pub nix_package_sha256: Option<String>,
+
+    /// Install from this local Nix tarball (such as `nix-2.24.9-x86_64-linux.tar.xz`) instead of fetching `--nix-package-url`, for hosts without network access
+    ///
+    /// Nothing is fetched then, diagnostics are still sent unless `--diagnostic-endpoint ""` is given.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            env = "NIX_INSTALLER_NIX_PACKAGE_FILE",
+            global = true,
+            conflicts_with = "nix_package_url",
+            value_parser = nix_package_file
+        )
+    )]
+    #[serde(default)]
+    pub nix_package_file: Option<PathBuf>,
+
     /// The proxy to use (if any), valid proxy bases are `https://$URL`, `http://$URL` and `socks5://$URL`
+    ///
+    /// It (or else the proxy in the environment) is also set as `http_proxy` and `https_proxy` for the daemon.
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_PROXY"))]
     pub proxy: Option<Url>,
 
+    /// Only modify paths under this one (after following symlinks), refusing to plan otherwise, may be repeated
+    ///
+    /// Must be absolute. Without any, every path the install needs may be modified.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action = ArgAction::Append,
+            value_delimiter = ',',
+            value_parser = absolute_policy_path,
+            global = true,
+            env = "NIX_INSTALLER_ALLOW_PATH"
+        )
+    )]
+    #[serde(default)]
+    pub allow_path: Vec<PathBuf>,
+
+    /// Never modify paths under this one (after following symlinks), refusing to plan otherwise, may be repeated
+    ///
+    /// Must be absolute. Wins over `--allow-path`.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action = ArgAction::Append,
+            value_delimiter = ',',
+            value_parser = absolute_policy_path,
+            global = true,
+            env = "NIX_INSTALLER_DENY_PATH"
+        )
+    )]
+    #[serde(default)]
+    pub deny_path: Vec<PathBuf>,
+
+    /// Write `nix.conf` (and the other Nix configuration files) here instead of `/etc/nix`, for hosts where it is read only
+    ///
+    /// The daemon and shells are pointed at it with `NIX_CONF_DIR`.
+    #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_NIX_CONF_DIR"))]
+    #[serde(default)]
+    pub nix_conf_dir: Option<PathBuf>,
+
     /// An SSL cert to use (if any), used for fetching Nix and sets `ssl-cert-file` in `/etc/nix/nix.conf`
     #[cfg_attr(feature = "cli", clap(long, env = "NIX_INSTALLER_SSL_CERT_FILE"))]
     pub ssl_cert_file: Option<PathBuf>,
 
+    /// Append `--ssl-cert-file` to the CA bundle shipped with Nix in `/etc/nix/ca-bundle.crt`, instead of trusting only its CAs
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            requires = "ssl_cert_file",
+            env = "NIX_INSTALLER_APPEND_CORP_CA",
+        )
+    )]
+    #[serde(default)]
+    pub append_corp_ca: bool,
+
+    /// Connect to IPv4 addresses first when fetching Nix, for hosts with broken IPv6
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            conflicts_with = "prefer_ipv6",
+            env = "NIX_INSTALLER_PREFER_IPV4",
+        )
+    )]
+    #[serde(default)]
+    pub prefer_ipv4: bool,
+
+    /// Connect to IPv6 addresses first when fetching Nix, for IPv6-only hosts
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            env = "NIX_INSTALLER_PREFER_IPV6",
+        )
+    )]
+    #[serde(default)]
+    pub prefer_ipv6: bool,
+
     /// Extra configuration lines for `/etc/nix.conf`
     #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_EXTRA_CONF", global = true))]
     pub extra_conf: Vec<UrlOrPathOrString>,
 
+    /// Remote builders to write to `/etc/nix/machines`, in the machines file syntax (eg. `ssh://user@host x86_64-linux /key 8 1 kvm`)
+    #[cfg_attr(feature = "cli", clap(long = "builder", action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_BUILDERS", global = true))]
+    #[serde(default)]
+    pub builders: Vec<NixMachine>,
+
+    /// Environment variables (`KEY=VALUE`) to set for the Nix daemon, rendered into a systemd drop-in
+    #[cfg_attr(feature = "cli", clap(long, action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_DAEMON_ENV", global = true))]
+    #[serde(default)]
+    pub daemon_env: Vec<String>,
+
+    /// The `CPUWeight=` of the Nix daemon and its builds, between `1` and `10000` (systemd only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DAEMON_CPU_WEIGHT", global = true)
+    )]
+    #[serde(default)]
+    pub daemon_cpu_weight: Option<u64>,
+
+    /// The `MemoryMax=` of the Nix daemon and its builds, like `8G`, `50%` or `infinity` (systemd only)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DAEMON_MEMORY_MAX", global = true)
+    )]
+    #[serde(default)]
+    pub daemon_memory_max: Option<String>,
+
+    /// The `TasksMax=` of the Nix daemon and its builds, like `4096`, `50%` or `infinity` (a number of processes with launchd)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DAEMON_TASKS_MAX", global = true)
+    )]
+    #[serde(default)]
+    pub daemon_tasks_max: Option<String>,
+
+    /// Rate limit the Nix daemon's journal logging as `BURST/INTERVAL`, like `10000/30s` (on MacOS, log to rotated files under `/var/log/nix` instead)
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_DAEMON_LOG_LIMIT", global = true)
+    )]
+    #[serde(default)]
+    pub daemon_log_limit: Option<String>,
+
+    /// Raise the open file and inotify limits large builds need where they are low, in `/etc/sysctl.d` and the daemon's `LimitNOFILE=`
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_RAISE_LIMITS"
+        )
+    )]
+    #[serde(default)]
+    pub raise_limits: bool,
+
     /// If `nix-installer` should forcibly recreate files it finds existing
     #[cfg_attr(
         feature = "cli",
@@ -204,6 +582,118 @@ pub struct CommonSettings {
     )]
     pub force: bool,
 
+    /// Repair the Nix store database (`nix-store --verify --repair`) if verifying it finds inconsistencies
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_REPAIR_STORE"
+        )
+    )]
+    #[serde(default)]
+    pub repair_store: bool,
+
+    /// Change the group of an existing `/nix/store` owned by another GID to the build group's, instead of adopting its GID (this can take minutes on large stores)
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_REGROUP_STORE"
+        )
+    )]
+    #[serde(default)]
+    pub regroup_store: bool,
+
+    /// Turn off settings of `nix.conf` the kernel lacks features for (eg `auto-allocate-uids` without user namespaces) with a warning, instead of failing
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_RELAX_UNSUPPORTED_SETTINGS"
+        )
+    )]
+    #[serde(default)]
+    pub relax_unsupported_settings: bool,
+
+    /// The maximum size (in bytes) of in-memory buffers used while fetching and unpacking Nix, everything else is streamed to disk
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_MAX_BUFFER_SIZE,
+            env = "NIX_INSTALLER_MAX_BUFFER_SIZE",
+            global = true
+        )
+    )]
+    #[serde(default = "default_max_buffer_size")]
+    pub max_buffer_size: usize,
+
+    /// The number of connections to download the Nix package over, each fetching a range of it, when the server supports ranges
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_DOWNLOAD_CONNECTIONS,
+            value_parser = clap::value_parser!(u8).range(1..=16),
+            env = "NIX_INSTALLER_DOWNLOAD_CONNECTIONS",
+            global = true
+        )
+    )]
+    #[serde(default = "default_download_connections")]
+    pub download_connections: u8,
+
+    /// How many times to try downloading the Nix package, retrying dropped or failed connections and server errors with exponential backoff
+    ///
+    /// An interrupted download is resumed where the server supports ranges.
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            default_value_t = DEFAULT_DOWNLOAD_ATTEMPTS,
+            value_parser = clap::value_parser!(u8).range(1..=10),
+            env = "NIX_INSTALLER_DOWNLOAD_ATTEMPTS",
+            global = true
+        )
+    )]
+    #[serde(default = "default_download_attempts")]
+    pub download_attempts: u8,
+
+    /// Seconds to wait for the system clock to be synchronized (such as by cloud-init) when it is too far off to fetch the Nix package over TLS, before retrying once
+    #[cfg_attr(
+        feature = "cli",
+        clap(long, env = "NIX_INSTALLER_WAIT_FOR_CLOCK_SYNC", global = true)
+    )]
+    #[serde(default)]
+    pub wait_for_clock_sync: Option<u64>,
+
+    /// Skip checking the filesystem Nix is unpacked onto has room for it, such as a small tmpfs
+    #[cfg_attr(
+        feature = "cli",
+        clap(
+            long,
+            action(ArgAction::SetTrue),
+            default_value = "false",
+            global = true,
+            env = "NIX_INSTALLER_SKIP_SPACE_CHECK"
+        )
+    )]
+    #[serde(default)]
+    pub skip_space_check: bool,
+
+    /// Where to find an external tool (`NAME=PATH`, eg. `useradd=/opt/shadow/sbin/useradd`), in place of searching `PATH` and the standard locations
+    #[cfg_attr(feature = "cli", clap(long = "tool-path", action = ArgAction::Append, num_args = 0.., env = "NIX_INSTALLER_TOOL_PATHS", global = true))]
+    #[serde(default)]
+    pub tool_paths: Vec<ToolPath>,
+
     #[cfg(feature = "diagnostics")]
     /// Relate the install diagnostic to a specific value
     #[cfg_attr(
@@ -303,16 +793,47 @@ impl CommonSettings {
 
         Ok(Self {
             modify_profile: true,
+            posix_only_profile: false,
+            profile_targets: Default::default(),
+            replace_profile_targets: false,
+            user_nix_conf: Default::default(),
+            prompt_integration: false,
+            write_motd: false,
             nix_build_group_name: String::from("nixbld"),
             nix_build_group_id: 30_000,
             nix_build_user_id_base,
             nix_build_user_count,
             nix_build_user_prefix: nix_build_user_prefix.to_string(),
+            create_users: true,
             nix_package_url: url.parse()?,
+            nix_package_sha256: None,
+            nix_package_file: None,
             proxy: Default::default(),
+            allow_path: Default::default(),
+            deny_path: Default::default(),
             extra_conf: Default::default(),
+            builders: Default::default(),
+            daemon_env: Default::default(),
+            daemon_cpu_weight: Default::default(),
+            daemon_memory_max: Default::default(),
+            daemon_tasks_max: Default::default(),
+            daemon_log_limit: Default::default(),
+            raise_limits: false,
             force: false,
+            repair_store: false,
+            regroup_store: false,
+            relax_unsupported_settings: false,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            download_connections: DEFAULT_DOWNLOAD_CONNECTIONS,
+            download_attempts: DEFAULT_DOWNLOAD_ATTEMPTS,
+            wait_for_clock_sync: None,
+            skip_space_check: false,
+            tool_paths: Default::default(),
+            nix_conf_dir: None,
             ssl_cert_file: Default::default(),
+            append_corp_ca: false,
+            prefer_ipv4: false,
+            prefer_ipv6: false,
             #[cfg(feature = "diagnostics")]
             diagnostic_attribution: None,
             #[cfg(feature = "diagnostics")]
@@ -320,20 +841,138 @@ impl CommonSettings {
         })
     }
 
+    /// Hook Nix into the shell profiles, with `false` the plan leaves them to the user (like `--no-modify-profile`)
+    pub fn modify_profile(&mut self, toggle: bool) -> &mut Self {
+        self.modify_profile = toggle;
+        self
+    }
+
+    /// The CA bundle Nix, its daemon and shells should use
+    pub fn nix_ssl_cert_file(&self) -> Option<PathBuf> {
+        nix_ssl_cert_file(
+            self.ssl_cert_file.as_deref(),
+            self.append_corp_ca,
+            &self.conf_dir(),
+        )
+    }
+
+    /// The directory Nix's configuration is written to, `--nix-conf-dir` or [`NIX_CONF_DIR`]
+    pub fn conf_dir(&self) -> PathBuf {
+        self.nix_conf_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(NIX_CONF_DIR))
+    }
+
+    /// Where Nix is installed from, `--nix-package-file` or else `--nix-package-url`
+    pub fn nix_package(&self) -> UrlOrPath {
+        match &self.nix_package_file {
+            Some(file) => UrlOrPath::Path(file.clone()),
+            None => self.nix_package_url.clone(),
+        }
+    }
+
+    /// The address family to connect with first when fetching Nix, if any
+    pub fn preferred_ip_family(&self) -> Option<IpFamily> {
+        match (self.prefer_ipv4, self.prefer_ipv6) {
+            (true, _) => Some(IpFamily::Ipv4),
+            (false, true) => Some(IpFamily::Ipv6),
+            (false, false) => None,
+        }
+    }
+
+    /**
+    Ensure the [build user count](CommonSettings::nix_build_user_count) is in [`BUILD_USER_COUNT_RANGE`]
+    and their IDs, counting up from the [base](CommonSettings::nix_build_user_id_base), stay at or below
+    [`MAX_BUILD_USER_ID`], returning what is unusual about them
+    */
+    pub fn check_build_users(&self) -> Result<Vec<String>, InstallSettingsError> {
+        let count = self.nix_build_user_count;
+        if !BUILD_USER_COUNT_RANGE.contains(&count) {
+            return Err(InstallSettingsError::BuildUserCount(count));
+        }
+        let first = u64::from(self.nix_build_user_id_base) + 1;
+        let last = u64::from(self.nix_build_user_id_base) + u64::from(count);
+        // Provisioned users keep the IDs they were given
+        if self.create_users && last > u64::from(MAX_BUILD_USER_ID) {
+            return Err(InstallSettingsError::BuildUserIds { first, last });
+        }
+        let mut warnings = vec![];
+        if count > MANY_BUILD_USERS {
+            warnings.push(format!(
+                "Creating {count} build users, more than the {MANY_BUILD_USERS} even large machines usually need, each of them is an account"
+            ));
+        }
+        Ok(warnings)
+    }
+
+    /// A note that fewer builds than the machine has `cpus` can run at once, if so
+    pub fn build_parallelism_note(&self, cpus: usize) -> Option<String> {
+        let count = self.nix_build_user_count;
+        (usize::try_from(count).unwrap_or(usize::MAX) < cpus).then(|| {
+            format!(
+                "With {count} build users only {count} builds can run at once, this machine has {cpus} CPUs, pass `--nix-build-user-count` to run more"
+            )
+        })
+    }
+
+    /// The resource limits for the Nix daemon
+    pub fn daemon_limits(&self) -> DaemonLimits {
+        DaemonLimits {
+            cpu_weight: self.daemon_cpu_weight,
+            memory_max: self.daemon_memory_max.clone(),
+            tasks_max: self.daemon_tasks_max.clone(),
+            log_limit: self.daemon_log_limit.clone(),
+            nofile: self
+                .raise_limits
+                .then_some(crate::os::limits::SERVICE_NOFILE.raised_to),
+        }
+    }
+
     /// A listing of the settings, suitable for [`Planner::settings`](crate::planner::Planner::settings)
     pub fn settings(&self) -> Result<HashMap<String, serde_json::Value>, InstallSettingsError> {
         let Self {
             modify_profile,
+            posix_only_profile,
+            profile_targets,
+            replace_profile_targets,
+            user_nix_conf,
+            prompt_integration,
+            write_motd,
             nix_build_group_name,
             nix_build_group_id,
             nix_build_user_prefix,
             nix_build_user_id_base,
             nix_build_user_count,
+            create_users,
             nix_package_url,
+            nix_package_sha256,
+            nix_package_file,
             proxy,
+            allow_path,
+            deny_path,
             extra_conf,
+            builders,
+            daemon_env,
+            daemon_cpu_weight,
+            daemon_memory_max,
+            daemon_tasks_max,
+            daemon_log_limit,
+            raise_limits,
             force,
+            repair_store,
+            regroup_store,
+            relax_unsupported_settings,
+            max_buffer_size,
+            download_connections,
+            download_attempts,
+            wait_for_clock_sync,
+            skip_space_check,
+            tool_paths,
+            nix_conf_dir,
             ssl_cert_file,
+            append_corp_ca,
+            prefer_ipv4,
+            prefer_ipv6,
             #[cfg(feature = "diagnostics")]
                 diagnostic_attribution: _,
             #[cfg(feature = "diagnostics")]
@@ -345,6 +984,24 @@ impl CommonSettings {
             "modify_profile".into(),
             serde_json::to_value(modify_profile)?,
         );
+        map.insert(
+            "posix_only_profile".into(),
+            serde_json::to_value(posix_only_profile)?,
+        );
+        map.insert(
+            "profile_targets".into(),
+            serde_json::to_value(profile_targets)?,
+        );
+        map.insert(
+            "replace_profile_targets".into(),
+            serde_json::to_value(replace_profile_targets)?,
+        );
+        map.insert("user_nix_conf".into(), serde_json::to_value(user_nix_conf)?);
+        map.insert(
+            "prompt_integration".into(),
+            serde_json::to_value(prompt_integration)?,
+        );
+        map.insert("write_motd".into(), serde_json::to_value(write_motd)?);
         map.insert(
             "nix_build_group_name".into(),
             serde_json::to_value(nix_build_group_name)?,
@@ -365,14 +1022,78 @@ impl CommonSettings {
             "nix_build_user_count".into(),
             serde_json::to_value(nix_build_user_count)?,
         );
+        map.insert("create_users".into(), serde_json::to_value(create_users)?);
         map.insert(
             "nix_package_url".into(),
             serde_json::to_value(nix_package_url)?,
         );
+        map.insert(
+            "nix_package_sha256".into(),
+            serde_json::to_value(nix_package_sha256)?,
+        );
+        map.insert(
+            "nix_package_file".into(),
+            serde_json::to_value(nix_package_file)?,
+        );
         map.insert("proxy".into(), serde_json::to_value(proxy)?);
+        map.insert("allow_path".into(), serde_json::to_value(allow_path)?);
+        map.insert("deny_path".into(), serde_json::to_value(deny_path)?);
+        map.insert("nix_conf_dir".into(), serde_json::to_value(nix_conf_dir)?);
         map.insert("ssl_cert_file".into(), serde_json::to_value(ssl_cert_file)?);
+        map.insert(
+            "append_corp_ca".into(),
+            serde_json::to_value(append_corp_ca)?,
+        );
+        map.insert("prefer_ipv4".into(), serde_json::to_value(prefer_ipv4)?);
+        map.insert("prefer_ipv6".into(), serde_json::to_value(prefer_ipv6)?);
         map.insert("extra_conf".into(), serde_json::to_value(extra_conf)?);
+        map.insert("builders".into(), serde_json::to_value(builders)?);
+        map.insert("daemon_env".into(), serde_json::to_value(daemon_env)?);
+        map.insert(
+            "daemon_cpu_weight".into(),
+            serde_json::to_value(daemon_cpu_weight)?,
+        );
+        map.insert(
+            "daemon_memory_max".into(),
+            serde_json::to_value(daemon_memory_max)?,
+        );
+        map.insert(
+            "daemon_tasks_max".into(),
+            serde_json::to_value(daemon_tasks_max)?,
+        );
+        map.insert(
+            "daemon_log_limit".into(),
+            serde_json::to_value(daemon_log_limit)?,
+        );
+        map.insert("raise_limits".into(), serde_json::to_value(raise_limits)?);
         map.insert("force".into(), serde_json::to_value(force)?);
+        map.insert("repair_store".into(), serde_json::to_value(repair_store)?);
+        map.insert("regroup_store".into(), serde_json::to_value(regroup_store)?);
+        map.insert(
+            "relax_unsupported_settings".into(),
+            serde_json::to_value(relax_unsupported_settings)?,
+        );
+        map.insert(
+            "max_buffer_size".into(),
+            serde_json::to_value(max_buffer_size)?,
+        );
+        map.insert(
+            "download_connections".into(),
+            serde_json::to_value(download_connections)?,
+        );
+        map.insert(
+            "download_attempts".into(),
+            serde_json::to_value(download_attempts)?,
+        );
+        map.insert(
+            "wait_for_clock_sync".into(),
+            serde_json::to_value(wait_for_clock_sync)?,
+        );
+        map.insert(
+            "skip_space_check".into(),
+            serde_json::to_value(skip_space_check)?,
+        );
+        map.insert("tool_paths".into(), serde_json::to_value(tool_paths)?);
 
         #[cfg(feature = "diagnostics")]
         map.insert(
@@ -390,16 +1111,17 @@ async fn linux_detect_systemd_started() -> bool {
 
     let mut started = false;
     if std::path::Path::new("/run/systemd/system").exists() {
-        started = tokio::process::Command::new("systemctl")
-            .arg("status")
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()
-            .await
-            .ok()
-            .map(|exit| exit.success())
-            .unwrap_or(false)
+        started = crate::command_runner::output(
+            crate::os::tools::command("systemctl")
+                .arg("status")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )
+        .await
+        .ok()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
     }
 
     // TODO: Other inits
@@ -518,6 +1240,12 @@ pub enum InstallSettingsError {
     InitNotSupported,
     #[error(transparent)]
     UrlOrPath(#[from] UrlOrPathError),
+    /// See [`CommonSettings::check_build_users`]
+    #[error("`--nix-build-user-count` must be between {} and {}, got {0}", BUILD_USER_COUNT_RANGE.start(), BUILD_USER_COUNT_RANGE.end())]
+    BuildUserCount(u32),
+    /// See [`CommonSettings::check_build_users`]
+    #[error("The build users would get the IDs {first} to {last}, above {} where IDs of other accounts begin, lower `--nix-build-user-id-base` or `--nix-build-user-count`", MAX_BUILD_USER_ID)]
+    BuildUserIds { first: u64, last: u64 },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -532,10 +1260,54 @@ pub enum UrlOrPathError {
     Io(PathBuf, #[source] std::io::Error),
 }
 
+/// The location of an external tool, overriding where it would be found
+#[derive(
+    Debug, Clone, PartialEq, Eq, serde_with::SerializeDisplay, serde_with::DeserializeFromStr,
+)]
+pub struct ToolPath {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Display for ToolPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.path.display())
+    }
+}
+
+impl FromStr for ToolPath {
+    type Err = ToolPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s
+            .split_once('=')
+            .filter(|(name, path)| !name.is_empty() && !path.is_empty())
+            .ok_or_else(|| ToolPathError::Format(s.to_string()))?;
+        let path = PathBuf::from(path);
+        if !path.is_absolute() {
+            return Err(ToolPathError::NotAbsolute(name.to_string(), path));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            path,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ToolPathError {
+    #[error("Expected `NAME=PATH`, like `useradd=/usr/sbin/useradd`, got `{0}`")]
+    Format(String),
+    #[error("The path given for `{0}` must be absolute, got `{1}`")]
+    NotAbsolute(String, PathBuf),
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize, Clone)]
 pub enum UrlOrPath {
     Url(Url),
     Path(PathBuf),
+    /// Read from stdin, given as `-`
+    Stdin,
 }
 
 impl Display for UrlOrPath {
@@ -543,6 +1315,7 @@ impl Display for UrlOrPath {
         match self {
             UrlOrPath::Url(url) => f.write_fmt(format_args!("{url}")),
             UrlOrPath::Path(path) => f.write_fmt(format_args!("{}", path.display())),
+            UrlOrPath::Stdin => f.write_str("-"),
         }
     }
 }
@@ -551,6 +1324,9 @@ impl FromStr for UrlOrPath {
     type Err = UrlOrPathError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(UrlOrPath::Stdin);
+        }
         match Url::parse(s) {
             Ok(url) => Ok(UrlOrPath::Url(url)),
             Err(url::ParseError::RelativeUrlWithoutBase) => {
@@ -559,11 +1335,13 @@ impl FromStr for UrlOrPath {
                 //
                 // So we'll see if such a path exists, and if so, use it
                 let path = PathBuf::from(s);
-                if path.exists() {
-                    Ok(UrlOrPath::Path(path))
-                } else {
-                    Err(UrlOrPathError::PathDoesNotExist(path))
+                if !path.exists() {
+                    return Err(UrlOrPathError::PathDoesNotExist(path));
                 }
+                // Recorded in the plan, which may be installed from another directory
+                std::path::absolute(&path)
+                    .map(UrlOrPath::Path)
+                    .map_err(|e| UrlOrPathError::Io(path, e))
             },
             Err(e) => Err(UrlOrPathError::Url(s.to_string(), e)),
         }
@@ -675,7 +1453,84 @@ impl crate::diagnostics::ErrorDiagnostic for InstallSettingsError {
 
 #[cfg(test)]
 mod tests {
-    use super::{FromStr, PathBuf, Url, UrlOrPath, UrlOrPathOrString};
+    use super::{
+        absolute_profile_target, nix_package_file, CommonSettings, FromStr, InstallSettingsError,
+        PathBuf, Url, UrlOrPath, UrlOrPathOrString, MAX_BUILD_USER_ID,
+    };
+
+    #[tokio::test]
+    async fn build_user_count_is_bounded() -> Result<(), Box<dyn std::error::Error>> {
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_build_user_id_base = 100;
+        for (count, warns) in [(1, false), (64, false), (65, true), (399, true)] {
+            settings.nix_build_user_count = count;
+            assert_eq!(
+                settings.check_build_users()?.len(),
+                usize::from(warns),
+                "{count}"
+            );
+        }
+        settings.nix_build_user_id_base = 0;
+        settings.nix_build_user_count = 512;
+        if MAX_BUILD_USER_ID >= 512 {
+            assert_eq!(settings.check_build_users()?.len(), 1);
+        }
+        for count in [0, 513, 4096] {
+            settings.nix_build_user_count = count;
+            assert!(matches!(
+                settings.check_build_users(),
+                Err(InstallSettingsError::BuildUserCount(got)) if got == count
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_user_ids_stay_below_other_accounts() -> Result<(), Box<dyn std::error::Error>> {
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_build_user_count = 32;
+        settings.nix_build_user_id_base = MAX_BUILD_USER_ID - 32;
+        settings.check_build_users()?;
+
+        settings.nix_build_user_id_base += 1;
+        match settings.check_build_users() {
+            Err(InstallSettingsError::BuildUserIds { first, last }) => {
+                assert_eq!(first, u64::from(MAX_BUILD_USER_ID) - 30);
+                assert_eq!(last, u64::from(MAX_BUILD_USER_ID) + 1);
+            },
+            other => panic!("Expected the IDs to be refused, got {other:?}"),
+        }
+        // Without wrapping around to low IDs
+        settings.nix_build_user_id_base = u32::MAX - 1;
+        assert!(matches!(
+            settings.check_build_users(),
+            Err(InstallSettingsError::BuildUserIds { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notes_build_users_fewer_than_cpus() -> Result<(), Box<dyn std::error::Error>> {
+        let mut settings = CommonSettings::default().await?;
+        settings.nix_build_user_count = 32;
+        assert_eq!(settings.build_parallelism_note(32), None);
+        assert_eq!(settings.build_parallelism_note(8), None);
+        assert!(settings
+            .build_parallelism_note(64)
+            .is_some_and(|note| note.contains("64 CPUs")));
+        Ok(())
+    }
+
+    #[test]
+    fn profile_targets_must_be_absolute() {
+        assert_eq!(
+            absolute_profile_target("/etc/zsh/zprofile"),
+            Ok(PathBuf::from("/etc/zsh/zprofile"))
+        );
+        assert!(absolute_profile_target("etc/zsh/zprofile")
+            .unwrap_err()
+            .contains("must be absolute"));
+    }
 
     #[test]
     fn url_or_path_or_string_parses() -> Result<(), Box<dyn std::error::Error>> {
@@ -709,11 +1564,27 @@ mod tests {
             UrlOrPath::from_str("file:///boop/bleat")?,
             UrlOrPath::Url(Url::from_str("file:///boop/bleat")?),
         );
-        // The file *must* exist!
+        // The file *must* exist! It is recorded absolute, for the plan to be installed from anywhere
         assert_eq!(
             UrlOrPath::from_str(file!())?,
-            UrlOrPath::Path(PathBuf::from_str(file!())?),
+            UrlOrPath::Path(std::env::current_dir()?.join(file!())),
+        );
+        assert_eq!(UrlOrPath::from_str("-")?, UrlOrPath::Stdin);
+        Ok(())
+    }
+
+    #[test]
+    fn nix_package_file_must_exist() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            nix_package_file(file!())?,
+            std::env::current_dir()?.join(file!())
         );
+        assert!(nix_package_file("does/not/exist.tar.xz")
+            .unwrap_err()
+            .contains("does not exist"));
+        assert!(nix_package_file("src")
+            .unwrap_err()
+            .contains("is not a file"));
         Ok(())
     }
 }
@@ -0,0 +1,327 @@
+/*! Opt-in [OpenTelemetry](https://opentelemetry.io/) metrics and tracing export
+
+Only available with the `telemetry` feature, and nothing is exported unless a collector is
+configured with `--telemetry-endpoint` (or `NIX_INSTALLER_TELEMETRY_ENDPOINT`) or the standard
+`OTEL_EXPORTER_OTLP_ENDPOINT`. Data is sent over OTLP/HTTP, the other `OTEL_EXPORTER_OTLP_*`
+variables (such as `OTEL_EXPORTER_OTLP_HEADERS`) are honored.
+
+The names below are stable, dashboards and alerts may rely on them:
+
+| Name                                | Kind      | Unit        | Attributes                                         |
+|-------------------------------------|-----------|-------------|----------------------------------------------------|
+| `nix_installer.installs.started`    | Counter   | `{install}` | `nix_installer.planner`                            |
+| `nix_installer.installs.succeeded`  | Counter   | `{install}` | `nix_installer.planner`                            |
+| `nix_installer.installs.failed`     | Counter   | `{install}` | `nix_installer.planner`                            |
+| `nix_installer.action.duration`     | Histogram | `s`         | `nix_installer.action`, `nix_installer.outcome`    |
+
+`nix_installer.outcome` is either `success` or `failure`.
+
+Tracing spans are exported at the configured verbosity, pass `-v` to include a span per action.
+*/
+
+use std::{sync::OnceLock, time::Duration};
+
+use opentelemetry::{
+    metrics::{Counter, Histogram, Meter, MetricsError, Unit},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{metrics::MeterProvider, runtime, trace::Tracer, Resource};
+use url::Url;
+
+use crate::env;
+
+/// The instrumentation scope of all metrics and spans
+pub const INSTRUMENTATION_SCOPE: &str = "nix-installer";
+
+pub const INSTALLS_STARTED: &str = "nix_installer.installs.started";
+pub const INSTALLS_SUCCEEDED: &str = "nix_installer.installs.succeeded";
+pub const INSTALLS_FAILED: &str = "nix_installer.installs.failed";
+pub const ACTION_DURATION: &str = "nix_installer.action.duration";
+
+pub const ATTRIBUTE_PLANNER: &str = "nix_installer.planner";
+pub const ATTRIBUTE_ACTION: &str = "nix_installer.action";
+pub const ATTRIBUTE_OUTCOME: &str = "nix_installer.outcome";
+
+const OTEL_EXPORTER_OTLP_ENDPOINTS: &[env::EnvVar] = &[
+    env::OTEL_EXPORTER_OTLP_ENDPOINT,
+    env::OTEL_EXPORTER_OTLP_TRACES_ENDPOINT,
+    env::OTEL_EXPORTER_OTLP_METRICS_ENDPOINT,
+];
+
+static METER_PROVIDER: OnceLock<MeterProvider> = OnceLock::new();
+
+/// The install metrics, see the [module documentation](self) for their names
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    installs_started: Counter<u64>,
+    installs_succeeded: Counter<u64>,
+    installs_failed: Counter<u64>,
+    action_duration: Histogram<f64>,
+}
+
+impl Metrics {
+    pub fn new(meter: &Meter) -> Self {
+        let install_counter = |name: &'static str, description: &'static str| {
+            meter
+                .u64_counter(name)
+                .with_description(description)
+                .with_unit(Unit::new("{install}"))
+                .init()
+        };
+        Self {
+            installs_started: install_counter(INSTALLS_STARTED, "Installs which began executing"),
+            installs_succeeded: install_counter(
+                INSTALLS_SUCCEEDED,
+                "Installs which executed every action",
+            ),
+            installs_failed: install_counter(
+                INSTALLS_FAILED,
+                "Installs which failed or were cancelled",
+            ),
+            action_duration: meter
+                .f64_histogram(ACTION_DURATION)
+                .with_description("Time taken to execute a top level action")
+                .with_unit(Unit::new("s"))
+                .init(),
+        }
+    }
+
+    /// Metrics recorded to the global meter provider, a no-op unless [`init`] enabled export
+    pub fn global() -> Self {
+        Self::new(&opentelemetry::global::meter(INSTRUMENTATION_SCOPE))
+    }
+
+    pub fn install_started(&self, planner: &str) {
+        self.installs_started
+            .add(1, &[KeyValue::new(ATTRIBUTE_PLANNER, planner.to_string())]);
+    }
+
+    pub fn install_finished(&self, planner: &str, succeeded: bool) {
+        let counter = if succeeded {
+            &self.installs_succeeded
+        } else {
+            &self.installs_failed
+        };
+        counter.add(1, &[KeyValue::new(ATTRIBUTE_PLANNER, planner.to_string())]);
+    }
+
+    pub fn action_finished(&self, action: &str, duration: Duration, succeeded: bool) {
+        self.action_duration.record(
+            duration.as_secs_f64(),
+            &[
+                KeyValue::new(ATTRIBUTE_ACTION, action.to_string()),
+                KeyValue::new(ATTRIBUTE_OUTCOME, outcome(succeeded)),
+            ],
+        );
+    }
+}
+
+fn outcome(succeeded: bool) -> &'static str {
+    if succeeded {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+/// The collector to export to, if the user opted in
+pub fn endpoint(telemetry_endpoint: Option<&Url>) -> Option<String> {
+    if let Some(telemetry_endpoint) = telemetry_endpoint {
+        return Some(
+            telemetry_endpoint
+                .as_str()
+                .trim_end_matches('/')
+                .to_string(),
+        );
+    }
+    OTEL_EXPORTER_OTLP_ENDPOINTS
+        .iter()
+        .find_map(|var| var.get().filter(|value| !value.is_empty()))
+}
+
+/**
+Start exporting metrics and spans, if the user opted in
+
+Returns the [`Tracer`] to hand to a [`tracing_opentelemetry`] layer, or `None` when telemetry is disabled.
+Must be called from within a Tokio runtime, and followed by [`shutdown`] before exiting.
+*/
+pub fn init(telemetry_endpoint: Option<&Url>) -> Result<Option<Tracer>, TelemetryError> {
+    let Some(endpoint) = endpoint(telemetry_endpoint) else {
+        return Ok(None);
+    };
+    let resource = Resource::new([
+        KeyValue::new("service.name", INSTRUMENTATION_SCOPE),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ]);
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource.clone()))
+        .install_batch(runtime::Tokio)?;
+
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_resource(resource)
+        .build()?;
+    METER_PROVIDER.set(meter_provider).ok();
+
+    Ok(Some(tracer))
+}
+
+/// Flush any pending metrics and spans, a no-op if [`init`] did not enable export
+pub fn shutdown() {
+    if let Some(meter_provider) = METER_PROVIDER.get() {
+        if let Err(err) = meter_provider.shutdown() {
+            tracing::warn!(%err, "Could not flush telemetry metrics");
+        }
+    }
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[non_exhaustive]
+#[derive(Debug, thiserror::Error)]
+pub enum TelemetryError {
+    #[error("Setting up the OpenTelemetry trace exporter")]
+    Trace(
+        #[source]
+        #[from]
+        TraceError,
+    ),
+    #[error("Setting up the OpenTelemetry metrics exporter")]
+    Metrics(
+        #[source]
+        #[from]
+        MetricsError,
+    ),
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::{metrics::MeterProvider as _, trace::TracerProvider as _};
+    use opentelemetry_sdk::{
+        metrics::{
+            data::{Histogram, Sum},
+            PeriodicReader,
+        },
+        testing::{metrics::InMemoryMetricsExporter, trace::InMemorySpanExporter},
+        trace::TracerProvider,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::action::base::CreateDirectory;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn records_install_metrics() -> eyre::Result<()> {
+        let exporter = InMemoryMetricsExporter::default();
+        let reader = PeriodicReader::builder(exporter.clone(), runtime::Tokio).build();
+        let provider = MeterProvider::builder().with_reader(reader).build();
+        let metrics = Metrics::new(&provider.meter(INSTRUMENTATION_SCOPE));
+
+        metrics.install_started("linux");
+        metrics.action_finished("provision_nix", Duration::from_millis(1500), true);
+        metrics.action_finished("configure_nix", Duration::from_millis(10), false);
+        metrics.install_finished("linux", false);
+        provider.force_flush()?;
+
+        let resource_metrics = exporter.get_finished_metrics()?;
+        let exported = resource_metrics
+            .iter()
+            .flat_map(|resource_metrics| &resource_metrics.scope_metrics)
+            .inspect(|scope_metrics| assert_eq!(scope_metrics.scope.name, INSTRUMENTATION_SCOPE))
+            .flat_map(|scope_metrics| &scope_metrics.metrics)
+            .collect::<Vec<_>>();
+        let find = |name: &str| exported.iter().find(|metric| metric.name == name).copied();
+
+        for (name, expected) in [
+            (INSTALLS_STARTED, 1),
+            (INSTALLS_FAILED, 1),
+            (INSTALLS_SUCCEEDED, 0),
+        ] {
+            let total = find(name)
+                .and_then(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+                .map(|sum| sum.data_points.iter().map(|point| point.value).sum::<u64>())
+                .unwrap_or_default();
+            assert_eq!(total, expected, "{name}");
+        }
+
+        let duration = find(ACTION_DURATION)
+            .and_then(|metric| metric.data.as_any().downcast_ref::<Histogram<f64>>())
+            .ok_or_else(|| eyre::eyre!("No `{ACTION_DURATION}` histogram exported"))?;
+        assert_eq!(duration.data_points.len(), 2);
+        let provision_nix = duration
+            .data_points
+            .iter()
+            .find(|point| {
+                point.attributes.iter().any(|(key, value)| {
+                    key.as_str() == ATTRIBUTE_ACTION && value.as_str() == "provision_nix"
+                })
+            })
+            .ok_or_else(|| eyre::eyre!("No `provision_nix` duration exported"))?;
+        assert_eq!(provision_nix.count, 1);
+        assert_eq!(provision_nix.sum, 1.5);
+        Ok(())
+    }
+
+    #[test]
+    fn exports_action_spans() -> eyre::Result<()> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(INSTRUMENTATION_SCOPE)),
+        ));
+        let _guard = tracing::dispatcher::set_default(&dispatch);
+        // Spans also close on the threads `tokio::fs` runs on, which need to see the subscriber
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .on_thread_start(move || std::mem::forget(tracing::dispatcher::set_default(&dispatch)))
+            .build()?;
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("nix");
+        runtime.block_on(async {
+            CreateDirectory::plan(&path, None, None, 0o0755, false)
+                .await?
+                .try_execute()
+                .await
+        })?;
+        drop(runtime);
+        provider.force_flush();
+
+        let spans = exporter.get_finished_spans()?;
+        let span = spans
+            .iter()
+            .find(|span| span.name == "create_directory")
+            .ok_or_else(|| eyre::eyre!("No `create_directory` span exported"))?;
+        assert_eq!(span.instrumentation_lib.name, INSTRUMENTATION_SCOPE);
+        let attribute = |key: &str| {
+            span.attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.as_str().into_owned())
+        };
+        assert_eq!(attribute("path"), Some(path.display().to_string()));
+        assert_eq!(attribute("mode"), Some("0o755".to_string()));
+        // The action's own steps are nested under its span
+        let execute = spans
+            .iter()
+            .find(|execute| execute.name == "execute")
+            .ok_or_else(|| eyre::eyre!("No `execute` span exported"))?;
+        assert_eq!(execute.parent_span_id, span.span_context.span_id());
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+/*! Temporary files and directories created while installing
+
+Actions [`register`] the scratch paths they create and [`unregister`] them once they are cleaned up
+or moved into place. Whatever is still registered when the install stops, successfully or not, is
+removed by the [`TempArtifacts`] of the executing [`InstallPlan`](crate::InstallPlan).
+*/
+
+use std::{
+    future::Future,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// The paths registered so far, with the index of the plan step which registered each
+type Registry = Arc<Mutex<Vec<(PathBuf, usize)>>>;
+
+tokio::task_local! {
+    static CURRENT: (Registry, usize);
+}
+
+/// Register a temporary path created by the executing action, it is removed if the install stops before it is unregistered
+///
+/// Does nothing outside of [`TempArtifacts::scope`], such as when an action is executed directly.
+pub(crate) fn register(path: impl Into<PathBuf>) {
+    let path = path.into();
+    let _ = CURRENT.try_with(|(registry, step)| {
+        let mut registry = registry.lock().unwrap_or_else(PoisonError::into_inner);
+        if !registry.iter().any(|(existing, _)| *existing == path) {
+            registry.push((path, *step));
+        }
+    });
+}
+
+/// Stop tracking a temporary path, because it was removed or is no longer temporary
+pub(crate) fn unregister(path: &Path) {
+    let _ = CURRENT.try_with(|(registry, _)| {
+        registry
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(existing, _)| existing != path);
+    });
+}
+
+/// Carry the registry of the executing plan step into a future which is spawned as its own task
+pub(crate) fn propagate<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let current = CURRENT.try_with(Clone::clone).ok();
+    async move {
+        match current {
+            Some(current) => CURRENT.scope(current, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/**
+The temporary artifacts of an install, anything left is removed when dropped (including while
+unwinding from a panic)
+
+With `keep`, the artifacts are left in place and their locations logged instead, for debugging.
+*/
+#[derive(Debug)]
+pub(crate) struct TempArtifacts {
+    registry: Registry,
+    keep: bool,
+}
+
+impl TempArtifacts {
+    pub(crate) fn new(keep: bool) -> Self {
+        Self {
+            registry: Registry::default(),
+            keep,
+        }
+    }
+
+    /// Execute plan step `step`, attributing any paths it registers to it
+    pub(crate) async fn scope<F: Future>(&self, step: usize, fut: F) -> F::Output {
+        CURRENT.scope((self.registry.clone(), step), fut).await
+    }
+
+    /// Remove every path still registered, returning the steps which had created any that were removed
+    pub(crate) fn clean_up(&self) -> Vec<usize> {
+        let artifacts =
+            std::mem::take(&mut *self.registry.lock().unwrap_or_else(PoisonError::into_inner));
+
+        if self.keep {
+            for (path, _) in &artifacts {
+                tracing::warn!("Keeping temporary artifact `{}`", path.display());
+            }
+            return vec![];
+        }
+
+        let mut steps = vec![];
+        // Most recent first, so files registered inside a registered directory go before it
+        for (path, step) in artifacts.into_iter().rev() {
+            let removed = match std::fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+                Ok(_) => std::fs::remove_file(&path),
+                Err(e) => Err(e),
+            };
+            match removed {
+                Ok(()) => {
+                    tracing::info!("Removed temporary artifact `{}`", path.display());
+                    if !steps.contains(&step) {
+                        steps.push(step);
+                    }
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => tracing::warn!(
+                    "Could not remove temporary artifact `{}`: {e}",
+                    path.display()
+                ),
+            }
+        }
+        steps
+    }
+}
+
+impl Drop for TempArtifacts {
+    fn drop(&mut self) {
+        self.clean_up();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::TempArtifacts;
+    use crate::{
+        action::base::{FetchAndUnpackNix, RemoveDirectory},
+        settings::UrlOrPath,
+    };
+
+    fn write_fixture_tarball(path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let encoder = xz2::write::XzEncoder::new(file, 1);
+        let mut builder = tar::Builder::new(encoder);
+        let contents = b"fixture";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "nix-fixture/store/file", &contents[..])?;
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    /// Fetch into a scratch directory as step 0, then fail in step 1
+    async fn fetch_then_fail(
+        temp_artifacts: &TempArtifacts,
+        temp_dir: &Path,
+    ) -> eyre::Result<std::path::PathBuf> {
+        let tarball = temp_dir.join("fixture.tar.xz");
+        write_fixture_tarball(&tarball)?;
+        let scratch = temp_dir.join("temp-install-dir");
+
+        let mut fetch_nix = FetchAndUnpackNix::plan(
+            UrlOrPath::Path(tarball),
+            scratch.clone(),
+            None,
+            None,
+            None,
+            4096,
+            1,
+        )
+        .await?;
+        temp_artifacts.scope(0, fetch_nix.try_execute()).await?;
+        assert!(scratch.join("nix-fixture/store/file").exists());
+
+        let not_a_directory = temp_dir.join("not-a-directory");
+        std::fs::write(&not_a_directory, "")?;
+        let mut failing = RemoveDirectory::plan(&not_a_directory).await?;
+        assert!(temp_artifacts
+            .scope(1, failing.try_execute())
+            .await
+            .is_err());
+
+        Ok(scratch)
+    }
+
+    #[tokio::test]
+    async fn removes_scratch_after_failure() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_artifacts = TempArtifacts::new(false);
+        let scratch = fetch_then_fail(&temp_artifacts, temp_dir.path()).await?;
+
+        assert_eq!(temp_artifacts.clean_up(), vec![0]);
+        assert!(
+            !scratch.exists(),
+            "Scratch directory should have been removed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removes_scratch_when_dropped() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_artifacts = TempArtifacts::new(false);
+        let scratch = fetch_then_fail(&temp_artifacts, temp_dir.path()).await?;
+
+        drop(temp_artifacts);
+        assert!(
+            !scratch.exists(),
+            "Scratch directory should have been removed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_scratch_with_keep_temp() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_artifacts = TempArtifacts::new(true);
+        let scratch = fetch_then_fail(&temp_artifacts, temp_dir.path()).await?;
+
+        assert!(temp_artifacts.clean_up().is_empty());
+        drop(temp_artifacts);
+        assert!(scratch.exists(), "Scratch directory should have been kept");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unregistered_paths_are_left_alone() -> eyre::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let temp_artifacts = TempArtifacts::new(false);
+        let scratch = fetch_then_fail(&temp_artifacts, temp_dir.path()).await?;
+
+        let mut remove_scratch = RemoveDirectory::plan(&scratch).await?;
+        temp_artifacts
+            .scope(2, remove_scratch.try_execute())
+            .await?;
+        std::fs::create_dir(&scratch)?;
+
+        assert!(temp_artifacts.clean_up().is_empty());
+        assert!(scratch.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,636 @@
+/*! Running the installer for real in tests, as `root` of a fake root, without being `root`
+
+A test calls [`run_in_fake_root`], which runs that test again in a child process entering new user
+and mount namespaces. Only the user running the tests is mapped (to `root`), so no `newuidmap` or
+other setuid helper is needed. The child mounts a `tmpfs` as its root, binds the host's programs and
+libraries into it read only, copies in a fixture from `tests/fixtures/fake-root` and `chroot`s into
+it.
+
+Users and groups resolve through NSS from the fake root's `/etc` files, which the [`FakeRootRunner`]
+edits as `useradd` and friends would. It emulates the other commands the installer runs too, and
+refuses any it does not know, so nothing runs against the host.
+
+Where user namespaces are not available (such as in some containers), the tests are skipped.
+*/
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    future::Future,
+    os::unix::{
+        fs::{symlink, PermissionsExt},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output},
+    sync::Arc,
+};
+
+use eyre::{ensure, WrapErr};
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::{
+        stat::Mode,
+        statvfs::{statvfs, FsFlags},
+    },
+    unistd::{chdir, chroot, close, getgid, getuid, write},
+};
+use tokio::process::Command;
+
+use crate::command_runner::{set_runner, CommandRunner, RealRunner};
+
+/// Set for the child process, to the directory its fake root is mounted on
+const FAKE_ROOT_ENV: &str = "NIX_INSTALLER_TEST_FAKE_ROOT";
+
+/// Bound from the host into every fake root, read only except for `/dev` and `/proc`
+const HOST_PATHS: &[(&str, bool)] = &[
+    ("bin", true),
+    ("sbin", true),
+    ("lib", true),
+    ("lib32", true),
+    ("lib64", true),
+    ("libx32", true),
+    ("usr", true),
+    ("dev", false),
+    ("proc", false),
+];
+
+/// Created in every fake root, in addition to what the fixture has
+const SKELETON: &[(&str, u32)] = &[
+    ("etc", 0o755),
+    ("root", 0o700),
+    ("run", 0o755),
+    ("tmp", 0o1777),
+    ("var", 0o755),
+];
+
+/// Where every fake root has a Nix package, made of `tests/fixtures/fake-root/nix-tarball`
+pub(crate) const NIX_TARBALL: &str = "/tmp/nix-2.24.0-x86_64-linux.tar.xz";
+
+/**
+Run `body` as `root` of a fake root made of `tests/fixtures/fake-root/<fixture>`
+
+`test` is the name of the calling test, which is run again in the child process, `module_path` the
+[`module_path!`] of it. Returns once the child passed, or right away when user namespaces are not
+available.
+*/
+pub(crate) fn run_in_fake_root<F, Fut>(
+    module_path: &str,
+    test: &str,
+    fixture: &str,
+    body: F,
+) -> eyre::Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = eyre::Result<()>>,
+{
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/fake-root");
+    match std::env::var_os(FAKE_ROOT_ENV) {
+        Some(root) => {
+            enter_fake_root(Path::new(&root), &fixtures.join(fixture), &fixtures)?;
+            set_runner(Arc::new(FakeRootRunner::default()));
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(body())
+        },
+        None => run_child(&test_path(module_path, test)),
+    }
+}
+
+/// Set for the child process [`run_without_path`] runs
+const WITHOUT_PATH_ENV: &str = "NIX_INSTALLER_TEST_WITHOUT_PATH";
+
+/**
+Run `body` with an empty `PATH`, as on a minimal host without any of the usual tools
+
+Like [`run_in_fake_root`] the calling `test` is run again in a child process, whose environment
+alone is changed. Nothing the child runs can be found on `PATH`, though it can still be run by its
+absolute path.
+*/
+pub(crate) fn run_without_path<F, Fut>(module_path: &str, test: &str, body: F) -> eyre::Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = eyre::Result<()>>,
+{
+    run_again(
+        module_path,
+        test,
+        WITHOUT_PATH_ENV,
+        &[("PATH", "")],
+        "without `PATH`",
+        body,
+    )
+}
+
+/// Set for the child process [`run_alone`] runs
+const ALONE_ENV: &str = "NIX_INSTALLER_TEST_ALONE";
+
+/**
+Run `body` in a process of its own, for tests measuring the whole process (like its peak memory)
+
+Like [`run_without_path`] the calling `test` is run again in a child process, where no other tests
+run alongside it.
+*/
+pub(crate) fn run_alone<F, Fut>(module_path: &str, test: &str, body: F) -> eyre::Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = eyre::Result<()>>,
+{
+    run_again(module_path, test, ALONE_ENV, &[], "alone", body)
+}
+
+/// Run `body` when `marker` is set, otherwise run the calling `test` again in a child process with `marker` and `envs` set
+fn run_again<F, Fut>(
+    module_path: &str,
+    test: &str,
+    marker: &str,
+    envs: &[(&str, &str)],
+    how: &str,
+    body: F,
+) -> eyre::Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = eyre::Result<()>>,
+{
+    if std::env::var_os(marker).is_some() {
+        return tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(body());
+    }
+    let test_path = test_path(module_path, test);
+    let output = std::process::Command::new(std::env::current_exe()?)
+        .args([&test_path, "--exact", "--test-threads=1"])
+        .env(marker, "1")
+        .envs(envs.iter().copied())
+        .output()
+        .wrap_err_with(|| format!("Running the test {how}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    ensure!(
+        output.status.success() && stdout.contains("1 passed"),
+        "`{test_path}` failed {how} ({})\n{stdout}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+/// The path of `test` as the test harness filters it, from the [`module_path!`] it is in
+fn test_path(module_path: &str, test: &str) -> String {
+    format!(
+        "{}::{test}",
+        module_path
+            .split_once("::")
+            .map_or("", |(_crate, path)| path)
+    )
+}
+
+fn run_child(test_path: &str) -> eyre::Result<()> {
+    let root = tempfile::tempdir()?;
+    // Formatted before forking, only async-signal-safe calls are made between forking and `exec`
+    let maps = [
+        ("/proc/self/setgroups", "deny".to_string()),
+        ("/proc/self/uid_map", format!("0 {} 1", getuid())),
+        ("/proc/self/gid_map", format!("0 {} 1", getgid())),
+    ]
+    .map(|(path, contents)| (CString::new(path).expect("No NUL"), contents));
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args([test_path, "--exact", "--test-threads=1"])
+        .env(FAKE_ROOT_ENV, root.path())
+        .env("HOME", "/root");
+    // SAFETY: `enter_namespaces` only makes async-signal-safe calls and doesn't allocate
+    unsafe {
+        command.pre_exec(move || enter_namespaces(&maps));
+    }
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e)
+            if matches!(
+                e.raw_os_error().map(Errno::from_i32),
+                Some(Errno::EPERM | Errno::EACCES | Errno::EINVAL | Errno::ENOSPC | Errno::ENOSYS)
+            ) =>
+        {
+            eprintln!("Skipping `{test_path}`, user namespaces are not available: {e}");
+            return Ok(());
+        },
+        Err(e) => return Err(e).wrap_err("Running the test in a fake root"),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // A test path matching nothing passes without running anything
+    ensure!(
+        output.status.success() && stdout.contains("1 passed"),
+        "`{test_path}` failed in its fake root ({})\n{stdout}\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+fn enter_namespaces(maps: &[(CString, String)]) -> std::io::Result<()> {
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+    for (path, contents) in maps {
+        let fd = open(path.as_c_str(), OFlag::O_WRONLY, Mode::empty())?;
+        let written = write(fd, contents.as_bytes());
+        let _ = close(fd);
+        written?;
+    }
+    Ok(())
+}
+
+fn enter_fake_root(root: &Path, fixture: &Path, fixtures: &Path) -> eyre::Result<()> {
+    // Nothing mounted from here on may show up outside of the child
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )?;
+    mount(
+        Some("tmpfs"),
+        root,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        Some("mode=0755"),
+    )?;
+
+    for (path, read_only) in HOST_PATHS {
+        let host = Path::new("/").join(path);
+        let target = root.join(path);
+        let metadata = match std::fs::symlink_metadata(&host) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).wrap_err_with(|| format!("Reading `{}`", host.display())),
+        };
+        if metadata.is_symlink() {
+            // Such as `/bin` linking to `usr/bin` on merged `/usr` distributions
+            symlink(std::fs::read_link(&host)?, &target)?;
+        } else {
+            std::fs::create_dir(&target)?;
+            bind(&host, &target, *read_only)
+                .wrap_err_with(|| format!("Binding `{}`", host.display()))?;
+        }
+    }
+    for (path, mode) in SKELETON {
+        let path = root.join(path);
+        std::fs::create_dir(&path)?;
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(*mode))?;
+    }
+    copy_tree(fixture, root)
+        .wrap_err_with(|| format!("Copying the fixture `{}`", fixture.display()))?;
+    write_nix_tarball(
+        &fixtures.join("nix-tarball"),
+        &root.join(NIX_TARBALL.trim_start_matches('/')),
+    )?;
+
+    chroot(root)?;
+    chdir("/")?;
+    Ok(())
+}
+
+fn bind(source: &Path, target: &Path, read_only: bool) -> eyre::Result<()> {
+    mount(
+        Some(source),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    if read_only {
+        // Remounting may not clear flags the host mounted with, they are locked in a user namespace
+        let host_flags = statvfs(target)?.flags();
+        let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+        for (host_flag, flag) in [
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+            (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+            (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+            (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+        ] {
+            if host_flags.contains(host_flag) {
+                flags |= flag;
+            }
+        }
+        mount(None::<&str>, target, None::<&str>, flags, None::<&str>)?;
+    }
+    Ok(())
+}
+
+/// Copy what is in `source` into `dest`, keeping modes and symlinks
+fn copy_tree(source: &Path, dest: &Path) -> eyre::Result<()> {
+    for entry in walkdir::WalkDir::new(source).min_depth(1) {
+        let entry = entry?;
+        let target = dest.join(entry.path().strip_prefix(source)?);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_nix_tarball(source: &Path, dest: &Path) -> eyre::Result<()> {
+    let file = std::fs::File::create(dest)?;
+    let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(file, 1));
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        builder.append_dir_all(entry.file_name(), entry.path())?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Shells whose syntax checks (`-n`) are run for real
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "fish"];
+
+/**
+Emulates the commands the installer runs on Linux, against the fake root
+
+* `useradd`, `userdel`, `groupadd`, `groupdel` and `gpasswd` edit `/etc/passwd` and `/etc/group`
+* `nix-env` installs into and uninstalls from a profile with a single generation
+* `nix-store` loads nothing into its database and finds every path valid
+* `systemctl` (every unit disabled and inactive), `systemd-tmpfiles` and
+  `findmnt` (finding nothing) do nothing
+* Shells only check syntax (`-n`), which is run for real
+
+Any other command fails to run. A test makes a command fail (or answer differently) with
+[`respond`](FakeRootRunner::respond).
+*/
+#[derive(Debug, Default)]
+pub(crate) struct FakeRootRunner {
+    responses: Vec<(Vec<String>, i32, Vec<u8>)>,
+}
+
+impl FakeRootRunner {
+    /// Answer commands whose argv starts with `prefix` with `exit_code` and `stdout`, instead of emulating them
+    ///
+    /// The program is matched by its file name, as with [`MockRunner`](crate::command_runner::MockRunner).
+    pub(crate) fn respond(
+        mut self,
+        prefix: &[&str],
+        exit_code: i32,
+        stdout: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.responses.push((
+            prefix.iter().map(ToString::to_string).collect(),
+            exit_code,
+            stdout.into(),
+        ));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl CommandRunner for FakeRootRunner {
+    async fn output(&self, command: &mut Command, stdin: Option<&[u8]>) -> std::io::Result<Output> {
+        let std = command.as_std();
+        let program = Path::new(std.get_program())
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let argv = std::iter::once(program)
+            .chain(std.get_args().map(|arg| arg.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>();
+
+        let (exit_code, stdout) = match self
+            .responses
+            .iter()
+            .find(|(prefix, _, _)| argv.starts_with(prefix))
+        {
+            Some((_, exit_code, stdout)) => (*exit_code, stdout.clone()),
+            // Checking the syntax of shell code only reads it
+            None if SHELLS.contains(&argv[0].as_str()) && argv[1..] == ["-n"] => {
+                return RealRunner.output(command, stdin).await
+            },
+            None => emulate(&argv)?,
+        };
+        Ok(Output {
+            status: ExitStatus::from_raw(exit_code << 8),
+            stdout,
+            stderr: vec![],
+        })
+    }
+}
+
+fn emulate(argv: &[String]) -> std::io::Result<(i32, Vec<u8>)> {
+    let args = argv.iter().map(String::as_str).collect::<Vec<_>>();
+    let succeeded = |()| (0, vec![]);
+    match args[..] {
+        ["groupadd", "-g", gid, "--system", name] => {
+            add_entry("/etc/group", name, &format!("{name}:x:{gid}:")).map(succeeded)
+        },
+        ["groupdel", name] => remove_entry("/etc/group", name).map(succeeded),
+        ["useradd", ref flags @ .., name] => {
+            let flag = |flag: &str| {
+                flags
+                    .windows(2)
+                    .find(|pair| pair[0] == flag)
+                    .map_or("", |pair| pair[1])
+            };
+            add_entry(
+                "/etc/passwd",
+                name,
+                &format!(
+                    "{name}:x:{}:{}:{}:{}:{}",
+                    flag("--uid"),
+                    flag("--gid"),
+                    flag("--comment"),
+                    flag("--home-dir"),
+                    flag("--shell")
+                ),
+            )?;
+            match flag("--groups") {
+                "" => Ok((0, vec![])),
+                gid => edit_members(|group| group[2] == gid, |members| add_member(members, name))
+                    .map(succeeded),
+            }
+        },
+        ["userdel", name] => {
+            remove_entry("/etc/passwd", name)?;
+            edit_members(|_| true, |members| members.retain(|member| member != name)).map(succeeded)
+        },
+        ["gpasswd", "-a", user, group] => edit_members(
+            |entry| entry[0] == group,
+            |members| add_member(members, user),
+        )
+        .map(succeeded),
+        ["gpasswd", "-d", user, group] => edit_members(
+            |entry| entry[0] == group,
+            |members| members.retain(|member| member != user),
+        )
+        .map(succeeded),
+        ["nix-store", "--load-db" | "--verify"] | ["nix-store", "--check-validity", ..] => {
+            Ok((0, vec![]))
+        },
+        ["nix-env", "-i", package] => install_into_profile(Path::new(package)).map(succeeded),
+        ["nix-env", "--profile", profile, "--uninstall", ..] => {
+            remove_profile(Path::new(profile)).map(succeeded)
+        },
+        ["systemctl", "is-enabled", ..] => Ok((1, b"disabled\n".to_vec())),
+        ["systemctl", "is-active", ..] => Ok((3, b"inactive\n".to_vec())),
+        ["systemctl", ..] | ["systemd-tmpfiles", ..] => Ok((0, vec![])),
+        ["findmnt", ..] => Ok((1, vec![])),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("`{}` is not emulated in the fake root", argv.join(" ")),
+        )),
+    }
+}
+
+/// Append `line` to the `/etc/passwd` style `file`, failing as the tools do if `name` is there
+fn add_entry(file: &str, name: &str, line: &str) -> std::io::Result<()> {
+    let mut contents = std::fs::read_to_string(file)?;
+    if contents
+        .lines()
+        .any(|entry| entry.split(':').next() == Some(name))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("`{name}` already exists in `{file}`"),
+        ));
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    std::fs::write(file, contents)
+}
+
+fn remove_entry(file: &str, name: &str) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(file)?;
+    let kept = contents
+        .lines()
+        .filter(|entry| entry.split(':').next() != Some(name))
+        .map(|entry| format!("{entry}\n"))
+        .collect::<String>();
+    std::fs::write(file, kept)
+}
+
+/// Edit the members of the groups in `/etc/group` which `matches` (given their fields)
+fn edit_members(
+    matches: impl Fn(&[&str]) -> bool,
+    edit: impl Fn(&mut Vec<String>),
+) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string("/etc/group")?;
+    let mut edited = String::new();
+    for entry in contents.lines() {
+        let fields = entry.split(':').collect::<Vec<_>>();
+        if fields.len() == 4 && matches(&fields) {
+            let mut members = fields[3]
+                .split(',')
+                .filter(|member| !member.is_empty())
+                .map(ToString::to_string)
+                .collect();
+            edit(&mut members);
+            edited.push_str(&format!(
+                "{}:{}:{}:{}\n",
+                fields[0],
+                fields[1],
+                fields[2],
+                members.join(",")
+            ));
+        } else {
+            edited.push_str(&format!("{entry}\n"));
+        }
+    }
+    std::fs::write("/etc/group", edited)
+}
+
+fn add_member(members: &mut Vec<String>, user: &str) {
+    if !members.iter().any(|member| member == user) {
+        members.push(user.to_string());
+    }
+}
+
+/// The default profile, as `nix-env` makes it of the single generation installing packages
+const PROFILE: &str = "/nix/var/nix/profiles/default";
+const PROFILE_GENERATION: &str = "/nix/var/nix/profiles/default-1-link";
+const USER_ENVIRONMENT: &str = "/nix/store/00000000000000000000000000000000-user-environment";
+
+/// Link what `package` has at its top into the user environment of the profile
+fn install_into_profile(package: &Path) -> std::io::Result<()> {
+    let environment = Path::new(USER_ENVIRONMENT);
+    std::fs::create_dir_all(environment)?;
+    for entry in std::fs::read_dir(package)? {
+        let entry = entry?;
+        let linked = environment.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            // Packages share `etc` and `lib`, so link below those
+            std::fs::create_dir_all(&linked)?;
+            for inner in std::fs::read_dir(entry.path())? {
+                let inner = inner?;
+                symlink(inner.path(), linked.join(inner.file_name()))?;
+            }
+        } else {
+            symlink(entry.path(), linked)?;
+        }
+    }
+    if !Path::new(PROFILE).exists() {
+        std::fs::create_dir_all(Path::new(PROFILE).parent().expect("Has a parent"))?;
+        symlink(USER_ENVIRONMENT, PROFILE_GENERATION)?;
+        symlink("default-1-link", PROFILE)?;
+    }
+    Ok(())
+}
+
+fn remove_profile(profile: &Path) -> std::io::Result<()> {
+    if profile != Path::new(PROFILE) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Only `{PROFILE}` is emulated"),
+        ));
+    }
+    std::fs::remove_file(PROFILE)?;
+    std::fs::remove_file(PROFILE_GENERATION)?;
+    std::fs::remove_dir_all(USER_ENVIRONMENT)
+}
+
+/// What is at a path, as recorded by [`snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Entry {
+    Directory { mode: u32 },
+    File { mode: u32, contents: Vec<u8> },
+    Symlink(PathBuf),
+}
+
+/// Everything at and under `paths` (those missing are left out), to compare before and after
+pub(crate) fn snapshot(paths: &[&str]) -> eyre::Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+    for path in paths {
+        if std::fs::symlink_metadata(path).is_err() {
+            continue;
+        }
+        for entry in walkdir::WalkDir::new(path) {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            let recorded = if entry.file_type().is_symlink() {
+                Entry::Symlink(std::fs::read_link(entry.path())?)
+            } else if entry.file_type().is_dir() {
+                Entry::Directory { mode }
+            } else {
+                Entry::File {
+                    mode,
+                    contents: std::fs::read(entry.path())?,
+                }
+            };
+            entries.insert(entry.into_path(), recorded);
+        }
+    }
+    Ok(entries)
+}
+
+/// The contents of the text file at `path`, failing with its path
+pub(crate) fn read(path: &str) -> eyre::Result<String> {
+    std::fs::read_to_string(path).wrap_err_with(|| format!("Reading `{path}`"))
+}
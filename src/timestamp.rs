@@ -0,0 +1,283 @@
+/*! Timestamps as recorded in receipts, the audit log and diagnostics
+
+Always RFC 3339 in UTC with millisecond precision (`2024-03-01T12:34:56.789Z`), whatever the
+timezone of the host, so those of a fleet compare and sort as plain strings.
+*/
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+/// A point in time, (de)serialized as an RFC 3339 UTC timestamp
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde_with::SerializeDisplay,
+    serde_with::DeserializeFromStr,
+)]
+pub struct Timestamp(SystemTime);
+
+impl Timestamp {
+    /// The current time, truncated to what is recorded so it reads back unchanged
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn system_time(&self) -> SystemTime {
+        self.0
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(SystemTime::UNIX_EPOCH + Duration::from_millis(since_epoch.as_millis() as u64))
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Times before the epoch only come from broken clocks, and are recorded as the epoch
+        let since_epoch = self
+            .0
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let seconds = since_epoch.as_secs() as i64;
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+        let second_of_day = seconds.rem_euclid(86_400);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            second_of_day / 3_600,
+            second_of_day % 3_600 / 60,
+            second_of_day % 60,
+            since_epoch.subsec_millis(),
+        )
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = TimestampError;
+
+    /// Any RFC 3339 timestamp, including those with an offset other than UTC
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_rfc3339(s)
+            .map(Self::from)
+            .ok_or_else(|| TimestampError(s.to_string()))
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("`{0}` is not an RFC 3339 timestamp")]
+pub struct TimestampError(String);
+
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let number = |digits: &str| -> Option<i64> {
+        digits
+            .bytes()
+            .all(|byte| byte.is_ascii_digit())
+            .then(|| digits.parse().ok())?
+    };
+    let (date, time) = s.split_once(['T', 't'])?;
+    let [year, month, day] = date.split('-').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let sign_at = time.rfind(['+', '-'])?;
+            let (time, offset) = time.split_at(sign_at);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let (hours, minutes) = (number(hours)?, number(minutes)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset_seconds = hours * 3_600 + minutes * 60;
+            if offset.starts_with('-') {
+                (time, -offset_seconds)
+            } else {
+                (time, offset_seconds)
+            }
+        },
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let [hour, minute, second] = time.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let millis = match fraction {
+        "" => 0,
+        fraction => number(fraction)? * 1_000 / 10_i64.checked_pow(fraction.len() as u32)?,
+    };
+
+    let local = from_civil(
+        year,
+        month,
+        day,
+        number(hour)?,
+        number(minute)?,
+        number(second)?,
+    )?;
+    let seconds = local.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() as i64 - offset;
+    Some(
+        SystemTime::UNIX_EPOCH
+            + Duration::from_secs(seconds.try_into().ok()?)
+            + Duration::from_millis(millis.try_into().ok()?),
+    )
+}
+
+/// The time at the given UTC date and time of the proleptic Gregorian calendar, `None` for invalid ones or those before the epoch
+pub(crate) fn from_civil(
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+) -> Option<SystemTime> {
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..=23).contains(&hour)
+        || !(0..=59).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds.try_into().ok()?))
+}
+
+/// Days since the epoch of the proleptic Gregorian date, with years starting in March
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The proleptic Gregorian date of the day `days` since the epoch, the inverse of [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Times worth pinning down, in milliseconds since the epoch
+    const GOLDEN_TIMES: &[u64] = &[
+        0,
+        1,
+        951_782_400_000,     // 2000-02-29, a leap day of a century divisible by 400
+        1_709_164_800_123,   // 2024-02-29
+        1_709_251_199_999,   // The last millisecond of 2024-02-29
+        4_107_542_400_000,   // 2100-03-01, after the missing leap day of 2100
+        253_402_300_799_999, // The last millisecond of 9999
+    ];
+
+    /// Changes to how timestamps are written change this snapshot, regenerate it with
+    /// `NIX_INSTALLER_UPDATE_SNAPSHOTS=1` only if receipts written before can still be read
+    #[test]
+    fn matches_golden_file() -> eyre::Result<()> {
+        let golden =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/timestamps.txt");
+        let rendered = GOLDEN_TIMES
+            .iter()
+            .map(|millis| {
+                let timestamp =
+                    Timestamp::from(SystemTime::UNIX_EPOCH + Duration::from_millis(*millis));
+                format!("{millis} {timestamp}\n")
+            })
+            .collect::<String>();
+        if std::env::var_os("NIX_INSTALLER_UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&golden, &rendered)?;
+        }
+        assert_eq!(
+            rendered,
+            std::fs::read_to_string(&golden)?,
+            "Timestamps are written differently, regenerate `{}` with `NIX_INSTALLER_UPDATE_SNAPSHOTS=1 cargo test` if that is intended",
+            golden.display()
+        );
+
+        for line in rendered.lines() {
+            let (millis, timestamp) = line.split_once(' ').expect("A line is `millis timestamp`");
+            assert_eq!(
+                timestamp.parse::<Timestamp>()?.system_time(),
+                SystemTime::UNIX_EPOCH + Duration::from_millis(millis.parse()?),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_any_offset_as_utc() -> eyre::Result<()> {
+        let expected = "2024-03-01T12:34:56.700Z";
+        for offset in [
+            "2024-03-01T12:34:56.7Z",
+            "2024-03-01t12:34:56.700z",
+            "2024-03-01T14:34:56.700+02:00",
+            "2024-03-01T07:04:56.700-05:30",
+            "2024-03-01T12:34:56.700999+00:00",
+        ] {
+            assert_eq!(
+                offset.parse::<Timestamp>()?.to_string(),
+                expected,
+                "{offset}"
+            );
+        }
+        for invalid in [
+            "",
+            "2024-03-01",
+            "2024-03-01 12:34:56Z",
+            "2024-13-01T12:34:56Z",
+            "2024-03-01T12:34:56",
+            "2024-03-01T24:00:00Z",
+            "2024-03-01T12:34:56+2:00:00",
+        ] {
+            assert_eq!(
+                invalid.parse::<Timestamp>(),
+                Err(TimestampError(invalid.to_string()))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reads_back_unchanged() -> eyre::Result<()> {
+        let now = Timestamp::now();
+        assert_eq!(
+            serde_json::from_str::<Timestamp>(&serde_json::to_string(&now)?)?,
+            now
+        );
+        Ok(())
+    }
+}
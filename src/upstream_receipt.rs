@@ -0,0 +1,278 @@
+/*! Best effort translation of receipts written by the upstream `nix-installer`
+
+Machines installed with the upstream installer can be uninstalled with this one once their receipt
+is translated. Its actions are mapped onto ours where they mean the same thing (created files and
+directories, users and groups, the daemon's units, and shell profile edits), the rest are listed
+as [`Skipped`] so they can be reviewed and cleaned up by hand.
+*/
+
+use semver::{Version, VersionReq};
+use serde_json::Value;
+
+use crate::{
+    action::{Action, StatefulAction},
+    plan::current_version,
+    planner::{BuiltinPlanner, Planner},
+    InstallPlan, NixInstallerError,
+};
+
+/// Upstream action kinds which are ours under another name
+const RENAMED: &[(&str, &str)] = &[
+    ("create_users_and_groups", "create_users_and_group"),
+    ("encrypt_apfs_volume", "encrypt_volume"),
+    ("unmount_apfs_volume", "unmount_volume"),
+];
+
+/// Upstream action kinds wrapping one of ours, by the field it is in and its kind
+const WRAPPERS: &[(&str, &str, &str)] = &[(
+    "configure_upstream_init_service",
+    "configure_init_service",
+    "configure_init_service",
+)];
+
+/// Upstream action kinds this installer has no counterpart for
+const UNSUPPORTED: &[(&str, &str)] = &[
+    (
+        "provision_determinate_nixd",
+        "`determinate-nixd` is not managed by this installer",
+    ),
+    (
+        "configure_determinate_nixd_init_service",
+        "`determinate-nixd` is not managed by this installer",
+    ),
+    (
+        "create_determinate_nix_volume",
+        "Determinate Nix volumes are not managed by this installer",
+    ),
+];
+
+/// A receipt of the upstream installer, translated into a plan this installer can revert
+#[derive(Debug)]
+pub(crate) struct Translation {
+    pub(crate) plan: InstallPlan,
+    /// The version of the upstream installer which wrote the receipt
+    pub(crate) upstream_version: Version,
+    pub(crate) skipped: Vec<Skipped>,
+}
+
+/// An action of an upstream receipt which could not be translated, and will not be reverted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Skipped {
+    pub(crate) kind: String,
+    /// What the action was about, such as its path, if it is known
+    pub(crate) subject: Option<String>,
+    pub(crate) reason: String,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.subject {
+            Some(subject) => write!(f, "`{}` ({subject}): {}", self.kind, self.reason),
+            None => write!(f, "`{}`: {}", self.kind, self.reason),
+        }
+    }
+}
+
+/// The version of the installer which wrote `receipt`, if it is not one this installer reverts as is
+pub(crate) fn upstream_version(receipt: &Value) -> Option<Version> {
+    let version = Version::parse(receipt.get("version")?.as_str()?).ok()?;
+    let current = current_version().ok()?;
+    let compatible = VersionReq::parse(&version.to_string())
+        .map(|req| req.matches(&current))
+        .unwrap_or(false);
+    (!compatible).then_some(version)
+}
+
+/// Translate `receipt` if it was written by the upstream installer, `None` if it was not
+pub(crate) async fn translate(receipt: &str) -> Result<Option<Translation>, NixInstallerError> {
+    let Ok(receipt) = serde_json::from_str::<Value>(receipt) else {
+        return Ok(None);
+    };
+    let Some(upstream_version) = upstream_version(&receipt) else {
+        return Ok(None);
+    };
+
+    let (actions, skipped) = translate_actions(&receipt);
+
+    let planner = match receipt
+        .get("planner")
+        .cloned()
+        .map(serde_json::from_value::<Box<dyn Planner>>)
+    {
+        Some(Ok(planner)) => planner,
+        // Its settings only matter for the checks before reverting, which this host's defaults suit
+        _ => {
+            tracing::debug!("Could not translate the planner of the upstream receipt, using the default planner");
+            BuiltinPlanner::default().await?.boxed()
+        },
+    };
+
+    Ok(Some(Translation {
+        plan: InstallPlan {
+            version: current_version()?,
+            build: Some(crate::build_info::BuildInfo::current()),
+            actions,
+            planner,
+            #[cfg(feature = "diagnostics")]
+            diagnostic_data: None,
+            host_fingerprint: None,
+            tools: Default::default(),
+            commands: Vec::new(),
+            install_id: None,
+            planned_at: None,
+            installed_at: None,
+            reboot_checkpoint: None,
+            keep_temp: false,
+            receipt_location: None,
+            leftovers: Vec::new(),
+        },
+        upstream_version,
+        skipped,
+    }))
+}
+
+fn translate_actions(receipt: &Value) -> (Vec<StatefulAction<Box<dyn Action>>>, Vec<Skipped>) {
+    let mut actions = vec![];
+    let mut skipped = vec![];
+
+    let entries = receipt
+        .get("actions")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for entry in entries {
+        match translate_action(entry) {
+            Ok(action) => actions.push(action),
+            Err(skip) => skipped.push(skip),
+        }
+    }
+
+    (actions, skipped)
+}
+
+fn translate_action(mut entry: Value) -> Result<StatefulAction<Box<dyn Action>>, Skipped> {
+    let kind = entry["action"]["action"]
+        .as_str()
+        .unwrap_or("unknown")
+        .to_string();
+    let subject = ["path", "name", "label", "unit"]
+        .iter()
+        .find_map(|field| entry["action"][*field].as_str())
+        .map(ToString::to_string);
+    let skip = |reason: String| Skipped {
+        kind: kind.clone(),
+        subject: subject.clone(),
+        reason,
+    };
+
+    if let Some((_, reason)) = UNSUPPORTED
+        .iter()
+        .find(|(unsupported, _)| *unsupported == kind)
+    {
+        return Err(skip(reason.to_string()));
+    }
+
+    if let Some((_, field, inner_kind)) = WRAPPERS.iter().find(|(wrapper, _, _)| *wrapper == kind) {
+        let mut inner = entry["action"][*field].take();
+        if !inner["action"].is_object() {
+            return Err(skip(format!("it has no `{field}` to translate")));
+        }
+        inner["action"]["action"] = Value::from(*inner_kind);
+        entry = inner;
+    } else if let Some((_, ours)) = RENAMED.iter().find(|(theirs, _)| *theirs == kind) {
+        entry["action"]["action"] = Value::from(*ours);
+    }
+
+    serde_json::from_value(entry).map_err(|e| {
+        skip(if e.to_string().starts_with("unknown variant") {
+            "this installer has no such action".to_string()
+        } else {
+            format!("it does not match this installer's action ({e})")
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    const UPSTREAM_LINUX: &str = include_str!("../tests/fixtures/upstream/linux.json");
+
+    #[test]
+    fn detects_upstream_versions() {
+        let ours = serde_json::json!({ "version": env!("CARGO_PKG_VERSION") });
+        assert_eq!(upstream_version(&ours), None);
+        let upstream = serde_json::json!({ "version": "0.16.1" });
+        assert_eq!(upstream_version(&upstream), Some(Version::new(0, 16, 1)));
+        assert_eq!(upstream_version(&serde_json::json!({})), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn translates_upstream_linux_receipt() -> eyre::Result<()> {
+        let translation = translate(UPSTREAM_LINUX)
+            .await?
+            .expect("The fixture should be detected as an upstream receipt");
+
+        assert_eq!(translation.upstream_version, Version::new(0, 16, 1));
+        assert_eq!(translation.plan.version, current_version()?);
+        assert_eq!(translation.plan.planner.typetag_name(), "linux");
+        let kinds = translation
+            .plan
+            .actions
+            .iter()
+            .map(|action| action.inner_typetag_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "create_directory",
+                "provision_nix",
+                "create_users_and_group",
+                "configure_nix",
+                "create_directory",
+                "configure_init_service",
+                "remove_directory",
+            ]
+        );
+        assert!(translation
+            .plan
+            .actions
+            .iter()
+            .all(|action| action.state == crate::action::ActionState::Completed));
+        assert_eq!(
+            translation.skipped,
+            vec![Skipped {
+                kind: "provision_determinate_nixd".into(),
+                subject: None,
+                reason: "`determinate-nixd` is not managed by this installer".into(),
+            }]
+        );
+
+        // The translated plan is one this installer can revert
+        translation.plan.check_compatible()?;
+        Ok(())
+    }
+
+    #[test]
+    fn skips_unknown_and_mismatched_actions() {
+        let receipt = serde_json::json!({
+            "version": "0.16.1",
+            "actions": [
+                { "action": { "action": "setup_channels", "path": "/root/.nix-channels" }, "state": "Completed" },
+                { "action": { "action": "create_directory", "path": "/nix" }, "state": "Completed" },
+                { "action": { "action": "remove_directory", "path": "/nix/temp-install-dir" }, "state": "Completed" },
+            ],
+        });
+        let (actions, skipped) = translate_actions(&receipt);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].kind, "setup_channels");
+        assert_eq!(skipped[0].reason, "this installer has no such action");
+        assert_eq!(skipped[1].subject.as_deref(), Some("/nix"));
+        assert!(skipped[1]
+            .reason
+            .starts_with("it does not match this installer's action"));
+    }
+}
@@ -0,0 +1 @@
+export LANG=C.UTF-8
@@ -0,0 +1 @@
+# Stands in for the profile script of Nix
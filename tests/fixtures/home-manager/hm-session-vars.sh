@@ -0,0 +1,14 @@
+# Only source this once.
+if [ -n "$__HM_SESS_VARS_SOURCED" ]; then return; fi
+export __HM_SESS_VARS_SOURCED=1
+
+export EDITOR="nvim"
+export LOCALE_ARCHIVE_2_27="/nix/store/0v3gfl0iy5s2fd1g5qmjy41vw6q9dnyx-glibc-locales-2.39-52/lib/locale/locale-archive"
+export NIX_PATH="nixpkgs=/home/alice/.nix-defexpr/channels/nixpkgs${NIX_PATH:+:$NIX_PATH}"
+export NIX_SSL_CERT_FILE="/etc/pki/tls/certs/ca-bundle.crt"
+export XDG_CACHE_HOME="/home/alice/.cache"
+export XDG_CONFIG_HOME="/home/alice/.config"
+export XDG_DATA_HOME="/home/alice/.local/share"
+export XDG_STATE_HOME="/home/alice/.local/state"
+
+export PATH="$PATH${PATH:+:}/home/alice/.local/bin"